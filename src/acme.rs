@@ -0,0 +1,872 @@
+//! Minimal ACME (RFC 8555) client, as used by the background task.
+
+use base64::URL_SAFE_NO_PAD;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use http_types::other::Date;
+use http_types::{Method, Response, StatusCode};
+use rcgen::RcgenError;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+use x509_parser::parse_x509_certificate;
+
+use crate::https::{https, retry_after, ClientTls, HttpsRequestError};
+use crate::ip;
+use crate::jose::{external_account_binding, sign, AccountKey, ExternalAccountKey, JoseError};
+use crate::problem::{Problem, ValidationRecord};
+
+pub(crate) const LETS_ENCRYPT_STAGING_DIRECTORY: &str =
+    "https://acme-staging-v02.api.letsencrypt.org/directory";
+pub(crate) const LETS_ENCRYPT_PRODUCTION_DIRECTORY: &str =
+    "https://acme-v02.api.letsencrypt.org/directory";
+pub(crate) const ZEROSSL_DIRECTORY: &str = "https://acme.zerossl.com/v2/DV90";
+pub(crate) const GOOGLE_TRUST_SERVICES_STAGING_DIRECTORY: &str =
+    "https://dv.acme-v02.test-api.pki.goog/directory";
+pub(crate) const GOOGLE_TRUST_SERVICES_PRODUCTION_DIRECTORY: &str =
+    "https://dv.acme-v02.api.pki.goog/directory";
+/// The ALPN protocol of ACME tls-alpn-01 validation requests.
+///
+/// When using an [`AcmeResolver`](crate::AcmeResolver) in your own server configuration, add this
+/// to its ALPN protocols so validation requests get answered, and close connections that
+/// negotiate it after the handshake.
+pub const ACME_TLS_ALPN_NAME: &[u8] = b"acme-tls/1";
+
+/// The problem type of a request rejected for its nonce.
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+/// How many nonces from responses to keep for later requests.
+const MAX_NONCES: usize = 8;
+/// The shortest and longest waits between polls of an order the CA is processing.
+const MIN_POLL_DELAY: Duration = Duration::from_secs(1);
+const MAX_POLL_DELAY: Duration = Duration::from_secs(120);
+
+/// Why a certificate is revoked, as an RFC 5280 reason code; see
+/// [`AcmeHandle::revoke`](crate::AcmeHandle::revoke).
+///
+/// CAs may accept only some reasons; Let's Encrypt accepts these.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RevocationReason {
+    /// No reason given.
+    Unspecified = 0,
+    /// The private key may have been exposed.
+    KeyCompromise = 1,
+    /// The certificate is replaced, such as after its domains changed.
+    Superseded = 4,
+    /// The domains are no longer in use.
+    CessationOfOperation = 5,
+}
+
+pub(crate) struct Account {
+    key: AccountKey,
+    directory: Directory,
+    kid: String,
+}
+
+impl Account {
+    pub(crate) async fn create(
+        directory: Directory,
+        contact: &[String],
+        eab: Option<&ExternalAccountKey>,
+        key: AccountKey,
+    ) -> Result<(Self, bool), AcmeError> {
+        let mut payload = json!({
+            "termsOfServiceAgreed": true,
+            "contact": contact,
+        });
+        if let Some(eab) = eab {
+            let binding = external_account_binding(&key, eab, &directory.new_account)?;
+            payload["externalAccountBinding"] = serde_json::to_value(binding)?;
+        }
+        let payload = payload.to_string();
+        let response = directory
+            .post(&key, None, &directory.new_account, &payload)
+            .await?;
+        // The CA answers 201 Created for a new account, and 200 OK for an existing one.
+        let registered = response.status() == StatusCode::Created;
+        let kid = get_header(&response, "Location")?;
+        let account = Account {
+            key,
+            directory,
+            kid,
+        };
+        Ok((account, registered))
+    }
+
+    async fn request(&self, url: impl AsRef<str>, payload: &str) -> Result<Response, AcmeError> {
+        self.directory
+            .post(&self.key, Some(&self.kid), url.as_ref(), payload)
+            .await
+    }
+
+    async fn request_body(&self, url: impl AsRef<str>, payload: &str) -> Result<String, AcmeError> {
+        let body = self.request(url, payload).await?.body_string().await?;
+        tracing::debug!(?body, "ACME response");
+        Ok(body)
+    }
+
+    /// Create a new order, returning its URL along with the order. The certificate profile
+    /// `profile` is requested if the CA offers it.
+    pub(crate) async fn new_order(
+        &self,
+        domains: &[String],
+        profile: Option<&str>,
+    ) -> Result<(String, Order), AcmeError> {
+        let identifiers: Vec<Identifier> = domains
+            .iter()
+            .map(|domain| match ip::parse(domain) {
+                Some(ip) => Identifier::Ip(ip.to_string()),
+                None => Identifier::Dns(domain.clone()),
+            })
+            .collect();
+        let mut payload = json!({ "identifiers": identifiers });
+        if let Some(profile) = profile {
+            match self.directory.meta.profiles.contains_key(profile) {
+                true => payload["profile"] = profile.into(),
+                false => tracing::warn!(
+                    profile,
+                    offered = ?self.directory.meta.profiles.keys().collect::<Vec<_>>(),
+                    "the CA does not offer the certificate profile; ordering without it"
+                ),
+            }
+        }
+        let payload = payload.to_string();
+        let mut response = self.request(&self.directory.new_order, &payload).await?;
+        let url = get_header(&response, "Location")?;
+        Ok((url, serde_json::from_str(&response.body_string().await?)?))
+    }
+
+    pub(crate) async fn order(&self, url: impl AsRef<str>) -> Result<Order, AcmeError> {
+        Ok(self.poll_order(url).await?.0)
+    }
+
+    /// Fetch the order at `url`, along with when the CA asked for it to be polled again, if it
+    /// did.
+    pub(crate) async fn poll_order(
+        &self,
+        url: impl AsRef<str>,
+    ) -> Result<(Order, Option<SystemTime>), AcmeError> {
+        let response = self.request(url, "").await?;
+        Self::read_order(response).await
+    }
+
+    async fn read_order(mut response: Response) -> Result<(Order, Option<SystemTime>), AcmeError> {
+        let body = response.body_string().await?;
+        tracing::debug!(?body, "ACME response");
+        Ok((serde_json::from_str(&body)?, retry_after(&response)))
+    }
+
+    pub(crate) async fn auth(&self, url: impl AsRef<str>) -> Result<Auth, AcmeError> {
+        Ok(serde_json::from_str(&self.request_body(url, "").await?)?)
+    }
+
+    pub(crate) async fn challenge(&self, url: impl AsRef<str>) -> Result<(), AcmeError> {
+        self.request_body(url, "{}").await?;
+        Ok(())
+    }
+
+    /// Finalize the order with the certificate signing request `csr`, returning the order along
+    /// with when the CA asked for it to be polled again, if it did.
+    pub(crate) async fn finalize(
+        &self,
+        url: impl AsRef<str>,
+        csr: Vec<u8>,
+    ) -> Result<(Order, Option<SystemTime>), AcmeError> {
+        let payload = json!({ "csr": base64::encode_config(csr, URL_SAFE_NO_PAD) }).to_string();
+        let response = self.request(url, &payload).await?;
+        Self::read_order(response).await
+    }
+
+    /// Download the certificate chain, choosing among the alternate chains the CA offers the one
+    /// whose topmost certificate is issued by `preferred_chain`, if any; otherwise the default
+    /// chain.
+    pub(crate) async fn certificate(
+        &self,
+        url: impl AsRef<str>,
+        preferred_chain: Option<&str>,
+    ) -> Result<String, AcmeError> {
+        let mut response = self.request(url, "").await?;
+        let chain = response.body_string().await?;
+        let preferred_chain = match preferred_chain {
+            Some(preferred_chain) => preferred_chain,
+            None => return Ok(chain),
+        };
+        if top_issuer(&chain).as_deref() == Some(preferred_chain) {
+            return Ok(chain);
+        }
+        for url in alternate_links(&response) {
+            match self.request_body(&url, "").await {
+                Ok(alternate) if top_issuer(&alternate).as_deref() == Some(preferred_chain) => {
+                    tracing::info!(preferred_chain, url, "using an alternate chain");
+                    return Ok(alternate);
+                }
+                Ok(_) => {}
+                Err(err) => tracing::warn!(%err, url, "failed to download an alternate chain"),
+            }
+        }
+        tracing::warn!(
+            preferred_chain,
+            "the CA offers no chain issued by the preferred issuer; using the default chain"
+        );
+        Ok(chain)
+    }
+
+    /// Revoke the certificate with the DER encoding `der`, for `reason`.
+    pub(crate) async fn revoke_cert(
+        &self,
+        der: &[u8],
+        reason: RevocationReason,
+    ) -> Result<(), AcmeError> {
+        let url = self
+            .directory
+            .revoke_cert
+            .as_ref()
+            .ok_or(AcmeError::NoRevocation)?;
+        let payload = json!({
+            "certificate": base64::encode_config(der, URL_SAFE_NO_PAD),
+            "reason": reason as u8,
+        })
+        .to_string();
+        self.request_body(url, &payload).await?;
+        Ok(())
+    }
+
+    /// Compute the key authorization for a challenge token.
+    pub(crate) fn key_authorization(&self, token: &str) -> Result<String, AcmeError> {
+        Ok(self.key.key_authorization(token)?)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Directory {
+    /// How far the CA's clock is ahead of ours, in seconds, going by the `Date` header of the
+    /// directory response.
+    #[serde(skip)]
+    pub(crate) clock_skew: Option<i64>,
+    /// The TLS configuration for requests to the CA.
+    #[serde(skip)]
+    tls: ClientTls,
+    /// Nonces the CA sent with its responses, not yet used.
+    #[serde(skip)]
+    nonces: Arc<Mutex<Vec<String>>>,
+    pub(crate) new_nonce: String,
+    pub(crate) new_account: String,
+    pub(crate) new_order: String,
+    #[serde(default)]
+    pub(crate) revoke_cert: Option<String>,
+    #[serde(default)]
+    pub(crate) meta: DirectoryMeta,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct DirectoryMeta {
+    /// The certificate profiles the CA offers, by name, with their descriptions.
+    #[serde(default)]
+    pub(crate) profiles: BTreeMap<String, String>,
+}
+
+impl Directory {
+    pub(crate) async fn discover(url: impl AsRef<str>, tls: &ClientTls) -> Result<Self, AcmeError> {
+        let mut response = https(tls, url, Method::Get, None).await?;
+        let now = SystemTime::now();
+        let body = response.body_bytes().await?;
+        let mut directory: Directory = serde_json::from_slice(&body)?;
+        directory.tls = tls.clone();
+        directory.clock_skew = clock_skew(&response, now);
+        Ok(directory)
+    }
+
+    /// A nonce for the next request: one the CA sent with an earlier response, or a new one.
+    async fn nonce(&self) -> Result<String, AcmeError> {
+        if let Some(nonce) = self.nonces().pop() {
+            return Ok(nonce);
+        }
+        let response = &https(&self.tls, &self.new_nonce, Method::Head, None).await?;
+        get_header(response, "replay-nonce")
+    }
+
+    fn nonces(&self) -> std::sync::MutexGuard<'_, Vec<String>> {
+        self.nonces.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Sign `payload` with `key`, under the account URL `kid` once there is one, and post it to
+    /// `url`. A request rejected for its nonce is sent once more with a new nonce, as RFC 8555
+    /// section 6.5 asks of clients.
+    async fn post(
+        &self,
+        key: &AccountKey,
+        kid: Option<&str>,
+        url: &str,
+        payload: &str,
+    ) -> Result<Response, AcmeError> {
+        match self.post_once(key, kid, url, payload).await {
+            Err(err)
+                if err
+                    .problem()
+                    .is_some_and(|problem| problem.typ == BAD_NONCE) =>
+            {
+                tracing::debug!(url, "the CA rejected the nonce; retrying with a new one");
+                // The others kept were likely issued as long ago, so they're dropped too.
+                self.nonces().clear();
+                self.post_once(key, kid, url, payload).await
+            }
+            result => result,
+        }
+    }
+
+    async fn post_once(
+        &self,
+        key: &AccountKey,
+        kid: Option<&str>,
+        url: &str,
+        payload: &str,
+    ) -> Result<Response, AcmeError> {
+        let body = sign(key, kid, self.nonce().await?, url, payload)?;
+        let response = https(&self.tls, url, Method::Post, Some(body)).await?;
+        if let Ok(nonce) = get_header(&response, "replay-nonce") {
+            let mut nonces = self.nonces();
+            if nonces.len() < MAX_NONCES {
+                nonces.push(nonce);
+            }
+        }
+        Ok(response)
+    }
+}
+
+/// How long to wait before polling an order the CA is still processing, after `attempts` polls:
+/// until the time it asked for with `Retry-After`, if it did, or else doubling from a second.
+pub(crate) fn poll_delay(
+    attempts: u32,
+    retry_after: Option<SystemTime>,
+    now: SystemTime,
+) -> Duration {
+    let delay = match retry_after {
+        Some(at) => at.duration_since(now).unwrap_or_default(),
+        None => Duration::from_secs(1 << attempts),
+    };
+    delay.clamp(MIN_POLL_DELAY, MAX_POLL_DELAY)
+}
+
+/// An ACME order object.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum Order {
+    /// Authorizations are pending.
+    Pending {
+        /// URLs of the order's authorizations.
+        authorizations: Vec<String>,
+        /// URL to finalize the order.
+        finalize: String,
+    },
+    /// All authorizations are valid; the order can be finalized.
+    Ready {
+        /// URL to finalize the order.
+        finalize: String,
+    },
+    /// The CA is issuing the certificate.
+    Processing,
+    /// The certificate has been issued.
+    Valid {
+        /// URL to download the certificate.
+        certificate: String,
+    },
+    /// The order failed.
+    Invalid,
+}
+
+/// An ACME authorization object.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum Auth {
+    /// The authorization is waiting for a challenge to be validated.
+    Pending {
+        /// The identifier being authorized.
+        identifier: Identifier,
+        /// Whether the authorization is for a wildcard domain, whose identifier is the domain
+        /// without the `*.` prefix.
+        #[serde(default)]
+        wildcard: bool,
+        /// The challenges that can be used to validate the identifier.
+        challenges: Vec<Challenge>,
+    },
+    /// The authorization succeeded.
+    Valid,
+    /// The authorization failed.
+    Invalid {
+        /// The identifier that failed to be authorized, if the CA included it.
+        #[serde(default)]
+        identifier: Option<Identifier>,
+        /// The challenges of the authorization, with the error of the one that failed.
+        #[serde(default)]
+        challenges: Vec<Challenge>,
+    },
+    /// The authorization was revoked.
+    Revoked,
+    /// The authorization expired.
+    Expired,
+    /// The authorization was deactivated.
+    Deactivated,
+}
+
+/// An identifier in an ACME order or authorization.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "camelCase")]
+pub enum Identifier {
+    /// A DNS name.
+    Dns(String),
+    /// An IP address.
+    Ip(String),
+}
+
+/// A challenge offered by the ACME server for an authorization.
+#[derive(Debug, Deserialize)]
+pub struct Challenge {
+    /// The challenge type, such as `tls-alpn-01`.
+    #[serde(rename = "type")]
+    pub typ: String,
+    /// The challenge URL.
+    pub url: String,
+    /// The challenge token.
+    pub token: String,
+    /// Why validation failed, for failed challenges.
+    #[serde(default)]
+    pub error: Option<Problem>,
+    /// How the CA attempted validation.
+    #[serde(default, rename = "validationRecord")]
+    pub validation_record: Vec<ValidationRecord>,
+}
+
+/// Errors from the ACME protocol exchange.
+#[derive(Error, Debug)]
+pub enum AcmeError {
+    /// An I/O error occurred.
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    /// Generating a certificate or signing request failed.
+    #[error("certificate generation error: {0}")]
+    Rcgen(#[from] RcgenError),
+    /// Signing a request failed.
+    #[error("JOSE error: {0}")]
+    Jose(#[from] JoseError),
+    /// A request or response body was not valid JSON.
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    /// An HTTPS request to the ACME server failed.
+    #[error("http request error: {0}")]
+    HttpRequest(#[from] HttpsRequestError),
+    /// The ACME server response was missing a required header.
+    #[error("acme service response is missing {0} header")]
+    MissingHeader(&'static str),
+    /// The ACME server did not offer a challenge of a type we can answer.
+    #[error("no supported challenge found")]
+    NoSupportedChallenge,
+    /// The generated tls-alpn-01 validation key could not be loaded.
+    #[error("invalid tls-alpn-01 validation key")]
+    InvalidChallengeKey,
+    /// The ACME directory does not offer revocation.
+    #[error("acme directory has no revokeCert endpoint")]
+    NoRevocation,
+}
+
+impl AcmeError {
+    /// The problem document the CA responded with, if the request failed with one.
+    pub(crate) fn problem(&self) -> Option<Problem> {
+        match self {
+            AcmeError::HttpRequest(HttpsRequestError::Non2xxStatus { body, .. }) => {
+                Problem::parse(body)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the request failed at the network level rather than being answered.
+    pub(crate) fn is_io(&self) -> bool {
+        matches!(
+            self,
+            AcmeError::Io(_)
+                | AcmeError::HttpRequest(HttpsRequestError::Io(_) | HttpsRequestError::Http(_))
+        )
+    }
+}
+
+impl From<http_types::Error> for AcmeError {
+    fn from(e: http_types::Error) -> Self {
+        Self::HttpRequest(HttpsRequestError::from(e))
+    }
+}
+
+/// How far the clock of the server sending `response` is ahead of `now`, in seconds, going by
+/// its `Date` header.
+fn clock_skew(response: &Response, now: SystemTime) -> Option<i64> {
+    let date = SystemTime::from(Date::from_headers(response).ok()??);
+    Some(match date.duration_since(now) {
+        Ok(ahead) => ahead.as_secs() as i64,
+        Err(behind) => -(behind.duration().as_secs() as i64),
+    })
+}
+
+/// The URLs of the alternate certificate chains a certificate download links to.
+fn alternate_links(response: &Response) -> Vec<String> {
+    let values = match response.header("Link") {
+        Some(values) => values,
+        None => return vec![],
+    };
+    values
+        .iter()
+        .flat_map(|value| value.as_str().split(','))
+        .filter_map(|link| {
+            let (url, params) = link.trim().strip_prefix('<')?.split_once('>')?;
+            params
+                .split(';')
+                .any(|param| matches!(param.trim(), "rel=\"alternate\"" | "rel=alternate"))
+                .then(|| url.to_string())
+        })
+        .collect()
+}
+
+/// The common name of the issuer of the last certificate in the PEM chain `pem`, closest to the
+/// root.
+fn top_issuer(pem: &str) -> Option<String> {
+    let pems = pem::parse_many(pem).ok()?;
+    let (_, top) = parse_x509_certificate(&pems.last()?.contents).ok()?;
+    let common_name = top.issuer().iter_common_name().next()?.as_str().ok()?;
+    Some(common_name.to_string())
+}
+
+fn get_header(response: &Response, header: &'static str) -> Result<String, AcmeError> {
+    match response.header(header) {
+        None => Err(AcmeError::MissingHeader(header)),
+        Some(values) => Ok(values.last().to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::net::TcpListener;
+    use async_std::task::{self, block_on};
+    use http_types::{Request, StatusCode};
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+
+    use super::*;
+    use crate::jose::AccountKeyAlgorithm;
+
+    fn with_links(links: &[&str]) -> Response {
+        let mut response = Response::new(StatusCode::Ok);
+        for link in links {
+            response.append_header("Link", *link);
+        }
+        response
+    }
+
+    #[test]
+    fn finds_alternate_links() {
+        let response = with_links(&[
+            "<https://ca.example/cert/1/1>;rel=\"alternate\", <https://ca.example/dir>;rel=\"index\"",
+            "<https://ca.example/cert/1/2> ; rel=alternate",
+        ]);
+        assert_eq!(
+            alternate_links(&response),
+            ["https://ca.example/cert/1/1", "https://ca.example/cert/1/2"]
+        );
+        assert!(alternate_links(&with_links(&[])).is_empty());
+    }
+
+    #[test]
+    fn skips_malformed_links() {
+        let response = with_links(&[
+            "https://ca.example/cert/1/1;rel=\"alternate\"",
+            "<https://ca.example/cert/1/2;rel=\"alternate\"",
+            "<https://ca.example/cert/1/3>",
+            "<https://ca.example/cert/1/4>;rel=\"alternates\"",
+        ]);
+        assert!(alternate_links(&response).is_empty());
+    }
+
+    fn ca(name: &str) -> Certificate {
+        let mut params = CertificateParams::new(vec![]);
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        Certificate::from_params(params).unwrap()
+    }
+
+    #[test]
+    fn finds_top_issuers() {
+        let root = ca("Test Root");
+        let intermediate = ca("Test Intermediate");
+        let leaf = Certificate::from_params(CertificateParams::new(vec!["domain.example".into()]))
+            .unwrap();
+        let chain = [
+            leaf.serialize_pem_with_signer(&intermediate).unwrap(),
+            intermediate.serialize_pem_with_signer(&root).unwrap(),
+        ]
+        .concat();
+        assert_eq!(top_issuer(&chain).as_deref(), Some("Test Root"));
+        let leaf = leaf.serialize_pem_with_signer(&intermediate).unwrap();
+        assert_eq!(top_issuer(&leaf).as_deref(), Some("Test Intermediate"));
+
+        assert_eq!(top_issuer(""), None);
+        // A truncated last block is skipped, as if the chain ended before it.
+        assert_eq!(
+            top_issuer(&chain[..chain.len() - 40]).as_deref(),
+            Some("Test Intermediate")
+        );
+        let garbage = pem::encode(&pem::Pem {
+            tag: "CERTIFICATE".into(),
+            contents: b"not a certificate".to_vec(),
+        });
+        assert_eq!(top_issuer(&garbage), None);
+    }
+
+    #[test]
+    fn parses_orders_and_authorizations() {
+        let order = r#"{"status": "pending", "authorizations": ["https://ca.example/authz/1"],
+            "finalize": "https://ca.example/order/1/finalize", "identifiers": []}"#;
+        assert!(matches!(
+            serde_json::from_str(order).unwrap(),
+            Order::Pending { authorizations, .. } if authorizations == ["https://ca.example/authz/1"]
+        ));
+        let order = r#"{"status": "valid", "certificate": "https://ca.example/cert/1"}"#;
+        assert!(matches!(
+            serde_json::from_str(order).unwrap(),
+            Order::Valid { certificate } if certificate == "https://ca.example/cert/1"
+        ));
+        for order in [
+            r#"{"status": "valid"}"#,
+            r#"{"status": "unheard-of"}"#,
+            r#"{"certificate": "https://ca.example/cert/1"}"#,
+            r#"{"status": "valid", "certificate": "https://ca.exa"#,
+        ] {
+            assert!(serde_json::from_str::<Order>(order).is_err(), "{}", order);
+        }
+
+        let auth = r#"{"status": "pending",
+            "identifier": {"type": "dns", "value": "domain.example"},
+            "challenges": [{"type": "tls-alpn-01", "url": "https://ca.example/chall/1",
+                "token": "token", "status": "pending"}]}"#;
+        match serde_json::from_str(auth).unwrap() {
+            Auth::Pending {
+                identifier: Identifier::Dns(domain),
+                challenges,
+                wildcard,
+            } => {
+                assert_eq!(domain, "domain.example");
+                assert_eq!(challenges[0].typ, "tls-alpn-01");
+                assert_eq!(challenges[0].token, "token");
+                assert!(!wildcard);
+            }
+            auth => panic!("unexpected authorization {:?}", auth),
+        }
+        let auth = r#"{"status": "pending", "wildcard": true,
+            "identifier": {"type": "dns", "value": "domain.example"}, "challenges": []}"#;
+        assert!(matches!(
+            serde_json::from_str(auth).unwrap(),
+            Auth::Pending { wildcard: true, .. }
+        ));
+        assert!(matches!(
+            serde_json::from_str(r#"{"status": "invalid"}"#).unwrap(),
+            Auth::Invalid { identifier: None, challenges } if challenges.is_empty()
+        ));
+        let auth = r#"{"status": "invalid",
+            "identifier": {"type": "dns", "value": "domain.example"},
+            "challenges": [{"type": "tls-alpn-01",
+            "url": "https://ca.example/chall/1", "token": "token", "status": "invalid",
+            "error": {"type": "urn:ietf:params:acme:error:connection", "detail": "timeout"},
+            "validationRecord": [{"hostname": "domain.example", "port": "443",
+                "addressesResolved": ["192.0.2.1"], "addressUsed": "192.0.2.1"}]}]}"#;
+        match serde_json::from_str(auth).unwrap() {
+            Auth::Invalid {
+                identifier: Some(Identifier::Dns(domain)),
+                challenges,
+            } => {
+                assert_eq!(domain, "domain.example");
+                let problem = challenges[0].error.as_ref().unwrap();
+                assert_eq!(problem.typ, "urn:ietf:params:acme:error:connection");
+                assert_eq!(problem.detail.as_deref(), Some("timeout"));
+                let record = &challenges[0].validation_record[0];
+                assert_eq!(record.port, Some(443));
+                assert_eq!(record.address_used.as_deref(), Some("192.0.2.1"));
+            }
+            auth => panic!("unexpected authorization {:?}", auth),
+        }
+        let auth = r#"{"status": "pending", "identifier": {"type": "dns"}, "challenges": []}"#;
+        assert!(serde_json::from_str::<Auth>(auth).is_err());
+    }
+
+    #[test]
+    fn parses_directories() {
+        let directory = r#"{"newNonce": "https://ca.example/nonce",
+            "newAccount": "https://ca.example/account", "newOrder": "https://ca.example/order",
+            "meta": {"termsOfService": "https://ca.example/terms"}}"#;
+        let directory: Directory = serde_json::from_str(directory).unwrap();
+        assert_eq!(directory.new_order, "https://ca.example/order");
+        assert_eq!(directory.revoke_cert, None);
+        let directory = r#"{"newNonce": "https://ca.example/nonce",
+            "newAccount": "https://ca.example/account", "newOrder": "https://ca.example/order",
+            "revokeCert": "https://ca.example/revoke"}"#;
+        let directory: Directory = serde_json::from_str(directory).unwrap();
+        assert_eq!(
+            directory.revoke_cert.as_deref(),
+            Some("https://ca.example/revoke")
+        );
+        assert!(directory.meta.profiles.is_empty());
+        let directory = r#"{"newNonce": "https://ca.example/nonce",
+            "newAccount": "https://ca.example/account", "newOrder": "https://ca.example/order",
+            "meta": {"profiles": {"classic": "The default profile",
+                "shortlived": "Certificates valid for about six days"}}}"#;
+        let directory: Directory = serde_json::from_str(directory).unwrap();
+        assert_eq!(
+            directory.meta.profiles.keys().collect::<Vec<_>>(),
+            ["classic", "shortlived"]
+        );
+        let directory = r#"{"newNonce": "https://ca.example/nonce"}"#;
+        assert!(serde_json::from_str::<Directory>(directory).is_err());
+    }
+
+    #[test]
+    fn reads_problems_from_errors() {
+        let error = |body: &str| {
+            AcmeError::HttpRequest(HttpsRequestError::Non2xxStatus {
+                status_code: 429,
+                body: body.into(),
+                retry_after: None,
+            })
+        };
+        let problem = error(r#"{"type": "urn:ietf:params:acme:error:rateLimited"}"#)
+            .problem()
+            .unwrap();
+        assert_eq!(problem.typ, "urn:ietf:params:acme:error:rateLimited");
+        assert!(error("<html>Too Many Requests</html>").problem().is_none());
+        assert!(error(r#"{"type": "urn:ietf"#).problem().is_none());
+        assert!(AcmeError::NoRevocation.problem().is_none());
+    }
+
+    #[test]
+    fn requires_headers() {
+        let mut response = Response::new(StatusCode::Ok);
+        assert!(matches!(
+            get_header(&response, "replay-nonce"),
+            Err(AcmeError::MissingHeader("replay-nonce"))
+        ));
+        response.insert_header("Replay-Nonce", "nonce");
+        assert_eq!(get_header(&response, "replay-nonce").unwrap(), "nonce");
+    }
+
+    #[test]
+    fn measures_clock_skew() {
+        let mut response = Response::new(StatusCode::Ok);
+        let now = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000);
+        assert_eq!(clock_skew(&response, now), None);
+        response.insert_header("Date", "Sun, 13 Sep 2020 12:36:40 GMT");
+        assert_eq!(clock_skew(&response, now), Some(600));
+        response.insert_header("Date", "Sun, 13 Sep 2020 12:20:00 GMT");
+        assert_eq!(clock_skew(&response, now), Some(-400));
+        response.insert_header("Date", "not a date");
+        assert_eq!(clock_skew(&response, now), None);
+    }
+
+    #[test]
+    fn honors_retry_after_when_polling() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        assert_eq!(poll_delay(0, None, now), Duration::from_secs(1));
+        assert_eq!(poll_delay(3, None, now), Duration::from_secs(8));
+        let at = now + Duration::from_secs(5);
+        assert_eq!(poll_delay(3, Some(at), now), Duration::from_secs(5));
+        // Times already past, or too far ahead, are bounded.
+        assert_eq!(poll_delay(0, Some(now), now), MIN_POLL_DELAY);
+        let at = now + Duration::from_secs(3600);
+        assert_eq!(poll_delay(0, Some(at), now), MAX_POLL_DELAY);
+    }
+
+    /// The nonce a signed request was sent with.
+    async fn request_nonce(mut req: Request) -> String {
+        let body: serde_json::Value =
+            serde_json::from_str(&req.body_string().await.unwrap()).unwrap();
+        let protected = body["protected"].as_str().unwrap();
+        let protected = base64::decode_config(protected, URL_SAFE_NO_PAD).unwrap();
+        let protected: serde_json::Value = serde_json::from_slice(&protected).unwrap();
+        protected["nonce"].as_str().unwrap().to_string()
+    }
+
+    /// Serve a CA over plain HTTP that numbers its nonces by connection, rejects the first one as
+    /// bad, and answers every other request with an order to poll again in 3 seconds. Returns an
+    /// account with it, the URL of the order, and the requests seen, by method and nonce.
+    fn serve_ca() -> (Account, String, Arc<Mutex<Vec<String>>>) {
+        let listener = block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let seen = Arc::new(Mutex::new(vec![]));
+        let log = seen.clone();
+        task::spawn(async move {
+            let mut count = 0;
+            while let Ok((stream, _)) = listener.accept().await {
+                let log = log.clone();
+                count += 1;
+                let nonce = format!("nonce-{}", count);
+                let _ = async_h1::accept(stream, move |req| {
+                    let log = log.clone();
+                    let nonce = nonce.clone();
+                    async move {
+                        let mut res = Response::new(StatusCode::Ok);
+                        res.insert_header("Replay-Nonce", nonce);
+                        if req.method() == Method::Head {
+                            log.lock().unwrap().push("HEAD".to_string());
+                            return Ok(res);
+                        }
+                        let used = request_nonce(req).await;
+                        log.lock().unwrap().push(format!("POST {}", used));
+                        if used == "nonce-1" {
+                            res.set_status(StatusCode::BadRequest);
+                            res.set_body(format!(r#"{{"type": "{}"}}"#, BAD_NONCE));
+                        } else {
+                            res.insert_header("Retry-After", "3");
+                            res.set_body(r#"{"status": "processing"}"#);
+                        }
+                        Ok(res)
+                    }
+                })
+                .await;
+            }
+        });
+        let directory = format!(
+            r#"{{"newNonce": "{0}/nonce", "newAccount": "{0}/account", "newOrder": "{0}/order"}}"#,
+            base
+        );
+        let directory: Directory = serde_json::from_str(&directory).unwrap();
+        let algorithm = AccountKeyAlgorithm::EcdsaP256;
+        let key = AccountKey::generate_pkcs8(algorithm).unwrap();
+        let account = Account {
+            key: AccountKey::from_pkcs8(algorithm, &key).unwrap(),
+            directory,
+            kid: format!("{}/account/1", base),
+        };
+        (account, format!("{}/order/1", base), seen)
+    }
+
+    #[test]
+    fn reuses_nonces_and_retries_bad_ones() {
+        let (account, url, seen) = serve_ca();
+        let (order, retry_after) = block_on(account.poll_order(&url)).unwrap();
+        assert!(matches!(order, Order::Processing));
+        let wait = retry_after
+            .unwrap()
+            .duration_since(SystemTime::now())
+            .unwrap();
+        assert!(wait > Duration::from_secs(1) && wait <= Duration::from_secs(3));
+        // The nonce of the response is used for the next request, without asking for one.
+        assert!(matches!(
+            block_on(account.order(&url)),
+            Ok(Order::Processing)
+        ));
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                "HEAD",
+                "POST nonce-1",
+                "HEAD",
+                "POST nonce-3",
+                "POST nonce-4"
+            ]
+        );
+    }
+}
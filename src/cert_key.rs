@@ -0,0 +1,49 @@
+//! Generating the private keys of certificates.
+
+use rcgen::{KeyPair, SignatureAlgorithm, PKCS_ECDSA_P256_SHA256, PKCS_ECDSA_P384_SHA384};
+
+/// The algorithm of the private keys of new certificates, set with
+/// [`AcmeConfig::cert_key_algorithm`](crate::AcmeConfig::cert_key_algorithm).
+///
+/// Only ECDSA keys are generated. For clients that only support RSA, generate an RSA key with
+/// a dedicated tool, such as `openssl genpkey -algorithm RSA`, and supply it as PKCS#8 with a
+/// [`KeyProvider`](crate::KeyProvider).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CertKeyAlgorithm {
+    /// ECDSA using the P-256 curve. This is the default, and what most clients support best.
+    #[default]
+    EcdsaP256,
+    /// ECDSA using the P-384 curve.
+    EcdsaP384,
+}
+
+impl CertKeyAlgorithm {
+    /// The signature algorithm of certificate signing requests with keys of this algorithm.
+    pub(crate) fn signature_algorithm(self) -> &'static SignatureAlgorithm {
+        match self {
+            Self::EcdsaP256 => &PKCS_ECDSA_P256_SHA256,
+            Self::EcdsaP384 => &PKCS_ECDSA_P384_SHA384,
+        }
+    }
+
+    /// Generate a key pair.
+    pub(crate) fn generate(self) -> Result<KeyPair, rcgen::RcgenError> {
+        KeyPair::generate(self.signature_algorithm())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_keys_of_the_algorithm() {
+        for algorithm in [CertKeyAlgorithm::EcdsaP256, CertKeyAlgorithm::EcdsaP384].iter() {
+            let key_pair = algorithm.generate().unwrap();
+            assert!(key_pair.is_compatible(algorithm.signature_algorithm()));
+        }
+        let p256 = CertKeyAlgorithm::default().generate().unwrap();
+        assert!(!p256.is_compatible(&PKCS_ECDSA_P384_SHA384));
+    }
+}
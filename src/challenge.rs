@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_std::task;
+use async_trait::async_trait;
+use rcgen::{CustomExtension, PKCS_ECDSA_P256_SHA256};
+use ring::digest::{digest, SHA256};
+use tide_rustls::rustls::sign::{any_ecdsa_type, CertifiedKey};
+use tide_rustls::rustls::{Certificate, PrivateKey};
+use tracing::debug;
+
+use crate::acme::AcmeError;
+use crate::delegation::SharedChallenges;
+use crate::dns::DnsResolution;
+use crate::handle::Registry;
+use crate::ip;
+use crate::resolver::AcmeResolver;
+
+/// Error from a [`ChallengeResponder`].
+pub type ChallengeError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Answers ACME challenges of one type, proving control of domains to the CA.
+///
+/// tls-alpn-01 challenges are answered by the [`AcmeTlsAcceptor`](crate::AcmeTlsAcceptor)
+/// itself. Add responders with
+/// [`AcmeConfig::challenge_responder`](crate::AcmeConfig::challenge_responder) to answer other
+/// challenge types, or to answer challenges elsewhere, such as from an edge worker. For each
+/// authorization, the first responder whose challenge type the CA offers is used.
+#[async_trait]
+pub trait ChallengeResponder: Send + Sync + 'static {
+    /// The challenge type this responder answers, such as `http-01` or `dns-01`.
+    fn challenge_type(&self) -> &str;
+
+    /// Make the response to a challenge available, before the CA is asked to validate it.
+    ///
+    /// `key_authorization` is the key authorization for the challenge token, as defined in RFC
+    /// 8555 section 8.1; responders derive what they serve from it as their challenge type
+    /// requires.
+    async fn present(
+        &self,
+        domain: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> Result<(), ChallengeError>;
+
+    /// Remove the response to a challenge, once the authorization is valid or has failed. Does
+    /// nothing by default.
+    async fn cleanup(&self, _domain: &str, _token: &str) -> Result<(), ChallengeError> {
+        Ok(())
+    }
+}
+
+/// Sets and removes DNS TXT records, for answering dns-01 challenges.
+///
+/// Add one with [`AcmeConfig::dns_provider`](crate::AcmeConfig::dns_provider), such as for
+/// servers the CA can't reach, or to obtain wildcard certificates, which require dns-01.
+#[async_trait]
+pub trait DnsProvider: Send + Sync + 'static {
+    /// Add a TXT record with `value` to the name `name`, such as
+    /// `_acme-challenge.domain.example`, keeping any other TXT records of the name.
+    ///
+    /// Return once the record is visible to the CA, waiting for it to propagate to the
+    /// authoritative nameservers if needed.
+    async fn set_txt_record(&self, name: &str, value: &str) -> Result<(), ChallengeError>;
+
+    /// Remove the TXT record with `value` from the name `name`, once the authorization is valid
+    /// or has failed.
+    async fn remove_txt_record(&self, name: &str, value: &str) -> Result<(), ChallengeError>;
+}
+
+/// How long to wait for a dns-01 challenge record to propagate.
+const PROPAGATION_TIMEOUT: Duration = Duration::from_secs(300);
+/// How often to check whether it has.
+const PROPAGATION_POLL: Duration = Duration::from_secs(5);
+
+/// Responder answering dns-01 challenges via a [`DnsProvider`].
+pub(crate) struct Dns01Responder<P> {
+    provider: P,
+    /// How to check that records have propagated, shared with the configuration so it can be
+    /// set after the provider.
+    resolution: Arc<Mutex<DnsResolution>>,
+    /// The records set for pending challenges, by token.
+    records: Mutex<HashMap<String, (String, String)>>,
+}
+
+impl<P: DnsProvider> Dns01Responder<P> {
+    pub(crate) fn new(provider: P, resolution: Arc<Mutex<DnsResolution>>) -> Self {
+        Self {
+            provider,
+            resolution,
+            records: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl<P: DnsProvider> ChallengeResponder for Dns01Responder<P> {
+    fn challenge_type(&self) -> &str {
+        "dns-01"
+    }
+
+    async fn present(
+        &self,
+        domain: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> Result<(), ChallengeError> {
+        // RFC 8555 section 8.4: the record of a wildcard domain is set on its base domain.
+        let domain = domain.strip_prefix("*.").unwrap_or(domain);
+        let name = format!("_acme-challenge.{}", domain);
+        let digest = digest(&SHA256, key_authorization.as_bytes());
+        let value = base64::encode_config(digest, base64::URL_SAFE_NO_PAD);
+        self.provider.set_txt_record(&name, &value).await?;
+        self.records
+            .lock()
+            .unwrap()
+            .insert(token.into(), (name.clone(), value.clone()));
+        let resolution = self.resolution.lock().unwrap().clone();
+        let deadline = Instant::now() + PROPAGATION_TIMEOUT;
+        loop {
+            let missing = match resolution.missing(&name, &value).await {
+                Ok(missing) if missing.is_empty() => return Ok(()),
+                Ok(missing) => missing.join(", "),
+                Err(err) => err.to_string(),
+            };
+            if Instant::now() >= deadline {
+                return Err(format!(
+                    "TXT record of {} not visible after {:?}: {}",
+                    name, PROPAGATION_TIMEOUT, missing
+                )
+                .into());
+            }
+            debug!(name, missing, "waiting for the TXT record to propagate");
+            task::sleep(PROPAGATION_POLL).await;
+        }
+    }
+
+    async fn cleanup(&self, _domain: &str, token: &str) -> Result<(), ChallengeError> {
+        let record = self.records.lock().unwrap().remove(token);
+        match record {
+            Some((name, value)) => self.provider.remove_txt_record(&name, &value).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// The built-in responder answering tls-alpn-01 challenges via the certificate resolver.
+pub(crate) struct TlsAlpn01Responder {
+    pub(crate) resolver: Arc<AcmeResolver>,
+    /// Where to publish challenges for other instances to answer, if delegating them.
+    pub(crate) shared: Option<SharedChallenges>,
+}
+
+#[async_trait]
+impl ChallengeResponder for TlsAlpn01Responder {
+    fn challenge_type(&self) -> &str {
+        "tls-alpn-01"
+    }
+
+    async fn present(
+        &self,
+        domain: &str,
+        _token: &str,
+        key_authorization: &str,
+    ) -> Result<(), ChallengeError> {
+        let cert = tls_alpn_01_cert(domain, key_authorization)?;
+        self.resolver.set_auth_key(domain.into(), cert);
+        if let Some(shared) = &self.shared {
+            shared.publish(domain, key_authorization).await?;
+        }
+        Ok(())
+    }
+
+    async fn cleanup(&self, domain: &str, _token: &str) -> Result<(), ChallengeError> {
+        if let Some(shared) = &self.shared {
+            shared.withdraw(domain).await;
+        }
+        Ok(())
+    }
+}
+
+/// The built-in responder answering http-01 challenges via
+/// [`AcmeHttp01Middleware`](crate::AcmeHttp01Middleware).
+pub(crate) struct Http01Responder {
+    pub(crate) registry: Arc<Registry>,
+}
+
+#[async_trait]
+impl ChallengeResponder for Http01Responder {
+    fn challenge_type(&self) -> &str {
+        "http-01"
+    }
+
+    async fn present(
+        &self,
+        _domain: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> Result<(), ChallengeError> {
+        self.registry
+            .set_http01_response(token.into(), key_authorization.into());
+        Ok(())
+    }
+
+    async fn cleanup(&self, _domain: &str, token: &str) -> Result<(), ChallengeError> {
+        self.registry.remove_http01_response(token);
+        Ok(())
+    }
+}
+
+/// Generate the validation certificate answering a tls-alpn-01 challenge.
+pub(crate) fn tls_alpn_01_cert(
+    domain: &str,
+    key_authorization: &str,
+) -> Result<CertifiedKey, AcmeError> {
+    let key_auth = digest(&SHA256, key_authorization.as_bytes());
+    let mut params = rcgen::CertificateParams::new(vec![]);
+    params.subject_alt_names = ip::subject_alt_names(&[domain.into()]);
+    params.alg = &PKCS_ECDSA_P256_SHA256;
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(key_auth.as_ref())];
+    let cert = rcgen::Certificate::from_params(params)?;
+    let pk = any_ecdsa_type(&PrivateKey(cert.serialize_private_key_der()))
+        .map_err(|()| AcmeError::InvalidChallengeKey)?;
+    Ok(CertifiedKey::new(
+        vec![Certificate(cert.serialize_der()?)],
+        Arc::new(pk),
+    ))
+}
+
+/// Generate a self-signed certificate for `domains`, to serve until one is obtained.
+pub(crate) fn self_signed_cert(domains: Vec<String>) -> Result<CertifiedKey, AcmeError> {
+    let mut params = rcgen::CertificateParams::new(vec![]);
+    params.subject_alt_names = ip::subject_alt_names(&domains);
+    params.alg = &PKCS_ECDSA_P256_SHA256;
+    let cert = rcgen::Certificate::from_params(params)?;
+    let pk = any_ecdsa_type(&PrivateKey(cert.serialize_private_key_der()))
+        .map_err(|()| AcmeError::InvalidChallengeKey)?;
+    Ok(CertifiedKey::new(
+        vec![Certificate(cert.serialize_der()?)],
+        Arc::new(pk),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::block_on;
+    use x509_parser::extensions::GeneralName;
+    use x509_parser::parse_x509_certificate;
+
+    use super::*;
+    use crate::resolver::{MissingSni, SniPriority};
+    use crate::SystemClock;
+
+    #[test]
+    fn builds_tls_alpn_01_certificates() {
+        let key = tls_alpn_01_cert("domain.example", "token.thumbprint").unwrap();
+        let (_, cert) = parse_x509_certificate(&key.cert[0].0).unwrap();
+        let names = cert.subject_alternative_name().unwrap().unwrap();
+        assert_eq!(names.value.general_names.len(), 1);
+        // The id-pe-acmeIdentifier extension, critical and holding the key authorization digest.
+        let extension = cert
+            .extensions()
+            .iter()
+            .find(|e| e.oid.to_id_string() == "1.3.6.1.5.5.7.1.31")
+            .unwrap();
+        assert!(extension.critical);
+        let key_auth = digest(&SHA256, b"token.thumbprint");
+        assert_eq!(&extension.value[2..], key_auth.as_ref());
+
+        let key = tls_alpn_01_cert("192.0.2.1", "token.thumbprint").unwrap();
+        let (_, cert) = parse_x509_certificate(&key.cert[0].0).unwrap();
+        let names = cert.subject_alternative_name().unwrap().unwrap();
+        assert!(matches!(
+            names.value.general_names[..],
+            [GeneralName::IPAddress(&[192, 0, 2, 1])]
+        ));
+    }
+
+    #[test]
+    fn presents_tls_alpn_01_challenges() {
+        let resolver = AcmeResolver::new(
+            None,
+            SniPriority::default(),
+            MissingSni::default(),
+            None,
+            None,
+            Arc::new(SystemClock),
+        );
+        let responder = TlsAlpn01Responder {
+            resolver: resolver.clone(),
+            shared: None,
+        };
+        assert_eq!(responder.challenge_type(), "tls-alpn-01");
+        block_on(responder.present("domain.example", "token", "token.thumbprint")).unwrap();
+        // Handshakes for names with a challenge response are counted under their own name.
+        resolver.record_handshake(Some("domain.example"));
+        resolver.record_handshake(Some("other.example"));
+        let stats = resolver.stats();
+        assert_eq!(stats[&Some("domain.example".into())].handshakes, 1);
+        assert_eq!(stats[&None].handshakes, 1);
+        block_on(responder.cleanup("domain.example", "token")).unwrap();
+    }
+
+    #[test]
+    fn presents_http_01_challenges() {
+        let registry = Arc::new(Registry::default());
+        let responder = Http01Responder {
+            registry: registry.clone(),
+        };
+        assert_eq!(responder.challenge_type(), "http-01");
+        block_on(responder.present("domain.example", "token", "token.thumbprint")).unwrap();
+        assert_eq!(
+            registry.http01_response("token").as_deref(),
+            Some("token.thumbprint")
+        );
+        block_on(responder.cleanup("domain.example", "token")).unwrap();
+        assert_eq!(registry.http01_response("token"), None);
+    }
+
+    /// A DNS provider recording the TXT records it holds.
+    #[derive(Clone, Default)]
+    struct Records(Arc<Mutex<Vec<(String, String)>>>);
+
+    #[async_trait]
+    impl DnsProvider for Records {
+        async fn set_txt_record(&self, name: &str, value: &str) -> Result<(), ChallengeError> {
+            self.0.lock().unwrap().push((name.into(), value.into()));
+            Ok(())
+        }
+
+        async fn remove_txt_record(&self, name: &str, value: &str) -> Result<(), ChallengeError> {
+            let mut records = self.0.lock().unwrap();
+            records.retain(|record| record != &(name.into(), value.into()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn presents_dns_01_challenges() {
+        let records = Records::default();
+        let responder = Dns01Responder::new(records.clone(), Arc::default());
+        assert_eq!(responder.challenge_type(), "dns-01");
+        let value = base64::encode_config(
+            digest(&SHA256, b"token.thumbprint"),
+            base64::URL_SAFE_NO_PAD,
+        );
+        block_on(responder.present("*.domain.example", "token", "token.thumbprint")).unwrap();
+        block_on(responder.present("www.domain.example", "other", "token.thumbprint")).unwrap();
+        assert_eq!(
+            *records.0.lock().unwrap(),
+            [
+                ("_acme-challenge.domain.example".into(), value.clone()),
+                ("_acme-challenge.www.domain.example".into(), value.clone()),
+            ]
+        );
+        block_on(responder.cleanup("*.domain.example", "token")).unwrap();
+        block_on(responder.cleanup("*.domain.example", "token")).unwrap();
+        assert_eq!(
+            *records.0.lock().unwrap(),
+            [("_acme-challenge.www.domain.example".into(), value)]
+        );
+    }
+}
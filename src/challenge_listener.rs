@@ -0,0 +1,174 @@
+//! A Tide listener answering only tls-alpn-01 validation requests.
+
+use std::fmt::{self, Debug, Display};
+use std::io;
+use std::net::SocketAddr;
+
+use async_std::net::TcpListener;
+use tide::listener::{ListenInfo, Listener, ToListener};
+use tide::Server;
+
+use crate::{logged, AcmeTlsAcceptor};
+
+/// A listener answering tls-alpn-01 validation requests on a socket of its own, and nothing else,
+/// for hosts where port 443 is taken by another process that passes connections negotiating the
+/// `acme-tls/1` ALPN protocol through to a side port (for instance, with nginx's `ssl_preread`
+/// or HAProxy's `req.ssl_alpn`), while the application is served elsewhere.
+///
+/// Unlike [`AcmeTlsAcceptor::listen_challenges`], this is a Tide listener, so it can be added to
+/// a [`ConcurrentListener`](tide::listener::ConcurrentListener) next to the application's own
+/// listeners and runs for as long as they do. Other connections to its socket are closed after
+/// the handshake. Declare the side port with
+/// [`AcmeConfig::tls_alpn_port_mapping`](crate::AcmeConfig::tls_alpn_port_mapping).
+///
+/// ```no_run
+/// use tide_acme::{AcmeConfig, AcmeTlsAcceptor, ChallengeListener, TideRustlsExt};
+///
+/// # async_std::task::block_on(async {
+/// let config = AcmeConfig::new(vec!["domain.example"]).tls_alpn_port_mapping(5001);
+/// let acceptor = AcmeTlsAcceptor::new(config);
+/// let app = tide::new();
+/// let mut listener = tide::listener::ConcurrentListener::new();
+/// listener.add(ChallengeListener::new(acceptor.clone(), "127.0.0.1:5001")?)?;
+/// listener.add(
+///     tide_rustls::TlsListener::build()
+///         .addrs("0.0.0.0:8443")
+///         .acme_acceptor(acceptor),
+/// )?;
+/// app.listen(listener).await?;
+/// # tide::Result::Ok(())
+/// # });
+/// ```
+pub struct ChallengeListener {
+    acceptor: AcmeTlsAcceptor,
+    addrs: Vec<SocketAddr>,
+    listener: Option<TcpListener>,
+}
+
+impl ChallengeListener {
+    /// Create a listener answering validation requests through `acceptor` on a socket bound to
+    /// the first of `addrs` that works, once the application starts listening. Returns an error
+    /// if `addrs` can't be resolved.
+    ///
+    /// The socket is bound with the acceptor's
+    /// [`listener_options`](AcmeTlsAcceptor::listener_options), and its connections count
+    /// towards the acceptor's connection limit.
+    pub fn new(acceptor: AcmeTlsAcceptor, addrs: impl std::net::ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self {
+            acceptor,
+            addrs: addrs.to_socket_addrs()?.collect(),
+            listener: None,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Send + Sync + 'static> Listener<State> for ChallengeListener {
+    async fn bind(&mut self, _app: Server<State>) -> io::Result<()> {
+        let options = &self.acceptor.listener_options;
+        self.listener = Some(options.bind_async(&self.addrs[..]).await?);
+        Ok(())
+    }
+
+    async fn accept(&mut self) -> io::Result<()> {
+        let listener = self
+            .listener
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "listener isn't bound"))?;
+        let acceptor = &self.acceptor;
+        logged(acceptor.logging, acceptor.serve_challenges(listener)).await
+    }
+
+    fn info(&self) -> Vec<ListenInfo> {
+        self.local_addr()
+            .map(|addr| ListenInfo::new(addr.to_string(), "tcp".into(), true))
+            .into_iter()
+            .collect()
+    }
+}
+
+impl<State: Clone + Send + Sync + 'static> ToListener<State> for ChallengeListener {
+    type Listener = Self;
+
+    fn to_listener(self) -> io::Result<Self> {
+        Ok(self)
+    }
+}
+
+impl ChallengeListener {
+    /// The address of the bound socket, or the first address to bind to if it isn't bound yet.
+    fn local_addr(&self) -> Option<SocketAddr> {
+        match &self.listener {
+            Some(listener) => listener.local_addr().ok(),
+            None => self.addrs.first().copied(),
+        }
+    }
+}
+
+impl Debug for ChallengeListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChallengeListener")
+            .field("addrs", &self.addrs)
+            .field("bound", &self.listener.is_some())
+            .finish()
+    }
+}
+
+impl Display for ChallengeListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.local_addr() {
+            Some(addr) => write!(f, "acme-tls/1 validation on {}", addr),
+            None => write!(f, "acme-tls/1 validation"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::net::TcpStream;
+    use async_std::task::block_on;
+    use futures_lite::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::AcmeConfig;
+
+    fn listener(addrs: &str) -> ChallengeListener {
+        let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(Vec::<String>::new()));
+        ChallengeListener::new(acceptor, addrs).unwrap()
+    }
+
+    #[test]
+    fn describes_the_socket() {
+        let mut listener = listener("127.0.0.1:0");
+        assert_eq!(listener.to_string(), "acme-tls/1 validation on 127.0.0.1:0");
+        block_on(Listener::<()>::bind(&mut listener, tide::new())).unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        let info = Listener::<()>::info(&listener);
+        assert_eq!(info.len(), 1);
+        assert_eq!(info[0].connection(), addr.to_string());
+        assert!(info[0].is_encrypted());
+    }
+
+    #[test]
+    fn must_be_bound_to_accept() {
+        let mut listener = listener("127.0.0.1:0");
+        let err = block_on(Listener::<()>::accept(&mut listener)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn closes_other_connections() {
+        let mut listener = listener("127.0.0.1:0");
+        block_on(Listener::<()>::bind(&mut listener, tide::new())).unwrap();
+        let addr = listener.local_addr().unwrap();
+        async_std::task::spawn(async move { Listener::<()>::accept(&mut listener).await });
+        block_on(async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"not a client hello").await.unwrap();
+            let mut buf = Vec::new();
+            let _ = stream.read_to_end(&mut buf).await;
+            assert!(buf.is_empty());
+        });
+    }
+}
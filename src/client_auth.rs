@@ -0,0 +1,311 @@
+//! Verifying the certificates clients present on listeners requiring or accepting them.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use thiserror::Error;
+use tide_rustls::async_rustls::webpki::DNSName;
+use tide_rustls::rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, Certificate,
+    ClientCertVerified, ClientCertVerifier, DistinguishedNames, RootCertStore, TLSError,
+};
+use x509_parser::error::X509Error;
+use x509_parser::{parse_x509_certificate, parse_x509_crl};
+
+/// How a listener verifies client certificates; see
+/// [`AcmeTlsAcceptor::client_auth`](crate::AcmeTlsAcceptor::client_auth).
+///
+/// Clients must present a certificate issued by one of the roots, unless verification is made
+/// [optional](Self::optional). Certificates listed in a [revocation list](Self::crl) are refused.
+///
+/// ```no_run
+/// use tide_acme::ClientAuth;
+/// use tide_rustls::rustls::RootCertStore;
+///
+/// # fn example(internal_ca: RootCertStore) -> Result<(), Box<dyn std::error::Error>> {
+/// let client_auth = ClientAuth::new(internal_ca).crl(&std::fs::read("internal-ca.crl")?)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ClientAuth {
+    roots: RootCertStore,
+    optional: bool,
+    /// The issuer names and serial numbers of the revoked certificates, as DER.
+    revoked: Arc<HashSet<(Vec<u8>, Vec<u8>)>>,
+}
+
+impl ClientAuth {
+    /// Require clients to present a certificate issued by one of `roots`.
+    pub fn new(roots: RootCertStore) -> Self {
+        Self {
+            roots,
+            optional: false,
+            revoked: Arc::default(),
+        }
+    }
+
+    /// Also accept clients presenting no certificate if `optional` is true, still verifying
+    /// those that do present one. Defaults to false.
+    ///
+    /// Handlers tell the two apart by the
+    /// [`client_certificates`](crate::TlsConnectionInfo::client_certificates) of the connection.
+    /// Unlike with mandatory verification, ACME validators can connect, so validation requests
+    /// are answered on these listeners too.
+    pub fn optional(mut self, optional: bool) -> Self {
+        self.optional = optional;
+        self
+    }
+
+    /// Refuse the client certificates revoked by the certificate revocation list `crl`, as DER
+    /// or PEM. Call again for the lists of other issuers.
+    ///
+    /// The list's signature and validity period are not checked: it is configuration, to be
+    /// obtained from a trusted source and replaced with the next one by rebuilding the
+    /// acceptor.
+    pub fn crl(mut self, crl: &[u8]) -> Result<Self, CrlError> {
+        let der = match pem::parse(crl) {
+            Ok(pem) => pem.contents,
+            Err(_) => crl.to_vec(),
+        };
+        let (_, crl) = parse_x509_crl(&der)?;
+        let issuer = crl.issuer().as_raw().to_vec();
+        let revoked = Arc::make_mut(&mut self.revoked);
+        for cert in crl.iter_revoked_certificates() {
+            revoked.insert((issuer.clone(), cert.raw_serial().to_vec()));
+        }
+        Ok(self)
+    }
+
+    pub(crate) fn verifier(&self) -> Arc<dyn ClientCertVerifier> {
+        let inner = match self.optional {
+            true => AllowAnyAnonymousOrAuthenticatedClient::new(self.roots.clone()),
+            false => AllowAnyAuthenticatedClient::new(self.roots.clone()),
+        };
+        match self.revoked.is_empty() {
+            true => inner,
+            false => Arc::new(RevocationCheck {
+                inner,
+                revoked: self.revoked.clone(),
+            }),
+        }
+    }
+}
+
+impl From<RootCertStore> for ClientAuth {
+    fn from(roots: RootCertStore) -> Self {
+        Self::new(roots)
+    }
+}
+
+/// Refuses client certificates listed as revoked, after verifying them as `inner` does.
+struct RevocationCheck {
+    inner: Arc<dyn ClientCertVerifier>,
+    revoked: Arc<HashSet<(Vec<u8>, Vec<u8>)>>,
+}
+
+impl ClientCertVerifier for RevocationCheck {
+    fn offer_client_auth(&self) -> bool {
+        self.inner.offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self, sni: Option<&DNSName>) -> Option<bool> {
+        self.inner.client_auth_mandatory(sni)
+    }
+
+    fn client_auth_root_subjects(&self, sni: Option<&DNSName>) -> Option<DistinguishedNames> {
+        self.inner.client_auth_root_subjects(sni)
+    }
+
+    fn verify_client_cert(
+        &self,
+        presented_certs: &[Certificate],
+        sni: Option<&DNSName>,
+    ) -> Result<ClientCertVerified, TLSError> {
+        let verified = self.inner.verify_client_cert(presented_certs, sni)?;
+        for cert in presented_certs {
+            let (_, cert) = parse_x509_certificate(&cert.0)
+                .map_err(|_| TLSError::General("unparseable client certificate".into()))?;
+            let key = (
+                cert.issuer().as_raw().to_vec(),
+                cert.tbs_certificate.raw_serial().to_vec(),
+            );
+            if self.revoked.contains(&key) {
+                return Err(TLSError::General("client certificate revoked".into()));
+            }
+        }
+        Ok(verified)
+    }
+}
+
+/// Errors reading certificate revocation lists.
+#[derive(Error, Debug)]
+pub enum CrlError {
+    /// The list couldn't be parsed.
+    #[error("could not parse certificate revocation list: {0}")]
+    Parse(#[from] x509_parser::nom::Err<X509Error>),
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa};
+    use tide_rustls::async_rustls::webpki::DNSNameRef;
+    use tide_rustls::rustls::{
+        ClientConfig, ClientSession, PrivateKey, ServerConfig, ServerSession, Session,
+    };
+
+    use super::*;
+    use crate::der::der_encode;
+    use crate::TlsConnectionInfo;
+
+    struct Pki {
+        ca: rcgen::Certificate,
+        roots: RootCertStore,
+        client: Vec<u8>,
+        client_key: Vec<u8>,
+    }
+
+    impl Pki {
+        /// A CA and a client certificate it issued for `service.internal`.
+        fn new() -> Self {
+            let mut params = CertificateParams::new(vec![]);
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = rcgen::Certificate::from_params(params).unwrap();
+            let mut roots = RootCertStore::empty();
+            roots
+                .add(&Certificate(ca.serialize_der().unwrap()))
+                .unwrap();
+            let mut params = CertificateParams::new(vec!["service.internal".into()]);
+            params.serial_number = Some(1);
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "service.internal");
+            let client = rcgen::Certificate::from_params(params).unwrap();
+            Pki {
+                client: client.serialize_der_with_signer(&ca).unwrap(),
+                client_key: client.serialize_private_key_der(),
+                roots,
+                ca,
+            }
+        }
+    }
+
+    /// A revocation list of `issuer` listing the certificate `cert`, unsigned.
+    fn crl(issuer: &rcgen::Certificate, cert: &[u8]) -> Vec<u8> {
+        let issuer = issuer.serialize_der().unwrap();
+        let (_, issuer) = parse_x509_certificate(&issuer).unwrap();
+        let (_, cert) = parse_x509_certificate(cert).unwrap();
+        // ecdsa-with-SHA256
+        let oid = [0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+        let algorithm = der_encode(0x30, &der_encode(0x06, &oid));
+        let time = der_encode(0x17, b"200913122640Z");
+        let entry = [
+            der_encode(0x02, cert.tbs_certificate.raw_serial()),
+            time.clone(),
+        ]
+        .concat();
+        let list = [
+            algorithm.clone(),
+            issuer.subject().as_raw().to_vec(),
+            time,
+            der_encode(0x30, &der_encode(0x30, &entry)),
+        ]
+        .concat();
+        let list = [der_encode(0x30, &list), algorithm, der_encode(0x03, &[0])].concat();
+        der_encode(0x30, &list)
+    }
+
+    /// Perform a handshake with a listener verifying clients as `client_auth` does, presenting
+    /// `client_cert`, issued by `pki`'s CA for its client key, if any.
+    fn handshake(
+        client_auth: &ClientAuth,
+        pki: &Pki,
+        client_cert: Option<&[u8]>,
+    ) -> Result<ServerSession, TLSError> {
+        let server_cert = rcgen::generate_simple_self_signed(vec!["a.example".into()]).unwrap();
+        let der = server_cert.serialize_der().unwrap();
+        let mut server_config = ServerConfig::new(client_auth.verifier());
+        server_config
+            .set_single_cert(
+                vec![Certificate(der.clone())],
+                PrivateKey(server_cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let mut client_config = ClientConfig::new();
+        client_config.root_store.add(&Certificate(der)).unwrap();
+        if let Some(client_cert) = client_cert {
+            let chain = vec![Certificate(client_cert.to_vec())];
+            let key = PrivateKey(pki.client_key.clone());
+            client_config.set_single_client_cert(chain, key).unwrap();
+        }
+        let name = DNSNameRef::try_from_ascii_str("a.example").unwrap();
+        let mut client = ClientSession::new(&Arc::new(client_config), name);
+        let mut server = ServerSession::new(&Arc::new(server_config));
+        while client.is_handshaking() || server.is_handshaking() {
+            let mut buf = Vec::new();
+            while client.wants_write() {
+                client.write_tls(&mut buf).unwrap();
+            }
+            server.read_tls(&mut &buf[..]).unwrap();
+            server.process_new_packets()?;
+            let mut buf = Vec::new();
+            while server.wants_write() {
+                server.write_tls(&mut buf).unwrap();
+            }
+            client.read_tls(&mut &buf[..]).unwrap();
+            client.process_new_packets()?;
+        }
+        Ok(server)
+    }
+
+    #[test]
+    fn requires_client_certificates() {
+        let pki = Pki::new();
+        let client_auth = ClientAuth::from(pki.roots.clone());
+        assert!(handshake(&client_auth, &pki, None).is_err());
+        let server = handshake(&client_auth, &pki, Some(&pki.client)).unwrap();
+        let info = TlsConnectionInfo::new(&server);
+        assert_eq!(info.client_certificates[0], pki.client);
+        assert_eq!(info.client_subject.as_deref(), Some("CN=service.internal"));
+
+        let other = Pki::new();
+        assert!(handshake(&client_auth, &other, Some(&other.client)).is_err());
+    }
+
+    #[test]
+    fn optionally_accepts_anonymous_clients() {
+        let pki = Pki::new();
+        let client_auth = ClientAuth::new(pki.roots.clone()).optional(true);
+        let server = handshake(&client_auth, &pki, None).unwrap();
+        let info = TlsConnectionInfo::new(&server);
+        assert!(info.client_certificates.is_empty());
+        assert_eq!(info.client_subject, None);
+        let server = handshake(&client_auth, &pki, Some(&pki.client)).unwrap();
+        assert!(TlsConnectionInfo::new(&server).client_subject.is_some());
+    }
+
+    #[test]
+    fn refuses_revoked_certificates() {
+        let pki = Pki::new();
+        let crl = crl(&pki.ca, &pki.client);
+        let pem = pem::encode(&pem::Pem {
+            tag: "X509 CRL".into(),
+            contents: crl.clone(),
+        });
+        for crl in [crl.clone(), pem.into_bytes()].iter() {
+            let client_auth = ClientAuth::new(pki.roots.clone()).crl(crl).unwrap();
+            assert!(handshake(&client_auth, &pki, Some(&pki.client)).is_err());
+        }
+
+        // Another certificate of the same client, with another serial number.
+        let mut params = CertificateParams::new(vec!["service.internal".into()]);
+        params.serial_number = Some(2);
+        params.key_pair = Some(rcgen::KeyPair::from_der(&pki.client_key).unwrap());
+        let renewed = rcgen::Certificate::from_params(params).unwrap();
+        let renewed = renewed.serialize_der_with_signer(&pki.ca).unwrap();
+        let client_auth = ClientAuth::new(pki.roots.clone()).crl(&crl).unwrap();
+        assert!(handshake(&client_auth, &pki, Some(&renewed)).is_ok());
+
+        assert!(ClientAuth::new(pki.roots).crl(b"junk").is_err());
+    }
+}
@@ -0,0 +1,337 @@
+//! Peeking at the TLS ClientHello of a connection, before the handshake consumes it.
+
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_std::future::timeout;
+use async_std::net::TcpStream;
+use tide_rustls::rustls::ResolvesServerCert;
+
+/// The content type of TLS handshake records.
+const HANDSHAKE_RECORD: u8 = 22;
+/// The type of the ClientHello handshake message.
+const CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const EXTENSION_SUPPORTED_GROUPS: u16 = 10;
+const EXTENSION_EC_POINT_FORMATS: u16 = 11;
+const EXTENSION_ALPN: u16 = 16;
+/// The largest record payload TLS allows, plus the overhead allowed for compressed records.
+const MAX_RECORD_LEN: usize = 16384 + 2048;
+
+/// How long to wait for the ClientHello before closing the connection.
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait before peeking again at a ClientHello that arrived in pieces.
+const PEEK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What a TLS ClientHello asks for and offers, as passed to
+/// [`AcmeConfig::inspect_client_hello`](crate::AcmeConfig::inspect_client_hello).
+///
+/// Lists are in the order the client sent them, which tells client implementations apart.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct ClientHelloInfo {
+    /// The server name asked for via SNI, normalized to lowercase without a trailing dot.
+    pub server_name: Option<String>,
+    /// The ALPN protocols offered.
+    pub alpn: Vec<Vec<u8>>,
+    /// The legacy protocol version field, `0x0303` for TLS 1.2 and later.
+    pub version: u16,
+    /// The cipher suites offered, by their IANA code points.
+    pub cipher_suites: Vec<u16>,
+    /// The types of the extensions sent.
+    pub extensions: Vec<u16>,
+    /// The key exchange groups offered in the supported groups extension.
+    pub supported_groups: Vec<u16>,
+    /// The elliptic curve point formats offered.
+    pub ec_point_formats: Vec<u8>,
+}
+
+impl ClientHelloInfo {
+    /// The JA3 fingerprint string of the ClientHello: the version, cipher suites, extensions,
+    /// supported groups and point formats as decimal numbers, without GREASE values. Hash it
+    /// with MD5 to compare it with published JA3 hashes.
+    pub fn ja3(&self) -> String {
+        fn list<T: Copy + Into<u16> + fmt::Display>(values: &[T]) -> String {
+            values
+                .iter()
+                .filter(|value| !is_grease((**value).into()))
+                .map(|value| value.to_string())
+                .collect::<Vec<_>>()
+                .join("-")
+        }
+        format!(
+            "{},{},{},{},{}",
+            self.version,
+            list(&self.cipher_suites),
+            list(&self.extensions),
+            list(&self.supported_groups),
+            list(&self.ec_point_formats)
+        )
+    }
+}
+
+/// Whether `value` is one of the values clients send to keep servers tolerant of unknown ones,
+/// per RFC 8701.
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+/// What to do with a TLS handshake, as decided by
+/// [`AcmeConfig::inspect_client_hello`](crate::AcmeConfig::inspect_client_hello).
+#[derive(Clone)]
+#[non_exhaustive]
+pub enum HelloDecision {
+    /// Go on with the handshake.
+    Proceed,
+    /// Close the connection without an answer.
+    Reject,
+    /// Go on with the handshake, serving the certificate chosen by this resolver rather than
+    /// the managed certificates.
+    Resolve(Arc<dyn ResolvesServerCert>),
+}
+
+impl fmt::Debug for HelloDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Proceed => f.write_str("Proceed"),
+            Self::Reject => f.write_str("Reject"),
+            Self::Resolve(_) => f.write_str("Resolve(..)"),
+        }
+    }
+}
+
+/// Wait for the first TLS record of `stream` without consuming it, and parse the ClientHello it
+/// carries. Returns `None` if the record isn't a ClientHello that fits in it, leaving the TLS
+/// handshake to reject it or read the rest.
+pub(crate) async fn peek(stream: &TcpStream) -> Result<Option<ClientHelloInfo>> {
+    let mut buf = vec![0; 5 + MAX_RECORD_LEN];
+    let end = timeout(CLIENT_HELLO_TIMEOUT, async {
+        let mut needed = 5;
+        loop {
+            let len = stream.peek(&mut buf).await?;
+            if len == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed"));
+            }
+            if len >= 5 && needed == 5 {
+                if buf[0] != HANDSHAKE_RECORD {
+                    return Ok(None);
+                }
+                needed += (u16::from_be_bytes([buf[3], buf[4]]) as usize).min(MAX_RECORD_LEN);
+            }
+            if len >= needed {
+                return Ok(Some(needed));
+            }
+            async_std::task::sleep(PEEK_INTERVAL).await;
+        }
+    })
+    .await
+    .map_err(|_| Error::new(ErrorKind::TimedOut, "timed out waiting for the ClientHello"))??;
+    Ok(end.and_then(|end| parse(&buf[5..end])))
+}
+
+/// Parse the ClientHello handshake message at the start of `record`.
+fn parse(record: &[u8]) -> Option<ClientHelloInfo> {
+    let mut msg = Reader(record);
+    if msg.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    let len = msg.u24()?;
+    let mut body = Reader(msg.take(len)?);
+    let mut hello = ClientHelloInfo {
+        version: body.u16()?,
+        ..ClientHelloInfo::default()
+    };
+    // The random.
+    body.take(32)?;
+    let session_id = body.u8()? as usize;
+    body.take(session_id)?;
+    let cipher_suites = body.u16()? as usize;
+    let mut cipher_suites = Reader(body.take(cipher_suites)?);
+    while !cipher_suites.0.is_empty() {
+        hello.cipher_suites.push(cipher_suites.u16()?);
+    }
+    let compression_methods = body.u8()? as usize;
+    body.take(compression_methods)?;
+    if body.0.is_empty() {
+        return Some(hello);
+    }
+    let extensions_len = body.u16()? as usize;
+    let mut extensions = Reader(body.take(extensions_len)?);
+    while !extensions.0.is_empty() {
+        let typ = extensions.u16()?;
+        let len = extensions.u16()? as usize;
+        let mut data = Reader(extensions.take(len)?);
+        hello.extensions.push(typ);
+        match typ {
+            EXTENSION_SERVER_NAME => {
+                let list_len = data.u16()? as usize;
+                let mut list = Reader(data.take(list_len)?);
+                while !list.0.is_empty() {
+                    let name_type = list.u8()?;
+                    let len = list.u16()? as usize;
+                    let name = list.take(len)?;
+                    // Only host names are defined.
+                    if name_type == 0 {
+                        let name = std::str::from_utf8(name).ok()?;
+                        hello.server_name = Some(name.trim_end_matches('.').to_ascii_lowercase());
+                    }
+                }
+            }
+            EXTENSION_ALPN => {
+                let list_len = data.u16()? as usize;
+                let mut list = Reader(data.take(list_len)?);
+                while !list.0.is_empty() {
+                    let len = list.u8()? as usize;
+                    hello.alpn.push(list.take(len)?.to_vec());
+                }
+            }
+            EXTENSION_SUPPORTED_GROUPS => {
+                let list_len = data.u16()? as usize;
+                let mut list = Reader(data.take(list_len)?);
+                while !list.0.is_empty() {
+                    hello.supported_groups.push(list.u16()?);
+                }
+            }
+            EXTENSION_EC_POINT_FORMATS => {
+                let list_len = data.u8()? as usize;
+                hello.ec_point_formats = data.take(list_len)?.to_vec();
+            }
+            _ => {}
+        }
+    }
+    Some(hello)
+}
+
+/// Reads big-endian integers and length-prefixed data off the front of a slice.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        let bytes = self.take(3)?;
+        Some(usize::from(bytes[0]) << 16 | usize::from(bytes[1]) << 8 | usize::from(bytes[2]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `data` prefixed with its length in `len` big-endian bytes.
+    fn prefixed(len: usize, data: &[u8]) -> Vec<u8> {
+        [&data.len().to_be_bytes()[8 - len..], data].concat()
+    }
+
+    fn extension(typ: u16, data: &[u8]) -> Vec<u8> {
+        [&typ.to_be_bytes()[..], &prefixed(2, data)].concat()
+    }
+
+    /// A ClientHello handshake message with `extensions`, if any.
+    fn client_hello(extensions: Option<&[u8]>) -> Vec<u8> {
+        let mut body = [0x03, 0x03].to_vec();
+        body.extend([0; 32]);
+        body.extend(prefixed(1, &[7; 32]));
+        body.extend(prefixed(2, &[0x0a, 0x0a, 0x13, 0x01, 0xc0, 0x2b]));
+        body.extend(prefixed(1, &[0]));
+        if let Some(extensions) = extensions {
+            body.extend(prefixed(2, extensions));
+        }
+        [&[CLIENT_HELLO][..], &prefixed(3, &body)].concat()
+    }
+
+    fn extensions() -> Vec<u8> {
+        let name = [&[0][..], &prefixed(2, b"Domain.Example.")].concat();
+        let alpn = [prefixed(1, b"h2"), prefixed(1, b"http/1.1")].concat();
+        [
+            extension(0x1a1a, &[]),
+            extension(EXTENSION_SERVER_NAME, &prefixed(2, &name)),
+            extension(EXTENSION_ALPN, &prefixed(2, &alpn)),
+            extension(EXTENSION_SUPPORTED_GROUPS, &prefixed(2, &[0, 29, 0, 23])),
+            extension(EXTENSION_EC_POINT_FORMATS, &prefixed(1, &[0])),
+            extension(0xff01, &[0]),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn parses_client_hellos() {
+        let hello = parse(&client_hello(Some(&extensions()))).unwrap();
+        assert_eq!(hello.server_name.as_deref(), Some("domain.example"));
+        assert_eq!(hello.alpn, [&b"h2"[..], b"http/1.1"]);
+        assert_eq!(hello.version, 0x0303);
+        assert_eq!(hello.cipher_suites, [0x0a0a, 0x1301, 0xc02b]);
+        assert_eq!(hello.extensions, [0x1a1a, 0, 16, 10, 11, 0xff01]);
+        assert_eq!(hello.supported_groups, [29, 23]);
+        assert_eq!(hello.ec_point_formats, [0]);
+        assert_eq!(hello.ja3(), "771,4865-49195,0-16-10-11-65281,29-23,0");
+
+        let hello = parse(&client_hello(None)).unwrap();
+        assert_eq!(hello.server_name, None);
+        assert!(hello.alpn.is_empty());
+        assert!(hello.extensions.is_empty());
+        assert_eq!(hello.ja3(), "771,4865-49195,,,");
+    }
+
+    #[test]
+    fn recognizes_grease_values() {
+        for &value in [0x0a0a, 0x1a1a, 0xfafa].iter() {
+            assert!(is_grease(value));
+        }
+        for &value in [0x0a1a, 0x1301, 0x0a0b, 0].iter() {
+            assert!(!is_grease(value));
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_client_hellos() {
+        let record = client_hello(Some(&extensions()));
+        for len in 0..record.len() {
+            assert!(parse(&record[..len]).is_none(), "parsed {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_lengths() {
+        let mut record = client_hello(Some(&extensions()));
+        record[0] = 2;
+        assert!(parse(&record).is_none());
+
+        // An extension running past the end of the extensions.
+        let overrun = [&[0xff, 0x01, 0x00, 0x05][..], &[0; 4]].concat();
+        assert!(parse(&client_hello(Some(&overrun))).is_none());
+        // A server name list longer than its extension.
+        let names = extension(EXTENSION_SERVER_NAME, &[0x00, 0x10, 0x00]);
+        assert!(parse(&client_hello(Some(&names))).is_none());
+        // A server name running past the end of its list.
+        let name = extension(EXTENSION_SERVER_NAME, &prefixed(2, &[0, 0, 9, b'a']));
+        assert!(parse(&client_hello(Some(&name))).is_none());
+        // A protocol name running past the end of its list.
+        let alpn = extension(EXTENSION_ALPN, &prefixed(2, &[5, b'h', b'2']));
+        assert!(parse(&client_hello(Some(&alpn))).is_none());
+        // An odd number of bytes of supported groups.
+        let groups = extension(EXTENSION_SUPPORTED_GROUPS, &prefixed(2, &[0, 29, 0]));
+        assert!(parse(&client_hello(Some(&groups))).is_none());
+        // Point formats longer than their extension.
+        let formats = extension(EXTENSION_EC_POINT_FORMATS, &[3, 0]);
+        assert!(parse(&client_hello(Some(&formats))).is_none());
+    }
+}
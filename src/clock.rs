@@ -0,0 +1,97 @@
+//! The clock certificate management goes by.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use async_std::task;
+use async_trait::async_trait;
+use tracing::warn;
+
+/// The longest to sleep before checking the wall clock again while waiting.
+const CLOCK_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How far the wall clock may move relative to the monotonic clock while sleeping before a
+/// warning about a clock jump is logged.
+const MAX_CLOCK_JUMP: Duration = Duration::from_secs(30);
+
+/// The time certificate management goes by: when certificates are due for renewal, how long to
+/// back off after failed orders, and whether certificates have expired.
+///
+/// Set one with [`AcmeConfig::clock`](crate::AcmeConfig::clock). The default, [`SystemClock`],
+/// follows the system's wall clock. Tests can substitute a clock they control, such as
+/// `tide_acme::test::ManualClock` with the `test-util` feature, to exercise renewal, backoff and
+/// expiry warnings without waiting days. Exchanges with the CA, such as polling an order, and the
+/// validity of OCSP responses still go by the system clock.
+#[async_trait]
+pub trait Clock: Send + Sync + 'static {
+    /// The current time.
+    fn now(&self) -> SystemTime;
+
+    /// Wait until the time is `at`, or return right away if it is already past.
+    async fn sleep_until(&self, at: SystemTime);
+
+    /// Wait for `duration` to pass.
+    async fn sleep(&self, duration: Duration) {
+        self.sleep_until(self.now() + duration).await
+    }
+}
+
+/// The system's wall clock, the default [`Clock`].
+///
+/// While waiting, it checks the wall clock every minute rather than sleeping for the whole time
+/// at once, so that when it jumps, such as after an NTP correction or when a virtual machine
+/// resumes, the wait ends at the right time instead of days late or early.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+#[async_trait]
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    async fn sleep_until(&self, at: SystemTime) {
+        loop {
+            let wall = SystemTime::now();
+            let remaining = match at.duration_since(wall) {
+                Ok(remaining) if !remaining.is_zero() => remaining,
+                _ => return,
+            };
+            let monotonic = Instant::now();
+            task::sleep(remaining.min(CLOCK_CHECK_INTERVAL)).await;
+            let elapsed = monotonic.elapsed();
+            let jump = match SystemTime::now().duration_since(wall) {
+                Ok(wall_elapsed) if wall_elapsed >= elapsed => {
+                    (wall_elapsed - elapsed).as_secs() as i64
+                }
+                Ok(wall_elapsed) => -((elapsed - wall_elapsed).as_secs() as i64),
+                Err(behind) => -((elapsed + behind.duration()).as_secs() as i64),
+            };
+            if jump.unsigned_abs() > MAX_CLOCK_JUMP.as_secs() {
+                warn!(
+                    jump_secs = jump,
+                    "the system clock jumped; re-evaluating the renewal schedule"
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::block_on;
+
+    use super::*;
+
+    #[test]
+    fn sleeps_until_wall_clock_times() {
+        block_on(async {
+            let started = Instant::now();
+            SystemClock
+                .sleep_until(SystemTime::now() - Duration::from_secs(60))
+                .await;
+            assert!(started.elapsed() < Duration::from_millis(50));
+            SystemClock.sleep(Duration::from_millis(100)).await;
+            assert!(started.elapsed() >= Duration::from_millis(100));
+        });
+    }
+}
@@ -0,0 +1,2012 @@
+use std::convert::Infallible;
+use std::fmt::Debug;
+use std::future::Future;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use rustls_acme::caches::{BoxedErrCache, CompositeCache, NoCache};
+use rustls_acme::{AccountCache, Cache, CertCache};
+use tide_rustls::rustls::{ClientConfig, ServerConfig};
+use tracing::{warn, Level};
+
+use crate::acme::{
+    GOOGLE_TRUST_SERVICES_PRODUCTION_DIRECTORY, GOOGLE_TRUST_SERVICES_STAGING_DIRECTORY,
+    LETS_ENCRYPT_PRODUCTION_DIRECTORY, LETS_ENCRYPT_STAGING_DIRECTORY, ZEROSSL_DIRECTORY,
+};
+use crate::challenge::Dns01Responder;
+use crate::dns::DnsResolution;
+use crate::https::ClientTls;
+use crate::jose::ExternalAccountKey;
+use crate::state::AcmeState;
+use crate::{
+    AccountKeyAlgorithm, AcmeEvent, CertKeyAlgorithm, CertRotation, CertStatus, CertificateSource,
+    ChallengeDelegation, ChallengeResponder, ClientHelloInfo, Clock, Csr, DnsProvider,
+    DomainPolicy, ErrorReporter, HelloDecision, KeyProvider, KeyStore, ListenerOptions, Lock,
+    MissingSni, RateLimits, RenewalPolicy, RetryPolicy, SessionResumption, Snapshot, SniPriority,
+    StaleOcspPolicy, StaticCertificate, SystemClock,
+};
+
+/// Configuration for obtaining certificates via ACME.
+///
+/// `EC` and `EA` are the error types of the certificate cache and account cache, respectively.
+pub struct AcmeConfig<EC: Debug, EA: Debug = EC> {
+    pub(crate) name: Option<String>,
+    pub(crate) directory_url: String,
+    pub(crate) domains: Vec<String>,
+    pub(crate) contact: Vec<String>,
+    pub(crate) external_account_key: Option<ExternalAccountKey>,
+    pub(crate) directory_tls: ClientTls,
+    pub(crate) cache: Arc<dyn Cache<EC = EC, EA = EA>>,
+    /// The cache backend's type name, for the configuration summary.
+    pub(crate) cache_name: &'static str,
+    pub(crate) secondary_cache: Option<Box<dyn Cache<EC = EC, EA = EA>>>,
+    pub(crate) cache_store_retries: u32,
+    pub(crate) cache_store_fatal: bool,
+    pub(crate) account_key_algorithm: AccountKeyAlgorithm,
+    pub(crate) cert_per_domain: bool,
+    pub(crate) max_concurrent_orders: usize,
+    pub(crate) order_rate: Option<(u32, Duration)>,
+    pub(crate) retry_policy: RetryPolicy,
+    pub(crate) renewal_policy: RenewalPolicy,
+    pub(crate) rate_limits: Option<RateLimits>,
+    pub(crate) max_sans_per_cert: usize,
+    pub(crate) ephemeral: bool,
+    pub(crate) sni_override: Option<SniOverride>,
+    pub(crate) missing_sni: MissingSni,
+    pub(crate) default_domain: Option<String>,
+    pub(crate) sni_priority: SniPriority,
+    pub(crate) self_signed_fallback: bool,
+    pub(crate) tls_alpn_port: Option<u16>,
+    pub(crate) previous_certs: usize,
+    pub(crate) event_history: usize,
+    pub(crate) snapshot: Option<Snapshot>,
+    pub(crate) error_reporter: Option<Arc<dyn ErrorReporter>>,
+    pub(crate) domain_reporters: Vec<(Vec<String>, Arc<dyn ErrorReporter>)>,
+    pub(crate) challenge_responders: Vec<Arc<dyn ChallengeResponder>>,
+    pub(crate) dns_resolution: Arc<Mutex<DnsResolution>>,
+    pub(crate) wait_for_not_before: bool,
+    pub(crate) ocsp_stapling: bool,
+    pub(crate) must_staple: bool,
+    pub(crate) stale_ocsp_policy: StaleOcspPolicy,
+    pub(crate) directory_groups: Vec<DirectoryGroup<EC, EA>>,
+    pub(crate) staging_check: bool,
+    pub(crate) preflight_check: bool,
+    pub(crate) challenge_close: ChallengeClose,
+    pub(crate) listener_options: ListenerOptions,
+    pub(crate) order_lock: Option<Arc<dyn Lock>>,
+    pub(crate) challenge_delegation: Option<ChallengeDelegation>,
+    pub(crate) logging: bool,
+    pub(crate) tracing_level: Level,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) http01: bool,
+    pub(crate) on_demand: Option<Arc<dyn DomainPolicy>>,
+    pub(crate) on_demand_wait: Duration,
+    pub(crate) key_provider: Option<Arc<dyn KeyProvider>>,
+    pub(crate) key_store: Option<Arc<dyn KeyStore>>,
+    pub(crate) customize_csr: Option<CsrCustomizer>,
+    pub(crate) cert_key_algorithm: CertKeyAlgorithm,
+    pub(crate) reuse_cert_key: bool,
+    pub(crate) preferred_chain: Option<String>,
+    pub(crate) profile: Option<String>,
+    pub(crate) export_dir: Option<PathBuf>,
+    pub(crate) on_rotation: Option<RotationHook>,
+    pub(crate) domain_rotation_hooks: Vec<(Vec<String>, RotationHook)>,
+    pub(crate) on_cert_obtained: Option<RotationHook>,
+    pub(crate) on_order_failed: Option<OrderFailedHook>,
+    pub(crate) on_event: Option<EventCallback>,
+    pub(crate) expiry_alarms: Vec<Duration>,
+    pub(crate) handshake_filter: Option<HandshakeFilter>,
+    pub(crate) configure_rustls: Option<ConfigureRustls>,
+    pub(crate) keylog: bool,
+    pub(crate) session_resumption: Option<SessionResumption>,
+    pub(crate) inspect_client_hello: Option<ClientHelloInspector>,
+    #[cfg(feature = "watch")]
+    pub(crate) watch_cache_dir: Option<PathBuf>,
+}
+
+/// A bundle of defaults for [`AcmeConfig::preset`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Profile {
+    /// For trying things out: the Let's Encrypt staging directory, whose certificates browsers
+    /// don't trust but whose rate limits are generous, and short timeouts so mistakes show up
+    /// quickly.
+    Development,
+    /// For serving real traffic: the Let's Encrypt production directory, guarded by a
+    /// [staging check](AcmeConfig::staging_check) so configuration mistakes don't use up its
+    /// rate limits, and timeouts tolerant of slow validators.
+    Production,
+}
+
+/// How to close validation connections once the handshake is done.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ChallengeClose {
+    pub(crate) timeout: Duration,
+    pub(crate) close_notify: bool,
+}
+
+/// Domains obtaining certificates from a directory other than the main one, or from a
+/// [`CertificateSource`].
+pub(crate) struct DirectoryGroup<EC: Debug, EA: Debug> {
+    /// The directory URL, or the source's name.
+    pub(crate) directory_url: String,
+    pub(crate) source: Option<Arc<dyn CertificateSource>>,
+    pub(crate) domains: Vec<String>,
+    /// The group's own cache, or `None` to share the main one.
+    pub(crate) cache: Option<Box<dyn Cache<EC = EC, EA = EA>>>,
+    /// Whether the domains share one certificate, rather than being split like the main ones.
+    pub(crate) single_cert: bool,
+    /// Whether the group uses the main directory, whose URL is filled in on startup.
+    pub(crate) main_directory: bool,
+    /// The contact URLs of the group's account, or `None` to use the main ones.
+    pub(crate) contact: Option<Vec<String>>,
+}
+
+/// Callback mapping a handshake's server name to the name to resolve a certificate for.
+pub(crate) type SniOverride = Arc<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+/// Callback receiving each certificate as it is deployed.
+pub(crate) type RotationHook = Arc<dyn Fn(CertRotation) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Callback receiving each event of certificate management.
+pub(crate) type EventCallback = Arc<dyn Fn(&AcmeEvent) + Send + Sync>;
+
+/// Callback deciding whether to go on with a handshake, given the client's address and the
+/// server name it asks for.
+pub(crate) type HandshakeFilter =
+    Arc<dyn Fn(IpAddr, Option<String>) -> BoxFuture<'static, bool> + Send + Sync>;
+
+/// Callback deciding what to do with a handshake, given the client's address and its
+/// ClientHello.
+pub(crate) type ClientHelloInspector =
+    Arc<dyn Fn(IpAddr, &ClientHelloInfo) -> HelloDecision + Send + Sync>;
+
+/// Callback adjusting the certificate signing requests of new certificates.
+pub(crate) type CsrCustomizer = Arc<dyn Fn(&mut Csr) + Send + Sync>;
+
+/// Callback adjusting the rustls configuration the acceptor builds.
+pub(crate) type ConfigureRustls = Arc<dyn Fn(&mut ServerConfig) + Send + Sync>;
+
+pub(crate) type OrderFailedHook =
+    Arc<dyn Fn(AcmeEvent, CertStatus) -> BoxFuture<'static, ()> + Send + Sync>;
+
+impl AcmeConfig<Infallible, Infallible> {
+    /// Create a configuration to obtain a certificate for the specified domains, using the Let's
+    /// Encrypt staging directory and no cache.
+    ///
+    /// A domain may also be a public IP address, such as `203.0.113.7` or `2001:db8::7`, from CAs
+    /// that issue certificates for them, validated with http-01 or tls-alpn-01. Clients
+    /// connecting by IP address send no SNI, so the listeners serve them the certificate for the
+    /// local address the connection arrived on.
+    pub fn new(domains: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        AcmeConfig {
+            name: None,
+            directory_url: LETS_ENCRYPT_STAGING_DIRECTORY.into(),
+            domains: domains.into_iter().map(|s| s.as_ref().into()).collect(),
+            contact: vec![],
+            external_account_key: None,
+            directory_tls: ClientTls::default(),
+            cache: Arc::new(NoCache::new()),
+            cache_name: cache_name::<NoCache<Infallible, Infallible>>(),
+            secondary_cache: None,
+            cache_store_retries: 0,
+            cache_store_fatal: false,
+            account_key_algorithm: AccountKeyAlgorithm::default(),
+            cert_per_domain: false,
+            max_concurrent_orders: 4,
+            order_rate: None,
+            retry_policy: RetryPolicy::default(),
+            renewal_policy: RenewalPolicy::default(),
+            rate_limits: None,
+            max_sans_per_cert: 100,
+            ephemeral: false,
+            sni_override: None,
+            missing_sni: MissingSni::default(),
+            default_domain: None,
+            sni_priority: SniPriority::default(),
+            self_signed_fallback: false,
+            tls_alpn_port: None,
+            previous_certs: 1,
+            event_history: 100,
+            snapshot: None,
+            error_reporter: None,
+            domain_reporters: vec![],
+            challenge_responders: vec![],
+            dns_resolution: Arc::default(),
+            wait_for_not_before: false,
+            ocsp_stapling: false,
+            must_staple: false,
+            stale_ocsp_policy: StaleOcspPolicy::default(),
+            directory_groups: vec![],
+            staging_check: false,
+            preflight_check: false,
+            challenge_close: ChallengeClose {
+                timeout: Duration::from_secs(10),
+                close_notify: true,
+            },
+            listener_options: ListenerOptions::new(),
+            order_lock: None,
+            challenge_delegation: None,
+            logging: true,
+            tracing_level: Level::INFO,
+            clock: Arc::new(SystemClock),
+            http01: false,
+            on_demand: None,
+            on_demand_wait: Duration::from_secs(10),
+            key_provider: None,
+            key_store: None,
+            customize_csr: None,
+            cert_key_algorithm: CertKeyAlgorithm::default(),
+            reuse_cert_key: false,
+            preferred_chain: None,
+            profile: None,
+            export_dir: None,
+            on_rotation: None,
+            domain_rotation_hooks: vec![],
+            on_cert_obtained: None,
+            on_order_failed: None,
+            on_event: None,
+            expiry_alarms: vec![],
+            handshake_filter: None,
+            configure_rustls: None,
+            keylog: false,
+            session_resumption: None,
+            inspect_client_hello: None,
+            #[cfg(feature = "watch")]
+            watch_cache_dir: None,
+        }
+    }
+}
+
+impl<EC: 'static + Debug, EA: 'static + Debug> AcmeConfig<EC, EA> {
+    /// Set the URL of the ACME directory.
+    pub fn directory(mut self, directory_url: impl AsRef<str>) -> Self {
+        self.directory_url = directory_url.as_ref().into();
+        self
+    }
+
+    /// Use the Let's Encrypt production directory if `production` is true, or the staging
+    /// directory otherwise.
+    pub fn directory_lets_encrypt(mut self, production: bool) -> Self {
+        self.directory_url = match production {
+            true => LETS_ENCRYPT_PRODUCTION_DIRECTORY,
+            false => LETS_ENCRYPT_STAGING_DIRECTORY,
+        }
+        .into();
+        self
+    }
+
+    /// Use the ZeroSSL directory. ZeroSSL requires
+    /// [external account binding](Self::external_account_binding), with credentials from its
+    /// developer dashboard, and has no staging directory.
+    ///
+    /// ```no_run
+    /// use tide_acme::AcmeConfig;
+    ///
+    /// let config = AcmeConfig::new(["example.com"])
+    ///     .directory_zerossl()
+    ///     .external_account_binding("key-id", "base64url-hmac-key");
+    /// ```
+    pub fn directory_zerossl(mut self) -> Self {
+        self.directory_url = ZEROSSL_DIRECTORY.into();
+        self
+    }
+
+    /// Use the Google Trust Services production directory if `production` is true, or the
+    /// staging directory otherwise. Google Trust Services requires
+    /// [external account binding](Self::external_account_binding), with credentials from the
+    /// Google Cloud Public CA API; staging and production credentials differ.
+    pub fn directory_google_trust_services(mut self, production: bool) -> Self {
+        self.directory_url = match production {
+            true => GOOGLE_TRUST_SERVICES_PRODUCTION_DIRECTORY,
+            false => GOOGLE_TRUST_SERVICES_STAGING_DIRECTORY,
+        }
+        .into();
+        self
+    }
+
+    /// Use `config` for the TLS connections to ACME directories, such as to trust the root of a
+    /// private CA, rather than the web PKI roots.
+    ///
+    /// ```no_run
+    /// use tide_acme::AcmeConfig;
+    /// use tide_rustls::rustls::ClientConfig;
+    ///
+    /// # fn example(internal_root: &[u8]) {
+    /// let mut tls = ClientConfig::new();
+    /// let _ = tls.root_store.add_pem_file(&mut &internal_root[..]);
+    /// let config = AcmeConfig::new(vec!["service.internal.example"])
+    ///     .directory("https://ca.internal.example/acme/directory")
+    ///     .directory_tls_config(tls);
+    /// # }
+    /// ```
+    pub fn directory_tls_config(mut self, config: ClientConfig) -> Self {
+        self.directory_tls = ClientTls::new(config);
+        self
+    }
+
+    /// Bind the account registered with the main directory to an existing account with the CA,
+    /// as CAs such as ZeroSSL and Google Trust Services require (RFC 8555 section 7.3.4).
+    ///
+    /// `key_id` and `hmac_key` are the credentials the CA issues for this; `hmac_key` is
+    /// base64url-encoded, as CAs hand it out. They are only used to register the account, so
+    /// once the account key is cached they can be rotated without affecting it.
+    pub fn external_account_binding(
+        mut self,
+        key_id: impl AsRef<str>,
+        hmac_key: impl AsRef<str>,
+    ) -> Self {
+        self.external_account_key = Some(ExternalAccountKey {
+            kid: key_id.as_ref().into(),
+            hmac_key: hmac_key.as_ref().into(),
+        });
+        self
+    }
+
+    /// Apply the defaults of `profile`, overriding the settings it covers; settings made
+    /// afterwards override the preset in turn.
+    ///
+    /// | Setting | Development | Production |
+    /// |---|---|---|
+    /// | [`directory_lets_encrypt`](Self::directory_lets_encrypt) | staging | production |
+    /// | [`staging_check`](Self::staging_check) | false | true |
+    /// | [`wait_for_not_before`](Self::wait_for_not_before) | false | true |
+    /// | [`challenge_close_timeout`](Self::challenge_close_timeout) | 2 seconds | 10 seconds |
+    /// | [`challenge_close_notify`](Self::challenge_close_notify) | false | true |
+    ///
+    /// ```no_run
+    /// use tide_acme::{AcmeConfig, Profile};
+    ///
+    /// let config = AcmeConfig::new(["example.com"])
+    ///     .preset(Profile::Production)
+    ///     .contact_push("mailto:admin@example.com");
+    /// ```
+    pub fn preset(self, profile: Profile) -> Self {
+        let production = profile == Profile::Production;
+        self.directory_lets_encrypt(production)
+            .staging_check(production)
+            .wait_for_not_before(production)
+            .challenge_close_timeout(match profile {
+                Profile::Development => Duration::from_secs(2),
+                Profile::Production => Duration::from_secs(10),
+            })
+            .challenge_close_notify(production)
+    }
+
+    /// Set the domains to obtain a certificate for.
+    ///
+    /// A wildcard domain such as `*.app.example` covers every name one label below
+    /// `app.example`, both when serving by SNI and when looking up certificates with
+    /// [`AcmeHandle`](crate::AcmeHandle). CAs only validate wildcard domains with dns-01
+    /// challenges, so they require a [`dns_provider`](Self::dns_provider).
+    pub fn domains(mut self, domains: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        self.domains = domains.into_iter().map(|s| s.as_ref().into()).collect();
+        self
+    }
+
+    /// Add a domain to obtain a certificate for.
+    pub fn domains_push(mut self, domain: impl AsRef<str>) -> Self {
+        self.domains.push(domain.as_ref().into());
+        self
+    }
+
+    /// Name the acceptor, such as `public` or `admin`, to tell apart the telemetry of several
+    /// acceptors in one process. The name tags the acceptor's log spans, its events (as
+    /// [`AcmeEvent::acceptor`]) and its metrics (as an `acceptor` label).
+    pub fn name(mut self, name: impl AsRef<str>) -> Self {
+        self.name = Some(name.as_ref().into());
+        self
+    }
+
+    /// Set the contact URLs for the ACME account, such as `mailto:admin@example.org`.
+    pub fn contact(mut self, contact: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        self.contact = contact.into_iter().map(|s| s.as_ref().into()).collect();
+        self
+    }
+
+    /// Add a contact URL for the ACME account.
+    pub fn contact_push(mut self, contact: impl AsRef<str>) -> Self {
+        self.contact.push(contact.as_ref().into());
+        self
+    }
+
+    /// Set the signature algorithm for the ACME account key.
+    ///
+    /// This only affects newly generated account keys. If the account cache holds a key for a
+    /// different algorithm, it will be replaced by a new key, registering a new account. Set it
+    /// to the algorithm of a key imported with [`AccountKeyPair`](crate::AccountKeyPair).
+    pub fn account_key_algorithm(mut self, algorithm: AccountKeyAlgorithm) -> Self {
+        self.account_key_algorithm = algorithm;
+        self
+    }
+
+    /// Obtain a separate certificate for each domain, rather than one certificate for all of them.
+    ///
+    /// Each certificate is ordered, renewed, and retried independently, so a domain that fails
+    /// validation (for instance, because of broken DNS) doesn't delay or block certificates for the
+    /// other domains. Certificates are served by SNI.
+    pub fn cert_per_domain(mut self, cert_per_domain: bool) -> Self {
+        self.cert_per_domain = cert_per_domain;
+        self
+    }
+
+    /// Set the maximum number of ACME orders to run concurrently, when managing more than one
+    /// certificate. Defaults to 4.
+    pub fn max_concurrent_orders(mut self, max_concurrent_orders: usize) -> Self {
+        self.max_concurrent_orders = max_concurrent_orders;
+        self
+    }
+
+    /// Place at most `orders` new orders every `per`, spaced evenly, rather than as soon as
+    /// certificates are due. Defaults to no limit.
+    ///
+    /// Set this below the CA's rate limit on new orders (for Let's Encrypt, 300 per 3 hours)
+    /// before importing many domains at once; the orders then wait their turn, and
+    /// [`AcmeHandle::order_pacing`](crate::AcmeHandle::order_pacing) reports the progress.
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    /// use tide_acme::AcmeConfig;
+    ///
+    /// let config = AcmeConfig::new(["example.com"]).order_rate(5, Duration::from_secs(60));
+    /// ```
+    pub fn order_rate(mut self, orders: u32, per: Duration) -> Self {
+        self.order_rate = Some((orders, per));
+        self
+    }
+
+    /// Delay orders to every directory to stay within `limits`, rather than having the CA refuse
+    /// them. By default, orders to the Let's Encrypt production directory stay within
+    /// [`RateLimits::lets_encrypt`], and orders to other directories aren't limited.
+    ///
+    /// Either way, when the CA refuses an order with a `Retry-After` header, orders to its
+    /// directory are put off until then. [`AcmeHandle::rate_limit_budgets`](crate::AcmeHandle::rate_limit_budgets)
+    /// shows what remains of the limits.
+    pub fn rate_limits(mut self, limits: RateLimits) -> Self {
+        self.rate_limits = Some(limits);
+        self
+    }
+
+    /// Retry failed orders, and orders for domains that failed validation, as `policy` says.
+    /// Defaults to [`RetryPolicy::default`].
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Renew certificates as `policy` says: at what point of their validity period, and within
+    /// which hours of the day. Defaults to [`RenewalPolicy::default`], two thirds of the way
+    /// through the validity period, at any hour.
+    pub fn renewal_policy(mut self, policy: RenewalPolicy) -> Self {
+        self.renewal_policy = policy;
+        self
+    }
+
+    /// Go by `clock` to schedule renewals and retries, and to tell whether certificates have
+    /// expired, rather than by the system clock. Defaults to [`SystemClock`].
+    ///
+    /// This is for tests: with a clock they control, they can fast-forward to a renewal, through
+    /// a backoff or to an expiry warning without waiting days.
+    ///
+    /// ```no_run
+    /// # #[cfg(feature = "test-util")]
+    /// # {
+    /// use std::time::Duration;
+    /// use tide_acme::test::{pebble_config, ManualClock};
+    /// use tide_acme::AcmeTlsAcceptor;
+    ///
+    /// # async_std::task::block_on(async {
+    /// let clock = ManualClock::new();
+    /// let acceptor = AcmeTlsAcceptor::new(pebble_config(vec!["test.localhost"]).clock(clock.clone()));
+    /// let handle = acceptor.handle();
+    /// let first = handle.ready().await;
+    /// clock.advance(Duration::from_secs(70 * 24 * 60 * 60));
+    /// let mut rotations = handle.cert_rotations();
+    /// # });
+    /// # }
+    /// ```
+    pub fn clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Set the maximum number of domains (subject alternative names) per certificate. Defaults to
+    /// 100, the Let's Encrypt limit.
+    ///
+    /// If more domains are configured, they are sorted and split into groups of at most this many,
+    /// each with its own certificate, instead of failing the order. Certificates are served by SNI.
+    pub fn max_sans_per_cert(mut self, max_sans_per_cert: usize) -> Self {
+        self.max_sans_per_cert = max_sans_per_cert;
+        self
+    }
+
+    /// Set how many of the most recent events to keep in memory, for
+    /// [`AcmeHandle::event_history`](crate::AcmeHandle::event_history). Defaults to 100.
+    ///
+    /// This answers what happened recently even when the logs don't, such as after they were
+    /// rotated. Set it to 0 to keep none.
+    pub fn event_history(mut self, events: usize) -> Self {
+        self.event_history = events;
+        self
+    }
+
+    /// Resume from the runtime state a previous process saved with
+    /// [`AcmeHandle::snapshot`](crate::AcmeHandle::snapshot), for fast restarts: failed orders
+    /// are retried as they were scheduled rather than right away, pending orders are resumed,
+    /// certificates obtained on demand or added at runtime are managed again, and the event
+    /// history carries on.
+    ///
+    /// The state of a certificate is restored only if the process serves the same certificate
+    /// the snapshot was taken with, as loaded from the cache; otherwise, it starts afresh.
+    /// Certificates obtained on demand are checked with the domain policy again.
+    pub fn restore(mut self, snapshot: Snapshot) -> Self {
+        self.snapshot = Some(snapshot);
+        self
+    }
+
+    /// Set how many previously served certificates to keep in the cache per certificate, for
+    /// [`AcmeHandle::rollback`](crate::AcmeHandle::rollback). Defaults to 1.
+    pub fn previous_certs(mut self, previous_certs: usize) -> Self {
+        self.previous_certs = previous_certs;
+        self
+    }
+
+    /// Report failures of the background certificate management to `reporter`, such as an error
+    /// tracker. See [`ErrorReporter`] for which failures are reported.
+    pub fn error_reporter(mut self, reporter: impl ErrorReporter) -> Self {
+        self.error_reporter = Some(Arc::new(reporter));
+        self
+    }
+
+    /// Report failures of the certificates covering any of `domains` to `reporter` instead of
+    /// the one set with [`error_reporter`](Self::error_reporter), such as to alert the customer
+    /// owning a white-label domain, while other failures go to the operators.
+    ///
+    /// Failures are reported to every matching reporter added this way, and to the main one only
+    /// if none matches. See [`domain_contact`](Self::domain_contact) to have the CA's own
+    /// notices, such as expiry emails, go to the customer too.
+    ///
+    /// ```no_run
+    /// use tide_acme::AcmeConfig;
+    ///
+    /// let config = AcmeConfig::new(vec!["platform.example", "shop.customer.example"])
+    ///     .error_reporter(|event: &tide_acme::AcmeEvent| eprintln!("ops: {}", event.to_json()))
+    ///     .domain_error_reporter(vec!["shop.customer.example"], |event: &tide_acme::AcmeEvent| {
+    ///         eprintln!("customer: {}", event.to_json())
+    ///     });
+    /// ```
+    pub fn domain_error_reporter(
+        mut self,
+        domains: impl IntoIterator<Item = impl AsRef<str>>,
+        reporter: impl ErrorReporter,
+    ) -> Self {
+        let domains = domains.into_iter().map(|s| s.as_ref().into()).collect();
+        self.domain_reporters.push((domains, Arc::new(reporter)));
+        self
+    }
+
+    /// Obtain certificates for `domains` from the main directory with an account of their own,
+    /// whose contact URLs are `contact`, so the CA sends its notices about them, such as expiry
+    /// emails, there rather than to the main contact.
+    ///
+    /// The domains are split into certificates like the main domains, and kept apart from them:
+    /// list them here rather than in [`AcmeConfig::new`]. Call it again for each further account.
+    ///
+    /// ```no_run
+    /// use tide_acme::AcmeConfig;
+    ///
+    /// let config = AcmeConfig::new(vec!["platform.example"])
+    ///     .contact_push("mailto:ops@platform.example")
+    ///     .domain_contact(vec!["shop.customer.example"], vec!["mailto:it@customer.example"]);
+    /// ```
+    pub fn domain_contact(
+        mut self,
+        domains: impl IntoIterator<Item = impl AsRef<str>>,
+        contact: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Self {
+        self.directory_groups.push(DirectoryGroup {
+            directory_url: String::new(),
+            source: None,
+            domains: domains.into_iter().map(|s| s.as_ref().into()).collect(),
+            cache: None,
+            single_cert: false,
+            main_directory: true,
+            contact: Some(contact.into_iter().map(|s| s.as_ref().into()).collect()),
+        });
+        self
+    }
+
+    /// Add a responder for ACME challenges, tried before the built-in tls-alpn-01 responder and
+    /// after responders added earlier.
+    pub fn challenge_responder(mut self, responder: impl ChallengeResponder) -> Self {
+        self.challenge_responders.push(Arc::new(responder));
+        self
+    }
+
+    /// Answer dns-01 challenges by setting TXT records with `provider`, preferring them over
+    /// the built-in challenge types. Like a responder added with
+    /// [`challenge_responder`](Self::challenge_responder), it's tried after those added earlier.
+    ///
+    /// DNS validation works for servers the CA can't connect to, such as behind a firewall,
+    /// and is required for wildcard domains such as `*.domain.example`.
+    pub fn dns_provider(self, provider: impl DnsProvider) -> Self {
+        let resolution = self.dns_resolution.clone();
+        self.challenge_responder(Dns01Responder::new(provider, resolution))
+    }
+
+    /// Check that dns-01 challenge records have propagated with `resolution`, before the CA is
+    /// asked to validate them. Defaults to [`DnsResolution::Provider`], trusting the
+    /// [`DnsProvider`] to wait for them.
+    ///
+    /// Checks are repeated every 5 seconds for up to 5 minutes, after which the order fails and
+    /// is retried with backoff.
+    ///
+    /// ```no_run
+    /// # fn example(provider: impl tide_acme::DnsProvider) {
+    /// use tide_acme::{AcmeConfig, DnsResolution};
+    ///
+    /// let config = AcmeConfig::new(vec!["*.domain.example"])
+    ///     .dns_provider(provider)
+    ///     .dns_resolution(DnsResolution::Authoritative);
+    /// # }
+    /// ```
+    pub fn dns_resolution(self, resolution: DnsResolution) -> Self {
+        *self.dns_resolution.lock().unwrap() = resolution;
+        self
+    }
+
+    /// Answer http-01 challenges, preferring them over tls-alpn-01, if `http01` is true. Defaults
+    /// to false.
+    ///
+    /// For when TLS is terminated in front of the application, such as by a load balancer, so
+    /// tls-alpn-01 validation requests can't reach it. The CA sends http-01 validation requests
+    /// to port 80, which must be served by an application with
+    /// [`AcmeHttp01Middleware`](crate::AcmeHttp01Middleware), or by
+    /// [`AcmeTlsAcceptor::listen_http`](crate::AcmeTlsAcceptor::listen_http). Responders added with
+    /// [`challenge_responder`](Self::challenge_responder) are still tried first.
+    pub fn http01(mut self, http01: bool) -> Self {
+        self.http01 = http01;
+        self
+    }
+
+    /// Obtain certificates on demand for server names no managed certificate covers, the first
+    /// time a client asks for them via SNI, if `policy` approves them.
+    ///
+    /// Each approved name gets a certificate of its own from the main directory, kept in the
+    /// cache and renewed like the configured domains, so after a restart it's loaded from the
+    /// cache the next time a client asks for it. Each name is ordered once however many clients
+    /// ask for it, and their handshakes wait for the certificate for up to
+    /// [`on_demand_wait`](Self::on_demand_wait); if it isn't issued by then, they get the
+    /// certificate of the first configured domain, if any, and fail otherwise. The configured
+    /// domains may be empty.
+    ///
+    /// Only valid host names that pass [`DomainPolicy::may_approve`] are queued for the policy,
+    /// and only so many at a time: names beyond that are asked for again by later handshakes.
+    ///
+    /// ```no_run
+    /// use tide_acme::{AcmeConfig, DomainPolicy};
+    ///
+    /// struct Customers;
+    ///
+    /// #[async_trait::async_trait]
+    /// impl DomainPolicy for Customers {
+    ///     async fn approve(&self, domain: &str) -> bool {
+    ///         // Look the domain up in the customer database here.
+    ///         domain.ends_with(".customer.example")
+    ///     }
+    /// }
+    ///
+    /// let config = AcmeConfig::new(Vec::<String>::new()).on_demand(Customers);
+    /// ```
+    pub fn on_demand(mut self, policy: impl DomainPolicy) -> Self {
+        self.on_demand = Some(Arc::new(policy));
+        self
+    }
+
+    /// How long handshakes for a server name whose certificate is being obtained
+    /// [on demand](Self::on_demand) wait for it, before going ahead with the certificate they
+    /// would get otherwise. Defaults to 10 seconds; zero doesn't wait.
+    ///
+    /// The wait ends early if the domain policy denies the name or an order for it fails. It
+    /// counts against the [handshake timeout](crate::ListenerOptions::handshake_timeout), which
+    /// bounds it, and the connection counts as open while it waits.
+    /// [`AcmeHandle::pending_issuances`](crate::AcmeHandle::pending_issuances) lists the
+    /// certificates being obtained.
+    pub fn on_demand_wait(mut self, wait: Duration) -> Self {
+        self.on_demand_wait = wait;
+        self
+    }
+
+    /// Let `provider` supply or escrow the private keys of new certificates, rather than keys
+    /// always being generated locally. See [`KeyProvider`].
+    pub fn key_provider(mut self, provider: impl KeyProvider) -> Self {
+        self.key_provider = Some(Arc::new(provider));
+        self
+    }
+
+    /// Keep the private keys of certificates in `key_store`, such as an HSM, rather than in the
+    /// process and the cache: it creates a key for each new certificate and signs its certificate
+    /// signing request and TLS handshakes. See [`KeyStore`].
+    ///
+    /// The [key provider](Self::key_provider) and [key reuse](Self::reuse_cert_key) don't apply to
+    /// keys in a key store, and orders fail with [must-staple](Self::must_staple), which needs to
+    /// sign the request again in-process.
+    pub fn key_store(mut self, key_store: impl KeyStore) -> Self {
+        self.key_store = Some(Arc::new(key_store));
+        self
+    }
+
+    /// Adjust the certificate signing request of each new certificate before it is sent to the
+    /// CA, such as to add names or set subject fields some organizations require. See [`Csr`].
+    ///
+    /// Added names count towards the [SAN limit](Self::max_sans_per_cert): those that don't fit
+    /// alongside the certificate's domains are left out, with a warning.
+    ///
+    /// ```no_run
+    /// use tide_acme::{AcmeConfig, SubjectField};
+    ///
+    /// let config = AcmeConfig::new(vec!["domain.example"]).customize_csr(|csr| {
+    ///     csr.subject(SubjectField::Organization, "Example Inc.");
+    ///     if csr.domains().iter().any(|domain| domain == "domain.example") {
+    ///         csr.add_name("www.domain.example");
+    ///     }
+    /// });
+    /// ```
+    pub fn customize_csr(mut self, customize: impl Fn(&mut Csr) + Send + Sync + 'static) -> Self {
+        self.customize_csr = Some(Arc::new(customize));
+        self
+    }
+
+    /// Set the algorithm of the private keys of new certificates. Defaults to ECDSA P-256.
+    ///
+    /// This only affects certificates ordered from now on; certificates in the cache are served
+    /// until they're renewed. Keys supplied by a [`KeyProvider`] are used as they are, which is
+    /// how to get RSA certificates for legacy clients that don't support ECDSA.
+    ///
+    /// ```no_run
+    /// use tide_acme::{AcmeConfig, CertKeyAlgorithm};
+    ///
+    /// let config = AcmeConfig::new(vec!["domain.example"])
+    ///     .cert_key_algorithm(CertKeyAlgorithm::EcdsaP384);
+    /// ```
+    pub fn cert_key_algorithm(mut self, algorithm: CertKeyAlgorithm) -> Self {
+        self.cert_key_algorithm = algorithm;
+        self
+    }
+
+    /// Keep the private key of the current certificate when renewing it if `reuse` is true,
+    /// such as for public key pinning, rather than generating a new key for each renewal.
+    /// Defaults to false, rotating keys on each renewal.
+    ///
+    /// A new key is still generated for the first certificate, and if the current key isn't of
+    /// the configured [algorithm](Self::cert_key_algorithm).
+    pub fn reuse_cert_key(mut self, reuse: bool) -> Self {
+        self.reuse_cert_key = reuse;
+        self
+    }
+
+    /// Serve the chain whose topmost certificate is issued by the CA with the common name
+    /// `issuer`, among the alternate chains the CA offers, such as for clients that only trust
+    /// certain roots. Falls back to the CA's default chain if none is, with a warning. Defaults to
+    /// the default chain.
+    ///
+    /// ```
+    /// use tide_acme::AcmeConfig;
+    ///
+    /// let config = AcmeConfig::new(vec!["domain.example"]).preferred_chain("ISRG Root X1");
+    /// ```
+    pub fn preferred_chain(mut self, issuer: impl Into<String>) -> Self {
+        self.preferred_chain = Some(issuer.into());
+        self
+    }
+
+    /// Order certificates with the CA's certificate profile `profile`, such as Let's Encrypt's
+    /// `shortlived`, for certificates valid for about six days, or `tlsserver`. Orders go without
+    /// a profile, with a warning, to CAs that don't offer it. Defaults to the CA's default
+    /// profile. Not to be confused with the [presets](Self::preset).
+    ///
+    /// Renewal follows from the validity period of the issued certificates, so it comes sooner
+    /// for shorter-lived ones; see [`RenewalPolicy`].
+    ///
+    /// ```
+    /// use tide_acme::AcmeConfig;
+    ///
+    /// let config = AcmeConfig::new(vec!["domain.example"]).profile("shortlived");
+    /// ```
+    pub fn profile(mut self, profile: impl Into<String>) -> Self {
+        self.profile = Some(profile.into());
+        self
+    }
+
+    /// Wait until a newly issued certificate is valid by the local clock before serving it.
+    /// Defaults to false.
+    ///
+    /// A certificate that isn't valid yet by the local clock points to the clock being behind.
+    /// This is always logged as a warning; waiting avoids serving a certificate that clients
+    /// with a similarly skewed clock would reject, at the cost of keeping the old certificate (or
+    /// none) in the meantime.
+    pub fn wait_for_not_before(mut self, wait_for_not_before: bool) -> Self {
+        self.wait_for_not_before = wait_for_not_before;
+        self
+    }
+
+    /// Staple OCSP responses to the certificates: fetch the CA's signed statement that a
+    /// certificate is not revoked from the OCSP responder it names, and send it along with the
+    /// certificate in handshakes, so clients needn't ask the responder themselves. Defaults to
+    /// false.
+    ///
+    /// Responses are fetched when a certificate is deployed and again halfway through each
+    /// response's validity, by the certificate's background task; a failed fetch is retried after
+    /// an hour, while the last good response keeps being served until it expires, and then as
+    /// the [stale OCSP policy](Self::stale_ocsp_policy) says. A certificate the responder reports
+    /// revoked is renewed immediately. Certificates naming no responder are served without a
+    /// staple.
+    ///
+    /// ```
+    /// use tide_acme::AcmeConfig;
+    ///
+    /// let config = AcmeConfig::new(vec!["domain.example"]).ocsp_stapling(true);
+    /// ```
+    pub fn ocsp_stapling(mut self, ocsp_stapling: bool) -> Self {
+        self.ocsp_stapling = ocsp_stapling;
+        self
+    }
+
+    /// Request must-staple certificates, carrying the TLS feature extension (RFC 7633), which
+    /// clients honoring it reject unless a good OCSP response is stapled. Defaults to false.
+    ///
+    /// This turns on [`ocsp_stapling`](Self::ocsp_stapling) too. Only certificates ordered from
+    /// now on are affected; renew to replace the current ones. While no response can be
+    /// fetched, such as when the responder is down past the last response's expiry, those
+    /// clients can't connect, so use this only where revocation must be enforced.
+    ///
+    /// ```
+    /// use tide_acme::AcmeConfig;
+    ///
+    /// let config = AcmeConfig::new(vec!["domain.example"]).must_staple(true);
+    /// ```
+    pub fn must_staple(mut self, must_staple: bool) -> Self {
+        self.must_staple = must_staple;
+        self.ocsp_stapling |= must_staple;
+        self
+    }
+
+    /// What to do once the stapled OCSP response expires because no fresh one could be fetched:
+    /// serve the certificate without a staple, go on stapling the expired response for a while,
+    /// or stop serving must-staple certificates. Defaults to
+    /// [`StaleOcspPolicy::ServeWithoutStaple`].
+    ///
+    /// Each outcome is reported as an event:
+    /// [`OcspStaleServed`](crate::EventKind::OcspStaleServed) when an expired response starts
+    /// being served, [`OcspStapleDropped`](crate::EventKind::OcspStapleDropped) when the staple
+    /// is dropped, and [`OcspCertWithheld`](crate::EventKind::OcspCertWithheld) when a
+    /// must-staple certificate stops being served. A fresh response staples and serves the
+    /// certificate again. Only matters with [`ocsp_stapling`](Self::ocsp_stapling).
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use tide_acme::{AcmeConfig, StaleOcspPolicy};
+    ///
+    /// let config = AcmeConfig::new(vec!["domain.example"])
+    ///     .ocsp_stapling(true)
+    ///     .stale_ocsp_policy(StaleOcspPolicy::ServeStale(Duration::from_secs(24 * 60 * 60)));
+    /// ```
+    pub fn stale_ocsp_policy(mut self, policy: StaleOcspPolicy) -> Self {
+        self.stale_ocsp_policy = policy;
+        self
+    }
+
+    /// Before ordering a certificate from the Let's Encrypt production directory for domains
+    /// without a cached certificate, first obtain one from the staging directory. Defaults to
+    /// false.
+    ///
+    /// Only if the staging order succeeds is the production order placed, so configuration
+    /// mistakes, such as DNS not pointing at the server yet, use up the generous staging rate
+    /// limits rather than the strict production ones. The staging certificate is discarded. The
+    /// staging order uses its own account, kept in the cache like the production one.
+    pub fn staging_check(mut self, staging_check: bool) -> Self {
+        self.staging_check = staging_check;
+        self
+    }
+
+    /// Before ordering a certificate validated with tls-alpn-01, check that the CA's validators would
+    /// reach this server: resolve each domain, connect to each of its addresses on port 443, and
+    /// check that the handshake for `acme-tls/1` is answered with a probe certificate this
+    /// server serves meanwhile. Defaults to false.
+    ///
+    /// A failed check fails the order without contacting the CA, with an error telling what is
+    /// wrong, such as a missing DNS record, a closed port, or another server answering for the
+    /// domain, rather than the CA's terse validation problem; the order is retried with backoff
+    /// as usual. The check runs from this server, so it resolves names through the host's
+    /// resolver and may fail where the network doesn't route connections to its own public
+    /// address back to it, though the CA's validators would get through. It's skipped when
+    /// another challenge type would be used, for wildcard domains and IP addresses, and when
+    /// [delegating challenges](Self::challenge_delegation). Probe handshakes are counted in
+    /// [`AcmeHandle::challenge_stats`](crate::AcmeHandle::challenge_stats).
+    ///
+    /// ```
+    /// use tide_acme::AcmeConfig;
+    ///
+    /// let config = AcmeConfig::new(vec!["domain.example"]).preflight_check(true);
+    /// ```
+    pub fn preflight_check(mut self, preflight_check: bool) -> Self {
+        self.preflight_check = preflight_check;
+        self
+    }
+
+    /// Set the deadline for closing a tls-alpn-01 validation connection after the handshake.
+    /// Defaults to 10 seconds.
+    ///
+    /// A validator that stops reading can stall sending `close_notify`; once the deadline passes,
+    /// the connection is dropped without it, so it can't hold on to a task forever.
+    pub fn challenge_close_timeout(mut self, timeout: Duration) -> Self {
+        self.challenge_close.timeout = timeout;
+        self
+    }
+
+    /// Send a TLS `close_notify` alert before closing a tls-alpn-01 validation connection, if
+    /// `close_notify` is true, or just close the TCP connection otherwise. Defaults to true.
+    ///
+    /// Validators only need the handshake, so closing without the alert is safe, and avoids
+    /// waiting on the validator.
+    pub fn challenge_close_notify(mut self, close_notify: bool) -> Self {
+        self.challenge_close.close_notify = close_notify;
+        self
+    }
+
+    /// Set the options for binding the sockets that answer validation requests, and accepting
+    /// connections on them.
+    pub fn listener_options(mut self, options: ListenerOptions) -> Self {
+        self.listener_options = options;
+        self
+    }
+
+    /// Take an exclusive lock on the file at `path` (created if necessary) while ordering a
+    /// certificate and storing it in the cache, for several processes sharing a cache, such as
+    /// workers sharing port 443 via [`ListenerOptions::reuse_port`]; short for
+    /// [`lock`](Self::lock) with a [`FileLock`](crate::FileLock).
+    ///
+    /// The lock must be on a filesystem that supports file locks, such as the one holding a
+    /// [`PrivateDirCache`](crate::PrivateDirCache) directory.
+    pub fn order_lock(self, path: impl Into<PathBuf>) -> Self {
+        self.lock(crate::FileLock::new(path))
+    }
+
+    /// Hold `lock` while ordering a certificate and storing it in the cache, for instances
+    /// sharing a cache, such as replicas sharing a `RedisCache` with a `RedisLock`, both
+    /// requiring the `redis` feature.
+    ///
+    /// With the lock held, an instance first checks the cache, and if another instance renewed
+    /// the certificate in the meantime, deploys that one instead of ordering, so only one
+    /// instance places each order and the others read the cache.
+    pub fn lock(mut self, lock: impl Lock) -> Self {
+        self.order_lock = Some(Arc::new(lock));
+        self
+    }
+
+    /// Run as one instance of a fleet sharing a cache, in the role `role`: publish pending
+    /// tls-alpn-01 challenges to the cache, and answer the validation connections of challenges
+    /// found there, so that the CA may connect to any instance, not only the one that placed the
+    /// order. With [`ChallengeDelegation::Responder`], this instance never places orders.
+    ///
+    /// Every instance must use the same cache, and the same main directory. Challenges are looked
+    /// up on validation connections to the listeners and the sockets of
+    /// [`AcmeTlsAcceptor::listen_challenges`](crate::AcmeTlsAcceptor::listen_challenges), not
+    /// on those passed to
+    /// [`AcmeTlsAcceptor::accept_stream`](crate::AcmeTlsAcceptor::accept_stream).
+    ///
+    /// ```no_run
+    /// use tide_acme::{AcmeConfig, ChallengeDelegation, PrivateDirCache};
+    ///
+    /// let orderer = std::env::var("ACME_ORDERER").is_ok();
+    /// let config = AcmeConfig::new(vec!["domain.example"])
+    ///     .cache(PrivateDirCache::new("/mnt/shared/tide-acme"))
+    ///     .order_lock("/mnt/shared/tide-acme/order.lock")
+    ///     .challenge_delegation(match orderer {
+    ///         true => ChallengeDelegation::Orderer,
+    ///         false => ChallengeDelegation::Responder,
+    ///     });
+    /// ```
+    pub fn challenge_delegation(mut self, role: ChallengeDelegation) -> Self {
+        self.challenge_delegation = Some(role);
+        self
+    }
+
+    /// Watch the directory `dir` holding the cache, such as a
+    /// [`PrivateDirCache`](crate::PrivateDirCache) directory, and serve certificates that another
+    /// process renews into it as soon as they're stored; see
+    /// [`AcmeHandle::reload`](crate::AcmeHandle::reload).
+    ///
+    /// This lets an external tool own renewal while this process only serves certificates. A
+    /// warning is logged if the directory can't be watched. Requires the `watch` feature.
+    #[cfg(feature = "watch")]
+    pub fn watch_cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.watch_cache_dir = Some(dir.into());
+        self
+    }
+
+    /// Emit tracing events (logs) if `logging` is true. Defaults to true.
+    ///
+    /// When false, the background tasks and the acceptor produce no tracing events at all, for
+    /// embedders that want no log output from libraries; observe certificate management through
+    /// [`AcmeHandle::events`](crate::AcmeHandle::events) and
+    /// [`error_reporter`](Self::error_reporter) instead.
+    pub fn logging(mut self, logging: bool) -> Self {
+        self.logging = logging;
+        self
+    }
+
+    /// Set the level of the tracing span each step of a certificate's management runs in.
+    /// Defaults to [`Level::INFO`].
+    ///
+    /// The span is named `acme`, with the target `tide_acme::acme`, and carries the certificate's
+    /// first `domain`, all its `domains` separated by commas, the `attempt` number of the order
+    /// (1 unless earlier ones failed), and once known, the `order_url`, the `challenge_type`
+    /// answered and how long the order took in `elapsed_ms`. Events about the outcome of each
+    /// step have the same target. With `tracing-subscriber`, a filter such as
+    /// `tide_acme[acme{domain=domain.example}]=debug` picks out one certificate.
+    pub fn tracing_level(mut self, level: Level) -> Self {
+        self.tracing_level = level;
+        self
+    }
+
+    /// Write each certificate as it is deployed to `fullchain.pem` and `privkey.pem` in a
+    /// directory of `dir` named after its first domain, for servers that can't embed tide-acme;
+    /// see [`AcmeTlsAcceptor::run_standalone`](crate::AcmeTlsAcceptor::run_standalone).
+    ///
+    /// Wildcard domains are spelled with `_` in place of `*`. The files are replaced atomically
+    /// and, on Unix, only readable by the owner. They're written before
+    /// [`on_rotation`](Self::on_rotation) is called, so it can reload the server reading them.
+    pub fn export_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.export_dir = Some(dir.into());
+        self
+    }
+
+    /// Call `callback` with each certificate as it is deployed: loaded from the cache on startup,
+    /// newly issued, or restored by a rollback.
+    ///
+    /// Certificate management waits for the returned future before going on, so the callback is
+    /// done with a certificate before the next one for the same domains is deployed, and before
+    /// it's stored in the cache. This suits pushing certificates to sidecars such as Envoy or
+    /// nginx, whose configuration must follow each rotation in order; see
+    /// [`AcmeHandle::cert_rotations`](crate::AcmeHandle::cert_rotations) to be notified without
+    /// holding up certificate management.
+    ///
+    /// ```no_run
+    /// use tide_acme::AcmeConfig;
+    ///
+    /// let config = AcmeConfig::new(vec!["domain.example"]).on_rotation(|rotation| async move {
+    ///     let domain = &rotation.status().domains[0];
+    ///     let pem = rotation.certificate_pem() + &rotation.private_key_pem();
+    ///     let path = format!("/etc/envoy/certs/{}.pem", domain);
+    ///     if let Err(err) = async_std::fs::write(path, pem).await {
+    ///         eprintln!("failed to write certificate for {}: {}", domain, err);
+    ///     }
+    /// });
+    /// ```
+    pub fn on_rotation<F: Future<Output = ()> + Send + 'static>(
+        mut self,
+        callback: impl Fn(CertRotation) -> F + Send + Sync + 'static,
+    ) -> Self {
+        self.on_rotation = Some(Arc::new(move |rotation| Box::pin(callback(rotation))));
+        self
+    }
+
+    /// Call `callback` with each certificate covering any of `domains` as it is deployed, like
+    /// [`on_rotation`](Self::on_rotation) but only for those certificates, such as to reload the
+    /// one service that serves a domain, rather than every service on each rotation.
+    ///
+    /// Callbacks added this way run after `on_rotation`, in the order they were added, and
+    /// certificate management waits for each of them.
+    ///
+    /// ```no_run
+    /// use tide_acme::AcmeConfig;
+    ///
+    /// let config = AcmeConfig::new(vec!["domain.example", "mail.domain.example"])
+    ///     .export_dir("/etc/ssl/tide-acme")
+    ///     .on_domain_rotation(vec!["mail.domain.example"], |_| async {
+    ///         let _ = async_std::task::spawn_blocking(|| {
+    ///             std::process::Command::new("systemctl")
+    ///                 .args(["reload", "postfix"])
+    ///                 .status()
+    ///         })
+    ///         .await;
+    ///     });
+    /// ```
+    pub fn on_domain_rotation<F: Future<Output = ()> + Send + 'static>(
+        mut self,
+        domains: impl IntoIterator<Item = impl AsRef<str>>,
+        callback: impl Fn(CertRotation) -> F + Send + Sync + 'static,
+    ) -> Self {
+        let domains = domains.into_iter().map(|s| s.as_ref().into()).collect();
+        let hook: RotationHook = Arc::new(move |rotation| Box::pin(callback(rotation)));
+        self.domain_rotation_hooks.push((domains, hook));
+        self
+    }
+
+    /// Run `command` each time a certificate covering any of `domains` is deployed, such as to
+    /// have a co-located proxy reload it with `SIGHUP`, as with
+    /// [`on_domain_rotation`](Self::on_domain_rotation). Requires the `reload-command` feature.
+    ///
+    /// The command runs without a shell, with the certificate's first domain in the
+    /// `TIDE_ACME_DOMAIN` environment variable. Certificate management waits for it to exit, so
+    /// it should be quick. A failure to run it, or a non-zero exit status, is logged.
+    ///
+    /// ```no_run
+    /// use std::process::Command;
+    /// use tide_acme::AcmeConfig;
+    ///
+    /// let mut reload = Command::new("pkill");
+    /// reload.args(["-HUP", "-F", "/run/haproxy.pid"]);
+    /// let config = AcmeConfig::new(vec!["domain.example"])
+    ///     .export_dir("/etc/ssl/tide-acme")
+    ///     .reload_command(vec!["domain.example"], reload);
+    /// ```
+    #[cfg(feature = "reload-command")]
+    pub fn reload_command(
+        self,
+        domains: impl IntoIterator<Item = impl AsRef<str>>,
+        command: std::process::Command,
+    ) -> Self {
+        let program = command.get_program().to_owned();
+        let args: Vec<_> = command.get_args().map(ToOwned::to_owned).collect();
+        let envs: Vec<_> = command
+            .get_envs()
+            .map(|(key, value)| (key.to_owned(), value.map(ToOwned::to_owned)))
+            .collect();
+        let dir = command.get_current_dir().map(ToOwned::to_owned);
+        self.on_domain_rotation(domains, move |rotation| {
+            let mut command = std::process::Command::new(&program);
+            command.args(&args);
+            for (key, value) in &envs {
+                match value {
+                    Some(value) => command.env(key, value),
+                    None => command.env_remove(key),
+                };
+            }
+            if let Some(dir) = &dir {
+                command.current_dir(dir);
+            }
+            if let Some(domain) = rotation.status().domains.first() {
+                command.env("TIDE_ACME_DOMAIN", domain);
+            }
+            async move {
+                match async_std::task::spawn_blocking(move || command.status()).await {
+                    Ok(status) if status.success() => {}
+                    Ok(status) => warn!(%status, "reload command failed"),
+                    Err(err) => warn!(%err, "failed to run reload command"),
+                }
+            }
+        })
+    }
+
+    /// Call `callback` with each newly issued certificate, once it is deployed, such as to announce
+    /// renewals. Unlike [`on_rotation`](Self::on_rotation), certificates loaded from the cache or
+    /// restored by a rollback are left out.
+    ///
+    /// Certificate management waits for the returned future before going on, as for
+    /// `on_rotation`.
+    pub fn on_cert_obtained<F: Future<Output = ()> + Send + 'static>(
+        mut self,
+        callback: impl Fn(CertRotation) -> F + Send + Sync + 'static,
+    ) -> Self {
+        self.on_cert_obtained = Some(Arc::new(move |rotation| Box::pin(callback(rotation))));
+        self
+    }
+
+    /// Call `callback` with the `order_failed` event of each failed order, along with the status
+    /// of the certificate, whose [`failed_orders`](crate::CertStatus::failed_orders) counts the
+    /// failures since it was last deployed. This suits paging someone when a renewal fails
+    /// repeatedly, from a webhook to a chat or incident service.
+    ///
+    /// Certificate management waits for the returned future before retrying, so the callback
+    /// should time out its requests.
+    ///
+    /// ```no_run
+    /// use tide_acme::AcmeConfig;
+    /// # async fn post_webhook(_text: String) {}
+    ///
+    /// let config = AcmeConfig::new(vec!["domain.example"]).on_order_failed(|event, status| async move {
+    ///     if status.failed_orders >= 3 {
+    ///         post_webhook(format!("renewing {:?} keeps failing: {:?}", event.domains, event.error))
+    ///             .await;
+    ///     }
+    /// });
+    /// ```
+    pub fn on_order_failed<F: Future<Output = ()> + Send + 'static>(
+        mut self,
+        callback: impl Fn(AcmeEvent, CertStatus) -> F + Send + Sync + 'static,
+    ) -> Self {
+        self.on_order_failed = Some(Arc::new(move |event, status| {
+            Box::pin(callback(event, status))
+        }));
+        self
+    }
+
+    /// Call `callback` with each event of certificate management, such as `deployed_new_cert`,
+    /// `order_failed` or `account_registered`, as it happens.
+    ///
+    /// The callback runs on the task managing the certificate, so it should return quickly; to
+    /// handle events asynchronously, subscribe with
+    /// [`AcmeHandle::events`](crate::AcmeHandle::events) instead.
+    ///
+    /// ```no_run
+    /// use tide_acme::{AcmeConfig, EventKind};
+    ///
+    /// let config = AcmeConfig::new(vec!["domain.example"]).on_event(|event| {
+    ///     if event.kind == EventKind::OrderFailed {
+    ///         eprintln!("ordering {:?} failed: {:?}", event.domains, event.error);
+    ///     }
+    /// });
+    /// ```
+    pub fn on_event(mut self, callback: impl Fn(&AcmeEvent) + Send + Sync + 'static) -> Self {
+        self.on_event = Some(Arc::new(callback));
+        self
+    }
+
+    /// Raise an alarm when a certificate being served has less than `remaining` validity left,
+    /// which, well past the renewal time, means renewal keeps failing. Call this again to add
+    /// more thresholds, so the alarms escalate as expiry gets closer. Defaults to none.
+    ///
+    /// A watchdog checks the certificates a minute after startup and every ten minutes after,
+    /// independently of their renewal, so alarms go off even if a certificate's management is
+    /// stuck. Each threshold crossed raises
+    /// one `expiry_alarm` failure [event](crate::AcmeHandle::events), passed to the
+    /// [event callback](Self::on_event) and [error reporters](Self::error_reporter) and logged
+    /// as a warning; [`AcmeHandle::expiry_alarms`](crate::AcmeHandle::expiry_alarms) counts the
+    /// certificates below a threshold, which the `metrics` feature exports too.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use tide_acme::AcmeConfig;
+    ///
+    /// const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+    /// let config = AcmeConfig::new(vec!["domain.example"])
+    ///     .expiry_alarm(14 * DAY)
+    ///     .expiry_alarm(3 * DAY);
+    /// ```
+    pub fn expiry_alarm(mut self, remaining: Duration) -> Self {
+        self.expiry_alarms.push(remaining);
+        self
+    }
+
+    /// Ask `filter` whether to go on with each TLS handshake, given the client's IP address and
+    /// the server name it asks for via SNI, such as to enforce IP blocklists or ban a tenant's
+    /// names, before the handshake or any request is processed. Connections it denies are closed
+    /// without an answer, and counted in
+    /// [`AcmeHandle::rejected_handshakes`](crate::AcmeHandle::rejected_handshakes).
+    ///
+    /// The filter runs once the ClientHello has arrived, on the listeners using
+    /// [`AcmeTlsAcceptor`](crate::AcmeTlsAcceptor); the client address is the one from the PROXY
+    /// protocol header if [enabled](crate::AcmeTlsAcceptor::proxy_protocol). The server name is
+    /// `None` if the client sent none, or split its ClientHello over several TLS records. The
+    /// CA's tls-alpn-01 validation connections bypass the filter, as do those to the sockets of
+    /// [`listen_challenges`](crate::AcmeTlsAcceptor::listen_challenges) and streams passed to
+    /// [`accept_stream`](crate::AcmeTlsAcceptor::accept_stream). Handshakes wait for the returned
+    /// future, so it should answer quickly, such as from an in-memory set.
+    ///
+    /// ```no_run
+    /// use std::collections::HashSet;
+    /// use std::net::IpAddr;
+    /// use std::sync::Arc;
+    /// use tide_acme::AcmeConfig;
+    ///
+    /// let blocked: Arc<HashSet<IpAddr>> = Arc::new(HashSet::new());
+    /// let config = AcmeConfig::new(vec!["domain.example"]).handshake_filter(move |ip, sni| {
+    ///     let blocked = blocked.clone();
+    ///     async move { !blocked.contains(&ip) && sni.as_deref() != Some("banned.domain.example") }
+    /// });
+    /// ```
+    pub fn handshake_filter<F: Future<Output = bool> + Send + 'static>(
+        mut self,
+        filter: impl Fn(IpAddr, Option<String>) -> F + Send + Sync + 'static,
+    ) -> Self {
+        self.handshake_filter = Some(Arc::new(move |ip, sni| Box::pin(filter(ip, sni))));
+        self
+    }
+
+    /// Call `inspect` with the client's IP address and the parsed ClientHello of each TLS
+    /// handshake, before the handshake goes on, to decide whether to go on, close the connection,
+    /// or serve a certificate from another resolver. This allows logging scanner fingerprints,
+    /// such as the [JA3 string](ClientHelloInfo::ja3), and blocking known-bad ones at the TLS
+    /// layer.
+    ///
+    /// The callback runs after the [handshake filter](Self::handshake_filter), if any, under the
+    /// same conditions: the client address comes from the PROXY protocol header if enabled, the
+    /// ClientHello is empty if the client split it over several TLS records, and the CA's
+    /// tls-alpn-01 validation connections bypass it. Rejected connections are counted in
+    /// [`AcmeHandle::rejected_handshakes`](crate::AcmeHandle::rejected_handshakes). Handshakes
+    /// routed to another resolver get a configuration built for the connection, with the
+    /// adjustments of [`configure_rustls`](Self::configure_rustls), so session resumption
+    /// doesn't apply to them.
+    ///
+    /// ```no_run
+    /// use tide_acme::{AcmeConfig, HelloDecision};
+    ///
+    /// let config = AcmeConfig::new(vec!["domain.example"]).inspect_client_hello(|ip, hello| {
+    ///     eprintln!("ClientHello from {}: {}", ip, hello.ja3());
+    ///     match hello.cipher_suites.len() {
+    ///         0..=2 => HelloDecision::Reject,
+    ///         _ => HelloDecision::Proceed,
+    ///     }
+    /// });
+    /// ```
+    pub fn inspect_client_hello(
+        mut self,
+        inspect: impl Fn(IpAddr, &ClientHelloInfo) -> HelloDecision + Send + Sync + 'static,
+    ) -> Self {
+        self.inspect_client_hello = Some(Arc::new(inspect));
+        self
+    }
+
+    /// Write the secrets of each TLS session to the file named by the `SSLKEYLOGFILE`
+    /// environment variable, in the NSS key log format, if `keylog` is true, so that captures of
+    /// the listeners' traffic can be decrypted with Wireshark while debugging. Defaults to false.
+    ///
+    /// Nothing is written if `SSLKEYLOGFILE` isn't set. Anyone reading the file can decrypt the
+    /// traffic, so a warning is logged on startup while this is on: never leave it on in
+    /// production.
+    ///
+    /// ```
+    /// use tide_acme::AcmeConfig;
+    ///
+    /// let config = AcmeConfig::new(vec!["domain.example"]).keylog(true);
+    /// ```
+    pub fn keylog(mut self, keylog: bool) -> Self {
+        self.keylog = keylog;
+        self
+    }
+
+    /// Configure TLS session resumption: session tickets, their lifetime and the rotation of
+    /// their keys, shared with other instances through the cache or not, and the session cache.
+    /// Defaults to rustls' defaults: a cache of 256 sessions, and no tickets.
+    ///
+    /// This applies to the listeners using [`AcmeTlsAcceptor`](crate::AcmeTlsAcceptor) and to
+    /// [`AcmeHandle::server_config`](crate::AcmeHandle::server_config). See
+    /// [`SessionResumption`] for an example.
+    pub fn session_resumption(mut self, resumption: SessionResumption) -> Self {
+        self.session_resumption = Some(resumption);
+        self
+    }
+
+    /// Adjust the rustls configuration of the listeners using
+    /// [`AcmeTlsAcceptor`](crate::AcmeTlsAcceptor), such as to tune cipher suites, TLS versions,
+    /// ALPN protocols, session storage or the ticketer.
+    ///
+    /// `configure` is called on each configuration the acceptor builds: once on creation, and
+    /// again when a clone overrides its policy, such as with
+    /// [`strict_sni`](crate::AcmeTlsAcceptor::strict_sni). Afterwards the certificate resolver is
+    /// set back to the one serving the managed certificates, the client certificate verifier to
+    /// the one set with [`client_auth`](crate::AcmeTlsAcceptor::client_auth), if any, and the
+    /// `acme-tls/1` ALPN protocol is added if missing, so that validation requests are still
+    /// answered. Restricting TLS versions or cipher suites beyond what the CA's validators
+    /// support makes validation fail.
+    ///
+    /// ```
+    /// use tide_acme::AcmeConfig;
+    /// use tide_rustls::rustls::ProtocolVersion;
+    ///
+    /// let config = AcmeConfig::new(vec!["domain.example"]).configure_rustls(|config| {
+    ///     config.versions = vec![ProtocolVersion::TLSv1_3];
+    ///     config.ignore_client_order = true;
+    /// });
+    /// ```
+    pub fn configure_rustls(
+        mut self,
+        configure: impl Fn(&mut ServerConfig) + Send + Sync + 'static,
+    ) -> Self {
+        self.configure_rustls = Some(Arc::new(configure));
+        self
+    }
+
+    /// Map the server name a client asks for (via SNI) before choosing the certificate to serve.
+    ///
+    /// The callback returns the name to serve a certificate for, or `None` to use the requested
+    /// name unchanged. This lets a routing layer in front of Tide send many names to one
+    /// certificate, such as `cdn-12345.customer.example` to the certificate for
+    /// `customer.example`. ACME validation requests are not affected.
+    pub fn sni_override(
+        mut self,
+        sni_override: impl Fn(&str) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.sni_override = Some(Arc::new(sni_override));
+        self
+    }
+
+    /// Declare that the CA's tls-alpn-01 validation connections, which always go to port 443,
+    /// reach this server on `local_port`, through NAT or a redirect such as 443→8443.
+    ///
+    /// This doesn't change where anything listens; it tells the checks of the sockets answering
+    /// validation requests which port to expect, and
+    /// [`AcmeHandle::validation_path`](crate::AcmeHandle::validation_path) and the logs of failed
+    /// validations describe the path through the mapping, rather than assuming the port the
+    /// listener is bound to is reachable.
+    ///
+    /// ```no_run
+    /// use tide_acme::{AcmeConfig, AcmeTlsAcceptor, TideRustlsExt};
+    ///
+    /// # async_std::task::block_on(async {
+    /// let config = AcmeConfig::new(vec!["domain.example"]).tls_alpn_port_mapping(8443);
+    /// let app = tide::new();
+    /// app.listen(
+    ///     tide_rustls::TlsListener::build()
+    ///         .addrs("0.0.0.0:8443")
+    ///         .acme(config),
+    /// )
+    /// .await?;
+    /// # tide::Result::Ok(())
+    /// # });
+    /// ```
+    pub fn tls_alpn_port_mapping(mut self, local_port: u16) -> Self {
+        self.tls_alpn_port = Some(local_port);
+        self
+    }
+
+    /// Set how to handle handshakes without SNI. Defaults to
+    /// [`MissingSni::FirstDomain`].
+    ///
+    /// Clients without SNI can't say which certificate they expect, so any choice may fail their
+    /// certificate checks; [`MissingSni::Domain`] serves the one they're known to expect, such
+    /// as the name a monitoring agent is configured with. Listeners with
+    /// [`AcmeTlsAcceptor::strict_sni`](crate::AcmeTlsAcceptor::strict_sni) reject these
+    /// handshakes regardless. ACME validation requests always carry SNI, so they're not
+    /// affected.
+    ///
+    /// ```no_run
+    /// use tide_acme::{AcmeConfig, MissingSni};
+    ///
+    /// let config = AcmeConfig::new(vec!["domain.example", "status.domain.example"])
+    ///     .missing_sni(MissingSni::Domain("status.domain.example".into()));
+    /// ```
+    pub fn missing_sni(mut self, missing_sni: MissingSni) -> Self {
+        self.missing_sni = missing_sni;
+        self
+    }
+
+    /// Serve the certificate covering `domain` to connections with a server name no certificate
+    /// covers, and to those without SNI unless [`missing_sni`](Self::missing_sni) says otherwise.
+    /// `domain` should be among the configured domains. Defaults to the first configured domain.
+    ///
+    /// ```no_run
+    /// use tide_acme::AcmeConfig;
+    ///
+    /// let config = AcmeConfig::new(vec!["api.domain.example", "www.domain.example"])
+    ///     .default_domain("www.domain.example");
+    /// ```
+    pub fn default_domain(mut self, domain: impl AsRef<str>) -> Self {
+        self.default_domain = Some(domain.as_ref().into());
+        self
+    }
+
+    /// Choose between the certificate for a server name and a wildcard certificate covering it,
+    /// when both are configured. Defaults to [`SniPriority::ExactFirst`].
+    ///
+    /// When several certificates cover the same name, such as a domain in both the main
+    /// domains and a [directory group](Self::directory_group), the one configured first is served:
+    /// the main domains, then groups in the order they were added, then domains obtained on
+    /// demand or added at runtime.
+    pub fn sni_priority(mut self, priority: SniPriority) -> Self {
+        self.sni_priority = priority;
+        self
+    }
+
+    /// Serve a self-signed certificate for the configured domains until the first certificate is
+    /// deployed, if `self_signed_fallback` is true. Defaults to false.
+    ///
+    /// Without one, handshakes fail until then, typically with an "access denied" or "internal
+    /// error" alert, which is confusing on a fresh deployment. Clients won't trust the
+    /// self-signed certificate, but health checks and `curl -k` work from the start. Listeners
+    /// with [`AcmeTlsAcceptor::strict_sni`](crate::AcmeTlsAcceptor::strict_sni) don't serve it,
+    /// and neither do handshakes rejected by [`MissingSni::Reject`]. ACME validation requests
+    /// are not affected.
+    pub fn self_signed_fallback(mut self, self_signed_fallback: bool) -> Self {
+        self.self_signed_fallback = self_signed_fallback;
+        self
+    }
+
+    /// Obtain certificates for `domains` from the ACME directory at `directory_url`, rather than
+    /// from the main directory, such as public names via Let's Encrypt and internal names via a
+    /// private CA.
+    ///
+    /// Each directory gets its own account, using the same contact URLs and account key
+    /// algorithm; see [`directory_group_contact`](Self::directory_group_contact) to give it
+    /// contact URLs of its own. The group shares the main cache, in which entries are kept
+    /// separate per directory; see [`directory_group_cache`](Self::directory_group_cache) to give
+    /// it its own. The group's domains are split into certificates like the main domains.
+    pub fn directory_group(
+        mut self,
+        directory_url: impl AsRef<str>,
+        domains: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Self {
+        self.directory_groups.push(DirectoryGroup {
+            directory_url: directory_url.as_ref().into(),
+            source: None,
+            domains: domains.into_iter().map(|s| s.as_ref().into()).collect(),
+            cache: None,
+            single_cert: false,
+            main_directory: false,
+            contact: None,
+        });
+        self
+    }
+
+    /// Like [`directory_group`](Self::directory_group), but with an account of its own whose
+    /// contact URLs are `contact`, so that the group is kept apart from the other domains at the
+    /// CA too: its rate limits count against its own account, and the CA sends its notices about
+    /// the group there. [`domain_contact`](Self::domain_contact) does the same for domains using
+    /// the main directory.
+    ///
+    /// Several groups may use the same directory, each with its own account, and their
+    /// certificates are all served by SNI from the one acceptor.
+    ///
+    /// ```no_run
+    /// use tide_acme::AcmeConfig;
+    ///
+    /// let config = AcmeConfig::new(vec!["brand-a.example"])
+    ///     .contact_push("mailto:ops@brand-a.example")
+    ///     .directory_group_contact(
+    ///         "https://acme.zerossl.com/v2/DV90",
+    ///         vec!["brand-b.example", "www.brand-b.example"],
+    ///         vec!["mailto:ops@brand-b.example"],
+    ///     );
+    /// ```
+    pub fn directory_group_contact(
+        mut self,
+        directory_url: impl AsRef<str>,
+        domains: impl IntoIterator<Item = impl AsRef<str>>,
+        contact: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Self {
+        self.directory_groups.push(DirectoryGroup {
+            directory_url: directory_url.as_ref().into(),
+            source: None,
+            domains: domains.into_iter().map(|s| s.as_ref().into()).collect(),
+            cache: None,
+            single_cert: false,
+            main_directory: false,
+            contact: Some(contact.into_iter().map(|s| s.as_ref().into()).collect()),
+        });
+        self
+    }
+
+    /// Like [`directory_group`](Self::directory_group), but keep the group's certificates and
+    /// account key in `cache` instead of the main cache.
+    ///
+    /// The cache must have the same error types as the main cache, so set the main cache first:
+    /// setting the main cache afterwards makes groups added before it share the new main cache.
+    pub fn directory_group_cache(
+        mut self,
+        directory_url: impl AsRef<str>,
+        domains: impl IntoIterator<Item = impl AsRef<str>>,
+        cache: impl Cache<EC = EC, EA = EA> + 'static,
+    ) -> Self {
+        self.directory_groups.push(DirectoryGroup {
+            directory_url: directory_url.as_ref().into(),
+            source: None,
+            domains: domains.into_iter().map(|s| s.as_ref().into()).collect(),
+            cache: Some(Box::new(cache)),
+            single_cert: false,
+            main_directory: false,
+            contact: None,
+        });
+        self
+    }
+
+    /// Retry failed cache stores up to `retries` times, with exponential backoff starting at one
+    /// second, before falling back to the [secondary cache](Self::secondary_cache) or reporting
+    /// the failure. Defaults to 0.
+    ///
+    /// A certificate that couldn't be stored is still served, but is lost on restart, and a new
+    /// one ordered then.
+    pub fn cache_store_retries(mut self, retries: u32) -> Self {
+        self.cache_store_retries = retries;
+        self
+    }
+
+    /// Store certificates and account keys in `cache` when storing them in the main cache, or a
+    /// group's own cache, fails after any [retries](Self::cache_store_retries). Entries missing
+    /// from the main cache, or that fail to load from it, are loaded from `cache` in turn.
+    ///
+    /// Storing in the secondary cache instead emits a `cert_cache_store_secondary` or
+    /// `account_cache_store_secondary` event; failing there too emits the usual store failure.
+    /// Like [`directory_group_cache`](Self::directory_group_cache), set the main cache first:
+    /// setting the main cache afterwards drops the secondary cache.
+    pub fn secondary_cache(mut self, cache: impl Cache<EC = EC, EA = EA> + 'static) -> Self {
+        self.secondary_cache = Some(Box::new(cache));
+        self
+    }
+
+    /// Exit the process if `fatal` is true and a certificate or account key can't be stored,
+    /// after any [retries](Self::cache_store_retries) and the
+    /// [secondary cache](Self::secondary_cache), rather than serving a certificate that is lost on
+    /// restart. Defaults to false.
+    ///
+    /// The failure is logged and reported before exiting, with status 1, so a supervisor can
+    /// restart the process or alert on it.
+    pub fn cache_store_fatal(mut self, fatal: bool) -> Self {
+        self.cache_store_fatal = fatal;
+        self
+    }
+
+    /// Obtain certificates for `domains` from `source` instead of via ACME.
+    ///
+    /// The certificates are kept in the main cache, under the source's name in place of a
+    /// directory URL. The domains are split into certificates like the main domains.
+    pub fn certificate_source(
+        mut self,
+        domains: impl IntoIterator<Item = impl AsRef<str>>,
+        source: impl CertificateSource,
+    ) -> Self {
+        self.directory_groups.push(DirectoryGroup {
+            directory_url: source.name().into(),
+            source: Some(Arc::new(source)),
+            domains: domains.into_iter().map(|s| s.as_ref().into()).collect(),
+            cache: None,
+            single_cert: false,
+            main_directory: false,
+            contact: None,
+        });
+        self
+    }
+
+    /// Serve `certificate` for `domains`, resolved by SNI alongside the certificates obtained via
+    /// ACME, such as for an internal name signed by a corporate CA.
+    ///
+    /// The domains are served together by the one certificate, which must cover them all.
+    ///
+    /// ```no_run
+    /// use tide_acme::{AcmeConfig, StaticCertificate};
+    ///
+    /// let config = AcmeConfig::new(vec!["domain.example"]).static_certificate(
+    ///     vec!["internal.corp.example"],
+    ///     StaticCertificate::from_files("/etc/tls/internal.key", "/etc/tls/internal.pem"),
+    /// );
+    /// ```
+    pub fn static_certificate(
+        mut self,
+        domains: impl IntoIterator<Item = impl AsRef<str>>,
+        certificate: StaticCertificate,
+    ) -> Self {
+        self.directory_groups.push(DirectoryGroup {
+            directory_url: certificate.name().into(),
+            source: Some(Arc::new(certificate)),
+            domains: domains.into_iter().map(|s| s.as_ref().into()).collect(),
+            cache: None,
+            single_cert: true,
+            main_directory: false,
+            contact: None,
+        });
+        self
+    }
+
+    /// Set the cache for certificates and the account key.
+    ///
+    /// Entries start with a line stamping their format version, and entries in an older format,
+    /// including those written before versioning, are migrated and stored again when loaded.
+    ///
+    /// Accounts are kept per directory, so switching between the staging and production
+    /// directories uses a separate account for each. Account entries also record their
+    /// directory: if the cache doesn't keep directories apart and returns another directory's
+    /// account, a new account is used without storing it, rather than replacing that one.
+    ///
+    /// The URL of each order in progress is kept too, so an order interrupted by a restart is
+    /// resumed, as long as its authorizations are still pending, rather than placed again. Its
+    /// certificate signing request key is kept with it, and stored as certificates are: through
+    /// the secondary cache too, and encrypted by an [`EncryptedCache`](crate::EncryptedCache).
+    pub fn cache<C: 'static + Cache>(self, cache: C) -> AcmeConfig<C::EC, C::EA> {
+        AcmeConfig {
+            name: self.name,
+            directory_url: self.directory_url,
+            domains: self.domains,
+            contact: self.contact,
+            external_account_key: self.external_account_key,
+            directory_tls: self.directory_tls,
+            cache: Arc::new(cache),
+            cache_name: cache_name::<C>(),
+            secondary_cache: None,
+            cache_store_retries: self.cache_store_retries,
+            cache_store_fatal: self.cache_store_fatal,
+            account_key_algorithm: self.account_key_algorithm,
+            cert_per_domain: self.cert_per_domain,
+            max_concurrent_orders: self.max_concurrent_orders,
+            order_rate: self.order_rate,
+            retry_policy: self.retry_policy,
+            renewal_policy: self.renewal_policy,
+            rate_limits: self.rate_limits,
+            max_sans_per_cert: self.max_sans_per_cert,
+            ephemeral: false,
+            sni_override: self.sni_override,
+            missing_sni: self.missing_sni,
+            default_domain: self.default_domain,
+            sni_priority: self.sni_priority,
+            self_signed_fallback: self.self_signed_fallback,
+            tls_alpn_port: self.tls_alpn_port,
+            previous_certs: self.previous_certs,
+            event_history: self.event_history,
+            snapshot: self.snapshot,
+            error_reporter: self.error_reporter,
+            domain_reporters: self.domain_reporters,
+            challenge_responders: self.challenge_responders,
+            dns_resolution: self.dns_resolution,
+            wait_for_not_before: self.wait_for_not_before,
+            ocsp_stapling: self.ocsp_stapling,
+            must_staple: self.must_staple,
+            stale_ocsp_policy: self.stale_ocsp_policy,
+            directory_groups: self
+                .directory_groups
+                .into_iter()
+                .map(|group| DirectoryGroup {
+                    directory_url: group.directory_url,
+                    source: group.source,
+                    domains: group.domains,
+                    cache: None,
+                    single_cert: group.single_cert,
+                    main_directory: group.main_directory,
+                    contact: group.contact,
+                })
+                .collect(),
+            staging_check: self.staging_check,
+            preflight_check: self.preflight_check,
+            challenge_close: self.challenge_close,
+            listener_options: self.listener_options,
+            order_lock: self.order_lock,
+            challenge_delegation: self.challenge_delegation,
+            logging: self.logging,
+            tracing_level: self.tracing_level,
+            clock: self.clock,
+            http01: self.http01,
+            on_demand: self.on_demand,
+            on_demand_wait: self.on_demand_wait,
+            key_provider: self.key_provider,
+            key_store: self.key_store,
+            customize_csr: self.customize_csr,
+            cert_key_algorithm: self.cert_key_algorithm,
+            reuse_cert_key: self.reuse_cert_key,
+            preferred_chain: self.preferred_chain,
+            profile: self.profile,
+            export_dir: self.export_dir,
+            on_rotation: self.on_rotation,
+            domain_rotation_hooks: self.domain_rotation_hooks,
+            on_cert_obtained: self.on_cert_obtained,
+            on_order_failed: self.on_order_failed,
+            on_event: self.on_event,
+            expiry_alarms: self.expiry_alarms,
+            handshake_filter: self.handshake_filter,
+            configure_rustls: self.configure_rustls,
+            keylog: self.keylog,
+            session_resumption: self.session_resumption,
+            inspect_client_hello: self.inspect_client_hello,
+            #[cfg(feature = "watch")]
+            watch_cache_dir: self.watch_cache_dir,
+        }
+    }
+
+    /// Set separate caches for certificates and the account key.
+    pub fn cache_compose<CC: 'static + CertCache, CA: 'static + AccountCache>(
+        self,
+        cert_cache: CC,
+        account_cache: CA,
+    ) -> AcmeConfig<CC::EC, CA::EA> {
+        self.cache(CompositeCache::new(cert_cache, account_cache))
+    }
+
+    /// Set the cache, boxing its errors.
+    pub fn cache_with_boxed_err<C: 'static + Cache>(self, cache: C) -> AcmeConfig<Box<dyn Debug>> {
+        self.cache(BoxedErrCache::new(cache))
+    }
+
+    /// Set the cache if `cache` is `Some`, or use no cache otherwise.
+    pub fn cache_option<C: 'static + Cache>(self, cache: Option<C>) -> AcmeConfig<C::EC, C::EA> {
+        match cache {
+            Some(cache) => self.cache(cache),
+            None => self.cache(NoCache::<C::EC, C::EA>::new()),
+        }
+    }
+
+    /// Keep certificates and the account key in memory only, for throwaway test servers.
+    ///
+    /// Every start registers a new account and orders new certificates, which would quickly hit
+    /// the production rate limits, so this always uses the Let's Encrypt staging directory,
+    /// overriding any directory set before or after. A warning is logged on startup.
+    pub fn cache_none(self) -> AcmeConfig<Infallible, Infallible> {
+        let mut config = self.cache(NoCache::new());
+        config.ephemeral = true;
+        config
+    }
+
+    pub(crate) fn state(mut self) -> AcmeState<EC, EA> {
+        if self.ephemeral {
+            warn!(
+                "tide-acme is running without a cache: certificates and the account key will be \
+                 lost on exit, and new ones ordered on every start"
+            );
+            if self.directory_url != LETS_ENCRYPT_STAGING_DIRECTORY {
+                warn!(
+                    directory_url = %self.directory_url,
+                    "ignoring the configured ACME directory without a cache, using Let's Encrypt \
+                     staging"
+                );
+                self.directory_url = LETS_ENCRYPT_STAGING_DIRECTORY.into();
+            }
+            for group in &mut self.directory_groups {
+                if group.cache.is_none()
+                    && group.source.is_none()
+                    && !group.main_directory
+                    && group.directory_url != LETS_ENCRYPT_STAGING_DIRECTORY
+                {
+                    warn!(
+                        directory_url = %group.directory_url,
+                        "ignoring the configured ACME directory without a cache, using Let's \
+                         Encrypt staging"
+                    );
+                    group.directory_url = LETS_ENCRYPT_STAGING_DIRECTORY.into();
+                }
+            }
+        }
+        for group in &mut self.directory_groups {
+            if group.main_directory {
+                group.directory_url = self.directory_url.clone();
+            }
+        }
+        // Groups are given contact URLs of their own to keep their accounts apart; the account
+        // cache keys accounts by contact and directory, so groups sharing both share an account.
+        let mut accounts = vec![(&self.directory_url, &self.contact)];
+        for group in self.directory_groups.iter().filter(|g| g.source.is_none()) {
+            let contact = group.contact.as_ref().unwrap_or(&self.contact);
+            let account = (&group.directory_url, contact);
+            if group.contact.is_some() && accounts.contains(&account) {
+                warn!(
+                    directory_url = %group.directory_url,
+                    ?contact,
+                    domains = ?group.domains,
+                    "domains given their own account share it with other domains, which have the \
+                     same contact URLs at the same directory"
+                );
+            }
+            accounts.push(account);
+        }
+        if let Some(default_domain) = &self.default_domain {
+            let configured = self
+                .domains
+                .iter()
+                .chain(
+                    self.directory_groups
+                        .iter()
+                        .flat_map(|group| &group.domains),
+                )
+                .any(|domain| domain.eq_ignore_ascii_case(default_domain));
+            if !configured {
+                warn!(
+                    default_domain = %default_domain,
+                    "the default domain is not among the configured domains; unmatched server \
+                     names will get the certificate configured first"
+                );
+            }
+        }
+        let dns01 = self
+            .challenge_responders
+            .iter()
+            .any(|responder| responder.challenge_type() == "dns-01");
+        let wildcards: Vec<&String> = self
+            .domains
+            .iter()
+            .chain(
+                self.directory_groups
+                    .iter()
+                    .filter(|group| group.source.is_none())
+                    .flat_map(|group| &group.domains),
+            )
+            .filter(|domain| domain.starts_with("*."))
+            .collect();
+        if !dns01 && !wildcards.is_empty() {
+            warn!(
+                ?wildcards,
+                "wildcard domains can only be validated with dns-01 challenges; set a DNS \
+                 provider with AcmeConfig::dns_provider"
+            );
+        }
+        AcmeState::new(self)
+    }
+}
+
+/// The type name of a cache backend, without its type parameters.
+fn cache_name<C>() -> &'static str {
+    let name = std::any::type_name::<C>();
+    name.split('<').next().unwrap_or(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls_acme::caches::DirCache;
+
+    use super::*;
+
+    #[test]
+    fn builds_domain_and_contact_lists() {
+        let config = AcmeConfig::new(["a.example"])
+            .domains_push("b.example")
+            .contact(["mailto:admin@a.example"])
+            .contact_push("mailto:ops@a.example");
+        assert_eq!(config.domains, ["a.example", "b.example"]);
+        assert_eq!(
+            config.contact,
+            ["mailto:admin@a.example", "mailto:ops@a.example"]
+        );
+        let config = config.domains(["c.example"]);
+        assert_eq!(config.domains, ["c.example"]);
+    }
+
+    #[test]
+    fn selects_directories() {
+        let config = AcmeConfig::new(["domain.example"]);
+        assert_eq!(config.directory_url, LETS_ENCRYPT_STAGING_DIRECTORY);
+        let config = config.directory_lets_encrypt(true);
+        assert_eq!(config.directory_url, LETS_ENCRYPT_PRODUCTION_DIRECTORY);
+        let config = config.directory("https://ca.example/dir");
+        assert_eq!(config.directory_url, "https://ca.example/dir");
+        let config = config.directory_zerossl();
+        assert_eq!(config.directory_url, ZEROSSL_DIRECTORY);
+        let config = config.directory_google_trust_services(false);
+        assert_eq!(
+            config.directory_url,
+            GOOGLE_TRUST_SERVICES_STAGING_DIRECTORY
+        );
+        let config = config.directory_google_trust_services(true);
+        assert_eq!(
+            config.directory_url,
+            GOOGLE_TRUST_SERVICES_PRODUCTION_DIRECTORY
+        );
+    }
+
+    #[test]
+    fn keeps_settings_when_changing_caches() {
+        let config = AcmeConfig::new(["domain.example"])
+            .directory("https://ca.example/dir")
+            .contact_push("mailto:admin@domain.example")
+            .account_key_algorithm(AccountKeyAlgorithm::Ed25519)
+            .external_account_binding("kid-1", "c2VjcmV0LWtleQ")
+            .cache(DirCache::new("/tmp/tide-acme-test"));
+        assert_eq!(config.directory_url, "https://ca.example/dir");
+        assert_eq!(config.domains, ["domain.example"]);
+        assert_eq!(config.contact, ["mailto:admin@domain.example"]);
+        assert_eq!(config.account_key_algorithm, AccountKeyAlgorithm::Ed25519);
+        assert_eq!(config.external_account_key.unwrap().kid, "kid-1");
+    }
+
+    #[test]
+    fn configures_challenge_connection_closing() {
+        let config = AcmeConfig::new(["domain.example"]);
+        assert_eq!(config.challenge_close.timeout, Duration::from_secs(10));
+        assert!(config.challenge_close.close_notify);
+        let config = config
+            .challenge_close_timeout(Duration::from_secs(1))
+            .challenge_close_notify(false);
+        assert_eq!(config.challenge_close.timeout, Duration::from_secs(1));
+        assert!(!config.challenge_close.close_notify);
+    }
+
+    #[test]
+    fn applies_presets() {
+        let config = AcmeConfig::new(["domain.example"]).preset(Profile::Production);
+        assert_eq!(config.directory_url, LETS_ENCRYPT_PRODUCTION_DIRECTORY);
+        assert!(config.staging_check && config.wait_for_not_before);
+        assert!(config.challenge_close.close_notify);
+        let config = config.preset(Profile::Development);
+        assert_eq!(config.directory_url, LETS_ENCRYPT_STAGING_DIRECTORY);
+        assert!(!config.staging_check && !config.wait_for_not_before);
+        assert_eq!(config.challenge_close.timeout, Duration::from_secs(2));
+        assert!(!config.challenge_close.close_notify);
+    }
+
+    #[test]
+    fn must_staple_turns_on_stapling() {
+        let config = AcmeConfig::new(["domain.example"]);
+        assert!(!config.must_staple && !config.ocsp_stapling);
+        let config = config.must_staple(true);
+        assert!(config.must_staple && config.ocsp_stapling);
+        let config = config.must_staple(false);
+        assert!(!config.must_staple && config.ocsp_stapling);
+    }
+}
@@ -0,0 +1,155 @@
+use rcgen::{DistinguishedName, DnType, DnValue};
+
+/// A field of the subject of a certificate signing request, set with [`Csr::subject`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SubjectField {
+    /// The common name (CN).
+    CommonName,
+    /// The organization (O).
+    Organization,
+    /// The organizational unit (OU).
+    OrganizationalUnit,
+    /// The two-letter country code (C).
+    Country,
+    /// The state or province (ST).
+    StateOrProvince,
+    /// The locality (L).
+    Locality,
+}
+
+/// The certificate signing request of a new certificate, before it is sent to the CA, for the
+/// callback set with [`AcmeConfig::customize_csr`](crate::AcmeConfig::customize_csr).
+///
+/// By default it names the certificate's domains as subject alternative names and has an empty
+/// subject. CAs validate every name of the request, so names added here are ordered and validated
+/// along with the certificate's domains, and CAs issuing domain-validated certificates, such as
+/// Let's Encrypt, ignore subject fields other than the common name.
+#[derive(Clone, Debug)]
+pub struct Csr {
+    domains: Vec<String>,
+    extra_names: Vec<String>,
+    subject: Vec<(SubjectField, String)>,
+}
+
+impl Csr {
+    pub(crate) fn new(domains: &[String]) -> Self {
+        Self {
+            domains: domains.to_vec(),
+            extra_names: vec![],
+            subject: vec![],
+        }
+    }
+
+    /// The domains of the certificate being ordered.
+    pub fn domains(&self) -> &[String] {
+        &self.domains
+    }
+
+    /// Add `name`, a domain or IP address, to the subject alternative names. Names already in
+    /// the request are left as they are.
+    pub fn add_name(&mut self, name: impl Into<String>) -> &mut Self {
+        let name = name.into();
+        if !self.domains.contains(&name) && !self.extra_names.contains(&name) {
+            self.extra_names.push(name);
+        }
+        self
+    }
+
+    /// Set a field of the subject to `value`, replacing any value set before.
+    pub fn subject(&mut self, field: SubjectField, value: impl Into<String>) -> &mut Self {
+        let value = value.into();
+        match self.subject.iter_mut().find(|(set, _)| *set == field) {
+            Some((_, set)) => *set = value,
+            None => self.subject.push((field, value)),
+        }
+        self
+    }
+
+    /// Drop the added names beyond `max` subject alternative names in all, returning them. The
+    /// domains themselves are already grouped to fit.
+    pub(crate) fn limit_names(&mut self, max: usize) -> Vec<String> {
+        let room = max.saturating_sub(self.domains.len());
+        if self.extra_names.len() <= room {
+            return vec![];
+        }
+        self.extra_names.split_off(room)
+    }
+
+    /// All subject alternative names: the domains, followed by the added names.
+    pub(crate) fn names(&self) -> Vec<String> {
+        self.domains
+            .iter()
+            .chain(&self.extra_names)
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn distinguished_name(&self) -> DistinguishedName {
+        let mut name = DistinguishedName::new();
+        for (field, value) in &self.subject {
+            let value = value.clone();
+            match field {
+                SubjectField::CommonName => name.push(DnType::CommonName, value),
+                SubjectField::Organization => name.push(DnType::OrganizationName, value),
+                SubjectField::OrganizationalUnit => {
+                    name.push(DnType::OrganizationalUnitName, value)
+                }
+                // RFC 5280 requires country codes to be PrintableString.
+                SubjectField::Country => {
+                    name.push(DnType::CountryName, DnValue::PrintableString(value))
+                }
+                SubjectField::StateOrProvince => name.push(DnType::StateOrProvinceName, value),
+                SubjectField::Locality => name.push(DnType::LocalityName, value),
+            }
+        }
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_names_once() {
+        let mut csr = Csr::new(&["a.example".into()]);
+        csr.add_name("www.a.example")
+            .add_name("a.example")
+            .add_name("www.a.example")
+            .add_name("192.0.2.1");
+        assert_eq!(csr.domains(), ["a.example"]);
+        assert_eq!(csr.names(), ["a.example", "www.a.example", "192.0.2.1"]);
+    }
+
+    #[test]
+    fn drops_added_names_beyond_the_limit() {
+        let mut csr = Csr::new(&["a.example".into(), "b.example".into()]);
+        csr.add_name("www.a.example").add_name("www.b.example");
+        assert!(csr.limit_names(4).is_empty());
+        assert_eq!(csr.limit_names(3), ["www.b.example"]);
+        assert_eq!(csr.names(), ["a.example", "b.example", "www.a.example"]);
+        // The domains are kept even if they alone exceed the limit.
+        assert_eq!(csr.limit_names(1), ["www.a.example"]);
+        assert_eq!(csr.names(), ["a.example", "b.example"]);
+    }
+
+    #[test]
+    fn sets_subject_fields() {
+        let mut csr = Csr::new(&["a.example".into()]);
+        assert!(csr.distinguished_name().iter().next().is_none());
+        csr.subject(SubjectField::Organization, "Old Inc.")
+            .subject(SubjectField::Country, "NL")
+            .subject(SubjectField::Organization, "Example Inc.");
+        let name = csr.distinguished_name();
+        assert_eq!(
+            name.get(&DnType::OrganizationName),
+            Some(&DnValue::Utf8String("Example Inc.".into()))
+        );
+        assert_eq!(
+            name.get(&DnType::CountryName),
+            Some(&DnValue::PrintableString("NL".into()))
+        );
+        assert_eq!(name.iter().count(), 2);
+    }
+}
@@ -0,0 +1,203 @@
+//! Sharing pending tls-alpn-01 challenges through the cache, so that any instance of a fleet can
+//! answer them.
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use rustls_acme::Cache;
+use tracing::{debug, warn};
+
+use crate::challenge::tls_alpn_01_cert;
+use crate::format::{self, EntryKind};
+use crate::resolver::AcmeResolver;
+
+/// The role of an instance in a fleet sharing a cache, set with
+/// [`AcmeConfig::challenge_delegation`](crate::AcmeConfig::challenge_delegation).
+///
+/// Pending tls-alpn-01 challenges are published to the shared cache, and every instance,
+/// whatever its role, answers validation connections with the challenges found there, so the
+/// CA's connection may land on any replica behind the load balancer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChallengeDelegation {
+    /// Place orders and renew certificates, publishing their challenges for the other instances
+    /// to answer too. Pair with a [`Lock`](crate::Lock) when there are several orderers.
+    Orderer,
+    /// Never place orders: serve the certificates the orderers store in the cache, checking it
+    /// for a renewed certificate once renewal is due, and answer their challenges.
+    Responder,
+}
+
+/// How often an instance that doesn't order checks the cache for a certificate an orderer
+/// renewed, once renewal is due.
+pub(crate) const ORDERER_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// The cache key for the key authorization of the pending tls-alpn-01 challenge of `domain`.
+fn challenge_key(domain: &str) -> Vec<String> {
+    vec!["tls-alpn-01-challenge".into(), domain.into()]
+}
+
+/// The cache, with its error types erased, for entries shared with other instances.
+#[async_trait]
+pub(crate) trait SharedCache: Send + Sync {
+    async fn store(&self, key: &[String], entry: &[u8]) -> Result<(), String>;
+    async fn load(&self, key: &[String]) -> Result<Option<Vec<u8>>, String>;
+}
+
+struct ErasedCache<EC, EA> {
+    cache: Arc<dyn Cache<EC = EC, EA = EA>>,
+    directory_url: String,
+}
+
+#[async_trait]
+impl<EC: 'static + Debug, EA: 'static + Debug> SharedCache for ErasedCache<EC, EA> {
+    async fn store(&self, key: &[String], entry: &[u8]) -> Result<(), String> {
+        self.cache
+            .store_cert(key, &self.directory_url, entry)
+            .await
+            .map_err(|err| format!("{:?}", err))
+    }
+
+    async fn load(&self, key: &[String]) -> Result<Option<Vec<u8>>, String> {
+        self.cache
+            .load_cert(key, &self.directory_url)
+            .await
+            .map_err(|err| format!("{:?}", err))
+    }
+}
+
+/// Erase the error types of `cache`, keeping shared entries under `directory_url`.
+pub(crate) fn shared_cache<EC: 'static + Debug, EA: 'static + Debug>(
+    cache: Arc<dyn Cache<EC = EC, EA = EA>>,
+    directory_url: String,
+) -> Arc<dyn SharedCache> {
+    Arc::new(ErasedCache {
+        cache,
+        directory_url,
+    })
+}
+
+/// Pending tls-alpn-01 challenges kept in the main cache, under the main directory.
+#[derive(Clone)]
+pub(crate) struct SharedChallenges {
+    cache: Arc<dyn SharedCache>,
+    resolver: Arc<AcmeResolver>,
+}
+
+impl SharedChallenges {
+    pub(crate) fn new<EC: 'static + Debug, EA: 'static + Debug>(
+        cache: Arc<dyn Cache<EC = EC, EA = EA>>,
+        directory_url: String,
+        resolver: Arc<AcmeResolver>,
+    ) -> Self {
+        Self {
+            cache: shared_cache(cache, directory_url),
+            resolver,
+        }
+    }
+
+    /// Publish the key authorization of the pending challenge of `domain`. Fails if the other
+    /// instances couldn't answer the challenge.
+    pub(crate) async fn publish(
+        &self,
+        domain: &str,
+        key_authorization: &str,
+    ) -> Result<(), String> {
+        let entry = format::stamp(key_authorization.as_bytes());
+        self.cache.store(&challenge_key(domain), &entry).await
+    }
+
+    /// Withdraw the challenge of `domain`, once the authorization is valid or has failed.
+    pub(crate) async fn withdraw(&self, domain: &str) {
+        if let Err(err) = self.cache.store(&challenge_key(domain), &[]).await {
+            warn!(%domain, %err, "failed to withdraw shared challenge");
+        }
+    }
+
+    /// Load the pending challenge of `domain` from the cache, if any, and answer it.
+    pub(crate) async fn answer(&self, domain: &str) {
+        let entry = match self.cache.load(&challenge_key(domain)).await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return,
+            Err(err) => {
+                warn!(%domain, %err, "failed to load shared challenge");
+                return;
+            }
+        };
+        let key_authorization = match format::read(entry, EntryKind::Challenge) {
+            Ok((entry, _)) if !entry.is_empty() => String::from_utf8_lossy(&entry).into_owned(),
+            Ok(_) => return,
+            Err(err) => {
+                warn!(%domain, %err, "invalid shared challenge");
+                return;
+            }
+        };
+        match tls_alpn_01_cert(domain, &key_authorization) {
+            Ok(cert) => {
+                debug!(%domain, "answering shared challenge");
+                self.resolver.set_auth_key(domain.into(), cert);
+            }
+            Err(err) => warn!(%domain, %err, "failed to answer shared challenge"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::block_on;
+
+    use super::*;
+    use crate::resolver::{MissingSni, SniPriority};
+    use crate::PrivateDirCache;
+    use crate::SystemClock;
+
+    fn resolver() -> Arc<AcmeResolver> {
+        AcmeResolver::new(
+            None,
+            SniPriority::default(),
+            MissingSni::default(),
+            None,
+            None,
+            Arc::new(SystemClock),
+        )
+    }
+
+    /// Whether `resolver` answers challenges for `domain`, going by handshakes being counted
+    /// under its name.
+    fn answered(resolver: &AcmeResolver, domain: &str) -> bool {
+        resolver.record_handshake(Some(domain));
+        resolver.stats().contains_key(&Some(domain.into()))
+    }
+
+    #[test]
+    fn shares_challenges_through_the_cache() {
+        let dir = std::env::temp_dir().join(format!("tide-acme-delegation-{}", std::process::id()));
+        let cache: Arc<dyn Cache<EC = _, EA = _>> = Arc::new(PrivateDirCache::new(&dir));
+        let directory_url = "https://acme.test/directory".to_string();
+        let orderer = SharedChallenges::new(cache.clone(), directory_url.clone(), resolver());
+        let responder_resolver = resolver();
+        let responder = SharedChallenges::new(cache, directory_url, responder_resolver.clone());
+        block_on(async {
+            responder.answer("a.example").await;
+            assert!(!answered(&responder_resolver, "a.example"));
+            orderer
+                .publish("a.example", "token.thumbprint")
+                .await
+                .unwrap();
+            responder.answer("b.example").await;
+            assert!(!answered(&responder_resolver, "b.example"));
+            responder.answer("a.example").await;
+            assert!(answered(&responder_resolver, "a.example"));
+
+            orderer.withdraw("a.example").await;
+            let fresh = resolver();
+            let responder = SharedChallenges {
+                resolver: fresh.clone(),
+                ..responder
+            };
+            responder.answer("a.example").await;
+            assert!(!answered(&fresh, "a.example"));
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+//! Minimal DER encoding and decoding, for the structures of keys and certificates the crate
+//! takes apart or assembles itself.
+
+/// A DER element: its tag, the whole encoded element, and its contents.
+pub(crate) struct DerElement<'a> {
+    pub(crate) tag: u8,
+    pub(crate) element: &'a [u8],
+    pub(crate) contents: &'a [u8],
+}
+
+/// Split the first DER element off `input`, returning it and the remaining input.
+pub(crate) fn der_element(input: &[u8]) -> Option<(DerElement<'_>, &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let (len, rest) = rest.split_at(n);
+        (len.iter().fold(0, |acc, &b| acc << 8 | b as usize), rest)
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let header_len = input.len() - rest.len();
+    let (contents, rest) = rest.split_at(len);
+    let element = DerElement {
+        tag,
+        element: &input[..header_len + len],
+        contents,
+    };
+    Some((element, rest))
+}
+
+/// Encode `contents` as a DER element with the tag `tag`.
+pub(crate) fn der_encode(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = contents.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(contents);
+    out
+}
+
+/// Encode the unsigned big-endian integer `int` as a DER INTEGER.
+pub(crate) fn der_integer(int: &[u8]) -> Vec<u8> {
+    let skip = int.iter().take_while(|&&b| b == 0).count();
+    let int = &int[skip..];
+    match int.first() {
+        None => der_encode(0x02, &[0]),
+        Some(&first) if first >= 0x80 => der_encode(0x02, &[&[0][..], int].concat()),
+        Some(_) => der_encode(0x02, int),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_der_elements() {
+        for len in [0, 1, 0x7f, 0x80, 0xff, 0x100, 0x1_0000] {
+            let contents = vec![0x5a; len];
+            let encoded = [der_encode(0x04, &contents), b"rest".to_vec()].concat();
+            let (element, rest) = der_element(&encoded).unwrap();
+            assert_eq!(element.tag, 0x04);
+            assert_eq!(element.contents, &contents[..]);
+            assert_eq!(element.element, &encoded[..encoded.len() - 4]);
+            assert_eq!(rest, b"rest");
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_der_elements() {
+        let encoded = der_encode(0x30, &[0; 300]);
+        for len in 0..encoded.len() {
+            assert!(
+                der_element(&encoded[..len]).is_none(),
+                "parsed {} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_der_lengths() {
+        // Long form without length bytes.
+        assert!(der_element(&[0x04, 0x80]).is_none());
+        // More length bytes than supported.
+        assert!(der_element(&[0x04, 0x85, 0, 0, 0, 0, 1, 0]).is_none());
+        // A length past the end of the input.
+        assert!(der_element(&[0x04, 0x82, 0xff, 0xff, 0]).is_none());
+        assert!(der_element(&[0x04, 0x03, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn encodes_integers() {
+        assert_eq!(der_integer(&[]), [0x02, 0x01, 0x00]);
+        assert_eq!(der_integer(&[0, 0, 0x7f]), [0x02, 0x01, 0x7f]);
+        assert_eq!(der_integer(&[0x80]), [0x02, 0x02, 0x00, 0x80]);
+        assert_eq!(
+            der_integer(&[0x01, 0x00, 0x01]),
+            [0x02, 0x03, 0x01, 0x00, 0x01]
+        );
+    }
+}
@@ -0,0 +1,296 @@
+//! A directory cache that keeps its files private.
+
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use async_std::task::spawn_blocking;
+use async_trait::async_trait;
+use ring::digest::{Context, SHA256};
+use rustls_acme::{AccountCache, CertCache};
+use tracing::warn;
+
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+
+/// Filesystem cache for certificates and account keys that keeps them private to the server.
+///
+/// This is a drop-in replacement for [`DirCache`](rustls_acme::caches::DirCache), using the same
+/// file names, so it can take over an existing cache directory. On Unix, it creates the directory
+/// with mode 0700 and files with mode 0600, and tightens the permissions of existing ones when
+/// writing to them. When loading, it warns about a directory or file that is accessible by group
+/// or others, or with [`strict`](Self::strict), fails to load it. Elsewhere, it behaves like
+/// `DirCache`.
+///
+/// ```no_run
+/// use tide_acme::{AcmeConfig, PrivateDirCache};
+///
+/// let config = AcmeConfig::new(vec!["domain.example"])
+///     .cache(PrivateDirCache::new("/srv/example/tide-acme-cache-dir").strict(true));
+/// ```
+#[derive(Clone, Debug)]
+pub struct PrivateDirCache {
+    dir: PathBuf,
+    strict: bool,
+    owner: Option<u32>,
+}
+
+impl PrivateDirCache {
+    /// Create a cache keeping its files in `dir`, which is created if necessary.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            strict: false,
+            owner: None,
+        }
+    }
+
+    /// Fail to load from a cache directory or file that is accessible by group or others, rather
+    /// than only warning. Defaults to false.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Require the cache directory and files to be owned by the user with ID `uid`, failing to
+    /// load or store them otherwise.
+    #[cfg(unix)]
+    pub fn owner(mut self, uid: u32) -> Self {
+        self.owner = Some(uid);
+        self
+    }
+
+    pub(crate) fn file_name(prefix: &str, names: &[String], directory_url: &str) -> String {
+        let mut ctx = Context::new(&SHA256);
+        for name in names {
+            ctx.update(name.as_bytes());
+            ctx.update(&[0])
+        }
+        ctx.update(directory_url.as_bytes());
+        let hash = base64::encode_config(ctx.finish(), base64::URL_SAFE_NO_PAD);
+        format!("{}_{}", prefix, hash)
+    }
+
+    async fn read(&self, file: String) -> io::Result<Option<Vec<u8>>> {
+        let cache = self.clone();
+        spawn_blocking(move || {
+            let path = cache.dir.join(file);
+            match fs::metadata(&path) {
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(err),
+            }
+            cache.check(&cache.dir)?;
+            cache.check(&path)?;
+            fs::read(&path).map(Some)
+        })
+        .await
+    }
+
+    async fn write(&self, file: String, contents: Vec<u8>) -> io::Result<()> {
+        let cache = self.clone();
+        spawn_blocking(move || {
+            let mut builder = DirBuilder::new();
+            builder.recursive(true);
+            #[cfg(unix)]
+            builder.mode(0o700);
+            builder.create(&cache.dir)?;
+            cache.restrict(&cache.dir, 0o700)?;
+            cache.check_owner(&cache.dir)?;
+
+            let path = cache.dir.join(file);
+            let mut options = OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            options.mode(0o600);
+            let mut f = options.open(&path)?;
+            cache.restrict(&path, 0o600)?;
+            cache.check_owner(&path)?;
+            f.write_all(&contents)
+        })
+        .await
+    }
+
+    /// Check that `path` is private: owned by the configured owner, if any, and not accessible by
+    /// group or others.
+    #[cfg(unix)]
+    fn check(&self, path: &Path) -> io::Result<()> {
+        self.check_owner(path)?;
+        let mode = fs::metadata(path)?.permissions().mode() & 0o777;
+        if mode & 0o077 != 0 {
+            if self.strict {
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!(
+                        "{} is accessible by group or others (mode {:o})",
+                        path.display(),
+                        mode
+                    ),
+                ));
+            }
+            warn!(
+                path = %path.display(),
+                mode = %format!("{:o}", mode),
+                "cache directory or file is accessible by group or others"
+            );
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn check(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn check_owner(&self, path: &Path) -> io::Result<()> {
+        let owner = match self.owner {
+            Some(owner) => owner,
+            None => return Ok(()),
+        };
+        let uid = fs::metadata(path)?.uid();
+        if uid != owner {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "{} is owned by uid {}, expected {}",
+                    path.display(),
+                    uid,
+                    owner
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn check_owner(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Set the permissions of `path` to `mode`, if they differ.
+    #[cfg(unix)]
+    fn restrict(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let permissions = fs::metadata(path)?.permissions();
+        if permissions.mode() & 0o777 != mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn restrict(&self, _path: &Path, _mode: u32) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CertCache for PrivateDirCache {
+    type EC = io::Error;
+
+    async fn load_cert(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+    ) -> Result<Option<Vec<u8>>, Self::EC> {
+        self.read(Self::file_name("cached_cert", domains, directory_url))
+            .await
+    }
+
+    async fn store_cert(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+        cert: &[u8],
+    ) -> Result<(), Self::EC> {
+        let file = Self::file_name("cached_cert", domains, directory_url);
+        self.write(file, cert.to_vec()).await
+    }
+}
+
+#[async_trait]
+impl AccountCache for PrivateDirCache {
+    type EA = io::Error;
+
+    async fn load_account(
+        &self,
+        contact: &[String],
+        directory_url: &str,
+    ) -> Result<Option<Vec<u8>>, Self::EA> {
+        self.read(Self::file_name("cached_account", contact, directory_url))
+            .await
+    }
+
+    async fn store_account(
+        &self,
+        contact: &[String],
+        directory_url: &str,
+        account: &[u8],
+    ) -> Result<(), Self::EA> {
+        let file = Self::file_name("cached_account", contact, directory_url);
+        self.write(file, account.to_vec()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::block_on;
+
+    use super::*;
+
+    /// A fresh directory under the system's temporary directory.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tide-acme-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn stores_entries_privately() {
+        let dir = temp_dir("private");
+        let cache = PrivateDirCache::new(dir.join("cache"));
+        let domains = vec!["domain.example".to_string()];
+        let url = "https://ca.example/directory";
+        assert_eq!(block_on(cache.load_cert(&domains, url)).unwrap(), None);
+        block_on(cache.store_cert(&domains, url, b"cert")).unwrap();
+        block_on(cache.store_account(&domains, url, b"account")).unwrap();
+        let cert = block_on(cache.load_cert(&domains, url)).unwrap();
+        assert_eq!(cert.as_deref(), Some(&b"cert"[..]));
+        let account = block_on(cache.load_account(&domains, url)).unwrap();
+        assert_eq!(account.as_deref(), Some(&b"account"[..]));
+        let other = block_on(cache.load_cert(&domains, "https://other.example")).unwrap();
+        assert_eq!(other, None);
+        #[cfg(unix)]
+        {
+            let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&dir.join("cache")), 0o700);
+            for entry in fs::read_dir(dir.join("cache")).unwrap() {
+                assert_eq!(mode(&entry.unwrap().path()), 0o600);
+            }
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_accessible_entries_when_strict() {
+        let dir = temp_dir("strict");
+        let cache = PrivateDirCache::new(&dir);
+        let domains = vec!["domain.example".to_string()];
+        block_on(cache.store_cert(&domains, "url", b"cert")).unwrap();
+        let file = dir.join(PrivateDirCache::file_name("cached_cert", &domains, "url"));
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(block_on(cache.load_cert(&domains, "url"))
+            .unwrap()
+            .is_some());
+        let strict = cache.clone().strict(true);
+        let err = block_on(strict.load_cert(&domains, "url")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+        let uid = fs::metadata(&file).unwrap().uid();
+        let other = cache.owner(uid + 1);
+        let err = block_on(other.load_cert(&domains, "url")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
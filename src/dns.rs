@@ -0,0 +1,528 @@
+//! Minimal DNS client, for checking that dns-01 challenge records have propagated.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use async_std::future::timeout;
+use async_std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use futures_lite::io::{AsyncReadExt, AsyncWriteExt};
+use http_types::{Method, Request};
+use ring::rand::{SecureRandom, SystemRandom};
+use thiserror::Error;
+
+use crate::https::{send, HttpsRequestError};
+
+const TYPE_NS: u16 = 2;
+const TYPE_CNAME: u16 = 5;
+const TYPE_TXT: u16 = 16;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u8 = 3;
+/// The header flag set on a response cut short to fit a UDP datagram.
+const FLAG_TC: u16 = 0x0200;
+/// The longest label and encoded name allowed, RFC 1035 section 2.3.4.
+const MAX_LABEL_LEN: usize = 63;
+const MAX_NAME_LEN: usize = 255;
+
+/// How long to wait for a nameserver to answer a query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many CNAMEs to follow from the record's name.
+const MAX_CNAMES: usize = 8;
+/// The resolvers used to find a domain's authoritative nameservers, tried in order.
+const BOOTSTRAP_RESOLVERS: [&str; 2] = ["1.1.1.1:53", "8.8.8.8:53"];
+
+/// Which resolvers check that a dns-01 challenge record has propagated, before the CA is asked
+/// to validate it; see [`AcmeConfig::dns_resolution`](crate::AcmeConfig::dns_resolution).
+///
+/// Checking through the host's stub resolver is deliberately not offered: behind split-horizon
+/// DNS it sees records the CA can't, so the check passes while validation fails.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DnsResolution {
+    /// Trust the [`DnsProvider`](crate::DnsProvider) to return once the record is visible,
+    /// without checking. The default.
+    #[default]
+    Provider,
+    /// Ask each of the domain's authoritative nameservers, as the CA does. They're found via
+    /// public resolvers (1.1.1.1 and 8.8.8.8), and CNAMEs are followed to the zone they point
+    /// into.
+    Authoritative,
+    /// Ask each of these recursive resolvers, such as `8.8.8.8:53`. They may answer from their
+    /// cache, so a record that was just changed can take up to its TTL to show up.
+    Recursive(Vec<SocketAddr>),
+    /// Ask a DNS-over-HTTPS resolver at this URL, such as `https://cloudflare-dns.com/dns-query`,
+    /// with RFC 8484 GET requests. For networks that block outgoing DNS.
+    DnsOverHttps(String),
+}
+
+impl DnsResolution {
+    /// The servers that don't yet see `value` among the TXT records of `name`.
+    pub(crate) async fn missing(&self, name: &str, value: &str) -> Result<Vec<String>, DnsError> {
+        let mut name = name.to_owned();
+        for _ in 0..MAX_CNAMES {
+            let servers = match self {
+                Self::Provider => return Ok(vec![]),
+                Self::Authoritative => nameservers(&name)
+                    .await?
+                    .into_iter()
+                    .map(|addr| Server::Udp {
+                        addr,
+                        recursive: false,
+                    })
+                    .collect(),
+                Self::Recursive(addrs) => addrs
+                    .iter()
+                    .map(|&addr| Server::Udp {
+                        addr,
+                        recursive: true,
+                    })
+                    .collect(),
+                Self::DnsOverHttps(url) => vec![Server::Https(url.clone())],
+            };
+            let mut missing = vec![];
+            let mut cname = None;
+            for server in &servers {
+                let answers = server.query(&name, TYPE_TXT).await?;
+                if answers.iter().any(|answer| answer.txt() == Some(value)) {
+                    continue;
+                }
+                match answers.iter().find_map(Answer::cname) {
+                    Some(target) => cname = Some(target.to_owned()),
+                    None => missing.push(server.to_string()),
+                }
+            }
+            match cname {
+                Some(target) => name = target,
+                None => return Ok(missing),
+            }
+        }
+        Err(DnsError::TooManyCnames(name))
+    }
+}
+
+/// The addresses of the authoritative nameservers of the zone containing `name`.
+async fn nameservers(name: &str) -> Result<Vec<SocketAddr>, DnsError> {
+    let mut zone = name.trim_end_matches('.');
+    loop {
+        let hosts: Vec<String> = bootstrap_query(zone, TYPE_NS)
+            .await?
+            .into_iter()
+            .filter_map(|answer| match answer {
+                Answer::Ns(host) => Some(host),
+                _ => None,
+            })
+            .collect();
+        if !hosts.is_empty() {
+            let mut addrs = vec![];
+            for host in hosts {
+                addrs.extend((host.as_str(), 53).to_socket_addrs().await?);
+            }
+            return Ok(addrs);
+        }
+        zone = match zone.split_once('.') {
+            Some((_, parent)) => parent,
+            None => return Err(DnsError::NoNameservers(name.into())),
+        };
+    }
+}
+
+/// Query the first bootstrap resolver that answers.
+async fn bootstrap_query(name: &str, typ: u16) -> Result<Vec<Answer>, DnsError> {
+    let mut result = Err(DnsError::NoNameservers(name.into()));
+    for addr in BOOTSTRAP_RESOLVERS {
+        let server = Server::Udp {
+            addr: addr.parse().expect("valid resolver address"),
+            recursive: true,
+        };
+        result = server.query(name, typ).await;
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
+
+enum Server {
+    Udp { addr: SocketAddr, recursive: bool },
+    Https(String),
+}
+
+impl fmt::Display for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Udp { addr, .. } => write!(f, "{}", addr),
+            Self::Https(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+impl Server {
+    async fn query(&self, name: &str, typ: u16) -> Result<Vec<Answer>, DnsError> {
+        match self {
+            Self::Udp { addr, recursive } => {
+                let mut id = [0; 2];
+                SystemRandom::new()
+                    .fill(&mut id)
+                    .map_err(|_| DnsError::Malformed)?;
+                let id = u16::from_be_bytes(id);
+                let query = encode_query(id, name, typ, *recursive)?;
+                let local = match addr {
+                    SocketAddr::V4(_) => "0.0.0.0:0",
+                    SocketAddr::V6(_) => "[::]:0",
+                };
+                let socket = UdpSocket::bind(local).await?;
+                socket.connect(addr).await?;
+                socket.send(&query).await?;
+                let mut buf = [0; 4096];
+                let len = timeout(QUERY_TIMEOUT, socket.recv(&mut buf)).await??;
+                match parse_response(&buf[..len], id) {
+                    Err(DnsError::Truncated) => query_tcp(*addr, &query, id).await,
+                    result => result,
+                }
+            }
+            Self::Https(url) => {
+                // RFC 8484 section 4.1: the ID should be zero, for caching.
+                let query = encode_query(0, name, typ, true)?;
+                let dns = base64::encode_config(query, base64::URL_SAFE_NO_PAD);
+                let separator = if url.contains('?') { '&' } else { '?' };
+                let url = format!("{}{}dns={}", url, separator, dns);
+                let mut request = Request::new(Method::Get, url.as_str());
+                request.insert_header("accept", "application/dns-message");
+                let mut response = timeout(QUERY_TIMEOUT, send(request)).await??;
+                let body = response
+                    .body_bytes()
+                    .await
+                    .map_err(HttpsRequestError::from)?;
+                parse_response(&body, 0)
+            }
+        }
+    }
+}
+
+/// Repeat a query whose UDP response was truncated over TCP, RFC 7766 section 5.
+async fn query_tcp(addr: SocketAddr, query: &[u8], id: u16) -> Result<Vec<Answer>, DnsError> {
+    timeout(QUERY_TIMEOUT, async {
+        let mut stream = TcpStream::connect(addr).await?;
+        // Over TCP, each message is preceded by its length.
+        let len = (query.len() as u16).to_be_bytes();
+        stream.write_all(&[&len[..], query].concat()).await?;
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).await?;
+        let mut msg = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut msg).await?;
+        parse_response(&msg, id)
+    })
+    .await?
+}
+
+/// A record from the answer section of a response.
+enum Answer {
+    Txt(String),
+    Ns(String),
+    Cname(String),
+    Other,
+}
+
+impl Answer {
+    fn txt(&self) -> Option<&str> {
+        match self {
+            Self::Txt(txt) => Some(txt),
+            _ => None,
+        }
+    }
+
+    fn cname(&self) -> Option<&str> {
+        match self {
+            Self::Cname(target) => Some(target),
+            _ => None,
+        }
+    }
+}
+
+fn encode_query(id: u16, name: &str, typ: u16, recursive: bool) -> Result<Vec<u8>, DnsError> {
+    let flags: u16 = if recursive { 0x0100 } else { 0 };
+    let mut query = vec![];
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&flags.to_be_bytes());
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_LEN {
+            return Err(DnsError::InvalidName(name.into()));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    // The name follows the 12-byte header.
+    if query.len() - 12 > MAX_NAME_LEN {
+        return Err(DnsError::InvalidName(name.into()));
+    }
+    query.extend_from_slice(&typ.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+fn parse_response(msg: &[u8], id: u16) -> Result<Vec<Answer>, DnsError> {
+    if read_u16(msg, 0)? != id {
+        return Err(DnsError::Malformed);
+    }
+    let flags = read_u16(msg, 2)?;
+    // A truncated response may lack the record looked for, so it can't tell it's missing.
+    if flags & FLAG_TC != 0 {
+        return Err(DnsError::Truncated);
+    }
+    match (flags & 0x0f) as u8 {
+        0 => {}
+        RCODE_NXDOMAIN => return Ok(vec![]),
+        rcode => return Err(DnsError::Rcode(rcode)),
+    }
+    let questions = read_u16(msg, 4)?;
+    let answers = read_u16(msg, 6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(msg, pos)?.1 + 4;
+    }
+    let mut records = vec![];
+    for _ in 0..answers {
+        pos = read_name(msg, pos)?.1;
+        let typ = read_u16(msg, pos)?;
+        let len = read_u16(msg, pos + 8)? as usize;
+        let start = pos + 10;
+        let rdata = msg.get(start..start + len).ok_or(DnsError::Malformed)?;
+        records.push(match typ {
+            TYPE_TXT => {
+                // The record's character-strings, concatenated.
+                let mut txt = vec![];
+                let mut rest = rdata;
+                while let Some((&n, tail)) = rest.split_first() {
+                    let n = n as usize;
+                    txt.extend_from_slice(tail.get(..n).ok_or(DnsError::Malformed)?);
+                    rest = &tail[n..];
+                }
+                Answer::Txt(String::from_utf8_lossy(&txt).into_owned())
+            }
+            TYPE_NS => Answer::Ns(read_name(msg, start)?.0),
+            TYPE_CNAME => Answer::Cname(read_name(msg, start)?.0),
+            _ => Answer::Other,
+        });
+        pos = start + len;
+    }
+    Ok(records)
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> Result<u16, DnsError> {
+    match msg.get(pos..pos + 2) {
+        Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+        None => Err(DnsError::Malformed),
+    }
+}
+
+/// Read the possibly compressed name at `pos`, returning it and the position after it.
+fn read_name(msg: &[u8], mut pos: usize) -> Result<(String, usize), DnsError> {
+    let mut labels = vec![];
+    let mut end = None;
+    // Bounds the pointers followed, so a pointer loop can't hang.
+    for _ in 0..128 {
+        let len = *msg.get(pos).ok_or(DnsError::Malformed)? as usize;
+        if len & 0xc0 == 0xc0 {
+            end.get_or_insert(pos + 2);
+            pos = (read_u16(msg, pos)? & 0x3fff) as usize;
+        } else if len == 0 {
+            return Ok((labels.join("."), end.unwrap_or(pos + 1)));
+        } else {
+            let label = msg.get(pos + 1..pos + 1 + len).ok_or(DnsError::Malformed)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            pos += 1 + len;
+        }
+    }
+    Err(DnsError::Malformed)
+}
+
+/// Errors from checking DNS records.
+#[derive(Error, Debug)]
+pub(crate) enum DnsError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("timed out waiting for a nameserver")]
+    Timeout(#[from] async_std::future::TimeoutError),
+    #[error("dns-over-https request failed: {0}")]
+    Https(#[from] HttpsRequestError),
+    #[error("malformed dns response")]
+    Malformed,
+    #[error("truncated dns response")]
+    Truncated,
+    #[error("invalid dns name {0}")]
+    InvalidName(String),
+    #[error("nameserver answered with error code {0}")]
+    Rcode(u8),
+    #[error("no nameservers found for {0}")]
+    NoNameservers(String),
+    #[error("too many cnames, up to {0}")]
+    TooManyCnames(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::block_on;
+
+    use super::*;
+
+    fn response(id: u16, rcode: u8, answers: &[&[u8]]) -> Vec<u8> {
+        let mut msg = vec![];
+        msg.extend_from_slice(&id.to_be_bytes());
+        msg.extend_from_slice(&[0x81, 0x80 | rcode, 0, 1, 0, answers.len() as u8, 0, 0, 0, 0]);
+        msg.extend_from_slice(
+            &encode_query(id, "_acme-challenge.a.example", TYPE_TXT, true).unwrap()[12..],
+        );
+        for answer in answers {
+            msg.extend_from_slice(answer);
+        }
+        msg
+    }
+
+    fn record(typ: u16, rdata: &[u8]) -> Vec<u8> {
+        // The owner name points back at the question, at offset 12.
+        let mut record = vec![0xc0, 12];
+        record.extend_from_slice(&typ.to_be_bytes());
+        record.extend_from_slice(&CLASS_IN.to_be_bytes());
+        record.extend_from_slice(&[0, 0, 0, 60]);
+        record.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        record.extend_from_slice(rdata);
+        record
+    }
+
+    #[test]
+    fn encodes_queries() {
+        let query = encode_query(0x1234, "a.example.", TYPE_TXT, true).unwrap();
+        assert_eq!(
+            query,
+            [
+                &[0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0][..],
+                b"\x01a\x07example\x00",
+                &[0, 16, 0, 1],
+            ]
+            .concat()
+        );
+        let query = encode_query(0, "a.example", TYPE_NS, false).unwrap();
+        assert_eq!(query[2..4], [0, 0]);
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        let label = "a".repeat(63);
+        assert!(encode_query(0, &format!("{}.example", label), TYPE_TXT, true).is_ok());
+        let long = format!("a{}.example", label);
+        let empty = "a..example";
+        // Four labels of 63 bytes encode to 257 bytes, with their lengths and the root.
+        let name = [&label[..]; 4].join(".");
+        for name in [&long[..], empty, &name, ""] {
+            assert!(matches!(
+                encode_query(0, name, TYPE_TXT, true),
+                Err(DnsError::InvalidName(_))
+            ));
+        }
+        let name = [&label[..], &label, &label, &label[..61]].join(".");
+        assert_eq!(
+            encode_query(0, &name, TYPE_TXT, true).unwrap().len(),
+            12 + 255 + 4
+        );
+    }
+
+    #[test]
+    fn parses_txt_and_compressed_names() {
+        let txt = record(TYPE_TXT, b"\x03abc\x03def");
+        // "www" followed by a pointer to "a.example" in the question.
+        let cname = record(TYPE_CNAME, b"\x03www\xc0\x1c");
+        let msg = response(7, 0, &[&txt, &cname]);
+        let answers = parse_response(&msg, 7).unwrap();
+        assert_eq!(answers[0].txt(), Some("abcdef"));
+        assert_eq!(answers[1].cname(), Some("www.a.example"));
+        assert!(matches!(parse_response(&msg, 8), Err(DnsError::Malformed)));
+    }
+
+    #[test]
+    fn follows_chained_compression_pointers() {
+        // The owner name points at the cname target, which points into the question. The
+        // target follows the header, the question and the cname's own fixed fields.
+        let target = 12 + 27 + 4 + 12;
+        let cname = record(TYPE_CNAME, b"\x03www\xc0\x1c");
+        let mut txt = vec![0xc0, target];
+        txt.extend_from_slice(&record(TYPE_TXT, b"\x03abc")[2..]);
+        let msg = response(7, 0, &[&cname, &txt]);
+        assert_eq!(read_name(&msg, target.into()).unwrap().0, "www.a.example");
+        let answers = parse_response(&msg, 7).unwrap();
+        assert_eq!(answers[1].txt(), Some("abc"));
+        // A pointer past the end of the message.
+        let msg = response(7, 0, &[&record(TYPE_CNAME, b"\xc0\xff")]);
+        assert!(matches!(parse_response(&msg, 7), Err(DnsError::Malformed)));
+    }
+
+    #[test]
+    fn treats_nxdomain_as_no_answers() {
+        let msg = response(7, RCODE_NXDOMAIN, &[]);
+        assert!(parse_response(&msg, 7).unwrap().is_empty());
+        let msg = response(7, 2, &[]);
+        assert!(matches!(parse_response(&msg, 7), Err(DnsError::Rcode(2))));
+        // NXDOMAIN for the name a CNAME points at, answered along with the CNAME.
+        let cname = record(TYPE_CNAME, b"\x03www\xc0\x1c");
+        let msg = response(7, RCODE_NXDOMAIN, &[&cname]);
+        assert!(parse_response(&msg, 7).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_truncated_responses() {
+        let mut msg = response(7, 0, &[&record(TYPE_TXT, b"\x03abc")]);
+        msg[2] |= (FLAG_TC >> 8) as u8;
+        assert!(matches!(parse_response(&msg, 7), Err(DnsError::Truncated)));
+    }
+
+    #[test]
+    fn retries_truncated_responses_over_tcp() {
+        let udp = block_on(UdpSocket::bind("127.0.0.1:0")).unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp = block_on(async_std::net::TcpListener::bind(addr)).unwrap();
+        let server = async_std::task::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, client) = udp.recv_from(&mut buf).await.unwrap();
+            let id = read_u16(&buf[..len], 0).unwrap();
+            let mut truncated = response(id, 0, &[]);
+            truncated[2] |= (FLAG_TC >> 8) as u8;
+            udp.send_to(&truncated, client).await.unwrap();
+
+            let (mut stream, _) = tcp.accept().await.unwrap();
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).await.unwrap();
+            let mut query = vec![0; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut query).await.unwrap();
+            assert_eq!(read_u16(&query, 0).unwrap(), id);
+            let full = response(id, 0, &[&record(TYPE_TXT, b"\x03abc")]);
+            let len = (full.len() as u16).to_be_bytes();
+            stream.write_all(&[&len[..], &full].concat()).await.unwrap();
+        });
+        let resolution = DnsResolution::Recursive(vec![addr]);
+        let name = "_acme-challenge.a.example";
+        assert!(block_on(resolution.missing(name, "abc"))
+            .unwrap()
+            .is_empty());
+        block_on(server);
+    }
+
+    #[test]
+    fn rejects_cut_off_responses() {
+        let msg = response(7, 0, &[&record(TYPE_TXT, b"\x03abc")]);
+        for len in [0, 5, 20, msg.len() - 1].iter() {
+            assert!(matches!(
+                parse_response(&msg[..*len], 7),
+                Err(DnsError::Malformed)
+            ));
+        }
+        // A name pointing at itself.
+        assert!(matches!(read_name(&[0xc0, 0], 0), Err(DnsError::Malformed)));
+    }
+
+    #[test]
+    fn trusts_the_provider_by_default() {
+        let missing = block_on(DnsResolution::default().missing("a.example", "value"));
+        assert!(missing.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,555 @@
+//! A cache wrapper encrypting entries before they reach the underlying cache.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt::Debug;
+use std::future::Future;
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
+
+use async_std::task::spawn_blocking;
+use async_trait::async_trait;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::pbkdf2::{self, PBKDF2_HMAC_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use rustls_acme::{AccountCache, CertCache};
+use thiserror::Error;
+use tracing::{info, warn};
+use zeroize::{Zeroize, Zeroizing};
+
+/// Marker starting each encrypted entry, followed by a version byte.
+const MAGIC: &[u8] = b"tide-acme-encrypted";
+const VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+/// PBKDF2 iterations deriving keys from passphrases, as OWASP recommends for HMAC-SHA256.
+const PBKDF2_ITERATIONS: u32 = 600_000;
+
+/// Cache wrapper encrypting certificates, with their private keys, and account keys with
+/// AES-256-GCM before passing them to the underlying cache `C`, so that a shared directory,
+/// bucket or database only ever holds ciphertext.
+///
+/// The key is either 32 random bytes, or derived from a passphrase with PBKDF2-HMAC-SHA256 and a
+/// random salt stored with each entry. Each entry is bound to the domains or contacts and the
+/// directory it was stored for, so entries can't be swapped for one another in the underlying
+/// cache. Entries that fail to decrypt, such as with the wrong key, fail to load.
+///
+/// To rotate the key, make the new key current and pass the old one to
+/// [`previous_key`](Self::previous_key) or [`previous_passphrase`](Self::previous_passphrase).
+/// Entries that only decrypt with a previous key are re-encrypted under the current one as they
+/// are loaded, and [`AcmeHandle::reencrypt_cache`](crate::AcmeHandle::reencrypt_cache) loads
+/// every entry the acceptor uses. Once that has succeeded, drop the previous key. Certificates
+/// go on being served throughout, and nothing is ordered again.
+///
+/// ```no_run
+/// use tide_acme::{AcmeConfig, EncryptedCache, PrivateDirCache};
+///
+/// let passphrase = std::env::var("TIDE_ACME_CACHE_PASSPHRASE").unwrap();
+/// let config = AcmeConfig::new(vec!["domain.example"]).cache(EncryptedCache::with_passphrase(
+///     PrivateDirCache::new("/srv/example/tide-acme-cache-dir"),
+///     passphrase,
+/// ));
+/// ```
+#[derive(Clone)]
+pub struct EncryptedCache<C> {
+    inner: C,
+    secret: Secret,
+    /// Secrets entries are still decrypted with, but no longer encrypted with.
+    previous: Vec<Secret>,
+    allow_plaintext: bool,
+}
+
+#[derive(Clone)]
+enum Secret {
+    Key(Arc<LessSafeKey>),
+    Passphrase {
+        passphrase: Arc<Zeroizing<Vec<u8>>>,
+        /// The salt of entries this process stores, and the keys derived for each salt seen.
+        salt: [u8; SALT_LEN],
+        keys: Arc<Mutex<HashMap<[u8; SALT_LEN], Arc<LessSafeKey>>>>,
+    },
+}
+
+impl<C: Debug> Debug for EncryptedCache<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptedCache")
+            .field("inner", &self.inner)
+            .field("previous_keys", &self.previous.len())
+            .field("allow_plaintext", &self.allow_plaintext)
+            .finish()
+    }
+}
+
+impl<C> EncryptedCache<C> {
+    /// Wrap `inner`, encrypting with the AES-256 key `key`, which should be random, such as from
+    /// a secrets manager.
+    pub fn new(inner: C, key: [u8; 32]) -> Self {
+        Self {
+            inner,
+            secret: Secret::from_key(key),
+            previous: vec![],
+            allow_plaintext: false,
+        }
+    }
+
+    /// Wrap `inner`, encrypting with a key derived from `passphrase`.
+    ///
+    /// Deriving a key is deliberately slow, so it is done once per salt, off the executor, and
+    /// kept in memory.
+    pub fn with_passphrase(inner: C, passphrase: impl AsRef<[u8]>) -> Self {
+        Self {
+            inner,
+            secret: Secret::from_passphrase(passphrase.as_ref()),
+            previous: vec![],
+            allow_plaintext: false,
+        }
+    }
+
+    /// Also decrypt entries encrypted with the AES-256 key `key`, such as the key before a
+    /// rotation, re-encrypting them under the current key as they are loaded. Can be called
+    /// several times; previous keys are tried in the order given.
+    pub fn previous_key(mut self, key: [u8; 32]) -> Self {
+        self.previous.push(Secret::from_key(key));
+        self
+    }
+
+    /// Also decrypt entries encrypted with a key derived from `passphrase`, like
+    /// [`previous_key`](Self::previous_key).
+    pub fn previous_passphrase(mut self, passphrase: impl AsRef<[u8]>) -> Self {
+        self.previous
+            .push(Secret::from_passphrase(passphrase.as_ref()));
+        self
+    }
+
+    /// Load entries that aren't encrypted if `allow_plaintext` is true, rather than failing, to
+    /// migrate an existing cache: each entry is encrypted the next time it is stored. Defaults to
+    /// false, so that an attacker able to write to the underlying cache can't plant a key.
+    pub fn allow_plaintext(mut self, allow_plaintext: bool) -> Self {
+        self.allow_plaintext = allow_plaintext;
+        self
+    }
+
+    /// Encrypt `plaintext`, binding it to `aad`. Empty entries, which mark unused slots, are
+    /// stored as they are.
+    async fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if plaintext.is_empty() {
+            return Ok(vec![]);
+        }
+        let salt = match &self.secret {
+            Secret::Key(_) => [0; SALT_LEN],
+            Secret::Passphrase { salt, .. } => *salt,
+        };
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| CryptoError::Random)?;
+        let mut sealed = Zeroizing::new(plaintext.to_vec());
+        self.secret
+            .key(salt)
+            .await
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(aad),
+                &mut *sealed,
+            )
+            .map_err(|_| CryptoError::Encrypt)?;
+        let mut entry = MAGIC.to_vec();
+        entry.push(VERSION);
+        entry.extend_from_slice(&salt);
+        entry.extend_from_slice(&nonce);
+        entry.extend_from_slice(&sealed);
+        Ok(entry)
+    }
+
+    /// Decrypt an entry stored by [`seal`](Self::seal) with the same `aad`, trying the current
+    /// secret and then the previous ones. Returns the plaintext, and whether it was decrypted
+    /// with a previous secret, so should be encrypted again.
+    async fn open(&self, entry: Vec<u8>, aad: &[u8]) -> Result<(Vec<u8>, bool), CryptoError> {
+        let rest = match entry.strip_prefix(MAGIC) {
+            Some(rest) => rest,
+            None if self.allow_plaintext || entry.is_empty() => return Ok((entry, false)),
+            None => return Err(CryptoError::NotEncrypted),
+        };
+        match rest.split_first() {
+            Some((&VERSION, _)) => {}
+            Some((&version, _)) => return Err(CryptoError::UnsupportedVersion(version)),
+            None => return Err(CryptoError::Malformed),
+        }
+        let rest = &rest[1..];
+        if rest.len() < SALT_LEN + NONCE_LEN {
+            return Err(CryptoError::Malformed);
+        }
+        let salt: [u8; SALT_LEN] = rest[..SALT_LEN].try_into().unwrap();
+        let nonce: [u8; NONCE_LEN] = rest[SALT_LEN..SALT_LEN + NONCE_LEN].try_into().unwrap();
+        let sealed = &rest[SALT_LEN + NONCE_LEN..];
+        let secrets = std::iter::once(&self.secret).chain(&self.previous);
+        for (n, secret) in secrets.enumerate() {
+            // A failed attempt leaves the buffer unspecified, so each works on its own copy, which
+            // becomes the plaintext.
+            let mut opened = Zeroizing::new(sealed.to_vec());
+            let key = secret.key(salt).await;
+            let nonce = Nonce::assume_unique_for_key(nonce);
+            if let Ok(plaintext) = key.open_in_place(nonce, Aad::from(aad), &mut opened) {
+                let len = plaintext.len();
+                opened.truncate(len);
+                return Ok((std::mem::take(&mut *opened), n > 0));
+            }
+        }
+        Err(CryptoError::Decrypt)
+    }
+
+    /// Load an entry with `load`, decrypting it, and store it again with `store` if it was
+    /// encrypted with a previous secret.
+    async fn load<E: Debug, L, S>(
+        &self,
+        load: L,
+        store: impl FnOnce(Vec<u8>) -> S,
+        aad: &[u8],
+    ) -> Result<Option<Vec<u8>>, EncryptedCacheError<E>>
+    where
+        L: Future<Output = Result<Option<Vec<u8>>, E>>,
+        S: Future<Output = Result<(), E>>,
+    {
+        let entry = match load.await.map_err(EncryptedCacheError::Cache)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let (plaintext, stale) = self.open(entry, aad).await?;
+        if stale {
+            let entry = self.seal(&plaintext, aad).await?;
+            match store(entry).await {
+                Ok(()) => info!("re-encrypted cache entry under the current key"),
+                Err(err) => warn!(
+                    ?err,
+                    "failed to re-encrypt cache entry under the current key"
+                ),
+            }
+        }
+        Ok(Some(plaintext))
+    }
+}
+
+impl Secret {
+    fn from_key(mut key: [u8; 32]) -> Self {
+        let unbound = UnboundKey::new(&AES_256_GCM, &key).expect("AES-256 keys are 32 bytes");
+        key.zeroize();
+        Secret::Key(Arc::new(LessSafeKey::new(unbound)))
+    }
+
+    fn from_passphrase(passphrase: &[u8]) -> Self {
+        let mut salt = [0; SALT_LEN];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .expect("system random number generator failed");
+        Secret::Passphrase {
+            passphrase: Arc::new(Zeroizing::new(passphrase.to_vec())),
+            salt,
+            keys: Arc::default(),
+        }
+    }
+
+    /// The key for entries with `salt`, deriving it from the passphrase if not done yet.
+    async fn key(&self, salt: [u8; SALT_LEN]) -> Arc<LessSafeKey> {
+        let (passphrase, keys) = match self {
+            Secret::Key(key) => return key.clone(),
+            Secret::Passphrase {
+                passphrase, keys, ..
+            } => (passphrase.clone(), keys),
+        };
+        if let Some(key) = keys.lock().unwrap().get(&salt) {
+            return key.clone();
+        }
+        let key = spawn_blocking(move || {
+            let mut key = [0; 32];
+            pbkdf2::derive(
+                PBKDF2_HMAC_SHA256,
+                NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+                &salt,
+                &passphrase,
+                &mut key,
+            );
+            let unbound = UnboundKey::new(&AES_256_GCM, &key).expect("AES-256 keys are 32 bytes");
+            key.zeroize();
+            Arc::new(LessSafeKey::new(unbound))
+        })
+        .await;
+        keys.lock().unwrap().insert(salt, key.clone());
+        key
+    }
+}
+
+/// The associated data binding an entry to what it was stored for.
+fn aad(kind: &str, names: &[String], directory_url: &str) -> Vec<u8> {
+    let mut aad = kind.as_bytes().to_vec();
+    for name in names {
+        aad.push(0);
+        aad.extend_from_slice(name.as_bytes());
+    }
+    aad.push(0);
+    aad.extend_from_slice(directory_url.as_bytes());
+    aad
+}
+
+/// Errors from an [`EncryptedCache`]: those of the underlying cache, or of encryption.
+#[derive(Error, Debug)]
+pub enum EncryptedCacheError<E: Debug> {
+    /// The underlying cache failed.
+    #[error("cache error: {0:?}")]
+    Cache(E),
+    /// Encrypting or decrypting an entry failed.
+    #[error("{0}")]
+    Crypto(#[from] CryptoError),
+}
+
+/// Why encrypting or decrypting a cache entry failed.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum CryptoError {
+    /// The system random number generator failed.
+    #[error("random number generator failed")]
+    Random,
+    /// Encrypting the entry failed.
+    #[error("encrypting cache entry failed")]
+    Encrypt,
+    /// The entry couldn't be decrypted: the key is wrong, or the entry was tampered with or
+    /// stored for something else.
+    #[error("decrypting cache entry failed; wrong key, or tampered entry")]
+    Decrypt,
+    /// The entry isn't encrypted, and plaintext entries aren't allowed.
+    #[error("cache entry is not encrypted")]
+    NotEncrypted,
+    /// The entry was encrypted by a later version of tide-acme.
+    #[error("cache entry is encrypted with unsupported format version {0}")]
+    UnsupportedVersion(u8),
+    /// The entry is truncated.
+    #[error("malformed encrypted cache entry")]
+    Malformed,
+}
+
+#[async_trait]
+impl<C: CertCache> CertCache for EncryptedCache<C> {
+    type EC = EncryptedCacheError<C::EC>;
+
+    async fn load_cert(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+    ) -> Result<Option<Vec<u8>>, Self::EC> {
+        let load = self.inner.load_cert(domains, directory_url);
+        let store = |entry: Vec<u8>| async move {
+            self.inner.store_cert(domains, directory_url, &entry).await
+        };
+        self.load(load, store, &aad("cert", domains, directory_url))
+            .await
+    }
+
+    async fn store_cert(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+        cert: &[u8],
+    ) -> Result<(), Self::EC> {
+        let entry = self
+            .seal(cert, &aad("cert", domains, directory_url))
+            .await?;
+        self.inner
+            .store_cert(domains, directory_url, &entry)
+            .await
+            .map_err(EncryptedCacheError::Cache)
+    }
+}
+
+#[async_trait]
+impl<C: AccountCache> AccountCache for EncryptedCache<C> {
+    type EA = EncryptedCacheError<C::EA>;
+
+    async fn load_account(
+        &self,
+        contact: &[String],
+        directory_url: &str,
+    ) -> Result<Option<Vec<u8>>, Self::EA> {
+        let load = self.inner.load_account(contact, directory_url);
+        let store = |entry: Vec<u8>| async move {
+            self.inner
+                .store_account(contact, directory_url, &entry)
+                .await
+        };
+        self.load(load, store, &aad("account", contact, directory_url))
+            .await
+    }
+
+    async fn store_account(
+        &self,
+        contact: &[String],
+        directory_url: &str,
+        account: &[u8],
+    ) -> Result<(), Self::EA> {
+        let entry = self
+            .seal(account, &aad("account", contact, directory_url))
+            .await?;
+        self.inner
+            .store_account(contact, directory_url, &entry)
+            .await
+            .map_err(EncryptedCacheError::Cache)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::block_on;
+
+    use super::*;
+
+    /// A cache in memory, shared by its clones.
+    #[derive(Clone, Default)]
+    struct MemoryCache(Arc<Mutex<HashMap<Vec<String>, Vec<u8>>>>);
+
+    #[async_trait]
+    impl CertCache for MemoryCache {
+        type EC = std::convert::Infallible;
+
+        async fn load_cert(
+            &self,
+            domains: &[String],
+            _directory_url: &str,
+        ) -> Result<Option<Vec<u8>>, Self::EC> {
+            Ok(self.0.lock().unwrap().get(domains).cloned())
+        }
+
+        async fn store_cert(
+            &self,
+            domains: &[String],
+            _directory_url: &str,
+            cert: &[u8],
+        ) -> Result<(), Self::EC> {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(domains.to_vec(), cert.to_vec());
+            Ok(())
+        }
+    }
+
+    const DIRECTORY: &str = "https://acme.example/directory";
+
+    fn domains() -> Vec<String> {
+        vec!["domain.example".into()]
+    }
+
+    fn load(cache: &EncryptedCache<MemoryCache>) -> Result<Option<Vec<u8>>, CryptoError> {
+        block_on(cache.load_cert(&domains(), DIRECTORY)).map_err(|err| match err {
+            EncryptedCacheError::Crypto(err) => err,
+            EncryptedCacheError::Cache(err) => match err {},
+        })
+    }
+
+    fn store(cache: &EncryptedCache<MemoryCache>, entry: &[u8]) {
+        block_on(cache.store_cert(&domains(), DIRECTORY, entry)).unwrap();
+    }
+
+    #[test]
+    fn round_trips_entries() {
+        let cache = EncryptedCache::new((), [7; 32]);
+        let sealed = block_on(cache.seal(b"secret", b"aad")).unwrap();
+        assert!(sealed.starts_with(MAGIC));
+        assert!(!sealed.windows(6).any(|window| window == b"secret"));
+        assert_eq!(
+            block_on(cache.open(sealed.clone(), b"aad")).unwrap(),
+            (b"secret".to_vec(), false)
+        );
+        // Entries are bound to their names, and to the key.
+        assert!(matches!(
+            block_on(cache.open(sealed.clone(), b"other")),
+            Err(CryptoError::Decrypt)
+        ));
+        let other = EncryptedCache::new((), [8; 32]);
+        assert!(matches!(
+            block_on(other.open(sealed, b"aad")),
+            Err(CryptoError::Decrypt)
+        ));
+        assert!(block_on(cache.seal(b"", b"aad")).unwrap().is_empty());
+        assert!(block_on(cache.open(vec![], b"aad")).unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn rejects_plaintext_and_malformed_entries() {
+        let cache = EncryptedCache::new((), [7; 32]);
+        let open =
+            |cache: &EncryptedCache<()>, entry: &[u8]| block_on(cache.open(entry.to_vec(), b""));
+        assert!(matches!(
+            open(&cache, b"plain"),
+            Err(CryptoError::NotEncrypted)
+        ));
+        assert_eq!(
+            open(&cache.clone().allow_plaintext(true), b"plain")
+                .unwrap()
+                .0,
+            b"plain"
+        );
+        let entry = [MAGIC, &[2]].concat();
+        assert!(matches!(
+            open(&cache, &entry),
+            Err(CryptoError::UnsupportedVersion(2))
+        ));
+        let entry = [MAGIC, &[VERSION, 0, 0]].concat();
+        assert!(matches!(open(&cache, &entry), Err(CryptoError::Malformed)));
+    }
+
+    #[test]
+    fn derives_keys_from_passphrases() {
+        let cache = EncryptedCache::with_passphrase((), "passphrase");
+        let sealed = block_on(cache.seal(b"secret", b"")).unwrap();
+        // Another instance salts its own entries differently, but reads these.
+        let other = EncryptedCache::with_passphrase((), "passphrase");
+        assert_eq!(block_on(other.open(sealed, b"")).unwrap().0, b"secret");
+        let resealed = block_on(other.seal(b"secret", b"")).unwrap();
+        let salt = |entry: &[u8]| entry[MAGIC.len() + 1..][..SALT_LEN].to_vec();
+        let sealed = block_on(cache.seal(b"secret", b"")).unwrap();
+        assert_ne!(salt(&sealed), salt(&resealed));
+    }
+
+    #[test]
+    fn binds_entries_to_their_names() {
+        let names = ["a.example".to_string(), "b.example".to_string()];
+        assert_eq!(
+            aad("cert", &names, "https://acme.test"),
+            b"cert\0a.example\0b.example\0https://acme.test"
+        );
+        assert_ne!(
+            aad("cert", &names, "https://acme.test"),
+            aad("account", &names, "https://acme.test")
+        );
+    }
+
+    #[test]
+    fn rejects_entries_moved_to_other_names() {
+        let inner = MemoryCache::default();
+        let cache = EncryptedCache::new(inner.clone(), [1; 32]);
+        store(&cache, b"private key");
+        let stored = inner.0.lock().unwrap()[&domains()].clone();
+        let other = vec!["other.example".to_string()];
+        inner.0.lock().unwrap().insert(other.clone(), stored);
+        let loaded = block_on(cache.load_cert(&other, DIRECTORY));
+        assert!(matches!(
+            loaded,
+            Err(EncryptedCacheError::Crypto(CryptoError::Decrypt))
+        ));
+    }
+
+    #[test]
+    fn reencrypts_under_the_current_key() {
+        let inner = MemoryCache::default();
+        store(&EncryptedCache::new(inner.clone(), [1; 32]), b"private key");
+        let rotated = EncryptedCache::new(inner.clone(), [2; 32]).previous_key([1; 32]);
+        assert_eq!(
+            load(&rotated).unwrap().as_deref(),
+            Some(&b"private key"[..])
+        );
+        let current = EncryptedCache::new(inner.clone(), [2; 32]);
+        assert_eq!(
+            load(&current).unwrap().as_deref(),
+            Some(&b"private key"[..])
+        );
+        let old = EncryptedCache::new(inner, [1; 32]);
+        assert!(matches!(load(&old), Err(CryptoError::Decrypt)));
+    }
+}
@@ -0,0 +1,88 @@
+//! The causes of failures in the background management of certificates.
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::problem::Problem;
+
+/// Why the background management of a certificate failed, as reported in
+/// [`AcmeEvent::failure`](crate::AcmeEvent::failure) and
+/// [`CertStatus::last_failure`](crate::CertStatus::last_failure), to act on failures by their
+/// cause rather than by their message.
+///
+/// The underlying errors are kept as their descriptions, so that failures can be cloned, saved
+/// in [snapshots](crate::Snapshot) and compared across versions. More variants may be added.
+///
+/// ```no_run
+/// # async fn example(handle: tide_acme::AcmeHandle) {
+/// use futures::StreamExt;
+/// use tide_acme::{Error, EventFilter};
+///
+/// let mut events = handle.events(EventFilter::new().failures_only(true));
+/// while let Some(event) = events.next().await {
+///     match event.failure {
+///         Some(Error::Rejected(problem)) => eprintln!("the CA said no: {}", problem.typ),
+///         Some(Error::Io(err)) => eprintln!("the CA is unreachable: {}", err),
+///         Some(err) => eprintln!("{}", err),
+///         None => {}
+///     }
+/// }
+/// # }
+/// ```
+#[derive(Clone, Debug, Error, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum Error {
+    /// Loading from or storing to the cache failed, or a cache entry was not in a format we can
+    /// read.
+    #[error("cache error: {0}")]
+    Cache(String),
+    /// Finding or registering the ACME account failed.
+    #[error("account error: {0}")]
+    Account(String),
+    /// The CA rejected a request of the order with this problem document.
+    #[error("the CA rejected the request: {}", .0.detail.as_deref().unwrap_or(&.0.typ))]
+    Rejected(Problem),
+    /// The order went wrong in another way, such as the CA responding with something we don't
+    /// understand, or the order not completing in time.
+    #[error("order error: {0}")]
+    Order(String),
+    /// The CA failed to validate the challenge for a domain. The event's
+    /// [`challenge_failure`](crate::AcmeEvent::challenge_failure) has the details the CA gave.
+    #[error("validation failed: {0}")]
+    Validation(String),
+    /// Talking to the CA failed at the network level, such as when it can't be reached.
+    #[error("io error: {0}")]
+    Io(String),
+    /// A certificate, from the cache or the CA, was invalid, such as not matching its key or not
+    /// covering the domains.
+    #[error("invalid certificate: {0}")]
+    Certificate(String),
+    /// The [challenge responder](crate::AcmeConfig::challenge_responder) failed.
+    #[error("challenge responder error: {0}")]
+    Challenge(String),
+    /// The [certificate source](crate::AcmeConfig::certificate_source) failed.
+    #[error("certificate source error: {0}")]
+    Source(String),
+    /// The [key provider](crate::AcmeConfig::key_provider) failed.
+    #[error("key provider error: {0}")]
+    KeyProvider(String),
+    /// The [key store](crate::AcmeConfig::key_store) failed.
+    #[error("key store error: {0}")]
+    KeyStore(String),
+    /// Taking the [order lock](crate::AcmeConfig::lock) failed.
+    #[error("order lock error: {0}")]
+    Lock(String),
+    /// The [pre-flight check](crate::AcmeConfig::preflight_check) found the domain unreachable.
+    #[error("pre-flight check failed: {0}")]
+    Preflight(String),
+    /// Revoking the certificate failed.
+    #[error("revocation error: {0}")]
+    Revocation(String),
+    /// Fetching the OCSP response to staple failed.
+    #[error("ocsp error: {0}")]
+    Ocsp(String),
+    /// A certificate being served is close to expiry, so its renewal is presumably failing; see
+    /// [`AcmeConfig::expiry_alarm`](crate::AcmeConfig::expiry_alarm).
+    #[error("certificate close to expiry: {0}")]
+    Expiring(String),
+}
@@ -0,0 +1,641 @@
+use std::fmt::Debug;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+
+use crate::error::Error;
+use crate::problem::{ChallengeFailure, Problem};
+use crate::state::{Event, EventError, EventOk};
+
+/// An event from the background management of a certificate, as returned by
+/// [`AcmeHandle::events`](crate::AcmeHandle::events).
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct AcmeEvent {
+    /// The domains of the certificate the event concerns.
+    pub domains: Vec<String>,
+    /// What happened.
+    pub kind: EventKind,
+    /// The URL of the ACME order, for events about an order.
+    pub order_url: Option<String>,
+    /// A stable name for the cause of the error, for failures, such as `cache` or `bad_auth`.
+    pub error_class: Option<&'static str>,
+    /// A description of the error, for failures.
+    pub error: Option<String>,
+    /// The cause of the error, for failures, to match on.
+    pub failure: Option<Error>,
+    /// The ACME problem type the CA reported, for failures it reported one for, such as
+    /// `urn:ietf:params:acme:error:rateLimited`.
+    pub problem_type: Option<String>,
+    /// An explanation of the problem and what to do about it, for common problem types; see
+    /// [`problem_hint`](crate::problem_hint).
+    pub hint: Option<&'static str>,
+    /// The problem document the CA reported, for failures it reported one for, with its
+    /// description and the problems of individual domains.
+    pub problem: Option<Problem>,
+    /// The challenge the CA failed to validate, for orders failing validation, with how the CA
+    /// attempted it.
+    pub challenge_failure: Option<ChallengeFailure>,
+    /// When the event happened.
+    pub at: SystemTime,
+    /// The name of the acceptor managing the certificate, if it was given one with
+    /// [`AcmeConfig::name`](crate::AcmeConfig::name).
+    pub acceptor: Option<String>,
+}
+
+impl AcmeEvent {
+    pub(crate) fn new<EC: Debug, EA: Debug>(
+        domains: &[String],
+        order_url: Option<&str>,
+        event: &Event<EC, EA>,
+    ) -> Self {
+        let kind = match event {
+            Ok(EventOk::DeployedCachedCert) => EventKind::DeployedCachedCert,
+            Ok(EventOk::DeployedNewCert) => EventKind::DeployedNewCert,
+            Ok(EventOk::DeployedPreviousCert) => EventKind::DeployedPreviousCert,
+            Ok(EventOk::NoPreviousCert) => EventKind::NoPreviousCert,
+            Ok(EventOk::RenewalRequested) => EventKind::RenewalRequested,
+            Ok(EventOk::StagingCheckPassed) => EventKind::StagingCheckPassed,
+            Ok(EventOk::CertCacheStore) => EventKind::CertCacheStore,
+            Ok(EventOk::AccountCacheStore) => EventKind::AccountCacheStore,
+            Ok(EventOk::CertCacheQuarantine) => EventKind::CertCacheQuarantine,
+            Ok(EventOk::AccountCacheQuarantine) => EventKind::AccountCacheQuarantine,
+            Ok(EventOk::CacheUnchanged) => EventKind::CacheUnchanged,
+            Ok(EventOk::CacheReencrypted) => EventKind::CacheReencrypted,
+            Ok(EventOk::DomainRemoved) => EventKind::DomainRemoved,
+            Ok(EventOk::CertRemoved) => EventKind::CertRemoved,
+            Ok(EventOk::AccountRegistered) => EventKind::AccountRegistered,
+            Ok(EventOk::CertCacheStoreSecondary) => EventKind::CertCacheStoreSecondary,
+            Ok(EventOk::AccountCacheStoreSecondary) => EventKind::AccountCacheStoreSecondary,
+            Ok(EventOk::OrderDeferred) => EventKind::OrderDeferred,
+            Ok(EventOk::AwaitingOrderer) => EventKind::AwaitingOrderer,
+            Ok(EventOk::CertRevoked) => EventKind::CertRevoked,
+            Ok(EventOk::NoCertToRevoke) => EventKind::NoCertToRevoke,
+            Ok(EventOk::OcspStapled) => EventKind::OcspStapled,
+            Ok(EventOk::OcspStaleServed) => EventKind::OcspStaleServed,
+            Ok(EventOk::OcspStapleDropped) => EventKind::OcspStapleDropped,
+            Ok(EventOk::OcspCertWithheld) => EventKind::OcspCertWithheld,
+            Err(EventError::CertCacheLoad(_)) | Err(EventError::CertCacheFormat(_)) => {
+                EventKind::CertCacheLoadFailed
+            }
+            Err(EventError::AccountCacheLoad(_)) | Err(EventError::AccountCacheFormat(_)) => {
+                EventKind::AccountCacheLoadFailed
+            }
+            Err(EventError::CertCacheStore(_)) => EventKind::CertCacheStoreFailed,
+            Err(EventError::AccountCacheStore(_)) => EventKind::AccountCacheStoreFailed,
+            Err(EventError::CachedCertParse(_)) => EventKind::CachedCertInvalid,
+            Err(EventError::Order(_)) | Err(EventError::StagingCheck(_)) => EventKind::OrderFailed,
+            Err(EventError::NewCertParse(_)) => EventKind::NewCertInvalid,
+            Err(EventError::PreviousCertParse(_)) => EventKind::PreviousCertInvalid,
+            Err(EventError::Revocation(_)) => EventKind::RevocationFailed,
+            Err(EventError::Ocsp(_)) => EventKind::OcspFetchFailed,
+        };
+        let problem = event.as_ref().err().and_then(EventError::problem);
+        Self {
+            domains: domains.to_vec(),
+            kind,
+            order_url: match kind.category() {
+                EventCategory::Order => order_url.map(Into::into),
+                _ => None,
+            },
+            error_class: event.as_ref().err().map(EventError::class),
+            error: event.as_ref().err().map(ToString::to_string),
+            failure: event.as_ref().err().map(EventError::failure),
+            hint: problem.as_ref().and_then(Problem::hint),
+            problem_type: problem.as_ref().map(|problem| problem.typ.clone()),
+            problem,
+            challenge_failure: event.as_ref().err().and_then(EventError::challenge_failure),
+            at: SystemTime::now(),
+            acceptor: None,
+        }
+    }
+
+    /// An alarm that the certificate for `domains` has only `remaining` validity left, less than
+    /// `threshold`.
+    pub(crate) fn expiry_alarm(
+        domains: &[String],
+        remaining: Duration,
+        threshold: Duration,
+    ) -> Self {
+        let error = format!(
+            "{}s of validity left, less than the {}s alarm threshold; renewal may be failing",
+            remaining.as_secs(),
+            threshold.as_secs()
+        );
+        Self {
+            domains: domains.to_vec(),
+            kind: EventKind::ExpiryAlarm,
+            order_url: None,
+            error_class: Some("expiry_alarm"),
+            failure: Some(Error::Expiring(error.clone())),
+            error: Some(error),
+            problem_type: None,
+            hint: None,
+            problem: None,
+            challenge_failure: None,
+            at: SystemTime::now(),
+            acceptor: None,
+        }
+    }
+
+    /// Whether this event is a failure.
+    pub fn is_failure(&self) -> bool {
+        self.error.is_some()
+    }
+
+    /// Format this event as a single line of JSON, for log pipelines.
+    ///
+    /// The field names are stable: `timestamp` (seconds since the Unix epoch), `event_type`,
+    /// `category`, `domains`, and, where they apply, `acceptor`, `order_url`, `error_class`,
+    /// `error`, `problem_type`, `problem_detail`, `hint` and `challenge_failure` (with
+    /// `domain`, `challenge_type` and the CA's `validation_records`).
+    ///
+    /// ```no_run
+    /// # async fn example(handle: tide_acme::AcmeHandle) {
+    /// use futures::StreamExt;
+    ///
+    /// let mut events = handle.events(tide_acme::EventFilter::new());
+    /// while let Some(event) = events.next().await {
+    ///     println!("{}", event.to_json());
+    /// }
+    /// # }
+    /// ```
+    pub fn to_json(&self) -> String {
+        let timestamp = self
+            .at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let mut json = json!({
+            "timestamp": timestamp,
+            "event_type": self.kind.name(),
+            "category": self.kind.category().name(),
+            "domains": self.domains,
+        });
+        if let Some(acceptor) = &self.acceptor {
+            json["acceptor"] = json!(acceptor);
+        }
+        if let Some(order_url) = &self.order_url {
+            json["order_url"] = json!(order_url);
+        }
+        if let Some(error_class) = self.error_class {
+            json["error_class"] = json!(error_class);
+        }
+        if let Some(error) = &self.error {
+            json["error"] = json!(error);
+        }
+        if let Some(problem_type) = &self.problem_type {
+            json["problem_type"] = json!(problem_type);
+        }
+        if let Some(detail) = self
+            .problem
+            .as_ref()
+            .and_then(|problem| problem.detail.as_ref())
+        {
+            json["problem_detail"] = json!(detail);
+        }
+        if let Some(hint) = self.hint {
+            json["hint"] = json!(hint);
+        }
+        if let Some(failure) = &self.challenge_failure {
+            json["challenge_failure"] = json!({
+                "domain": failure.domain,
+                "challenge_type": failure.challenge_type,
+                "validation_records": failure.validation_records,
+            });
+        }
+        json.to_string()
+    }
+}
+
+/// The kind of an [`AcmeEvent`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum EventKind {
+    /// A certificate loaded from the cache is being served.
+    DeployedCachedCert,
+    /// A newly issued certificate is being served.
+    DeployedNewCert,
+    /// A previous certificate is being served again, after a rollback.
+    DeployedPreviousCert,
+    /// A rollback was requested, but there was no previous certificate.
+    NoPreviousCert,
+    /// Renewal was requested, so a new certificate is ordered now.
+    RenewalRequested,
+    /// A certificate was obtained from the Let's Encrypt staging directory, so the production
+    /// order follows.
+    StagingCheckPassed,
+    /// A certificate was stored in the cache.
+    CertCacheStore,
+    /// A new account key was stored in the cache.
+    AccountCacheStore,
+    /// A corrupt cached certificate was moved aside.
+    CertCacheQuarantine,
+    /// A corrupt or unusable cached account key was moved aside.
+    AccountCacheQuarantine,
+    /// The cache was checked for a certificate renewed by another process after
+    /// [`AcmeHandle::reload`](crate::AcmeHandle::reload), but had none.
+    CacheUnchanged,
+    /// The cache entries of a certificate and its account were loaded again after
+    /// [`AcmeHandle::reencrypt_cache`](crate::AcmeHandle::reencrypt_cache), re-encrypting any
+    /// still encrypted with a previous key.
+    CacheReencrypted,
+    /// A domain was removed with [`AcmeHandle::remove_domain`](crate::AcmeHandle::remove_domain)
+    /// from a certificate covering other domains too, so a certificate for the others is ordered
+    /// now.
+    DomainRemoved,
+    /// The last domain of a certificate was removed with
+    /// [`AcmeHandle::remove_domain`](crate::AcmeHandle::remove_domain), so it is no longer
+    /// served or managed.
+    CertRemoved,
+    /// Loading a certificate from the cache failed.
+    CertCacheLoadFailed,
+    /// Loading the account key from the cache failed.
+    AccountCacheLoadFailed,
+    /// Storing a certificate in the cache failed.
+    CertCacheStoreFailed,
+    /// Storing the account key in the cache failed.
+    AccountCacheStoreFailed,
+    /// A certificate loaded from the cache could not be used.
+    CachedCertInvalid,
+    /// Ordering a certificate failed; it will be retried.
+    OrderFailed,
+    /// A newly issued certificate could not be used or failed sanity checks, such as not covering
+    /// every domain; a new one will be ordered.
+    NewCertInvalid,
+    /// A previous certificate could not be used for a rollback.
+    PreviousCertInvalid,
+    /// An order registered a new account with the CA, rather than finding an existing one for
+    /// the account key.
+    AccountRegistered,
+    /// Storing a certificate in the cache failed, so it was stored in the
+    /// [secondary cache](crate::AcmeConfig::secondary_cache) instead.
+    CertCacheStoreSecondary,
+    /// Storing the account key in the cache failed, so it was stored in the
+    /// [secondary cache](crate::AcmeConfig::secondary_cache) instead.
+    AccountCacheStoreSecondary,
+    /// An order was put off to stay within the CA's
+    /// [rate limits](crate::AcmeConfig::rate_limits), or until the CA asked to be retried.
+    OrderDeferred,
+    /// Renewal is due, but this instance doesn't place orders, so it checks the cache for a
+    /// certificate renewed by an orderer; see
+    /// [`AcmeConfig::challenge_delegation`](crate::AcmeConfig::challenge_delegation).
+    AwaitingOrderer,
+    /// The certificate was revoked with
+    /// [`AcmeHandle::revoke`](crate::AcmeHandle::revoke), so a replacement is ordered now.
+    CertRevoked,
+    /// A revocation was requested, but no certificate from an ACME CA was being served.
+    NoCertToRevoke,
+    /// Revoking the certificate failed; it is still served, and no replacement is ordered.
+    RevocationFailed,
+    /// A fresh OCSP response is stapled to the certificate; see
+    /// [`AcmeConfig::ocsp_stapling`](crate::AcmeConfig::ocsp_stapling).
+    OcspStapled,
+    /// Fetching an OCSP response failed, and will be retried, or the responder reported the
+    /// certificate revoked, so a replacement is ordered now.
+    OcspFetchFailed,
+    /// The stapled OCSP response expired without a fresh one, and goes on being stapled for the
+    /// window the [stale OCSP policy](crate::AcmeConfig::stale_ocsp_policy) allows.
+    OcspStaleServed,
+    /// The stapled OCSP response expired without a fresh one, so the certificate is served
+    /// without a staple.
+    OcspStapleDropped,
+    /// The stapled OCSP response of a must-staple certificate expired without a fresh one, so the
+    /// certificate is no longer served and handshakes for its names fail.
+    OcspCertWithheld,
+    /// A certificate being served has less validity left than an
+    /// [expiry alarm](crate::AcmeConfig::expiry_alarm) threshold.
+    ExpiryAlarm,
+}
+
+impl EventKind {
+    const ALL: [EventKind; 36] = {
+        use EventKind::*;
+        [
+            DeployedCachedCert,
+            DeployedNewCert,
+            DeployedPreviousCert,
+            NoPreviousCert,
+            RenewalRequested,
+            StagingCheckPassed,
+            CertCacheStore,
+            AccountCacheStore,
+            CertCacheQuarantine,
+            AccountCacheQuarantine,
+            CacheUnchanged,
+            CacheReencrypted,
+            DomainRemoved,
+            CertRemoved,
+            CertCacheLoadFailed,
+            AccountCacheLoadFailed,
+            CertCacheStoreFailed,
+            AccountCacheStoreFailed,
+            CachedCertInvalid,
+            OrderFailed,
+            NewCertInvalid,
+            PreviousCertInvalid,
+            AccountRegistered,
+            CertCacheStoreSecondary,
+            AccountCacheStoreSecondary,
+            OrderDeferred,
+            AwaitingOrderer,
+            CertRevoked,
+            NoCertToRevoke,
+            RevocationFailed,
+            OcspStapled,
+            OcspFetchFailed,
+            OcspStaleServed,
+            OcspStapleDropped,
+            OcspCertWithheld,
+            ExpiryAlarm,
+        ]
+    };
+
+    /// The kind of event with the [name](Self::name) `name`.
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|kind| kind.name() == name)
+    }
+
+    /// A stable name for this kind of event, for structured logging, such as
+    /// `deployed_new_cert`.
+    pub fn name(self) -> &'static str {
+        use EventKind::*;
+        match self {
+            DeployedCachedCert => "deployed_cached_cert",
+            DeployedNewCert => "deployed_new_cert",
+            DeployedPreviousCert => "deployed_previous_cert",
+            NoPreviousCert => "no_previous_cert",
+            RenewalRequested => "renewal_requested",
+            StagingCheckPassed => "staging_check_passed",
+            CertCacheStore => "cert_cache_store",
+            AccountCacheStore => "account_cache_store",
+            CertCacheQuarantine => "cert_cache_quarantine",
+            AccountCacheQuarantine => "account_cache_quarantine",
+            CacheUnchanged => "cache_unchanged",
+            CacheReencrypted => "cache_reencrypted",
+            DomainRemoved => "domain_removed",
+            CertRemoved => "cert_removed",
+            CertCacheLoadFailed => "cert_cache_load_failed",
+            AccountCacheLoadFailed => "account_cache_load_failed",
+            CertCacheStoreFailed => "cert_cache_store_failed",
+            AccountCacheStoreFailed => "account_cache_store_failed",
+            CachedCertInvalid => "cached_cert_invalid",
+            OrderFailed => "order_failed",
+            NewCertInvalid => "new_cert_invalid",
+            PreviousCertInvalid => "previous_cert_invalid",
+            AccountRegistered => "account_registered",
+            CertCacheStoreSecondary => "cert_cache_store_secondary",
+            AccountCacheStoreSecondary => "account_cache_store_secondary",
+            OrderDeferred => "order_deferred",
+            AwaitingOrderer => "awaiting_orderer",
+            CertRevoked => "cert_revoked",
+            NoCertToRevoke => "no_cert_to_revoke",
+            RevocationFailed => "revocation_failed",
+            OcspStapled => "ocsp_stapled",
+            OcspFetchFailed => "ocsp_fetch_failed",
+            OcspStaleServed => "ocsp_stale_served",
+            OcspStapleDropped => "ocsp_staple_dropped",
+            OcspCertWithheld => "ocsp_cert_withheld",
+            ExpiryAlarm => "expiry_alarm",
+        }
+    }
+
+    /// The category of this kind of event.
+    pub fn category(self) -> EventCategory {
+        use EventKind::*;
+        match self {
+            RenewalRequested | StagingCheckPassed | DeployedNewCert | OrderFailed
+            | NewCertInvalid | OrderDeferred | AwaitingOrderer | CertRevoked | NoCertToRevoke
+            | RevocationFailed => EventCategory::Order,
+            DeployedCachedCert | DeployedPreviousCert | NoPreviousCert | CachedCertInvalid
+            | PreviousCertInvalid | DomainRemoved | CertRemoved | OcspStapled | OcspFetchFailed
+            | OcspStaleServed | OcspStapleDropped | OcspCertWithheld | ExpiryAlarm => {
+                EventCategory::Deployment
+            }
+            CertCacheStore
+            | CertCacheQuarantine
+            | CertCacheLoadFailed
+            | CertCacheStoreFailed
+            | CertCacheStoreSecondary
+            | CacheUnchanged
+            | CacheReencrypted => EventCategory::Cache,
+            AccountRegistered
+            | AccountCacheStore
+            | AccountCacheQuarantine
+            | AccountCacheLoadFailed
+            | AccountCacheStoreFailed
+            | AccountCacheStoreSecondary => EventCategory::Account,
+        }
+    }
+}
+
+/// Broad categories of [`EventKind`]s, for filtering.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum EventCategory {
+    /// The order lifecycle: renewal requests, issuance of new certificates, and failed orders.
+    Order,
+    /// Serving certificates from the cache or after a rollback.
+    Deployment,
+    /// Storing and loading certificates in the cache.
+    Cache,
+    /// Managing the ACME account key.
+    Account,
+}
+
+impl EventCategory {
+    /// A stable name for this category, for structured logging, such as `order`.
+    pub fn name(self) -> &'static str {
+        match self {
+            EventCategory::Order => "order",
+            EventCategory::Deployment => "deployment",
+            EventCategory::Cache => "cache",
+            EventCategory::Account => "account",
+        }
+    }
+}
+
+/// Filter selecting which events a subscription receives, for
+/// [`AcmeHandle::events`](crate::AcmeHandle::events).
+///
+/// The default filter matches every event; each restriction narrows it further.
+#[derive(Clone, Debug, Default)]
+pub struct EventFilter {
+    failures_only: bool,
+    domain: Option<String>,
+    categories: Vec<EventCategory>,
+}
+
+impl EventFilter {
+    /// Create a filter matching every event.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only match failures if `failures_only` is true.
+    pub fn failures_only(mut self, failures_only: bool) -> Self {
+        self.failures_only = failures_only;
+        self
+    }
+
+    /// Only match events for the certificate covering `domain`.
+    pub fn domain(mut self, domain: impl AsRef<str>) -> Self {
+        self.domain = Some(domain.as_ref().into());
+        self
+    }
+
+    /// Only match events in `category`, or any of the other categories added.
+    pub fn category(mut self, category: EventCategory) -> Self {
+        self.categories.push(category);
+        self
+    }
+
+    /// Check whether `event` matches this filter.
+    pub fn matches(&self, event: &AcmeEvent) -> bool {
+        (!self.failures_only || event.is_failure())
+            && self
+                .domain
+                .as_ref()
+                .is_none_or(|domain| event.domains.contains(domain))
+            && (self.categories.is_empty() || self.categories.contains(&event.kind.category()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::*;
+    use crate::state::{CertParseError, OrderError};
+
+    fn event(domain: &str, event: &Event<Infallible, Infallible>) -> AcmeEvent {
+        AcmeEvent::new(
+            &[domain.to_string()],
+            Some("https://acme.test/order/1"),
+            event,
+        )
+    }
+
+    #[test]
+    fn describes_events() {
+        let deployed = event("a.example", &Ok(EventOk::DeployedNewCert));
+        assert_eq!(deployed.domains, ["a.example"]);
+        assert_eq!(deployed.kind, EventKind::DeployedNewCert);
+        assert!(!deployed.is_failure());
+        let failed = event(
+            "a.example",
+            &Err(EventError::CachedCertParse(CertParseError::TooFewPem(1))),
+        );
+        assert_eq!(failed.kind, EventKind::CachedCertInvalid);
+        assert_eq!(failed.kind.category(), EventCategory::Deployment);
+        assert!(failed.is_failure());
+        assert!(failed.error.unwrap().contains("expected 2 or more pem"));
+        let registered = event("a.example", &Ok(EventOk::AccountRegistered));
+        assert_eq!(registered.kind.name(), "account_registered");
+        assert_eq!(registered.kind.category(), EventCategory::Account);
+        let deferred = event("a.example", &Ok(EventOk::OrderDeferred));
+        assert_eq!(deferred.kind.name(), "order_deferred");
+        assert_eq!(deferred.kind.category(), EventCategory::Order);
+        let awaiting = event("a.example", &Ok(EventOk::AwaitingOrderer));
+        assert_eq!(awaiting.kind.name(), "awaiting_orderer");
+        assert!(!awaiting.is_failure());
+    }
+
+    #[test]
+    fn filters_events() {
+        let deployed = event("a.example", &Ok(EventOk::DeployedNewCert));
+        let stored = event("b.example", &Ok(EventOk::CertCacheStore));
+        let failed = event(
+            "a.example",
+            &Err(EventError::NewCertParse(CertParseError::InvalidPrivateKey)),
+        );
+        let matching = |filter: EventFilter| {
+            [&deployed, &stored, &failed]
+                .iter()
+                .map(|event| filter.matches(event))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(matching(EventFilter::new()), [true, true, true]);
+        let failures = EventFilter::new().failures_only(true);
+        assert_eq!(matching(failures), [false, false, true]);
+        let domain = EventFilter::new().domain("a.example");
+        assert_eq!(matching(domain), [true, false, true]);
+        let cache = EventFilter::new().category(EventCategory::Cache);
+        assert_eq!(matching(cache), [false, true, false]);
+        let categories = EventFilter::new()
+            .category(EventCategory::Cache)
+            .category(EventCategory::Order);
+        assert_eq!(matching(categories), [true, true, true]);
+        let combined = EventFilter::new().failures_only(true).domain("b.example");
+        assert_eq!(matching(combined), [false, false, false]);
+    }
+
+    #[test]
+    fn formats_events_as_json() {
+        let deployed = event("a.example", &Ok(EventOk::DeployedCachedCert));
+        let json: serde_json::Value = serde_json::from_str(&deployed.to_json()).unwrap();
+        assert_eq!(json["event_type"], "deployed_cached_cert");
+        assert_eq!(json["category"], "deployment");
+        assert_eq!(json["domains"], serde_json::json!(["a.example"]));
+        assert!(json["timestamp"].as_f64().unwrap() > 0.0);
+        assert!(json.get("order_url").is_none());
+        assert!(json.get("error_class").is_none());
+        assert!(json.get("error").is_none());
+        assert!(json.get("acceptor").is_none());
+
+        let failed = event(
+            "a.example",
+            &Err(EventError::Order(OrderError::TooManyAttemptsOrder)),
+        );
+        let json: serde_json::Value = serde_json::from_str(&failed.to_json()).unwrap();
+        assert_eq!(json["event_type"], "order_failed");
+        assert_eq!(json["category"], "order");
+        assert_eq!(json["order_url"], "https://acme.test/order/1");
+        assert_eq!(json["error_class"], "too_many_attempts_order");
+        assert!(json["error"].is_string());
+
+        let mut named = event("a.example", &Ok(EventOk::DeployedNewCert));
+        named.acceptor = Some("public".into());
+        let json: serde_json::Value = serde_json::from_str(&named.to_json()).unwrap();
+        assert_eq!(json["acceptor"], "public");
+    }
+
+    #[test]
+    fn explains_problems() {
+        let challenges = serde_json::from_str(
+            r#"[{"type": "tls-alpn-01", "url": "https://ca.example/chall/1", "token": "token",
+                "error": {"type": "urn:ietf:params:acme:error:caa",
+                    "detail": "CAA record forbids issuance"},
+                "validationRecord": [{"hostname": "a.example", "port": "443"}]}]"#,
+        )
+        .unwrap();
+        let failed = event(
+            "a.example",
+            &Err(EventError::Order(OrderError::BadAuth(
+                crate::acme::Auth::Invalid {
+                    identifier: Some(crate::acme::Identifier::Dns("a.example".into())),
+                    challenges,
+                },
+            ))),
+        );
+        let failure = failed.challenge_failure.as_ref().unwrap();
+        assert_eq!(failure.domain.as_deref(), Some("a.example"));
+        assert_eq!(failure.challenge_type, "tls-alpn-01");
+        assert_eq!(failure.validation_records[0].port, Some(443));
+        assert_eq!(
+            failed.problem_type.as_deref(),
+            Some("urn:ietf:params:acme:error:caa")
+        );
+        assert!(failed.hint.unwrap().contains("CAA"));
+        assert!(matches!(failed.failure, Some(crate::Error::Validation(_))));
+        let json: serde_json::Value = serde_json::from_str(&failed.to_json()).unwrap();
+        assert_eq!(json["problem_type"], "urn:ietf:params:acme:error:caa");
+        assert_eq!(json["problem_detail"], "CAA record forbids issuance");
+        assert_eq!(json["hint"], failed.hint.unwrap());
+        assert_eq!(json["challenge_failure"]["domain"], "a.example");
+        assert_eq!(
+            json["challenge_failure"]["validation_records"][0]["hostname"],
+            "a.example"
+        );
+    }
+}
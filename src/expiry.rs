@@ -0,0 +1,142 @@
+//! The expiry watchdog, raising alarms for certificates close to expiry.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tracing::warn;
+
+use crate::state::AcmeState;
+use crate::{AcmeEvent, AcmeHandle};
+
+/// How long after startup the watchdog first checks, once cached certificates are loaded.
+const FIRST_CHECK_DELAY: Duration = Duration::from_secs(60);
+
+/// How often the watchdog checks the certificates being served.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Check the certificates being served every few minutes, independently of their renewal, and
+/// raise an alarm for each one whose remaining validity drops below one of the `thresholds`.
+///
+/// A certificate raises one alarm per threshold it crosses, so it alarms again as it gets closer
+/// to expiry, and none once it is renewed.
+pub(crate) async fn watch<EC: 'static + Debug, EA: 'static + Debug>(
+    state: Arc<AcmeState<EC, EA>>,
+    handle: AcmeHandle,
+    thresholds: Vec<Duration>,
+) {
+    // The lowest threshold each certificate, by its domains, has alarmed for.
+    let mut alarmed = HashMap::new();
+    let mut delay = FIRST_CHECK_DELAY;
+    loop {
+        state.clock().sleep(delay).await;
+        delay = CHECK_INTERVAL;
+        check(
+            &state,
+            &handle,
+            &thresholds,
+            &mut alarmed,
+            state.clock().now(),
+        );
+    }
+}
+
+/// Check the certificates being served as of `now`, raising an alarm for those crossing a lower
+/// threshold than they had alarmed for, and update `alarmed` with the thresholds they're below.
+fn check<EC: 'static + Debug, EA: 'static + Debug>(
+    state: &AcmeState<EC, EA>,
+    handle: &AcmeHandle,
+    thresholds: &[Duration],
+    alarmed: &mut HashMap<Vec<String>, Duration>,
+    now: SystemTime,
+) {
+    let mut current = HashMap::new();
+    for status in handle.status() {
+        let not_after = match status.not_after {
+            Some(not_after) => not_after,
+            None => continue,
+        };
+        let remaining = not_after.duration_since(now).unwrap_or_default();
+        let threshold = match thresholds
+            .iter()
+            .copied()
+            .filter(|&threshold| remaining < threshold)
+            .min()
+        {
+            Some(threshold) => threshold,
+            None => continue,
+        };
+        let previous = alarmed.get(&status.domains).copied();
+        if previous.is_none_or(|previous| threshold < previous) {
+            warn!(
+                domains = ?status.domains,
+                remaining_secs = remaining.as_secs(),
+                threshold_secs = threshold.as_secs(),
+                "certificate is close to expiry; renewal may be failing"
+            );
+            let mut event = AcmeEvent::expiry_alarm(&status.domains, remaining, threshold);
+            event.acceptor = handle.name();
+            state.report(&status.domains, &event, false);
+            handle.registry.publish(event);
+        }
+        current.insert(status.domains, threshold);
+    }
+    handle.registry.set_expiry_alarms(current.len());
+    *alarmed = current;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::snapshot::CertOrigin;
+    use crate::{AcmeConfig, EventKind};
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    #[test]
+    fn escalates_alarms_as_expiry_nears() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let reported = events.clone();
+        let config = AcmeConfig::new(Vec::<String>::new())
+            .expiry_alarm(14 * DAY)
+            .expiry_alarm(3 * DAY)
+            .on_event(move |event| reported.lock().unwrap().push(event.clone()));
+        let state = config.state();
+        let handle = state.handle();
+        let thresholds = state.expiry_alarms();
+        let registry = &handle.registry;
+        let (index, _) = registry.register(vec!["a.example".into()], 0, CertOrigin::Configured);
+        registry.register(vec!["pending.example".into()], 0, CertOrigin::Configured);
+        let now = SystemTime::now();
+        registry.update(index, |status| status.not_after = Some(now + 30 * DAY));
+        let mut alarmed = HashMap::new();
+        let mut check_at = |at| check(&state, &handle, &thresholds, &mut alarmed, at);
+        let alarms = || {
+            let events = events.lock().unwrap();
+            assert!(events
+                .iter()
+                .all(|event| event.kind == EventKind::ExpiryAlarm));
+            events.len()
+        };
+
+        check_at(now);
+        assert_eq!((alarms(), handle.expiry_alarms()), (0, 0));
+        check_at(now + 20 * DAY);
+        assert_eq!((alarms(), handle.expiry_alarms()), (1, 1));
+        check_at(now + 21 * DAY);
+        assert_eq!(alarms(), 1);
+        check_at(now + 28 * DAY);
+        assert_eq!((alarms(), handle.expiry_alarms()), (2, 1));
+        let event = events.lock().unwrap()[1].clone();
+        assert_eq!(event.domains, ["a.example"]);
+        assert_eq!(event.error_class, Some("expiry_alarm"));
+        assert!(matches!(event.failure, Some(crate::Error::Expiring(_))));
+        // Renewed.
+        registry.update(index, |status| status.not_after = Some(now + 90 * DAY));
+        check_at(now + 28 * DAY);
+        assert_eq!((alarms(), handle.expiry_alarms()), (2, 0));
+    }
+}
@@ -0,0 +1,96 @@
+//! Writing certificates to files, for servers that can't embed tide-acme.
+
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use async_std::task::spawn_blocking;
+use zeroize::Zeroizing;
+
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+
+/// Write a certificate to `fullchain.pem` and `privkey.pem` in a directory of `dir` named after
+/// its first domain, with `*` spelled `_` for wildcard domains. Each file is replaced atomically,
+/// so a server reloading it never sees it half written. Keys held by a key store, passed as an
+/// empty `key_pem`, aren't written. Returns the certificate's directory.
+pub(crate) async fn export(
+    dir: &Path,
+    domains: &[String],
+    chain_pem: String,
+    key_pem: Zeroizing<String>,
+) -> io::Result<PathBuf> {
+    let name = domains.first().map_or("default", String::as_str);
+    let dir = dir.join(name.replace('*', "_"));
+    spawn_blocking(move || {
+        let mut builder = DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        builder.mode(0o700);
+        builder.create(&dir)?;
+        // The key first, so the chain never names a key that isn't there yet.
+        if !key_pem.is_empty() {
+            replace(&dir.join("privkey.pem"), key_pem.as_bytes())?;
+        }
+        replace(&dir.join("fullchain.pem"), chain_pem.as_bytes())?;
+        Ok(dir)
+    })
+    .await
+}
+
+/// Replace the file at `path` with `contents`, via a temporary file renamed over it.
+fn replace(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut f = options.open(&tmp)?;
+    f.write_all(contents)?;
+    f.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::block_on;
+
+    use super::*;
+
+    #[test]
+    fn replaces_exported_files() {
+        let root = std::env::temp_dir().join(format!("tide-acme-export-{}", std::process::id()));
+        let domains = ["*.a.example".to_string(), "a.example".to_string()];
+        let export = |chain: &str, key: &str| {
+            block_on(export(
+                &root,
+                &domains,
+                chain.into(),
+                key.to_string().into(),
+            ))
+            .unwrap()
+        };
+        let dir = export("chain 1", "key 1");
+        assert_eq!(dir, root.join("_.a.example"));
+        let dir = export("chain 2", "key 2");
+        assert_eq!(
+            fs::read_to_string(dir.join("fullchain.pem")).unwrap(),
+            "chain 2"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("privkey.pem")).unwrap(),
+            "key 2"
+        );
+        assert!(!dir.join("privkey.pem.tmp").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&dir), 0o700);
+            assert_eq!(mode(&dir.join("privkey.pem")), 0o600);
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
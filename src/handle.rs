@@ -0,0 +1,2238 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use async_std::task;
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::{Stream, StreamExt};
+use ring::digest::{digest, SHA256};
+use tide_rustls::rustls::sign::CertifiedKey;
+use tide_rustls::rustls::{
+    CipherSuite, KeyLog, NoClientAuth, ProtocolVersion, ServerConfig, ServerSession, Session,
+};
+use tracing::{info, warn};
+use x509_parser::parse_x509_certificate;
+use zeroize::Zeroizing;
+
+use crate::acme::RevocationReason;
+use crate::error::Error;
+use crate::events::{AcmeEvent, EventFilter};
+use crate::format;
+use crate::listener::{Connections, PeerMap, Requests};
+use crate::pacing::OrderPacer;
+use crate::problem::ChallengeFailure;
+use crate::rate_limit::{RateBudgets, RateLimitBudget};
+use crate::resolver::AcmeResolver;
+use crate::shutdown::Shutdown;
+use crate::snapshot::{CertOrigin, CertResume, SavedEvent, Snapshot};
+use crate::state::ConfigReload;
+use crate::summary::{ConfigSummary, GroupSummary};
+use crate::tickets::Resumption;
+use crate::{AcmeConfig, Clock};
+
+/// How often draining checks whether the connections and requests it waits for have completed.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Status of a certificate managed by an [`AcmeTlsAcceptor`](crate::AcmeTlsAcceptor).
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct CertStatus {
+    /// The domains the certificate covers.
+    pub domains: Vec<String>,
+    /// The start of the validity period of the certificate being served, if any.
+    pub not_before: Option<SystemTime>,
+    /// The end of the validity period of the certificate being served, if any.
+    pub not_after: Option<SystemTime>,
+    /// When the next order is scheduled: the renewal time the
+    /// [renewal policy](crate::AcmeConfig::renewal_policy) derives from the certificate's validity
+    /// period, or the next retry after a failure. `None` while an order is due or in progress.
+    pub renew_at: Option<SystemTime>,
+    /// The number of previously served certificates available to
+    /// [`rollback`](AcmeHandle::rollback) to.
+    pub previous_certs: usize,
+    /// Domains backing off after failing validation. Unless the certificate being served
+    /// outlasts its backoff, a domain is left out of orders until the backoff ends, so the
+    /// certificate is ordered for the other domains in the meantime.
+    pub backed_off_domains: Vec<String>,
+    /// The most recent failure since the certificate was last deployed, if any. Failures are
+    /// retried in the background.
+    pub last_error: Option<String>,
+    /// The cause of the most recent failure since the certificate was last deployed, if any, to
+    /// match on.
+    pub last_failure: Option<Error>,
+    /// An explanation of the most recent failure and what to do about it, if the CA reported an
+    /// ACME problem of a common type; see [`problem_hint`](crate::problem_hint).
+    pub last_error_hint: Option<&'static str>,
+    /// The most recent failed validation since the certificate was last deployed, if any, with
+    /// the problem and validation records the CA reported for it.
+    pub challenge_failure: Option<ChallengeFailure>,
+    /// The number of failed orders since the certificate was last deployed.
+    pub failed_orders: u32,
+    /// Why the background task managing the certificate is degraded: it panicked, with this
+    /// message, and is restarting after a backoff. The certificate being served is kept, but
+    /// isn't renewed until the task recovers. Cleared once the task makes progress again.
+    pub degraded: Option<String>,
+}
+
+impl CertStatus {
+    /// Whether the certificate has been obtained, is still pending, or has failed to be ordered,
+    /// going by the system clock. With a [`Clock`](crate::Clock) configured, use
+    /// [`readiness_at`](Self::readiness_at) with its time instead, as the methods of
+    /// [`AcmeHandle`] do.
+    pub fn readiness(&self) -> CertReadiness {
+        self.readiness_at(SystemTime::now())
+    }
+
+    /// Whether the certificate has been obtained, is still pending, or has failed to be ordered,
+    /// as of `now`.
+    pub fn readiness_at(&self, now: SystemTime) -> CertReadiness {
+        let valid = self.not_after.is_some_and(|not_after| not_after > now);
+        if valid {
+            CertReadiness::Obtained
+        } else if self.failed_orders > 0 {
+            CertReadiness::Failed
+        } else {
+            CertReadiness::Pending
+        }
+    }
+}
+
+/// Whether a certificate can be served yet, as returned by [`CertStatus::readiness`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum CertReadiness {
+    /// A valid certificate is being served. Failures renewing it don't change this until it
+    /// expires.
+    Obtained,
+    /// No valid certificate yet, and no order has failed: one is being loaded or ordered.
+    Pending,
+    /// No valid certificate, and ordering one failed; it is retried in the background, and
+    /// [`CertStatus::last_error`] says why.
+    Failed,
+}
+
+/// A certificate that was just deployed, as yielded by [`AcmeHandle::cert_rotations`].
+#[derive(Clone)]
+pub struct CertRotation {
+    status: CertStatus,
+    material: Arc<CertMaterial>,
+}
+
+impl CertRotation {
+    /// The status of the certificate, including the domains it covers.
+    pub fn status(&self) -> &CertStatus {
+        &self.status
+    }
+
+    /// The certificate chain, as PEM, leaf certificate first.
+    pub fn certificate_pem(&self) -> String {
+        self.material.chain_pem()
+    }
+
+    /// The private key of the certificate, as PKCS#8 PEM, or empty if it is held by a
+    /// [`KeyStore`](crate::KeyStore).
+    pub fn private_key_pem(&self) -> String {
+        self.material.key_pem().to_string()
+    }
+
+    /// The certificate chain, as DER, leaf certificate first.
+    pub fn certificate_der(&self) -> Vec<Vec<u8>> {
+        self.material.chain.clone()
+    }
+
+    /// The private key of the certificate, as PKCS#8 DER, or empty if it is held by a
+    /// [`KeyStore`](crate::KeyStore).
+    pub fn private_key_der(&self) -> Vec<u8> {
+        self.material.key.to_vec()
+    }
+
+    /// The certificate and its signing key, ready to serve from a rustls endpoint of your own.
+    pub fn certified_key(&self) -> CertifiedKey {
+        self.material.certified_key.clone()
+    }
+}
+
+impl std::fmt::Debug for CertRotation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CertRotation")
+            .field("status", &self.status)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A certificate being served, with its metadata, as returned by
+/// [`AcmeHandle::served_certificates`], for monitoring to compare against an inventory without
+/// probing the server over TLS.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ServedCertificate {
+    /// The domains the certificate covers.
+    pub domains: Vec<String>,
+    /// The serial number of the leaf certificate, as colon-separated hex bytes.
+    pub serial: String,
+    /// The subject of the leaf certificate, such as `CN=domain.example`.
+    pub subject: String,
+    /// The issuer of the leaf certificate, such as `C=US, O=Let's Encrypt, CN=R3`.
+    pub issuer: String,
+    /// The start of the validity period of the leaf certificate.
+    pub not_before: SystemTime,
+    /// The end of the validity period of the leaf certificate.
+    pub not_after: SystemTime,
+    /// The SHA-256 fingerprint of the leaf certificate, as lowercase hex.
+    pub sha256: String,
+    /// The certificate chain, as DER, leaf certificate first.
+    pub chain_der: Vec<Vec<u8>>,
+    /// The certificate chain, as PEM, leaf certificate first.
+    pub chain_pem: String,
+}
+
+impl ServedCertificate {
+    /// Describe the certificate `material`, covering `domains`, or `None` if its leaf can't be
+    /// parsed.
+    fn new(domains: Vec<String>, material: &CertMaterial) -> Option<Self> {
+        let leaf = material.chain.first()?;
+        let (_, cert) = parse_x509_certificate(leaf).ok()?;
+        let timestamp = |time: x509_parser::time::ASN1Time| {
+            UNIX_EPOCH + Duration::from_secs(time.timestamp().max(0) as u64)
+        };
+        Some(Self {
+            domains,
+            serial: cert.raw_serial_as_string(),
+            subject: cert.subject().to_string(),
+            issuer: cert.issuer().to_string(),
+            not_before: timestamp(cert.validity().not_before),
+            not_after: timestamp(cert.validity().not_after),
+            sha256: digest(&SHA256, leaf)
+                .as_ref()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+            chain_der: material.chain.clone(),
+            chain_pem: material.chain_pem(),
+        })
+    }
+}
+
+/// Details of the TLS session of a connection, as negotiated in the handshake, as returned by
+/// [`AcmeHandle::tls_info`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct TlsConnectionInfo {
+    /// The server name the client asked for via SNI, if any.
+    pub server_name: Option<String>,
+    /// The TLS version.
+    pub protocol_version: Option<ProtocolVersion>,
+    /// The cipher suite.
+    pub cipher_suite: Option<CipherSuite>,
+    /// The application protocol negotiated via ALPN, if any, such as `http/1.1`.
+    pub alpn_protocol: Option<Vec<u8>>,
+    /// The verified certificate chain the client presented, leaf first, as DER; empty unless
+    /// the listener [verifies client certificates](crate::AcmeTlsAcceptor::client_auth) and the
+    /// client presented one.
+    pub client_certificates: Vec<Vec<u8>>,
+    /// The subject of the client's certificate, such as `CN=service.internal`, if it presented
+    /// one.
+    pub client_subject: Option<String>,
+}
+
+impl TlsConnectionInfo {
+    pub(crate) fn new(session: &ServerSession) -> Self {
+        let client_certificates: Vec<Vec<u8>> = session
+            .get_peer_certificates()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|cert| cert.0)
+            .collect();
+        let client_subject = client_certificates.first().and_then(|leaf| {
+            let (_, cert) = parse_x509_certificate(leaf).ok()?;
+            Some(cert.subject().to_string())
+        });
+        Self {
+            server_name: session.get_sni_hostname().map(Into::into),
+            protocol_version: session.get_protocol_version(),
+            cipher_suite: session
+                .get_negotiated_ciphersuite()
+                .map(|suite| suite.suite),
+            alpn_protocol: session.get_alpn_protocol().map(Into::into),
+            client_certificates,
+            client_subject,
+        }
+    }
+}
+
+/// TLS termination statistics for one server name, as returned by [`AcmeHandle::tls_stats`].
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct TlsStats {
+    /// The number of TLS handshakes started.
+    pub client_hellos: u64,
+    /// The number of TLS handshakes completed, that is, connections handed to the server.
+    pub handshakes: u64,
+    /// The number of handshakes started without a certificate to serve.
+    pub no_certificate: u64,
+}
+
+impl TlsStats {
+    /// The number of handshakes started but not completed: failed, or still in progress.
+    pub fn incomplete_handshakes(&self) -> u64 {
+        self.client_hellos.saturating_sub(self.handshakes)
+    }
+}
+
+/// A certificate being obtained on demand, as returned by [`AcmeHandle::pending_issuances`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct PendingIssuance {
+    /// The server name the certificate is for, normalized.
+    pub domain: String,
+    /// When a client first asked for the name.
+    pub requested_at: SystemTime,
+    /// Whether the [domain policy](crate::DomainPolicy) approved the name, so the certificate is
+    /// being ordered. Otherwise the policy hasn't answered yet.
+    pub approved: bool,
+    /// The number of failed orders for the certificate so far. Failures are retried in the
+    /// background.
+    pub failed_attempts: u32,
+    /// The number of handshakes waiting for the certificate.
+    pub waiting_handshakes: usize,
+}
+
+/// Connections of the listeners using an [`AcmeTlsAcceptor`](crate::AcmeTlsAcceptor), as
+/// returned by [`AcmeHandle::connection_stats`]. Counts are since startup.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct ConnectionStats {
+    /// Connections open, counted against
+    /// [`ListenerOptions::max_connections`](crate::ListenerOptions::max_connections): those to
+    /// the sockets answering validation requests, and those to the application's listeners until
+    /// their TLS handshake completes.
+    pub open_connections: usize,
+    /// TLS handshakes in progress on the application's listeners.
+    pub handshakes_in_progress: usize,
+    /// Connections held before their TLS handshake; see
+    /// [`AcmeHandle::throttled_connections`].
+    pub throttled_connections: usize,
+    /// Connections refused under a limit; see [`AcmeHandle::refused_connections`].
+    pub refused_connections: u64,
+    /// Connections the handshake filter rejected; see [`AcmeHandle::rejected_handshakes`].
+    pub rejected_handshakes: u64,
+    /// Connections closed for not completing their TLS handshake in time; see
+    /// [`AcmeHandle::timed_out_handshakes`].
+    pub timed_out_handshakes: u64,
+    /// Errors accepting connections on the sockets answering validation requests, which pause
+    /// accepting as set with
+    /// [`ListenerOptions::accept_error_delay`](crate::ListenerOptions::accept_error_delay).
+    /// Errors concerning a single connection, such as a connection aborted before it was
+    /// accepted, aren't counted. The application's listeners are accepted on by tide-rustls,
+    /// which handles its errors itself.
+    pub accept_errors: u64,
+}
+
+/// How many completed TLS handshakes negotiated each protocol version, application protocol and
+/// cipher suite, as returned by [`AcmeHandle::protocol_stats`], to tell when dropping TLS 1.2 or
+/// legacy cipher suites is safe.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct ProtocolStats {
+    /// Handshakes by TLS version, such as `TLSv1.3`.
+    pub versions: BTreeMap<String, u64>,
+    /// Handshakes by application protocol negotiated via ALPN, such as `h2`, or `None` for
+    /// handshakes without ALPN.
+    pub alpn_protocols: BTreeMap<Option<String>, u64>,
+    /// Handshakes by cipher suite, by its IANA name, such as `TLS13_AES_128_GCM_SHA256`.
+    pub cipher_suites: BTreeMap<String, u64>,
+}
+
+impl ProtocolStats {
+    fn record(&mut self, session: &ServerSession) {
+        if let Some(version) = session.get_protocol_version() {
+            let version = match version {
+                ProtocolVersion::TLSv1_2 => "TLSv1.2".into(),
+                ProtocolVersion::TLSv1_3 => "TLSv1.3".into(),
+                version => format!("{:?}", version),
+            };
+            *self.versions.entry(version).or_default() += 1;
+        }
+        let alpn = session
+            .get_alpn_protocol()
+            .map(|protocol| String::from_utf8_lossy(protocol).into_owned());
+        *self.alpn_protocols.entry(alpn).or_default() += 1;
+        if let Some(suite) = session.get_negotiated_ciphersuite() {
+            let suite = format!("{:?}", suite.suite);
+            *self.cipher_suites.entry(suite).or_default() += 1;
+        }
+    }
+}
+
+/// Progress of paced orders, as returned by [`AcmeHandle::order_pacing`].
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct OrderPacing {
+    /// The number of certificates not obtained yet, whether waiting for a slot, being ordered,
+    /// or backing off after a failure.
+    pub pending_certs: usize,
+    /// The number of orders waiting for a slot.
+    pub waiting_orders: usize,
+    /// The number of orders placed since startup.
+    pub placed_orders: u64,
+    /// When the next order may be placed, if orders are paced and that's in the future.
+    pub next_slot: Option<SystemTime>,
+}
+
+/// Statistics of ACME validation connections for one domain, as returned by
+/// [`AcmeHandle::challenge_stats`].
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct ChallengeStats {
+    /// The number of tls-alpn-01 validation connections received.
+    pub tls_alpn_01: u64,
+    /// The number of those connections for which no validation certificate was available.
+    pub missing_key: u64,
+    /// When the first validation connection was received.
+    pub first_at: Option<SystemTime>,
+    /// When the most recent validation connection was received.
+    pub last_at: Option<SystemTime>,
+    /// The number of validation connections dropped because closing them didn't finish before
+    /// the deadline set with
+    /// [`AcmeConfig::challenge_close_timeout`](crate::AcmeConfig::challenge_close_timeout).
+    pub close_timeouts: u64,
+    /// The total time validation connections took, from accepting them until they were closed.
+    pub total_duration: Duration,
+    /// The longest time a validation connection took, from accepting it until it was closed.
+    pub max_duration: Duration,
+}
+
+/// Handle for inspecting and controlling the certificates managed by an
+/// [`AcmeTlsAcceptor`](crate::AcmeTlsAcceptor).
+///
+/// Handles are cheap to clone, and remain valid as long as the background tasks run.
+#[derive(Clone)]
+pub struct AcmeHandle {
+    pub(crate) registry: Arc<Registry>,
+    pub(crate) resolver: Arc<AcmeResolver>,
+}
+
+impl AcmeHandle {
+    /// Get the status of each managed certificate.
+    pub fn status(&self) -> Vec<CertStatus> {
+        let certs = self
+            .registry
+            .certs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        certs
+            .iter()
+            .filter(|entry| !entry.removed)
+            .map(|entry| entry.status.clone())
+            .collect()
+    }
+
+    /// Get the certificate chain currently served for `domain`, as PEM, leaf certificate first.
+    pub fn certificate_pem(&self, domain: &str) -> Option<String> {
+        Some(self.registry.material(domain)?.chain_pem())
+    }
+
+    /// Get each certificate being served, with its chain and the metadata of its leaf, such as
+    /// its serial number and issuer. Certificates not obtained yet are left out.
+    ///
+    /// ```no_run
+    /// # fn example(handle: tide_acme::AcmeHandle) {
+    /// for cert in handle.served_certificates() {
+    ///     println!("{:?}: serial {} from {}", cert.domains, cert.serial, cert.issuer);
+    /// }
+    /// # }
+    /// ```
+    pub fn served_certificates(&self) -> Vec<ServedCertificate> {
+        let certs = self
+            .registry
+            .certs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        certs
+            .iter()
+            .filter(|entry| !entry.removed)
+            .filter_map(|entry| {
+                ServedCertificate::new(entry.status.domains.clone(), entry.material.as_ref()?)
+            })
+            .collect()
+    }
+
+    /// Get the certificate served for `domain`, with its chain and the metadata of its leaf; see
+    /// [`served_certificates`](Self::served_certificates).
+    pub fn served_certificate(&self, domain: &str) -> Option<ServedCertificate> {
+        let certs = self
+            .registry
+            .certs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let entry = Registry::find(&certs, domain)?;
+        ServedCertificate::new(entry.status.domains.clone(), entry.material.as_ref()?)
+    }
+
+    /// Get the private key of the certificate currently served for `domain`, as PKCS#8 PEM.
+    /// Returns `None` if the key is held by a [`KeyStore`](crate::KeyStore).
+    ///
+    /// Take care where this ends up: anyone holding it can impersonate the domain.
+    pub fn private_key_pem(&self, domain: &str) -> Option<String> {
+        let material = self.registry.material(domain)?;
+        Some(material.key_pem().to_string()).filter(|key| !key.is_empty())
+    }
+
+    /// Get the certificate chain currently served for `domain`, as DER, leaf certificate first.
+    pub fn certificate_der(&self, domain: &str) -> Option<Vec<Vec<u8>>> {
+        Some(self.registry.material(domain)?.chain.clone())
+    }
+
+    /// Get the private key of the certificate currently served for `domain`, as PKCS#8 DER.
+    /// Returns `None` if the key is held by a [`KeyStore`](crate::KeyStore).
+    ///
+    /// Take care where this ends up: anyone holding it can impersonate the domain.
+    pub fn private_key_der(&self, domain: &str) -> Option<Vec<u8>> {
+        Some(self.registry.material(domain)?.key.to_vec()).filter(|key| !key.is_empty())
+    }
+
+    /// Get the certificate currently served for `domain` with its signing key, ready to serve
+    /// from a rustls endpoint of your own without parsing PEM. Subscribe to
+    /// [`cert_rotations`](Self::cert_rotations) to pick up each new one.
+    pub fn certified_key(&self, domain: &str) -> Option<CertifiedKey> {
+        Some(self.registry.material(domain)?.certified_key.clone())
+    }
+
+    /// Get how long the certificate served for `domain` remains valid: zero once it has expired,
+    /// or `None` if no certificate is served for `domain`.
+    pub fn time_to_expiry(&self, domain: &str) -> Option<Duration> {
+        let not_after = self.registry.not_after(domain)?;
+        Some(
+            not_after
+                .duration_since(self.registry.now())
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Wait until a certificate for `domain` is next deployed, such as when it is renewed, and
+    /// get its status. Returns `None` right away if no managed certificate covers `domain`.
+    ///
+    /// This lets an application restart workers that snapshot the certificate once it rotates:
+    ///
+    /// ```no_run
+    /// # async fn example(handle: tide_acme::AcmeHandle) {
+    /// loop {
+    ///     if handle.renewed("domain.example").await.is_none() {
+    ///         break;
+    ///     }
+    ///     // Restart the workers here.
+    /// }
+    /// # }
+    /// ```
+    pub async fn renewed(&self, domain: &str) -> Option<CertStatus> {
+        let mut updates = self.cert_updates();
+        Registry::find(
+            &self
+                .registry
+                .certs
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+            domain,
+        )?;
+        while let Some(status) = updates.next().await {
+            if status.domains.iter().any(|d| d == domain) {
+                return Some(status);
+            }
+        }
+        None
+    }
+
+    /// Wait until a valid certificate is served for at least one managed certificate, so TLS is
+    /// usable, and get its status. Resolves right away if one is already served.
+    ///
+    /// ```no_run
+    /// use tide_acme::{AcmeConfig, AcmeTlsAcceptor};
+    ///
+    /// # async_std::task::block_on(async {
+    /// let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(vec!["domain.example"]));
+    /// let status = acceptor.handle().ready().await;
+    /// println!("serving {:?} until {:?}", status.domains, status.not_after);
+    /// # });
+    /// ```
+    pub async fn ready(&self) -> CertStatus {
+        let mut updates = self.cert_updates();
+        let ready = |status: &CertStatus| {
+            status.readiness_at(self.registry.now()) == CertReadiness::Obtained
+        };
+        if let Some(status) = self.status().into_iter().find(ready) {
+            return status;
+        }
+        loop {
+            match updates.next().await {
+                Some(status) if ready(&status) => return status,
+                Some(_) => {}
+                // The registry outlives the handle, so updates never end.
+                None => futures::future::pending().await,
+            }
+        }
+    }
+
+    /// Get the number of connections refused for exceeding
+    /// [`ListenerOptions::max_connections`](crate::ListenerOptions::max_connections) or another
+    /// limit of the [`ListenerOptions`](crate::ListenerOptions).
+    pub fn refused_connections(&self) -> u64 {
+        self.connection_stats().refused_connections
+    }
+
+    /// Get the number of connections closed for not completing their TLS handshake within
+    /// [`ListenerOptions::handshake_timeout`](crate::ListenerOptions::handshake_timeout).
+    pub fn timed_out_handshakes(&self) -> u64 {
+        self.connection_stats().timed_out_handshakes
+    }
+
+    /// Get the number of connections closed because the
+    /// [handshake filter](crate::AcmeConfig::handshake_filter) rejected them.
+    pub fn rejected_handshakes(&self) -> u64 {
+        self.registry.rejected_handshakes.load(Ordering::Relaxed)
+    }
+
+    /// Get how many connections the listeners using the
+    /// [`AcmeTlsAcceptor`](crate::AcmeTlsAcceptor) have open, and how many they refused or closed
+    /// early under the limits of their [`ListenerOptions`](crate::ListenerOptions), to observe
+    /// what is being rejected. With the `metrics` feature, these are exported too.
+    ///
+    /// ```no_run
+    /// # fn example(handle: tide_acme::AcmeHandle) {
+    /// let stats = handle.connection_stats();
+    /// eprintln!(
+    ///     "{} open, {} refused, {} timed out",
+    ///     stats.open_connections, stats.refused_connections, stats.timed_out_handshakes
+    /// );
+    /// # }
+    /// ```
+    pub fn connection_stats(&self) -> ConnectionStats {
+        let totals = self.registry.connections.totals();
+        ConnectionStats {
+            open_connections: totals.open.load(Ordering::Acquire),
+            handshakes_in_progress: totals.handshakes.load(Ordering::Acquire),
+            throttled_connections: self.registry.requests.throttled(),
+            refused_connections: totals.refused.load(Ordering::Relaxed),
+            rejected_handshakes: self.registry.rejected_handshakes.load(Ordering::Relaxed),
+            timed_out_handshakes: totals.timed_out.load(Ordering::Relaxed),
+            accept_errors: totals.accept_errors.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Get how many completed handshakes negotiated each TLS version, ALPN protocol and cipher
+    /// suite since startup. Like [`tls_stats`](Self::tls_stats), only handshakes completed by the
+    /// [`AcmeTlsAcceptor`](crate::AcmeTlsAcceptor) are counted, without ACME validation
+    /// connections. With the `metrics` feature, these are exported as counters too, whose rates
+    /// show how the mix changes over time.
+    pub fn protocol_stats(&self) -> ProtocolStats {
+        self.registry
+            .protocol_stats
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Get the number of responses [`ExpiryWarning`](crate::ExpiryWarning) flagged as served
+    /// under a certificate close to expiry.
+    pub fn expiring_cert_responses(&self) -> u64 {
+        self.registry
+            .expiring_cert_responses
+            .load(Ordering::Relaxed)
+    }
+
+    /// Get the number of certificates being served with less validity left than an
+    /// [expiry alarm](crate::AcmeConfig::expiry_alarm) threshold, as of the watchdog's last
+    /// check. Zero means all is well, or no thresholds are configured.
+    pub fn expiry_alarms(&self) -> usize {
+        self.registry.expiry_alarms.load(Ordering::Relaxed)
+    }
+
+    /// Stop the background tasks managing certificates, and wait until they have. Orders in
+    /// progress are abandoned, to be resumed on the next start; certificates already obtained
+    /// are still served.
+    ///
+    /// Shutdown also starts, without waiting, when the last clone of the
+    /// [`AcmeTlsAcceptor`](crate::AcmeTlsAcceptor) is dropped. To let connections and cache
+    /// writes complete first, use [`drain`](Self::drain).
+    pub async fn shutdown(&self) {
+        self.registry.shutdown.shutdown().await
+    }
+
+    /// Shut down gracefully, such as when a pod is terminated during a rolling update: stop
+    /// accepting connections, wait for those accepted to complete their TLS handshake, for the
+    /// requests in flight to complete and for cache writes in progress to complete, then
+    /// [shut down](Self::shutdown) the background tasks. Returns whether everything completed
+    /// within `deadline`; whatever hasn't by then is cut off.
+    ///
+    /// Once draining starts, listeners using the acceptor close new connections as soon as they
+    /// are accepted, and responses ask clients to close kept-alive connections with `Connection:
+    /// close`. Requests are only counted, and the header only added, by the
+    /// [`InFlightRequests`](crate::InFlightRequests) middleware, so use it. The listening sockets
+    /// stay open until the application stops listening, so stop it once this returns, such as by
+    /// racing `app.listen` with it.
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    /// use futures::future::{select, Either};
+    /// use tide_acme::{AcmeConfig, AcmeTlsAcceptor, InFlightRequests, TideRustlsExt};
+    ///
+    /// # async fn terminated() {}
+    /// # async_std::task::block_on(async {
+    /// let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(vec!["domain.example"]));
+    /// let handle = acceptor.handle();
+    /// let mut app = tide::new();
+    /// app.with(InFlightRequests::new(handle.clone()));
+    /// let listening = app.listen(
+    ///     tide_rustls::TlsListener::build()
+    ///         .addrs("0.0.0.0:443")
+    ///         .acme_acceptor(acceptor),
+    /// );
+    /// let drained = async {
+    ///     terminated().await; // such as on SIGTERM
+    ///     handle.drain(Duration::from_secs(25)).await
+    /// };
+    /// match select(Box::pin(listening), Box::pin(drained)).await {
+    ///     Either::Left((result, _)) => result?,
+    ///     Either::Right((drained, _)) => eprintln!("drained cleanly: {}", drained),
+    /// }
+    /// # tide::Result::Ok(())
+    /// # });
+    /// ```
+    pub async fn drain(&self, deadline: Duration) -> bool {
+        let deadline = Instant::now() + deadline;
+        let shutdown = &self.registry.shutdown;
+        shutdown.start_draining();
+        info!("draining: refusing new connections");
+        let totals = self.registry.connections.totals();
+        let requests = &self.registry.requests;
+        let idle = || {
+            totals.open.load(Ordering::Acquire) == 0
+                && totals.handshakes.load(Ordering::Acquire) == 0
+                && requests.in_flight() == 0
+        };
+        while !idle() && Instant::now() < deadline {
+            task::sleep(DRAIN_POLL_INTERVAL.min(deadline - Instant::now())).await;
+        }
+        let drained = idle() && shutdown.wait_for_writes(deadline).await;
+        if !drained {
+            warn!(
+                connections = totals.open.load(Ordering::Acquire),
+                requests = requests.in_flight(),
+                "draining timed out; cutting off what remains"
+            );
+        }
+        shutdown.shutdown().await;
+        drained
+    }
+
+    /// Whether [draining](Self::drain) has started, such as to fail a readiness check so that
+    /// no more traffic is routed to this process.
+    pub fn draining(&self) -> bool {
+        self.registry.shutdown.draining()
+    }
+
+    /// Get what remains of the CA's rate limits for each account, as counted since startup; see
+    /// [`AcmeConfig::rate_limits`](crate::AcmeConfig::rate_limits).
+    ///
+    /// ```no_run
+    /// # fn example(handle: tide_acme::AcmeHandle) {
+    /// for budget in handle.rate_limit_budgets() {
+    ///     if budget.orders_remaining == Some(0) || budget.retry_after.is_some() {
+    ///         eprintln!("orders to {} are on hold", budget.directory);
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn rate_limit_budgets(&self) -> Vec<RateLimitBudget> {
+        self.registry.rate_budgets.budgets()
+    }
+
+    /// Get the progress of orders paced with
+    /// [`AcmeConfig::order_rate`](crate::AcmeConfig::order_rate), such as to report on a bulk
+    /// import of domains.
+    pub fn order_pacing(&self) -> OrderPacing {
+        let pending_certs = self
+            .status()
+            .iter()
+            .filter(|status| status.readiness_at(self.registry.now()) != CertReadiness::Obtained)
+            .count();
+        let pacer = &self.registry.pacer;
+        OrderPacing {
+            pending_certs,
+            waiting_orders: pacer.waiting(),
+            placed_orders: pacer.placed(),
+            next_slot: pacer.next_slot(),
+        }
+    }
+
+    /// Get the number of requests the application is handling, as counted by the
+    /// [`InFlightRequests`](crate::InFlightRequests) middleware.
+    pub fn in_flight_requests(&self) -> usize {
+        self.registry.requests.in_flight()
+    }
+
+    /// Get the number of connections being held before their TLS handshake because the
+    /// application is handling too many requests; see
+    /// [`ListenerOptions::max_in_flight_requests`](crate::ListenerOptions::max_in_flight_requests).
+    /// More than zero means accepting is throttled.
+    pub fn throttled_connections(&self) -> usize {
+        self.registry.requests.throttled()
+    }
+
+    /// Describe how the CA's tls-alpn-01 validation connections reach this server: to port 443,
+    /// forwarded to the local port declared with
+    /// [`AcmeConfig::tls_alpn_port_mapping`](crate::AcmeConfig::tls_alpn_port_mapping), if any,
+    /// and whether any listener seen so far is bound to that port. Useful in status pages when
+    /// diagnosing failed validations.
+    pub fn validation_path(&self) -> String {
+        self.registry.validation_path()
+    }
+
+    /// Get the client address a load balancer reported via the PROXY protocol for a connection
+    /// from `peer`, the load balancer's end of the connection, which is what Tide reports as the
+    /// peer address; see [`AcmeTlsAcceptor::proxy_protocol`](crate::AcmeTlsAcceptor::proxy_protocol).
+    pub fn client_addr(&self, peer: SocketAddr) -> Option<SocketAddr> {
+        self.registry.client_addrs.get(peer)
+    }
+
+    /// Get the details of the TLS session of the connection from `peer`, the peer address Tide
+    /// reports, if it was accepted by a listener using this handle's acceptor. See
+    /// [`TlsInfo`](crate::TlsInfo) to have them added to each request.
+    pub fn tls_info(&self, peer: SocketAddr) -> Option<TlsConnectionInfo> {
+        self.registry.tls_info.get(peer)
+    }
+
+    /// Get TLS termination statistics per SNI hostname.
+    ///
+    /// Only names covered by a managed certificate are broken down; connections without SNI or for
+    /// any other name are counted under `None`, so clients can't grow the table without bound.
+    /// ACME validation connections are not counted. Completed handshakes are only counted by the
+    /// [`AcmeTlsAcceptor`](crate::AcmeTlsAcceptor), not by listeners using
+    /// [`server_config`](Self::server_config).
+    pub fn tls_stats(&self) -> BTreeMap<Option<String>, TlsStats> {
+        self.resolver.stats()
+    }
+
+    /// Get the certificates being obtained on demand: server names clients asked for that no
+    /// certificate covers yet, and which the [domain policy](crate::DomainPolicy) hasn't denied.
+    ///
+    /// Each name has one order in flight at a time, however many clients ask for it, and their
+    /// handshakes wait for it for up to [`AcmeConfig::on_demand_wait`]. A name is listed until its
+    /// certificate is deployed or it is denied.
+    pub fn pending_issuances(&self) -> Vec<PendingIssuance> {
+        self.resolver.pending_issuances()
+    }
+
+    /// Get a stream of the events from the background management of certificates that match
+    /// `filter`, from now on.
+    ///
+    /// ```no_run
+    /// # fn example(handle: tide_acme::AcmeHandle) {
+    /// use tide_acme::{EventCategory, EventFilter};
+    ///
+    /// let failed_orders = handle.events(
+    ///     EventFilter::new()
+    ///         .failures_only(true)
+    ///         .domain("customer.example")
+    ///         .category(EventCategory::Order),
+    /// );
+    /// # }
+    /// ```
+    pub fn events(&self, filter: EventFilter) -> impl Stream<Item = AcmeEvent> {
+        let (sender, receiver) = unbounded();
+        self.registry
+            .event_subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((filter, sender));
+        receiver
+    }
+
+    /// Get the most recent events matching `filter`, oldest first, from the history kept as set
+    /// with [`AcmeConfig::event_history`](crate::AcmeConfig::event_history).
+    ///
+    /// ```no_run
+    /// # fn example(handle: tide_acme::AcmeHandle) {
+    /// use std::time::{Duration, SystemTime};
+    ///
+    /// let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    /// for event in handle.event_history(tide_acme::EventFilter::new()) {
+    ///     if event.at >= hour_ago {
+    ///         println!("{}", event.to_json());
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn event_history(&self, filter: EventFilter) -> Vec<AcmeEvent> {
+        let history = self
+            .registry
+            .history
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        history
+            .iter()
+            .filter(|event| filter.matches(event))
+            .cloned()
+            .collect()
+    }
+
+    /// Take a snapshot of the runtime state beyond what the cache keeps, such as renewal
+    /// schedules and backoff, to restore with [`AcmeConfig::restore`](crate::AcmeConfig::restore)
+    /// after a restart; see [`Snapshot`].
+    ///
+    /// Take it as late as possible before exiting, such as on shutdown, so that it reflects the
+    /// latest events.
+    pub fn snapshot(&self) -> Snapshot {
+        self.registry.snapshot()
+    }
+
+    /// Get a summary of the effective configuration: the directories and domains, cache, challenge
+    /// types and renewal policy in effect once defaults and overrides are applied. The same
+    /// summary is logged at startup.
+    ///
+    /// ```no_run
+    /// # fn example(handle: tide_acme::AcmeHandle) {
+    /// let summary = handle.config_summary();
+    /// if summary.staging {
+    ///     eprintln!("warning: serving untrusted staging certificates");
+    /// }
+    /// println!("{}", summary);
+    /// # }
+    /// ```
+    pub fn config_summary(&self) -> ConfigSummary {
+        self.registry
+            .config_summary
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Get the acceptor's name, if it was given one with
+    /// [`AcmeConfig::name`](crate::AcmeConfig::name).
+    pub fn name(&self) -> Option<String> {
+        self.registry
+            .config_summary
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .name
+            .clone()
+    }
+
+    /// Get certificate lifecycle metrics in the Prometheus text exposition format: certificates
+    /// obtained, renewal failures, seconds until expiry per domain, validation handshakes answered
+    /// and TLS handshake errors. See [`Metrics`](crate::Metrics) to serve them for scraping.
+    #[cfg(feature = "metrics")]
+    pub fn prometheus_metrics(&self) -> String {
+        crate::metrics::render(self)
+    }
+
+    /// Get statistics of ACME validation connections per domain, to confirm the CA's validator
+    /// reached the server when diagnosing failed authorizations.
+    ///
+    /// Only domains with a certificate or a validation in progress are broken down; validation
+    /// connections without SNI or for any other name are counted under `None`. Only tls-alpn-01
+    /// validation is supported, so these are all tls-alpn-01 connections.
+    pub fn challenge_stats(&self) -> BTreeMap<Option<String>, ChallengeStats> {
+        self.resolver.challenge_stats()
+    }
+
+    /// Get how far the CA's clock was ahead of the local clock at the most recent order, in
+    /// seconds, going by the `Date` header of the CA's responses. Negative if the CA's clock is
+    /// behind. `None` before the first order.
+    ///
+    /// Large skew makes certificates appear not yet valid or expired; a warning is logged when it
+    /// exceeds five minutes.
+    pub fn clock_skew(&self) -> Option<i64> {
+        *self
+            .registry
+            .clock_skew
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Record that an HTTP/3 endpoint serving the managed certificates, such as a QUIC server
+    /// using [`server_config`](Self::server_config), listens on UDP port `port`, or that none
+    /// does if `None`, for [`TlsHeaders`](crate::TlsHeaders) to advertise.
+    pub fn set_http3_port(&self, port: Option<u16>) {
+        *self
+            .registry
+            .http3_port
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = port;
+    }
+
+    /// Get the UDP port of the HTTP/3 endpoint recorded with
+    /// [`set_http3_port`](Self::set_http3_port), if any.
+    pub fn http3_port(&self) -> Option<u16> {
+        *self
+            .registry
+            .http3_port
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Go back to serving the previous certificate for `domain`, for when a newly issued one turns
+    /// out to have a problem.
+    ///
+    /// The current certificate is discarded, and the previous one is served and stored in the
+    /// cache as the current one. Renewal is scheduled based on the previous certificate, so a
+    /// replacement is ordered once it is due. Returns false if no managed certificate covers
+    /// `domain`, or there is no previous certificate to go back to.
+    ///
+    /// The rollback happens in the background; watch [`cert_updates`](Self::cert_updates) to
+    /// see it take effect.
+    pub fn rollback(&self, domain: &str) -> bool {
+        self.registry.command(domain, Command::Rollback, |status| {
+            status.previous_certs > 0
+        })
+    }
+
+    /// Order a new certificate for `domain` now, rather than when renewal is due, such as after a
+    /// DNS cutover.
+    ///
+    /// If an order is already in progress, another one follows it. Returns false if no managed
+    /// certificate covers `domain`.
+    pub fn renew(&self, domain: &str) -> bool {
+        self.registry.command(domain, Command::Renew, |_| true)
+    }
+
+    /// Revoke the certificate covering `domain` with the CA, for `reason`, and order a replacement
+    /// right away, such as when its key may have been exposed.
+    ///
+    /// The replacement gets a new key, even if [keys are reused](crate::AcmeConfig::reuse_cert_key).
+    /// Until it is deployed, the revoked certificate is still served, as clients checking
+    /// revocation reject it but others don't. The revocation happens in the background; watch
+    /// [`events`](Self::events) for [`CertRevoked`](crate::EventKind::CertRevoked), or
+    /// [`RevocationFailed`](crate::EventKind::RevocationFailed). Returns false if no managed
+    /// certificate covers `domain`, or none is being served for it yet.
+    ///
+    /// ```no_run
+    /// # fn example(handle: tide_acme::AcmeHandle) {
+    /// use tide_acme::RevocationReason;
+    ///
+    /// handle.revoke("domain.example", RevocationReason::KeyCompromise);
+    /// # }
+    /// ```
+    pub fn revoke(&self, domain: &str, reason: RevocationReason) -> bool {
+        self.registry
+            .command(domain, Command::Revoke(reason), |status| {
+                status.not_after.is_some()
+            })
+    }
+
+    /// Report a synthetic renewal failure for the certificate covering `domain`, with the error
+    /// `message`, to rehearse runbooks and check alerting against a staging deployment. Requires
+    /// the `simulate-failure` feature.
+    ///
+    /// The failure goes through the same path as a real failed order: it is logged, passed to the
+    /// [error reporter](crate::AcmeConfig::error_reporter) and
+    /// [`on_order_failed`](crate::AcmeConfig::on_order_failed), published as an
+    /// [`OrderFailed`](crate::EventKind::OrderFailed) event, counted in
+    /// [`CertStatus::failed_orders`] and exported in metrics. The certificate being served and
+    /// the renewal schedule are left alone, and the count is reset by the next successful
+    /// deployment. Returns false if no managed certificate covers `domain`.
+    #[cfg(feature = "simulate-failure")]
+    pub fn simulate_failure(&self, domain: &str, message: impl Into<String>) -> bool {
+        let command = Command::SimulateFailure(message.into());
+        self.registry.command(domain, command, |_| true)
+    }
+
+    /// Start managing a certificate for `domain`, from the main directory, without restarting.
+    ///
+    /// The domain gets a certificate of its own, loaded from the cache or ordered in the
+    /// background, and renewed like the configured domains. Returns false if a managed
+    /// certificate already names `domain`. Domains added this way are not remembered across
+    /// restarts unless [restored from a snapshot](crate::AcmeConfig::restore); add them to the
+    /// configuration too.
+    pub fn add_domain(&self, domain: &str) -> bool {
+        if self.registry.names(domain) {
+            return false;
+        }
+        match &*self
+            .registry
+            .added_domains
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+        {
+            Some(added) => added.unbounded_send(domain.into()).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Stop serving and managing `domain`, without restarting.
+    ///
+    /// If the certificate naming `domain` covers other domains too, a new certificate for the
+    /// others is ordered right away; until it is deployed, the current one is served for them.
+    /// Otherwise, the certificate is no longer served or renewed; it stays in the cache. Returns
+    /// false if no managed certificate names `domain`.
+    pub fn remove_domain(&self, domain: &str) -> bool {
+        self.registry
+            .command(domain, Command::RemoveDomain(domain.into()), |status| {
+                status.domains.iter().any(|d| d == domain)
+            })
+    }
+
+    /// Check the cache for certificates renewed by another process sharing it, and serve them.
+    ///
+    /// A cached certificate is served if it differs from the one being served and isn't due for
+    /// renewal yet, so with renewals done externally, ahead of the renewal time, this process
+    /// only serves certificates. Call this when the cache backend reports a change, or, for a
+    /// cache in a directory, enable `AcmeConfig::watch_cache_dir` with the `watch` feature. The
+    /// check happens in the background.
+    pub fn reload(&self) {
+        self.registry.command_all(Command::Reload);
+    }
+
+    /// Load every cache entry of the managed certificates and their accounts again, so that an
+    /// [`EncryptedCache`](crate::EncryptedCache) re-encrypts those still encrypted with a
+    /// [previous key](crate::EncryptedCache::previous_key) under its current key, to finish a key
+    /// rotation. This happens in the background, with a
+    /// [`CacheReencrypted`](crate::EventKind::CacheReencrypted) event for each certificate once
+    /// done.
+    pub fn reencrypt_cache(&self) {
+        self.registry.command_all(Command::Reencrypt);
+    }
+
+    /// Apply the domains, contact URLs and ACME directory of `config` without restarting,
+    /// keeping the listeners, their connections, and the certificates already obtained, such as
+    /// when configuration management pushes a new configuration, or on `SIGHUP`.
+    ///
+    /// Certificates whose domains are unchanged are kept. Those for domains no longer
+    /// configured stop being served and managed, and those for new domains are loaded from the
+    /// cache or ordered in the background; until then, the previous certificates go on being
+    /// served for the domains they cover. A new directory replaces the main directory for every
+    /// certificate from it, including those [added](Self::add_domain) at runtime or obtained on
+    /// demand, which are loaded from the cache or ordered from the new directory in the same
+    /// way. New contact URLs switch to the account cached for them, registering one with the
+    /// next order if there is none.
+    ///
+    /// Other settings of `config`, including its directory groups, cache and hooks, are ignored:
+    /// they keep the values the acceptor was created with. The reload happens in the background;
+    /// [`config_summary`](Self::config_summary) shows it once applied. Returns false if the
+    /// acceptor has shut down.
+    ///
+    /// ```no_run
+    /// # fn example(handle: tide_acme::AcmeHandle) {
+    /// use tide_acme::AcmeConfig;
+    ///
+    /// handle.reload_config(
+    ///     AcmeConfig::new(vec!["domain.example", "www.domain.example"])
+    ///         .contact_push("mailto:admin@domain.example")
+    ///         .directory_lets_encrypt(true),
+    /// );
+    /// # }
+    /// ```
+    pub fn reload_config<EC: Debug, EA: Debug>(&self, config: AcmeConfig<EC, EA>) -> bool {
+        self.registry.reload_config(ConfigReload {
+            domains: Some(config.domains),
+            contact: Some(config.contact),
+            directory_url: Some(config.directory_url),
+        })
+    }
+
+    /// Get the certificate resolver serving the managed certificates by SNI.
+    ///
+    /// The resolver always serves the current certificates, so a TLS stack using it picks up
+    /// renewals without reconfiguration. See [`AcmeResolver`] for embedding it in your own server
+    /// configuration.
+    pub fn cert_resolver(&self) -> Arc<AcmeResolver> {
+        self.resolver.clone()
+    }
+
+    /// Create a rustls server configuration serving the managed certificates, for use by another
+    /// listener such as a QUIC endpoint serving HTTP/3 alongside the Tide HTTPS listener.
+    ///
+    /// The configuration has no ALPN protocols set; add the ones the listener speaks (such as
+    /// `h3`). Validation requests are only answered by the listener using the
+    /// [`AcmeTlsAcceptor`](crate::AcmeTlsAcceptor). Session secrets are logged if
+    /// [`keylog`](crate::AcmeConfig::keylog) is on, and sessions resumed as configured with
+    /// [`session_resumption`](crate::AcmeConfig::session_resumption), sharing the acceptor's
+    /// session cache and ticket keys.
+    pub fn server_config(&self) -> ServerConfig {
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.cert_resolver = self.cert_resolver();
+        if let Some(key_log) = self
+            .registry
+            .key_log
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+        {
+            config.key_log = key_log;
+        }
+        if let Some(resumption) = &*self
+            .registry
+            .resumption
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+        {
+            resumption.apply(&mut config);
+        }
+        config
+    }
+
+    /// Get a stream yielding the status of a certificate each time it is deployed, whether loaded
+    /// from the cache or newly issued.
+    ///
+    /// This is useful for TLS stacks that take a snapshot of their certificates rather than
+    /// using [`cert_resolver`](Self::cert_resolver).
+    pub fn cert_updates(&self) -> impl Stream<Item = CertStatus> {
+        let (sender, receiver) = unbounded();
+        self.registry
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(sender);
+        receiver
+    }
+
+    /// Get a stream yielding each certificate as it is deployed: loaded from the cache on
+    /// startup, newly issued, or restored by a rollback.
+    ///
+    /// This is useful for pushing certificates to other infrastructure, such as load balancers,
+    /// the moment they change.
+    pub fn cert_rotations(&self) -> impl Stream<Item = CertRotation> {
+        let (sender, receiver) = unbounded();
+        self.registry
+            .rotation_subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(sender);
+        receiver
+    }
+}
+
+/// The DER-encoded certificate chain and private key of a certificate, and the rustls
+/// certificate made of them.
+pub(crate) struct CertMaterial {
+    pub(crate) chain: Vec<Vec<u8>>,
+    pub(crate) key: Zeroizing<Vec<u8>>,
+    pub(crate) certified_key: CertifiedKey,
+}
+
+impl CertMaterial {
+    pub(crate) fn chain_pem(&self) -> String {
+        let pems: Vec<pem::Pem> = self
+            .chain
+            .iter()
+            .map(|der| pem::Pem {
+                tag: "CERTIFICATE".into(),
+                contents: der.clone(),
+            })
+            .collect();
+        pem::encode_many(&pems)
+    }
+
+    /// The private key as PEM, or empty if it is held by a key store.
+    pub(crate) fn key_pem(&self) -> Zeroizing<String> {
+        if self.key.is_empty() {
+            return Zeroizing::default();
+        }
+        format::encode_pem("PRIVATE KEY", &self.key)
+    }
+}
+
+#[cfg(test)]
+impl CertMaterial {
+    /// Material made of `chain` and `key` as given, signing with a throwaway key.
+    pub(crate) fn fake(chain: Vec<Vec<u8>>, key: Vec<u8>) -> Self {
+        use tide_rustls::rustls::sign::any_ecdsa_type;
+        use tide_rustls::rustls::{Certificate, PrivateKey};
+
+        let signer = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        let signer = any_ecdsa_type(&PrivateKey(signer.serialize_der())).unwrap();
+        let certs = chain.iter().cloned().map(Certificate).collect();
+        Self {
+            certified_key: CertifiedKey::new(certs, Arc::new(signer)),
+            chain,
+            key: key.into(),
+        }
+    }
+}
+
+/// Shared record of certificate status, updated by the background tasks.
+#[derive(Default)]
+pub(crate) struct Registry {
+    certs: Mutex<Vec<Entry>>,
+    subscribers: Mutex<Vec<UnboundedSender<CertStatus>>>,
+    rotation_subscribers: Mutex<Vec<UnboundedSender<CertRotation>>>,
+    clock_skew: Mutex<Option<i64>>,
+    event_subscribers: Mutex<Vec<(EventFilter, UnboundedSender<AcmeEvent>)>>,
+    /// The most recent events, oldest first, up to the configured number.
+    history: Mutex<VecDeque<AcmeEvent>>,
+    history_capacity: AtomicUsize,
+    /// The number of events published since startup, by event type.
+    #[cfg(feature = "metrics")]
+    event_counts: Mutex<BTreeMap<&'static str, u64>>,
+    pub(crate) connections: Arc<Connections>,
+    /// The number of connections the handshake filter rejected.
+    rejected_handshakes: AtomicU64,
+    /// The parameters negotiated by completed handshakes.
+    protocol_stats: Mutex<ProtocolStats>,
+    /// The ALPN protocols offered by any listener.
+    offered_alpn: Mutex<BTreeSet<Vec<u8>>>,
+    /// The number of responses `ExpiryWarning` flagged.
+    expiring_cert_responses: AtomicU64,
+    /// The number of certificates the expiry watchdog last found below an alarm threshold.
+    expiry_alarms: AtomicUsize,
+    pub(crate) requests: Arc<Requests>,
+    pub(crate) pacer: OrderPacer,
+    pub(crate) rate_budgets: RateBudgets,
+    pub(crate) shutdown: Shutdown,
+    pub(crate) client_addrs: PeerMap<SocketAddr>,
+    pub(crate) tls_info: PeerMap<TlsConnectionInfo>,
+    /// Key authorizations answering pending http-01 challenges, by token.
+    http01_responses: Mutex<HashMap<String, String>>,
+    /// Where to send domains added at runtime.
+    added_domains: Mutex<Option<UnboundedSender<String>>>,
+    /// Where to send configurations to reload.
+    config_reloads: Mutex<Option<UnboundedSender<ConfigReload>>>,
+    validation_ports: Mutex<ValidationPorts>,
+    /// The local port last recorded, to skip recording it again for each connection.
+    last_port: AtomicU16,
+    config_summary: Mutex<ConfigSummary>,
+    /// Where to write TLS session secrets, if key logging is on.
+    key_log: Mutex<Option<Arc<dyn KeyLog>>>,
+    /// The session cache and ticketer, if session resumption is configured.
+    resumption: Mutex<Option<Resumption>>,
+    /// The configured clock, if set; otherwise the system clock is used.
+    clock: Mutex<Option<Arc<dyn Clock>>>,
+    /// The UDP port of the HTTP/3 endpoint serving the certificates alongside, if any.
+    http3_port: Mutex<Option<u16>>,
+}
+
+/// Where tls-alpn-01 validation connections, which the CA always makes to port 443, arrive.
+#[derive(Default)]
+struct ValidationPorts {
+    /// The local port they're forwarded to, if declared.
+    forwarded_to: Option<u16>,
+    /// The local ports of the sockets answering them seen so far.
+    bound: BTreeSet<u16>,
+}
+
+struct Entry {
+    status: CertStatus,
+    material: Option<Arc<CertMaterial>>,
+    commands: UnboundedSender<Command>,
+    /// Whether the certificate was removed from management, after its last domain was removed.
+    removed: bool,
+    /// The runtime state of the certificate's management, for snapshots.
+    resume: Option<CertResume>,
+    /// The index of the directory the certificate comes from.
+    directory: usize,
+    origin: CertOrigin,
+}
+
+/// Request for the background task managing a certificate.
+#[derive(Clone, Debug)]
+pub(crate) enum Command {
+    Rollback,
+    Renew,
+    Reload,
+    Reencrypt,
+    RemoveDomain(String),
+    Revoke(RevocationReason),
+    /// Stop managing the certificate, leaving it served, as its configuration was reloaded.
+    Retire,
+    #[cfg(feature = "simulate-failure")]
+    SimulateFailure(String),
+}
+
+impl Registry {
+    pub(crate) fn record_rejected_handshake(&self) {
+        self.rejected_handshakes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_protocols(&self, session: &ServerSession) {
+        self.protocol_stats
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .record(session);
+    }
+
+    pub(crate) fn record_offered_alpn(&self, protocols: &[Vec<u8>]) {
+        let mut offered = self
+            .offered_alpn
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        offered.extend(protocols.iter().cloned());
+    }
+
+    pub(crate) fn offered_alpn(&self) -> Vec<Vec<u8>> {
+        self.offered_alpn
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn record_expiring_cert_response(&self) {
+        self.expiring_cert_responses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_expiry_alarms(&self, alarms: usize) {
+        self.expiry_alarms.store(alarms, Ordering::Relaxed);
+    }
+
+    /// Register a certificate, returning its index for updates and the receiver for commands.
+    pub(crate) fn register(
+        &self,
+        domains: Vec<String>,
+        directory: usize,
+        origin: CertOrigin,
+    ) -> (usize, UnboundedReceiver<Command>) {
+        let (commands, receiver) = unbounded();
+        let mut certs = self.certs.lock().unwrap_or_else(PoisonError::into_inner);
+        certs.push(Entry {
+            status: CertStatus {
+                domains,
+                not_before: None,
+                not_after: None,
+                renew_at: None,
+                previous_certs: 0,
+                backed_off_domains: vec![],
+                last_error: None,
+                last_failure: None,
+                last_error_hint: None,
+                challenge_failure: None,
+                failed_orders: 0,
+                degraded: None,
+            },
+            material: None,
+            commands,
+            removed: false,
+            resume: None,
+            directory,
+            origin,
+        });
+        (certs.len() - 1, receiver)
+    }
+
+    pub(crate) fn update(&self, index: usize, f: impl FnOnce(&mut CertStatus)) {
+        f(&mut self.certs.lock().unwrap_or_else(PoisonError::into_inner)[index].status)
+    }
+
+    pub(crate) fn cert_status(&self, index: usize) -> CertStatus {
+        self.certs.lock().unwrap_or_else(PoisonError::into_inner)[index]
+            .status
+            .clone()
+    }
+
+    pub(crate) fn set_resume(&self, index: usize, resume: CertResume) {
+        self.certs.lock().unwrap_or_else(PoisonError::into_inner)[index].resume = Some(resume);
+    }
+
+    /// The runtime state of the certificates still managed, and the event history.
+    fn snapshot(&self) -> Snapshot {
+        let certs = self
+            .certs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|entry| !entry.removed)
+            .filter_map(|entry| {
+                let mut resume = entry.resume.clone()?;
+                resume.failed_orders = entry.status.failed_orders;
+                resume.last_error = entry.status.last_error.clone();
+                resume.last_failure = entry.status.last_failure.clone();
+                Some(resume)
+            })
+            .collect();
+        let events = self
+            .history
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(SavedEvent::new)
+            .collect();
+        Snapshot { certs, events }
+    }
+
+    /// Fill the history with events restored from a snapshot, up to its capacity.
+    pub(crate) fn restore_history(&self, events: Vec<AcmeEvent>) {
+        let capacity = self.history_capacity.load(Ordering::Relaxed);
+        let skip = events.len().saturating_sub(capacity);
+        self.history
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .extend(events.into_iter().skip(skip));
+    }
+
+    /// Mark a certificate as removed from management, leaving it out of the status and lookups.
+    pub(crate) fn remove(&self, index: usize) {
+        let mut certs = self.certs.lock().unwrap_or_else(PoisonError::into_inner);
+        certs[index].removed = true;
+        certs[index].material = None;
+    }
+
+    pub(crate) fn set_validation_port(&self, port: u16) {
+        self.validation_ports
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .forwarded_to = Some(port);
+    }
+
+    /// The local port validation connections arrive on.
+    pub(crate) fn validation_port(&self) -> u16 {
+        let ports = self
+            .validation_ports
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        ports.forwarded_to.unwrap_or(443)
+    }
+
+    /// Record that a socket answering validation requests is bound to `port`. Returns whether it
+    /// wasn't seen before.
+    pub(crate) fn record_port(&self, port: u16) -> bool {
+        if self.last_port.swap(port, Ordering::Relaxed) == port {
+            return false;
+        }
+        self.validation_ports
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .bound
+            .insert(port)
+    }
+
+    pub(crate) fn validation_path(&self) -> String {
+        let ports = self
+            .validation_ports
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let expected = ports.forwarded_to.unwrap_or(443);
+        let mut path = match expected {
+            443 => "port 443".to_string(),
+            port => format!("port 443, forwarded to local port {}", port),
+        };
+        if !ports.bound.is_empty() && !ports.bound.contains(&expected) {
+            let bound: Vec<String> = ports.bound.iter().map(ToString::to_string).collect();
+            path += &format!(
+                ", but no listener is bound to local port {} (bound: {})",
+                expected,
+                bound.join(", ")
+            );
+        }
+        path
+    }
+
+    pub(crate) fn set_added_domains(&self, added: UnboundedSender<String>) {
+        *self
+            .added_domains
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(added);
+    }
+
+    pub(crate) fn set_config_reloads(&self, reloads: UnboundedSender<ConfigReload>) {
+        *self
+            .config_reloads
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(reloads);
+    }
+
+    /// Send `reload` to the task applying configuration reloads. Returns false if it's gone.
+    pub(crate) fn reload_config(&self, reload: ConfigReload) -> bool {
+        match &*self
+            .config_reloads
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+        {
+            Some(reloads) => reloads.unbounded_send(reload).is_ok(),
+            None => false,
+        }
+    }
+
+    /// The domains and origins of the managed certificates from the directories `directories`
+    /// selects, by index.
+    pub(crate) fn managed(
+        &self,
+        directories: impl Fn(usize) -> bool,
+    ) -> Vec<(usize, Vec<String>, CertOrigin)> {
+        let certs = self.certs.lock().unwrap_or_else(PoisonError::into_inner);
+        certs
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| !entry.removed && directories(entry.directory))
+            .map(|(index, entry)| (index, entry.status.domains.clone(), entry.origin))
+            .collect()
+    }
+
+    /// Send `command` to the task managing the certificate at `index`.
+    pub(crate) fn command_index(&self, index: usize, command: Command) {
+        let _ = self.certs.lock().unwrap_or_else(PoisonError::into_inner)[index]
+            .commands
+            .unbounded_send(command);
+    }
+
+    /// Record the main directory and its domains after a configuration reload.
+    pub(crate) fn reload_config_summary(
+        &self,
+        directory_url: &str,
+        staging: bool,
+        domains: &[String],
+    ) {
+        let mut summary = self
+            .config_summary
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        summary.directory_url = directory_url.into();
+        summary.staging = staging;
+        let main = GroupSummary {
+            directory: directory_url.into(),
+            source: false,
+            domains: domains.to_vec(),
+        };
+        match summary.groups.first_mut() {
+            Some(group) => *group = main,
+            None => summary.groups.push(main),
+        }
+    }
+
+    /// Whether a managed certificate names `domain`, not counting wildcard domains covering it.
+    pub(crate) fn names(&self, domain: &str) -> bool {
+        let certs = self.certs.lock().unwrap_or_else(PoisonError::into_inner);
+        certs
+            .iter()
+            .any(|entry| !entry.removed && entry.status.domains.iter().any(|d| d == domain))
+    }
+
+    /// Whether a managed certificate covers `domain`, by name or by wildcard.
+    pub(crate) fn covers(&self, domain: &str) -> bool {
+        let certs = self.certs.lock().unwrap_or_else(PoisonError::into_inner);
+        Registry::find(&certs, domain).is_some()
+    }
+
+    /// The end of the validity period of the certificate served for `domain`, if any.
+    pub(crate) fn not_after(&self, domain: &str) -> Option<SystemTime> {
+        let certs = self.certs.lock().unwrap_or_else(PoisonError::into_inner);
+        Registry::find(&certs, domain)?.status.not_after
+    }
+
+    pub(crate) fn set_material(&self, index: usize, material: CertMaterial) {
+        self.certs.lock().unwrap_or_else(PoisonError::into_inner)[index].material =
+            Some(Arc::new(material));
+    }
+
+    pub(crate) fn set_clock_skew(&self, skew: i64) {
+        *self
+            .clock_skew
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(skew);
+    }
+
+    /// Notify subscribers that a certificate was deployed, dropping those that went away. Returns
+    /// the deployed certificate.
+    pub(crate) fn notify(&self, index: usize) -> Option<CertRotation> {
+        let (status, material) = {
+            let certs = self.certs.lock().unwrap_or_else(PoisonError::into_inner);
+            (certs[index].status.clone(), certs[index].material.clone())
+        };
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|sender| sender.unbounded_send(status.clone()).is_ok());
+        let rotation = CertRotation {
+            status,
+            material: material?,
+        };
+        self.rotation_subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|sender| sender.unbounded_send(rotation.clone()).is_ok());
+        Some(rotation)
+    }
+
+    /// The material of each certificate being served, with the domains it covers.
+    #[cfg(unix)]
+    pub(crate) fn materials(&self) -> Vec<(Vec<String>, Arc<CertMaterial>)> {
+        let certs = self.certs.lock().unwrap_or_else(PoisonError::into_inner);
+        certs
+            .iter()
+            .filter_map(|entry| Some((entry.status.domains.clone(), entry.material.clone()?)))
+            .collect()
+    }
+
+    /// The material of the certificate at `index`, if one is being served.
+    pub(crate) fn material_at(&self, index: usize) -> Option<Arc<CertMaterial>> {
+        self.certs.lock().unwrap_or_else(PoisonError::into_inner)[index]
+            .material
+            .clone()
+    }
+
+    /// Find the material of the certificate covering `domain`.
+    pub(crate) fn material(&self, domain: &str) -> Option<Arc<CertMaterial>> {
+        let certs = self.certs.lock().unwrap_or_else(PoisonError::into_inner);
+        Self::find(&certs, domain)?.material.clone()
+    }
+
+    pub(crate) fn set_config_summary(&self, summary: ConfigSummary) {
+        *self
+            .config_summary
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = summary;
+    }
+
+    pub(crate) fn set_clock(&self, clock: Arc<dyn Clock>) {
+        *self.clock.lock().unwrap_or_else(PoisonError::into_inner) = Some(clock);
+    }
+
+    /// The current time, by the configured clock.
+    pub(crate) fn now(&self) -> SystemTime {
+        match &*self.clock.lock().unwrap_or_else(PoisonError::into_inner) {
+            Some(clock) => clock.now(),
+            None => SystemTime::now(),
+        }
+    }
+
+    pub(crate) fn set_key_log(&self, key_log: Arc<dyn KeyLog>) {
+        *self.key_log.lock().unwrap_or_else(PoisonError::into_inner) = Some(key_log);
+    }
+
+    pub(crate) fn set_resumption(&self, resumption: Resumption) {
+        *self
+            .resumption
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(resumption);
+    }
+
+    pub(crate) fn set_history_capacity(&self, capacity: usize) {
+        self.history_capacity.store(capacity, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "metrics")]
+    pub(crate) fn event_counts(&self) -> BTreeMap<&'static str, u64> {
+        self.event_counts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Send an event to the subscribers whose filter matches, dropping those that went away, and
+    /// keep it in the history.
+    pub(crate) fn publish(&self, event: AcmeEvent) {
+        #[cfg(feature = "metrics")]
+        {
+            *self
+                .event_counts
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .entry(event.kind.name())
+                .or_default() += 1;
+        }
+        let capacity = self.history_capacity.load(Ordering::Relaxed);
+        if capacity > 0 {
+            let mut history = self.history.lock().unwrap_or_else(PoisonError::into_inner);
+            if history.len() == capacity {
+                history.pop_front();
+            }
+            history.push_back(event.clone());
+        }
+        self.event_subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(filter, sender)| {
+                !filter.matches(&event) || sender.unbounded_send(event.clone()).is_ok()
+            });
+    }
+
+    /// Send a command to the task managing the certificate covering `domain`, if its status passes
+    /// `check`.
+    fn command(
+        &self,
+        domain: &str,
+        command: Command,
+        check: impl FnOnce(&CertStatus) -> bool,
+    ) -> bool {
+        let certs = self.certs.lock().unwrap_or_else(PoisonError::into_inner);
+        match Self::find(&certs, domain) {
+            Some(entry) if check(&entry.status) => entry.commands.unbounded_send(command).is_ok(),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn set_http01_response(&self, token: String, key_authorization: String) {
+        self.http01_responses
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(token, key_authorization);
+    }
+
+    pub(crate) fn remove_http01_response(&self, token: &str) {
+        self.http01_responses
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(token);
+    }
+
+    /// The key authorization answering the pending http-01 challenge for `token`, if any.
+    pub(crate) fn http01_response(&self, token: &str) -> Option<String> {
+        self.http01_responses
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(token)
+            .cloned()
+    }
+
+    /// Send a command to the tasks managing every certificate.
+    fn command_all(&self, command: Command) {
+        for entry in self
+            .certs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+        {
+            let _ = entry.commands.unbounded_send(command.clone());
+        }
+    }
+
+    /// Find the entry of the certificate covering `domain`: the one naming it, or else the one
+    /// with a wildcard domain covering it.
+    fn find<'a>(certs: &'a [Entry], domain: &str) -> Option<&'a Entry> {
+        let named = |name: &str| {
+            certs
+                .iter()
+                .find(|entry| !entry.removed && entry.status.domains.iter().any(|d| d == name))
+        };
+        named(domain).or_else(|| {
+            let (_, parent) = domain.split_once('.')?;
+            named(&format!("*.{}", parent))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use async_std::task::block_on;
+    use futures::{FutureExt, StreamExt};
+
+    use super::*;
+    use crate::state::{Event, EventError, EventOk, OrderError};
+    use crate::{AcmeConfig, EventKind};
+
+    #[test]
+    fn drains_before_shutting_down() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        assert!(!handle.draining());
+        let request = handle.registry.requests.start();
+        assert!(!block_on(handle.drain(Duration::from_millis(100))));
+        assert!(handle.draining());
+        assert!(handle.registry.shutdown.stopping());
+        drop(request);
+
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let request = handle.registry.requests.start();
+        let drained = block_on(async {
+            let finishing = async {
+                task::sleep(Duration::from_millis(100)).await;
+                drop(request);
+            };
+            futures::join!(handle.drain(Duration::from_secs(10)), finishing).0
+        });
+        assert!(drained);
+    }
+
+    #[test]
+    fn reports_registered_certificates() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        assert!(handle.status().is_empty());
+        let (first, _) =
+            handle
+                .registry
+                .register(vec!["a.example".into()], 0, CertOrigin::Configured);
+        let (second, _) =
+            handle
+                .registry
+                .register(vec!["b.example".into()], 0, CertOrigin::Configured);
+        let renew_at = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        handle
+            .registry
+            .update(second, |status| status.renew_at = Some(renew_at));
+        let status = handle.status();
+        assert_eq!(status.len(), 2);
+        assert_eq!(status[first].domains, ["a.example"]);
+        assert_eq!(status[first].renew_at, None);
+        assert_eq!(status[second].domains, ["b.example"]);
+        assert_eq!(status[second].renew_at, Some(renew_at));
+    }
+
+    #[test]
+    fn survives_panics_while_holding_the_registry() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let registry = handle.registry.clone();
+        let _ = std::thread::spawn(move || {
+            let _certs = registry.certs.lock().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+        assert!(handle.registry.certs.is_poisoned());
+        let (index, _) =
+            handle
+                .registry
+                .register(vec!["a.example".into()], 0, CertOrigin::Configured);
+        assert_eq!(handle.status()[index].domains, ["a.example"]);
+    }
+
+    #[test]
+    fn publishes_events_to_matching_subscribers() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let mut all = handle.events(EventFilter::new());
+        let mut other = handle.events(EventFilter::new().domain("b.example"));
+        let dropped = handle.events(EventFilter::new());
+        drop(dropped);
+        let event: Event<Infallible, Infallible> = Ok(EventOk::DeployedNewCert);
+        handle
+            .registry
+            .publish(AcmeEvent::new(&["a.example".to_string()], None, &event));
+        let received = all.next().now_or_never().flatten().unwrap();
+        assert_eq!(received.kind, EventKind::DeployedNewCert);
+        assert!(other.next().now_or_never().is_none());
+        assert_eq!(handle.registry.event_subscribers.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn streams_certificate_rotations() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let mut rotations = handle.cert_rotations();
+        let (index, _) =
+            handle
+                .registry
+                .register(vec!["a.example".into()], 0, CertOrigin::Configured);
+        handle.registry.notify(index);
+        assert!(rotations.next().now_or_never().is_none());
+        let material = CertMaterial::fake(vec![b"leaf".to_vec()], b"key".to_vec());
+        handle.registry.set_material(index, material);
+        handle.registry.notify(index);
+        let rotation = rotations.next().now_or_never().flatten().unwrap();
+        assert_eq!(rotation.status().domains, ["a.example"]);
+        assert!(rotation.certificate_pem().contains("BEGIN CERTIFICATE"));
+        assert!(rotation.private_key_pem().contains("BEGIN PRIVATE KEY"));
+        assert!(!format!("{:?}", rotation).contains("PRIVATE KEY"));
+        drop(rotations);
+        handle.registry.notify(index);
+        assert!(handle
+            .registry
+            .rotation_subscribers
+            .lock()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn exposes_the_certificate_resolver() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        assert!(Arc::ptr_eq(&handle.cert_resolver(), &handle.resolver));
+        assert_eq!(crate::ACME_TLS_ALPN_NAME, b"acme-tls/1");
+    }
+
+    #[test]
+    fn reports_clock_skew() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        assert_eq!(handle.clock_skew(), None);
+        handle.registry.set_clock_skew(-42);
+        assert_eq!(handle.clock_skew(), Some(-42));
+    }
+
+    #[test]
+    fn reports_time_to_expiry() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let (index, _) =
+            handle
+                .registry
+                .register(vec!["a.example".into()], 0, CertOrigin::Configured);
+        assert_eq!(handle.time_to_expiry("a.example"), None);
+        let day = Duration::from_secs(24 * 60 * 60);
+        let not_after = SystemTime::now() + 30 * day;
+        handle
+            .registry
+            .update(index, |status| status.not_after = Some(not_after));
+        let left = handle.time_to_expiry("a.example").unwrap();
+        assert!(29 * day < left && left <= 30 * day);
+        handle.registry.update(index, |status| {
+            status.not_after = Some(SystemTime::now() - day)
+        });
+        assert_eq!(handle.time_to_expiry("a.example"), Some(Duration::ZERO));
+        assert_eq!(handle.time_to_expiry("other.example"), None);
+    }
+
+    #[test]
+    fn resolves_on_the_next_deployment() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let (a, _) = handle
+            .registry
+            .register(vec!["a.example".into()], 0, CertOrigin::Configured);
+        let (b, _) = handle
+            .registry
+            .register(vec!["b.example".into()], 0, CertOrigin::Configured);
+        assert!(block_on(handle.renewed("other.example")).is_none());
+        let mut renewed = Box::pin(handle.renewed("a.example"));
+        assert!((&mut renewed).now_or_never().is_none());
+        handle.registry.notify(b);
+        assert!((&mut renewed).now_or_never().is_none());
+        handle.registry.notify(a);
+        let status = renewed.now_or_never().flatten().unwrap();
+        assert_eq!(status.domains, ["a.example"]);
+    }
+
+    #[test]
+    fn finds_certificates_by_wildcard() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let (index, _) =
+            handle
+                .registry
+                .register(vec!["*.b.example".into()], 0, CertOrigin::Configured);
+        let not_after = SystemTime::now() + Duration::from_secs(60 * 60);
+        handle
+            .registry
+            .update(index, |status| status.not_after = Some(not_after));
+        assert!(handle.time_to_expiry("www.b.example").is_some());
+        assert!(handle.time_to_expiry("*.b.example").is_some());
+        assert!(handle.time_to_expiry("b.example").is_none());
+        assert!(handle.time_to_expiry("a.www.b.example").is_none());
+    }
+
+    #[test]
+    fn adds_domains_at_runtime() {
+        let state = AcmeConfig::new(Vec::<String>::new()).state();
+        let handle: AcmeHandle = state.handle();
+        let mut added = state.take_added_domains().unwrap();
+        let (index, _) =
+            handle
+                .registry
+                .register(vec!["a.example".into()], 0, CertOrigin::Configured);
+        assert!(!handle.add_domain("a.example"));
+        assert!(handle.add_domain("b.example"));
+        assert_eq!(added.try_recv().ok().as_deref(), Some("b.example"));
+        // Names of removed certificates may be added again.
+        handle.registry.remove(index);
+        assert!(!handle.registry.names("a.example"));
+        assert!(handle.add_domain("a.example"));
+        assert_eq!(added.try_recv().ok().as_deref(), Some("a.example"));
+    }
+
+    #[test]
+    fn reports_readiness() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let (index, _) =
+            handle
+                .registry
+                .register(vec!["a.example".into()], 0, CertOrigin::Configured);
+        let readiness = || handle.status()[index].readiness();
+        assert_eq!(readiness(), CertReadiness::Pending);
+        handle
+            .registry
+            .update(index, |status| status.failed_orders = 1);
+        assert_eq!(readiness(), CertReadiness::Failed);
+        let expired = SystemTime::now() - Duration::from_secs(60);
+        handle
+            .registry
+            .update(index, |status| status.not_after = Some(expired));
+        assert_eq!(readiness(), CertReadiness::Failed);
+
+        let mut ready = Box::pin(handle.ready());
+        assert!((&mut ready).now_or_never().is_none());
+        let not_after = SystemTime::now() + Duration::from_secs(60 * 60);
+        handle
+            .registry
+            .update(index, |status| status.not_after = Some(not_after));
+        assert_eq!(readiness(), CertReadiness::Obtained);
+        handle.registry.notify(index);
+        let status = ready.now_or_never().unwrap();
+        assert_eq!(status.domains, ["a.example"]);
+        // Once a certificate is usable, it resolves right away.
+        assert!(handle.ready().now_or_never().is_some());
+    }
+
+    #[test]
+    fn describes_the_validation_path() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        assert_eq!(handle.validation_path(), "port 443");
+        assert!(handle.registry.record_port(8443));
+        assert!(!handle.registry.record_port(8443));
+        assert_eq!(
+            handle.validation_path(),
+            "port 443, but no listener is bound to local port 443 (bound: 8443)"
+        );
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new())
+            .tls_alpn_port_mapping(8443)
+            .state()
+            .handle();
+        assert_eq!(handle.registry.validation_port(), 8443);
+        handle.registry.record_port(8443);
+        assert_eq!(
+            handle.validation_path(),
+            "port 443, forwarded to local port 8443"
+        );
+    }
+
+    #[test]
+    fn keeps_a_bounded_event_history() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new())
+            .event_history(2)
+            .state()
+            .handle();
+        let publish = |event: Event<Infallible, Infallible>| {
+            handle
+                .registry
+                .publish(AcmeEvent::new(&["a.example".to_string()], None, &event))
+        };
+        publish(Ok(EventOk::CertCacheStore));
+        publish(Ok(EventOk::DeployedNewCert));
+        publish(Err(EventError::Order(OrderError::TooManyAttemptsOrder)));
+        let kinds = |filter| {
+            handle
+                .event_history(filter)
+                .iter()
+                .map(|event| event.kind)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            kinds(EventFilter::new()),
+            [EventKind::DeployedNewCert, EventKind::OrderFailed]
+        );
+        assert_eq!(
+            kinds(EventFilter::new().failures_only(true)),
+            [EventKind::OrderFailed]
+        );
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new())
+            .event_history(0)
+            .state()
+            .handle();
+        handle.registry.publish(AcmeEvent::new(
+            &["a.example".to_string()],
+            None,
+            &Ok::<_, EventError<Infallible, Infallible>>(EventOk::DeployedNewCert),
+        ));
+        assert!(handle.event_history(EventFilter::new()).is_empty());
+    }
+
+    #[test]
+    fn reports_order_pacing() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new())
+            .order_rate(1, Duration::from_secs(60 * 60))
+            .state()
+            .handle();
+        let (index, _) =
+            handle
+                .registry
+                .register(vec!["a.example".into()], 0, CertOrigin::Configured);
+        handle
+            .registry
+            .register(vec!["b.example".into()], 0, CertOrigin::Configured);
+        let not_after = SystemTime::now() + Duration::from_secs(60 * 60);
+        handle
+            .registry
+            .update(index, |status| status.not_after = Some(not_after));
+        assert!(handle.registry.pacer.wait().now_or_never().is_some());
+        let pacing = handle.order_pacing();
+        assert_eq!(pacing.pending_certs, 1);
+        assert_eq!(pacing.waiting_orders, 0);
+        assert_eq!(pacing.placed_orders, 1);
+        let next_slot = pacing.next_slot.unwrap();
+        assert!(next_slot > SystemTime::now() + Duration::from_secs(59 * 60));
+    }
+
+    #[test]
+    fn logs_session_secrets_when_asked() {
+        let handle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        assert!(handle.registry.key_log.lock().unwrap().is_none());
+        let handle = AcmeConfig::new(Vec::<String>::new())
+            .keylog(true)
+            .state()
+            .handle();
+        let key_log = handle.registry.key_log.lock().unwrap().clone().unwrap();
+        assert!(Arc::ptr_eq(&handle.server_config().key_log, &key_log));
+    }
+
+    #[test]
+    fn gathers_connection_stats() {
+        use crate::ListenerOptions;
+
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let connections = &handle.registry.connections;
+        let sibling = connections.sibling();
+        let options = ListenerOptions::new().max_connections(1);
+        let open = connections.open(&options).unwrap();
+        let sibling_open = sibling.open(&options).unwrap();
+        assert!(sibling.open(&options).is_none());
+        let handshake = sibling.start_handshake([192, 0, 2, 1].into(), &options);
+        sibling.record_timeout();
+        connections.record_accept_error();
+        let stats = handle.connection_stats();
+        assert_eq!(stats.open_connections, 2);
+        assert_eq!(stats.handshakes_in_progress, 1);
+        assert_eq!(stats.refused_connections, 1);
+        assert_eq!(stats.timed_out_handshakes, 1);
+        assert_eq!(stats.accept_errors, 1);
+        assert_eq!(handle.refused_connections(), 1);
+        assert_eq!(handle.timed_out_handshakes(), 1);
+        drop((open, sibling_open, handshake));
+        let stats = handle.connection_stats();
+        assert_eq!(
+            (stats.open_connections, stats.handshakes_in_progress),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn counts_negotiated_protocols() {
+        use tide_rustls::async_rustls::webpki::DNSNameRef;
+        use tide_rustls::rustls::{
+            Certificate, ClientConfig, ClientSession, NoClientAuth, PrivateKey, ServerConfig,
+            Session,
+        };
+
+        let cert = rcgen::generate_simple_self_signed(vec!["a.example".into()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        let key = PrivateKey(cert.serialize_private_key_der());
+        let mut server_config = ServerConfig::new(NoClientAuth::new());
+        server_config
+            .set_single_cert(vec![Certificate(der.clone())], key)
+            .unwrap();
+        server_config.set_protocols(&[b"h2".to_vec()]);
+        let server_config = Arc::new(server_config);
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        for alpn in [true, false].iter() {
+            let mut client_config = ClientConfig::new();
+            client_config
+                .root_store
+                .add(&Certificate(der.clone()))
+                .unwrap();
+            if *alpn {
+                client_config.set_protocols(&[b"h2".to_vec()]);
+            }
+            let name = DNSNameRef::try_from_ascii_str("a.example").unwrap();
+            let mut client = ClientSession::new(&Arc::new(client_config), name);
+            let mut server = ServerSession::new(&server_config);
+            while client.is_handshaking() || server.is_handshaking() {
+                let mut buf = Vec::new();
+                while client.wants_write() {
+                    client.write_tls(&mut buf).unwrap();
+                }
+                server.read_tls(&mut &buf[..]).unwrap();
+                server.process_new_packets().unwrap();
+                let mut buf = Vec::new();
+                while server.wants_write() {
+                    server.write_tls(&mut buf).unwrap();
+                }
+                client.read_tls(&mut &buf[..]).unwrap();
+                client.process_new_packets().unwrap();
+            }
+            handle.registry.record_protocols(&server);
+        }
+        let stats = handle.protocol_stats();
+        assert_eq!(stats.versions.get("TLSv1.3"), Some(&2));
+        assert_eq!(stats.alpn_protocols.get(&Some("h2".into())), Some(&1));
+        assert_eq!(stats.alpn_protocols.get(&None), Some(&1));
+        assert_eq!(stats.cipher_suites.values().sum::<u64>(), 2);
+        assert!(stats
+            .cipher_suites
+            .keys()
+            .all(|suite| suite.starts_with("TLS13_")));
+    }
+}
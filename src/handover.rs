@@ -0,0 +1,200 @@
+//! Handing the listening socket and current certificates over to a new process, for upgrades
+//! without downtime.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use command_fds::CommandFdExt;
+use listenfd::ListenFd;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::AcmeHandle;
+
+/// Environment variable holding the path of the file with the handed over certificates.
+const HANDOVER_ENV: &str = "TIDE_ACME_HANDOVER";
+
+/// A certificate handed over to a new process: the PEM-encoded private key followed by the
+/// certificate chain, as kept in the cache.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct HandoverCert {
+    pub(crate) domains: Vec<String>,
+    pub(crate) pem: String,
+}
+
+impl Drop for HandoverCert {
+    fn drop(&mut self) {
+        self.pem.zeroize();
+    }
+}
+
+impl AcmeHandle {
+    /// Start a new process running `command`, such as an upgraded binary, handing it the
+    /// listening socket `listener` and the certificates being served.
+    ///
+    /// The new process picks up the socket with [`ListenerOptions::inherit_or_bind`], and the
+    /// certificates when creating its [`AcmeTlsAcceptor`](crate::AcmeTlsAcceptor), which serves
+    /// them right away rather than ordering new ones, even without a cache. Connections queued on
+    /// the socket are accepted by whichever process accepts first, so none are dropped: once the
+    /// new process is up, stop accepting in this one and exit after in-flight requests finish.
+    ///
+    /// The socket is passed using the systemd socket activation protocol, with the
+    /// `LISTEN_FDS` and `LISTEN_FDS_FIRST_FD` environment variables.
+    ///
+    /// [`ListenerOptions::inherit_or_bind`]: crate::ListenerOptions::inherit_or_bind
+    pub fn handover(
+        &self,
+        listener: &std::net::TcpListener,
+        mut command: Command,
+    ) -> io::Result<Child> {
+        let certs: Vec<HandoverCert> = self
+            .registry
+            .materials()
+            .into_iter()
+            .map(|(domains, material)| HandoverCert {
+                domains,
+                pem: [material.key_pem().as_str(), &material.chain_pem()].concat(),
+            })
+            .collect();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let path = std::env::temp_dir().join(format!(
+            "tide-acme-handover-{}-{}.json",
+            std::process::id(),
+            now.as_nanos()
+        ));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        file.write_all(&Zeroizing::new(serde_json::to_vec(&certs)?))?;
+
+        let fd = listener.as_raw_fd();
+        command
+            .preserved_fds(vec![fd])
+            .env("LISTEN_FDS", "1")
+            .env("LISTEN_FDS_FIRST_FD", fd.to_string())
+            .env_remove("LISTEN_PID")
+            .env(HANDOVER_ENV, &path);
+        command.spawn().inspect_err(|_| {
+            let _ = fs::remove_file(&path);
+        })
+    }
+}
+
+/// Take the certificates handed over by the process that started this one, if any, deleting the
+/// file holding them.
+pub(crate) fn take_certs() -> Vec<HandoverCert> {
+    let path = match std::env::var_os(HANDOVER_ENV) {
+        Some(path) => PathBuf::from(path),
+        None => return vec![],
+    };
+    std::env::remove_var(HANDOVER_ENV);
+    read_certs(&path)
+}
+
+/// Read the certificates handed over in the file at `path`, and remove it.
+fn read_certs(path: &Path) -> Vec<HandoverCert> {
+    let certs = fs::read(path).and_then(|json| Ok(serde_json::from_slice(&Zeroizing::new(json))?));
+    let _ = fs::remove_file(path);
+    certs.unwrap_or_else(|err| {
+        warn!(path = %path.display(), %err, "failed to read handed over certificates");
+        vec![]
+    })
+}
+
+/// The sockets passed by the process that started this one, read from the environment once, so
+/// that each is taken by one listener only.
+fn passed_sockets() -> MutexGuard<'static, ListenFd> {
+    static SOCKETS: OnceLock<Mutex<ListenFd>> = OnceLock::new();
+    SOCKETS
+        .get_or_init(|| Mutex::new(ListenFd::from_env()))
+        .lock()
+        .unwrap()
+}
+
+/// Take the first listening TCP socket handed over by the process that started this one, or
+/// passed by systemd socket activation, if any. Sockets of other kinds are left in place.
+pub(crate) fn take_listener() -> Option<std::net::TcpListener> {
+    let mut sockets = passed_sockets();
+    (0..sockets.len()).find_map(|index| sockets.take_tcp_listener(index).ok().flatten())
+}
+
+/// Take the first listening Unix domain socket passed by systemd socket activation, if any.
+pub(crate) fn take_unix_listener() -> Option<std::os::unix::net::UnixListener> {
+    let mut sockets = passed_sockets();
+    (0..sockets.len()).find_map(|index| sockets.take_unix_listener(index).ok().flatten())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Stdio;
+
+    use super::*;
+    use crate::handle::CertMaterial;
+    use crate::snapshot::CertOrigin;
+    use crate::AcmeConfig;
+
+    #[test]
+    fn hands_over_the_listener_and_certificates() {
+        let handle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let (index, _) =
+            handle
+                .registry
+                .register(vec!["domain.example".into()], 0, CertOrigin::Configured);
+        let material = CertMaterial::fake(vec![b"leaf".to_vec()], b"key".to_vec());
+        handle.registry.set_material(index, material);
+        handle
+            .registry
+            .register(vec!["pending.example".into()], 0, CertOrigin::Configured);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(
+                "echo $LISTEN_FDS $LISTEN_FDS_FIRST_FD; test -e /dev/fd/$LISTEN_FDS_FIRST_FD && \
+                 cat \"$TIDE_ACME_HANDOVER\"",
+            )
+            .stdout(Stdio::piped());
+        let output = handle
+            .handover(&listener, command)
+            .unwrap()
+            .wait_with_output()
+            .unwrap();
+        assert!(output.status.success());
+        let output = String::from_utf8(output.stdout).unwrap();
+        let (fds, json) = output.split_once('\n').unwrap();
+        assert_eq!(fds, format!("1 {}", listener.as_raw_fd()));
+
+        let path =
+            std::env::temp_dir().join(format!("tide-acme-handover-test-{}", std::process::id()));
+        fs::write(&path, json).unwrap();
+        let certs = read_certs(&path);
+        assert!(!path.exists());
+        assert_eq!(certs.len(), 1);
+        assert_eq!(certs[0].domains, ["domain.example"]);
+        let pems = pem::parse_many(&certs[0].pem).unwrap();
+        assert_eq!(pems[0].tag, "PRIVATE KEY");
+        assert_eq!(pems[1].contents, b"leaf");
+    }
+
+    #[test]
+    fn ignores_unreadable_handovers() {
+        let path =
+            std::env::temp_dir().join(format!("tide-acme-handover-bad-{}", std::process::id()));
+        assert!(read_certs(&path).is_empty());
+        fs::write(&path, "not json").unwrap();
+        assert!(read_certs(&path).is_empty());
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,256 @@
+//! Minimal HTTPS client for talking to ACME servers.
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use async_std::future::timeout;
+use async_std::net::TcpStream;
+use http_types::other::RetryAfter;
+use http_types::{Method, Request, Response};
+use thiserror::Error;
+use tide_rustls::async_rustls::webpki::{DNSNameRef, InvalidDNSNameError};
+use tide_rustls::async_rustls::TlsConnector;
+use tide_rustls::rustls::ClientConfig;
+use webpki_roots::TLS_SERVER_ROOTS;
+
+/// How long a request may take, from connecting to reading the whole response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The TLS client configuration for requests to a server, trusting the web PKI roots unless
+/// replaced.
+#[derive(Clone, Default)]
+pub(crate) struct ClientTls(Option<Arc<ClientConfig>>);
+
+impl ClientTls {
+    pub(crate) fn new(config: ClientConfig) -> Self {
+        Self(Some(Arc::new(config)))
+    }
+
+    fn config(&self) -> Arc<ClientConfig> {
+        match &self.0 {
+            Some(config) => config.clone(),
+            None => {
+                let mut config = ClientConfig::default();
+                config
+                    .root_store
+                    .add_server_trust_anchors(&TLS_SERVER_ROOTS);
+                Arc::new(config)
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ClientTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(_) => f.write_str("ClientTls(custom)"),
+            None => f.write_str("ClientTls(web PKI)"),
+        }
+    }
+}
+
+pub(crate) async fn https(
+    tls: &ClientTls,
+    url: impl AsRef<str>,
+    method: Method,
+    body: Option<String>,
+) -> Result<Response, HttpsRequestError> {
+    let mut request = Request::new(method, url.as_ref());
+    if let Some(body) = body {
+        request.set_body(body);
+        request.set_content_type("application/jose+json".parse()?);
+    }
+    send_with(tls, request).await
+}
+
+/// Send `request`, over TLS unless its URL is `http`, failing on a non-2xx status.
+pub(crate) async fn send(request: Request) -> Result<Response, HttpsRequestError> {
+    send_with(&ClientTls::default(), request).await
+}
+
+/// Send `request` as [`send`] does, with the TLS configuration `tls`.
+async fn send_with(tls: &ClientTls, request: Request) -> Result<Response, HttpsRequestError> {
+    send_within(tls, request, REQUEST_TIMEOUT).await
+}
+
+/// Send `request` as [`send_with`] does, failing with a timeout if the response isn't read in
+/// full within `limit`.
+async fn send_within(
+    tls: &ClientTls,
+    request: Request,
+    limit: Duration,
+) -> Result<Response, HttpsRequestError> {
+    // Boxed, as the future of the TLS exchange is too large to keep on the stack.
+    match timeout(limit, Box::pin(exchange(tls, request))).await {
+        Ok(result) => result,
+        Err(_) => Err(HttpsRequestError::Io(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "request timed out",
+        ))),
+    }
+}
+
+async fn exchange(tls: &ClientTls, request: Request) -> Result<Response, HttpsRequestError> {
+    let host = request.host().ok_or(HttpsRequestError::UndefinedHost)?;
+    let port = request.url().port_or_known_default().unwrap_or(443);
+    let tcp = TcpStream::connect((host, port)).await?;
+    // Plain HTTP is for OCSP responders, whose responses are signed instead.
+    let mut response = if request.url().scheme() == "http" {
+        async_h1::connect(tcp, request).await?
+    } else {
+        let domain = DNSNameRef::try_from_ascii_str(host)?;
+        let tls = TlsConnector::from(tls.config())
+            .connect(domain, tcp)
+            .await?;
+        async_h1::connect(tls, request).await?
+    };
+    let status = response.status();
+    if !status.is_success() {
+        return Err(HttpsRequestError::Non2xxStatus {
+            status_code: status.into(),
+            retry_after: retry_after(&response),
+            body: response.body_string().await?,
+        });
+    }
+    // Read the body before returning, so that the timeout covers it too.
+    let body = response.body_bytes().await?;
+    response.set_body(body);
+    Ok(response)
+}
+
+/// When the server sending `response` asked to be retried or polled again, if it sent a
+/// `Retry-After` header.
+pub(crate) fn retry_after(response: &Response) -> Option<SystemTime> {
+    let retry_after = RetryAfter::from_headers(response).ok().flatten();
+    retry_after.map(SystemTime::from)
+}
+
+/// Errors from HTTPS requests to the ACME server.
+#[derive(Error, Debug)]
+pub enum HttpsRequestError {
+    /// An I/O error occurred.
+    #[error("io error: {0:?}")]
+    Io(#[from] std::io::Error),
+    /// The host in the URL was not a valid DNS name.
+    #[error("invalid dns name: {0:?}")]
+    InvalidDNSName(#[from] InvalidDNSNameError),
+    /// The HTTP exchange failed.
+    #[error("http error: {0:?}")]
+    Http(Box<dyn std::error::Error + Send + Sync + 'static>),
+    /// The server responded with a non-2xx status.
+    #[error("non 2xx http status: {status_code} {body:?}")]
+    Non2xxStatus {
+        /// The HTTP status code.
+        status_code: u16,
+        /// The response body.
+        body: String,
+        /// When the server asked to be retried, if it sent a `Retry-After` header.
+        retry_after: Option<SystemTime>,
+    },
+    /// The URL did not include a host.
+    #[error("could not determine host from url")]
+    UndefinedHost,
+}
+
+impl From<http_types::Error> for HttpsRequestError {
+    fn from(e: http_types::Error) -> Self {
+        Self::Http(e.into_inner().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::net::TcpListener;
+    use async_std::task::{self, block_on};
+    use futures_lite::io::{AsyncReadExt, AsyncWriteExt};
+    use http_types::Url;
+    use tide_rustls::async_rustls::TlsAcceptor;
+    use tide_rustls::rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig};
+
+    use super::*;
+
+    /// Answer one HTTPS request on a local port with a `localhost` certificate, returning the URL
+    /// to request and the certificate.
+    fn serve_once() -> (String, Vec<u8>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config
+            .set_single_cert(
+                vec![Certificate(der.clone())],
+                PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        task::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut tls = match acceptor.accept(stream).await {
+                    Ok(tls) => tls,
+                    Err(_) => continue,
+                };
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = tls.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let response = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok";
+                tls.write_all(response).await.unwrap();
+                tls.flush().await.unwrap();
+            }
+        });
+        (format!("https://localhost:{}/dir", port), der)
+    }
+
+    fn get(url: &str) -> Request {
+        Request::new(Method::Get, Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn uses_the_configured_tls_client() {
+        let (url, der) = serve_once();
+        // The web PKI roots don't include the test certificate.
+        assert!(block_on(send(get(&url))).is_err());
+
+        let mut config = ClientConfig::new();
+        config.root_store.add(&Certificate(der)).unwrap();
+        let tls = ClientTls::new(config);
+        let mut response = block_on(send_with(&tls, get(&url))).unwrap();
+        assert_eq!(block_on(response.body_string()).unwrap(), "ok");
+    }
+
+    #[test]
+    fn times_out_stalled_responses() {
+        let listener = block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        // Accept the connection but never answer.
+        let server = task::spawn(async move { listener.accept().await });
+        let limit = Duration::from_millis(100);
+        let result = block_on(send_within(&ClientTls::default(), get(&url), limit));
+        match result {
+            Err(HttpsRequestError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+        drop(block_on(server));
+    }
+
+    #[test]
+    fn reads_retry_after() {
+        let mut response = Response::new(200);
+        assert_eq!(retry_after(&response), None);
+        response.insert_header("Retry-After", "Wed, 21 Oct 2015 07:28:00 GMT");
+        let at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_445_412_480);
+        assert_eq!(retry_after(&response), Some(at));
+    }
+
+    #[test]
+    fn describes_the_tls_client() {
+        assert_eq!(format!("{:?}", ClientTls::default()), "ClientTls(web PKI)");
+        let tls = ClientTls::new(ClientConfig::new());
+        assert_eq!(format!("{:?}", tls), "ClientTls(custom)");
+    }
+}
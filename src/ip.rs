@@ -0,0 +1,117 @@
+//! Certificates for IP addresses (RFC 8738), which are named like domains but validated and
+//! matched differently.
+
+use std::net::IpAddr;
+
+use rcgen::SanType;
+
+/// The IP address `name` stands for, if it is one rather than a domain.
+pub(crate) fn parse(name: &str) -> Option<IpAddr> {
+    let ip: IpAddr = name.parse().ok()?;
+    Some(canonical(ip))
+}
+
+/// `ip`, as an IPv4 address if it is one mapped into IPv6, as dual-stack sockets report them.
+pub(crate) fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, ..] => v6.to_ipv4().map_or(ip, IpAddr::V4),
+            _ => ip,
+        },
+        ip => ip,
+    }
+}
+
+/// The IP address of the octets of an `iPAddress` subject alternative name.
+pub(crate) fn from_octets(octets: &[u8]) -> Option<IpAddr> {
+    match *octets {
+        [a, b, c, d] => Some(IpAddr::from([a, b, c, d])),
+        _ => {
+            let mut v6 = [0; 16];
+            (octets.len() == 16).then(|| {
+                v6.copy_from_slice(octets);
+                canonical(IpAddr::from(v6))
+            })
+        }
+    }
+}
+
+/// The IP address of the reverse DNS name `name`, such as `4.3.2.1.in-addr.arpa`, which
+/// tls-alpn-01 validation requests for an IP address send as their server name.
+pub(crate) fn from_reverse_name(name: &str) -> Option<IpAddr> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    if let Some(labels) = name.strip_suffix(".in-addr.arpa") {
+        let octets: Vec<&str> = labels.rsplit('.').collect();
+        return match octets.len() {
+            4 => octets.join(".").parse().ok(),
+            _ => None,
+        };
+    }
+    let nibbles: Vec<&str> = name.strip_suffix(".ip6.arpa")?.rsplit('.').collect();
+    if nibbles.len() != 32 || nibbles.iter().any(|nibble| nibble.len() != 1) {
+        return None;
+    }
+    let groups: Vec<String> = nibbles.chunks(4).map(|group| group.concat()).collect();
+    groups.join(":").parse().ok()
+}
+
+/// The subject alternative names of a certificate for `names`: IP addresses as such, and the
+/// rest as DNS names.
+pub(crate) fn subject_alt_names(names: &[String]) -> Vec<SanType> {
+    names
+        .iter()
+        .map(|name| match parse(name) {
+            Some(ip) => SanType::IpAddress(ip),
+            None => SanType::DnsName(name.clone()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ip_addresses() {
+        assert_eq!(parse("192.0.2.1"), Some(IpAddr::from([192, 0, 2, 1])));
+        assert_eq!(
+            parse("::ffff:192.0.2.1"),
+            Some(IpAddr::from([192, 0, 2, 1]))
+        );
+        assert_eq!(parse("2001:db8::1"), "2001:db8::1".parse().ok());
+        assert_eq!(parse("domain.example"), None);
+        assert_eq!(parse("192.0.2.256"), None);
+    }
+
+    #[test]
+    fn reads_san_octets() {
+        assert_eq!(from_octets(&[192, 0, 2, 1]), parse("192.0.2.1"));
+        let mapped = "::ffff:192.0.2.1".parse::<std::net::Ipv6Addr>().unwrap();
+        assert_eq!(from_octets(&mapped.octets()), parse("192.0.2.1"));
+        assert_eq!(from_octets(&[192, 0, 2]), None);
+    }
+
+    #[test]
+    fn reads_reverse_names() {
+        assert_eq!(
+            from_reverse_name("1.2.0.192.in-addr.arpa."),
+            parse("192.0.2.1")
+        );
+        assert_eq!(
+            from_reverse_name(
+                "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.IP6.ARPA"
+            ),
+            parse("2001:db8::1")
+        );
+        assert_eq!(from_reverse_name("2.0.192.in-addr.arpa"), None);
+        assert_eq!(from_reverse_name("1.0.8.b.d.0.1.0.0.2.ip6.arpa"), None);
+        assert_eq!(from_reverse_name("domain.example"), None);
+    }
+
+    #[test]
+    fn names_ip_addresses_as_such() {
+        let names = subject_alt_names(&["domain.example".into(), "192.0.2.1".into()]);
+        assert!(matches!(&names[0], SanType::DnsName(name) if name == "domain.example"));
+        assert!(matches!(names[1], SanType::IpAddress(ip) if ip == IpAddr::from([192, 0, 2, 1])));
+    }
+}
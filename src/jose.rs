@@ -0,0 +1,428 @@
+//! JSON Web Signatures for ACME requests, with a configurable account key algorithm.
+
+use base64::URL_SAFE_NO_PAD;
+use ring::digest::{digest, SHA256};
+use ring::hmac;
+use ring::rand::SystemRandom;
+use ring::signature::{
+    EcdsaKeyPair, EcdsaSigningAlgorithm, Ed25519KeyPair, KeyPair, RsaKeyPair,
+    ECDSA_P256_SHA256_FIXED_SIGNING, ECDSA_P384_SHA384_FIXED_SIGNING, RSA_PKCS1_SHA256,
+};
+use serde::Serialize;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// Signature algorithm for the ACME account key.
+///
+/// The account key is long-lived: it identifies your ACME account for as long as the account
+/// cache keeps it. It is independent of the keys of the certificates issued to the account.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum AccountKeyAlgorithm {
+    /// ECDSA using the P-256 curve and SHA-256 (`ES256`). This is the default.
+    #[default]
+    EcdsaP256,
+    /// ECDSA using the P-384 curve and SHA-384 (`ES384`).
+    EcdsaP384,
+    /// Ed25519 (`EdDSA`). Not every CA supports this; Let's Encrypt currently does not.
+    Ed25519,
+    /// RSA with SHA-256 (`RS256`), as other clients such as certbot use, for keys imported from
+    /// them with [`AccountKeyPair`](crate::AccountKeyPair). RSA keys are not generated: without
+    /// an imported key in the account cache, orders fail.
+    Rsa,
+}
+
+impl AccountKeyAlgorithm {
+    fn ecdsa(self) -> Option<&'static EcdsaSigningAlgorithm> {
+        match self {
+            Self::EcdsaP256 => Some(&ECDSA_P256_SHA256_FIXED_SIGNING),
+            Self::EcdsaP384 => Some(&ECDSA_P384_SHA384_FIXED_SIGNING),
+            Self::Ed25519 | Self::Rsa => None,
+        }
+    }
+}
+
+/// An ACME account key pair.
+pub(crate) enum AccountKey {
+    Ecdsa(AccountKeyAlgorithm, EcdsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+    Rsa(RsaKeyPair),
+}
+
+impl AccountKey {
+    /// Generate a new key pair, returned in PKCS#8 form for caching.
+    pub(crate) fn generate_pkcs8(
+        algorithm: AccountKeyAlgorithm,
+    ) -> Result<Zeroizing<Vec<u8>>, JoseError> {
+        let rng = SystemRandom::new();
+        let pkcs8 = match (algorithm.ecdsa(), algorithm) {
+            (Some(alg), _) => EcdsaKeyPair::generate_pkcs8(alg, &rng)?,
+            (None, AccountKeyAlgorithm::Rsa) => return Err(JoseError::RsaGeneration),
+            (None, _) => Ed25519KeyPair::generate_pkcs8(&rng)?,
+        };
+        Ok(pkcs8.as_ref().to_vec().into())
+    }
+
+    /// Load a PKCS#8 key pair, which must use the specified algorithm.
+    pub(crate) fn from_pkcs8(
+        algorithm: AccountKeyAlgorithm,
+        pkcs8: &[u8],
+    ) -> Result<Self, JoseError> {
+        Ok(match (algorithm.ecdsa(), algorithm) {
+            (Some(alg), _) => Self::Ecdsa(algorithm, EcdsaKeyPair::from_pkcs8(alg, pkcs8)?),
+            (None, AccountKeyAlgorithm::Rsa) => Self::Rsa(RsaKeyPair::from_pkcs8(pkcs8)?),
+            (None, _) => Self::Ed25519(Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8)?),
+        })
+    }
+
+    fn alg(&self) -> &'static str {
+        match self {
+            Self::Ecdsa(AccountKeyAlgorithm::EcdsaP384, _) => "ES384",
+            Self::Ecdsa(..) => "ES256",
+            Self::Ed25519(_) => "EdDSA",
+            Self::Rsa(_) => "RS256",
+        }
+    }
+
+    fn jwk(&self) -> Jwk {
+        match self {
+            Self::Ecdsa(algorithm, key) => {
+                let (crv, len) = match algorithm {
+                    AccountKeyAlgorithm::EcdsaP384 => ("P-384", 48),
+                    _ => ("P-256", 32),
+                };
+                let (x, y) = key.public_key().as_ref()[1..].split_at(len);
+                Jwk::Curve {
+                    crv,
+                    kty: "EC",
+                    x: base64::encode_config(x, URL_SAFE_NO_PAD),
+                    y: Some(base64::encode_config(y, URL_SAFE_NO_PAD)),
+                }
+            }
+            Self::Ed25519(key) => Jwk::Curve {
+                crv: "Ed25519",
+                kty: "OKP",
+                x: base64::encode_config(key.public_key(), URL_SAFE_NO_PAD),
+                y: None,
+            },
+            Self::Rsa(key) => {
+                let public_key = key.public_key();
+                Jwk::Rsa {
+                    e: base64::encode_config(
+                        public_key.exponent().big_endian_without_leading_zero(),
+                        URL_SAFE_NO_PAD,
+                    ),
+                    kty: "RSA",
+                    n: base64::encode_config(
+                        public_key.modulus().big_endian_without_leading_zero(),
+                        URL_SAFE_NO_PAD,
+                    ),
+                }
+            }
+        }
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, JoseError> {
+        Ok(match self {
+            Self::Ecdsa(_, key) => key.sign(&SystemRandom::new(), message)?.as_ref().to_vec(),
+            Self::Ed25519(key) => key.sign(message).as_ref().to_vec(),
+            Self::Rsa(key) => {
+                let mut signature = vec![0; key.public_modulus_len()];
+                key.sign(
+                    &RSA_PKCS1_SHA256,
+                    &SystemRandom::new(),
+                    message,
+                    &mut signature,
+                )?;
+                signature
+            }
+        })
+    }
+
+    /// Compute the key authorization for a challenge token.
+    pub(crate) fn key_authorization(&self, token: &str) -> Result<String, JoseError> {
+        Ok(format!("{}.{}", token, self.jwk().thumb_sha256_base64()?))
+    }
+}
+
+/// Sign an ACME request body. Requests before account creation identify the key by `jwk`;
+/// requests after identify the account by `kid`.
+pub(crate) fn sign(
+    key: &AccountKey,
+    kid: Option<&str>,
+    nonce: String,
+    url: &str,
+    payload: &str,
+) -> Result<String, JoseError> {
+    let jwk = match kid {
+        None => Some(key.jwk()),
+        Some(_) => None,
+    };
+    let protected = Protected {
+        alg: key.alg(),
+        jwk,
+        kid,
+        nonce,
+        url,
+    };
+    let protected = base64::encode_config(serde_json::to_vec(&protected)?, URL_SAFE_NO_PAD);
+    let payload = base64::encode_config(payload, URL_SAFE_NO_PAD);
+    let combined = format!("{}.{}", &protected, &payload);
+    let signature = base64::encode_config(key.sign(combined.as_bytes())?, URL_SAFE_NO_PAD);
+    let body = Body {
+        protected,
+        payload,
+        signature,
+    };
+    Ok(serde_json::to_string(&body)?)
+}
+
+/// A key binding new accounts to an account with the CA outside ACME, as CAs such as ZeroSSL and
+/// Google Trust Services require (RFC 8555 section 7.3.4).
+#[derive(Clone, Debug)]
+pub(crate) struct ExternalAccountKey {
+    pub(crate) kid: String,
+    /// The HMAC key, base64url-encoded as CAs issue it.
+    pub(crate) hmac_key: String,
+}
+
+/// Sign the external account binding of a new-account request to `url`: a JWS over the account's
+/// public key, MACed with the external account key.
+pub(crate) fn external_account_binding(
+    key: &AccountKey,
+    eab: &ExternalAccountKey,
+    url: &str,
+) -> Result<Body, JoseError> {
+    let hmac_key = base64::decode_config(eab.hmac_key.trim_end_matches('='), URL_SAFE_NO_PAD)?;
+    let protected = EabProtected {
+        alg: "HS256",
+        kid: &eab.kid,
+        url,
+    };
+    let protected = base64::encode_config(serde_json::to_vec(&protected)?, URL_SAFE_NO_PAD);
+    let payload = base64::encode_config(serde_json::to_vec(&key.jwk())?, URL_SAFE_NO_PAD);
+    let combined = format!("{}.{}", &protected, &payload);
+    let tag = hmac::sign(
+        &hmac::Key::new(hmac::HMAC_SHA256, &hmac_key),
+        combined.as_bytes(),
+    );
+    Ok(Body {
+        protected,
+        payload,
+        signature: base64::encode_config(tag.as_ref(), URL_SAFE_NO_PAD),
+    })
+}
+
+#[derive(Serialize)]
+pub(crate) struct Body {
+    protected: String,
+    payload: String,
+    signature: String,
+}
+
+#[derive(Serialize)]
+struct Protected<'a> {
+    alg: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jwk: Option<Jwk>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kid: Option<&'a str>,
+    nonce: String,
+    url: &'a str,
+}
+
+#[derive(Serialize)]
+struct EabProtected<'a> {
+    alg: &'static str,
+    kid: &'a str,
+    url: &'a str,
+}
+
+/// A public JSON Web Key. Fields are in lexicographic order and limited to the required members,
+/// so the serialization doubles as the RFC 7638 thumbprint input.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Jwk {
+    Curve {
+        crv: &'static str,
+        kty: &'static str,
+        x: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        y: Option<String>,
+    },
+    Rsa {
+        e: String,
+        kty: &'static str,
+        n: String,
+    },
+}
+
+impl Jwk {
+    fn thumb_sha256_base64(&self) -> Result<String, JoseError> {
+        let hash = digest(&SHA256, &serde_json::to_vec(self)?);
+        Ok(base64::encode_config(hash, URL_SAFE_NO_PAD))
+    }
+}
+
+/// Errors from signing ACME requests.
+#[derive(Error, Debug)]
+pub enum JoseError {
+    /// JSON serialization failed.
+    #[error("json serialization failed: {0}")]
+    Json(#[from] serde_json::Error),
+    /// A cryptographic operation failed.
+    #[error("crypto error: {0}")]
+    Crypto(#[from] ring::error::Unspecified),
+    /// The account key was rejected, for instance because it uses a different algorithm.
+    #[error("invalid account key: {0}")]
+    KeyRejected(#[from] ring::error::KeyRejected),
+    /// The external account binding HMAC key is not valid base64url.
+    #[error("invalid external account binding key: {0}")]
+    ExternalAccountKey(#[from] base64::DecodeError),
+    /// An RSA account key was to be generated; RSA keys must be imported instead.
+    #[error("RSA account keys are not generated; import one with AccountKeyPair")]
+    RsaGeneration,
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::signature::{UnparsedPublicKey, VerificationAlgorithm};
+    use serde_json::Value;
+
+    use super::*;
+
+    fn decode(value: &Value) -> Vec<u8> {
+        base64::decode_config(value.as_str().unwrap(), URL_SAFE_NO_PAD).unwrap()
+    }
+
+    #[test]
+    fn signs_requests() {
+        let algorithms: [(_, &dyn VerificationAlgorithm); 3] = [
+            (
+                AccountKeyAlgorithm::EcdsaP256,
+                &ring::signature::ECDSA_P256_SHA256_FIXED,
+            ),
+            (
+                AccountKeyAlgorithm::EcdsaP384,
+                &ring::signature::ECDSA_P384_SHA384_FIXED,
+            ),
+            (AccountKeyAlgorithm::Ed25519, &ring::signature::ED25519),
+        ];
+        for (algorithm, verification) in algorithms {
+            let pkcs8 = AccountKey::generate_pkcs8(algorithm).unwrap();
+            let key = AccountKey::from_pkcs8(algorithm, &pkcs8).unwrap();
+            let url = "https://ca.example/new-account";
+            let body = sign(&key, None, "nonce".into(), url, "{}").unwrap();
+            let body: Value = serde_json::from_str(&body).unwrap();
+            let protected: Value = serde_json::from_slice(&decode(&body["protected"])).unwrap();
+            assert_eq!(protected["alg"], key.alg());
+            assert_eq!(protected["nonce"], "nonce");
+            assert_eq!(protected["url"], url);
+            assert!(protected.get("kid").is_none());
+            assert_eq!(decode(&body["payload"]), b"{}");
+
+            let public_key = match &key {
+                AccountKey::Ecdsa(_, key) => key.public_key().as_ref().to_vec(),
+                AccountKey::Ed25519(key) => key.public_key().as_ref().to_vec(),
+                AccountKey::Rsa(_) => unreachable!(),
+            };
+            let message = format!(
+                "{}.{}",
+                body["protected"].as_str().unwrap(),
+                body["payload"].as_str().unwrap()
+            );
+            UnparsedPublicKey::new(verification, public_key)
+                .verify(message.as_bytes(), &decode(&body["signature"]))
+                .unwrap();
+
+            let body = sign(&key, Some("https://ca.example/acct/1"), "n".into(), url, "").unwrap();
+            let body: Value = serde_json::from_str(&body).unwrap();
+            let protected: Value = serde_json::from_slice(&decode(&body["protected"])).unwrap();
+            assert_eq!(protected["kid"], "https://ca.example/acct/1");
+            assert!(protected.get("jwk").is_none());
+        }
+    }
+
+    #[test]
+    fn computes_key_authorizations() {
+        // The Ed25519 key and JWK thumbprint of RFC 8037, appendix A.
+        let seed = base64::decode_config(
+            "nWGxne_9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2A",
+            URL_SAFE_NO_PAD,
+        )
+        .unwrap();
+        let key = AccountKey::Ed25519(Ed25519KeyPair::from_seed_unchecked(&seed).unwrap());
+        assert_eq!(
+            key.key_authorization("token").unwrap(),
+            "token.kPrK_qmxVWaYVA9wwBF6Iuo3vVzz7TxHCTwXBygrS4k"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_keys() {
+        let pkcs8 = AccountKey::generate_pkcs8(AccountKeyAlgorithm::EcdsaP256).unwrap();
+        for len in [0, 1, pkcs8.len() / 2, pkcs8.len() - 1] {
+            assert!(matches!(
+                AccountKey::from_pkcs8(AccountKeyAlgorithm::EcdsaP256, &pkcs8[..len]),
+                Err(JoseError::KeyRejected(_))
+            ));
+        }
+        for algorithm in [
+            AccountKeyAlgorithm::EcdsaP384,
+            AccountKeyAlgorithm::Ed25519,
+            AccountKeyAlgorithm::Rsa,
+        ] {
+            assert!(matches!(
+                AccountKey::from_pkcs8(algorithm, &pkcs8),
+                Err(JoseError::KeyRejected(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn generates_no_rsa_keys() {
+        assert!(matches!(
+            AccountKey::generate_pkcs8(AccountKeyAlgorithm::Rsa),
+            Err(JoseError::RsaGeneration)
+        ));
+    }
+
+    #[test]
+    fn binds_external_accounts() {
+        let pkcs8 = AccountKey::generate_pkcs8(AccountKeyAlgorithm::EcdsaP256).unwrap();
+        let key = AccountKey::from_pkcs8(AccountKeyAlgorithm::EcdsaP256, &pkcs8).unwrap();
+        let eab = ExternalAccountKey {
+            kid: "kid-1".into(),
+            hmac_key: "c2VjcmV0LWtleQ==".into(),
+        };
+        let url = "https://ca.example/new-account";
+        let body = external_account_binding(&key, &eab, url).unwrap();
+        let protected: Value = serde_json::from_slice(
+            &base64::decode_config(&body.protected, URL_SAFE_NO_PAD).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(protected["alg"], "HS256");
+        assert_eq!(protected["kid"], "kid-1");
+        assert_eq!(protected["url"], url);
+        let jwk: Value =
+            serde_json::from_slice(&base64::decode_config(&body.payload, URL_SAFE_NO_PAD).unwrap())
+                .unwrap();
+        assert_eq!(jwk["kty"], "EC");
+        let tag = base64::decode_config(&body.signature, URL_SAFE_NO_PAD).unwrap();
+        let message = format!("{}.{}", body.protected, body.payload);
+        hmac::verify(
+            &hmac::Key::new(hmac::HMAC_SHA256, b"secret-key"),
+            message.as_bytes(),
+            &tag,
+        )
+        .unwrap();
+
+        let eab = ExternalAccountKey {
+            kid: "kid-1".into(),
+            hmac_key: "not base64!".into(),
+        };
+        assert!(matches!(
+            external_account_binding(&key, &eab, url),
+            Err(JoseError::ExternalAccountKey(_))
+        ));
+    }
+}
@@ -0,0 +1,34 @@
+use async_trait::async_trait;
+
+/// An error from a [`KeyProvider`].
+pub type KeyProviderError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Hooks around generating the private keys of new certificates, so an external secrets system,
+/// such as Vault Transit or SPIFFE/SPIRE, can supply the keys or escrow them.
+///
+/// Set with [`AcmeConfig::key_provider`](crate::AcmeConfig::key_provider). Both methods default to
+/// doing nothing, so a provider implements only the hook it needs. An error fails the order, which
+/// is retried with backoff like other failures.
+#[async_trait]
+pub trait KeyProvider: Send + Sync + 'static {
+    /// Supply the private key for a new certificate covering `domains`, as PKCS#8 DER, before
+    /// the certificate is ordered. ECDSA P-256 and P-384, Ed25519 and RSA keys are supported.
+    ///
+    /// Returning `None` generates a key of the configured
+    /// [`CertKeyAlgorithm`](crate::CertKeyAlgorithm), as without a provider.
+    async fn supply_key(&self, _domains: &[String]) -> Result<Option<Vec<u8>>, KeyProviderError> {
+        Ok(None)
+    }
+
+    /// Receive the private key for a new certificate covering `domains`, as PKCS#8 DER, after it
+    /// is generated or supplied and before the certificate signing request is sent, to escrow it.
+    ///
+    /// An error stops the order, so no certificate is issued for a key that wasn't escrowed.
+    async fn key_generated(
+        &self,
+        _domains: &[String],
+        _key: &[u8],
+    ) -> Result<(), KeyProviderError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use async_trait::async_trait;
+use rcgen::{RcgenError, RemoteKeyPair, SignatureAlgorithm};
+use tide_rustls::rustls::internal::msgs::enums::SignatureAlgorithm as TlsSignatureAlgorithm;
+use tide_rustls::rustls::sign::{Signer, SigningKey};
+use tide_rustls::rustls::{SignatureScheme, TLSError};
+use x509_parser::parse_x509_certificate;
+
+use crate::format;
+
+/// An error from a [`KeyStore`] or [`RemoteKey`].
+pub type KeyStoreError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The algorithm of a [`RemoteKey`].
+///
+/// RSA keys aren't supported, as TLS 1.3 handshakes need RSA-PSS signatures, which certificate
+/// signing requests don't use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RemoteKeyAlgorithm {
+    /// ECDSA with the P-256 curve and SHA-256.
+    EcdsaP256Sha256,
+    /// ECDSA with the P-384 curve and SHA-384.
+    EcdsaP384Sha384,
+    /// Ed25519.
+    Ed25519,
+}
+
+impl RemoteKeyAlgorithm {
+    fn rcgen(self) -> &'static SignatureAlgorithm {
+        match self {
+            RemoteKeyAlgorithm::EcdsaP256Sha256 => &rcgen::PKCS_ECDSA_P256_SHA256,
+            RemoteKeyAlgorithm::EcdsaP384Sha384 => &rcgen::PKCS_ECDSA_P384_SHA384,
+            RemoteKeyAlgorithm::Ed25519 => &rcgen::PKCS_ED25519,
+        }
+    }
+
+    fn scheme(self) -> SignatureScheme {
+        match self {
+            RemoteKeyAlgorithm::EcdsaP256Sha256 => SignatureScheme::ECDSA_NISTP256_SHA256,
+            RemoteKeyAlgorithm::EcdsaP384Sha384 => SignatureScheme::ECDSA_NISTP384_SHA384,
+            RemoteKeyAlgorithm::Ed25519 => SignatureScheme::ED25519,
+        }
+    }
+}
+
+/// A private key held by a [`KeyStore`], which signs without ever handing the key out.
+///
+/// It signs the certificate signing request when ordering, and then every full TLS handshake
+/// served with the certificate. rustls signs synchronously, so [`sign`](Self::sign) runs on the
+/// executor thread driving the handshake and blocks it until it returns.
+///
+/// Implementations must therefore not do network I/O in `sign`: a round trip to a cloud KMS
+/// would stall every connection sharing the thread. Sign with a local device, such as a PKCS#11
+/// HSM on the host, or through a local agent holding a session with the KMS. [Session
+/// resumption](crate::AcmeConfig::session_resumption) skips signing for resumed handshakes.
+pub trait RemoteKey: Send + Sync + 'static {
+    /// The algorithm of the key.
+    fn algorithm(&self) -> RemoteKeyAlgorithm;
+
+    /// The public key, as the contents of the subject public key bit string of a certificate: the
+    /// uncompressed point for ECDSA, and the 32-byte key for Ed25519.
+    fn public_key(&self) -> &[u8];
+
+    /// Sign `message` with the key's algorithm, hashing it first for ECDSA. ECDSA signatures are
+    /// ASN.1 DER-encoded.
+    ///
+    /// This blocks the handshake's executor thread, so it must return quickly and not wait on
+    /// the network.
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, KeyStoreError>;
+}
+
+/// Where the private keys of certificates live when they must not be held in the process, such
+/// as a PKCS#11 HSM or a KMS behind a local signing agent.
+///
+/// Creating and loading keys is async and may go over the network, but signing with a
+/// [`RemoteKey`] blocks the handshake and must not.
+///
+/// Set with [`AcmeConfig::key_store`](crate::AcmeConfig::key_store). Certificates are then kept
+/// in the cache without their keys, and their keys are looked up by public key when loaded.
+/// Methods exporting private keys, such as
+/// [`AcmeHandle::private_key_pem`](crate::AcmeHandle::private_key_pem), return nothing for them.
+#[async_trait]
+pub trait KeyStore: Send + Sync + 'static {
+    /// Create the key for a new certificate covering `domains`, before it is ordered.
+    ///
+    /// An error fails the order, which is retried with backoff like other failures.
+    async fn create_key(&self, domains: &[String]) -> Result<Arc<dyn RemoteKey>, KeyStoreError>;
+
+    /// Look up the key whose [public key](RemoteKey::public_key) is `public_key`, for a
+    /// certificate loaded from the cache or an order resumed after a restart. Returns `None` if
+    /// the key is gone, such as after being destroyed, and a new certificate is ordered.
+    async fn load_key(
+        &self,
+        public_key: &[u8],
+    ) -> Result<Option<Arc<dyn RemoteKey>>, KeyStoreError>;
+}
+
+/// The keys of a [`KeyStore`] in use, by public key, so certificates kept without their keys can
+/// be parsed.
+#[derive(Default)]
+pub(crate) struct RemoteKeys {
+    keys: Mutex<HashMap<Vec<u8>, Arc<dyn RemoteKey>>>,
+}
+
+impl RemoteKeys {
+    pub(crate) fn get(&self, public_key: &[u8]) -> Option<Arc<dyn RemoteKey>> {
+        let keys = self.keys.lock().unwrap_or_else(PoisonError::into_inner);
+        keys.get(public_key).cloned()
+    }
+
+    pub(crate) fn insert(&self, key: Arc<dyn RemoteKey>) {
+        let public_key = key.public_key().to_vec();
+        let mut keys = self.keys.lock().unwrap_or_else(PoisonError::into_inner);
+        keys.insert(public_key, key);
+    }
+}
+
+/// The public key of the leaf certificate of `pem`, if it is kept without its private key.
+pub(crate) fn keyless_public_key(pem: &[u8]) -> Option<Vec<u8>> {
+    let pems = format::parse_pems(pem).ok()?;
+    let leaf = pems.first().filter(|leaf| leaf.tag == "CERTIFICATE")?;
+    let (_, cert) = parse_x509_certificate(&leaf.contents).ok()?;
+    Some(cert.public_key().subject_public_key.data.to_vec())
+}
+
+/// The PEM tag of the public key of a key store key, raw as [`RemoteKey::public_key`] returns
+/// it, rather than the `PUBLIC KEY` of a SubjectPublicKeyInfo.
+pub(crate) const PUBLIC_KEY_TAG: &str = "KEY STORE PUBLIC KEY";
+
+/// The public key of `key` as PEM, to keep with an order in progress in place of a private key.
+pub(crate) fn public_key_pem(key: &dyn RemoteKey) -> String {
+    pem::encode(&pem::Pem {
+        tag: PUBLIC_KEY_TAG.into(),
+        contents: key.public_key().to_vec(),
+    })
+}
+
+/// An rcgen key pair signing with `key`, for certificate signing requests.
+pub(crate) fn rcgen_key_pair(key: Arc<dyn RemoteKey>) -> Result<rcgen::KeyPair, RcgenError> {
+    rcgen::KeyPair::from_remote(Box::new(RcgenKey(key)))
+}
+
+struct RcgenKey(Arc<dyn RemoteKey>);
+
+impl RemoteKeyPair for RcgenKey {
+    fn public_key(&self) -> &[u8] {
+        self.0.public_key()
+    }
+
+    fn sign(&self, msg: &[u8]) -> Result<Vec<u8>, RcgenError> {
+        self.0.sign(msg).map_err(|_| RcgenError::RemoteKeyError)
+    }
+
+    fn algorithm(&self) -> &'static SignatureAlgorithm {
+        self.0.algorithm().rcgen()
+    }
+}
+
+/// A rustls signing key signing handshakes with `key`.
+pub(crate) fn signing_key(key: Arc<dyn RemoteKey>) -> Box<dyn SigningKey> {
+    Box::new(TlsKey(key))
+}
+
+struct TlsKey(Arc<dyn RemoteKey>);
+
+impl SigningKey for TlsKey {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+        let scheme = self.0.algorithm().scheme();
+        if !offered.contains(&scheme) {
+            return None;
+        }
+        Some(Box::new(TlsSigner {
+            key: self.0.clone(),
+            scheme,
+        }))
+    }
+
+    fn algorithm(&self) -> TlsSignatureAlgorithm {
+        match self.0.algorithm() {
+            RemoteKeyAlgorithm::EcdsaP256Sha256 | RemoteKeyAlgorithm::EcdsaP384Sha384 => {
+                TlsSignatureAlgorithm::ECDSA
+            }
+            RemoteKeyAlgorithm::Ed25519 => TlsSignatureAlgorithm::ED25519,
+        }
+    }
+}
+
+struct TlsSigner {
+    key: Arc<dyn RemoteKey>,
+    scheme: SignatureScheme,
+}
+
+impl Signer for TlsSigner {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, TLSError> {
+        self.key
+            .sign(message)
+            .map_err(|err| TLSError::General(format!("key store signing failed: {}", err)))
+    }
+
+    fn get_scheme(&self) -> SignatureScheme {
+        self.scheme
+    }
+}
+
+/// A key store holding ECDSA P-256 keys in memory.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct MemoryKeyStore {
+    pub(crate) keys: Mutex<Vec<Arc<dyn RemoteKey>>>,
+}
+
+#[cfg(test)]
+struct MemoryKey {
+    key_pair: ring::signature::EcdsaKeyPair,
+}
+
+#[cfg(test)]
+impl RemoteKey for MemoryKey {
+    fn algorithm(&self) -> RemoteKeyAlgorithm {
+        RemoteKeyAlgorithm::EcdsaP256Sha256
+    }
+
+    fn public_key(&self) -> &[u8] {
+        use ring::signature::KeyPair;
+        self.key_pair.public_key().as_ref()
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, KeyStoreError> {
+        let rng = ring::rand::SystemRandom::new();
+        let signature = self
+            .key_pair
+            .sign(&rng, message)
+            .map_err(|_| "signing failed")?;
+        Ok(signature.as_ref().to_vec())
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl KeyStore for MemoryKeyStore {
+    async fn create_key(&self, _domains: &[String]) -> Result<Arc<dyn RemoteKey>, KeyStoreError> {
+        use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+            .map_err(|_| "key generation failed")?;
+        let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref())
+            .map_err(|_| "key generation failed")?;
+        let key: Arc<dyn RemoteKey> = Arc::new(MemoryKey { key_pair });
+        self.keys.lock().unwrap().push(key.clone());
+        Ok(key)
+    }
+
+    async fn load_key(
+        &self,
+        public_key: &[u8],
+    ) -> Result<Option<Arc<dyn RemoteKey>>, KeyStoreError> {
+        let keys = self.keys.lock().unwrap();
+        Ok(keys
+            .iter()
+            .find(|key| key.public_key() == public_key)
+            .cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::block_on;
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+
+    use super::*;
+
+    #[test]
+    fn signs_requests_and_handshakes() {
+        let key = block_on(MemoryKeyStore::default().create_key(&[])).unwrap();
+        let mut params = rcgen::CertificateParams::new(vec!["a.example".into()]);
+        params.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+        params.key_pair = Some(rcgen_key_pair(key.clone()).unwrap());
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let der = cert.serialize_der().unwrap();
+        let (_, parsed) = parse_x509_certificate(&der).unwrap();
+        let public_key = parsed.public_key().subject_public_key.data;
+        assert_eq!(public_key, key.public_key());
+        let signature = parsed.signature_value.data;
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, public_key)
+            .verify(parsed.tbs_certificate.as_ref(), signature)
+            .unwrap();
+
+        let signing_key = signing_key(key.clone());
+        assert_eq!(signing_key.algorithm(), TlsSignatureAlgorithm::ECDSA);
+        assert!(signing_key
+            .choose_scheme(&[SignatureScheme::ED25519])
+            .is_none());
+        let signer = signing_key
+            .choose_scheme(&[
+                SignatureScheme::ED25519,
+                SignatureScheme::ECDSA_NISTP256_SHA256,
+            ])
+            .unwrap();
+        assert_eq!(signer.get_scheme(), SignatureScheme::ECDSA_NISTP256_SHA256);
+        let signature = signer.sign(b"handshake").unwrap();
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, key.public_key())
+            .verify(b"handshake", &signature)
+            .unwrap();
+    }
+
+    #[test]
+    fn identifies_keys_by_public_key() {
+        let store = MemoryKeyStore::default();
+        let key = block_on(store.create_key(&[])).unwrap();
+        let mut params = rcgen::CertificateParams::new(vec!["a.example".into()]);
+        params.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+        params.key_pair = Some(rcgen_key_pair(key.clone()).unwrap());
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let keyless = cert.serialize_pem().unwrap();
+        assert_eq!(
+            keyless_public_key(keyless.as_bytes()).as_deref(),
+            Some(key.public_key())
+        );
+        let local = rcgen::generate_simple_self_signed(vec!["a.example".into()]).unwrap();
+        let with_key = local.serialize_private_key_pem() + &local.serialize_pem().unwrap();
+        assert!(keyless_public_key(with_key.as_bytes()).is_none());
+
+        let pem = pem::parse(public_key_pem(key.as_ref())).unwrap();
+        assert_eq!(pem.tag, PUBLIC_KEY_TAG);
+        let loaded = block_on(store.load_key(&pem.contents)).unwrap().unwrap();
+        assert!(Arc::ptr_eq(&loaded, &key));
+        assert!(block_on(store.load_key(b"other")).unwrap().is_none());
+
+        let keys = RemoteKeys::default();
+        assert!(keys.get(key.public_key()).is_none());
+        keys.insert(key.clone());
+        assert!(Arc::ptr_eq(&keys.get(key.public_key()).unwrap(), &key));
+    }
+
+    #[test]
+    fn survives_panics_while_looking_up_keys() {
+        let key = block_on(MemoryKeyStore::default().create_key(&[])).unwrap();
+        let keys = RemoteKeys::default();
+        let _ = std::panic::catch_unwind(|| {
+            let _keys = keys.keys.lock().unwrap();
+            panic!("poison the lock");
+        });
+        assert!(keys.keys.is_poisoned());
+        keys.insert(key.clone());
+        assert!(Arc::ptr_eq(&keys.get(key.public_key()).unwrap(), &key));
+    }
+}
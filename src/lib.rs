@@ -27,15 +27,35 @@
 //! This will configure the TLS stack to obtain a certificate for the domain `domain.example`,
 //! which must be a domain for which your Tide server handles HTTPS traffic.
 //!
+//! If the domain has an IPv6 address, Let's Encrypt may validate it over IPv6, so listen on both
+//! IPv4 and IPv6 with [`AcmeTlsAcceptor::dual_stack`], or on any set of addresses with
+//! [`AcmeTlsAcceptor::listeners`]:
+//!
+//! ```no_run
+//! # use tide_acme::{AcmeConfig, AcmeTlsAcceptor};
+//! # async_std::task::block_on(async {
+//! # let app = tide::new();
+//! let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(vec!["domain.example"]));
+//! app.listen(acceptor.dual_stack(443)?).await?;
+//! # tide::Result::Ok(())
+//! # });
+//! ```
+//!
 //! On initial startup, your server will register a certificate via Let's Encrypt. Let's Encrypt
 //! will verify your server's control of the domain via an [ACME tls-alpn-01
 //! challenge](https://tools.ietf.org/html/rfc8737), which the TLS listener configured by
-//! `tide-acme` will respond to.
+//! `tide-acme` will respond to. If TLS is terminated in front of your server, use
+//! [`AcmeConfig::http01`] and [`AcmeHttp01Middleware`] to answer http-01 challenges over plain
+//! HTTP instead. For wildcard domains, which must be validated via DNS, use
+//! [`AcmeConfig::dns_provider`].
 //!
 //! You must supply a cache via [`AcmeConfig::cache`] or one of the other cache methods. This cache
 //! will keep the ACME account key and registered certificates between runs, needed to avoid
 //! hitting rate limits. You can use [`rustls_acme::caches::DirCache`] for a simple filesystem
-//! cache, or implement your own caching using the `rustls_acme` cache traits.
+//! cache, [`PrivateDirCache`] for one that keeps its files private to the server, or implement
+//! your own caching using the `rustls_acme` cache traits. To check that issued certificates have
+//! valid Certificate Transparency SCTs, wrap your cache in [`SctCheck`]. For throwaway test
+//! servers, [`AcmeConfig::cache_none`] keeps everything in memory only.
 //!
 //! By default, `tide-acme` will use the Let's Encrypt staging environment, which is suitable for
 //! testing purposes; it produces certificates signed by a staging root so that you can verify your
@@ -53,59 +73,1356 @@
 #![forbid(unsafe_code)]
 #![deny(missing_docs)]
 
+use std::any::Any;
 use std::fmt::Debug;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use async_std::{net::TcpStream, stream::StreamExt};
+use async_std::future::timeout;
+use async_std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use futures::future::Either;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::{FutureExt, StreamExt};
 use futures_lite::io::AsyncWriteExt;
-pub use rustls_acme::{self, AcmeConfig};
+pub use rustls_acme;
+use tide::listener::{ConcurrentListener, ToListener};
 use tide_rustls::async_rustls::{server::TlsStream, TlsAcceptor};
-use tide_rustls::rustls::Session;
-use tracing::{error, info, info_span, Instrument};
+use tide_rustls::rustls::{ResolvesServerCert, Session};
+use tracing::instrument::WithSubscriber;
+use tracing::subscriber::NoSubscriber;
+use tracing::{debug, error, field, info, info_span, span, warn, Instrument, Level, Span};
+
+use crate::config::{ChallengeClose, ClientHelloInspector, ConfigureRustls, HandshakeFilter};
+use crate::delegation::SharedChallenges;
+use crate::listener::{ConnectionGuard, Connections, HandshakeGuard};
+use crate::proxy::PROXY_HEADER_TIMEOUT;
+use crate::resolver::{challenge_name, LocalIpResolver, StrictSniResolver};
+use crate::shutdown::ShutdownOnDrop;
+use crate::snapshot::CertOrigin;
+use crate::state::CertState;
+
+mod account_key;
+mod acme;
+mod cert_key;
+mod challenge;
+mod challenge_listener;
+mod client_auth;
+mod client_hello;
+mod clock;
+mod config;
+mod csr;
+mod delegation;
+mod der;
+mod dir_cache;
+mod dns;
+mod encrypted_cache;
+mod error;
+mod events;
+mod expiry;
+mod export;
+mod format;
+mod handle;
+#[cfg(unix)]
+mod handover;
+mod https;
+mod ip;
+mod jose;
+mod key_provider;
+mod key_store;
+mod listener;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "object-store")]
+mod object_store;
+mod ocsp;
+mod on_demand;
+mod order_lock;
+mod pacing;
+mod preflight;
+mod problem;
+mod promote;
+mod proxy;
+mod rate_limit;
+#[cfg(feature = "redis")]
+mod redis_cache;
+mod renewal;
+mod report;
+mod resolver;
+mod retry;
+mod routes;
+mod sct;
+mod self_test;
+mod shutdown;
+mod snapshot;
+mod source;
+#[cfg(feature = "sql")]
+mod sql_cache;
+mod state;
+mod summary;
+#[cfg(feature = "test-util")]
+pub mod test;
+mod tickets;
+mod topology;
+#[cfg(unix)]
+mod unix_listener;
+#[cfg(feature = "watch")]
+mod watch;
+
+pub use account_key::{AccountKeyError, AccountKeyPair};
+pub use acme::{RevocationReason, ACME_TLS_ALPN_NAME};
+pub use cert_key::CertKeyAlgorithm;
+pub use challenge::{ChallengeError, ChallengeResponder, DnsProvider};
+pub use challenge_listener::ChallengeListener;
+pub use client_auth::{ClientAuth, CrlError};
+pub use client_hello::{ClientHelloInfo, HelloDecision};
+pub use clock::{Clock, SystemClock};
+pub use config::{AcmeConfig, Profile};
+pub use csr::{Csr, SubjectField};
+pub use delegation::ChallengeDelegation;
+pub use dir_cache::PrivateDirCache;
+pub use dns::DnsResolution;
+pub use encrypted_cache::{CryptoError, EncryptedCache, EncryptedCacheError};
+pub use error::Error;
+pub use events::{AcmeEvent, EventCategory, EventFilter, EventKind};
+pub use handle::{
+    AcmeHandle, CertReadiness, CertRotation, CertStatus, ChallengeStats, ConnectionStats,
+    OrderPacing, PendingIssuance, ProtocolStats, ServedCertificate, TlsConnectionInfo, TlsStats,
+};
+pub use jose::AccountKeyAlgorithm;
+pub use key_provider::{KeyProvider, KeyProviderError};
+pub use key_store::{KeyStore, KeyStoreError, RemoteKey, RemoteKeyAlgorithm};
+pub use listener::ListenerOptions;
+#[cfg(feature = "metrics")]
+pub use metrics::Metrics;
+#[cfg(feature = "object-store")]
+pub use object_store::{ObjectStoreCache, ServerSideEncryption};
+pub use ocsp::{OcspError, StaleOcspPolicy};
+pub use on_demand::DomainPolicy;
+pub use order_lock::{FileLock, Lock, LockGuard};
+pub use problem::{problem_hint, ChallengeFailure, Problem, Subproblem, ValidationRecord};
+pub use promote::PromotionError;
+pub use rate_limit::{RateLimitBudget, RateLimits};
+#[cfg(feature = "redis")]
+pub use redis_cache::{RedisCache, RedisLock};
+pub use renewal::RenewalPolicy;
+pub use report::ErrorReporter;
+#[cfg(feature = "sentry")]
+pub use report::SentryReporter;
+pub use resolver::{AcmeResolver, MissingSni, SniPriority};
+pub use retry::RetryPolicy;
+#[cfg(feature = "simulate-failure")]
+pub use routes::FailureSimulation;
+pub use routes::{
+    AcmeHttp01Middleware, CertInventory, ClientAddr, EventHistory, ExpiryWarning, HealthCheck,
+    HostRouter, Hsts, HttpsRedirect, InFlightRequests, PemDownload, ProxyClientAddr, RenewTrigger,
+    TlsDebug, TlsHeaders, TlsInfo,
+};
+pub use sct::{verify_scts, CtLog, SctCheck, SctError};
+pub use self_test::SelfTestReport;
+pub use snapshot::Snapshot;
+pub use source::{CertificateSource, CertificateSourceError, StaticCertificate};
+#[cfg(feature = "sql")]
+pub use sql_cache::{SqlCache, SqlDatabase, SqlDialect, SqlError, SqlParam};
+pub use summary::{ConfigSummary, GroupSummary};
+pub use tickets::SessionResumption;
+pub use topology::Topology;
+#[cfg(unix)]
+pub use unix_listener::UnixTlsListener;
 
 /// Custom TLS acceptor that answers ACME tls-alpn-01 challenges.
-pub struct AcmeTlsAcceptor(TlsAcceptor);
+///
+/// Clones share the background tasks and certificates, so to serve the same certificates on
+/// several listeners, create one acceptor and pass a clone to each, or build the listeners with
+/// [`listeners`](Self::listeners):
+///
+/// ```no_run
+/// use tide_acme::{AcmeConfig, AcmeTlsAcceptor, TideRustlsExt};
+///
+/// # async_std::task::block_on(async {
+/// let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(vec!["domain.example"]));
+/// let app = tide::new();
+/// let mut listener = tide::listener::ConcurrentListener::new();
+/// listener.add(
+///     tide_rustls::TlsListener::build()
+///         .addrs("0.0.0.0:443")
+///         .acme_acceptor(acceptor.clone()),
+/// )?;
+/// listener.add(
+///     tide_rustls::TlsListener::build()
+///         .addrs("0.0.0.0:8443")
+///         .acme_acceptor(acceptor),
+/// )?;
+/// app.listen(listener).await?;
+/// # tide::Result::Ok(())
+/// # });
+/// ```
+///
+/// A clone can also override the policy of the listeners using it, such as a public listener
+/// with strict SNI and an internal one requiring client certificates, with a higher connection
+/// limit:
+///
+/// ```no_run
+/// use tide_acme::{AcmeConfig, AcmeTlsAcceptor, ListenerOptions, TideRustlsExt};
+/// use tide_rustls::rustls::RootCertStore;
+///
+/// # async_std::task::block_on(async {
+/// # let internal_ca = RootCertStore::empty();
+/// let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(vec!["domain.example"]));
+/// let app = tide::new();
+/// let mut listener = tide::listener::ConcurrentListener::new();
+/// listener.add(
+///     tide_rustls::TlsListener::build()
+///         .addrs("0.0.0.0:443")
+///         .acme_acceptor(
+///             acceptor
+///                 .clone()
+///                 .strict_sni(true)
+///                 .listener_options(ListenerOptions::new().max_connections(1000)),
+///         ),
+/// )?;
+/// listener.add(
+///     tide_rustls::TlsListener::build()
+///         .addrs("0.0.0.0:8443")
+///         .acme_acceptor(acceptor.client_auth(internal_ca)),
+/// )?;
+/// app.listen(listener).await?;
+/// # tide::Result::Ok(())
+/// # });
+/// ```
+#[derive(Clone)]
+pub struct AcmeTlsAcceptor {
+    acceptor: TlsAcceptor,
+    handle: AcmeHandle,
+    challenge_close: ChallengeClose,
+    listener_options: ListenerOptions,
+    connections: Arc<Connections>,
+    strict_sni: bool,
+    client_auth: Option<ClientAuth>,
+    proxy_protocol: bool,
+    handshake_filter: Option<HandshakeFilter>,
+    inspect_client_hello: Option<ClientHelloInspector>,
+    configure_rustls: Option<ConfigureRustls>,
+    alpn_protocols: Vec<Vec<u8>>,
+    shared_challenges: Option<SharedChallenges>,
+    on_demand_wait: Duration,
+    logging: bool,
+    _shutdown: Arc<ShutdownOnDrop>,
+}
 
 impl AcmeTlsAcceptor {
     /// Create a new TLS acceptor that answers ACME tls-alpn-01 challenges, based on the specified
     /// configuration.
     ///
-    /// This will start a background task to manage certificates via ACME.
+    /// This will start a background task per certificate to manage certificates via ACME.
     pub fn new<EC: 'static + Debug, EA: 'static + Debug>(config: AcmeConfig<EC, EA>) -> Self {
-        let mut state = config.state();
-        let acceptor = state.acceptor();
-        async_std::task::spawn(async move {
-            loop {
-                async {
-                    match state
-                        .next()
-                        .await
-                        .expect("AcmeState::next() always returns Some")
-                    {
-                        Ok(event) => info!(?event, "AcmeState::next() processed an event"),
-                        Err(event) => error!(?event, "AcmeState::next() returned an error"),
+        let logging = config.logging;
+        let state = match logging {
+            true => config.state(),
+            false => tracing::subscriber::with_default(NoSubscriber::default(), || config.state()),
+        };
+        let state = Arc::new(state);
+        let handle = state.handle();
+        let registry = handle.registry.clone();
+        #[cfg(feature = "watch")]
+        if let Some(dir) = state.watch_cache_dir() {
+            if let Err(err) = watch::watch(dir, handle.clone(), logging) {
+                if logging {
+                    warn!(%err, dir = %dir.display(), "failed to watch the cache directory");
+                }
+            }
+        }
+        let challenge_close = state.challenge_close();
+        let listener_options = state.listener_options();
+        let handshake_filter = state.handshake_filter();
+        let configure_rustls = state.configure_rustls();
+        let inspect_client_hello = state.inspect_client_hello();
+        let shared_challenges = state.shared_challenges();
+        let on_demand_wait = state.on_demand_wait();
+        for cert in state.certs() {
+            spawn_cert(cert, handle.clone(), logging);
+        }
+        if let Some(requests) = state.take_on_demand_requests() {
+            let state = state.clone();
+            let handle = handle.clone();
+            registry.shutdown.spawn(logged(logging, async move {
+                requests
+                    .for_each_concurrent(None, |domain| async {
+                        // A name whose certificate is already managed, such as one withheld for
+                        // lack of an OCSP response, shares that certificate's order.
+                        if handle.registry.names(&domain) {
+                            debug!(%domain, "certificate on demand already being obtained");
+                            state.share_on_demand(&domain);
+                        } else if state.approve_on_demand(&domain).await {
+                            info!(%domain, "obtaining a certificate on demand");
+                            let cert = state.domain_cert(domain, CertOrigin::OnDemand);
+                            spawn_cert(cert, handle.clone(), logging);
+                        } else {
+                            debug!(%domain, "domain policy denied a certificate on demand");
+                        }
+                    })
+                    .await
+            }));
+        }
+        if let Some(mut added) = state.take_added_domains() {
+            let state = state.clone();
+            let handle = handle.clone();
+            registry.shutdown.spawn(logged(logging, async move {
+                while let Some(domain) = added.next().await {
+                    if !handle.registry.names(&domain) {
+                        info!(%domain, "domain added; obtaining a certificate");
+                        let cert = state.domain_cert(domain, CertOrigin::Added);
+                        spawn_cert(cert, handle.clone(), logging);
                     }
                 }
-                .instrument(info_span!("AcmeState::next()"))
+            }));
+        }
+        if let Some(mut reloads) = state.take_config_reloads() {
+            let state = state.clone();
+            let handle = handle.clone();
+            registry.shutdown.spawn(logged(logging, async move {
+                while let Some(reload) = reloads.next().await {
+                    for cert in state.reload_config(reload).await {
+                        spawn_cert(cert, handle.clone(), logging);
+                    }
+                }
+            }));
+        }
+        if let Some(sync) = state.take_ticket_key_sync() {
+            registry.shutdown.spawn(logged(logging, sync.run()));
+        }
+        let expiry_alarms = state.expiry_alarms();
+        if !expiry_alarms.is_empty() {
+            let watchdog = expiry::watch(state.clone(), handle.clone(), expiry_alarms);
+            registry.shutdown.spawn(logged(logging, watchdog));
+        }
+        for (domain, origin) in state.take_restored_domains() {
+            match origin {
+                CertOrigin::OnDemand => state.request_on_demand(&domain),
+                CertOrigin::Added | CertOrigin::Configured => {
+                    handle.add_domain(&domain);
+                }
+            }
+        }
+        Self {
+            acceptor: Self::tls_acceptor(
+                &handle,
+                configure_rustls.as_ref(),
+                false,
+                None,
+                &[],
+                None,
+                None,
+            ),
+            connections: handle.registry.connections.clone(),
+            handle,
+            challenge_close,
+            listener_options,
+            strict_sni: false,
+            client_auth: None,
+            proxy_protocol: false,
+            handshake_filter,
+            inspect_client_hello,
+            configure_rustls,
+            alpn_protocols: vec![],
+            shared_challenges,
+            on_demand_wait,
+            logging,
+            _shutdown: Arc::new(ShutdownOnDrop(registry)),
+        }
+    }
+
+    fn tls_acceptor(
+        handle: &AcmeHandle,
+        configure: Option<&ConfigureRustls>,
+        strict_sni: bool,
+        client_auth: Option<&ClientAuth>,
+        alpn_protocols: &[Vec<u8>],
+        local_ip: Option<IpAddr>,
+        resolver: Option<Arc<dyn ResolvesServerCert>>,
+    ) -> TlsAcceptor {
+        let mut config = handle.server_config();
+        if let Some(configure) = configure {
+            configure(&mut config);
+            config.cert_resolver = handle.cert_resolver();
+        }
+        if !alpn_protocols.is_empty() {
+            config.alpn_protocols = alpn_protocols.to_vec();
+        }
+        if let Some(local_ip) = local_ip {
+            config.cert_resolver = Arc::new(LocalIpResolver {
+                resolver: handle.cert_resolver(),
+                strict: strict_sni,
+                local_ip,
+            });
+        } else if strict_sni {
+            config.cert_resolver = Arc::new(StrictSniResolver(handle.cert_resolver()));
+        }
+        if let Some(resolver) = resolver {
+            config.cert_resolver = resolver;
+        }
+        if let Some(client_auth) = client_auth {
+            config.set_client_certificate_verifier(client_auth.verifier());
+        }
+        let acme_tls = acme::ACME_TLS_ALPN_NAME.to_vec();
+        if !config.alpn_protocols.contains(&acme_tls) {
+            config.alpn_protocols.push(acme_tls);
+        }
+        handle.registry.record_offered_alpn(&config.alpn_protocols);
+        TlsAcceptor::from(Arc::new(config))
+    }
+
+    fn rebuild(mut self) -> Self {
+        self.acceptor = Self::tls_acceptor(
+            &self.handle,
+            self.configure_rustls.as_ref(),
+            self.strict_sni,
+            self.client_auth.as_ref(),
+            &self.alpn_protocols,
+            None,
+            None,
+        );
+        self
+    }
+
+    /// Refuse handshakes without SNI or for a name no managed certificate covers if
+    /// `strict_sni` is true, rather than serving the certificate of the first domain. Defaults
+    /// to false.
+    ///
+    /// This only affects the listeners using this acceptor, not those using its clones.
+    pub fn strict_sni(mut self, strict_sni: bool) -> Self {
+        self.strict_sni = strict_sni;
+        self.rebuild()
+    }
+
+    /// Verify client certificates, such as for an internal listener: pass the roots to require
+    /// a certificate issued by one of them, or a [`ClientAuth`] to make verification optional or
+    /// check revocation lists. The verified certificates are part of each connection's
+    /// [`TlsConnectionInfo`], which the [`TlsInfo`] middleware hands to handlers.
+    ///
+    /// This only affects the listeners using this acceptor, not those using its clones. ACME
+    /// validators don't present client certificates, so unless verification is optional,
+    /// validation requests can't be answered on these listeners: the CA must reach another one.
+    ///
+    /// ```no_run
+    /// use tide_acme::{AcmeConfig, AcmeTlsAcceptor, ClientAuth, TideRustlsExt, TlsConnectionInfo, TlsInfo};
+    /// use tide_rustls::rustls::RootCertStore;
+    ///
+    /// # async_std::task::block_on(async {
+    /// # let internal_ca = RootCertStore::empty();
+    /// let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(vec!["domain.example"]));
+    /// let mut app = tide::new();
+    /// app.with(TlsInfo::new(acceptor.handle()));
+    /// app.at("/").get(|req: tide::Request<()>| async move {
+    ///     let info = req.ext::<TlsConnectionInfo>();
+    ///     Ok(format!("Hello, {:?}", info.and_then(|info| info.client_subject.as_deref())))
+    /// });
+    /// app.listen(
+    ///     tide_rustls::TlsListener::build()
+    ///         .addrs("0.0.0.0:443")
+    ///         .acme_acceptor(acceptor.client_auth(ClientAuth::new(internal_ca).optional(true))),
+    /// )
+    /// .await?;
+    /// # tide::Result::Ok(())
+    /// # });
+    /// ```
+    pub fn client_auth(mut self, client_auth: impl Into<ClientAuth>) -> Self {
+        self.client_auth = Some(client_auth.into());
+        self.rebuild()
+    }
+
+    /// Offer `protocols` via ALPN to clients, in order of preference, such as `h2` and
+    /// `http/1.1`, instead of none. Validation requests are answered either way.
+    ///
+    /// The listeners of `tide-rustls` speak HTTP/1.1 only, so a listener offering `h2` must be
+    /// served by an HTTP/2 capable server, handed the streams of
+    /// [`accept_stream`](Self::accept_stream); the protocol a stream negotiated is in its
+    /// session, and in its [`TlsConnectionInfo`]. Offering `http/1.1` alone makes clients
+    /// negotiate it explicitly. This only affects the listeners using this acceptor, not those
+    /// using its clones.
+    ///
+    /// ```no_run
+    /// # async fn example(stream: async_std::net::TcpStream) -> std::io::Result<()> {
+    /// use tide_acme::{AcmeConfig, AcmeTlsAcceptor};
+    /// use tide_rustls::rustls::Session;
+    ///
+    /// let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(vec!["domain.example"]))
+    ///     .alpn_protocols(vec![b"h2".to_vec(), b"http/1.1".to_vec()]);
+    /// if let Some(tls) = acceptor.accept_stream(stream).await? {
+    ///     match tls.get_ref().1.get_alpn_protocol() {
+    ///         Some(b"h2") => { /* serve HTTP/2 */ }
+    ///         _ => { /* serve HTTP/1.1 */ }
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn alpn_protocols(mut self, protocols: Vec<Vec<u8>>) -> Self {
+        self.alpn_protocols = protocols;
+        self.rebuild()
+    }
+
+    /// Expect each connection to start with a PROXY protocol header, version 1 or 2, as sent by
+    /// HAProxy or a network load balancer, if `proxy_protocol` is true. Defaults to false.
+    ///
+    /// The header is read and stripped before the TLS handshake, so handshakes, including those
+    /// of validation requests passed through the load balancer, succeed. Connections without a
+    /// valid header are closed. Tide still reports the load balancer's address as the peer
+    /// address, except on a [`UnixTlsListener`], which reports the client address; get the
+    /// client address from [`AcmeHandle::client_addr`](crate::AcmeHandle::client_addr), or with
+    /// the [`ProxyClientAddr`] middleware.
+    ///
+    /// This only affects the listeners using this acceptor, and the sockets of its
+    /// [`listen_challenges`](Self::listen_challenges) and [`warm_up`](Self::warm_up), not those
+    /// using its clones. Only enable it behind a proxy that always sends the header, since
+    /// anyone connecting directly could claim any address.
+    pub fn proxy_protocol(mut self, proxy_protocol: bool) -> Self {
+        self.proxy_protocol = proxy_protocol;
+        self
+    }
+
+    /// Read the PROXY protocol header of a connection if expected, recording the client address
+    /// it carries. Returns whether the connection should be handled.
+    async fn read_proxy_header(&self, stream: &mut TcpStream) -> bool {
+        if !self.proxy_protocol {
+            return true;
+        }
+        match timeout(PROXY_HEADER_TIMEOUT, proxy::read_header(stream)).await {
+            Ok(Ok(client)) => {
+                if let (Some(client), Ok(peer)) = (client, stream.peer_addr()) {
+                    self.handle.registry.client_addrs.insert(peer, client);
+                }
+                true
+            }
+            Ok(Err(err)) => {
+                debug!(%err, "invalid PROXY protocol header; closing connection");
+                false
+            }
+            Err(_) => {
+                debug!("timed out reading the PROXY protocol header; closing connection");
+                false
+            }
+        }
+    }
+
+    /// Ask the [handshake filter](AcmeConfig::handshake_filter), if any, whether to go on with the
+    /// handshake of a connection whose PROXY protocol header was read. Returns whether the
+    /// connection should be handled.
+    async fn filter_handshake(&self, stream: &TcpStream) -> bool {
+        let filter = match &self.handshake_filter {
+            Some(filter) => filter,
+            None => return true,
+        };
+        let hello = match client_hello::peek(stream).await {
+            Ok(hello) => hello.unwrap_or_default(),
+            Err(err) => {
+                debug!(%err, "failed to read the ClientHello; closing connection");
+                return false;
+            }
+        };
+        if hello.alpn == [acme::ACME_TLS_ALPN_NAME] {
+            return true;
+        }
+        let peer = match stream.peer_addr() {
+            Ok(peer) => peer,
+            Err(_) => return false,
+        };
+        let client = match self.proxy_protocol {
+            true => self.handle.registry.client_addrs.get(peer).unwrap_or(peer),
+            false => peer,
+        };
+        let sni = hello.server_name;
+        if filter(client.ip(), sni.clone()).await {
+            return true;
+        }
+        debug!(client = %client.ip(), sni = ?sni, "handshake filter rejected connection");
+        self.handle.registry.record_rejected_handshake();
+        false
+    }
+
+    /// Count a handshake as in progress for the client address of a connection whose PROXY
+    /// protocol header was read, until the returned guard is dropped. Returns `None` if the
+    /// connection should be refused under
+    /// [`ListenerOptions::max_handshakes_per_ip`].
+    fn start_handshake(&self, stream: &TcpStream) -> Option<HandshakeGuard> {
+        let peer = stream.peer_addr().ok()?;
+        let client = match self.proxy_protocol {
+            true => self.handle.registry.client_addrs.get(peer).unwrap_or(peer),
+            false => peer,
+        };
+        let guard = self
+            .connections
+            .start_handshake(client.ip(), &self.listener_options);
+        if guard.is_none() {
+            debug!(client = %client.ip(), "too many handshakes in progress; refusing connection");
+        }
+        guard
+    }
+
+    fn handshake_timed_out(&self) {
+        debug!("timed out waiting for the TLS handshake; closing connection");
+        self.connections.record_timeout();
+    }
+
+    /// Ask the [ClientHello inspector](AcmeConfig::inspect_client_hello), if any, what to do
+    /// with the handshake of a connection whose PROXY protocol header was read.
+    async fn inspect_client_hello(&self, stream: &TcpStream) -> HelloDecision {
+        let inspect = match &self.inspect_client_hello {
+            Some(inspect) => inspect,
+            None => return HelloDecision::Proceed,
+        };
+        let hello = match client_hello::peek(stream).await {
+            Ok(hello) => hello.unwrap_or_default(),
+            Err(err) => {
+                debug!(%err, "failed to read the ClientHello; closing connection");
+                return HelloDecision::Reject;
+            }
+        };
+        if hello.alpn == [acme::ACME_TLS_ALPN_NAME] {
+            return HelloDecision::Proceed;
+        }
+        let peer = match stream.peer_addr() {
+            Ok(peer) => peer,
+            Err(_) => return HelloDecision::Reject,
+        };
+        let client = match self.proxy_protocol {
+            true => self.handle.registry.client_addrs.get(peer).unwrap_or(peer),
+            false => peer,
+        };
+        let decision = inspect(client.ip(), &hello);
+        if let HelloDecision::Reject = decision {
+            debug!(client = %client.ip(), sni = ?hello.server_name, "ClientHello inspection rejected connection");
+            self.handle.registry.record_rejected_handshake();
+        }
+        decision
+    }
+
+    /// The acceptor for a connection without SNI to a local IP address a certificate covers,
+    /// serving that certificate, such as to clients connecting by IP address.
+    async fn local_ip_acceptor(&self, stream: &TcpStream) -> Option<TlsAcceptor> {
+        let local_ip = stream.local_addr().ok()?.ip();
+        if !self.handle.resolver.covers_ip(local_ip) {
+            return None;
+        }
+        match client_hello::peek(stream).await {
+            Ok(Some(ClientHelloInfo {
+                server_name: None, ..
+            })) => Some(Self::tls_acceptor(
+                &self.handle,
+                self.configure_rustls.as_ref(),
+                self.strict_sni,
+                self.client_auth.as_ref(),
+                &self.alpn_protocols,
+                Some(local_ip),
+                None,
+            )),
+            _ => None,
+        }
+    }
+
+    /// The server name of a connection, if its handshake should wait for a certificate being
+    /// obtained on demand; see [`AcmeConfig::on_demand_wait`].
+    async fn on_demand_name(&self, stream: &TcpStream) -> Option<String> {
+        if self.on_demand_wait.is_zero() || !self.handle.resolver.on_demand() {
+            return None;
+        }
+        match client_hello::peek(stream).await {
+            Ok(Some(ClientHelloInfo {
+                server_name: Some(domain),
+                alpn,
+                ..
+            })) if alpn != [acme::ACME_TLS_ALPN_NAME] => Some(domain),
+            _ => None,
+        }
+    }
+
+    /// Answer the validation connection of a challenge another instance published to the cache,
+    /// if delegating challenges. Returns whether the connection should be handled.
+    async fn answer_shared_challenge(&self, stream: &TcpStream) -> bool {
+        let shared = match &self.shared_challenges {
+            Some(shared) => shared,
+            None => return true,
+        };
+        let hello = match client_hello::peek(stream).await {
+            Ok(hello) => hello,
+            Err(err) => {
+                debug!(%err, "failed to read the ClientHello; closing connection");
+                return false;
+            }
+        };
+        if let Some(ClientHelloInfo {
+            server_name: Some(domain),
+            alpn,
+            ..
+        }) = hello
+        {
+            if alpn == [acme::ACME_TLS_ALPN_NAME] {
+                shared.answer(&challenge_name(&domain)).await;
+            }
+        }
+        true
+    }
+
+    /// Override the options for accepting connections set with
+    /// [`AcmeConfig::listener_options`], with a connection limit of their own.
+    ///
+    /// This only affects the listeners using this acceptor, and the sockets of its
+    /// [`listen_challenges`](Self::listen_challenges) and [`warm_up`](Self::warm_up), not those
+    /// using its clones. Connections refused for exceeding the limit are still counted in
+    /// [`AcmeHandle::refused_connections`].
+    pub fn listener_options(mut self, options: ListenerOptions) -> Self {
+        self.listener_options = options;
+        self.connections = self.handle.registry.connections.sibling();
+        self
+    }
+
+    /// Build a listener serving HTTPS through this acceptor on every address `addrs` resolve to,
+    /// such as ports 443 and 8443, or the IPv4 and IPv6 addresses of a host. The sockets share
+    /// this acceptor's background tasks and certificates, so each certificate is ordered once
+    /// however many sockets serve it.
+    ///
+    /// A [`TlsListenerBuilder`](tide_rustls::TlsListenerBuilder) given several addresses only
+    /// binds the first one it can, so this binds each address with
+    /// [`ListenerOptions::bind_all`], using the acceptor's
+    /// [`listener_options`](Self::listener_options), and builds one listener per socket. Returns
+    /// an error if an address can't be resolved or bound, or `addrs` resolve to none.
+    ///
+    /// ```no_run
+    /// use tide_acme::{AcmeConfig, AcmeTlsAcceptor};
+    ///
+    /// # async_std::task::block_on(async {
+    /// let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(vec!["domain.example"]));
+    /// let app = tide::new();
+    /// app.listen(acceptor.listeners(["0.0.0.0:443", "[::]:443", "0.0.0.0:8443"])?)
+    ///     .await?;
+    /// # tide::Result::Ok(())
+    /// # });
+    /// ```
+    pub fn listeners<State, A>(
+        &self,
+        addrs: impl IntoIterator<Item = A>,
+    ) -> std::io::Result<ConcurrentListener<State>>
+    where
+        State: Clone + Send + Sync + 'static,
+        A: std::net::ToSocketAddrs,
+    {
+        let mut listener = ConcurrentListener::new();
+        for socket in self.listener_options.bind_all(addrs)? {
+            listener.add(
+                tide_rustls::TlsListener::build()
+                    .tcp(socket)
+                    .acme_acceptor(self.clone()),
+            )?;
+        }
+        Ok(listener)
+    }
+
+    /// Build a listener serving HTTPS through this acceptor on `port` of every IPv4 and IPv6
+    /// address of the host, as [`listeners`](Self::listeners) does for `0.0.0.0` and `[::]`.
+    ///
+    /// Serve IPv6 clients if the domains have AAAA records: the CA validates tls-alpn-01 over
+    /// IPv6 then, and validation fails if nothing answers there. On hosts without IPv6 support,
+    /// this serves IPv4 clients only, and logs a warning.
+    ///
+    /// ```no_run
+    /// use tide_acme::{AcmeConfig, AcmeTlsAcceptor, ListenerOptions};
+    ///
+    /// # async_std::task::block_on(async {
+    /// let config = AcmeConfig::new(vec!["domain.example"])
+    ///     .listener_options(ListenerOptions::new().reuse_port(true));
+    /// let app = tide::new();
+    /// app.listen(AcmeTlsAcceptor::new(config).dual_stack(443)?)
+    ///     .await?;
+    /// # tide::Result::Ok(())
+    /// # });
+    /// ```
+    pub fn dual_stack<State>(&self, port: u16) -> std::io::Result<ConcurrentListener<State>>
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        let ipv4 = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+        let ipv6 = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+        if listener::ipv6_supported() {
+            self.listeners([ipv4, ipv6])
+        } else {
+            warn!(
+                port,
+                "IPv6 isn't supported on this host; serving IPv4 clients only"
+            );
+            self.listeners([ipv4])
+        }
+    }
+
+    /// Answer tls-alpn-01 validation requests on a dedicated socket, for deployments where a proxy
+    /// terminates TLS for application traffic.
+    ///
+    /// The proxy must pass connections negotiating the `acme-tls/1` ALPN protocol through to this
+    /// socket unterminated (for instance, with nginx's `ssl_preread` or HAProxy's `req.ssl_alpn`),
+    /// since the CA always connects to port 443. Other connections to this socket are closed
+    /// after the handshake. This only returns if binding the socket fails, or the socket becomes
+    /// unusable; transient errors accepting connections are retried, as set with
+    /// [`AcmeConfig::listener_options`](crate::AcmeConfig::listener_options). To run the socket
+    /// alongside the application's listeners instead, use a [`ChallengeListener`].
+    pub async fn listen_challenges(&self, addrs: impl ToSocketAddrs) -> std::io::Result<()> {
+        let listener = self.listener_options.bind_async(addrs).await?;
+        logged(self.logging, self.serve_challenges(&listener)).await
+    }
+
+    /// Run as a standalone certificate manager, for hosts whose servers can't embed Rust TLS:
+    /// answer tls-alpn-01 validation requests on a socket bound to `addrs`, normally port 443,
+    /// and serve no application traffic. This only returns if binding the socket fails, or the
+    /// socket becomes unusable.
+    ///
+    /// Certificates are kept in the cache and renewed as usual. Have other servers use them by
+    /// exporting them to files with [`AcmeConfig::export_dir`], and reload those servers from
+    /// [`AcmeConfig::on_rotation`], which is called once the files are written. Connections not
+    /// negotiating `acme-tls/1` are closed after the handshake.
+    ///
+    /// ```no_run
+    /// use tide_acme::{AcmeConfig, AcmeTlsAcceptor};
+    ///
+    /// # async_std::task::block_on(async {
+    /// let config = AcmeConfig::new(vec!["domain.example"])
+    ///     .cache(tide_acme::PrivateDirCache::new("/var/lib/tide-acme"))
+    ///     .export_dir("/etc/ssl/tide-acme")
+    ///     .on_rotation(|_| async {
+    ///         let _ = async_std::process::Command::new("systemctl")
+    ///             .args(["reload", "nginx"])
+    ///             .status()
+    ///             .await;
+    ///     });
+    /// AcmeTlsAcceptor::new(config).run_standalone("0.0.0.0:443").await?;
+    /// # std::io::Result::Ok(())
+    /// # });
+    /// ```
+    pub async fn run_standalone(&self, addrs: impl ToSocketAddrs) -> std::io::Result<()> {
+        let summary = self.handle.config_summary();
+        if summary.export_dir.is_none() {
+            logged(self.logging, async {
+                warn!(
+                    "running standalone without exporting certificates; set \
+                     AcmeConfig::export_dir for other servers to use them"
+                )
+            })
+            .await;
+        }
+        self.listen_challenges(addrs).await
+    }
+
+    /// Serve plain HTTP on `listener`, normally port 80, redirecting every request to HTTPS,
+    /// except http-01 validation requests, which are answered if enabled with
+    /// [`AcmeConfig::http01`]. This only returns if listening fails.
+    ///
+    /// ```no_run
+    /// use tide_acme::{AcmeConfig, AcmeTlsAcceptor, TideRustlsExt};
+    ///
+    /// # async_std::task::block_on(async {
+    /// let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(vec!["domain.example"]));
+    /// let redirect = acceptor.clone();
+    /// async_std::task::spawn(async move { redirect.listen_http("0.0.0.0:80").await });
+    /// let app = tide::new();
+    /// app.listen(
+    ///     tide_rustls::TlsListener::build()
+    ///         .addrs("0.0.0.0:443")
+    ///         .acme_acceptor(acceptor),
+    /// )
+    /// .await?;
+    /// # tide::Result::Ok(())
+    /// # });
+    /// ```
+    pub async fn listen_http<L: ToListener<()>>(&self, listener: L) -> std::io::Result<()> {
+        let mut app = tide::new();
+        app.with(AcmeHttp01Middleware::new(self.handle.clone()));
+        app.at("").all(HttpsRedirect::new());
+        app.at("*").all(HttpsRedirect::new());
+        logged(self.logging, app.listen(listener)).await
+    }
+
+    /// Obtain certificates before the application starts accepting traffic, answering
+    /// validation requests on a temporary socket bound to `addrs` (normally the address the
+    /// application listens on later) until every certificate is served or has failed to be
+    /// ordered.
+    ///
+    /// Certificates are served once they're loaded from the cache or newly issued, so with a
+    /// warm cache this returns almost immediately. Otherwise, it waits for the first order of
+    /// each certificate, so that the certificates obtained are served right away even if others
+    /// fail. Failed orders are retried in the background; a warning is logged for each
+    /// certificate not served yet, and [`AcmeHandle::status`] shows the errors. Wrap this in a
+    /// timeout to bound the wait.
+    ///
+    /// ```no_run
+    /// use tide_acme::{AcmeConfig, AcmeTlsAcceptor, TideRustlsExt};
+    ///
+    /// # async_std::task::block_on(async {
+    /// let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(vec!["domain.example"]));
+    /// acceptor.warm_up("0.0.0.0:443").await?;
+    /// let app = tide::new();
+    /// app.listen(
+    ///     tide_rustls::TlsListener::build()
+    ///         .addrs("0.0.0.0:443")
+    ///         .acme_acceptor(acceptor),
+    /// )
+    /// .await?;
+    /// # tide::Result::Ok(())
+    /// # });
+    /// ```
+    pub async fn warm_up(&self, addrs: impl ToSocketAddrs) -> std::io::Result<()> {
+        let listener = self.listener_options.bind_async(addrs).await?;
+        let serve = Box::pin(self.serve_challenges(&listener));
+        let deployed = Box::pin(self.settled());
+        let settled = logged(self.logging, futures::future::select(serve, deployed)).await;
+        match settled {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Ok(()),
+        }
+    }
+
+    /// Wait until every managed certificate is being served or has failed to be ordered, and
+    /// warn about those not being served.
+    async fn settled(&self) {
+        let mut events = self.handle.events(EventFilter::new());
+        loop {
+            let status = self.handle.status();
+            if status
+                .iter()
+                .all(|status| status.not_after.is_some() || status.failed_orders > 0)
+            {
+                for status in status.iter().filter(|status| status.not_after.is_none()) {
+                    warn!(
+                        domains = ?status.domains,
+                        error = ?status.last_error,
+                        hint = status.last_error_hint,
+                        "no certificate obtained yet; serving the other domains and retrying in \
+                         the background"
+                    );
+                }
+                return;
+            }
+            if events.next().await.is_none() {
+                return;
+            }
+        }
+    }
+
+    /// Record the local port of a socket answering validation requests, and warn if it is
+    /// `dedicated` to them but validation connections don't arrive on its port.
+    fn check_port(&self, port: u16, dedicated: bool) {
+        let registry = &self.handle.registry;
+        if !registry.record_port(port) {
+            return;
+        }
+        let expected = registry.validation_port();
+        if port == expected {
+            debug!(
+                port,
+                "answering validation requests on the port they arrive on"
+            );
+        } else if dedicated {
+            warn!(
+                port,
+                expected,
+                "tls-alpn-01 validation connections arrive on local port {}, but this socket is \
+                 bound to port {}; declare port forwarding with \
+                 AcmeConfig::tls_alpn_port_mapping",
+                expected,
+                port
+            );
+        }
+    }
+
+    async fn serve_challenges(&self, listener: &TcpListener) -> std::io::Result<()> {
+        if let Ok(addr) = listener.local_addr() {
+            self.check_port(addr.port(), true);
+        }
+        loop {
+            let (mut stream, addr) = match self
+                .listener_options
+                .accept(listener, &self.connections)
                 .await
+            {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!(%err, "accepting validation connections failed; closing the socket");
+                    return Err(err);
+                }
+            };
+            let guard = match self.open_connection() {
+                Some(guard) => guard,
+                None => continue,
+            };
+            let acceptor = self.clone();
+            async_std::task::spawn(logged(self.logging, async move {
+                let _guard = guard;
+                if !acceptor.read_proxy_header(&mut stream).await
+                    || !acceptor.answer_shared_challenge(&stream).await
+                {
+                    return;
+                }
+                let started = Instant::now();
+                match acceptor.acceptor.accept(stream).await {
+                    Ok(tls) => {
+                        if tls.get_ref().1.get_alpn_protocol() == Some(acme::ACME_TLS_ALPN_NAME) {
+                            info!(%addr, "received acme-tls/1 validation request");
+                            acceptor.close_challenge(tls, started).await;
+                        } else {
+                            debug!(%addr, "closing non-validation connection to challenge socket");
+                        }
+                    }
+                    Err(err) => debug!(%addr, %err, "challenge socket handshake failed"),
+                }
+            }));
+        }
+    }
+
+    /// Count a newly accepted connection as open, or refuse it if there are too many or the
+    /// acceptor is draining.
+    fn open_connection(&self) -> Option<ConnectionGuard> {
+        if self.handle.registry.shutdown.draining() {
+            debug!("draining; closing new connection");
+            return None;
+        }
+        let guard = self.connections.open(&self.listener_options);
+        if guard.is_none() {
+            debug!("too many open connections; refusing connection");
+        }
+        guard
+    }
+
+    /// Close a validation connection according to the configured policy, and record how long it
+    /// took.
+    async fn close_challenge<IO: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut tls: TlsStream<IO>,
+        started: Instant,
+    ) {
+        let domain = tls.get_ref().1.get_sni_hostname().map(String::from);
+        let policy = self.challenge_close;
+        let mut timed_out = false;
+        if policy.close_notify {
+            match timeout(policy.timeout, tls.close()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => debug!(%err, "closing validation connection failed"),
+                Err(_) => {
+                    debug!(
+                        timeout = ?policy.timeout,
+                        "closing validation connection timed out; dropping it"
+                    );
+                    timed_out = true;
+                }
             }
-        });
-        Self(acceptor)
+        }
+        drop(tls);
+        self.handle.resolver.record_challenge_close(
+            domain.as_deref(),
+            started.elapsed(),
+            timed_out,
+        );
+    }
+
+    /// Perform the TLS handshake on a connection of any kind, such as a Unix domain socket, an
+    /// in-memory stream in tests, or a stream whose PROXY protocol header was already read. To
+    /// serve a Tide application on a Unix domain socket, use a [`UnixTlsListener`].
+    ///
+    /// Like the listeners using this acceptor, this answers tls-alpn-01 validation requests and
+    /// returns `None` for them, as well as for connections refused under the limits of the
+    /// [`ListenerOptions`]; otherwise it returns the TLS stream for the application to serve.
+    ///
+    /// Connections without SNI are only matched to a certificate for the local IP address they
+    /// arrived on by the listeners, which know that address.
+    pub async fn accept_stream<IO: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: IO,
+    ) -> std::io::Result<Option<TlsStream<IO>>> {
+        self.accept_with(&self.acceptor, stream, None).await
+    }
+
+    /// Complete the handshake of `stream` with `acceptor`, first waiting for the certificate of
+    /// `on_demand`, a server name it is being obtained for, if any. The wait counts against the
+    /// handshake timeout, with the connection held open.
+    async fn accept_with<IO: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        acceptor: &TlsAcceptor,
+        stream: IO,
+        on_demand: Option<String>,
+    ) -> std::io::Result<Option<TlsStream<IO>>> {
+        logged(self.logging, async {
+            let _guard = match self.open_connection() {
+                Some(guard) => guard,
+                None => return Ok(None),
+            };
+            let requests = &self.handle.registry.requests;
+            if !requests.wait_for_capacity(&self.listener_options).await {
+                debug!("too many requests in flight; refusing connection");
+                self.connections.refuse();
+                return Ok(None);
+            }
+            let started = Instant::now();
+            let handshake_timeout = self.listener_options.handshake_timeout_duration();
+            let handshake = async {
+                if let Some(domain) = &on_demand {
+                    let resolver = &self.handle.resolver;
+                    resolver
+                        .wait_for_issuance(domain, self.on_demand_wait)
+                        .await;
+                }
+                acceptor.accept(stream).await
+            };
+            let tls = match timeout(handshake_timeout, handshake).await {
+                Ok(tls) => tls?,
+                Err(_) => {
+                    self.handshake_timed_out();
+                    return Ok(None);
+                }
+            };
+            match tls.get_ref().1.get_alpn_protocol() {
+                Some(acme::ACME_TLS_ALPN_NAME) => {
+                    info_span!(
+                        "AcmeTlsAcceptor::accept()",
+                        acceptor = self.handle.name().as_deref()
+                    )
+                    .in_scope(|| info!("received acme-tls/1 validation request"));
+                    self.close_challenge(tls, started).await;
+                    Ok(None)
+                }
+                _ => {
+                    let session = &tls.get_ref().1;
+                    self.handle
+                        .resolver
+                        .record_handshake(session.get_sni_hostname());
+                    self.handle.registry.record_protocols(session);
+                    Ok(Some(tls))
+                }
+            }
+        })
+        .await
+    }
+
+    /// Get a handle for inspecting the certificates this acceptor manages.
+    ///
+    /// The handle doesn't keep the background tasks running: they stop once the last clone of
+    /// the acceptor is dropped.
+    pub fn handle(&self) -> AcmeHandle {
+        self.handle.clone()
+    }
+
+    /// Stop the background tasks managing certificates, and wait until they have; see
+    /// [`AcmeHandle::shutdown`]. Listeners using the acceptor go on serving the certificates
+    /// already obtained.
+    pub async fn shutdown(&self) {
+        self.handle.shutdown().await
+    }
+
+    /// Stop accepting connections, wait for those accepted, the requests in flight and cache
+    /// writes in progress to complete within `deadline`, then shut down the background tasks;
+    /// see [`AcmeHandle::drain`]. Returns whether everything completed in time.
+    pub async fn drain(&self, deadline: Duration) -> bool {
+        self.handle.drain(deadline).await
     }
 }
 
 #[async_trait::async_trait]
 impl tide_rustls::CustomTlsAcceptor for AcmeTlsAcceptor {
-    async fn accept(&self, stream: TcpStream) -> std::io::Result<Option<TlsStream<TcpStream>>> {
-        let mut tls = self.0.accept(stream).await?;
-        match tls.get_ref().1.get_alpn_protocol() {
-            Some(rustls_acme::acme::ACME_TLS_ALPN_NAME) => {
-                info_span!("AcmeTlsAcceptor::accept()")
-                    .in_scope(|| info!("received acme-tls/1 validation request"));
-                tls.close().await?;
-                Ok(None)
+    async fn accept(&self, mut stream: TcpStream) -> std::io::Result<Option<TlsStream<TcpStream>>> {
+        let prepared = logged(self.logging, async {
+            if let Ok(addr) = stream.local_addr() {
+                self.check_port(addr.port(), false);
+            }
+            if !self.read_proxy_header(&mut stream).await {
+                return None;
             }
-            _ => Ok(Some(tls)),
+            let handshake = self.start_handshake(&stream)?;
+            let checks = async {
+                if !(self.answer_shared_challenge(&stream).await
+                    && self.filter_handshake(&stream).await)
+                {
+                    return None;
+                }
+                let acceptor = match self.inspect_client_hello(&stream).await {
+                    HelloDecision::Proceed => self.local_ip_acceptor(&stream).await,
+                    HelloDecision::Reject => return None,
+                    HelloDecision::Resolve(resolver) => Some(Self::tls_acceptor(
+                        &self.handle,
+                        self.configure_rustls.as_ref(),
+                        self.strict_sni,
+                        self.client_auth.as_ref(),
+                        &self.alpn_protocols,
+                        None,
+                        Some(resolver),
+                    )),
+                };
+                Some((acceptor, self.on_demand_name(&stream).await))
+            };
+            match timeout(self.listener_options.handshake_timeout_duration(), checks).await {
+                Ok(prepared) => Some((handshake, prepared?)),
+                Err(_) => {
+                    self.handshake_timed_out();
+                    None
+                }
+            }
+        })
+        .await;
+        let (_handshake, (acceptor, on_demand)) = match prepared {
+            Some(prepared) => prepared,
+            None => return Ok(None),
+        };
+        let peer = stream.peer_addr();
+        let acceptor = acceptor.as_ref().unwrap_or(&self.acceptor);
+        let tls = self.accept_with(acceptor, stream, on_demand).await?;
+        if let (Some(tls), Ok(peer)) = (&tls, peer) {
+            let info = TlsConnectionInfo::new(tls.get_ref().1);
+            self.handle.registry.tls_info.insert(peer, info);
         }
+        Ok(tls)
+    }
+}
+
+/// Start the background task managing a certificate.
+fn spawn_cert<EC: 'static + Debug, EA: 'static + Debug>(
+    mut cert: CertState<EC, EA>,
+    handle: AcmeHandle,
+    logging: bool,
+) {
+    let registry = handle.registry.clone();
+    registry.shutdown.spawn(logged(logging, async move {
+        let mut panics = 0;
+        while !cert.is_removed() {
+            let span = cert_span(&cert, handle.name().as_deref());
+            let step = async {
+                let event = cert.next().await;
+                let event = process_event(&cert, &handle, event);
+                cert.save_resume();
+                cert.run_hooks(&event).await;
+            };
+            let result = AssertUnwindSafe(step.instrument(span.clone()))
+                .catch_unwind()
+                .await;
+            if let Err(payload) = result {
+                // Restart with exponential backoff, so a panic that recurs doesn't spin.
+                panics += 1;
+                let backoff = Duration::from_secs(1 << panics.min(9));
+                let message = panic_message(&*payload);
+                span.in_scope(|| {
+                    error!(
+                        target: ACME_TARGET,
+                        panic = %message,
+                        ?backoff,
+                        "certificate management panicked; restarting after backoff"
+                    )
+                });
+                cert.panicked(message);
+                async_std::task::sleep(backoff).await;
+            } else {
+                panics = 0;
+            }
+        }
+    }));
+}
+
+/// The tracing target of the spans and events of certificate management.
+const ACME_TARGET: &str = "tide_acme::acme";
+
+/// The tracing span of a step of the background task managing `cert`, at the configured level;
+/// see [`AcmeConfig::tracing_level`].
+fn cert_span<EC: 'static + Debug, EA: 'static + Debug>(
+    cert: &CertState<EC, EA>,
+    acceptor: Option<&str>,
+) -> Span {
+    let domains = cert.domains();
+    macro_rules! cert_span {
+        ($level:expr) => {
+            span!(
+                target: ACME_TARGET,
+                $level,
+                "acme",
+                acceptor,
+                domain = domains.first().map(String::as_str),
+                domains = %domains.join(","),
+                attempt = cert.attempt(),
+                order_url = cert.order_url(),
+                challenge_type = field::Empty,
+                elapsed_ms = field::Empty,
+            )
+        };
+    }
+    match cert.tracing_level() {
+        Level::ERROR => cert_span!(Level::ERROR),
+        Level::WARN => cert_span!(Level::WARN),
+        Level::INFO => cert_span!(Level::INFO),
+        Level::DEBUG => cert_span!(Level::DEBUG),
+        _ => cert_span!(Level::TRACE),
+    }
+}
+
+/// The message of a panic, from its payload.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "panic with a non-string payload".into(),
+        },
+    }
+}
+
+/// Log an event of the background task managing a certificate, and pass it on. Exits the
+/// process on cache store failures configured to be fatal.
+fn process_event<EC: 'static + Debug, EA: 'static + Debug>(
+    cert: &CertState<EC, EA>,
+    handle: &AcmeHandle,
+    event: state::Event<EC, EA>,
+) -> AcmeEvent {
+    let mut acme_event = AcmeEvent::new(cert.domains(), cert.order_url(), &event);
+    acme_event.acceptor = handle.name();
+    let event_type = acme_event.kind.name();
+    let order_url = acme_event.order_url.as_deref();
+    let validation_path = acme_event
+        .problem_type
+        .as_deref()
+        .filter(|typ| typ.ends_with(":connection") || typ.ends_with(":tls"))
+        .map(|_| handle.validation_path());
+    match &event {
+        Ok(event) => info!(
+            target: ACME_TARGET,
+            event_type,
+            order_url,
+            ?event,
+            "AcmeState::next() processed an event"
+        ),
+        Err(event) => error!(
+            target: ACME_TARGET,
+            event_type,
+            order_url,
+            error_class = event.class(),
+            hint = acme_event.hint,
+            problem_detail = acme_event
+                .problem
+                .as_ref()
+                .and_then(|problem| problem.detail.as_deref()),
+            validation_path = validation_path.as_deref(),
+            ?event,
+            "AcmeState::next() returned an error"
+        ),
+    }
+    cert.record(&acme_event);
+    cert.report(&acme_event);
+    if acme_event.is_failure() {
+        handle.resolver.issuance_failed(cert.domains());
+    }
+    handle.registry.publish(acme_event.clone());
+    if cert.is_fatal(&event) {
+        error!("failed to store in the cache, which is configured to be fatal; exiting");
+        std::process::exit(1);
+    }
+    acme_event
+}
+
+/// Run `future` without emitting tracing events if `logging` is false.
+fn logged<F: Future>(logging: bool, future: F) -> impl Future<Output = F::Output> {
+    match logging {
+        true => Either::Left(future),
+        false => Either::Right(future.with_subscriber(NoSubscriber::default())),
     }
 }
 
@@ -118,12 +1435,510 @@ pub trait TideRustlsExt {
     /// configuration.
     ///
     /// This creates an [`AcmeTlsAcceptor`], which will start a background task to manage
-    /// certificates via ACME.
+    /// certificates via ACME. To serve the same certificates on several listeners, use
+    /// [`acme_acceptor`](Self::acme_acceptor) with clones of one acceptor instead, so they
+    /// aren't managed, and ordered, once per listener.
     fn acme<EC: 'static + Debug, EA: 'static + Debug>(self, config: AcmeConfig<EC, EA>) -> Self;
+
+    /// Use an existing [`AcmeTlsAcceptor`], sharing its background task and certificates with
+    /// the other listeners using it.
+    fn acme_acceptor(self, acceptor: AcmeTlsAcceptor) -> Self;
 }
 
 impl<State> TideRustlsExt for tide_rustls::TlsListenerBuilder<State> {
     fn acme<EC: 'static + Debug, EA: 'static + Debug>(self, config: AcmeConfig<EC, EA>) -> Self {
-        self.tls_acceptor(std::sync::Arc::new(AcmeTlsAcceptor::new(config)))
+        self.acme_acceptor(AcmeTlsAcceptor::new(config))
+    }
+
+    fn acme_acceptor(self, acceptor: AcmeTlsAcceptor) -> Self {
+        self.tls_acceptor(Arc::new(acceptor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::block_on;
+
+    use super::*;
+
+    #[test]
+    fn shares_certificates_between_clones() {
+        let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(Vec::<String>::new()));
+        let clone = acceptor.clone();
+        let (index, _) = acceptor.handle().registry.register(
+            vec!["domain.example".into()],
+            0,
+            CertOrigin::Configured,
+        );
+        assert_eq!(clone.handle().status()[index].domains, ["domain.example"]);
+        assert!(std::sync::Arc::ptr_eq(
+            &acceptor.handle().resolver,
+            &clone.handle().resolver
+        ));
+    }
+
+    #[test]
+    fn closes_connections_to_the_challenge_socket() {
+        use futures_lite::io::AsyncReadExt;
+
+        let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(Vec::<String>::new()));
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap();
+        assert!(block_on(acceptor.listen_challenges(addr)).is_err());
+        drop(taken);
+
+        async_std::task::spawn(async move { acceptor.listen_challenges(addr).await });
+        block_on(async {
+            let mut stream = loop {
+                match TcpStream::connect(addr).await {
+                    Ok(stream) => break stream,
+                    Err(_) => async_std::task::sleep(std::time::Duration::from_millis(10)).await,
+                }
+            };
+            stream.write_all(b"not a client hello").await.unwrap();
+            let mut buf = Vec::new();
+            // The connection is closed rather than kept open.
+            let _ = stream.read_to_end(&mut buf).await;
+        });
+    }
+
+    #[test]
+    fn warms_up_until_all_certificates_settle() {
+        let state = AcmeConfig::new(Vec::<String>::new()).state();
+        let handle = state.handle();
+        let acceptor = AcmeTlsAcceptor {
+            acceptor: AcmeTlsAcceptor::tls_acceptor(&handle, None, false, None, &[], None, None),
+            connections: handle.registry.connections.clone(),
+            _shutdown: Arc::new(ShutdownOnDrop(handle.registry.clone())),
+            handle,
+            challenge_close: state.challenge_close(),
+            listener_options: state.listener_options(),
+            strict_sni: false,
+            client_auth: None,
+            proxy_protocol: false,
+            handshake_filter: None,
+            inspect_client_hello: None,
+            configure_rustls: None,
+            alpn_protocols: vec![],
+            shared_challenges: None,
+            on_demand_wait: state.on_demand_wait(),
+            logging: true,
+        };
+        let registry = acceptor.handle.registry.clone();
+        let (deployed, _) = registry.register(vec!["a.example".into()], 0, CertOrigin::Configured);
+        let (failing, _) = registry.register(vec!["b.example".into()], 0, CertOrigin::Configured);
+        let event = |domain: &str, kind: EventKind| AcmeEvent {
+            domains: vec![domain.into()],
+            kind,
+            order_url: None,
+            error_class: None,
+            problem_type: None,
+            hint: None,
+            problem: None,
+            challenge_failure: None,
+            error: None,
+            failure: None,
+            at: std::time::SystemTime::now(),
+            acceptor: None,
+        };
+        block_on(async {
+            let mut warm_up = Box::pin(acceptor.warm_up("127.0.0.1:0"));
+            assert!(futures::poll!(&mut warm_up).is_pending());
+            registry.update(deployed, |status| {
+                status.not_after = Some(std::time::SystemTime::now())
+            });
+            registry.publish(event("a.example", EventKind::DeployedNewCert));
+            assert!(futures::poll!(&mut warm_up).is_pending());
+            // Domains that failed to get a certificate don't hold up the others.
+            registry.update(failing, |status| status.failed_orders = 1);
+            registry.publish(event("b.example", EventKind::OrderFailed));
+            warm_up.await.unwrap();
+        });
+    }
+
+    /// A subscriber counting the events it sees.
+    #[derive(Default)]
+    struct Counter(std::sync::atomic::AtomicUsize);
+
+    impl tracing::Subscriber for Counter {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            tracing::span::Id::from_u64(1)
+        }
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+        fn event(&self, _: &tracing::Event<'_>) {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+        fn enter(&self, _: &tracing::span::Id) {}
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[test]
+    fn describes_panic_payloads() {
+        let payload = std::panic::catch_unwind(|| panic!("boom")).unwrap_err();
+        assert_eq!(panic_message(&*payload), "boom");
+        let payload = std::panic::catch_unwind(|| panic!("{} failed", "order")).unwrap_err();
+        assert_eq!(panic_message(&*payload), "order failed");
+        let payload = std::panic::catch_unwind(|| std::panic::panic_any(7)).unwrap_err();
+        assert_eq!(panic_message(&*payload), "panic with a non-string payload");
+    }
+
+    #[test]
+    fn silences_logging_when_disabled() {
+        let counter = std::sync::Arc::new(Counter::default());
+        let dispatch = tracing::Dispatch::from(counter.clone());
+        tracing::dispatcher::with_default(&dispatch, || {
+            block_on(logged(true, async { info!("logged") }));
+            block_on(logged(false, async { info!("silenced") }));
+        });
+        assert_eq!(counter.0.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    /// A subscriber describing the spans created, with their fields.
+    #[derive(Default)]
+    struct Spans(std::sync::Mutex<Vec<String>>);
+
+    /// Append the fields visited to `described`.
+    fn describe_fields(described: &mut String) -> impl FnMut(&field::Field, &dyn Debug) + '_ {
+        move |field, value| described.push_str(&format!(" {}={:?}", field, value))
+    }
+
+    impl tracing::Subscriber for Spans {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let meta = span.metadata();
+            let mut described = format!("{} {} {}", meta.level(), meta.target(), meta.name());
+            span.record(&mut describe_fields(&mut described));
+            let mut spans = self.0.lock().unwrap();
+            spans.push(described);
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+        fn record(&self, id: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+            let mut spans = self.0.lock().unwrap();
+            values.record(&mut describe_fields(&mut spans[id.into_u64() as usize - 1]));
+        }
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+        fn event(&self, _: &tracing::Event<'_>) {}
+        fn enter(&self, _: &tracing::span::Id) {}
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[test]
+    fn describes_certificates_in_spans() {
+        let state = Arc::new(
+            AcmeConfig::new(vec!["a.example", "b.example"])
+                .tracing_level(Level::DEBUG)
+                .state(),
+        );
+        let cert = state.certs().remove(0);
+        let spans = std::sync::Arc::new(Spans::default());
+        let dispatch = tracing::Dispatch::from(spans.clone());
+        tracing::dispatcher::with_default(&dispatch, || {
+            let span = cert_span(&cert, Some("main"));
+            span.record("challenge_type", "tls-alpn-01");
+        });
+        let spans = spans.0.lock().unwrap();
+        assert_eq!(
+            *spans,
+            [
+                "DEBUG tide_acme::acme acme acceptor=\"main\" domain=\"a.example\" \
+              domains=a.example,b.example attempt=1 challenge_type=\"tls-alpn-01\""
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn accepts_tls_over_any_stream() {
+        use async_std::os::unix::net::UnixStream;
+        use futures_lite::io::{AsyncReadExt, AsyncWriteExt};
+        use tide_rustls::async_rustls::webpki::DNSNameRef;
+        use tide_rustls::async_rustls::TlsConnector;
+        use tide_rustls::rustls::sign::{any_supported_type, CertifiedKey};
+        use tide_rustls::rustls::{Certificate, ClientConfig, PrivateKey};
+
+        let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(Vec::<String>::new()));
+        let cert = rcgen::generate_simple_self_signed(vec!["a.example".into()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        let key = any_supported_type(&PrivateKey(cert.serialize_private_key_der())).unwrap();
+        acceptor.handle().resolver.set_cert(
+            0,
+            &["a.example".into()],
+            CertifiedKey::new(vec![Certificate(der.clone())], Arc::new(key)),
+        );
+        let mut config = ClientConfig::new();
+        config.root_store.add(&Certificate(der)).unwrap();
+        let connector = TlsConnector::from(Arc::new(config));
+        let name = DNSNameRef::try_from_ascii_str("a.example").unwrap();
+
+        block_on(async {
+            let (client, server) = UnixStream::pair().unwrap();
+            let client = async_std::task::spawn(async move {
+                let mut tls = connector.connect(name.to_owned().as_ref(), client).await?;
+                tls.write_all(b"hello").await?;
+                tls.flush().await?;
+                Ok::<_, std::io::Error>(tls)
+            });
+            let mut tls = acceptor.accept_stream(server).await.unwrap().unwrap();
+            let mut buf = [0; 5];
+            tls.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            drop(client.await.unwrap());
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn applies_the_rustls_configuration() {
+        use async_std::os::unix::net::UnixStream;
+        use tide_rustls::async_rustls::webpki::DNSNameRef;
+        use tide_rustls::async_rustls::TlsConnector;
+        use tide_rustls::rustls::sign::{any_supported_type, CertifiedKey};
+        use tide_rustls::rustls::{
+            Certificate, ClientConfig, PrivateKey, ProtocolVersion, ResolvesServerCertUsingSNI,
+        };
+
+        let config = AcmeConfig::new(Vec::<String>::new()).configure_rustls(|config| {
+            config.versions = vec![ProtocolVersion::TLSv1_2];
+            config.set_protocols(&[b"h2".to_vec()]);
+            // Set back to the managed certificates.
+            config.cert_resolver = Arc::new(ResolvesServerCertUsingSNI::new());
+        });
+        let acceptor = AcmeTlsAcceptor::new(config);
+        let cert = rcgen::generate_simple_self_signed(vec!["a.example".into()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        let key = any_supported_type(&PrivateKey(cert.serialize_private_key_der())).unwrap();
+        acceptor.handle().resolver.set_cert(
+            0,
+            &["a.example".into()],
+            CertifiedKey::new(vec![Certificate(der.clone())], Arc::new(key)),
+        );
+        let mut config = ClientConfig::new();
+        config.root_store.add(&Certificate(der)).unwrap();
+        config.set_protocols(&[b"h2".to_vec()]);
+        let connector = TlsConnector::from(Arc::new(config));
+        let name = DNSNameRef::try_from_ascii_str("a.example").unwrap();
+
+        block_on(async {
+            let (client, server) = UnixStream::pair().unwrap();
+            let client = async_std::task::spawn(async move {
+                connector.connect(name.to_owned().as_ref(), client).await
+            });
+            let tls = acceptor.accept_stream(server).await.unwrap().unwrap();
+            let (_, session) = tls.get_ref();
+            assert_eq!(
+                session.get_protocol_version(),
+                Some(ProtocolVersion::TLSv1_2)
+            );
+            assert_eq!(session.get_alpn_protocol(), Some(&b"h2"[..]));
+            drop(client.await.unwrap());
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn offers_alpn_protocols() {
+        use async_std::os::unix::net::UnixStream;
+        use tide_rustls::async_rustls::webpki::DNSNameRef;
+        use tide_rustls::async_rustls::TlsConnector;
+        use tide_rustls::rustls::sign::{any_supported_type, CertifiedKey};
+        use tide_rustls::rustls::{Certificate, ClientConfig, PrivateKey};
+
+        let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(Vec::<String>::new()));
+        let cert = rcgen::generate_simple_self_signed(vec!["a.example".into()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        let key = any_supported_type(&PrivateKey(cert.serialize_private_key_der())).unwrap();
+        acceptor.handle().resolver.set_cert(
+            0,
+            &["a.example".into()],
+            CertifiedKey::new(vec![Certificate(der.clone())], Arc::new(key)),
+        );
+        let plain = acceptor.clone();
+        let acceptor = acceptor.alpn_protocols(vec![b"h2".to_vec(), b"http/1.1".to_vec()]);
+        assert_eq!(
+            acceptor.handle().registry.offered_alpn(),
+            [&b"acme-tls/1"[..], b"h2", b"http/1.1"]
+        );
+
+        let negotiate = |acceptor: AcmeTlsAcceptor, offered: &[&[u8]]| {
+            let mut config = ClientConfig::new();
+            config.root_store.add(&Certificate(der.clone())).unwrap();
+            config.set_protocols(&offered.iter().map(|p| p.to_vec()).collect::<Vec<_>>());
+            let connector = TlsConnector::from(Arc::new(config));
+            let name = DNSNameRef::try_from_ascii_str("a.example").unwrap();
+            block_on(async {
+                let (client, server) = UnixStream::pair().unwrap();
+                let client = async_std::task::spawn(async move {
+                    connector.connect(name.to_owned().as_ref(), client).await
+                });
+                let tls = acceptor.accept_stream(server).await.unwrap().unwrap();
+                drop(client.await.unwrap());
+                tls.get_ref().1.get_alpn_protocol().map(<[u8]>::to_vec)
+            })
+        };
+        assert_eq!(
+            negotiate(acceptor.clone(), &[b"http/1.1", b"h2"]),
+            Some(b"h2".to_vec())
+        );
+        assert_eq!(
+            negotiate(acceptor, &[b"http/1.1"]),
+            Some(b"http/1.1".to_vec())
+        );
+        assert_eq!(negotiate(plain, &[b"h2"]), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn times_out_handshakes() {
+        use async_std::os::unix::net::UnixStream;
+
+        let options = ListenerOptions::new().handshake_timeout(Duration::from_millis(50));
+        let acceptor =
+            AcmeTlsAcceptor::new(AcmeConfig::new(Vec::<String>::new())).listener_options(options);
+        block_on(async {
+            // A client that connects and then sends nothing.
+            let (_client, server) = UnixStream::pair().unwrap();
+            assert!(acceptor.accept_stream(server).await.unwrap().is_none());
+        });
+        assert_eq!(acceptor.handle().timed_out_handshakes(), 1);
+    }
+
+    #[test]
+    fn reads_proxy_headers_before_handshakes() {
+        use futures_lite::io::AsyncWriteExt;
+
+        let acceptor =
+            AcmeTlsAcceptor::new(AcmeConfig::new(Vec::<String>::new())).proxy_protocol(true);
+        block_on(async {
+            let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            client
+                .write_all(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n")
+                .await
+                .unwrap();
+            assert!(acceptor.read_proxy_header(&mut server).await);
+            assert_eq!(
+                acceptor.handle().client_addr(client.local_addr().unwrap()),
+                Some("192.0.2.1:56324".parse().unwrap())
+            );
+
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            client.write_all(b"\x16\x03\x01\x00\x05").await.unwrap();
+            assert!(!acceptor.read_proxy_header(&mut server).await);
+        });
+    }
+
+    #[test]
+    fn filters_handshakes_by_client_and_sni() {
+        use futures_lite::io::AsyncWriteExt;
+        use tide_rustls::async_rustls::webpki::DNSNameRef;
+        use tide_rustls::rustls::{ClientConfig, ClientSession, Session};
+
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let filtered = seen.clone();
+        let config = AcmeConfig::new(Vec::<String>::new()).handshake_filter(move |ip, sni| {
+            let allowed = sni.as_deref() == Some("allowed.example");
+            filtered.lock().unwrap().push((ip, sni));
+            async move { allowed }
+        });
+        let acceptor = AcmeTlsAcceptor::new(config);
+        block_on(async {
+            let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = listener.local_addr().unwrap();
+            for &(name, allowed) in [("allowed.example", true), ("other.example", false)].iter() {
+                let mut client = TcpStream::connect(addr).await.unwrap();
+                let (server, _) = listener.accept().await.unwrap();
+                let name = DNSNameRef::try_from_ascii_str(name).unwrap();
+                let mut session = ClientSession::new(&Arc::new(ClientConfig::new()), name);
+                let mut hello = Vec::new();
+                session.write_tls(&mut hello).unwrap();
+                client.write_all(&hello).await.unwrap();
+                assert_eq!(acceptor.filter_handshake(&server).await, allowed);
+            }
+        });
+        let ip: std::net::IpAddr = "127.0.0.1".parse().unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                (ip, Some("allowed.example".to_string())),
+                (ip, Some("other.example".to_string()))
+            ]
+        );
+        assert_eq!(acceptor.handle().rejected_handshakes(), 1);
+    }
+
+    #[test]
+    fn inspects_client_hellos() {
+        use futures_lite::io::AsyncWriteExt;
+        use tide_rustls::async_rustls::webpki::DNSNameRef;
+        use tide_rustls::rustls::{ClientConfig, ClientSession, ResolvesServerCertUsingSNI};
+
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let inspected = seen.clone();
+        let config =
+            AcmeConfig::new(Vec::<String>::new()).inspect_client_hello(move |ip, hello| {
+                inspected.lock().unwrap().push((ip, hello.clone()));
+                match hello.server_name.as_deref() {
+                    Some("blocked.example") => HelloDecision::Reject,
+                    Some("routed.example") => {
+                        HelloDecision::Resolve(Arc::new(ResolvesServerCertUsingSNI::new()))
+                    }
+                    _ => HelloDecision::Proceed,
+                }
+            });
+        let acceptor = AcmeTlsAcceptor::new(config);
+        let decisions = block_on(async {
+            let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mut decisions = vec![];
+            for &name in ["allowed.example", "blocked.example", "routed.example"].iter() {
+                let mut client = TcpStream::connect(addr).await.unwrap();
+                let (server, _) = listener.accept().await.unwrap();
+                let name = DNSNameRef::try_from_ascii_str(name).unwrap();
+                let mut session = ClientSession::new(&Arc::new(ClientConfig::new()), name);
+                let mut hello = Vec::new();
+                session.write_tls(&mut hello).unwrap();
+                client.write_all(&hello).await.unwrap();
+                decisions.push(acceptor.inspect_client_hello(&server).await);
+            }
+            decisions
+        });
+        assert!(matches!(decisions[0], HelloDecision::Proceed));
+        assert!(matches!(decisions[1], HelloDecision::Reject));
+        assert!(matches!(decisions[2], HelloDecision::Resolve(_)));
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 3);
+        let (ip, hello) = &seen[0];
+        assert_eq!(ip.to_string(), "127.0.0.1");
+        assert_eq!(hello.server_name.as_deref(), Some("allowed.example"));
+        assert!(hello.ja3().starts_with("771,"));
+        assert_eq!(acceptor.handle().rejected_handshakes(), 1);
+    }
+
+    #[test]
+    fn builds_a_listener_per_address() {
+        let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(Vec::<String>::new()));
+        let listener = acceptor
+            .listeners::<(), _>(["127.0.0.1:0", "127.0.0.1:0"])
+            .unwrap();
+        assert_eq!(listener.to_string().matches("127.0.0.1:").count(), 1);
+        let none: [&str; 0] = [];
+        let err = acceptor.listeners::<(), _>(none).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(acceptor.listeners::<(), _>(["not an address"]).is_err());
     }
 }
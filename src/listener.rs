@@ -0,0 +1,830 @@
+//! Binding listening sockets, and accepting connections on them.
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_std::future::timeout;
+use async_std::net::{TcpListener, TcpStream};
+use event_listener::Event;
+use ring::rand::{SecureRandom, SystemRandom};
+#[cfg(unix)]
+use socket2::SockAddr;
+use socket2::{Domain, Protocol, Socket, Type};
+use tracing::warn;
+
+/// Options for binding listening sockets and accepting connections on them.
+///
+/// Set with [`AcmeConfig::listener_options`](crate::AcmeConfig::listener_options) for the sockets
+/// answering validation requests, such as the one of
+/// [`AcmeTlsAcceptor::warm_up`](crate::AcmeTlsAcceptor::warm_up). To bind the application's
+/// listener with the same backlog, pass a socket from [`bind`](Self::bind) to
+/// [`TlsListenerBuilder::tcp`](tide_rustls::TlsListenerBuilder::tcp).
+///
+/// ```no_run
+/// use tide_acme::{AcmeConfig, ListenerOptions, TideRustlsExt};
+///
+/// # async_std::task::block_on(async {
+/// let options = ListenerOptions::new().backlog(4096);
+/// let app = tide::new();
+/// app.listen(
+///     tide_rustls::TlsListener::build()
+///         .tcp(options.bind("0.0.0.0:443")?)
+///         .acme(AcmeConfig::new(vec!["domain.example"]).listener_options(options)),
+/// )
+/// .await?;
+/// # tide::Result::Ok(())
+/// # });
+/// ```
+#[derive(Clone, Debug)]
+pub struct ListenerOptions {
+    backlog: i32,
+    reuse_port: bool,
+    min_error_delay: Duration,
+    max_error_delay: Duration,
+    error_delay_jitter: f64,
+    max_connections: Option<usize>,
+    max_in_flight_requests: Option<(usize, Duration)>,
+    handshake_timeout: Duration,
+    max_handshakes_per_ip: Option<usize>,
+}
+
+impl Default for ListenerOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ListenerOptions {
+    /// Create options with a backlog of 1024, accept error delays from 10 milliseconds to 1
+    /// second shortened by up to a quarter at random, a handshake timeout of 10 seconds, and no
+    /// connection or request limit.
+    pub fn new() -> Self {
+        Self {
+            backlog: 1024,
+            reuse_port: false,
+            min_error_delay: Duration::from_millis(10),
+            max_error_delay: Duration::from_secs(1),
+            error_delay_jitter: 0.25,
+            max_connections: None,
+            max_in_flight_requests: None,
+            handshake_timeout: Duration::from_secs(10),
+            max_handshakes_per_ip: None,
+        }
+    }
+
+    /// Set the TCP accept backlog: how many connections the kernel queues before they're
+    /// accepted. The kernel may cap it, such as at `net.core.somaxconn` on Linux.
+    pub fn backlog(mut self, backlog: i32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Bind with `SO_REUSEPORT` if `reuse_port` is true, so several processes can listen on the
+    /// same port, with the kernel spreading connections between them. Defaults to false.
+    ///
+    /// This allows zero-downtime restarts, by starting the new process before stopping the old
+    /// one. Use [`AcmeConfig::order_lock`](crate::AcmeConfig::order_lock) so that processes
+    /// sharing a cache don't all order the same certificates. Binding fails on platforms without
+    /// `SO_REUSEPORT`.
+    pub fn reuse_port(mut self, reuse_port: bool) -> Self {
+        self.reuse_port = reuse_port;
+        self
+    }
+
+    /// Set how long to pause accepting after an error, such as running out of file descriptors
+    /// (`EMFILE`): `min` after the first error, doubling for each error in a row up to `max`.
+    ///
+    /// Pausing lets connections close and free resources, rather than hot-looping on the error.
+    /// Errors that only concern one connection, such as a connection aborted before it was
+    /// accepted (`ECONNABORTED`), and interrupted calls (`EINTR`) are retried without pausing.
+    /// Errors meaning the listening socket itself is unusable, such as `EBADF` or `EINVAL`, aren't
+    /// retried: they end the accept loop of
+    /// [`AcmeTlsAcceptor::listen_challenges`](crate::AcmeTlsAcceptor::listen_challenges), which
+    /// returns them.
+    pub fn accept_error_delay(mut self, min: Duration, max: Duration) -> Self {
+        self.min_error_delay = min;
+        self.max_error_delay = max.max(min);
+        self
+    }
+
+    /// Shorten each pause after an accept error by a random fraction of up to `jitter`, between
+    /// 0 and 1, so that processes hitting the same error don't resume in lockstep. Defaults to
+    /// 0.25.
+    pub fn accept_error_jitter(mut self, jitter: f64) -> Self {
+        self.error_delay_jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Refuse connections beyond `max_connections` open at once, closing them right after they
+    /// are accepted, rather than letting them queue for resources. Defaults to no limit.
+    ///
+    /// Refused connections are counted in
+    /// [`AcmeHandle::refused_connections`](crate::AcmeHandle::refused_connections). The limit
+    /// covers connections to the sockets answering validation requests for as long as they're
+    /// open. For the application's listener, it covers connections until their TLS handshake
+    /// completes, since Tide handles them after that, so it bounds the handshakes clients can
+    /// leave hanging.
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    /// While the application is handling `max` requests or more, hold new connections before
+    /// their TLS handshake until it handles fewer, for up to `max_wait`, and refuse them if it
+    /// still doesn't by then. Defaults to no limit.
+    ///
+    /// This keeps a saturated application from taking on handshakes it can't serve in time.
+    /// Requests are counted by the [`InFlightRequests`](crate::InFlightRequests) middleware,
+    /// which the application must use. Connections refused are counted in
+    /// [`AcmeHandle::refused_connections`](crate::AcmeHandle::refused_connections), and
+    /// [`AcmeHandle::throttled_connections`](crate::AcmeHandle::throttled_connections) tells how
+    /// many are being held. This only applies to the application's listener, where tls-alpn-01
+    /// validation connections are held too, so during sustained saturation the CA's validation
+    /// may time out; answer validation requests on a socket of their own with
+    /// [`AcmeTlsAcceptor::listen_challenges`](crate::AcmeTlsAcceptor::listen_challenges) to avoid
+    /// that.
+    pub fn max_in_flight_requests(mut self, max: usize, max_wait: Duration) -> Self {
+        self.max_in_flight_requests = Some((max, max_wait));
+        self
+    }
+
+    /// Close connections that don't complete their TLS handshake within `timeout` of being
+    /// accepted, such as clients that connect and then send nothing, or trickle their
+    /// ClientHello. Defaults to 10 seconds.
+    ///
+    /// On connections starting with a PROXY protocol header, the time to read the header is
+    /// limited separately, and the timeout starts after it. Connections closed are counted in
+    /// [`AcmeHandle::timed_out_handshakes`](crate::AcmeHandle::timed_out_handshakes).
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    pub(crate) fn handshake_timeout_duration(&self) -> Duration {
+        self.handshake_timeout
+    }
+
+    /// Refuse connections from a client IP address with `max` TLS handshakes in progress
+    /// already, so a few clients holding handshakes open can't use up the
+    /// [connection limit](Self::max_connections). Defaults to no limit.
+    ///
+    /// The client address is the one from the PROXY protocol header if
+    /// [enabled](crate::AcmeTlsAcceptor::proxy_protocol). Clients behind a shared address, such
+    /// as a carrier-grade NAT, count as one, so leave room for them. Refused connections are
+    /// counted in [`AcmeHandle::refused_connections`](crate::AcmeHandle::refused_connections).
+    /// This only applies to the application's listeners, not to streams passed to
+    /// [`accept_stream`](crate::AcmeTlsAcceptor::accept_stream), whose address isn't known.
+    pub fn max_handshakes_per_ip(mut self, max: usize) -> Self {
+        self.max_handshakes_per_ip = Some(max);
+        self
+    }
+
+    /// Use the listening socket handed over by the process that started this one, with
+    /// [`AcmeHandle::handover`](crate::AcmeHandle::handover) or via systemd socket activation,
+    /// or bind one to `addrs` if there is none.
+    ///
+    /// Of the sockets systemd passes, this takes the first TCP one, leaving Unix domain sockets
+    /// to [`UnixTlsListener::inherit_or_bind`](crate::UnixTlsListener::inherit_or_bind). Sockets
+    /// passed this way are bound already, so these options don't apply to them.
+    #[cfg(unix)]
+    pub fn inherit_or_bind(
+        &self,
+        addrs: impl std::net::ToSocketAddrs,
+    ) -> io::Result<std::net::TcpListener> {
+        match crate::handover::take_listener() {
+            Some(listener) => Ok(listener),
+            None => self.bind(addrs),
+        }
+    }
+
+    /// Bind a listening socket to the first of `addrs` that works, with these options.
+    pub fn bind(&self, addrs: impl std::net::ToSocketAddrs) -> io::Result<std::net::TcpListener> {
+        let mut last_err = None;
+        for addr in addrs.to_socket_addrs()? {
+            match self.bind_addr(addr, false) {
+                Ok(listener) => return Ok(listener),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no addresses to bind to")))
+    }
+
+    /// Bind a listening socket to every address `addrs` resolve to, with these options, such as
+    /// to serve both IPv4 and IPv6 clients. Fails if an address can't be resolved or bound, or
+    /// `addrs` resolve to none.
+    ///
+    /// An IPv6 socket is bound with `IPV6_V6ONLY` if an IPv4 socket is bound to the same port,
+    /// so that `0.0.0.0:443` and `[::]:443` can be bound together: on most platforms, an IPv6
+    /// socket bound to `[::]` otherwise takes the port for IPv4 too.
+    pub fn bind_all<A: std::net::ToSocketAddrs>(
+        &self,
+        addrs: impl IntoIterator<Item = A>,
+    ) -> io::Result<Vec<std::net::TcpListener>> {
+        let mut resolved: Vec<SocketAddr> = Vec::new();
+        for addrs in addrs {
+            for addr in addrs.to_socket_addrs()? {
+                if !resolved.contains(&addr) {
+                    resolved.push(addr);
+                }
+            }
+        }
+        if resolved.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "no addresses to bind to",
+            ));
+        }
+        resolved
+            .iter()
+            .map(|addr| {
+                let only_v6 = addr.is_ipv6()
+                    && resolved
+                        .iter()
+                        .any(|other| other.is_ipv4() && other.port() == addr.port());
+                self.bind_addr(*addr, only_v6)
+            })
+            .collect()
+    }
+
+    /// Bind an asynchronous listening socket to the first of `addrs` that works.
+    pub(crate) async fn bind_async(
+        &self,
+        addrs: impl async_std::net::ToSocketAddrs,
+    ) -> io::Result<TcpListener> {
+        let mut last_err = None;
+        for addr in addrs.to_socket_addrs().await? {
+            match self.bind_addr(addr, false) {
+                Ok(listener) => return Ok(listener.into()),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no addresses to bind to")))
+    }
+
+    fn bind_addr(&self, addr: SocketAddr, only_v6: bool) -> io::Result<std::net::TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        if only_v6 {
+            socket.set_only_v6(true)?;
+        }
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        if self.reuse_port {
+            set_reuse_port(&socket)?;
+        }
+        socket.bind(&addr.into())?;
+        socket.listen(self.backlog)?;
+        Ok(socket.into())
+    }
+
+    /// Bind a listening Unix domain socket to `path`, with the backlog of these options.
+    #[cfg(unix)]
+    pub(crate) fn bind_unix(&self, path: &Path) -> io::Result<std::os::unix::net::UnixListener> {
+        let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+        socket.bind(&SockAddr::unix(path)?)?;
+        socket.listen(self.backlog)?;
+        Ok(socket.into())
+    }
+
+    /// Accept the next connection, retrying after transient errors as configured and counting
+    /// those not concerning a single connection in the totals of `connections`. Only fails if
+    /// the listening socket is unusable.
+    pub(crate) async fn accept(
+        &self,
+        listener: &TcpListener,
+        connections: &Connections,
+    ) -> io::Result<(TcpStream, SocketAddr)> {
+        self.accept_with(|| listener.accept(), connections).await
+    }
+
+    /// Like [`accept`](Self::accept), for listening sockets of any kind, accepting with
+    /// `accept`.
+    pub(crate) async fn accept_with<T, F, Fut>(
+        &self,
+        mut accept: F,
+        connections: &Connections,
+    ) -> io::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        let mut delay = self.min_error_delay;
+        loop {
+            match accept().await {
+                Ok(accepted) => return Ok(accepted),
+                Err(err) if is_connection_error(&err) => continue,
+                Err(err) => {
+                    connections.record_accept_error();
+                    if is_fatal_error(&err) {
+                        return Err(err);
+                    }
+                    let pause = jittered(delay, self.error_delay_jitter);
+                    warn!(%err, ?pause, "accepting a connection failed; pausing");
+                    async_std::task::sleep(pause).await;
+                    delay = (delay * 2).min(self.max_error_delay);
+                }
+            }
+        }
+    }
+}
+
+/// Whether this host supports IPv6 sockets at all, rather than having IPv6 compiled out or
+/// disabled at boot.
+pub(crate) fn ipv6_supported() -> bool {
+    Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP)).is_ok()
+}
+
+/// Shorten `delay` by a random fraction of up to `jitter`.
+fn jittered(delay: Duration, jitter: f64) -> Duration {
+    let mut random = [0; 4];
+    if jitter == 0.0 || SystemRandom::new().fill(&mut random).is_err() {
+        return delay;
+    }
+    let fraction = u32::from_be_bytes(random) as f64 / u32::MAX as f64;
+    delay.mul_f64(1.0 - jitter * fraction)
+}
+
+/// Count of open connections and of handshakes in progress by client address, shared by the
+/// listeners of an acceptor, with totals shared by all its listeners.
+#[derive(Debug, Default)]
+pub(crate) struct Connections {
+    open: AtomicUsize,
+    handshakes: Mutex<HashMap<IpAddr, usize>>,
+    totals: Arc<ConnectionTotals>,
+}
+
+/// Totals of the listeners of an acceptor, whatever their limits.
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionTotals {
+    pub(crate) open: AtomicUsize,
+    pub(crate) handshakes: AtomicUsize,
+    pub(crate) refused: AtomicU64,
+    pub(crate) timed_out: AtomicU64,
+    pub(crate) accept_errors: AtomicU64,
+}
+
+impl Connections {
+    /// A separate count of open connections, for listeners with their own limit, sharing the
+    /// totals.
+    pub(crate) fn sibling(&self) -> Arc<Self> {
+        Arc::new(Self {
+            open: AtomicUsize::new(0),
+            handshakes: Mutex::default(),
+            totals: self.totals.clone(),
+        })
+    }
+
+    pub(crate) fn totals(&self) -> &ConnectionTotals {
+        &self.totals
+    }
+
+    /// Count a newly accepted connection as open until the returned guard is dropped, or count it
+    /// as refused and return `None` if the limit of `options` is reached.
+    pub(crate) fn open(self: &Arc<Self>, options: &ListenerOptions) -> Option<ConnectionGuard> {
+        let max = options.max_connections.unwrap_or(usize::MAX);
+        let opened = self
+            .open
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+                (open < max).then(|| open + 1)
+            });
+        match opened {
+            Ok(_) => {
+                self.totals.open.fetch_add(1, Ordering::AcqRel);
+                Some(ConnectionGuard(self.clone()))
+            }
+            Err(_) => {
+                self.refuse();
+                None
+            }
+        }
+    }
+
+    /// Count a connection refused for another reason than the limit of open connections.
+    pub(crate) fn refuse(&self) {
+        self.totals.refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a handshake from `ip` as in progress until the returned guard is dropped, or count
+    /// the connection as refused and return `None` if the per-address limit of `options` is
+    /// reached.
+    pub(crate) fn start_handshake(
+        self: &Arc<Self>,
+        ip: IpAddr,
+        options: &ListenerOptions,
+    ) -> Option<HandshakeGuard> {
+        let max = options.max_handshakes_per_ip.unwrap_or(usize::MAX);
+        let mut handshakes = self.handshakes.lock().unwrap();
+        let count = handshakes.entry(ip).or_default();
+        if *count >= max {
+            self.refuse();
+            return None;
+        }
+        *count += 1;
+        self.totals.handshakes.fetch_add(1, Ordering::AcqRel);
+        Some(HandshakeGuard(self.clone(), ip))
+    }
+
+    pub(crate) fn record_accept_error(&self) {
+        self.totals.accept_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_timeout(&self) {
+        self.totals.timed_out.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Count of requests the application is handling, and of connections held until it handles
+/// fewer.
+#[derive(Debug, Default)]
+pub(crate) struct Requests {
+    in_flight: AtomicUsize,
+    throttled: AtomicUsize,
+    finished: Event,
+}
+
+impl Requests {
+    /// Count a request as in flight until the returned guard is dropped.
+    pub(crate) fn start(self: &Arc<Self>) -> RequestGuard {
+        self.in_flight.fetch_add(1, Ordering::AcqRel);
+        RequestGuard(self.clone())
+    }
+
+    pub(crate) fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn throttled(&self) -> usize {
+        self.throttled.load(Ordering::Acquire)
+    }
+
+    /// Wait until fewer requests than the limit of `options` are in flight, for up to its
+    /// maximum wait. Returns whether there are.
+    pub(crate) async fn wait_for_capacity(&self, options: &ListenerOptions) -> bool {
+        let (max, max_wait) = match options.max_in_flight_requests {
+            Some(limit) => limit,
+            None => return true,
+        };
+        if self.in_flight() < max {
+            return true;
+        }
+        self.throttled.fetch_add(1, Ordering::AcqRel);
+        let deadline = Instant::now() + max_wait;
+        let has_capacity = loop {
+            let finished = self.finished.listen();
+            if self.in_flight() < max {
+                break true;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if timeout(remaining, finished).await.is_err() {
+                break self.in_flight() < max;
+            }
+        };
+        self.throttled.fetch_sub(1, Ordering::AcqRel);
+        has_capacity
+    }
+}
+
+/// A request in flight, counted until dropped.
+pub(crate) struct RequestGuard(Arc<Requests>);
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::AcqRel);
+        self.0.finished.notify(1);
+    }
+}
+
+/// An open connection, counted until dropped.
+pub(crate) struct ConnectionGuard(Arc<Connections>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::AcqRel);
+        self.0.totals.open.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// A handshake in progress, counted for its client address until dropped.
+pub(crate) struct HandshakeGuard(Arc<Connections>, IpAddr);
+
+impl Drop for HandshakeGuard {
+    fn drop(&mut self) {
+        self.0.totals.handshakes.fetch_sub(1, Ordering::AcqRel);
+        let mut handshakes = self.0.handshakes.lock().unwrap();
+        if let Some(count) = handshakes.get_mut(&self.1) {
+            *count -= 1;
+            if *count == 0 {
+                handshakes.remove(&self.1);
+            }
+        }
+    }
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        ErrorKind::Other,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
+
+/// Whether an accept error only concerns the connection being accepted, or interrupted the call.
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+    )
+}
+
+/// Whether an accept error means the listening socket is unusable, so retrying is futile.
+fn is_fatal_error(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    if let Some(code) = err.raw_os_error() {
+        return matches!(
+            code,
+            libc::EBADF | libc::EINVAL | libc::ENOTSOCK | libc::EOPNOTSUPP
+        );
+    }
+    matches!(err.kind(), ErrorKind::InvalidInput | ErrorKind::Unsupported)
+}
+
+/// How many connections to remember details of, evicting the oldest beyond that.
+const MAX_PEERS: usize = 65536;
+
+/// Details of connections, such as the client address a proxy reported, by peer address, for
+/// Tide middleware to look up: the application only sees the peer address of a connection.
+pub(crate) struct PeerMap<V> {
+    inner: Mutex<PeerMapInner<V>>,
+}
+
+struct PeerMapInner<V> {
+    values: HashMap<SocketAddr, V>,
+    /// The keys of `values`, oldest first, for evicting the oldest.
+    order: VecDeque<SocketAddr>,
+}
+
+impl<V> Default for PeerMap<V> {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(PeerMapInner {
+                values: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+}
+
+impl<V: Clone> PeerMap<V> {
+    /// Record `value` for the connection from `peer`, replacing that of an earlier connection
+    /// from the same address.
+    pub(crate) fn insert(&self, peer: SocketAddr, value: V) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.values.insert(peer, value).is_none() {
+            inner.order.push_back(peer);
+        }
+        while inner.order.len() > MAX_PEERS {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.values.remove(&oldest);
+            }
+        }
+    }
+
+    pub(crate) fn get(&self, peer: SocketAddr) -> Option<V> {
+        self.inner.lock().unwrap().values.get(&peer).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::block_on;
+
+    use super::*;
+
+    #[test]
+    fn binds_every_address() {
+        let options = ListenerOptions::new();
+        let port = options
+            .bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let bound = options
+            .bind_all([format!("127.0.0.1:{}", port), format!("127.0.0.1:{}", port)])
+            .unwrap();
+        assert_eq!(bound.len(), 1);
+        assert_eq!(bound[0].local_addr().unwrap().port(), port);
+        let none: [&str; 0] = [];
+        let err = options.bind_all(none).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(options.bind_all(["127.0.0.1:0", "not an address"]).is_err());
+    }
+
+    #[test]
+    fn binds_both_stacks_to_one_port() {
+        if !ipv6_supported() {
+            return;
+        }
+        let options = ListenerOptions::new();
+        let port = options
+            .bind("0.0.0.0:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let ipv4 = SocketAddr::from((std::net::Ipv4Addr::UNSPECIFIED, port));
+        let ipv6 = SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, port));
+        let bound = options.bind_all([ipv6, ipv4]).unwrap();
+        let addrs: Vec<_> = bound.iter().map(|l| l.local_addr().unwrap()).collect();
+        assert_eq!(addrs, [ipv6, ipv4]);
+    }
+
+    #[test]
+    fn bounds_accept_error_delays() {
+        let options = ListenerOptions::new()
+            .accept_error_delay(Duration::from_millis(100), Duration::from_millis(10));
+        assert_eq!(options.min_error_delay, Duration::from_millis(100));
+        assert_eq!(options.max_error_delay, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn jitters_accept_error_delays() {
+        let delay = Duration::from_millis(100);
+        assert_eq!(jittered(delay, 0.0), delay);
+        for _ in 0..100 {
+            let pause = jittered(delay, 0.25);
+            assert!(pause >= Duration::from_millis(75) && pause <= delay);
+        }
+        let options = ListenerOptions::new().accept_error_jitter(2.0);
+        assert_eq!(options.error_delay_jitter, 1.0);
+    }
+
+    #[test]
+    fn binds_and_accepts_connections() {
+        let options = ListenerOptions::new().backlog(16);
+        let listener = options.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(options.bind(addr).is_err());
+        let no_addrs: &[SocketAddr] = &[];
+        assert_eq!(
+            options.bind(no_addrs).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        drop(listener);
+
+        block_on(async {
+            let listener = options.bind_async(addr).await.unwrap();
+            let client = TcpStream::connect(addr).await.unwrap();
+            let (_, peer) = options
+                .accept(&listener, &Connections::default())
+                .await
+                .unwrap();
+            assert_eq!(peer, client.local_addr().unwrap());
+        });
+    }
+
+    #[test]
+    fn classifies_connection_errors() {
+        assert!(is_connection_error(&ErrorKind::ConnectionAborted.into()));
+        assert!(is_connection_error(&ErrorKind::Interrupted.into()));
+        assert!(!is_connection_error(&io::Error::from_raw_os_error(24)));
+        // Running out of file descriptors is neither, so it's retried after a pause.
+        assert!(!is_fatal_error(&io::Error::from_raw_os_error(24)));
+        assert!(is_fatal_error(&ErrorKind::InvalidInput.into()));
+        #[cfg(unix)]
+        assert!(is_fatal_error(&io::Error::from_raw_os_error(libc::EBADF)));
+    }
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    #[test]
+    fn shares_ports_with_reuse_port() {
+        let options = ListenerOptions::new().reuse_port(true);
+        let first = options.bind("127.0.0.1:0").unwrap();
+        let second = options.bind(first.local_addr().unwrap()).unwrap();
+        assert_eq!(first.local_addr().unwrap(), second.local_addr().unwrap());
+    }
+
+    #[test]
+    fn caps_open_connections() {
+        let connections = Arc::new(Connections::default());
+        let options = ListenerOptions::new().max_connections(2);
+        let first = connections.open(&options).unwrap();
+        let second = connections.open(&options).unwrap();
+        assert!(connections.open(&options).is_none());
+        assert_eq!(connections.totals().refused.load(Ordering::Relaxed), 1);
+        drop(first);
+        let third = connections.open(&options).unwrap();
+        assert!(connections.open(&options).is_none());
+        assert_eq!(connections.totals().refused.load(Ordering::Relaxed), 2);
+        drop((second, third));
+        assert_eq!(connections.open.load(Ordering::Acquire), 0);
+        // Without a cap, connections are only counted.
+        let unlimited = ListenerOptions::new();
+        let guards: Vec<_> = (0..10).map(|_| connections.open(&unlimited)).collect();
+        assert!(guards.iter().all(Option::is_some));
+        assert_eq!(connections.open.load(Ordering::Acquire), 10);
+    }
+
+    #[test]
+    fn counts_sibling_connections_separately() {
+        let connections = Arc::new(Connections::default());
+        let sibling = connections.sibling();
+        let options = ListenerOptions::new().max_connections(1);
+        let _first = connections.open(&options).unwrap();
+        let _second = sibling.open(&options).unwrap();
+        assert!(sibling.open(&options).is_none());
+        // Refused connections are counted across siblings.
+        assert!(connections.open(&options).is_none());
+        assert_eq!(connections.totals().refused.load(Ordering::Relaxed), 2);
+        assert_eq!(sibling.totals().refused.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn caps_handshakes_per_ip() {
+        let connections = Arc::new(Connections::default());
+        let options = ListenerOptions::new().max_handshakes_per_ip(2);
+        let (a, b): (IpAddr, IpAddr) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        let first = connections.start_handshake(a, &options).unwrap();
+        let second = connections.start_handshake(a, &options).unwrap();
+        assert!(connections.start_handshake(a, &options).is_none());
+        assert_eq!(connections.totals().refused.load(Ordering::Relaxed), 1);
+        // Other clients aren't affected.
+        let other = connections.start_handshake(b, &options).unwrap();
+        drop(first);
+        let third = connections.start_handshake(a, &options).unwrap();
+        assert!(connections.start_handshake(a, &options).is_none());
+        drop((second, third, other));
+        assert!(connections.handshakes.lock().unwrap().is_empty());
+        // Without a cap, handshakes are only counted.
+        let guards: Vec<_> = (0..10)
+            .map(|_| connections.start_handshake(a, &ListenerOptions::new()))
+            .collect();
+        assert!(guards.iter().all(Option::is_some));
+        assert_eq!(connections.handshakes.lock().unwrap()[&a], 10);
+    }
+
+    #[test]
+    fn holds_connections_while_requests_are_in_flight() {
+        let requests = Arc::new(Requests::default());
+        let options = ListenerOptions::new().max_in_flight_requests(1, Duration::from_millis(50));
+        block_on(async {
+            assert!(requests.wait_for_capacity(&options).await);
+            let guard = requests.start();
+            assert_eq!(requests.in_flight(), 1);
+            assert!(!requests.wait_for_capacity(&options).await);
+            assert_eq!(requests.throttled(), 0);
+            // A finishing request lets a held connection through before the wait runs out.
+            let options = ListenerOptions::new().max_in_flight_requests(1, Duration::from_secs(5));
+            let waiting = async_std::task::spawn({
+                let requests = requests.clone();
+                async move { requests.wait_for_capacity(&options).await }
+            });
+            async_std::task::sleep(Duration::from_millis(20)).await;
+            assert_eq!(requests.throttled(), 1);
+            drop(guard);
+            assert!(waiting.await);
+            assert_eq!(requests.in_flight(), 0);
+            assert_eq!(requests.throttled(), 0);
+        });
+        // Without a limit, connections are never held.
+        block_on(async {
+            let _guards: Vec<_> = (0..10).map(|_| requests.start()).collect();
+            assert!(requests.wait_for_capacity(&ListenerOptions::new()).await);
+        });
+    }
+
+    #[test]
+    fn remembers_recent_peers() {
+        let addrs = PeerMap::default();
+        let peer = |n: usize| SocketAddr::new(std::net::Ipv4Addr::from(n as u32).into(), 443);
+        let client: SocketAddr = "192.0.2.1:56324".parse().unwrap();
+        addrs.insert(peer(1), client);
+        assert_eq!(addrs.get(peer(1)), Some(client));
+        assert_eq!(addrs.get(peer(2)), None);
+        for n in 2..=MAX_PEERS {
+            addrs.insert(peer(n), client);
+        }
+        assert_eq!(addrs.get(peer(1)), Some(client));
+        // Beyond the limit, the oldest peer is evicted.
+        addrs.insert(peer(0), client);
+        assert_eq!(addrs.get(peer(1)), None);
+        assert_eq!(addrs.get(peer(0)), Some(client));
+    }
+}
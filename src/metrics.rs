@@ -0,0 +1,427 @@
+//! Certificate lifecycle metrics in the Prometheus text exposition format.
+
+use std::fmt::{Display, Write};
+use std::time::SystemTime;
+
+use tide::{Request, Response, StatusCode};
+
+use crate::AcmeHandle;
+
+/// Render the metrics of `handle`.
+pub(crate) fn render(handle: &AcmeHandle) -> String {
+    let mut out = Samples {
+        out: String::new(),
+        acceptor: handle.name(),
+    };
+    let events = handle.registry.event_counts();
+    let count = |name: &str| events.get(name).copied().unwrap_or(0);
+
+    out.header(
+        "tide_acme_certificates_obtained_total",
+        "counter",
+        "Certificates obtained from the CA or a certificate source.",
+    );
+    out.sample(
+        "tide_acme_certificates_obtained_total",
+        None,
+        count("deployed_new_cert"),
+    );
+    out.header(
+        "tide_acme_renewal_failures_total",
+        "counter",
+        "Failed orders, which are retried with backoff.",
+    );
+    out.sample(
+        "tide_acme_renewal_failures_total",
+        None,
+        count("order_failed"),
+    );
+    out.header(
+        "tide_acme_events_total",
+        "counter",
+        "Events of the certificate management tasks, by event type.",
+    );
+    for (event_type, n) in &events {
+        out.sample(
+            "tide_acme_events_total",
+            Some(("event_type", event_type)),
+            n,
+        );
+    }
+
+    out.header(
+        "tide_acme_certificate_expiry_seconds",
+        "gauge",
+        "Seconds until the certificate served for a domain expires; negative once expired.",
+    );
+    let now = SystemTime::now();
+    for status in handle.status() {
+        let not_after = match status.not_after {
+            Some(not_after) => not_after,
+            None => continue,
+        };
+        let seconds = match not_after.duration_since(now) {
+            Ok(remaining) => remaining.as_secs() as i64,
+            Err(err) => -(err.duration().as_secs() as i64),
+        };
+        for domain in &status.domains {
+            out.sample(
+                "tide_acme_certificate_expiry_seconds",
+                Some(("domain", domain)),
+                seconds,
+            );
+        }
+    }
+    out.header(
+        "tide_acme_expiring_cert_responses_total",
+        "counter",
+        "Responses served under a certificate close to expiry, flagged by ExpiryWarning.",
+    );
+    out.sample(
+        "tide_acme_expiring_cert_responses_total",
+        None,
+        handle.expiring_cert_responses(),
+    );
+
+    out.header(
+        "tide_acme_expiry_alarms",
+        "gauge",
+        "Certificates served with less validity left than an expiry alarm threshold.",
+    );
+    out.sample("tide_acme_expiry_alarms", None, handle.expiry_alarms());
+
+    let challenges = handle.challenge_stats();
+    out.header(
+        "tide_acme_challenge_handshakes_total",
+        "counter",
+        "tls-alpn-01 validation handshakes answered, by domain.",
+    );
+    for (domain, stats) in &challenges {
+        out.sample(
+            "tide_acme_challenge_handshakes_total",
+            Some(("domain", domain.as_deref().unwrap_or(""))),
+            stats.tls_alpn_01.saturating_sub(stats.missing_key),
+        );
+    }
+    out.header(
+        "tide_acme_challenge_missing_key_total",
+        "counter",
+        "tls-alpn-01 validation handshakes without a validation certificate, by domain.",
+    );
+    for (domain, stats) in &challenges {
+        out.sample(
+            "tide_acme_challenge_missing_key_total",
+            Some(("domain", domain.as_deref().unwrap_or(""))),
+            stats.missing_key,
+        );
+    }
+
+    let tls = handle.tls_stats();
+    out.header(
+        "tide_acme_tls_handshakes_total",
+        "counter",
+        "TLS handshakes completed, by server name.",
+    );
+    for (name, stats) in &tls {
+        out.sample(
+            "tide_acme_tls_handshakes_total",
+            Some(("server_name", name.as_deref().unwrap_or(""))),
+            stats.handshakes,
+        );
+    }
+    out.header(
+        "tide_acme_tls_handshake_errors_total",
+        "counter",
+        "TLS handshakes started but not completed, by server name, including those in progress.",
+    );
+    for (name, stats) in &tls {
+        out.sample(
+            "tide_acme_tls_handshake_errors_total",
+            Some(("server_name", name.as_deref().unwrap_or(""))),
+            stats.incomplete_handshakes(),
+        );
+    }
+    out.header(
+        "tide_acme_tls_no_certificate_total",
+        "counter",
+        "TLS handshakes started without a certificate to serve, by server name.",
+    );
+    for (name, stats) in &tls {
+        out.sample(
+            "tide_acme_tls_no_certificate_total",
+            Some(("server_name", name.as_deref().unwrap_or(""))),
+            stats.no_certificate,
+        );
+    }
+
+    let connections = handle.connection_stats();
+    out.header(
+        "tide_acme_open_connections",
+        "gauge",
+        "Connections open, counted against the connection limit.",
+    );
+    out.sample(
+        "tide_acme_open_connections",
+        None,
+        connections.open_connections,
+    );
+    out.header(
+        "tide_acme_handshakes_in_progress",
+        "gauge",
+        "TLS handshakes in progress on the application's listeners.",
+    );
+    out.sample(
+        "tide_acme_handshakes_in_progress",
+        None,
+        connections.handshakes_in_progress,
+    );
+    out.header(
+        "tide_acme_throttled_connections",
+        "gauge",
+        "Connections held before their TLS handshake while the application is saturated.",
+    );
+    out.sample(
+        "tide_acme_throttled_connections",
+        None,
+        connections.throttled_connections,
+    );
+    out.header(
+        "tide_acme_refused_connections_total",
+        "counter",
+        "Connections refused under the listener limits.",
+    );
+    out.sample(
+        "tide_acme_refused_connections_total",
+        None,
+        connections.refused_connections,
+    );
+    out.header(
+        "tide_acme_rejected_handshakes_total",
+        "counter",
+        "Connections rejected by the handshake filter.",
+    );
+    out.sample(
+        "tide_acme_rejected_handshakes_total",
+        None,
+        connections.rejected_handshakes,
+    );
+    out.header(
+        "tide_acme_timed_out_handshakes_total",
+        "counter",
+        "Connections closed for not completing their TLS handshake in time.",
+    );
+    out.sample(
+        "tide_acme_timed_out_handshakes_total",
+        None,
+        connections.timed_out_handshakes,
+    );
+    out.header(
+        "tide_acme_accept_errors_total",
+        "counter",
+        "Errors accepting connections on the sockets answering validation requests.",
+    );
+    out.sample(
+        "tide_acme_accept_errors_total",
+        None,
+        connections.accept_errors,
+    );
+
+    let protocols = handle.protocol_stats();
+    out.header(
+        "tide_acme_tls_versions_total",
+        "counter",
+        "TLS handshakes completed, by negotiated TLS version.",
+    );
+    for (version, n) in &protocols.versions {
+        out.sample(
+            "tide_acme_tls_versions_total",
+            Some(("version", version)),
+            n,
+        );
+    }
+    out.header(
+        "tide_acme_tls_alpn_protocols_total",
+        "counter",
+        "TLS handshakes completed, by application protocol negotiated via ALPN, empty for none.",
+    );
+    for (protocol, n) in &protocols.alpn_protocols {
+        out.sample(
+            "tide_acme_tls_alpn_protocols_total",
+            Some(("protocol", protocol.as_deref().unwrap_or(""))),
+            n,
+        );
+    }
+    out.header(
+        "tide_acme_tls_cipher_suites_total",
+        "counter",
+        "TLS handshakes completed, by negotiated cipher suite.",
+    );
+    for (suite, n) in &protocols.cipher_suites {
+        out.sample(
+            "tide_acme_tls_cipher_suites_total",
+            Some(("cipher_suite", suite)),
+            n,
+        );
+    }
+    out.out
+}
+
+/// Metrics being rendered, labelled with the acceptor's name if it has one.
+struct Samples {
+    out: String,
+    acceptor: Option<String>,
+}
+
+impl Samples {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, label: Option<(&str, &str)>, value: impl Display) {
+        let labels: Vec<String> = self
+            .acceptor
+            .as_deref()
+            .map(|acceptor| ("acceptor", acceptor))
+            .into_iter()
+            .chain(label)
+            .map(|(key, value)| format!("{}=\"{}\"", key, escape(value)))
+            .collect();
+        let _ = match labels.is_empty() {
+            true => writeln!(self.out, "{} {}", name, value),
+            false => writeln!(self.out, "{}{{{}}} {}", name, labels.join(","), value),
+        };
+    }
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Tide endpoint serving certificate lifecycle metrics for Prometheus to scrape; see
+/// [`AcmeHandle::prometheus_metrics`].
+///
+/// The metrics name the managed domains, so serve them on an internal listener.
+///
+/// ```no_run
+/// # fn example(handle: tide_acme::AcmeHandle) {
+/// let mut app = tide::new();
+/// app.at("/metrics").get(tide_acme::Metrics::new(handle));
+/// # }
+/// ```
+pub struct Metrics {
+    handle: AcmeHandle,
+}
+
+impl Metrics {
+    /// Create an endpoint serving the metrics of `handle`.
+    pub fn new(handle: AcmeHandle) -> Self {
+        Self { handle }
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Endpoint<State> for Metrics {
+    async fn call(&self, _req: Request<State>) -> tide::Result {
+        Ok(Response::builder(StatusCode::Ok)
+            .content_type("text/plain; version=0.0.4")
+            .body(render(&self.handle))
+            .build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::time::Duration;
+
+    use async_std::task::block_on;
+
+    use super::*;
+    use crate::snapshot::CertOrigin;
+    use crate::state::{Event, EventOk};
+    use crate::{AcmeConfig, AcmeEvent, ListenerOptions};
+
+    #[test]
+    fn renders_prometheus_metrics() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let (index, _) =
+            handle
+                .registry
+                .register(vec!["a.example".into()], 0, CertOrigin::Configured);
+        let not_after = SystemTime::now() + Duration::from_secs(60 * 60 + 30);
+        handle
+            .registry
+            .update(index, |status| status.not_after = Some(not_after));
+        let event: Event<Infallible, Infallible> = Ok(EventOk::DeployedNewCert);
+        for _ in 0..2 {
+            handle
+                .registry
+                .publish(AcmeEvent::new(&["a.example".to_string()], None, &event));
+        }
+        let metrics = handle.prometheus_metrics();
+        let lines: Vec<&str> = metrics.lines().collect();
+        assert!(lines.contains(&"# TYPE tide_acme_certificates_obtained_total counter"));
+        assert!(lines.contains(&"tide_acme_certificates_obtained_total 2"));
+        assert!(lines.contains(&"tide_acme_renewal_failures_total 0"));
+        assert!(lines.contains(&"tide_acme_expiry_alarms 0"));
+        assert!(lines.contains(&"tide_acme_events_total{event_type=\"deployed_new_cert\"} 2"));
+        assert!(lines.contains(&"tide_acme_certificate_expiry_seconds{domain=\"a.example\"} 3629"));
+
+        let mut app = tide::new();
+        app.at("/metrics").get(Metrics::new(handle));
+        let req = http_types::Request::new(
+            http_types::Method::Get,
+            http_types::Url::parse("http://localhost/metrics").unwrap(),
+        );
+        let mut res: http_types::Response = block_on(app.respond(req)).unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(block_on(res.body_string())
+            .unwrap()
+            .contains("tide_acme_events_total"));
+    }
+
+    #[test]
+    fn labels_samples_with_the_acceptor_name() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new())
+            .name("public")
+            .state()
+            .handle();
+        let event: Event<Infallible, Infallible> = Ok(EventOk::DeployedNewCert);
+        handle
+            .registry
+            .publish(AcmeEvent::new(&["a.example".to_string()], None, &event));
+        let metrics = handle.prometheus_metrics();
+        let lines: Vec<&str> = metrics.lines().collect();
+        assert!(lines.contains(&"tide_acme_certificates_obtained_total{acceptor=\"public\"} 1"));
+        assert!(lines.contains(
+            &"tide_acme_events_total{acceptor=\"public\",event_type=\"deployed_new_cert\"} 1"
+        ));
+    }
+
+    #[test]
+    fn exports_connection_stats() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let connections = &handle.registry.connections;
+        let _open = connections.open(&ListenerOptions::new()).unwrap();
+        connections.refuse();
+        connections.record_accept_error();
+        let metrics = handle.prometheus_metrics();
+        let lines: Vec<&str> = metrics.lines().collect();
+        assert!(lines.contains(&"# TYPE tide_acme_open_connections gauge"));
+        assert!(lines.contains(&"tide_acme_open_connections 1"));
+        assert!(lines.contains(&"tide_acme_handshakes_in_progress 0"));
+        assert!(lines.contains(&"tide_acme_refused_connections_total 1"));
+        assert!(lines.contains(&"tide_acme_timed_out_handshakes_total 0"));
+        assert!(lines.contains(&"tide_acme_accept_errors_total 1"));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -0,0 +1,315 @@
+//! A cache in S3-compatible object storage, for deployments without persistent volumes.
+
+use std::io;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use async_compat::Compat;
+use async_trait::async_trait;
+use object_store::aws::{AmazonS3, AmazonS3Builder};
+use object_store::path::Path;
+use object_store::ObjectStore;
+use rustls_acme::{AccountCache, CertCache};
+
+use crate::dir_cache::PrivateDirCache;
+
+/// How objects are encrypted at rest by the storage service; see
+/// [`ObjectStoreCache::server_side_encryption`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ServerSideEncryption {
+    /// Leave it to the bucket's default encryption.
+    #[default]
+    BucketDefault,
+    /// Encrypt with keys managed by the storage service (`AES256`).
+    S3,
+    /// Encrypt with a key managed by AWS KMS: the one with this ID or ARN, or the account's
+    /// default key for S3 if `None`.
+    Kms(Option<String>),
+}
+
+/// Cache for certificates and account keys in an S3-compatible bucket, such as AWS S3, MinIO or
+/// Cloudflare R2, so that containers without persistent volumes on Kubernetes or ECS keep their
+/// account and certificates across restarts, and share them between replicas. Requires the
+/// `object-store` feature.
+///
+/// Objects are named like the files of a [`PrivateDirCache`], under a prefix, `tide-acme/` by
+/// default. Requests are made with the `object_store` crate, signed with the credentials set with
+/// [`credentials`](Self::credentials), or else found as the AWS SDKs find them: in the
+/// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN` environment variables,
+/// through web identity federation, or from the ECS task role or EC2 instance role.
+///
+/// The objects hold private keys, so keep the bucket private, and only grant the server
+/// `s3:GetObject` and `s3:PutObject` on the prefix.
+///
+/// ```no_run
+/// use tide_acme::{AcmeConfig, ObjectStoreCache, ServerSideEncryption};
+///
+/// let config = AcmeConfig::new(vec!["domain.example"]).cache(
+///     ObjectStoreCache::new("example-tls-cache", "eu-west-1")
+///         .prefix("production/tide-acme/")
+///         .server_side_encryption(ServerSideEncryption::Kms(None)),
+/// );
+/// ```
+#[derive(Clone)]
+pub struct ObjectStoreCache {
+    endpoint: Option<String>,
+    bucket: String,
+    region: String,
+    credentials: Option<Credentials>,
+    prefix: String,
+    encryption: ServerSideEncryption,
+    /// The client, built on first use, so that it keeps credentials it fetches.
+    store: Arc<Mutex<Option<Arc<AmazonS3>>>>,
+}
+
+#[derive(Clone)]
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl std::fmt::Debug for ObjectStoreCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ObjectStoreCache")
+            .field("endpoint", &self.endpoint)
+            .field("bucket", &self.bucket)
+            .field("region", &self.region)
+            .field("prefix", &self.prefix)
+            .field("encryption", &self.encryption)
+            .finish()
+    }
+}
+
+impl ObjectStoreCache {
+    /// Create a cache in `bucket`, in the AWS S3 region `region`.
+    pub fn new(bucket: impl AsRef<str>, region: impl AsRef<str>) -> Self {
+        Self {
+            endpoint: None,
+            bucket: bucket.as_ref().into(),
+            region: region.as_ref().into(),
+            credentials: None,
+            prefix: "tide-acme/".into(),
+            encryption: ServerSideEncryption::default(),
+            store: Arc::default(),
+        }
+    }
+
+    /// Send requests to the S3-compatible service at `endpoint`, such as
+    /// `https://minio.internal:9000`, rather than AWS. Buckets are addressed by path, which such
+    /// services support. The endpoint must use HTTPS.
+    pub fn endpoint(mut self, endpoint: impl AsRef<str>) -> Self {
+        self.endpoint = Some(endpoint.as_ref().trim_end_matches('/').into());
+        self.store = Arc::default();
+        self
+    }
+
+    /// Sign requests with these credentials, rather than those found in the environment.
+    pub fn credentials(
+        mut self,
+        access_key_id: impl AsRef<str>,
+        secret_access_key: impl AsRef<str>,
+        session_token: Option<String>,
+    ) -> Self {
+        self.credentials = Some(Credentials {
+            access_key_id: access_key_id.as_ref().into(),
+            secret_access_key: secret_access_key.as_ref().into(),
+            session_token,
+        });
+        self.store = Arc::default();
+        self
+    }
+
+    /// Prefix the object names with `prefix`, such as to keep the caches of several deployments
+    /// in one bucket. Defaults to `tide-acme/`.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Ask the service to encrypt the objects stored with `encryption`. Defaults to the bucket's
+    /// default encryption.
+    pub fn server_side_encryption(mut self, encryption: ServerSideEncryption) -> Self {
+        self.encryption = encryption;
+        self.store = Arc::default();
+        self
+    }
+
+    /// The client, built on first use.
+    fn store(&self) -> io::Result<Arc<AmazonS3>> {
+        let mut store = self.store.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(store) = &*store {
+            return Ok(store.clone());
+        }
+        let built = Arc::new(self.builder().build().map_err(io::Error::other)?);
+        *store = Some(built.clone());
+        Ok(built)
+    }
+
+    fn builder(&self) -> AmazonS3Builder {
+        let mut builder = AmazonS3Builder::from_env()
+            .with_bucket_name(&self.bucket)
+            .with_region(&self.region);
+        if let Some(endpoint) = &self.endpoint {
+            builder = builder.with_endpoint(endpoint);
+        }
+        if let Some(credentials) = &self.credentials {
+            builder = builder
+                .with_access_key_id(&credentials.access_key_id)
+                .with_secret_access_key(&credentials.secret_access_key);
+            if let Some(token) = &credentials.session_token {
+                builder = builder.with_token(token);
+            }
+        }
+        let sse = "aws_server_side_encryption"
+            .parse()
+            .expect("valid config key");
+        match &self.encryption {
+            ServerSideEncryption::BucketDefault => builder,
+            ServerSideEncryption::S3 => builder.with_config(sse, "AES256"),
+            ServerSideEncryption::Kms(Some(key_id)) => builder.with_sse_kms_encryption(key_id),
+            ServerSideEncryption::Kms(None) => builder.with_config(sse, "aws:kms"),
+        }
+    }
+
+    fn path(&self, name: &str) -> Path {
+        Path::from(format!("{}{}", self.prefix, name))
+    }
+
+    async fn get(&self, name: String) -> io::Result<Option<Vec<u8>>> {
+        let store = self.store()?;
+        let path = self.path(&name);
+        // The client runs on tokio, so run it in a tokio context.
+        let result = Compat::new(async {
+            let result = store.get(&path).await?;
+            result.bytes().await
+        })
+        .await;
+        match result {
+            Ok(bytes) => Ok(Some(bytes.to_vec())),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(err) => Err(io::Error::other(err)),
+        }
+    }
+
+    async fn put(&self, name: String, contents: &[u8]) -> io::Result<()> {
+        let store = self.store()?;
+        let path = self.path(&name);
+        Compat::new(store.put(&path, contents.to_vec().into()))
+            .await
+            .map_err(io::Error::other)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CertCache for ObjectStoreCache {
+    type EC = io::Error;
+
+    async fn load_cert(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+    ) -> Result<Option<Vec<u8>>, Self::EC> {
+        self.get(PrivateDirCache::file_name(
+            "cached_cert",
+            domains,
+            directory_url,
+        ))
+        .await
+    }
+
+    async fn store_cert(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+        cert: &[u8],
+    ) -> Result<(), Self::EC> {
+        let name = PrivateDirCache::file_name("cached_cert", domains, directory_url);
+        self.put(name, cert).await
+    }
+}
+
+#[async_trait]
+impl AccountCache for ObjectStoreCache {
+    type EA = io::Error;
+
+    async fn load_account(
+        &self,
+        contact: &[String],
+        directory_url: &str,
+    ) -> Result<Option<Vec<u8>>, Self::EA> {
+        self.get(PrivateDirCache::file_name(
+            "cached_account",
+            contact,
+            directory_url,
+        ))
+        .await
+    }
+
+    async fn store_account(
+        &self,
+        contact: &[String],
+        directory_url: &str,
+        account: &[u8],
+    ) -> Result<(), Self::EA> {
+        let name = PrivateDirCache::file_name("cached_account", contact, directory_url);
+        self.put(name, account).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use object_store::aws::AmazonS3ConfigKey;
+
+    use super::*;
+
+    fn config(cache: &ObjectStoreCache, key: &str) -> Option<String> {
+        let key: AmazonS3ConfigKey = key.parse().unwrap();
+        cache.builder().get_config_value(&key)
+    }
+
+    #[test]
+    fn configures_the_client() {
+        let cache = ObjectStoreCache::new("bucket", "eu-west-1")
+            .endpoint("https://minio.internal:9000/")
+            .credentials("key-id", "secret", Some("token".into()));
+        assert_eq!(config(&cache, "aws_bucket").as_deref(), Some("bucket"));
+        assert_eq!(config(&cache, "aws_region").as_deref(), Some("eu-west-1"));
+        let endpoint = config(&cache, "aws_endpoint");
+        assert_eq!(endpoint.as_deref(), Some("https://minio.internal:9000"));
+        assert_eq!(
+            config(&cache, "aws_access_key_id").as_deref(),
+            Some("key-id")
+        );
+        assert_eq!(
+            config(&cache, "aws_session_token").as_deref(),
+            Some("token")
+        );
+        assert!(cache.store().is_ok());
+    }
+
+    #[test]
+    fn configures_encryption() {
+        let cache = ObjectStoreCache::new("bucket", "eu-west-1");
+        let sse = |cache: &ObjectStoreCache| config(cache, "aws_server_side_encryption");
+        assert_eq!(sse(&cache), None);
+        let cache = cache.server_side_encryption(ServerSideEncryption::S3);
+        assert_eq!(sse(&cache).as_deref(), Some("AES256"));
+        let cache = cache.server_side_encryption(ServerSideEncryption::Kms(None));
+        assert_eq!(sse(&cache).as_deref(), Some("aws:kms"));
+        assert_eq!(config(&cache, "aws_sse_kms_key_id"), None);
+        let key_id = Some("alias/tls".to_string());
+        let cache = cache.server_side_encryption(ServerSideEncryption::Kms(key_id));
+        assert_eq!(sse(&cache).as_deref(), Some("aws:kms"));
+        let key_id = config(&cache, "aws_sse_kms_key_id");
+        assert_eq!(key_id.as_deref(), Some("alias/tls"));
+    }
+
+    #[test]
+    fn names_objects_under_the_prefix() {
+        let cache = ObjectStoreCache::new("bucket", "eu-west-1").prefix("production/tide-acme/");
+        let path = cache.path("cached_cert_abc");
+        assert_eq!(path.as_ref(), "production/tide-acme/cached_cert_abc");
+    }
+}
@@ -0,0 +1,569 @@
+//! OCSP stapling: fetching the CA's signed statement that a certificate is not revoked, to send
+//! along with it in handshakes, and requesting certificates that require it.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use http_types::{Method, Request};
+use rcgen::{
+    RcgenError, SignatureAlgorithm, PKCS_ECDSA_P256_SHA256, PKCS_ECDSA_P384_SHA384, PKCS_ED25519,
+    PKCS_RSA_SHA256, PKCS_RSA_SHA384, PKCS_RSA_SHA512,
+};
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use ring::rand::SystemRandom;
+use ring::signature::{
+    EcdsaKeyPair, Ed25519KeyPair, RsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING,
+    ECDSA_P384_SHA384_ASN1_SIGNING, RSA_PKCS1_SHA256, RSA_PKCS1_SHA384, RSA_PKCS1_SHA512,
+};
+use thiserror::Error;
+use x509_parser::error::X509Error;
+use x509_parser::extensions::{GeneralName, ParsedExtension};
+use x509_parser::parse_x509_certificate;
+use x509_parser::prelude::FromDer;
+use x509_parser::time::ASN1Time;
+
+use crate::der::{der_element, der_encode};
+use crate::https::{send, HttpsRequestError};
+
+/// DER encoding of the OID for OCSP in the authority information access extension,
+/// 1.3.6.1.5.5.7.48.1.
+const OCSP_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01];
+
+/// The DER-encoded AlgorithmIdentifier for SHA-1, which responders universally support for
+/// identifying certificates.
+const SHA1_ALGORITHM: &[u8] = &[
+    0x30, 0x09, 0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a, 0x05, 0x00,
+];
+
+/// DER encoding of the OID for the PKCS #9 extension request attribute of a CSR,
+/// 1.2.840.113549.1.9.14.
+const EXTENSION_REQUEST_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0e];
+
+/// The DER-encoded TLS feature extension (RFC 7633) listing status_request, which makes a
+/// certificate must-staple.
+const MUST_STAPLE_EXTENSION: &[u8] = &[
+    0x30, 0x11, 0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x18, 0x04, 0x05, 0x30, 0x03,
+    0x02, 0x01, 0x05,
+];
+
+/// DER encoding of the OID for the TLS feature extension, 1.3.6.1.5.5.7.1.24.
+const TLS_FEATURE_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x18];
+
+/// The contents of the DER-encoded TLS feature number of status_request.
+const STATUS_REQUEST: &[u8] = &[0x05];
+
+/// How long after a failed or unusable fetch the response is fetched again.
+pub(crate) const OCSP_RETRY: Duration = Duration::from_secs(60 * 60);
+
+/// How long a response without a next update time is used before being fetched again.
+const OCSP_DEFAULT_REFRESH: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// The least time between fetches, in case a responder sends responses that are already stale.
+const OCSP_MIN_REFRESH: Duration = Duration::from_secs(5 * 60);
+
+/// A good OCSP response for a certificate.
+pub(crate) struct OcspResponse {
+    /// The DER-encoded OCSPResponse, to staple.
+    pub(crate) der: Vec<u8>,
+    /// When to fetch a new response: halfway between the response's update times.
+    pub(crate) refresh_at: SystemTime,
+    /// When the response expires, its next update time, if it has one.
+    pub(crate) expires_at: Option<SystemTime>,
+}
+
+/// What to do with the stapled OCSP response once it expires without a fresh one having been
+/// fetched; see [`AcmeConfig::stale_ocsp_policy`](crate::AcmeConfig::stale_ocsp_policy).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum StaleOcspPolicy {
+    /// Serve the certificate without a staple. This is the default.
+    #[default]
+    ServeWithoutStaple,
+    /// Go on stapling the expired response for up to this long, then serve the certificate
+    /// without a staple.
+    ServeStale(Duration),
+    /// Stop serving must-staple certificates, so handshakes for their names fail rather than
+    /// present a certificate clients honoring must-staple reject; serve other certificates
+    /// without a staple.
+    FailMustStaple,
+}
+
+/// Fetch an OCSP response for the leaf of `chain`, issued by the next certificate, from the
+/// responder the leaf names.
+///
+/// The response's signature is not verified: clients verify it in the handshake, and stapling a
+/// bad response does no more harm than stapling none.
+pub(crate) async fn fetch(chain: &[Vec<u8>]) -> Result<OcspResponse, OcspError> {
+    let (leaf, issuer) = match chain {
+        [leaf, issuer, ..] => (leaf, issuer),
+        _ => return Err(OcspError::NoIssuer),
+    };
+    let (_, leaf) = parse_x509_certificate(leaf)?;
+    let (_, issuer) = parse_x509_certificate(issuer)?;
+    let url = leaf
+        .extensions()
+        .iter()
+        .find_map(|extension| match extension.parsed_extension() {
+            ParsedExtension::AuthorityInfoAccess(access) => access.iter().find_map(|desc| {
+                match (desc.access_method.as_bytes(), &desc.access_location) {
+                    (OCSP_OID, GeneralName::URI(url)) => Some(url.to_string()),
+                    _ => None,
+                }
+            }),
+            _ => None,
+        })
+        .ok_or(OcspError::NoResponder)?;
+
+    let serial = leaf.tbs_certificate.raw_serial();
+    let name_hash = digest(&SHA1_FOR_LEGACY_USE_ONLY, leaf.issuer().as_raw());
+    let key_hash = digest(
+        &SHA1_FOR_LEGACY_USE_ONLY,
+        issuer.public_key().subject_public_key.data,
+    );
+    let cert_id = [
+        SHA1_ALGORITHM,
+        &der_encode(0x04, name_hash.as_ref()),
+        &der_encode(0x04, key_hash.as_ref()),
+        &der_encode(0x02, serial),
+    ]
+    .concat();
+    // The CertID, in a Request, in the request list, in the TBSRequest, in the OCSPRequest.
+    let request = (0..5).fold(cert_id, |der, _| der_encode(0x30, &der));
+
+    let mut http = Request::new(Method::Post, url.as_str());
+    http.set_body(request);
+    http.set_content_type("application/ocsp-request".parse()?);
+    let der = send(http).await?.body_bytes().await?;
+    let (status, this_update, next_update) =
+        single_response(&der, serial).ok_or(OcspError::Malformed)?;
+    match status {
+        0x80 => {}
+        0xa1 => return Err(OcspError::Revoked),
+        _ => return Err(OcspError::Unknown),
+    }
+    let refresh_at = match next_update {
+        Some(next_update) => {
+            this_update + next_update.duration_since(this_update).unwrap_or_default() / 2
+        }
+        None => this_update + OCSP_DEFAULT_REFRESH,
+    };
+    let refresh_at = refresh_at.max(SystemTime::now() + OCSP_MIN_REFRESH);
+    Ok(OcspResponse {
+        der,
+        refresh_at,
+        expires_at: next_update,
+    })
+}
+
+/// Whether the DER-encoded certificate `leaf` is must-staple: carries the TLS feature extension
+/// listing status_request.
+pub(crate) fn is_must_staple(leaf: &[u8]) -> bool {
+    let leaf = match parse_x509_certificate(leaf) {
+        Ok((_, leaf)) => leaf,
+        Err(_) => return false,
+    };
+    leaf.extensions()
+        .iter()
+        .filter(|extension| extension.oid.as_bytes() == TLS_FEATURE_OID)
+        .any(|extension| {
+            let mut features = match der_element(extension.value) {
+                Some((features, _)) if features.tag == 0x30 => features.contents,
+                _ => return false,
+            };
+            while let Some((feature, rest)) = der_element(features) {
+                if feature.tag == 0x02 && feature.contents == STATUS_REQUEST {
+                    return true;
+                }
+                features = rest;
+            }
+            false
+        })
+}
+
+/// The status tag, thisUpdate and nextUpdate of the single response for the certificate with
+/// serial number `serial` in the OCSPResponse `der`.
+fn single_response(der: &[u8], serial: &[u8]) -> Option<(u8, SystemTime, Option<SystemTime>)> {
+    let (response, _) = der_element(der)?;
+    let (status, rest) = der_element(response.contents)?;
+    // Anything but successful(0) carries no response.
+    if status.tag != 0x0a || status.contents != [0] {
+        return None;
+    }
+    let (bytes, _) = der_element(rest)?;
+    let (bytes, _) = der_element(bytes.contents)?;
+    let (_response_type, rest) = der_element(bytes.contents)?;
+    let (basic, _) = der_element(rest)?;
+    let (basic, _) = der_element(basic.contents)?;
+    let (data, _) = der_element(basic.contents)?;
+
+    let mut fields = data.contents;
+    // Skip the optional version, the responder ID and producedAt, to the responses.
+    let mut responses = loop {
+        let (field, rest) = der_element(fields)?;
+        if field.tag == 0x30 {
+            break field.contents;
+        }
+        fields = rest;
+    };
+    while !responses.is_empty() {
+        let (single, rest) = der_element(responses)?;
+        responses = rest;
+        let (cert_id, rest) = der_element(single.contents)?;
+        let (_, id_rest) = der_element(cert_id.contents)?;
+        let (_, id_rest) = der_element(id_rest)?;
+        let (_, id_rest) = der_element(id_rest)?;
+        let (id_serial, _) = der_element(id_rest)?;
+        if id_serial.contents != serial {
+            continue;
+        }
+        let (status, rest) = der_element(rest)?;
+        let (this_update, rest) = der_element(rest)?;
+        let next_update = match der_element(rest) {
+            Some((next, _)) if next.tag == 0xa0 => Some(time(next.contents)?),
+            _ => None,
+        };
+        return Some((status.tag, time(this_update.element)?, next_update));
+    }
+    None
+}
+
+fn time(der: &[u8]) -> Option<SystemTime> {
+    let (_, time) = ASN1Time::from_der(der).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(time.timestamp().max(0) as u64))
+}
+
+/// Errors fetching OCSP responses.
+#[derive(Error, Debug)]
+pub enum OcspError {
+    /// The certificate chain has no issuer certificate to identify the certificate by.
+    #[error("the certificate chain has no issuer")]
+    NoIssuer,
+    /// The certificate names no OCSP responder.
+    #[error("the certificate names no OCSP responder")]
+    NoResponder,
+    /// A certificate couldn't be parsed.
+    #[error("could not parse certificate: {0}")]
+    Parse(#[from] x509_parser::nom::Err<X509Error>),
+    /// The request to the responder failed.
+    #[error(transparent)]
+    Https(#[from] HttpsRequestError),
+    /// The responder sent no usable response for the certificate.
+    #[error("malformed or unsuccessful OCSP response")]
+    Malformed,
+    /// The responder reports the certificate revoked.
+    #[error("the certificate is revoked")]
+    Revoked,
+    /// The responder doesn't know the certificate.
+    #[error("the responder does not know the certificate")]
+    Unknown,
+}
+
+impl From<http_types::Error> for OcspError {
+    fn from(e: http_types::Error) -> Self {
+        Self::Https(e.into())
+    }
+}
+
+/// Add the must-staple extension to the extensions requested by `csr`, signing it again with the
+/// PKCS #8 key `key` using `alg`, the algorithm the CSR was signed with.
+pub(crate) fn must_staple_csr(
+    csr: &[u8],
+    key: &[u8],
+    alg: &SignatureAlgorithm,
+) -> Result<Vec<u8>, RcgenError> {
+    let malformed = || RcgenError::CouldNotParseCertificationRequest;
+    let (request, _) = der_element(csr).ok_or_else(malformed)?;
+    let (info, rest) = der_element(request.contents).ok_or_else(malformed)?;
+    let (signature_alg, _) = der_element(rest).ok_or_else(malformed)?;
+
+    let mut fields = info.contents;
+    let mut out = vec![];
+    let mut attributes = vec![];
+    let mut requested = false;
+    while !fields.is_empty() {
+        let (field, rest) = der_element(fields).ok_or_else(malformed)?;
+        fields = rest;
+        if field.tag != 0xa0 {
+            out.extend_from_slice(field.element);
+            continue;
+        }
+        let mut existing = field.contents;
+        while !existing.is_empty() {
+            let (attribute, rest) = der_element(existing).ok_or_else(malformed)?;
+            existing = rest;
+            let (oid, values) = der_element(attribute.contents).ok_or_else(malformed)?;
+            if oid.contents != EXTENSION_REQUEST_OID {
+                attributes.extend_from_slice(attribute.element);
+                continue;
+            }
+            let (values, _) = der_element(values).ok_or_else(malformed)?;
+            let (extensions, _) = der_element(values.contents).ok_or_else(malformed)?;
+            let extensions = [extensions.contents, MUST_STAPLE_EXTENSION].concat();
+            attributes.extend(extension_request(&extensions));
+            requested = true;
+        }
+    }
+    if !requested {
+        attributes.extend(extension_request(MUST_STAPLE_EXTENSION));
+    }
+    out.extend(der_encode(0xa0, &attributes));
+    let info = der_encode(0x30, &out);
+
+    let ecdsa = if alg == &PKCS_ECDSA_P256_SHA256 {
+        Some(&ECDSA_P256_SHA256_ASN1_SIGNING)
+    } else if alg == &PKCS_ECDSA_P384_SHA384 {
+        Some(&ECDSA_P384_SHA384_ASN1_SIGNING)
+    } else {
+        None
+    };
+    let rsa = if alg == &PKCS_RSA_SHA256 {
+        Some(&RSA_PKCS1_SHA256)
+    } else if alg == &PKCS_RSA_SHA384 {
+        Some(&RSA_PKCS1_SHA384)
+    } else if alg == &PKCS_RSA_SHA512 {
+        Some(&RSA_PKCS1_SHA512)
+    } else {
+        None
+    };
+    let rng = SystemRandom::new();
+    let signature = if let Some(signing) = ecdsa {
+        let pair = EcdsaKeyPair::from_pkcs8(signing, key)?;
+        pair.sign(&rng, &info)?.as_ref().to_vec()
+    } else if let Some(padding) = rsa {
+        let pair = RsaKeyPair::from_pkcs8(key)?;
+        let mut signature = vec![0; pair.public_modulus_len()];
+        pair.sign(padding, &rng, &info, &mut signature)?;
+        signature
+    } else if alg == &PKCS_ED25519 {
+        let pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(key)?;
+        pair.sign(&info).as_ref().to_vec()
+    } else {
+        return Err(RcgenError::UnsupportedSignatureAlgorithm);
+    };
+    let signature = der_encode(0x03, &[&[0][..], &signature].concat());
+    Ok(der_encode(
+        0x30,
+        &[&info[..], signature_alg.element, &signature].concat(),
+    ))
+}
+
+/// An extension request attribute for the DER-encoded `extensions`.
+fn extension_request(extensions: &[u8]) -> Vec<u8> {
+    let oid = der_encode(0x06, EXTENSION_REQUEST_OID);
+    let values = der_encode(0x31, &der_encode(0x30, extensions));
+    der_encode(0x30, &[oid, values].concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::block_on;
+    use rcgen::{Certificate, CertificateParams, CustomExtension, KeyPair};
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1, ECDSA_P384_SHA384_ASN1};
+    use x509_parser::certification_request::X509CertificationRequest;
+
+    use super::*;
+
+    /// 2020-09-13T12:26:40Z, as a GeneralizedTime.
+    const THIS_UPDATE: &str = "20200913122640Z";
+    /// A week later.
+    const NEXT_UPDATE: &str = "20200920122640Z";
+
+    fn generalized_time(time: &str) -> Vec<u8> {
+        der_encode(0x18, time.as_bytes())
+    }
+
+    /// A SingleResponse for the certificate with serial number `serial`.
+    fn single(serial: &[u8], status: &[u8], next_update: Option<&str>) -> Vec<u8> {
+        let hash = der_encode(0x04, &[0; 20]);
+        let cert_id = [SHA1_ALGORITHM, &hash, &hash, &der_encode(0x02, serial)].concat();
+        let mut single = [
+            der_encode(0x30, &cert_id),
+            status.to_vec(),
+            generalized_time(THIS_UPDATE),
+        ]
+        .concat();
+        if let Some(next_update) = next_update {
+            single.extend(der_encode(0xa0, &generalized_time(next_update)));
+        }
+        der_encode(0x30, &single)
+    }
+
+    /// A successful OCSPResponse with the single responses `singles`, unsigned.
+    fn response(singles: &[Vec<u8>]) -> Vec<u8> {
+        let responder_id = der_encode(0xa2, &der_encode(0x04, &[0; 20]));
+        let data = [
+            responder_id,
+            generalized_time(THIS_UPDATE),
+            der_encode(0x30, &singles.concat()),
+        ]
+        .concat();
+        let basic = [
+            der_encode(0x30, &data),
+            der_encode(0x30, SHA1_ALGORITHM),
+            der_encode(0x03, &[0]),
+        ]
+        .concat();
+        let basic_type = [&[0x06, 0x09][..], OCSP_OID, &[0x01]].concat();
+        let bytes = [basic_type, der_encode(0x04, &der_encode(0x30, &basic))].concat();
+        let response = [
+            der_encode(0x0a, &[0]),
+            der_encode(0xa0, &der_encode(0x30, &bytes)),
+        ]
+        .concat();
+        der_encode(0x30, &response)
+    }
+
+    #[test]
+    fn reads_single_responses() {
+        let this_update = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let week = Duration::from_secs(7 * 24 * 60 * 60);
+        let good = response(&[single(&[1], &[0x80, 0x00], Some(NEXT_UPDATE))]);
+        assert_eq!(
+            single_response(&good, &[1]),
+            Some((0x80, this_update, Some(this_update + week)))
+        );
+        assert_eq!(single_response(&good, &[2]), None);
+
+        let revoked = der_encode(0xa1, &generalized_time(THIS_UPDATE));
+        let both = response(&[
+            single(&[1], &[0x80, 0x00], None),
+            single(&[2], &revoked, None),
+        ]);
+        assert_eq!(
+            single_response(&both, &[1]),
+            Some((0x80, this_update, None))
+        );
+        assert_eq!(
+            single_response(&both, &[2]),
+            Some((0xa1, this_update, None))
+        );
+    }
+
+    #[test]
+    fn rejects_unusable_responses() {
+        // tryLater(3), without a response.
+        let unsuccessful = der_encode(0x30, &der_encode(0x0a, &[3]));
+        assert_eq!(single_response(&unsuccessful, &[1]), None);
+        let good = response(&[single(&[1], &[0x80, 0x00], Some(NEXT_UPDATE))]);
+        for len in 0..good.len() {
+            assert_eq!(single_response(&good[..len], &[1]), None);
+        }
+    }
+
+    #[test]
+    fn needs_an_issuer_and_a_responder() {
+        let cert = rcgen::generate_simple_self_signed(vec!["domain.example".into()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        assert!(matches!(
+            block_on(fetch(std::slice::from_ref(&der))),
+            Err(OcspError::NoIssuer)
+        ));
+        assert!(matches!(
+            block_on(fetch(&[der.clone(), der])),
+            Err(OcspError::NoResponder)
+        ));
+        assert!(matches!(
+            block_on(fetch(&[b"junk".to_vec(), b"junk".to_vec()])),
+            Err(OcspError::Parse(_))
+        ));
+    }
+
+    /// The extensions `csr` requests: the OIDs of those x509-parser doesn't know, else their
+    /// debug representation.
+    fn requested_extensions(csr: &[u8]) -> Vec<String> {
+        let (_, request) = X509CertificationRequest::from_der(csr).unwrap();
+        let extensions = request.requested_extensions().into_iter().flatten();
+        extensions
+            .map(|extension| match extension {
+                ParsedExtension::UnsupportedExtension { oid } => oid.to_id_string(),
+                extension => format!("{:?}", extension),
+            })
+            .collect()
+    }
+
+    /// Whether `csr` is signed by the key `key_pair`.
+    fn verify(csr: &[u8], key_pair: &KeyPair) -> bool {
+        let algorithm = if key_pair.is_compatible(&PKCS_ECDSA_P256_SHA256) {
+            &ECDSA_P256_SHA256_ASN1
+        } else {
+            &ECDSA_P384_SHA384_ASN1
+        };
+        let (request, _) = der_element(csr).unwrap();
+        let (info, rest) = der_element(request.contents).unwrap();
+        let (_, rest) = der_element(rest).unwrap();
+        let (signature, _) = der_element(rest).unwrap();
+        UnparsedPublicKey::new(algorithm, key_pair.public_key_raw())
+            .verify(info.element, &signature.contents[1..])
+            .is_ok()
+    }
+
+    #[test]
+    fn requests_must_staple() {
+        let requests = [
+            (&PKCS_ECDSA_P256_SHA256, vec!["domain.example".to_string()]),
+            (&PKCS_ECDSA_P384_SHA384, vec![]),
+        ];
+        for (alg, names) in requests.iter() {
+            let mut params = CertificateParams::new(names.clone());
+            params.alg = alg;
+            let cert = Certificate::from_params(params).unwrap();
+            let csr = cert.serialize_request_der().unwrap();
+            let key = cert.serialize_private_key_der();
+            let staple = must_staple_csr(&csr, &key, alg).unwrap();
+
+            let mut extensions = requested_extensions(&csr);
+            extensions.push("1.3.6.1.5.5.7.1.24".into());
+            assert_eq!(requested_extensions(&staple), extensions);
+            assert_eq!(extensions.len(), names.len() + 1);
+            assert!(verify(&staple, cert.get_key_pair()));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let cert = rcgen::generate_simple_self_signed(vec!["domain.example".into()]).unwrap();
+        let csr = cert.serialize_request_der().unwrap();
+        let key = cert.serialize_private_key_der();
+        let alg = &PKCS_ECDSA_P256_SHA256;
+        assert!(must_staple_csr(&csr[..csr.len() / 2], &key, alg).is_err());
+        assert!(must_staple_csr(&csr, &key, &PKCS_ECDSA_P384_SHA384).is_err());
+        assert!(must_staple_csr(&csr, b"junk", alg).is_err());
+    }
+
+    fn cert(extensions: Vec<CustomExtension>) -> Vec<u8> {
+        let mut params = CertificateParams::new(vec!["domain.example".to_string()]);
+        params.custom_extensions = extensions;
+        Certificate::from_params(params)
+            .unwrap()
+            .serialize_der()
+            .unwrap()
+    }
+
+    fn tls_feature(features: &[u8]) -> CustomExtension {
+        let oid = [1, 3, 6, 1, 5, 5, 7, 1, 24];
+        CustomExtension::from_oid_content(&oid, features.to_vec())
+    }
+
+    #[test]
+    fn detects_must_staple_certs() {
+        assert!(is_must_staple(&cert(vec![tls_feature(&[
+            0x30, 0x03, 0x02, 0x01, 0x05
+        ])])));
+        assert!(is_must_staple(&cert(vec![tls_feature(&[
+            0x30, 0x06, 0x02, 0x01, 0x11, 0x02, 0x01, 0x05
+        ])])));
+    }
+
+    #[test]
+    fn ignores_other_certs() {
+        assert!(!is_must_staple(&cert(vec![])));
+        // Only status_request_v2.
+        assert!(!is_must_staple(&cert(vec![tls_feature(&[
+            0x30, 0x03, 0x02, 0x01, 0x11
+        ])])));
+        // A feature list whose length overruns the extension.
+        assert!(!is_must_staple(&cert(vec![tls_feature(&[
+            0x30, 0x05, 0x02, 0x01, 0x05
+        ])])));
+        assert!(!is_must_staple(b"not a certificate"));
+    }
+}
@@ -0,0 +1,28 @@
+use async_trait::async_trait;
+
+/// Decides which server names to obtain certificates for on demand, the first time a client asks
+/// for them via SNI.
+///
+/// Set with [`AcmeConfig::on_demand`](crate::AcmeConfig::on_demand), for multi-tenant servers
+/// whose customers point their own domains at the server at runtime. Without a policy checking
+/// names against the known customer domains, any client could make the server order certificates
+/// for names of its choosing, and use up the CA's rate limits.
+#[async_trait]
+pub trait DomainPolicy: Send + Sync + 'static {
+    /// Whether to obtain a certificate for `domain`, a normalized server name no managed
+    /// certificate covers yet.
+    ///
+    /// Approved names are managed like configured domains for the life of the process. Denied
+    /// names are asked about again the next time a client asks for them, so keep this cheap, such
+    /// as by caching lookups.
+    async fn approve(&self, domain: &str) -> bool;
+
+    /// Whether `domain` could be approved, checked during the handshake before the name is
+    /// queued to be [approved](Self::approve). Names this rejects aren't queued or tracked, so
+    /// clients sending made-up server names can't crowd out real ones. It must not block, such
+    /// as on a database lookup; by default, it accepts every name.
+    fn may_approve(&self, domain: &str) -> bool {
+        let _ = domain;
+        true
+    }
+}
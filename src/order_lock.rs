@@ -0,0 +1,97 @@
+//! Locks serializing ACME orders across processes sharing a cache.
+
+use std::fs::OpenOptions;
+use std::io;
+use std::path::PathBuf;
+
+use async_std::task::spawn_blocking;
+use async_trait::async_trait;
+use fs2::FileExt;
+
+/// A lock shared by the instances of a deployment, held while ordering a certificate and storing
+/// it in the shared cache, so that one instance orders each certificate while the others wait
+/// and then deploy it from the cache.
+///
+/// Set with [`AcmeConfig::lock`](crate::AcmeConfig::lock). [`FileLock`] serves processes on one
+/// host, and `RedisLock`, with the `redis` feature, instances sharing a Redis server; implement
+/// this trait for other coordination services. An error fails the order, which is retried with
+/// backoff like other failures.
+#[async_trait]
+pub trait Lock: Send + Sync + 'static {
+    /// Wait until the lock is free, and take it. The lock is held until the guard is dropped.
+    async fn acquire(&self) -> io::Result<LockGuard>;
+}
+
+/// A held [`Lock`], released when dropped.
+pub struct LockGuard {
+    _release: Box<dyn Send + Sync>,
+}
+
+impl std::fmt::Debug for LockGuard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LockGuard").finish_non_exhaustive()
+    }
+}
+
+impl LockGuard {
+    /// Create a guard holding `release`, whose `Drop` implementation releases the lock.
+    pub fn new(release: impl Send + Sync + 'static) -> Self {
+        Self {
+            _release: Box::new(release),
+        }
+    }
+}
+
+/// An exclusive lock on a file, for processes on one host, or sharing a filesystem that supports
+/// file locks, such as the one holding a [`PrivateDirCache`](crate::PrivateDirCache) directory.
+/// The file is created if necessary.
+#[derive(Clone, Debug)]
+pub struct FileLock {
+    path: PathBuf,
+}
+
+impl FileLock {
+    /// Create a lock on the file at `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl Lock for FileLock {
+    async fn acquire(&self) -> io::Result<LockGuard> {
+        let path = self.path.clone();
+        spawn_blocking(move || {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            file.lock_exclusive()?;
+            Ok(LockGuard::new(file))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::block_on;
+
+    use super::*;
+
+    #[test]
+    fn locks_out_other_holders() {
+        let path = std::env::temp_dir().join(format!("tide-acme-lock-{}", std::process::id()));
+        let lock = FileLock::new(&path);
+        let guard = block_on(lock.acquire()).unwrap();
+        let other = std::fs::File::open(&path).unwrap();
+        assert!(other.try_lock_exclusive().is_err());
+        drop(guard);
+        other.try_lock_exclusive().unwrap();
+        other.unlock().unwrap();
+        drop(block_on(lock.acquire()).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+}
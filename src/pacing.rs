@@ -0,0 +1,103 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use async_std::task;
+use tracing::info;
+
+/// Spaces out new ACME orders to a configured rate, so a bulk import of domains doesn't trip the
+/// CA's rate limits on new orders.
+#[derive(Default)]
+pub(crate) struct OrderPacer {
+    /// The time between orders, or zero to place them as they come.
+    interval: Mutex<Duration>,
+    /// When the next order may be placed.
+    next: Mutex<Option<Instant>>,
+    waiting: AtomicUsize,
+    placed: AtomicU64,
+}
+
+impl OrderPacer {
+    pub(crate) fn set_rate(&self, orders: u32, per: Duration) {
+        *self.interval.lock().unwrap() = per / orders.max(1);
+    }
+
+    /// Wait for the next slot to place an order in.
+    pub(crate) async fn wait(&self) {
+        let interval = *self.interval.lock().unwrap();
+        if !interval.is_zero() {
+            let now = Instant::now();
+            let slot = {
+                let mut next = self.next.lock().unwrap();
+                let slot = next.map_or(now, |next| next.max(now));
+                *next = Some(slot + interval);
+                slot
+            };
+            if slot > now {
+                let waiting = self.waiting.fetch_add(1, Ordering::Relaxed) + 1;
+                info!(
+                    waiting,
+                    delay = ?(slot - now),
+                    "pacing orders; waiting for the next slot"
+                );
+                task::sleep(slot - now).await;
+                self.waiting.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+        self.placed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The number of orders waiting for a slot.
+    pub(crate) fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+
+    /// The number of orders placed since startup.
+    pub(crate) fn placed(&self) -> u64 {
+        self.placed.load(Ordering::Relaxed)
+    }
+
+    /// When the next order may be placed, if orders are paced and that's in the future.
+    pub(crate) fn next_slot(&self) -> Option<SystemTime> {
+        let next = (*self.next.lock().unwrap())?;
+        let remaining = next.checked_duration_since(Instant::now())?;
+        Some(SystemTime::now() + remaining)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::block_on;
+    use futures::FutureExt;
+
+    use super::*;
+
+    #[test]
+    fn places_orders_freely_without_a_rate() {
+        let pacer = OrderPacer::default();
+        for _ in 0..3 {
+            assert!(pacer.wait().now_or_never().is_some());
+        }
+        assert_eq!(pacer.placed(), 3);
+        assert_eq!(pacer.next_slot(), None);
+    }
+
+    #[test]
+    fn paces_orders_to_the_rate() {
+        let pacer = OrderPacer::default();
+        pacer.set_rate(10, Duration::from_secs(1));
+        let started = Instant::now();
+        // The first order goes out right away, and each further one a tenth of a second later.
+        assert!(pacer.wait().now_or_never().is_some());
+        assert!(pacer.next_slot().is_some());
+        block_on(async {
+            let mut second = Box::pin(pacer.wait());
+            assert!(futures::poll!(&mut second).is_pending());
+            assert_eq!(pacer.waiting(), 1);
+            second.await;
+        });
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(pacer.waiting(), 0);
+        assert_eq!(pacer.placed(), 2);
+    }
+}
@@ -0,0 +1,201 @@
+//! Checking that the CA's tls-alpn-01 validation connections would reach this server, before
+//! placing an order that would otherwise fail validation with a terse problem.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_std::future::timeout;
+use async_std::net::{TcpStream, ToSocketAddrs};
+use thiserror::Error;
+use tide_rustls::async_rustls::webpki::DNSNameRef;
+use tide_rustls::async_rustls::TlsConnector;
+use tide_rustls::rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+};
+
+use crate::acme::ACME_TLS_ALPN_NAME;
+use crate::challenge::self_signed_cert;
+use crate::resolver::AcmeResolver;
+
+/// How long the check waits to connect to an address and complete the handshake.
+const PREFLIGHT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Check that each address `domain` resolves to answers tls-alpn-01 handshakes on port 443 with
+/// this server's validation certificates, as the CA's validators will expect, by serving a
+/// self-signed probe certificate for the domain while connecting.
+pub(crate) async fn check(resolver: &AcmeResolver, domain: &str) -> Result<(), PreflightError> {
+    let addrs: Vec<SocketAddr> = (domain, 443)
+        .to_socket_addrs()
+        .await
+        .map_err(|err| PreflightError::Resolve(domain.into(), err))?
+        .collect();
+    check_addrs(resolver, domain, addrs).await
+}
+
+/// Check that each of `addrs` answers tls-alpn-01 handshakes for `domain` as [`check`] does.
+async fn check_addrs(
+    resolver: &AcmeResolver,
+    domain: &str,
+    addrs: Vec<SocketAddr>,
+) -> Result<(), PreflightError> {
+    if addrs.is_empty() {
+        return Err(PreflightError::NoAddresses(domain.into()));
+    }
+    let dns_name = DNSNameRef::try_from_ascii_str(domain)
+        .map_err(|_| PreflightError::NoAddresses(domain.into()))?;
+    let probe = self_signed_cert(vec![domain.into()])
+        .map_err(|err| PreflightError::Probe(err.to_string()))?;
+    let expected = probe.cert[0].clone();
+    let previous = resolver.replace_auth_key(domain, Some(probe));
+    let mut result = Ok(());
+    for addr in addrs {
+        let presented = match handshake(addr, dns_name).await {
+            Ok(presented) => presented,
+            Err(reason) => {
+                result = Err(PreflightError::Unreachable(domain.into(), addr, reason));
+                break;
+            }
+        };
+        if presented.as_ref() != Some(&expected) {
+            result = Err(PreflightError::OtherResponder(domain.into(), addr));
+            break;
+        }
+    }
+    resolver.replace_auth_key(domain, previous);
+    result
+}
+
+/// Complete a tls-alpn-01 handshake with `addr` for `dns_name`, returning the leaf certificate
+/// presented, or why the handshake failed.
+async fn handshake(
+    addr: SocketAddr,
+    dns_name: DNSNameRef<'_>,
+) -> Result<Option<Certificate>, String> {
+    let verifier = Arc::new(RecordingVerifier::default());
+    let mut config = ClientConfig::default();
+    config.alpn_protocols = vec![ACME_TLS_ALPN_NAME.to_vec()];
+    config
+        .dangerous()
+        .set_certificate_verifier(verifier.clone());
+    let handshake = async {
+        let tcp = TcpStream::connect(addr).await?;
+        TlsConnector::from(Arc::new(config))
+            .connect(dns_name, tcp)
+            .await
+    };
+    match timeout(PREFLIGHT_TIMEOUT, handshake).await {
+        Ok(Ok(_)) => Ok(verifier.leaf.lock().unwrap().clone()),
+        Ok(Err(err)) => Err(err.to_string()),
+        Err(_) => Err(format!("timed out after {:?}", PREFLIGHT_TIMEOUT)),
+    }
+}
+
+/// Records the leaf certificate the server presents, accepting it: the probe is self-signed,
+/// and is compared with what was presented instead.
+#[derive(Default)]
+struct RecordingVerifier {
+    leaf: Mutex<Option<Certificate>>,
+}
+
+impl ServerCertVerifier for RecordingVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        _dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        *self.leaf.lock().unwrap() = presented_certs.first().cloned();
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Why the CA's tls-alpn-01 validation would fail, as found by the pre-flight check.
+#[derive(Error, Debug)]
+pub(crate) enum PreflightError {
+    #[error(
+        "{0} does not resolve: {1}; check that its A/AAAA records exist and point at this server"
+    )]
+    Resolve(String, io::Error),
+    #[error("{0} resolves to no addresses; add A/AAAA records pointing at this server")]
+    NoAddresses(String),
+    #[error("could not create the probe certificate: {0}")]
+    Probe(String),
+    #[error(
+        "no tls-alpn-01 handshake for {0} with {1} ({2}); check that port 443 is reachable \
+         and forwarded to this server, through firewalls and load balancers"
+    )]
+    Unreachable(String, SocketAddr, String),
+    #[error(
+        "{1} answered the tls-alpn-01 handshake for {0} with another certificate; the domain \
+         may resolve to another server, or a proxy terminating TLS answers for it"
+    )]
+    OtherResponder(String, SocketAddr),
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::net::TcpListener;
+    use async_std::task::{self, block_on};
+    use tide_rustls::async_rustls::TlsAcceptor;
+    use tide_rustls::rustls::{NoClientAuth, PrivateKey, ServerConfig};
+
+    use super::*;
+    use crate::{AcmeConfig, AcmeHandle};
+
+    /// Answer TLS handshakes on a local port with `config`, returning its address.
+    fn serve(mut config: ServerConfig) -> SocketAddr {
+        config.alpn_protocols = vec![ACME_TLS_ALPN_NAME.to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        task::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let _ = acceptor.accept(stream).await;
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn passes_when_this_server_answers() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let addr = serve(handle.server_config());
+        block_on(check_addrs(&handle.resolver, "a.example", vec![addr])).unwrap();
+        // The probe is no longer served afterwards.
+        assert!(handle
+            .resolver
+            .replace_auth_key("a.example", None)
+            .is_none());
+    }
+
+    #[test]
+    fn fails_when_another_server_answers() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let cert = rcgen::generate_simple_self_signed(vec!["a.example".into()]).unwrap();
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config
+            .set_single_cert(
+                vec![Certificate(cert.serialize_der().unwrap())],
+                PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let addr = serve(config);
+        let result = block_on(check_addrs(&handle.resolver, "a.example", vec![addr]));
+        assert!(matches!(result, Err(PreflightError::OtherResponder(_, a)) if a == addr));
+    }
+
+    #[test]
+    fn fails_when_unreachable() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed = listener.local_addr().unwrap();
+        drop(listener);
+        let result = block_on(check_addrs(&handle.resolver, "a.example", vec![closed]));
+        assert!(matches!(result, Err(PreflightError::Unreachable(_, a, _)) if a == closed));
+        let result = block_on(check_addrs(&handle.resolver, "a.example", vec![]));
+        assert!(matches!(result, Err(PreflightError::NoAddresses(_))));
+    }
+}
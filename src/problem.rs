@@ -0,0 +1,257 @@
+//! ACME problem documents (RFC 8555 section 6.7), and explanations of the common problem types.
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// The prefix of the problem types defined by RFC 8555.
+const ACME_ERROR_PREFIX: &str = "urn:ietf:params:acme:error:";
+
+/// An ACME problem document, as returned by the CA for failed requests and challenges.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct Problem {
+    /// The problem type, such as `urn:ietf:params:acme:error:rateLimited`.
+    #[serde(rename = "type")]
+    pub typ: String,
+    /// The CA's description of the problem, such as the address it failed to connect to.
+    #[serde(default)]
+    pub detail: Option<String>,
+    /// The HTTP status code of the response carrying the problem, if the CA included it.
+    #[serde(default)]
+    pub status: Option<u16>,
+    /// The problems with individual identifiers of an order, if the CA reported them
+    /// separately.
+    #[serde(default)]
+    pub subproblems: Vec<Subproblem>,
+}
+
+/// A problem with one identifier of an order, as part of a [`Problem`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct Subproblem {
+    /// The problem type.
+    #[serde(rename = "type")]
+    pub typ: String,
+    /// The CA's description of the problem.
+    #[serde(default)]
+    pub detail: Option<String>,
+    /// The domain or IP address the problem is about.
+    #[serde(default, deserialize_with = "identifier_value")]
+    pub identifier: Option<String>,
+}
+
+/// Deserialize the value of an ACME identifier object, such as `{"type": "dns", "value":
+/// "domain.example"}`, or a plain string as serialized from a [`Subproblem`].
+fn identifier_value<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Identifier {
+        Object { value: String },
+        Value(String),
+    }
+    Ok(
+        Option::<Identifier>::deserialize(deserializer)?.map(|identifier| match identifier {
+            Identifier::Object { value } | Identifier::Value(value) => value,
+        }),
+    )
+}
+
+/// How the CA attempted to validate a challenge, as reported in the challenge object's
+/// `validationRecord`: which name it resolved, to which addresses, and which it connected to.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[non_exhaustive]
+pub struct ValidationRecord {
+    /// The URL the CA requested, for http-01 challenges.
+    #[serde(default)]
+    pub url: Option<String>,
+    /// The name the CA resolved.
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// The port the CA connected to.
+    #[serde(default, deserialize_with = "port")]
+    pub port: Option<u16>,
+    /// The addresses the name resolved to.
+    #[serde(default)]
+    pub addresses_resolved: Vec<String>,
+    /// The address the CA connected to.
+    #[serde(default)]
+    pub address_used: Option<String>,
+}
+
+/// Deserialize a port sent as a number or, as Let's Encrypt does, as a string.
+fn port<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u16>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Port {
+        Number(u16),
+        String(String),
+    }
+    Ok(match Option::<Port>::deserialize(deserializer)? {
+        Some(Port::Number(port)) => Some(port),
+        Some(Port::String(port)) => port.parse().ok(),
+        None => None,
+    })
+}
+
+/// Why the CA failed to validate a domain, as reported in its failed challenge: part of
+/// [`AcmeEvent::challenge_failure`](crate::AcmeEvent::challenge_failure) and
+/// [`CertStatus::challenge_failure`](crate::CertStatus::challenge_failure).
+///
+/// ```no_run
+/// # fn example(handle: tide_acme::AcmeHandle) {
+/// for status in handle.status() {
+///     if let Some(failure) = &status.challenge_failure {
+///         eprintln!(
+///             "{} failed {}: {}",
+///             failure.domain.as_deref().unwrap_or("?"),
+///             failure.challenge_type,
+///             failure.problem.detail.as_deref().unwrap_or(&failure.problem.typ)
+///         );
+///         for record in &failure.validation_records {
+///             eprintln!("  tried {:?} of {:?}", record.address_used, record.addresses_resolved);
+///         }
+///     }
+/// }
+/// # }
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct ChallengeFailure {
+    /// The domain or IP address that failed validation, if the CA named it.
+    pub domain: Option<String>,
+    /// The type of the challenge that failed, such as `tls-alpn-01`.
+    pub challenge_type: String,
+    /// The problem the CA reported for the challenge.
+    pub problem: Problem,
+    /// How the CA attempted validation, if it reported it.
+    pub validation_records: Vec<ValidationRecord>,
+}
+
+impl Problem {
+    /// Parse a problem document from a response body, if it is one.
+    pub(crate) fn parse(body: &str) -> Option<Self> {
+        serde_json::from_str(body).ok()
+    }
+
+    /// An explanation of the problem and what to do about it, for the common problem types.
+    pub fn hint(&self) -> Option<&'static str> {
+        problem_hint(&self.typ)
+    }
+}
+
+/// Explain an ACME problem type, such as `urn:ietf:params:acme:error:caa`, and what to do about
+/// it. Returns `None` for problem types without an explanation.
+pub fn problem_hint(problem_type: &str) -> Option<&'static str> {
+    let hint = match problem_type.strip_prefix(ACME_ERROR_PREFIX)? {
+        "rateLimited" => {
+            "the CA's rate limit was hit; orders are retried with backoff, but persist a cache \
+             so restarts reuse certificates, and test against the staging directory"
+        }
+        "caa" => {
+            "a CAA DNS record of the domain forbids this CA from issuing for it; add a CAA \
+             record allowing the CA, or remove the restricting one"
+        }
+        "unauthorized" => {
+            "the CA refused to issue for the domain; check that the domain is spelled right and \
+             allowed by the CA's policy, and that the account is authorized for it"
+        }
+        "dns" => {
+            "the CA could not resolve the domain; check that its A/AAAA records exist and point \
+             at this server, and that its nameservers answer"
+        }
+        "connection" => {
+            "the CA could not connect to this server for validation; check that port 443 is \
+             reachable from the internet, through firewalls and load balancers"
+        }
+        "tls" => {
+            "the CA's validation TLS handshake failed; check that connections on port 443 reach \
+             this server directly, without TLS termination in front of it"
+        }
+        "incorrectResponse" => {
+            "the CA received an unexpected validation response; check that the domain resolves \
+             to this server rather than another one, such as an old address"
+        }
+        "rejectedIdentifier" => {
+            "the CA will not issue for the domain, such as for internal names or blocked \
+             domains; remove it from the configuration"
+        }
+        _ => return None,
+    };
+    Some(hint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_problem_documents() {
+        let body = r#"{"type": "urn:ietf:params:acme:error:rateLimited",
+            "detail": "too many certificates already issued", "status": 429}"#;
+        let problem = Problem::parse(body).unwrap();
+        assert_eq!(problem.typ, "urn:ietf:params:acme:error:rateLimited");
+        assert!(problem.hint().unwrap().contains("rate limit"));
+        assert!(Problem::parse("<html>").is_none());
+        assert!(Problem::parse(r#"{"detail": "no type"}"#).is_none());
+    }
+
+    #[test]
+    fn parses_subproblems() {
+        let body = r#"{"type": "urn:ietf:params:acme:error:compound", "status": 403,
+            "subproblems": [{"type": "urn:ietf:params:acme:error:caa", "detail": "forbidden",
+                "identifier": {"type": "dns", "value": "b.domain.example"}}]}"#;
+        let problem = Problem::parse(body).unwrap();
+        assert_eq!(problem.status, Some(403));
+        assert_eq!(problem.detail, None);
+        let subproblem = &problem.subproblems[0];
+        assert_eq!(subproblem.typ, "urn:ietf:params:acme:error:caa");
+        assert_eq!(subproblem.identifier.as_deref(), Some("b.domain.example"));
+        // As saved with events, the identifier is a plain string.
+        let saved = serde_json::to_string(&problem).unwrap();
+        let restored = Problem::parse(&saved).unwrap();
+        assert_eq!(
+            restored.subproblems[0].identifier.as_deref(),
+            Some("b.domain.example")
+        );
+    }
+
+    #[test]
+    fn parses_validation_records() {
+        let records: Vec<ValidationRecord> = serde_json::from_str(
+            r#"[{"hostname": "domain.example", "port": "443", "addressesResolved":
+                ["192.0.2.1", "2001:db8::1"], "addressUsed": "2001:db8::1"},
+                {"url": "http://domain.example/.well-known/acme-challenge/token", "port": 80},
+                {"port": "http"}]"#,
+        )
+        .unwrap();
+        assert_eq!(records[0].port, Some(443));
+        assert_eq!(records[0].addresses_resolved, ["192.0.2.1", "2001:db8::1"]);
+        assert_eq!(records[0].address_used.as_deref(), Some("2001:db8::1"));
+        assert_eq!(records[1].port, Some(80));
+        assert!(records[1].url.is_some() && records[1].hostname.is_none());
+        assert_eq!(records[2].port, None);
+    }
+
+    #[test]
+    fn explains_common_problem_types() {
+        for typ in [
+            "rateLimited",
+            "caa",
+            "unauthorized",
+            "dns",
+            "connection",
+            "tls",
+            "incorrectResponse",
+            "rejectedIdentifier",
+        ] {
+            assert!(problem_hint(&format!("{}{}", ACME_ERROR_PREFIX, typ)).is_some());
+        }
+        assert_eq!(
+            problem_hint("urn:ietf:params:acme:error:serverInternal"),
+            None
+        );
+        assert_eq!(problem_hint("caa"), None);
+    }
+}
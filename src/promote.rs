@@ -0,0 +1,163 @@
+//! Promoting a deployment from a staging ACME directory to production.
+
+use thiserror::Error;
+
+use crate::acme::{
+    GOOGLE_TRUST_SERVICES_PRODUCTION_DIRECTORY, GOOGLE_TRUST_SERVICES_STAGING_DIRECTORY,
+    LETS_ENCRYPT_PRODUCTION_DIRECTORY, LETS_ENCRYPT_STAGING_DIRECTORY,
+};
+use crate::state::ConfigReload;
+use crate::{AcmeHandle, CertReadiness};
+
+/// Why [`AcmeHandle::promote`] didn't switch to production.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum PromotionError {
+    /// The main directory, with this URL, isn't a staging directory with a known production
+    /// directory.
+    #[error("{0} is not a staging directory with a known production directory")]
+    NotStaging(String),
+    /// No cache is configured, so production certificates would be ordered again on every
+    /// start.
+    #[error("no cache is configured; production certificates would be ordered on every start")]
+    NoCache,
+    /// No certificate has been obtained from staging yet for these domains.
+    #[error("no certificate obtained from staging yet for {0:?}")]
+    NotIssued(Vec<String>),
+    /// The acceptor has shut down.
+    #[error("the acceptor has shut down")]
+    ShutDown,
+}
+
+/// The production directory of the staging directory at `url`, for the CAs that have one.
+pub(crate) fn production_directory(url: &str) -> Option<&'static str> {
+    match url {
+        LETS_ENCRYPT_STAGING_DIRECTORY => Some(LETS_ENCRYPT_PRODUCTION_DIRECTORY),
+        GOOGLE_TRUST_SERVICES_STAGING_DIRECTORY => Some(GOOGLE_TRUST_SERVICES_PRODUCTION_DIRECTORY),
+        _ => None,
+    }
+}
+
+/// Whether `url` is the staging directory of a CA, whose certificates browsers don't trust.
+pub(crate) fn is_staging_directory(url: &str) -> bool {
+    production_directory(url).is_some()
+}
+
+impl AcmeHandle {
+    /// Switch the main directory from staging to production (Let's Encrypt's or Google Trust
+    /// Services'), once every managed certificate has been obtained from staging, proving the
+    /// deployment works before spending production rate limits on it.
+    ///
+    /// The certificates are then ordered from production in the background, keeping the
+    /// domains and contact URLs, as with [`reload_config`](Self::reload_config); the staging
+    /// certificates go on being served until they're replaced. Certificates and accounts are
+    /// cached per directory, so production and staging don't share them, and a cached
+    /// certificate issued by a staging CA is never served for a production directory: it is
+    /// moved aside, and a production one ordered instead, in case the cache doesn't keep
+    /// directories apart. The switch only lasts until restart; make it in the configuration
+    /// too, with [`directory_lets_encrypt`](crate::AcmeConfig::directory_lets_encrypt) or
+    /// [`directory_google_trust_services`](crate::AcmeConfig::directory_google_trust_services).
+    ///
+    /// ```no_run
+    /// # async fn example(handle: tide_acme::AcmeHandle) {
+    /// handle.ready().await;
+    /// if let Err(err) = handle.promote() {
+    ///     eprintln!("staying on staging: {}", err);
+    /// }
+    /// # }
+    /// ```
+    pub fn promote(&self) -> Result<(), PromotionError> {
+        let summary = self.config_summary();
+        let production = production_directory(&summary.directory_url)
+            .ok_or_else(|| PromotionError::NotStaging(summary.directory_url.clone()))?;
+        if summary.cache.is_none() {
+            return Err(PromotionError::NoCache);
+        }
+        let pending: Vec<String> = self
+            .status()
+            .into_iter()
+            .filter(|status| status.readiness_at(self.registry.now()) != CertReadiness::Obtained)
+            .flat_map(|status| status.domains)
+            .collect();
+        if !pending.is_empty() {
+            return Err(PromotionError::NotIssued(pending));
+        }
+        let reload = ConfigReload {
+            directory_url: Some(production.into()),
+            ..ConfigReload::default()
+        };
+        match self.registry.reload_config(reload) {
+            true => Ok(()),
+            false => Err(PromotionError::ShutDown),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use async_std::task::block_on;
+    use futures::StreamExt;
+    use rustls_acme::caches::DirCache;
+
+    use super::*;
+    use crate::AcmeConfig;
+
+    #[test]
+    fn knows_production_directories() {
+        assert_eq!(
+            production_directory(LETS_ENCRYPT_STAGING_DIRECTORY),
+            Some(LETS_ENCRYPT_PRODUCTION_DIRECTORY)
+        );
+        assert_eq!(
+            production_directory(GOOGLE_TRUST_SERVICES_STAGING_DIRECTORY),
+            Some(GOOGLE_TRUST_SERVICES_PRODUCTION_DIRECTORY)
+        );
+        assert!(!is_staging_directory(LETS_ENCRYPT_PRODUCTION_DIRECTORY));
+        assert!(!is_staging_directory("https://ca.internal/directory"));
+    }
+
+    #[test]
+    fn promotes_staging_deployments() {
+        let config = AcmeConfig::new(["a.example"]).directory("https://ca.internal/directory");
+        let handle = config
+            .cache(DirCache::new(
+                std::env::temp_dir().join("tide-acme-promote"),
+            ))
+            .state()
+            .handle();
+        assert!(matches!(
+            handle.promote(),
+            Err(PromotionError::NotStaging(_))
+        ));
+        let handle = AcmeConfig::new(["a.example"]).cache_none().state().handle();
+        assert!(matches!(handle.promote(), Err(PromotionError::NoCache)));
+
+        let state = AcmeConfig::new(["a.example"])
+            .cache(DirCache::new(
+                std::env::temp_dir().join("tide-acme-promote"),
+            ))
+            .state();
+        let handle = state.handle();
+        let (index, _) = handle.registry.register(
+            vec!["a.example".into()],
+            0,
+            crate::snapshot::CertOrigin::Configured,
+        );
+        match handle.promote() {
+            Err(PromotionError::NotIssued(domains)) => assert_eq!(domains, ["a.example"]),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        handle.registry.update(index, |status| {
+            status.not_after = Some(SystemTime::now() + Duration::from_secs(3600));
+        });
+        handle.promote().unwrap();
+        let reload = block_on(state.take_config_reloads().unwrap().next()).unwrap();
+        assert_eq!(
+            reload.directory_url.as_deref(),
+            Some(LETS_ENCRYPT_PRODUCTION_DIRECTORY)
+        );
+        assert!(reload.domains.is_none() && reload.contact.is_none());
+    }
+}
@@ -0,0 +1,216 @@
+//! Parsing of the PROXY protocol header load balancers prepend to connections.
+//!
+//! See <https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt>.
+
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use futures::io::{AsyncRead, AsyncReadExt};
+
+/// The signature starting a version 2 header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest version 1 header, including the trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// How long to wait for the header before closing the connection.
+pub(crate) const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Read the PROXY protocol header, version 1 or 2, from the start of `stream`, leaving the
+/// stream positioned at the first byte after it. Returns the client address it carries, or `None`
+/// for connections the proxy made itself, such as health checks.
+pub(crate) async fn read_header<IO: AsyncRead + Unpin>(
+    stream: &mut IO,
+) -> Result<Option<SocketAddr>> {
+    let mut start = [0; 5];
+    stream.read_exact(&mut start).await?;
+    if &start == b"PROXY" {
+        read_v1(stream).await
+    } else if start == V2_SIGNATURE[..5] {
+        read_v2(stream).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1<IO: AsyncRead + Unpin>(stream: &mut IO) -> Result<Option<SocketAddr>> {
+    // Read byte by byte, so nothing after the header is consumed.
+    let mut line = b"PROXY".to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err(invalid("PROXY protocol v1 header too long"));
+        }
+        let mut byte = [0];
+        stream.read_exact(&mut byte).await?;
+        line.push(byte[0]);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY protocol v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, source_port, _] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| invalid("invalid PROXY protocol v1 source address"))?;
+            let port: u16 = source_port
+                .parse()
+                .map_err(|_| invalid("invalid PROXY protocol v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY protocol v1 header")),
+    }
+}
+
+async fn read_v2<IO: AsyncRead + Unpin>(stream: &mut IO) -> Result<Option<SocketAddr>> {
+    let mut rest = [0; 11];
+    stream.read_exact(&mut rest).await?;
+    if rest[..7] != V2_SIGNATURE[5..] {
+        return Err(invalid("invalid PROXY protocol v2 signature"));
+    }
+    let (version_command, family) = (rest[7], rest[8]);
+    let len = u16::from_be_bytes([rest[9], rest[10]]) as usize;
+    let mut addresses = vec![0; len];
+    stream.read_exact(&mut addresses).await?;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL: the proxy's own connection.
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unsupported PROXY protocol v2 command")),
+    }
+    // The address family is the high nibble; the transport protocol the low one.
+    let addr = match family >> 4 {
+        1 if len >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Some(SocketAddr::new(ip.into(), port))
+        }
+        2 if len >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port))
+        }
+        1 | 2 => return Err(invalid("truncated PROXY protocol v2 addresses")),
+        // UNSPEC, or Unix sockets: no client address to report.
+        _ => None,
+    };
+    Ok(addr)
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::io::Cursor;
+
+    use super::*;
+
+    /// Read the header off `input`, returning the result and the bytes left after it.
+    fn read(input: &[u8]) -> (Result<Option<SocketAddr>>, Vec<u8>) {
+        async_std::task::block_on(async {
+            let mut stream = Cursor::new(input.to_vec());
+            let addr = read_header(&mut stream).await;
+            let mut rest = vec![];
+            stream.read_to_end(&mut rest).await.unwrap();
+            (addr, rest)
+        })
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let len = (addresses.len() as u16).to_be_bytes();
+        [
+            &V2_SIGNATURE[..],
+            &[0x20 | command, family],
+            &len[..],
+            addresses,
+        ]
+        .concat()
+    }
+
+    fn error_kind(input: &[u8]) -> ErrorKind {
+        read(input).0.unwrap_err().kind()
+    }
+
+    #[test]
+    fn reads_v1_headers() {
+        let (addr, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /");
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+        let (addr, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n");
+        assert_eq!(addr.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+        let (addr, rest) = read(b"PROXY UNKNOWN\r\n\x16");
+        assert_eq!(addr.unwrap(), None);
+        assert_eq!(rest, b"\x16");
+    }
+
+    #[test]
+    fn rejects_malformed_v1_headers() {
+        assert_eq!(
+            error_kind(b"PROXY TCP4 192.0.2.1"),
+            ErrorKind::UnexpectedEof
+        );
+        let long = [&b"PROXY UNKNOWN "[..], &[b'x'; 100]].concat();
+        assert_eq!(error_kind(&long), ErrorKind::InvalidData);
+        for header in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n"[..],
+            b"PROXY TCP4 192.0.2.x 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 99999 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n",
+            b"PROXY \xff\r\n",
+        ] {
+            assert_eq!(error_kind(header), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn reads_v2_headers() {
+        let ipv4 = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        let input = [v2(1, 0x11, &ipv4), b"rest".to_vec()].concat();
+        let (addr, rest) = read(&input);
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"rest");
+
+        let mut ipv6 = [0; 36];
+        ipv6[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6[32..34].copy_from_slice(&56324u16.to_be_bytes());
+        let (addr, _) = read(&v2(1, 0x21, &ipv6));
+        assert_eq!(addr.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        // LOCAL connections and Unix sockets carry no client address.
+        assert_eq!(read(&v2(0, 0x11, &ipv4)).0.unwrap(), None);
+        assert_eq!(read(&v2(1, 0x31, &[0; 216])).0.unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_malformed_v2_headers() {
+        // Addresses shorter than the family needs.
+        assert_eq!(error_kind(&v2(1, 0x11, &[0; 4])), ErrorKind::InvalidData);
+        assert_eq!(error_kind(&v2(1, 0x21, &[0; 12])), ErrorKind::InvalidData);
+        // A length running past the end of the stream.
+        let mut truncated = v2(1, 0x11, &[0; 12]);
+        truncated.truncate(20);
+        assert_eq!(error_kind(&truncated), ErrorKind::UnexpectedEof);
+        assert_eq!(error_kind(&V2_SIGNATURE[..8]), ErrorKind::UnexpectedEof);
+
+        let mut signature = v2(1, 0x11, &[0; 12]);
+        signature[6] = b'x';
+        assert_eq!(error_kind(&signature), ErrorKind::InvalidData);
+        let mut version = v2(1, 0x11, &[0; 12]);
+        version[12] = 0x11;
+        assert_eq!(error_kind(&version), ErrorKind::InvalidData);
+        assert_eq!(error_kind(&v2(2, 0x11, &[0; 12])), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_missing_headers() {
+        assert_eq!(error_kind(b"GET / HTTP/1.1\r\n"), ErrorKind::InvalidData);
+        assert_eq!(error_kind(b"PRO"), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,378 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+const WEEK: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The CA's rate limits, which orders are delayed to stay within; see
+/// [`AcmeConfig::rate_limits`](crate::AcmeConfig::rate_limits).
+///
+/// Usage is counted from startup, so after a restart, orders placed before it aren't counted.
+/// The registered domain of a name is taken to be its last two labels, which lumps together
+/// domains under public suffixes such as `co.uk`, erring on the side of delaying orders.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use tide_acme::{AcmeConfig, RateLimits};
+///
+/// // Leave headroom for other servers ordering with the same account.
+/// let config = AcmeConfig::new(vec!["domain.example"]).rate_limits(
+///     RateLimits::lets_encrypt().new_orders(100, Duration::from_secs(3 * 60 * 60)),
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct RateLimits {
+    new_orders: Option<(u32, Duration)>,
+    certificates_per_domain: Option<(u32, Duration)>,
+    duplicate_certificates: Option<(u32, Duration)>,
+    failed_validations: Option<(u32, Duration)>,
+}
+
+impl RateLimits {
+    /// No limits of our own: orders are only delayed for as long as the CA asks with a
+    /// `Retry-After` header.
+    pub fn none() -> Self {
+        Self {
+            new_orders: None,
+            certificates_per_domain: None,
+            duplicate_certificates: None,
+            failed_validations: None,
+        }
+    }
+
+    /// The limits of the Let's Encrypt production directory: 300 new orders per account every 3
+    /// hours; 50 certificates per registered domain, and 5 for the same set of domains, every 7
+    /// days; and 5 failed validations per domain every hour.
+    pub fn lets_encrypt() -> Self {
+        Self {
+            new_orders: Some((300, 3 * HOUR)),
+            certificates_per_domain: Some((50, WEEK)),
+            duplicate_certificates: Some((5, WEEK)),
+            failed_validations: Some((5, HOUR)),
+        }
+    }
+
+    /// Place at most `orders` new orders per account within any period of `per`.
+    pub fn new_orders(mut self, orders: u32, per: Duration) -> Self {
+        self.new_orders = Some((orders.max(1), per));
+        self
+    }
+
+    /// Obtain at most `certificates` certificates per registered domain within any period of
+    /// `per`.
+    pub fn certificates_per_domain(mut self, certificates: u32, per: Duration) -> Self {
+        self.certificates_per_domain = Some((certificates.max(1), per));
+        self
+    }
+
+    /// Obtain at most `certificates` certificates for the same set of domains within any period
+    /// of `per`.
+    pub fn duplicate_certificates(mut self, certificates: u32, per: Duration) -> Self {
+        self.duplicate_certificates = Some((certificates.max(1), per));
+        self
+    }
+
+    /// Retry a domain at most `failures` times after failed validations within any period of
+    /// `per`.
+    pub fn failed_validations(mut self, failures: u32, per: Duration) -> Self {
+        self.failed_validations = Some((failures.max(1), per));
+        self
+    }
+}
+
+/// What remains of the rate limits of an account, as returned by
+/// [`AcmeHandle::rate_limit_budgets`](crate::AcmeHandle::rate_limit_budgets).
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct RateLimitBudget {
+    /// The ACME directory URL of the account.
+    pub directory: String,
+    /// The new orders the account may still place in the current period, if limited.
+    pub orders_remaining: Option<u32>,
+    /// The certificates that may still be obtained in the current period, by registered domain,
+    /// for the domains obtained since startup.
+    pub certificates_remaining: BTreeMap<String, u32>,
+    /// The failed validations still allowed in the current period, by domain, for the domains
+    /// that failed validation since startup.
+    pub failed_validations_remaining: BTreeMap<String, u32>,
+    /// Until when the CA asked not to be sent orders, if it rejected one with a `Retry-After`
+    /// header and that time hasn't passed.
+    pub retry_after: Option<SystemTime>,
+}
+
+/// Usage of the rate limits of each account, by directory URL.
+#[derive(Default)]
+pub(crate) struct RateBudgets {
+    accounts: Mutex<BTreeMap<String, Account>>,
+}
+
+#[derive(Default)]
+struct Account {
+    limits: Option<RateLimits>,
+    orders: Window,
+    certificates: BTreeMap<String, Window>,
+    duplicates: BTreeMap<Vec<String>, Window>,
+    failed_validations: BTreeMap<String, Window>,
+    retry_after: Option<SystemTime>,
+}
+
+/// The times of recent events counted against a limit, oldest first.
+#[derive(Default)]
+struct Window(VecDeque<SystemTime>);
+
+impl Window {
+    /// Forget the events that left the period, and count those left.
+    fn used(&mut self, per: Duration, now: SystemTime) -> u32 {
+        while self.0.front().is_some_and(|&at| at + per <= now) {
+            self.0.pop_front();
+        }
+        self.0.len() as u32
+    }
+
+    fn remaining(&mut self, (max, per): (u32, Duration), now: SystemTime) -> u32 {
+        max.saturating_sub(self.used(per, now))
+    }
+
+    /// When another event fits in the limit, if it doesn't now.
+    fn free_at(&mut self, (max, per): (u32, Duration), now: SystemTime) -> Option<SystemTime> {
+        let used = self.used(per, now);
+        match used >= max {
+            true => Some(self.0[(used - max) as usize] + per),
+            false => None,
+        }
+    }
+
+    fn record(&mut self, now: SystemTime) {
+        self.0.push_back(now);
+    }
+}
+
+/// The registered domain of `domain`, approximated as its last two labels.
+fn registered_domain(domain: &str) -> String {
+    let domain = domain.trim_start_matches("*.").to_ascii_lowercase();
+    let labels: Vec<&str> = domain.rsplitn(3, '.').collect();
+    match labels.as_slice() {
+        [tld, name, _] => format!("{}.{}", name, tld),
+        _ => domain,
+    }
+}
+
+fn sorted(domains: &[String]) -> Vec<String> {
+    let mut domains: Vec<String> = domains.iter().map(|d| d.to_ascii_lowercase()).collect();
+    domains.sort();
+    domains
+}
+
+impl RateBudgets {
+    pub(crate) fn set_limits(&self, directory: &str, limits: Option<RateLimits>) {
+        let mut accounts = self.accounts.lock().unwrap();
+        accounts.entry(directory.into()).or_default().limits = limits;
+    }
+
+    /// When an order for `domains` fits in the rate limits, if it doesn't now.
+    pub(crate) fn next_order(&self, directory: &str, domains: &[String]) -> Option<SystemTime> {
+        let now = SystemTime::now();
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = accounts.get_mut(directory)?;
+        let mut until = account.retry_after.filter(|&at| at > now);
+        let limits = match account.limits.clone() {
+            Some(limits) => limits,
+            None => return until,
+        };
+        let mut wait = |at: Option<SystemTime>| until = until.max(at);
+        if let Some(limit) = limits.new_orders {
+            wait(account.orders.free_at(limit, now));
+        }
+        if let Some(limit) = limits.certificates_per_domain {
+            for domain in domains {
+                let window = account.certificates.get_mut(&registered_domain(domain));
+                wait(window.and_then(|window| window.free_at(limit, now)));
+            }
+        }
+        if let Some(limit) = limits.duplicate_certificates {
+            let window = account.duplicates.get_mut(&sorted(domains));
+            wait(window.and_then(|window| window.free_at(limit, now)));
+        }
+        if let Some(limit) = limits.failed_validations {
+            for domain in domains {
+                let window = account
+                    .failed_validations
+                    .get_mut(&domain.to_ascii_lowercase());
+                wait(window.and_then(|window| window.free_at(limit, now)));
+            }
+        }
+        until
+    }
+
+    pub(crate) fn record_order(&self, directory: &str) {
+        self.update(directory, |account, now| account.orders.record(now));
+    }
+
+    pub(crate) fn record_issued(&self, directory: &str, domains: &[String]) {
+        self.update(directory, |account, now| {
+            let mut registered: Vec<String> =
+                domains.iter().map(|d| registered_domain(d)).collect();
+            registered.dedup();
+            for domain in registered {
+                account.certificates.entry(domain).or_default().record(now);
+            }
+            account
+                .duplicates
+                .entry(sorted(domains))
+                .or_default()
+                .record(now);
+        });
+    }
+
+    pub(crate) fn record_failed_validation(&self, directory: &str, domain: &str) {
+        self.update(directory, |account, now| {
+            account
+                .failed_validations
+                .entry(domain.to_ascii_lowercase())
+                .or_default()
+                .record(now);
+        });
+    }
+
+    /// Record that the CA asked not to be sent orders until `at`.
+    pub(crate) fn record_retry_after(&self, directory: &str, at: SystemTime) {
+        self.update(directory, |account, _| {
+            account.retry_after = account.retry_after.max(Some(at));
+        });
+    }
+
+    fn update(&self, directory: &str, f: impl FnOnce(&mut Account, SystemTime)) {
+        let mut accounts = self.accounts.lock().unwrap();
+        f(
+            accounts.entry(directory.into()).or_default(),
+            SystemTime::now(),
+        );
+    }
+
+    pub(crate) fn budgets(&self) -> Vec<RateLimitBudget> {
+        let now = SystemTime::now();
+        let mut accounts = self.accounts.lock().unwrap();
+        accounts
+            .iter_mut()
+            .map(|(directory, account)| {
+                let limits = account.limits.clone().unwrap_or_else(RateLimits::none);
+                let remaining = |windows: &mut BTreeMap<String, Window>, limit| match limit {
+                    Some(limit) => windows
+                        .iter_mut()
+                        .map(|(name, window)| (name.clone(), window.remaining(limit, now)))
+                        .collect(),
+                    None => BTreeMap::new(),
+                };
+                RateLimitBudget {
+                    directory: directory.clone(),
+                    orders_remaining: limits
+                        .new_orders
+                        .map(|limit| account.orders.remaining(limit, now)),
+                    certificates_remaining: remaining(
+                        &mut account.certificates,
+                        limits.certificates_per_domain,
+                    ),
+                    failed_validations_remaining: remaining(
+                        &mut account.failed_validations,
+                        limits.failed_validations,
+                    ),
+                    retry_after: account.retry_after.filter(|&at| at > now),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIRECTORY: &str = "https://acme.test/directory";
+
+    fn domains(domains: &[&str]) -> Vec<String> {
+        domains.iter().map(|domain| domain.to_string()).collect()
+    }
+
+    #[test]
+    fn groups_domains_by_registered_domain() {
+        assert_eq!(registered_domain("www.A.example"), "a.example");
+        assert_eq!(registered_domain("*.b.a.example"), "a.example");
+        assert_eq!(registered_domain("a.example"), "a.example");
+        assert_eq!(registered_domain("localhost"), "localhost");
+    }
+
+    #[test]
+    fn defers_orders_over_the_limits() {
+        let budgets = RateBudgets::default();
+        let a = domains(&["a.example", "www.a.example"]);
+        assert_eq!(budgets.next_order(DIRECTORY, &a), None);
+        let limits = RateLimits::none()
+            .new_orders(2, HOUR)
+            .certificates_per_domain(3, WEEK)
+            .duplicate_certificates(1, WEEK)
+            .failed_validations(1, HOUR);
+        budgets.set_limits(DIRECTORY, Some(limits));
+        let before = SystemTime::now();
+        budgets.record_order(DIRECTORY);
+        assert_eq!(budgets.next_order(DIRECTORY, &a), None);
+
+        // A duplicate certificate waits a week, other names of the registered domain don't.
+        budgets.record_issued(DIRECTORY, &a);
+        let until = budgets.next_order(DIRECTORY, &a).unwrap();
+        assert!(until >= before + WEEK && until <= SystemTime::now() + WEEK);
+        assert_eq!(
+            budgets.next_order(DIRECTORY, &domains(&["a.example"])),
+            None
+        );
+
+        // A failed validation holds back its domain for an hour.
+        budgets.record_failed_validation(DIRECTORY, "B.example");
+        let until = budgets.next_order(DIRECTORY, &domains(&["b.example"]));
+        assert!(until.unwrap() >= before + HOUR);
+
+        // So does running out of orders, for every domain.
+        budgets.record_order(DIRECTORY);
+        let until = budgets.next_order(DIRECTORY, &domains(&["c.example"]));
+        assert!(until.unwrap() >= before + HOUR);
+
+        let budget = &budgets.budgets()[0];
+        assert_eq!(budget.directory, DIRECTORY);
+        assert_eq!(budget.orders_remaining, Some(0));
+        assert_eq!(budget.certificates_remaining["a.example"], 2);
+        assert_eq!(budget.failed_validations_remaining["b.example"], 0);
+        assert_eq!(budget.retry_after, None);
+    }
+
+    #[test]
+    fn honors_retry_after_without_limits() {
+        let budgets = RateBudgets::default();
+        budgets.set_limits(DIRECTORY, None);
+        let a = domains(&["a.example"]);
+        budgets.record_order(DIRECTORY);
+        assert_eq!(budgets.next_order(DIRECTORY, &a), None);
+        let later = SystemTime::now() + HOUR;
+        budgets.record_retry_after(DIRECTORY, later);
+        budgets.record_retry_after(DIRECTORY, SystemTime::now());
+        assert_eq!(budgets.next_order(DIRECTORY, &a), Some(later));
+        let budget = &budgets.budgets()[0];
+        assert_eq!(budget.orders_remaining, None);
+        assert_eq!(budget.retry_after, Some(later));
+    }
+
+    #[test]
+    fn frees_windows_as_entries_expire() {
+        let start = SystemTime::UNIX_EPOCH + WEEK;
+        let mut window = Window::default();
+        window.record(start);
+        window.record(start + Duration::from_secs(60));
+        let limit = (2, HOUR);
+        assert_eq!(window.remaining(limit, start + Duration::from_secs(90)), 0);
+        assert_eq!(
+            window.free_at(limit, start + Duration::from_secs(90)),
+            Some(start + HOUR)
+        );
+        assert_eq!(window.remaining(limit, start + HOUR), 1);
+        assert_eq!(window.free_at(limit, start + HOUR), None);
+    }
+}
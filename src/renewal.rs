@@ -0,0 +1,268 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// When certificates are renewed; see
+/// [`AcmeConfig::renewal_policy`](crate::AcmeConfig::renewal_policy).
+///
+/// A certificate is due for renewal at a threshold in its validity period, by default two thirds
+/// of the way through it, as Let's Encrypt recommends. A daily window, in UTC, can confine
+/// renewals to off-peak hours: a renewal due outside the window is delayed to the window's next
+/// start, but by no more than half the time then left until expiry, so that short-lived
+/// certificates are still renewed in time.
+///
+/// ```
+/// use std::time::Duration;
+/// use tide_acme::{AcmeConfig, RenewalPolicy};
+///
+/// const HOUR: u64 = 60 * 60;
+///
+/// // Renew 30 days before expiry, between 02:00 and 05:00 UTC.
+/// let config = AcmeConfig::new(vec!["domain.example"]).renewal_policy(
+///     RenewalPolicy::new()
+///         .before_expiry(Duration::from_secs(30 * 24 * HOUR))
+///         .window(Duration::from_secs(2 * HOUR), Duration::from_secs(5 * HOUR)),
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct RenewalPolicy {
+    threshold: Threshold,
+    window: Option<(u64, u64)>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Threshold {
+    Fraction(f64),
+    BeforeExpiry(Duration),
+}
+
+impl Default for RenewalPolicy {
+    fn default() -> Self {
+        Self {
+            threshold: Threshold::Fraction(2.0 / 3.0),
+            window: None,
+        }
+    }
+}
+
+impl RenewalPolicy {
+    /// Create the default renewal policy.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Renew `fraction` of the way through the validity period, between 0 and 1. Defaults to
+    /// two thirds. Working from the certificate's own validity period suits CAs issuing
+    /// certificates of differing lifetimes.
+    ///
+    /// # Panics
+    ///
+    /// If `fraction` is NaN or infinite.
+    pub fn lifetime_fraction(mut self, fraction: f64) -> Self {
+        assert!(
+            fraction.is_finite(),
+            "lifetime fraction must be finite, got {}",
+            fraction
+        );
+        self.threshold = Threshold::Fraction(fraction.clamp(0.0, 1.0));
+        self
+    }
+
+    /// Renew once `remaining` is left until expiry, instead of at a
+    /// [fraction](Self::lifetime_fraction) of the validity period. For certificates valid for less
+    /// than twice as long, such as short-lived certificates, renew halfway through instead.
+    pub fn before_expiry(mut self, remaining: Duration) -> Self {
+        self.threshold = Threshold::BeforeExpiry(remaining);
+        self
+    }
+
+    /// Renew only between `start` and `end` each day, as times of day in UTC: durations since
+    /// midnight. The window wraps past midnight if `end` is before `start`. Unrestricted by
+    /// default.
+    pub fn window(mut self, start: Duration, end: Duration) -> Self {
+        self.window = Some((start.as_secs() % DAY, end.as_secs() % DAY));
+        self
+    }
+
+    /// When to renew a certificate valid from `not_before` to `not_after`.
+    pub(crate) fn renewal_time(&self, not_before: SystemTime, not_after: SystemTime) -> SystemTime {
+        let due = self.due(not_before, not_after);
+        let (start, end) = match self.window {
+            Some(window) => window,
+            None => return due,
+        };
+        let secs = due.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let time_of_day = secs % DAY;
+        let in_window = if start <= end {
+            (start..end).contains(&time_of_day)
+        } else {
+            time_of_day >= start || time_of_day < end
+        };
+        if in_window {
+            return due;
+        }
+        let delay = Duration::from_secs((start + DAY - time_of_day) % DAY);
+        let remaining = not_after.duration_since(due).unwrap_or_default();
+        if delay > remaining / 2 {
+            return due;
+        }
+        due + delay
+    }
+
+    /// When a certificate is due for renewal, before applying the window.
+    fn due(&self, not_before: SystemTime, not_after: SystemTime) -> SystemTime {
+        let lifetime = not_after.duration_since(not_before).unwrap_or_default();
+        match self.threshold {
+            Threshold::Fraction(fraction) => not_before + lifetime.mul_f64(fraction),
+            Threshold::BeforeExpiry(remaining) => {
+                not_before + lifetime.saturating_sub(remaining.min(lifetime / 2))
+            }
+        }
+    }
+
+    /// A description for the configuration summary.
+    pub(crate) fn describe(&self) -> String {
+        let threshold = match self.threshold {
+            Threshold::Fraction(fraction) if (fraction - 2.0 / 3.0).abs() < 1e-9 => {
+                "two thirds of the way through the validity period".into()
+            }
+            Threshold::Fraction(fraction) => {
+                format!(
+                    "{:.0}% of the way through the validity period",
+                    fraction * 100.0
+                )
+            }
+            Threshold::BeforeExpiry(remaining) => {
+                format!("{}s before expiry", remaining.as_secs())
+            }
+        };
+        match self.window {
+            Some((start, end)) => format!(
+                "{}, between {:02}:{:02} and {:02}:{:02} UTC",
+                threshold,
+                start / 3600,
+                start % 3600 / 60,
+                end / 3600,
+                end % 3600 / 60
+            ),
+            None => threshold,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 60 * 60;
+
+    /// A time `days` days and `hours` hours after an arbitrary midnight, UTC.
+    fn at(days: u64, hours: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(19_000 * DAY + days * DAY + hours * HOUR)
+    }
+
+    #[test]
+    fn renews_at_a_fraction_of_the_validity_period() {
+        let policy = RenewalPolicy::default();
+        assert_eq!(policy.renewal_time(at(0, 0), at(90, 0)), at(60, 0));
+        let policy = RenewalPolicy::new().lifetime_fraction(0.5);
+        assert_eq!(policy.renewal_time(at(0, 0), at(6, 0)), at(3, 0));
+        let policy = RenewalPolicy::new().lifetime_fraction(0.0);
+        assert_eq!(policy.renewal_time(at(0, 0), at(90, 0)), at(0, 0));
+        let policy = RenewalPolicy::new().lifetime_fraction(1.0);
+        assert_eq!(policy.renewal_time(at(0, 0), at(90, 0)), at(90, 0));
+        let policy = RenewalPolicy::new().lifetime_fraction(2.0);
+        assert_eq!(policy.renewal_time(at(0, 0), at(90, 0)), at(90, 0));
+    }
+
+    #[test]
+    fn computes_due_times_at_the_bounds() {
+        let policy = RenewalPolicy::new().lifetime_fraction(0.0);
+        assert_eq!(policy.due(at(0, 0), at(90, 0)), at(0, 0));
+        let policy = RenewalPolicy::new().lifetime_fraction(1.0);
+        assert_eq!(policy.due(at(0, 0), at(90, 0)), at(90, 0));
+        assert_eq!(policy.due(at(0, 0), at(0, 0)), at(0, 0));
+        // A validity period ending before it starts is renewed right away.
+        assert_eq!(policy.due(at(1, 0), at(0, 0)), at(1, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "lifetime fraction must be finite")]
+    fn rejects_nan_fractions() {
+        RenewalPolicy::new().lifetime_fraction(f64::NAN);
+    }
+
+    #[test]
+    #[should_panic(expected = "lifetime fraction must be finite")]
+    fn rejects_infinite_fractions() {
+        RenewalPolicy::new().lifetime_fraction(f64::INFINITY);
+    }
+
+    #[test]
+    fn renews_short_lived_certificates_by_their_lifetime() {
+        let start = at(0, 0);
+        let policy = RenewalPolicy::default();
+        // Valid for 6 hours, then for 3 minutes.
+        assert_eq!(policy.due(start, at(0, 6)), at(0, 4));
+        let due = policy.due(start, start + Duration::from_secs(180));
+        assert_eq!(due, start + Duration::from_secs(120));
+        let policy = RenewalPolicy::new().before_expiry(Duration::from_secs(30 * DAY));
+        assert_eq!(policy.due(start, at(0, 6)), at(0, 3));
+        let due = policy.due(start, start + Duration::from_secs(1));
+        assert_eq!(due, start + Duration::from_millis(500));
+    }
+
+    #[test]
+    fn renews_before_expiry() {
+        let policy = RenewalPolicy::new().before_expiry(Duration::from_secs(30 * DAY));
+        assert_eq!(policy.renewal_time(at(0, 0), at(90, 0)), at(60, 0));
+        // A certificate valid for less than twice the threshold is renewed halfway through.
+        assert_eq!(policy.renewal_time(at(0, 0), at(6, 0)), at(3, 0));
+        assert_eq!(policy.renewal_time(at(0, 0), at(60, 0)), at(30, 0));
+    }
+
+    #[test]
+    fn delays_renewals_to_the_window() {
+        let policy = RenewalPolicy::new()
+            .window(Duration::from_secs(2 * HOUR), Duration::from_secs(5 * HOUR));
+        // Due at 12:00 on day 60, delayed to 02:00 the next day.
+        assert_eq!(policy.renewal_time(at(0, 12), at(90, 12)), at(61, 2));
+        // Due within the window.
+        assert_eq!(policy.renewal_time(at(0, 3), at(90, 3)), at(60, 3));
+
+        let policy = RenewalPolicy::new().window(
+            Duration::from_secs(22 * HOUR),
+            Duration::from_secs(4 * HOUR),
+        );
+        // The window wraps past midnight.
+        assert_eq!(policy.renewal_time(at(0, 1), at(90, 1)), at(60, 1));
+        assert_eq!(policy.renewal_time(at(0, 23), at(90, 23)), at(60, 23));
+        assert_eq!(policy.renewal_time(at(0, 12), at(90, 12)), at(60, 22));
+    }
+
+    #[test]
+    fn renews_short_lived_certificates_in_time() {
+        let policy = RenewalPolicy::new()
+            .window(Duration::from_secs(2 * HOUR), Duration::from_secs(3 * HOUR));
+        // Valid for 6 hours, due at 04:00 with 2 hours left: waiting for 02:00 would overrun.
+        assert_eq!(policy.renewal_time(at(0, 0), at(0, 6)), at(0, 4));
+        // Valid for 6 days, due at 00:00 with 2 days left: waiting 2 hours is fine.
+        assert_eq!(policy.renewal_time(at(0, 0), at(6, 0)), at(4, 2));
+    }
+
+    #[test]
+    fn describes_the_policy() {
+        assert_eq!(
+            RenewalPolicy::default().describe(),
+            "two thirds of the way through the validity period"
+        );
+        let policy = RenewalPolicy::new().lifetime_fraction(0.5).window(
+            Duration::from_secs(2 * HOUR),
+            Duration::from_secs(5 * HOUR + 1800),
+        );
+        assert_eq!(
+            policy.describe(),
+            "50% of the way through the validity period, between 02:00 and 05:30 UTC"
+        );
+    }
+}
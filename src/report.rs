@@ -0,0 +1,56 @@
+use crate::AcmeEvent;
+
+/// Hook for reporting failures of the background certificate management to an error tracker.
+///
+/// Set with [`AcmeConfig::error_reporter`](crate::AcmeConfig::error_reporter). The reporter is
+/// called for every failure that won't resolve itself: failed orders are retried, so they are only
+/// reported from the third failure in a row, while all other failures are reported right away.
+/// The event carries the context: the domains, event type, error class, and order URL.
+pub trait ErrorReporter: Send + Sync + 'static {
+    /// Report a failure.
+    fn report(&self, event: &AcmeEvent);
+}
+
+impl<F: Fn(&AcmeEvent) + Send + Sync + 'static> ErrorReporter for F {
+    fn report(&self, event: &AcmeEvent) {
+        self(event)
+    }
+}
+
+/// [`ErrorReporter`] sending failures to Sentry, via the current Sentry hub.
+///
+/// Each failure becomes a Sentry event at error level, tagged with the event type, error class,
+/// domains and order URL, and grouped by event type and error class.
+#[cfg(feature = "sentry")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SentryReporter;
+
+#[cfg(feature = "sentry")]
+impl ErrorReporter for SentryReporter {
+    fn report(&self, event: &AcmeEvent) {
+        use sentry_core::protocol::{Event, Level};
+
+        let mut sentry_event = Event {
+            level: Level::Error,
+            message: event.error.clone(),
+            logger: Some("tide-acme".into()),
+            timestamp: event.at,
+            fingerprint: vec![
+                event.kind.name().into(),
+                event.error_class.unwrap_or_default().into(),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let tags = &mut sentry_event.tags;
+        tags.insert("event_type".into(), event.kind.name().into());
+        tags.insert("domains".into(), event.domains.join(","));
+        if let Some(error_class) = event.error_class {
+            tags.insert("error_class".into(), error_class.into());
+        }
+        if let Some(order_url) = &event.order_url {
+            tags.insert("order_url".into(), order_url.clone());
+        }
+        sentry_core::capture_event(sentry_event);
+    }
+}
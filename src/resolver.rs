@@ -0,0 +1,1389 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use event_listener::Event;
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::future::{select, Either};
+use tide_rustls::rustls::sign::CertifiedKey;
+use tide_rustls::rustls::{ClientHello, ResolvesServerCert};
+use tracing::debug;
+
+use crate::acme::ACME_TLS_ALPN_NAME;
+use crate::config::SniOverride;
+use crate::handle::{ChallengeStats, PendingIssuance, TlsStats};
+use crate::ip;
+use crate::{Clock, DomainPolicy};
+
+/// How many server names may wait for the domain policy to decide on them at once.
+const ON_DEMAND_QUEUE_LEN: usize = 64;
+/// How many server names may be tracked as being obtained on demand at once, waiting for the
+/// domain policy or for their certificates.
+const MAX_PENDING_ON_DEMAND: usize = 1024;
+
+/// Certificate resolver serving the ACME certificates by SNI, or tls-alpn-01 validation
+/// certificates to validation requests.
+///
+/// Server names are matched case-insensitively against a table of the names the certificates
+/// cover, normalized to lowercase A-labels (punycode), so internationalized domains can be
+/// configured in either form. A wildcard name such as `*.domain.example` matches one label in its
+/// place, and an exact name takes priority over a wildcard covering it unless
+/// [`AcmeConfig::sni_priority`](crate::AcmeConfig::sni_priority) says otherwise. When several
+/// certificates cover the same name, the one configured first is served. Connections with a name
+/// no certificate covers get the certificate for the
+/// [default domain](crate::AcmeConfig::default_domain) if there is one, or else the certificate
+/// configured first; connections without SNI are handled as
+/// [`AcmeConfig::missing_sni`](crate::AcmeConfig::missing_sni) sets.
+///
+/// Get one from [`AcmeHandle::cert_resolver`](crate::AcmeHandle::cert_resolver) to use in your
+/// own rustls `ServerConfig`, with your own ALPN protocols or client authentication. To answer
+/// validation requests there too, include [`ACME_TLS_ALPN_NAME`] in its ALPN protocols.
+pub struct AcmeResolver {
+    inner: Mutex<Inner>,
+    missing_sni: MissingSni,
+    sni_override: Option<SniOverride>,
+    /// Where to send server names to obtain certificates for on demand, if configured.
+    on_demand: Option<OnDemandQueue>,
+    /// Notified when a certificate on demand is deployed, denied or fails to be ordered.
+    issuance: Event,
+    /// The clock certificates on demand are requested and waited for by.
+    clock: Arc<dyn Clock>,
+}
+
+/// The queue of server names to obtain certificates for on demand, once the domain policy
+/// approves them.
+pub(crate) struct OnDemandQueue {
+    policy: Arc<dyn DomainPolicy>,
+    sender: Mutex<Sender<String>>,
+}
+
+impl OnDemandQueue {
+    /// Create a queue for names `policy` may approve, along with the receiving end.
+    pub(crate) fn new(policy: Arc<dyn DomainPolicy>) -> (Self, Receiver<String>) {
+        let (sender, receiver) = channel(ON_DEMAND_QUEUE_LEN);
+        let queue = Self {
+            policy,
+            sender: Mutex::new(sender),
+        };
+        (queue, receiver)
+    }
+}
+
+/// How to handle handshakes without SNI, which some legacy clients and monitoring agents send.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MissingSni {
+    /// Serve the certificate for the [default domain](crate::AcmeConfig::default_domain), or
+    /// the certificate configured first if that one isn't available yet. This is the default.
+    #[default]
+    FirstDomain,
+    /// Serve the certificate covering this domain, which must be among the configured domains;
+    /// the handshake fails if there is none yet.
+    Domain(String),
+    /// Serve no certificate, so the handshake fails.
+    Reject,
+}
+
+/// Which certificate to serve when both one for the exact server name and a wildcard
+/// certificate covering it are available; see
+/// [`AcmeConfig::sni_priority`](crate::AcmeConfig::sni_priority).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SniPriority {
+    /// Serve the certificate for the exact name. This is the default.
+    #[default]
+    ExactFirst,
+    /// Serve the wildcard certificate, such as to consolidate names onto it while their own
+    /// certificates are phased out.
+    WildcardFirst,
+}
+
+struct Inner {
+    default_domain: Option<String>,
+    priority: SniPriority,
+    /// The certificates by normalized name, along with their rank (lower ones configured
+    /// earlier) and the domains each covers.
+    certs: BTreeMap<String, (usize, Arc<[String]>, CertifiedKey)>,
+    auth_keys: BTreeMap<String, CertifiedKey>,
+    stats: BTreeMap<Option<String>, TlsStats>,
+    challenge_stats: BTreeMap<Option<String>, ChallengeStats>,
+    /// Server names sent to obtain certificates for on demand, until a certificate covers them
+    /// or they are denied.
+    on_demand_names: BTreeMap<String, PendingIssuance>,
+    /// The self-signed certificate to serve until a certificate is deployed, if configured.
+    fallback: Option<CertifiedKey>,
+}
+
+impl Inner {
+    /// The name in the table matching a normalized server name: the name itself, or the
+    /// wildcard name covering it, in order of priority.
+    fn lookup(&self, domain: &str) -> Option<&str> {
+        let exact = || {
+            self.certs
+                .get_key_value(domain)
+                .map(|(name, _)| name.as_str())
+        };
+        let wildcard = || {
+            let (_, parent) = domain.split_once('.')?;
+            let (name, _) = self.certs.get_key_value(&format!("*.{}", parent))?;
+            Some(name.as_str())
+        };
+        match self.priority {
+            SniPriority::ExactFirst => exact().or_else(wildcard),
+            SniPriority::WildcardFirst => wildcard().or_else(exact),
+        }
+    }
+
+    /// The statistics key for a normalized server name: the name matching it in the table, if
+    /// any, or `None`.
+    fn stats_key(&self, domain: Option<&str>) -> Option<String> {
+        let domain = domain?;
+        if self.auth_keys.contains_key(domain) {
+            return Some(domain.into());
+        }
+        self.lookup(domain).map(Into::into)
+    }
+}
+
+/// Normalize a domain for matching: lowercase A-labels, without a trailing dot. IP addresses are
+/// written out canonically, and other names that aren't valid domains are only lowercased.
+pub(crate) fn normalize(domain: &str) -> String {
+    let domain = domain.trim_end_matches('.');
+    if let Some(ip) = ip::parse(domain) {
+        return ip.to_string();
+    }
+    if domain.is_ascii() {
+        return domain.to_ascii_lowercase();
+    }
+    idna::domain_to_ascii(domain).unwrap_or_else(|_| domain.to_lowercase())
+}
+
+/// Whether the normalized name `domain` is a valid host name, with at least two labels, as
+/// certificates on demand are obtained for.
+fn is_hostname(domain: &str) -> bool {
+    let valid_label = |label: &str| {
+        (1..=63).contains(&label.len())
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+    };
+    domain.len() <= 253
+        && domain.contains('.')
+        && ip::parse(domain).is_none()
+        && domain.split('.').all(valid_label)
+}
+
+/// The normalized name a validation request with the server name `domain` is for: the IP address
+/// for the reverse DNS name of one, or else the domain.
+pub(crate) fn challenge_name(domain: &str) -> String {
+    match ip::from_reverse_name(domain) {
+        Some(ip) => ip::canonical(ip).to_string(),
+        None => normalize(domain),
+    }
+}
+
+impl AcmeResolver {
+    pub(crate) fn new(
+        default_domain: Option<String>,
+        priority: SniPriority,
+        missing_sni: MissingSni,
+        sni_override: Option<SniOverride>,
+        on_demand: Option<OnDemandQueue>,
+        clock: Arc<dyn Clock>,
+    ) -> Arc<Self> {
+        let missing_sni = match missing_sni {
+            MissingSni::Domain(domain) => MissingSni::Domain(normalize(&domain)),
+            missing_sni => missing_sni,
+        };
+        Arc::new(Self {
+            missing_sni,
+            sni_override,
+            on_demand,
+            issuance: Event::new(),
+            clock,
+            inner: Mutex::new(Inner {
+                default_domain: default_domain.as_deref().map(normalize),
+                priority,
+                certs: Default::default(),
+                auth_keys: Default::default(),
+                stats: Default::default(),
+                challenge_stats: Default::default(),
+                on_demand_names: Default::default(),
+                fallback: None,
+            }),
+        })
+    }
+
+    /// Serve `cert` for `domains`, except for names a certificate of a lower `rank` covers.
+    pub(crate) fn set_cert(&self, rank: usize, domains: &[String], cert: CertifiedKey) {
+        let covered: Arc<[String]> = domains.into();
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        for domain in domains {
+            let name = normalize(domain);
+            if let Some((existing, _, _)) = inner.certs.get(&name) {
+                if *existing < rank {
+                    debug!(name, "name covered by a certificate configured earlier");
+                    continue;
+                }
+            }
+            inner
+                .certs
+                .insert(name, (rank, covered.clone(), cert.clone()));
+        }
+        let issued: Vec<String> = inner
+            .on_demand_names
+            .keys()
+            .filter(|name| inner.lookup(name).is_some())
+            .cloned()
+            .collect();
+        if !issued.is_empty() {
+            for name in issued {
+                inner.on_demand_names.remove(&name);
+            }
+            self.issuance.notify(usize::MAX);
+        }
+    }
+
+    /// Stop serving certificates for `domains`.
+    pub(crate) fn remove_names(&self, domains: &[String]) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        for domain in domains {
+            let domain = normalize(domain);
+            inner.certs.remove(&domain);
+            inner.on_demand_names.remove(&domain);
+        }
+        self.issuance.notify(usize::MAX);
+    }
+
+    /// Serve `cert` to connections that would get no certificate, until one is deployed.
+    pub(crate) fn set_fallback(&self, cert: CertifiedKey) {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .fallback = Some(cert);
+    }
+
+    pub(crate) fn set_auth_key(&self, domain: String, cert: CertifiedKey) {
+        let domain = normalize(&domain);
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .auth_keys
+            .insert(domain, cert);
+    }
+
+    /// Serve `cert` for validation requests for `domain`, or none if `None`, returning the
+    /// certificate served before.
+    pub(crate) fn replace_auth_key(
+        &self,
+        domain: &str,
+        cert: Option<CertifiedKey>,
+    ) -> Option<CertifiedKey> {
+        let domain = normalize(domain);
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        match cert {
+            Some(cert) => inner.auth_keys.insert(domain, cert),
+            None => inner.auth_keys.remove(&domain),
+        }
+    }
+
+    /// Whether a certificate is being served for the IP address `ip`.
+    pub(crate) fn covers_ip(&self, ip: IpAddr) -> bool {
+        let ip = ip::canonical(ip).to_string();
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .certs
+            .contains_key(&ip)
+    }
+
+    /// Request a certificate on demand for `domain`, unless one is being served or was requested
+    /// already.
+    pub(crate) fn request_on_demand(&self, domain: &str) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        self.send_on_demand(&mut inner, domain);
+    }
+
+    /// Send `domain`, a normalized server name, to obtain a certificate for on demand, unless one
+    /// is being served or was requested already, so each name has one order in flight at a time.
+    /// Names that aren't valid domains or the domain policy rules out right away aren't sent,
+    /// and neither are names beyond the queue's capacity, which later handshakes send again.
+    fn send_on_demand(&self, inner: &mut Inner, domain: &str) {
+        let on_demand = match &self.on_demand {
+            Some(on_demand) => on_demand,
+            None => return,
+        };
+        if inner.lookup(domain).is_some() || inner.on_demand_names.contains_key(domain) {
+            return;
+        }
+        if !is_hostname(domain) || !on_demand.policy.may_approve(domain) {
+            return;
+        }
+        if inner.on_demand_names.len() >= MAX_PENDING_ON_DEMAND {
+            debug!(%domain, "too many certificates pending on demand; not requesting one");
+            return;
+        }
+        let mut sender = on_demand
+            .sender
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if sender.try_send(domain.into()).is_err() {
+            debug!(%domain, "on-demand queue is full; not requesting a certificate");
+            return;
+        }
+        inner.on_demand_names.insert(
+            domain.into(),
+            PendingIssuance {
+                domain: domain.into(),
+                requested_at: self.clock.now(),
+                approved: false,
+                failed_attempts: 0,
+                waiting_handshakes: 0,
+            },
+        );
+    }
+
+    /// Record that the domain policy approved a certificate on demand for `domain`, which is now
+    /// being ordered.
+    pub(crate) fn approve_on_demand(&self, domain: &str) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(pending) = inner.on_demand_names.get_mut(domain) {
+            pending.approved = true;
+        }
+    }
+
+    /// Allow a server name denied a certificate on demand to be requested again.
+    pub(crate) fn forget_on_demand(&self, domain: &str) {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .on_demand_names
+            .remove(domain);
+        self.issuance.notify(usize::MAX);
+    }
+
+    /// Record a failed order for `domains`, so handshakes waiting for a certificate on demand for
+    /// one of them go ahead without it. The order is retried with backoff as usual.
+    pub(crate) fn issuance_failed(&self, domains: &[String]) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut failed = false;
+        for domain in domains {
+            if let Some(pending) = inner.on_demand_names.get_mut(&normalize(domain)) {
+                pending.failed_attempts += 1;
+                failed = true;
+            }
+        }
+        if failed {
+            self.issuance.notify(usize::MAX);
+        }
+    }
+
+    /// The certificates being obtained on demand, by server name.
+    pub(crate) fn pending_issuances(&self) -> Vec<PendingIssuance> {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.on_demand_names.values().cloned().collect()
+    }
+
+    /// Whether certificates are obtained on demand.
+    pub(crate) fn on_demand(&self) -> bool {
+        self.on_demand.is_some()
+    }
+
+    /// Wait up to `max_wait` for the certificate on demand for the server name `domain`, if no
+    /// certificate covers it yet, requesting it unless it was requested already. Returns once it
+    /// is deployed, denied or an attempt to order it fails, so the handshake gets it if it can.
+    pub(crate) async fn wait_for_issuance(&self, domain: &str, max_wait: Duration) {
+        let domain = match self.server_name(Some(domain)) {
+            Some(domain) => domain,
+            None => return,
+        };
+        let failed_attempts = {
+            let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+            self.send_on_demand(&mut inner, &domain);
+            if inner.lookup(&domain).is_some() {
+                return;
+            }
+            match inner.on_demand_names.get_mut(&domain) {
+                Some(pending) => {
+                    pending.waiting_handshakes += 1;
+                    pending.failed_attempts
+                }
+                None => return,
+            }
+        };
+        // Counted as waiting until done, or until the handshake times out and drops the wait.
+        let _waiting = Waiting {
+            resolver: self,
+            domain: &domain,
+        };
+        debug!(%domain, "waiting for the certificate on demand");
+        let deadline = self.clock.now() + max_wait;
+        loop {
+            let issuance = self.issuance.listen();
+            {
+                let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+                let pending = inner.on_demand_names.get(&domain);
+                if pending.is_none_or(|pending| pending.failed_attempts != failed_attempts) {
+                    break;
+                }
+            }
+            let expired = Box::pin(self.clock.sleep_until(deadline));
+            if let Either::Right(_) = select(issuance, expired).await {
+                break;
+            }
+        }
+    }
+
+    /// Get the table server names are matched against: each normalized name, with the domains
+    /// of the certificate served for it, as configured.
+    pub fn sni_table(&self) -> BTreeMap<String, Vec<String>> {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner
+            .certs
+            .iter()
+            .map(|(name, (_, domains, _))| (name.clone(), domains.to_vec()))
+            .collect()
+    }
+
+    /// The normalized name to serve a certificate for, given the server name sent by the client.
+    fn server_name(&self, domain: Option<&str>) -> Option<String> {
+        let domain = domain?;
+        let domain = self
+            .sni_override
+            .as_ref()
+            .and_then(|sni_override| sni_override(domain))
+            .unwrap_or_else(|| domain.into());
+        Some(normalize(&domain))
+    }
+
+    /// Record a completed handshake for the server name sent by the client.
+    pub(crate) fn record_handshake(&self, domain: Option<&str>) {
+        let domain = self.server_name(domain);
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let key = inner.stats_key(domain.as_deref());
+        inner.stats.entry(key).or_default().handshakes += 1;
+    }
+
+    /// Record how long a validation connection took until it was closed, and whether closing it
+    /// timed out.
+    pub(crate) fn record_challenge_close(
+        &self,
+        domain: Option<&str>,
+        duration: Duration,
+        timed_out: bool,
+    ) {
+        let domain = domain.map(challenge_name);
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let key = inner.stats_key(domain.as_deref());
+        let stats = inner.challenge_stats.entry(key).or_default();
+        stats.total_duration += duration;
+        stats.max_duration = stats.max_duration.max(duration);
+        if timed_out {
+            stats.close_timeouts += 1;
+        }
+    }
+
+    pub(crate) fn stats(&self) -> BTreeMap<Option<String>, TlsStats> {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .stats
+            .clone()
+    }
+
+    pub(crate) fn challenge_stats(&self) -> BTreeMap<Option<String>, ChallengeStats> {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .challenge_stats
+            .clone()
+    }
+}
+
+/// A handshake waiting for a certificate on demand, counted in its [`PendingIssuance`] until
+/// dropped.
+struct Waiting<'a> {
+    resolver: &'a AcmeResolver,
+    domain: &'a str,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let mut inner = self
+            .resolver
+            .inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(pending) = inner.on_demand_names.get_mut(self.domain) {
+            pending.waiting_handshakes = pending.waiting_handshakes.saturating_sub(1);
+        }
+    }
+}
+
+impl ResolvesServerCert for AcmeResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        self.resolve_with(client_hello, false, None)
+    }
+}
+
+/// Resolver for a listener with strict SNI: like [`AcmeResolver`], but without a certificate for
+/// connections without SNI or with a name no certificate covers, so their handshakes fail.
+pub(crate) struct StrictSniResolver(pub(crate) Arc<AcmeResolver>);
+
+impl ResolvesServerCert for StrictSniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        self.0.resolve_with(client_hello, true, None)
+    }
+}
+
+/// Resolver for a connection without SNI to a local IP address a certificate covers, as from
+/// clients connecting by IP address: like [`AcmeResolver`], but serving that certificate.
+pub(crate) struct LocalIpResolver {
+    pub(crate) resolver: Arc<AcmeResolver>,
+    pub(crate) strict: bool,
+    pub(crate) local_ip: IpAddr,
+}
+
+impl ResolvesServerCert for LocalIpResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        self.resolver
+            .resolve_with(client_hello, self.strict, Some(self.local_ip))
+    }
+}
+
+impl AcmeResolver {
+    /// Resolve the certificate for a handshake, falling back to the default certificate unless
+    /// `strict`. Without SNI, the certificate for `local_ip`, the address the connection arrived
+    /// on, is served if there is one.
+    fn resolve_with(
+        &self,
+        client_hello: ClientHello,
+        strict: bool,
+        local_ip: Option<IpAddr>,
+    ) -> Option<CertifiedKey> {
+        if client_hello.alpn() == Some(&[ACME_TLS_ALPN_NAME]) {
+            let domain: Option<&str> = client_hello.server_name().map(Into::into);
+            let domain = domain.map(challenge_name);
+            let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+            let cert = match &domain {
+                None => {
+                    debug!("client did not supply SNI");
+                    None
+                }
+                Some(domain) => inner.auth_keys.get(domain).cloned(),
+            };
+            let key = inner.stats_key(domain.as_deref());
+            let stats = inner.challenge_stats.entry(key).or_default();
+            let now = self.clock.now();
+            stats.tls_alpn_01 += 1;
+            stats.first_at.get_or_insert(now);
+            stats.last_at = Some(now);
+            if cert.is_none() {
+                stats.missing_key += 1;
+            }
+            cert
+        } else {
+            let domain = self.server_name(client_hello.server_name().map(Into::into));
+            let domain = domain.as_deref();
+            let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+            // Unless strict, names no certificate covers get the default certificate, and so
+            // do connections without SNI if that's the policy for them.
+            let fallback =
+                !strict && (domain.is_some() || self.missing_sni == MissingSni::FirstDomain);
+            let local_ip = local_ip.map(|ip| ip::canonical(ip).to_string());
+            let name = match (domain, &self.missing_sni) {
+                (Some(domain), _) => inner.lookup(domain),
+                (None, _)
+                    if local_ip
+                        .as_ref()
+                        .is_some_and(|ip| inner.certs.contains_key(ip)) =>
+                {
+                    local_ip.as_deref()
+                }
+                (None, MissingSni::Domain(default)) if !strict => inner.lookup(default),
+                (None, _) => None,
+            };
+            let cert = match fallback {
+                true => name
+                    .or(inner.default_domain.as_deref())
+                    .and_then(|name| inner.certs.get(name))
+                    .or_else(|| inner.certs.values().min_by_key(|(rank, _, _)| *rank)),
+                false => name.and_then(|name| inner.certs.get(name)),
+            }
+            .map(|(_, _, cert)| cert.clone());
+            let rejected = strict || (domain.is_none() && self.missing_sni == MissingSni::Reject);
+            let cert = match (&cert, &inner.fallback) {
+                (None, Some(fallback)) if inner.certs.is_empty() && !rejected => {
+                    Some(fallback.clone())
+                }
+                _ => cert,
+            };
+            if let Some(domain) = domain {
+                self.send_on_demand(&mut inner, domain);
+            }
+            let key = inner.stats_key(domain);
+            let stats = inner.stats.entry(key).or_default();
+            stats.client_hellos += 1;
+            if cert.is_none() {
+                stats.no_certificate += 1;
+            }
+            cert
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use async_std::task::{self, block_on};
+    use futures::future::{join, join_all};
+    use tide_rustls::async_rustls::webpki::DNSNameRef;
+    use tide_rustls::rustls::sign::any_supported_type;
+    use tide_rustls::rustls::{
+        Certificate, ClientConfig, ClientSession, NoClientAuth, PrivateKey, RootCertStore,
+        ServerCertVerified, ServerCertVerifier, ServerConfig, ServerSession, Session, TLSError,
+    };
+
+    use super::*;
+    use crate::SystemClock;
+
+    /// A self-signed certificate for `names`, and its DER encoding to trust it by.
+    /// Approves every server name.
+    struct AnyName;
+
+    #[async_trait::async_trait]
+    impl DomainPolicy for AnyName {
+        async fn approve(&self, _: &str) -> bool {
+            true
+        }
+    }
+
+    fn on_demand_queue() -> (OnDemandQueue, Receiver<String>) {
+        OnDemandQueue::new(Arc::new(AnyName))
+    }
+
+    fn cert(names: &[&str]) -> (CertifiedKey, Vec<u8>) {
+        let names = names
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        let cert = rcgen::generate_simple_self_signed(names).unwrap();
+        let der = cert.serialize_der().unwrap();
+        let key = any_supported_type(&PrivateKey(cert.serialize_private_key_der())).unwrap();
+        let key = CertifiedKey::new(vec![Certificate(der.clone())], Arc::new(key));
+        (key, der)
+    }
+
+    /// Run a handshake against `resolver` for server name `name`, sent as SNI if `sni`, trusting
+    /// `roots`, returning the certificate the server presented.
+    fn handshake(
+        resolver: &Arc<AcmeResolver>,
+        name: &str,
+        sni: bool,
+        roots: &[&[u8]],
+    ) -> Result<Vec<u8>, TLSError> {
+        handshake_with(resolver.clone(), name, sni, roots)
+    }
+
+    fn handshake_with(
+        resolver: Arc<dyn ResolvesServerCert>,
+        name: &str,
+        sni: bool,
+        roots: &[&[u8]],
+    ) -> Result<Vec<u8>, TLSError> {
+        let mut server_config = ServerConfig::new(NoClientAuth::new());
+        server_config.cert_resolver = resolver;
+        let mut server = ServerSession::new(&Arc::new(server_config));
+        let mut client_config = ClientConfig::new();
+        for root in roots {
+            client_config
+                .root_store
+                .add(&Certificate(root.to_vec()))
+                .unwrap();
+        }
+        client_config.enable_sni = sni;
+        let name = DNSNameRef::try_from_ascii_str(name).unwrap();
+        let mut client = ClientSession::new(&Arc::new(client_config), name);
+        fn transfer(from: &mut dyn Session, to: &mut dyn Session) -> Result<(), TLSError> {
+            let mut buf = Vec::new();
+            while from.wants_write() {
+                from.write_tls(&mut buf).unwrap();
+            }
+            let mut rest = &buf[..];
+            while !rest.is_empty() {
+                to.read_tls(&mut rest).unwrap();
+                to.process_new_packets()?;
+            }
+            Ok(())
+        }
+        while client.is_handshaking() || server.is_handshaking() {
+            transfer(&mut client, &mut server)?;
+            transfer(&mut server, &mut client)?;
+        }
+        Ok(client.get_peer_certificates().unwrap()[0].0.clone())
+    }
+
+    #[test]
+    fn serves_certificates_by_sni() {
+        let resolver = AcmeResolver::new(
+            Some("a.example".into()),
+            SniPriority::default(),
+            MissingSni::default(),
+            None,
+            None,
+            Arc::new(SystemClock),
+        );
+        let (a, a_der) = cert(&["a.example", "other.example"]);
+        let (b, b_der) = cert(&["b.example", "c.example"]);
+        resolver.set_cert(0, &["a.example".into()], a);
+        resolver.set_cert(0, &["b.example".into(), "c.example".into()], b);
+        let roots = [&a_der[..], &b_der[..]];
+        let served = |name, sni| handshake(&resolver, name, sni, &roots).unwrap();
+        assert_eq!(served("a.example", true), a_der);
+        assert_eq!(served("b.example", true), b_der);
+        assert_eq!(served("c.example", true), b_der);
+        // Other names and handshakes without SNI get the default domain's certificate.
+        assert_eq!(served("other.example", true), a_der);
+        assert_eq!(served("a.example", false), a_der);
+    }
+
+    #[test]
+    fn survives_panics_while_holding_the_lock() {
+        let resolver = Arc::new(AcmeResolver::new(
+            None,
+            SniPriority::default(),
+            MissingSni::default(),
+            None,
+            None,
+            Arc::new(SystemClock),
+        ));
+        let poisoner = resolver.clone();
+        let _ = std::thread::spawn(move || {
+            let _inner = poisoner.inner.lock().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+        assert!(resolver.inner.is_poisoned());
+        let (a, a_der) = cert(&["a.example"]);
+        resolver.set_cert(0, &["a.example".into()], a);
+        let served = handshake(&resolver, "a.example", true, &[&a_der[..]]).unwrap();
+        assert_eq!(served, a_der);
+    }
+
+    #[test]
+    fn prefers_certificates_by_priority_and_rank() {
+        let (exact, exact_der) = cert(&["www.a.example", "b.example"]);
+        let (wildcard, wildcard_der) = cert(&["*.a.example", "b.example"]);
+        let roots = [&exact_der[..], &wildcard_der[..]];
+        for priority in [SniPriority::ExactFirst, SniPriority::WildcardFirst].iter() {
+            let resolver = AcmeResolver::new(
+                None,
+                *priority,
+                MissingSni::default(),
+                None,
+                None,
+                Arc::new(SystemClock),
+            );
+            resolver.set_cert(
+                1,
+                &["*.a.example".into(), "b.example".into()],
+                wildcard.clone(),
+            );
+            resolver.set_cert(
+                0,
+                &["www.a.example".into(), "b.example".into()],
+                exact.clone(),
+            );
+            let served = |name| handshake(&resolver, name, true, &roots).unwrap();
+            let www = match priority {
+                SniPriority::ExactFirst => &exact_der,
+                SniPriority::WildcardFirst => &wildcard_der,
+            };
+            assert_eq!(&served("www.a.example"), www);
+            assert_eq!(served("mail.a.example"), wildcard_der);
+            // The certificate configured first keeps the names both cover, and is the default.
+            assert_eq!(served("b.example"), exact_der);
+            let default = handshake(&resolver, "b.example", false, &roots).unwrap();
+            assert_eq!(default, exact_der);
+        }
+    }
+
+    #[test]
+    fn counts_handshakes_per_managed_name() {
+        let resolver = AcmeResolver::new(
+            None,
+            SniPriority::default(),
+            MissingSni::default(),
+            None,
+            None,
+            Arc::new(SystemClock),
+        );
+        assert!(handshake(&resolver, "a.example", true, &[]).is_err());
+        let (a, a_der) = cert(&["a.example"]);
+        resolver.set_cert(0, &["a.example".into()], a);
+        handshake(&resolver, "a.example", true, &[&a_der]).unwrap();
+        resolver.record_handshake(Some("a.example"));
+        assert!(handshake(&resolver, "other.example", true, &[]).is_err());
+        let stats = resolver.stats();
+        assert_eq!(stats.len(), 2);
+        let named = &stats[&Some("a.example".to_string())];
+        assert_eq!(
+            (named.client_hellos, named.handshakes, named.no_certificate),
+            (1, 1, 0)
+        );
+        let unnamed = &stats[&None];
+        assert_eq!(
+            (
+                unnamed.client_hellos,
+                unnamed.handshakes,
+                unnamed.no_certificate
+            ),
+            (2, 0, 1)
+        );
+        assert_eq!(unnamed.incomplete_handshakes(), 2);
+    }
+
+    #[test]
+    fn overrides_server_names() {
+        let sni_override: SniOverride = Arc::new(|name: &str| {
+            name.strip_suffix(".cdn.example")
+                .map(|customer| format!("{}.example", customer))
+        });
+        let resolver = AcmeResolver::new(
+            None,
+            SniPriority::default(),
+            MissingSni::default(),
+            Some(sni_override),
+            None,
+            Arc::new(SystemClock),
+        );
+        let (a, a_der) = cert(&["a.example", "a.cdn.example"]);
+        let (b, b_der) = cert(&["b.example", "b.cdn.example"]);
+        resolver.set_cert(0, &["a.example".into()], a);
+        resolver.set_cert(0, &["b.example".into()], b);
+        let roots = [&a_der[..], &b_der[..]];
+        let served = |name| handshake(&resolver, name, true, &roots).unwrap();
+        assert_eq!(served("b.cdn.example"), b_der);
+        assert_eq!(served("b.example"), b_der);
+        assert_eq!(served("a.cdn.example"), a_der);
+        resolver.record_handshake(Some("b.cdn.example"));
+        let stats = resolver.stats();
+        assert_eq!(stats[&Some("b.example".to_string())].client_hellos, 2);
+        assert_eq!(stats[&Some("b.example".to_string())].handshakes, 1);
+    }
+
+    /// Send a tls-alpn-01 validation ClientHello for `name` to `resolver`.
+    fn validation_hello(resolver: &Arc<AcmeResolver>, name: &str) {
+        let mut server_config = ServerConfig::new(NoClientAuth::new());
+        server_config.cert_resolver = resolver.clone();
+        server_config.alpn_protocols = vec![ACME_TLS_ALPN_NAME.to_vec()];
+        let mut server = ServerSession::new(&Arc::new(server_config));
+        let mut client_config = ClientConfig::new();
+        client_config.alpn_protocols = vec![ACME_TLS_ALPN_NAME.to_vec()];
+        let name = DNSNameRef::try_from_ascii_str(name).unwrap();
+        let mut client = ClientSession::new(&Arc::new(client_config), name);
+        let mut hello = Vec::new();
+        client.write_tls(&mut hello).unwrap();
+        server.read_tls(&mut &hello[..]).unwrap();
+        let _ = server.process_new_packets();
+    }
+
+    #[test]
+    fn counts_validation_connections() {
+        let resolver = AcmeResolver::new(
+            None,
+            SniPriority::default(),
+            MissingSni::default(),
+            None,
+            None,
+            Arc::new(SystemClock),
+        );
+        let (key, _) = cert(&["a.example"]);
+        resolver.set_auth_key("a.example".into(), key);
+        validation_hello(&resolver, "a.example");
+        validation_hello(&resolver, "a.example");
+        validation_hello(&resolver, "b.example");
+        let stats = resolver.challenge_stats();
+        let a = &stats[&Some("a.example".to_string())];
+        assert_eq!((a.tls_alpn_01, a.missing_key), (2, 0));
+        assert!(a.first_at.unwrap() <= a.last_at.unwrap());
+        let other = &stats[&None];
+        assert_eq!((other.tls_alpn_01, other.missing_key), (1, 1));
+        // Validation connections aren't counted as handshakes.
+        assert!(resolver.stats().is_empty());
+
+        let close = |domain, millis, timed_out| {
+            let duration = Duration::from_millis(millis);
+            resolver.record_challenge_close(Some(domain), duration, timed_out)
+        };
+        close("a.example", 20, false);
+        close("a.example", 50, true);
+        close("b.example", 10, false);
+        let stats = resolver.challenge_stats();
+        let a = &stats[&Some("a.example".to_string())];
+        assert_eq!(a.total_duration, Duration::from_millis(70));
+        assert_eq!(a.max_duration, Duration::from_millis(50));
+        assert_eq!(a.close_timeouts, 1);
+        assert_eq!(stats[&None].close_timeouts, 0);
+    }
+
+    #[test]
+    fn normalizes_names() {
+        assert_eq!(normalize("Domain.Example."), "domain.example");
+        assert_eq!(normalize("bücher.example"), "xn--bcher-kva.example");
+        assert_eq!(normalize("BÜCHER.example"), "xn--bcher-kva.example");
+        assert_eq!(normalize("::FFFF:192.0.2.1"), "192.0.2.1");
+        assert_eq!(normalize("2001:DB8:0::1"), "2001:db8::1");
+        assert_eq!(challenge_name("1.2.0.192.in-addr.arpa"), "192.0.2.1");
+        assert_eq!(challenge_name("Domain.Example"), "domain.example");
+    }
+
+    /// Accepts any certificate, to see which one is served for an IP address, which the client
+    /// can't verify certificates for.
+    struct AcceptAny;
+
+    impl ServerCertVerifier for AcceptAny {
+        fn verify_server_cert(
+            &self,
+            _roots: &RootCertStore,
+            _presented_certs: &[Certificate],
+            _dns_name: DNSNameRef,
+            _ocsp_response: &[u8],
+        ) -> Result<ServerCertVerified, TLSError> {
+            Ok(ServerCertVerified::assertion())
+        }
+    }
+
+    #[test]
+    fn serves_certificates_for_local_ip_addresses_without_sni() {
+        let resolver = AcmeResolver::new(
+            Some("a.example".into()),
+            SniPriority::default(),
+            MissingSni::default(),
+            None,
+            None,
+            Arc::new(SystemClock),
+        );
+        let (a, a_der) = cert(&["a.example"]);
+        let (ip, ip_der) = cert(&["192.0.2.1"]);
+        resolver.set_cert(0, &["a.example".into()], a);
+        resolver.set_cert(0, &["192.0.2.1".into()], ip);
+        assert!(resolver.covers_ip("::ffff:192.0.2.1".parse().unwrap()));
+        assert!(!resolver.covers_ip("192.0.2.2".parse().unwrap()));
+        let served = |local_ip: &str| {
+            let resolver = LocalIpResolver {
+                resolver: resolver.clone(),
+                strict: false,
+                local_ip: local_ip.parse().unwrap(),
+            };
+            let mut server_config = ServerConfig::new(NoClientAuth::new());
+            server_config.cert_resolver = Arc::new(resolver);
+            let mut server = ServerSession::new(&Arc::new(server_config));
+            let mut client_config = ClientConfig::new();
+            client_config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(AcceptAny));
+            client_config.enable_sni = false;
+            let name = DNSNameRef::try_from_ascii_str("ip.example").unwrap();
+            let mut client = ClientSession::new(&Arc::new(client_config), name);
+            while client.is_handshaking() || server.is_handshaking() {
+                let mut buf = Vec::new();
+                while client.wants_write() {
+                    client.write_tls(&mut buf).unwrap();
+                }
+                server.read_tls(&mut &buf[..]).unwrap();
+                server.process_new_packets().unwrap();
+                let mut buf = Vec::new();
+                while server.wants_write() {
+                    server.write_tls(&mut buf).unwrap();
+                }
+                client.read_tls(&mut &buf[..]).unwrap();
+                client.process_new_packets().unwrap();
+            }
+            client.get_peer_certificates().unwrap()[0].0.clone()
+        };
+        assert_eq!(served("192.0.2.1"), ip_der);
+        assert_eq!(served("192.0.2.2"), a_der);
+    }
+
+    #[test]
+    fn matches_wildcard_certificates() {
+        let resolver = AcmeResolver::new(
+            None,
+            SniPriority::default(),
+            MissingSni::default(),
+            None,
+            None,
+            Arc::new(SystemClock),
+        );
+        let (exact, exact_der) = cert(&["b.example"]);
+        let (wildcard, wildcard_der) = cert(&["*.b.example"]);
+        resolver.set_cert(0, &["B.example".into()], exact);
+        resolver.set_cert(0, &["*.b.example".into()], wildcard);
+        let roots = [&exact_der[..], &wildcard_der[..]];
+        let served = |name| handshake(&resolver, name, true, &roots);
+        assert_eq!(served("b.example").unwrap(), exact_der);
+        assert_eq!(served("www.b.example").unwrap(), wildcard_der);
+        // Wildcards only cover a single label.
+        assert!(served("a.www.b.example").is_err());
+        let table = resolver.sni_table();
+        assert_eq!(table["b.example"], ["B.example"]);
+        assert_eq!(table["*.b.example"], ["*.b.example"]);
+    }
+
+    #[test]
+    fn refuses_unknown_names_with_strict_sni() {
+        let resolver = AcmeResolver::new(
+            Some("a.example".into()),
+            SniPriority::default(),
+            MissingSni::default(),
+            None,
+            None,
+            Arc::new(SystemClock),
+        );
+        let (a, a_der) = cert(&["a.example", "other.example"]);
+        resolver.set_cert(0, &["a.example".into()], a);
+        let strict: Arc<dyn ResolvesServerCert> = Arc::new(StrictSniResolver(resolver));
+        let served = |name, sni| handshake_with(strict.clone(), name, sni, &[&a_der[..]]);
+        assert_eq!(served("a.example", true).unwrap(), a_der);
+        // Neither the default domain nor any other certificate is served instead.
+        assert!(served("other.example", true).is_err());
+        assert!(served("a.example", false).is_err());
+    }
+
+    #[test]
+    fn requests_unknown_names_on_demand() {
+        let (sender, mut requests) = on_demand_queue();
+        let resolver = AcmeResolver::new(
+            Some("a.example".into()),
+            SniPriority::default(),
+            MissingSni::default(),
+            None,
+            Some(sender),
+            Arc::new(SystemClock),
+        );
+        let (a, a_der) = cert(&["a.example"]);
+        resolver.set_cert(0, &["a.example".into()], a);
+        let served = |name| handshake(&resolver, name, true, &[&a_der[..]]);
+        assert!(served("a.example").is_ok());
+        assert!(served("New.example").is_err());
+        assert!(served("new.example").is_err());
+        assert_eq!(requests.try_recv().ok().as_deref(), Some("new.example"));
+        // Pending names are requested once, until forgotten.
+        assert!(requests.try_recv().is_err());
+        resolver.forget_on_demand("new.example");
+        assert!(served("new.example").is_err());
+        assert_eq!(requests.try_recv().ok().as_deref(), Some("new.example"));
+    }
+
+    /// Could approve customer domains only.
+    struct Customers;
+
+    #[async_trait::async_trait]
+    impl DomainPolicy for Customers {
+        async fn approve(&self, _: &str) -> bool {
+            true
+        }
+
+        fn may_approve(&self, domain: &str) -> bool {
+            domain.ends_with(".customer.example")
+        }
+    }
+
+    fn on_demand_resolver(queue: OnDemandQueue) -> Arc<AcmeResolver> {
+        AcmeResolver::new(
+            None,
+            SniPriority::default(),
+            MissingSni::default(),
+            None,
+            Some(queue),
+            Arc::new(SystemClock),
+        )
+    }
+
+    #[test]
+    fn checks_the_policy_before_queuing() {
+        let (queue, mut requests) = OnDemandQueue::new(Arc::new(Customers));
+        let resolver = on_demand_resolver(queue);
+        for name in [
+            "evil.example",
+            "customer.example",
+            "bad name.customer.example",
+            "-a.customer.example",
+        ] {
+            resolver.request_on_demand(name);
+        }
+        assert!(requests.try_recv().is_err());
+        assert!(resolver.pending_issuances().is_empty());
+        resolver.request_on_demand("shop.customer.example");
+        assert_eq!(requests.try_recv().as_deref(), Ok("shop.customer.example"));
+
+        // Names that aren't host names are never queued, whatever the policy.
+        let (queue, mut requests) = on_demand_queue();
+        let resolver = on_demand_resolver(queue);
+        let long = format!("{}.example", "a".repeat(64));
+        for name in ["localhost", "192.0.2.1", "a..example", "*.a.example", &long] {
+            resolver.request_on_demand(name);
+        }
+        assert!(requests.try_recv().is_err());
+        assert!(is_hostname("xn--bcher-kva.example"));
+    }
+
+    #[test]
+    fn bounds_the_names_queued_and_pending() {
+        let (queue, mut requests) = on_demand_queue();
+        let resolver = on_demand_resolver(queue);
+        for i in 0..ON_DEMAND_QUEUE_LEN * 2 {
+            resolver.request_on_demand(&format!("{}.example", i));
+        }
+        // The channel holds one more name than its buffer, for its one sender.
+        let queued = ON_DEMAND_QUEUE_LEN + 1;
+        assert_eq!(resolver.pending_issuances().len(), queued);
+        for _ in 0..queued {
+            assert!(requests.try_recv().is_ok());
+        }
+        assert!(requests.try_recv().is_err());
+        // A name turned away for lack of room is requested again by a later handshake.
+        resolver.request_on_demand(&format!("{}.example", queued));
+        assert_eq!(
+            requests.try_recv().ok(),
+            Some(format!("{}.example", queued))
+        );
+
+        let mut i = queued + 1;
+        while resolver.pending_issuances().len() < MAX_PENDING_ON_DEMAND {
+            resolver.request_on_demand(&format!("{}.example", i));
+            assert!(requests.try_recv().is_ok());
+            i += 1;
+        }
+        resolver.request_on_demand("over.example");
+        assert!(requests.try_recv().is_err());
+        assert_eq!(resolver.pending_issuances().len(), MAX_PENDING_ON_DEMAND);
+    }
+
+    #[test]
+    fn tracks_pending_issuances() {
+        let (sender, mut requests) = on_demand_queue();
+        let resolver = AcmeResolver::new(
+            None,
+            SniPriority::default(),
+            MissingSni::default(),
+            None,
+            Some(sender),
+            Arc::new(SystemClock),
+        );
+        resolver.request_on_demand("new.example");
+        resolver.request_on_demand("new.example");
+        assert_eq!(requests.try_recv().ok().as_deref(), Some("new.example"));
+        assert!(requests.try_recv().is_err());
+        let pending = resolver.pending_issuances();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].domain, "new.example");
+        assert!(!pending[0].approved);
+        resolver.approve_on_demand("new.example");
+        resolver.issuance_failed(&["New.example".into()]);
+        let pending = resolver.pending_issuances();
+        assert!(pending[0].approved);
+        assert_eq!(pending[0].failed_attempts, 1);
+        // Deploying a certificate for the name ends its issuance.
+        resolver.set_cert(0, &["new.example".into()], cert(&["new.example"]).0);
+        assert!(resolver.pending_issuances().is_empty());
+    }
+
+    #[test]
+    fn waits_for_issuance() {
+        let (sender, mut requests) = on_demand_queue();
+        let resolver = AcmeResolver::new(
+            None,
+            SniPriority::default(),
+            MissingSni::default(),
+            None,
+            Some(sender),
+            Arc::new(SystemClock),
+        );
+        let long = Duration::from_secs(60);
+        let waiting = |resolver: &AcmeResolver| {
+            resolver
+                .pending_issuances()
+                .iter()
+                .map(|pending| pending.waiting_handshakes)
+                .sum::<usize>()
+        };
+        block_on(async {
+            // Until the wait times out.
+            let started = Instant::now();
+            resolver
+                .wait_for_issuance("a.example", Duration::from_millis(50))
+                .await;
+            assert!(started.elapsed() >= Duration::from_millis(50));
+            assert_eq!(requests.try_recv().ok().as_deref(), Some("a.example"));
+            assert_eq!(waiting(&resolver), 0);
+            // Until the certificate is deployed.
+            let deploy = async {
+                while waiting(&resolver) == 0 {
+                    task::yield_now().await;
+                }
+                resolver.set_cert(0, &["a.example".into()], cert(&["a.example"]).0);
+            };
+            join(resolver.wait_for_issuance("a.example", long), deploy).await;
+            // Not at all once it is.
+            resolver.wait_for_issuance("a.example", long).await;
+            assert!(requests.try_recv().is_err());
+            // Until the name is denied.
+            let deny = async {
+                while waiting(&resolver) == 0 {
+                    task::yield_now().await;
+                }
+                resolver.forget_on_demand("b.example");
+            };
+            join(resolver.wait_for_issuance("b.example", long), deny).await;
+            // Until an order for it fails.
+            let fail = async {
+                while waiting(&resolver) == 0 {
+                    task::yield_now().await;
+                }
+                resolver.issuance_failed(&["c.example".into()]);
+            };
+            join(resolver.wait_for_issuance("c.example", long), fail).await;
+            assert_eq!(waiting(&resolver), 0);
+            assert_eq!(resolver.pending_issuances()[0].failed_attempts, 1);
+        });
+    }
+
+    #[test]
+    fn stops_counting_abandoned_waits() {
+        let (sender, _requests) = on_demand_queue();
+        let resolver = AcmeResolver::new(
+            None,
+            SniPriority::default(),
+            MissingSni::default(),
+            None,
+            Some(sender),
+            Arc::new(SystemClock),
+        );
+        block_on(async {
+            // As when the handshake times out first.
+            let mut wait =
+                Box::pin(resolver.wait_for_issuance("a.example", Duration::from_secs(3600)));
+            assert!(futures::poll!(wait.as_mut()).is_pending());
+            assert_eq!(resolver.pending_issuances()[0].waiting_handshakes, 1);
+            drop(wait);
+            assert_eq!(resolver.pending_issuances()[0].waiting_handshakes, 0);
+        });
+    }
+
+    #[cfg(feature = "test-util")]
+    #[test]
+    fn waits_by_the_configured_clock() {
+        use crate::test::ManualClock;
+
+        let (sender, _requests) = on_demand_queue();
+        let clock = Arc::new(ManualClock::new());
+        let resolver = AcmeResolver::new(
+            None,
+            SniPriority::default(),
+            MissingSni::default(),
+            None,
+            Some(sender),
+            clock.clone(),
+        );
+        block_on(async {
+            let mut wait =
+                Box::pin(resolver.wait_for_issuance("a.example", Duration::from_secs(5)));
+            assert!(futures::poll!(wait.as_mut()).is_pending());
+            assert_eq!(resolver.pending_issuances()[0].requested_at, clock.now());
+            clock.advance(Duration::from_secs(4));
+            assert!(futures::poll!(wait.as_mut()).is_pending());
+            clock.advance(Duration::from_secs(1));
+            wait.await;
+            assert_eq!(resolver.pending_issuances()[0].waiting_handshakes, 0);
+        });
+    }
+
+    #[test]
+    fn handles_handshakes_without_sni() {
+        let (a, a_der) = cert(&["a.example"]);
+        let (b, b_der) = cert(&["a.example", "b.example"]);
+        let roots = [&a_der[..], &b_der[..]];
+        let resolver = |missing_sni| {
+            let resolver = AcmeResolver::new(
+                Some("a.example".into()),
+                SniPriority::default(),
+                missing_sni,
+                None,
+                None,
+                Arc::new(SystemClock),
+            );
+            resolver.set_cert(0, &["a.example".into()], a.clone());
+            resolver.set_cert(0, &["b.example".into()], b.clone());
+            resolver
+        };
+        let served = |resolver, sni| handshake(&resolver, "a.example", sni, &roots);
+        let domain = resolver(MissingSni::Domain("B.example".into()));
+        assert_eq!(served(domain.clone(), false).unwrap(), b_der);
+        assert_eq!(served(domain, true).unwrap(), a_der);
+        let reject = resolver(MissingSni::Reject);
+        assert!(served(reject.clone(), false).is_err());
+        assert_eq!(served(reject, true).unwrap(), a_der);
+    }
+
+    #[test]
+    fn serves_the_fallback_until_a_certificate_is_obtained() {
+        let resolver = AcmeResolver::new(
+            None,
+            SniPriority::default(),
+            MissingSni::default(),
+            None,
+            None,
+            Arc::new(SystemClock),
+        );
+        let fallback = crate::challenge::self_signed_cert(vec!["a.example".into()]).unwrap();
+        let fallback_der = fallback.cert[0].0.clone();
+        resolver.set_fallback(fallback);
+        let (a, a_der) = cert(&["a.example"]);
+        let roots = [&fallback_der[..], &a_der[..]];
+        assert_eq!(
+            handshake(&resolver, "a.example", true, &roots).unwrap(),
+            fallback_der
+        );
+        resolver.set_cert(0, &["a.example".into()], a);
+        assert_eq!(
+            handshake(&resolver, "a.example", true, &roots).unwrap(),
+            a_der
+        );
+    }
+
+    #[test]
+    fn coalesces_concurrent_on_demand_requests() {
+        let (requests, mut received) = on_demand_queue();
+        let resolver = AcmeResolver::new(
+            None,
+            SniPriority::default(),
+            MissingSni::default(),
+            None,
+            Some(requests),
+            Arc::new(SystemClock),
+        );
+        let waits = join_all(
+            (0..50).map(|_| resolver.wait_for_issuance("New.Example", Duration::from_secs(60))),
+        );
+        let fail = async {
+            task::sleep(Duration::from_millis(50)).await;
+            assert_eq!(resolver.pending_issuances()[0].waiting_handshakes, 50);
+            resolver.issuance_failed(&["new.example".to_string()]);
+        };
+        let done = async_std::future::timeout(Duration::from_secs(10), join(waits, fail));
+        block_on(done).expect("a failed order releases every waiter");
+
+        assert_eq!(received.try_recv().as_deref(), Ok("new.example"));
+        assert!(received.try_recv().is_err());
+        let pending = resolver.pending_issuances();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].failed_attempts, 1);
+        assert_eq!(pending[0].waiting_handshakes, 0);
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// How failed orders are retried; see [`AcmeConfig::retry_policy`](crate::AcmeConfig::retry_policy).
+///
+/// Retries back off exponentially, doubling the delay after each consecutive failure from
+/// [`initial_delay`](Self::initial_delay) up to [`max_delay`](Self::max_delay). The defaults
+/// start at one second and stop doubling after about 18 hours, without jitter or a cap on
+/// attempts.
+///
+/// ```
+/// use std::time::Duration;
+/// use tide_acme::{AcmeConfig, RetryPolicy};
+///
+/// // Let's Encrypt allows 5 failed validations per domain per hour.
+/// let config = AcmeConfig::new(vec!["domain.example"]).retry_policy(
+///     RetryPolicy::new()
+///         .initial_delay(Duration::from_secs(60))
+///         .max_delay(Duration::from_secs(6 * 60 * 60))
+///         .jitter(0.2)
+///         .max_attempts(4, Duration::from_secs(60 * 60)),
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    initial_delay: Duration,
+    max_delay: Duration,
+    jitter: f64,
+    max_attempts: Option<(u32, Duration)>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(1 << 16),
+            jitter: 0.0,
+            max_attempts: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Create the default retry policy.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Retry after `delay` following the first failure. Defaults to one second.
+    pub fn initial_delay(mut self, delay: Duration) -> Self {
+        self.initial_delay = delay;
+        self
+    }
+
+    /// Stop doubling the delay once it reaches `delay`. Defaults to 2^16 seconds, about 18 hours.
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// Shorten each delay by a random fraction of up to `jitter`, between 0 and 1, so that
+    /// processes failing together don't retry in lockstep. Defaults to 0.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Place at most `attempts` orders for each certificate within any period of `window`,
+    /// delaying retries past the backoff if needed, so retries don't use up the CA's rate limits.
+    /// Unlimited by default.
+    pub fn max_attempts(mut self, attempts: u32, window: Duration) -> Self {
+        self.max_attempts = Some((attempts.max(1), window));
+        self
+    }
+
+    /// The delay before retrying after `failures` consecutive failures, counting from zero.
+    pub(crate) fn delay(&self, failures: u32) -> Duration {
+        let delay = self
+            .initial_delay
+            .saturating_mul(1 << failures.min(31))
+            .min(self.max_delay);
+        if self.jitter == 0.0 {
+            return delay;
+        }
+        let mut random = [0; 4];
+        if SystemRandom::new().fill(&mut random).is_err() {
+            return delay;
+        }
+        let fraction = u32::from_be_bytes(random) as f64 / u32::MAX as f64;
+        delay.mul_f64(1.0 - self.jitter * fraction)
+    }
+
+    /// Record an order placed at `now` in `attempts`, forgetting those outside the window.
+    pub(crate) fn record_attempt(&self, attempts: &mut VecDeque<SystemTime>, now: SystemTime) {
+        let (max, window) = match self.max_attempts {
+            Some(max_attempts) => max_attempts,
+            None => return,
+        };
+        while attempts.len() >= max as usize
+            || attempts.front().is_some_and(|&at| at + window <= now)
+        {
+            attempts.pop_front();
+        }
+        attempts.push_back(now);
+    }
+
+    /// The earliest time at or after `at` that another order fits in the window.
+    pub(crate) fn next_attempt(
+        &self,
+        attempts: &VecDeque<SystemTime>,
+        at: SystemTime,
+    ) -> SystemTime {
+        match self.max_attempts {
+            Some((max, window)) if attempts.len() >= max as usize => {
+                let oldest = attempts[attempts.len() - max as usize];
+                at.max(oldest + window)
+            }
+            _ => at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_maximum() {
+        let policy = RetryPolicy::new()
+            .initial_delay(Duration::from_secs(2))
+            .max_delay(Duration::from_secs(60));
+        let delays: Vec<u64> = (0..7).map(|n| policy.delay(n).as_secs()).collect();
+        assert_eq!(delays, [2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(
+            RetryPolicy::default().delay(u32::MAX),
+            Duration::from_secs(1 << 16)
+        );
+    }
+
+    #[test]
+    fn shortens_delays_by_up_to_the_jitter() {
+        let policy = RetryPolicy::new()
+            .initial_delay(Duration::from_secs(100))
+            .jitter(0.25);
+        for _ in 0..100 {
+            let delay = policy.delay(0);
+            assert!(delay >= Duration::from_secs(75) && delay <= Duration::from_secs(100));
+        }
+        assert_eq!(RetryPolicy::new().jitter(7.0).jitter, 1.0);
+    }
+
+    #[test]
+    fn limits_attempts_per_window() {
+        let hour = Duration::from_secs(60 * 60);
+        let policy = RetryPolicy::new().max_attempts(2, hour);
+        let start = SystemTime::UNIX_EPOCH + hour * 24;
+        let mut attempts = VecDeque::new();
+        policy.record_attempt(&mut attempts, start);
+        let soon = start + Duration::from_secs(60);
+        assert_eq!(policy.next_attempt(&attempts, soon), soon);
+        policy.record_attempt(&mut attempts, soon);
+        // Both attempts are in the window: wait until the first leaves it.
+        assert_eq!(policy.next_attempt(&attempts, soon), start + hour);
+        let later = start + hour * 2;
+        assert_eq!(policy.next_attempt(&attempts, later), later);
+        policy.record_attempt(&mut attempts, later);
+        assert_eq!(attempts, [later]);
+
+        let mut unlimited = VecDeque::new();
+        RetryPolicy::new().record_attempt(&mut unlimited, start);
+        assert!(unlimited.is_empty());
+    }
+}
@@ -0,0 +1,1925 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use http_types::other::Date;
+use ring::constant_time::verify_slices_are_equal;
+use ring::digest::{digest, SHA256};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tide::{Request, Response, StatusCode};
+use x509_parser::parse_x509_certificate;
+
+use crate::{AcmeEvent, AcmeHandle, CertReadiness, CertStatus, EventFilter, ServedCertificate};
+
+/// Tide endpoint serving the current certificate chain as PEM, for sharing renewed certificates
+/// with services on other hosts.
+///
+/// Requests must carry `Authorization: Bearer <token>`. The domain is taken from the `:domain`
+/// route parameter if there is one, or defaults to the first domain of the first certificate. The
+/// private key is only appended if enabled with [`PemDownload::include_key`].
+///
+/// ```no_run
+/// # fn example(handle: tide_acme::AcmeHandle) {
+/// let mut app = tide::new();
+/// app.at("/certs/:domain")
+///     .get(tide_acme::PemDownload::new(handle, "secret token"));
+/// # }
+/// ```
+///
+/// Serve this over HTTPS only, since the token is sent in the clear otherwise.
+pub struct PemDownload {
+    handle: AcmeHandle,
+    token: String,
+    include_key: bool,
+}
+
+impl PemDownload {
+    /// Create an endpoint serving certificates from `handle` to requests authenticated with
+    /// `token`.
+    pub fn new(handle: AcmeHandle, token: impl AsRef<str>) -> Self {
+        Self {
+            handle,
+            token: token.as_ref().into(),
+            include_key: false,
+        }
+    }
+
+    /// Append the private key to the certificate chain. Defaults to false.
+    pub fn include_key(mut self, include_key: bool) -> Self {
+        self.include_key = include_key;
+        self
+    }
+}
+
+/// Check that `req` carries `Authorization: Bearer <token>`, comparing in constant time.
+fn authorized<State>(req: &Request<State>, token: &str) -> bool {
+    let header = match req.header("Authorization") {
+        Some(header) => header.last().as_str(),
+        None => return false,
+    };
+    match header.strip_prefix("Bearer ") {
+        Some(sent) => verify_slices_are_equal(sent.as_bytes(), token.as_bytes()).is_ok(),
+        None => false,
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Endpoint<State> for PemDownload {
+    async fn call(&self, req: Request<State>) -> tide::Result {
+        if !authorized(&req, &self.token) {
+            return Ok(Response::new(StatusCode::Unauthorized));
+        }
+        let domain = match req.param("domain") {
+            Ok(domain) => domain.to_string(),
+            Err(_) => match self.handle.status().into_iter().next() {
+                Some(status) => status.domains[0].clone(),
+                None => return Ok(Response::new(StatusCode::NotFound)),
+            },
+        };
+        let mut pem = match self.handle.certificate_pem(&domain) {
+            Some(pem) => pem,
+            None => return Ok(Response::new(StatusCode::NotFound)),
+        };
+        if self.include_key {
+            match self.handle.private_key_pem(&domain) {
+                Some(key) => pem.push_str(&key),
+                None => return Ok(Response::new(StatusCode::NotFound)),
+            }
+        }
+        Ok(Response::builder(StatusCode::Ok)
+            .content_type("application/x-pem-file")
+            .header("Cache-Control", "no-store")
+            .body(pem)
+            .build())
+    }
+}
+
+/// Tide endpoint serving the event history, for answering what happened recently from an admin
+/// interface; see [`AcmeHandle::event_history`].
+///
+/// Requests must carry `Authorization: Bearer <token>`. Responds with a JSON array of the events,
+/// oldest first, in the format of [`AcmeEvent::to_json`](crate::AcmeEvent::to_json). The query
+/// parameter `since`, in seconds since the Unix epoch, leaves out older events, and
+/// `failures_only=true` leaves out events that aren't failures.
+///
+/// ```no_run
+/// # fn example(handle: tide_acme::AcmeHandle) {
+/// let mut app = tide::new();
+/// app.at("/admin/acme/events")
+///     .get(tide_acme::EventHistory::new(handle, "secret token"));
+/// # }
+/// ```
+pub struct EventHistory {
+    handle: AcmeHandle,
+    token: String,
+}
+
+impl EventHistory {
+    /// Create an endpoint serving the event history of `handle` to requests authenticated with
+    /// `token`.
+    pub fn new(handle: AcmeHandle, token: impl AsRef<str>) -> Self {
+        Self {
+            handle,
+            token: token.as_ref().into(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct EventHistoryQuery {
+    since: Option<u64>,
+    #[serde(default)]
+    failures_only: bool,
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Endpoint<State> for EventHistory {
+    async fn call(&self, req: Request<State>) -> tide::Result {
+        if !authorized(&req, &self.token) {
+            return Ok(Response::new(StatusCode::Unauthorized));
+        }
+        let query: EventHistoryQuery = req.query()?;
+        let since = UNIX_EPOCH + Duration::from_secs(query.since.unwrap_or(0));
+        let filter = EventFilter::new().failures_only(query.failures_only);
+        let events: Vec<String> = self
+            .handle
+            .event_history(filter)
+            .iter()
+            .filter(|event| event.at >= since)
+            .map(AcmeEvent::to_json)
+            .collect();
+        Ok(Response::builder(StatusCode::Ok)
+            .content_type(tide::http::mime::JSON)
+            .header("Cache-Control", "no-store")
+            .body(format!("[{}]", events.join(",")))
+            .build())
+    }
+}
+
+/// Tide endpoint describing what the server serves for each certificate, for support engineers
+/// to compare against what clients such as `openssl s_client` see.
+///
+/// Requests must carry `Authorization: Bearer <token>`. Responds with a JSON array with an object
+/// per certificate, or only the one covering the `:domain` route parameter if there is one, with:
+///
+/// - `domains`: the domains the certificate covers;
+/// - `chain`: each certificate of the chain, leaf first, with its `subject`, `issuer`, `serial`,
+///   `not_before` and `not_after` (in seconds since the Unix epoch) and `sha256` fingerprint;
+/// - `chain_pem`: the chain as PEM;
+/// - `ocsp_staple`: the stapled OCSP response, base64-encoded, or `null` if none is stapled;
+/// - `alpn_protocols`: the ALPN protocols the listeners using the
+///   [`AcmeTlsAcceptor`](crate::AcmeTlsAcceptor) offer: `acme-tls/1`, for validation requests,
+///   and any set with [`alpn_protocols`](crate::AcmeTlsAcceptor::alpn_protocols). Clients
+///   negotiating no protocol speak HTTP/1.1.
+///
+/// ```no_run
+/// # fn example(handle: tide_acme::AcmeHandle) {
+/// let mut app = tide::new();
+/// let debug = tide_acme::TlsDebug::new(handle, "secret token");
+/// app.at("/admin/tls").get(debug.clone());
+/// app.at("/admin/tls/:domain").get(debug);
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct TlsDebug {
+    handle: AcmeHandle,
+    token: String,
+}
+
+impl TlsDebug {
+    /// Create an endpoint describing the certificates of `handle` to requests authenticated with
+    /// `token`.
+    pub fn new(handle: AcmeHandle, token: impl AsRef<str>) -> Self {
+        Self {
+            handle,
+            token: token.as_ref().into(),
+        }
+    }
+
+    /// Describe the certificate served for `domain`.
+    fn describe(&self, domain: &str) -> Option<Value> {
+        let key = self.handle.certified_key(domain)?;
+        let chain: Vec<Value> = key
+            .cert
+            .iter()
+            .map(|cert| {
+                let sha256 = digest(&SHA256, &cert.0)
+                    .as_ref()
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>();
+                match parse_x509_certificate(&cert.0) {
+                    Ok((_, parsed)) => json!({
+                        "subject": parsed.subject().to_string(),
+                        "issuer": parsed.issuer().to_string(),
+                        "serial": parsed.raw_serial_as_string(),
+                        "not_before": parsed.validity().not_before.timestamp(),
+                        "not_after": parsed.validity().not_after.timestamp(),
+                        "sha256": sha256,
+                    }),
+                    Err(_) => json!({ "sha256": sha256 }),
+                }
+            })
+            .collect();
+        let domains = self
+            .handle
+            .status()
+            .into_iter()
+            .find(|status| status.domains.iter().any(|covered| covered == domain))
+            .map_or_else(|| vec![domain.to_string()], |status| status.domains);
+        Some(json!({
+            "domains": domains,
+            "chain": chain,
+            "chain_pem": self.handle.certificate_pem(domain),
+            "ocsp_staple": key.ocsp.as_ref().map(base64::encode),
+            "alpn_protocols": self
+                .handle
+                .registry
+                .offered_alpn()
+                .iter()
+                .map(|protocol| String::from_utf8_lossy(protocol))
+                .collect::<Vec<_>>(),
+        }))
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Endpoint<State> for TlsDebug {
+    async fn call(&self, req: Request<State>) -> tide::Result {
+        if !authorized(&req, &self.token) {
+            return Ok(Response::new(StatusCode::Unauthorized));
+        }
+        let certs: Vec<Value> = match req.param("domain") {
+            Ok(domain) => match self.describe(domain) {
+                Some(cert) => vec![cert],
+                None => return Ok(Response::new(StatusCode::NotFound)),
+            },
+            Err(_) => self
+                .handle
+                .status()
+                .iter()
+                .filter_map(|status| self.describe(&status.domains[0]))
+                .collect(),
+        };
+        Ok(Response::builder(StatusCode::Ok)
+            .content_type(tide::http::mime::JSON)
+            .header("Cache-Control", "no-store")
+            .body(Value::Array(certs))
+            .build())
+    }
+}
+
+/// Tide endpoint listing the certificates being served, for monitoring to compare against an
+/// inventory without probing the server over TLS; see
+/// [`AcmeHandle::served_certificates`].
+///
+/// Requests must carry `Authorization: Bearer <token>`. Responds with a JSON array with an object
+/// per certificate, or only the one covering the `:domain` route parameter if there is one, with
+/// `domains`, the leaf's `serial`, `subject`, `issuer`, `not_before` and `not_after` (in seconds
+/// since the Unix epoch) and `sha256` fingerprint, and the chain, leaf first, as `chain_pem` and
+/// as `chain_der`, an array of base64-encoded certificates. No private keys are included.
+///
+/// ```no_run
+/// # fn example(handle: tide_acme::AcmeHandle) {
+/// let mut app = tide::new();
+/// let inventory = tide_acme::CertInventory::new(handle, "secret token");
+/// app.at("/admin/certs").get(inventory.clone());
+/// app.at("/admin/certs/:domain").get(inventory);
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct CertInventory {
+    handle: AcmeHandle,
+    token: String,
+}
+
+impl CertInventory {
+    /// Create an endpoint listing the certificates of `handle` to requests authenticated with
+    /// `token`.
+    pub fn new(handle: AcmeHandle, token: impl AsRef<str>) -> Self {
+        Self {
+            handle,
+            token: token.as_ref().into(),
+        }
+    }
+}
+
+/// Describe `cert` as JSON, for [`CertInventory`].
+fn served_json(cert: &ServedCertificate) -> Value {
+    let timestamp = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    };
+    json!({
+        "domains": cert.domains,
+        "serial": cert.serial,
+        "subject": cert.subject,
+        "issuer": cert.issuer,
+        "not_before": timestamp(cert.not_before),
+        "not_after": timestamp(cert.not_after),
+        "sha256": cert.sha256,
+        "chain_pem": cert.chain_pem,
+        "chain_der": cert.chain_der.iter().map(base64::encode).collect::<Vec<_>>(),
+    })
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Endpoint<State> for CertInventory {
+    async fn call(&self, req: Request<State>) -> tide::Result {
+        if !authorized(&req, &self.token) {
+            return Ok(Response::new(StatusCode::Unauthorized));
+        }
+        let certs: Vec<Value> = match req.param("domain") {
+            Ok(domain) => match self.handle.served_certificate(domain) {
+                Some(cert) => vec![served_json(&cert)],
+                None => return Ok(Response::new(StatusCode::NotFound)),
+            },
+            Err(_) => self
+                .handle
+                .served_certificates()
+                .iter()
+                .map(served_json)
+                .collect(),
+        };
+        Ok(Response::builder(StatusCode::Ok)
+            .content_type(tide::http::mime::JSON)
+            .header("Cache-Control", "no-store")
+            .body(Value::Array(certs))
+            .build())
+    }
+}
+
+/// Tide endpoint reporting whether the application is ready to serve HTTPS, for load balancer
+/// and orchestrator health checks; see [`AcmeHandle::status`].
+///
+/// Responds with `200 OK` when ready, or `503 Service Unavailable` otherwise, and a JSON object
+/// with `ready`, `draining`, and `certificates`: an array with an object per managed
+/// certificate with `domains`, `readiness` (`obtained`, `pending` or `failed`), `not_after` and
+/// `renew_at` (in seconds since the Unix epoch, or null), `failed_orders` and `degraded`. It is
+/// ready once every certificate has been obtained, or any one with
+/// [`require_all`](Self::require_all) off, and until the acceptor starts
+/// [draining](AcmeHandle::drain). Error messages are left out, since health checks are often
+/// served without authentication; see [`CertInventory`] or [`AcmeHandle::status`] for those.
+///
+/// ```no_run
+/// # fn example(handle: tide_acme::AcmeHandle) {
+/// let mut app = tide::new();
+/// app.at("/healthz").get(tide_acme::HealthCheck::new(handle));
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct HealthCheck {
+    handle: AcmeHandle,
+    require_all: bool,
+}
+
+impl HealthCheck {
+    /// Create an endpoint reporting the readiness of the certificates of `handle`.
+    pub fn new(handle: AcmeHandle) -> Self {
+        Self {
+            handle,
+            require_all: true,
+        }
+    }
+
+    /// Report ready only once every certificate has been obtained if `require_all` is true, or
+    /// once any has if false, so that one domain failing validation doesn't take down the others.
+    /// Defaults to true.
+    pub fn require_all(mut self, require_all: bool) -> Self {
+        self.require_all = require_all;
+        self
+    }
+}
+
+/// Describe `status` as JSON, for [`HealthCheck`].
+fn health_json(status: &CertStatus, now: SystemTime) -> Value {
+    let timestamp = |time: Option<SystemTime>| {
+        time.map(|time| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        })
+    };
+    let readiness = match status.readiness_at(now) {
+        CertReadiness::Obtained => "obtained",
+        CertReadiness::Pending => "pending",
+        CertReadiness::Failed => "failed",
+    };
+    json!({
+        "domains": status.domains,
+        "readiness": readiness,
+        "not_after": timestamp(status.not_after),
+        "renew_at": timestamp(status.renew_at),
+        "failed_orders": status.failed_orders,
+        "degraded": status.degraded.is_some(),
+    })
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Endpoint<State> for HealthCheck {
+    async fn call(&self, _req: Request<State>) -> tide::Result {
+        let status = self.handle.status();
+        let now = self.handle.registry.now();
+        let obtained = |status: &CertStatus| status.readiness_at(now) == CertReadiness::Obtained;
+        let certs_ready = match self.require_all {
+            true => status.iter().all(obtained),
+            false => status.is_empty() || status.iter().any(obtained),
+        };
+        let draining = self.handle.draining();
+        let ready = certs_ready && !draining;
+        Ok(Response::builder(match ready {
+            true => StatusCode::Ok,
+            false => StatusCode::ServiceUnavailable,
+        })
+        .content_type(tide::http::mime::JSON)
+        .header("Cache-Control", "no-store")
+        .body(json!({
+            "ready": ready,
+            "draining": draining,
+            "certificates": status
+                .iter()
+                .map(|status| health_json(status, now))
+                .collect::<Vec<_>>(),
+        }))
+        .build())
+    }
+}
+
+/// Tide middleware answering ACME http-01 validation requests, for certificates obtained with
+/// [`AcmeConfig::http01`](crate::AcmeConfig::http01) when TLS is terminated in front of the
+/// application.
+///
+/// Requests for `/.well-known/acme-challenge/<token>` are answered with the key authorization of
+/// a pending challenge, or `404 Not Found`; other requests are passed on. Add it to the
+/// application serving plain HTTP on port 80, where the CA sends validation requests.
+///
+/// Behind a frontend routing by path, such as a Kubernetes ingress controller, set a
+/// [path prefix](Self::path_prefix) the challenge path is also served under, and
+/// [trust the forwarded headers](Self::trust_forwarded_headers) if the frontend rewrites paths.
+///
+/// ```no_run
+/// use tide_acme::{AcmeConfig, AcmeHttp01Middleware, AcmeTlsAcceptor};
+///
+/// # async_std::task::block_on(async {
+/// let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(vec!["domain.example"]).http01(true));
+/// let mut app = tide::new();
+/// app.with(AcmeHttp01Middleware::new(acceptor.handle()));
+/// app.listen("0.0.0.0:80").await?;
+/// # tide::Result::Ok(())
+/// # });
+/// ```
+pub struct AcmeHttp01Middleware {
+    handle: AcmeHandle,
+    path_prefix: Option<String>,
+    trust_forwarded_headers: bool,
+}
+
+impl AcmeHttp01Middleware {
+    /// Create a middleware answering the http-01 challenges of `handle`.
+    pub fn new(handle: AcmeHandle) -> Self {
+        Self {
+            handle,
+            path_prefix: None,
+            trust_forwarded_headers: false,
+        }
+    }
+
+    /// Also answer requests for `<prefix>/.well-known/acme-challenge/<token>`, for a frontend
+    /// that forwards requests under a path prefix, such as `/app`, without stripping it.
+    ///
+    /// ```no_run
+    /// # fn f(handle: tide_acme::AcmeHandle) {
+    /// use tide_acme::AcmeHttp01Middleware;
+    ///
+    /// let middleware = AcmeHttp01Middleware::new(handle).path_prefix("/app");
+    /// # }
+    /// ```
+    pub fn path_prefix(mut self, prefix: impl Into<String>) -> Self {
+        let prefix = prefix.into();
+        let prefix = prefix.trim_end_matches('/');
+        self.path_prefix = match prefix.starts_with('/') {
+            true => Some(prefix.into()),
+            false => Some(format!("/{}", prefix)),
+        };
+        self
+    }
+
+    /// Match the path the client requested, as told by the `X-Forwarded-Uri` header, or by the
+    /// `X-Forwarded-Prefix` header a frontend stripping a prefix adds, as well as the path the
+    /// request arrived with, if `trust` is true. Defaults to false.
+    ///
+    /// Only trust these headers if every request passes through a frontend that sets them, or
+    /// clients can have challenges answered under paths of their choosing.
+    pub fn trust_forwarded_headers(mut self, trust: bool) -> Self {
+        self.trust_forwarded_headers = trust;
+        self
+    }
+
+    /// The paths a request may have been sent for: the path it arrived with and, if trusted,
+    /// the paths the forwarded headers give.
+    fn paths<State>(&self, req: &Request<State>) -> Vec<String> {
+        let path = req.url().path();
+        let mut paths = vec![path.to_string()];
+        if self.trust_forwarded_headers {
+            if let Some(uri) = req.header("X-Forwarded-Uri") {
+                let uri = uri.last().as_str();
+                paths.push(uri.split('?').next().unwrap_or(uri).to_string());
+            }
+            if let Some(prefix) = req.header("X-Forwarded-Prefix") {
+                let prefix = prefix.last().as_str().trim_end_matches('/');
+                paths.push(format!("{}{}", prefix, path));
+            }
+        }
+        paths
+    }
+}
+
+/// The token in the http-01 challenge path `path`, served at the root or under `prefix`.
+fn challenge_token<'a>(path: &'a str, prefix: Option<&str>) -> Option<&'a str> {
+    let path = prefix
+        .and_then(|prefix| path.strip_prefix(prefix))
+        .filter(|path| path.starts_with('/'))
+        .unwrap_or(path);
+    path.strip_prefix("/.well-known/acme-challenge/")
+        .filter(|token| !token.is_empty() && !token.contains('/'))
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for AcmeHttp01Middleware {
+    async fn handle(&self, req: Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        let paths = self.paths(&req);
+        let token = match paths
+            .iter()
+            .find_map(|path| challenge_token(path, self.path_prefix.as_deref()))
+        {
+            Some(token) => token,
+            None => return Ok(next.run(req).await),
+        };
+        match self.handle.registry.http01_response(token) {
+            Some(key_authorization) => Ok(Response::builder(StatusCode::Ok)
+                .content_type("application/octet-stream")
+                .body(key_authorization)
+                .build()),
+            None => Ok(Response::new(StatusCode::NotFound)),
+        }
+    }
+}
+
+/// Tide endpoint redirecting plain HTTP requests to the same URL over HTTPS, with `301 Moved
+/// Permanently`.
+///
+/// The host is taken from the `Host` header, without its port; requests without one get `400
+/// Bad Request`. See [`AcmeTlsAcceptor::listen_http`](crate::AcmeTlsAcceptor::listen_http) for a
+/// complete port 80 listener.
+///
+/// ```no_run
+/// # async_std::task::block_on(async {
+/// let mut app = tide::new();
+/// app.at("").all(tide_acme::HttpsRedirect::new());
+/// app.at("*").all(tide_acme::HttpsRedirect::new());
+/// app.listen("0.0.0.0:80").await?;
+/// # tide::Result::Ok(())
+/// # });
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct HttpsRedirect {
+    port: Option<u16>,
+}
+
+impl HttpsRedirect {
+    /// Create an endpoint redirecting to HTTPS on the default port, 443.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Redirect to HTTPS on `port` instead of the default port.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = Some(port).filter(|&port| port != 443);
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Endpoint<State> for HttpsRedirect {
+    async fn call(&self, req: Request<State>) -> tide::Result {
+        let host = match req.host() {
+            Some(host) => strip_port(host),
+            None => return Ok(Response::new(StatusCode::BadRequest)),
+        };
+        let url = req.url();
+        let mut location = match self.port {
+            Some(port) => format!("https://{}:{}{}", host, port, url.path()),
+            None => format!("https://{}{}", host, url.path()),
+        };
+        if let Some(query) = url.query() {
+            location.push('?');
+            location.push_str(query);
+        }
+        Ok(Response::builder(StatusCode::MovedPermanently)
+            .header("Location", location)
+            .build())
+    }
+}
+
+/// Strip the port, if any, from a `Host` header.
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
+
+/// Tide endpoint ordering a new certificate for a domain now, for external orchestration such as
+/// after a DNS cutover.
+///
+/// Requests must carry `Authorization: Bearer <token>`. The domain is taken from the `:domain`
+/// route parameter. Responds with `202 Accepted` once the renewal is requested, or `404 Not
+/// Found` if no managed certificate covers the domain; see [`AcmeHandle::renew`].
+///
+/// ```no_run
+/// # fn example(handle: tide_acme::AcmeHandle) {
+/// let mut app = tide::new();
+/// app.at("/renew/:domain")
+///     .post(tide_acme::RenewTrigger::new(handle, "secret token"));
+/// # }
+/// ```
+pub struct RenewTrigger {
+    handle: AcmeHandle,
+    token: String,
+}
+
+impl RenewTrigger {
+    /// Create an endpoint renewing certificates of `handle` for requests authenticated with
+    /// `token`.
+    pub fn new(handle: AcmeHandle, token: impl AsRef<str>) -> Self {
+        Self {
+            handle,
+            token: token.as_ref().into(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Endpoint<State> for RenewTrigger {
+    async fn call(&self, req: Request<State>) -> tide::Result {
+        if !authorized(&req, &self.token) {
+            return Ok(Response::new(StatusCode::Unauthorized));
+        }
+        let domain = req.param("domain")?;
+        Ok(Response::new(match self.handle.renew(domain) {
+            true => StatusCode::Accepted,
+            false => StatusCode::NotFound,
+        }))
+    }
+}
+
+/// Tide endpoint injecting a synthetic renewal failure for a domain, to rehearse on-call runbooks
+/// and alert pipelines against a staging deployment without breaking real DNS. Requires the
+/// `simulate-failure` feature, which should stay disabled in production builds.
+///
+/// Requests must carry `Authorization: Bearer <token>`. The domain is taken from the `:domain`
+/// route parameter, and the error message from the request body, if any. Responds with `202
+/// Accepted` once the failure is injected, or `404 Not Found` if no managed certificate covers
+/// the domain; see [`AcmeHandle::simulate_failure`].
+///
+/// ```no_run
+/// # fn example(handle: tide_acme::AcmeHandle) {
+/// let mut app = tide::new();
+/// app.at("/simulate-failure/:domain")
+///     .post(tide_acme::FailureSimulation::new(handle, "secret token"));
+/// # }
+/// ```
+#[cfg(feature = "simulate-failure")]
+pub struct FailureSimulation {
+    handle: AcmeHandle,
+    token: String,
+}
+
+#[cfg(feature = "simulate-failure")]
+impl FailureSimulation {
+    /// Create an endpoint injecting failures into certificates of `handle` for requests
+    /// authenticated with `token`.
+    pub fn new(handle: AcmeHandle, token: impl AsRef<str>) -> Self {
+        Self {
+            handle,
+            token: token.as_ref().into(),
+        }
+    }
+}
+
+#[cfg(feature = "simulate-failure")]
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Endpoint<State> for FailureSimulation {
+    async fn call(&self, mut req: Request<State>) -> tide::Result {
+        if !authorized(&req, &self.token) {
+            return Ok(Response::new(StatusCode::Unauthorized));
+        }
+        let message = req.body_string().await?;
+        let message = match message.trim() {
+            "" => "renewal failure injected for a rehearsal",
+            message => message,
+        };
+        let domain = req.param("domain")?;
+        Ok(Response::new(
+            match self.handle.simulate_failure(domain, message) {
+                true => StatusCode::Accepted,
+                false => StatusCode::NotFound,
+            },
+        ))
+    }
+}
+
+type BoxedApp = Box<
+    dyn Fn(
+            http_types::Request,
+        )
+            -> Pin<Box<dyn Future<Output = http_types::Result<http_types::Response>> + Send>>
+        + Send
+        + Sync,
+>;
+
+/// Tide middleware routing requests to separate Tide applications by hostname, so one ACME
+/// listener can serve several sites with their own routers while sharing certificates and the
+/// accept loop.
+///
+/// Requests are routed by their `Host` header, which browsers set to the name they also send
+/// via SNI. Requests for hosts without an application of their own continue to the application
+/// the middleware is installed in.
+///
+/// ```no_run
+/// use tide_acme::{AcmeConfig, HostRouter, TideRustlsExt};
+///
+/// # async_std::task::block_on(async {
+/// let mut api = tide::new();
+/// api.at("/").get(|_| async { Ok("API") });
+/// let mut www = tide::new();
+/// www.at("/").get(|_| async { Ok("Website") });
+///
+/// let mut app = tide::new();
+/// app.with(HostRouter::new().host("api.example", api).host("www.example", www));
+/// app.listen(
+///     tide_rustls::TlsListener::build()
+///         .addrs("0.0.0.0:443")
+///         .acme(AcmeConfig::new(vec!["api.example", "www.example"])),
+/// )
+/// .await?;
+/// # tide::Result::Ok(())
+/// # });
+/// ```
+#[derive(Default)]
+pub struct HostRouter {
+    apps: HashMap<String, BoxedApp>,
+}
+
+impl HostRouter {
+    /// Create a router without any applications.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Route requests for `host` to `app`.
+    pub fn host<AppState: Clone + Send + Sync + 'static>(
+        mut self,
+        host: impl AsRef<str>,
+        app: tide::Server<AppState>,
+    ) -> Self {
+        let respond: BoxedApp = Box::new(move |req| {
+            let app = app.clone();
+            Box::pin(async move { app.respond(req).await })
+        });
+        self.apps
+            .insert(host.as_ref().to_ascii_lowercase(), respond);
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for HostRouter {
+    async fn handle(&self, req: Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        let host = req
+            .host()
+            .map(|host| strip_port(host).trim_end_matches('.').to_ascii_lowercase());
+        match host.and_then(|host| self.apps.get(&host)) {
+            Some(respond) => Ok(respond(req.into()).await?.into()),
+            None => Ok(next.run(req).await),
+        }
+    }
+}
+
+/// Tide middleware counting the requests the application is handling, for
+/// [`ListenerOptions::max_in_flight_requests`](crate::ListenerOptions::max_in_flight_requests)
+/// and [`AcmeHandle::in_flight_requests`].
+///
+/// Add it first, so it counts requests for as long as any other middleware handles them. While
+/// the acceptor is [draining](AcmeHandle::drain), it also asks clients to close their connection
+/// after each response, with `Connection: close`.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use tide_acme::{AcmeConfig, AcmeTlsAcceptor, InFlightRequests, ListenerOptions, TideRustlsExt};
+///
+/// # async_std::task::block_on(async {
+/// let options = ListenerOptions::new().max_in_flight_requests(512, Duration::from_secs(5));
+/// let acceptor =
+///     AcmeTlsAcceptor::new(AcmeConfig::new(vec!["domain.example"]).listener_options(options));
+/// let mut app = tide::new();
+/// app.with(InFlightRequests::new(acceptor.handle()));
+/// app.listen(
+///     tide_rustls::TlsListener::build()
+///         .addrs("0.0.0.0:443")
+///         .acme_acceptor(acceptor),
+/// )
+/// .await?;
+/// # tide::Result::Ok(())
+/// # });
+/// ```
+pub struct InFlightRequests {
+    handle: AcmeHandle,
+}
+
+impl InFlightRequests {
+    /// Create a middleware counting requests for `handle`.
+    pub fn new(handle: AcmeHandle) -> Self {
+        Self { handle }
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for InFlightRequests {
+    async fn handle(&self, req: Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        let _guard = self.handle.registry.requests.start();
+        let mut res = next.run(req).await;
+        if self.handle.registry.shutdown.draining() {
+            res.insert_header("Connection", "close");
+        }
+        Ok(res)
+    }
+}
+
+/// Tide middleware adding headers derived from the TLS state of an [`AcmeHandle`], looked up for
+/// each response, so they stay correct as certificates are renewed and endpoints come and go.
+///
+/// For HTTPS responses under a hostname a managed certificate is served for, taken from the TLS
+/// session or else the `Host` header, it adds:
+///
+/// - `Alt-Svc`, advertising the HTTP/3 endpoint recorded with
+///   [`AcmeHandle::set_http3_port`], if any, or clearing it while the acceptor is
+///   [draining](AcmeHandle::drain), so clients come back over TCP. Responses that set it already
+///   are left alone.
+/// - If enabled with [`not_after_header`](Self::not_after_header), the end of the validity
+///   period of the certificate served for the hostname, as an HTTP date, to check which
+///   certificate clients get without inspecting the handshake.
+///
+/// ```no_run
+/// use tide_acme::{AcmeConfig, AcmeTlsAcceptor, TideRustlsExt, TlsHeaders};
+///
+/// # async_std::task::block_on(async {
+/// let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(vec!["domain.example"]));
+/// let handle = acceptor.handle();
+/// // Once the QUIC endpoint, configured with `handle.server_config()`, is listening:
+/// handle.set_http3_port(Some(443));
+/// let mut app = tide::new();
+/// app.with(TlsHeaders::new(handle).not_after_header("X-Certificate-Not-After"));
+/// app.listen(
+///     tide_rustls::TlsListener::build()
+///         .addrs("0.0.0.0:443")
+///         .acme_acceptor(acceptor),
+/// )
+/// .await?;
+/// # tide::Result::Ok(())
+/// # });
+/// ```
+pub struct TlsHeaders {
+    handle: AcmeHandle,
+    max_age: Duration,
+    not_after_header: Option<String>,
+}
+
+impl TlsHeaders {
+    /// Create a middleware adding headers from the state of `handle`, advertising its HTTP/3
+    /// endpoint for a day.
+    pub fn new(handle: AcmeHandle) -> Self {
+        Self {
+            handle,
+            max_age: Duration::from_secs(24 * 60 * 60),
+            not_after_header: None,
+        }
+    }
+
+    /// Set how long clients may keep using the HTTP/3 endpoint without seeing the `Alt-Svc`
+    /// header again. Defaults to a day.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Add the end of the validity period of the certificate served as header `header`, such as
+    /// `X-Certificate-Not-After`. Off by default.
+    pub fn not_after_header(mut self, header: impl AsRef<str>) -> Self {
+        self.not_after_header = Some(header.as_ref().into());
+        self
+    }
+
+    fn alt_svc(&self) -> Option<String> {
+        if self.handle.registry.shutdown.draining() {
+            return Some("clear".into());
+        }
+        let port = self.handle.http3_port()?;
+        Some(format!("h3=\":{}\"; ma={}", port, self.max_age.as_secs()))
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for TlsHeaders {
+    async fn handle(&self, req: Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        let name = match req.url().scheme() {
+            "https" => server_name(&self.handle, &req),
+            _ => None,
+        };
+        let not_after = name
+            .as_deref()
+            .and_then(|name| self.handle.registry.not_after(name));
+        let mut res = next.run(req).await;
+        let not_after = match not_after {
+            Some(not_after) => not_after,
+            None => return Ok(res),
+        };
+        if res.header("Alt-Svc").is_none() {
+            if let Some(alt_svc) = self.alt_svc() {
+                res.insert_header("Alt-Svc", alt_svc);
+            }
+        }
+        if let Some(header) = &self.not_after_header {
+            res.insert_header(header.as_str(), Date::new(not_after).value());
+        }
+        Ok(res)
+    }
+}
+
+/// Tide middleware reporting the client address a load balancer sent via the PROXY protocol, for
+/// listeners with [`AcmeTlsAcceptor::proxy_protocol`](crate::AcmeTlsAcceptor::proxy_protocol).
+///
+/// For connections with a client address, it replaces the `Forwarded` and `X-Forwarded-For`
+/// headers, so [`Request::remote`] returns the client address, and inserts it as a
+/// [`ClientAddr`] request extension.
+///
+/// ```no_run
+/// use tide_acme::{AcmeConfig, AcmeTlsAcceptor, ClientAddr, ProxyClientAddr, TideRustlsExt};
+///
+/// # async_std::task::block_on(async {
+/// let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(vec!["domain.example"]));
+/// let mut app = tide::new();
+/// app.with(ProxyClientAddr::new(acceptor.handle()));
+/// app.at("/").get(|req: tide::Request<()>| async move {
+///     Ok(format!("Hello, {:?}", req.ext::<ClientAddr>()))
+/// });
+/// app.listen(
+///     tide_rustls::TlsListener::build()
+///         .addrs("0.0.0.0:443")
+///         .acme_acceptor(acceptor.proxy_protocol(true)),
+/// )
+/// .await?;
+/// # tide::Result::Ok(())
+/// # });
+/// ```
+pub struct ProxyClientAddr {
+    handle: AcmeHandle,
+}
+
+impl ProxyClientAddr {
+    /// Create a middleware reporting the client addresses recorded by `handle`.
+    pub fn new(handle: AcmeHandle) -> Self {
+        Self { handle }
+    }
+}
+
+/// The client address a load balancer sent via the PROXY protocol, as a request extension set
+/// by [`ProxyClientAddr`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientAddr(pub SocketAddr);
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for ProxyClientAddr {
+    async fn handle(&self, mut req: Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        let client = req
+            .peer_addr()
+            .and_then(|peer| peer.parse().ok())
+            .and_then(|peer| self.handle.client_addr(peer));
+        if let Some(client) = client {
+            req.remove_header("X-Forwarded-For");
+            req.insert_header("Forwarded", format!("for=\"{}\"", client));
+            req.set_ext(ClientAddr(client));
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+/// Tide middleware adding the details of the TLS session of each request's connection, such as
+/// the server name it was served under, as a [`TlsConnectionInfo`](crate::TlsConnectionInfo)
+/// request extension.
+///
+/// ```no_run
+/// use tide_acme::{AcmeConfig, AcmeTlsAcceptor, TideRustlsExt, TlsConnectionInfo, TlsInfo};
+///
+/// # async_std::task::block_on(async {
+/// let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(vec!["a.example", "b.example"]));
+/// let mut app = tide::new();
+/// app.with(TlsInfo::new(acceptor.handle()));
+/// app.at("/").get(|req: tide::Request<()>| async move {
+///     let info = req.ext::<TlsConnectionInfo>();
+///     Ok(format!("Served under {:?}", info.and_then(|info| info.server_name.as_deref())))
+/// });
+/// app.listen(
+///     tide_rustls::TlsListener::build()
+///         .addrs("0.0.0.0:443")
+///         .acme_acceptor(acceptor),
+/// )
+/// .await?;
+/// # tide::Result::Ok(())
+/// # });
+/// ```
+pub struct TlsInfo {
+    handle: AcmeHandle,
+}
+
+impl TlsInfo {
+    /// Create a middleware adding the details of connections accepted for `handle`.
+    pub fn new(handle: AcmeHandle) -> Self {
+        Self { handle }
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for TlsInfo {
+    async fn handle(&self, mut req: Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        let info = req
+            .peer_addr()
+            .and_then(|peer| peer.parse().ok())
+            .and_then(|peer| self.handle.tls_info(peer));
+        if let Some(info) = info {
+            req.set_ext(info);
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+/// Tide middleware flagging responses served under a certificate close to expiry, so external
+/// synthetic monitoring notices a renewal problem even if internal alerting is broken.
+///
+/// When the certificate served for the request's server name, taken from the TLS session or
+/// else the `Host` header, expires within the [window](Self::window), it adds an
+/// `X-Certificate-Expires-In` header with the seconds left, and counts the response in
+/// [`AcmeHandle::expiring_cert_responses`] and the
+/// `tide_acme_expiring_cert_responses_total` metric.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use tide_acme::{AcmeConfig, AcmeTlsAcceptor, ExpiryWarning, TideRustlsExt};
+///
+/// # async_std::task::block_on(async {
+/// let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(vec!["domain.example"]));
+/// let mut app = tide::new();
+/// app.with(ExpiryWarning::new(acceptor.handle()).window(Duration::from_secs(14 * 24 * 60 * 60)));
+/// app.listen(
+///     tide_rustls::TlsListener::build()
+///         .addrs("0.0.0.0:443")
+///         .acme_acceptor(acceptor),
+/// )
+/// .await?;
+/// # tide::Result::Ok(())
+/// # });
+/// ```
+pub struct ExpiryWarning {
+    handle: AcmeHandle,
+    window: Duration,
+    header: String,
+}
+
+impl ExpiryWarning {
+    /// Create a middleware flagging responses served under certificates of `handle` that expire
+    /// within 7 days.
+    pub fn new(handle: AcmeHandle) -> Self {
+        Self {
+            handle,
+            window: Duration::from_secs(7 * 24 * 60 * 60),
+            header: "X-Certificate-Expires-In".into(),
+        }
+    }
+
+    /// Flag responses once the certificate expires within `window`. Defaults to 7 days, well
+    /// after renewal, by default two thirds of the way through the validity period, should have
+    /// happened.
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Name the header `header` instead of `X-Certificate-Expires-In`.
+    pub fn header(mut self, header: impl AsRef<str>) -> Self {
+        self.header = header.as_ref().into();
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for ExpiryWarning {
+    async fn handle(&self, req: Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        let remaining = server_name(&self.handle, &req)
+            .and_then(|name| self.handle.time_to_expiry(&name))
+            .filter(|&remaining| remaining < self.window);
+        let mut res = next.run(req).await;
+        if let Some(remaining) = remaining {
+            res.insert_header(self.header.as_str(), remaining.as_secs().to_string());
+            self.handle.registry.record_expiring_cert_response();
+        }
+        Ok(res)
+    }
+}
+
+/// Tide middleware adding a `Strict-Transport-Security` header to HTTPS responses for the
+/// hostnames with a managed certificate, so browsers only connect to them over HTTPS from then on.
+///
+/// The hostname is the request's server name, taken from the TLS session or else the `Host`
+/// header, and it must be covered by a certificate of the handle, including one added with
+/// [`AcmeHandle::add_domain`](crate::AcmeHandle::add_domain) or through a wildcard. Other
+/// hostnames, such as an internal name the application is also reached under, and plain HTTP
+/// responses don't get the header, nor do responses that set it already.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use tide_acme::{AcmeConfig, AcmeTlsAcceptor, Hsts, TideRustlsExt};
+///
+/// # async_std::task::block_on(async {
+/// let acceptor = AcmeTlsAcceptor::new(AcmeConfig::new(vec!["domain.example"]));
+/// let mut app = tide::new();
+/// app.with(Hsts::new(acceptor.handle()).include_subdomains(true));
+/// app.listen(
+///     tide_rustls::TlsListener::build()
+///         .addrs("0.0.0.0:443")
+///         .acme_acceptor(acceptor),
+/// )
+/// .await?;
+/// # tide::Result::Ok(())
+/// # });
+/// ```
+///
+/// Browsers keep enforcing HTTPS for the `max-age` after they last saw the header, so start with
+/// a short one while trying it out.
+pub struct Hsts {
+    handle: AcmeHandle,
+    max_age: Duration,
+    include_subdomains: bool,
+    preload: bool,
+}
+
+impl Hsts {
+    /// Create a middleware adding the header for the hostnames of `handle`'s certificates, with a
+    /// `max-age` of a year.
+    pub fn new(handle: AcmeHandle) -> Self {
+        Self {
+            handle,
+            max_age: Duration::from_secs(365 * 24 * 60 * 60),
+            include_subdomains: false,
+            preload: false,
+        }
+    }
+
+    /// Set how long browsers enforce HTTPS after seeing the header. Defaults to a year.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Enforce HTTPS on all subdomains of each hostname too, with the `includeSubDomains`
+    /// directive, if `include_subdomains` is true. Defaults to false.
+    ///
+    /// Only enable this if every subdomain serves HTTPS, including those served by other
+    /// applications: browsers that saw the header refuse plain HTTP for all of them.
+    pub fn include_subdomains(mut self, include_subdomains: bool) -> Self {
+        self.include_subdomains = include_subdomains;
+        self
+    }
+
+    /// Consent to inclusion in browsers' HSTS preload lists, with the `preload` directive, if
+    /// `preload` is true. Defaults to false.
+    ///
+    /// Submitting a domain to the [preload list](https://hstspreload.org/) also requires
+    /// [`include_subdomains`](Self::include_subdomains) and a `max-age` of at least a year.
+    /// Removal from the list takes months, so only consent once HTTPS is there to stay.
+    pub fn preload(mut self, preload: bool) -> Self {
+        self.preload = preload;
+        self
+    }
+
+    fn header_value(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age.as_secs());
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        value
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for Hsts {
+    async fn handle(&self, req: Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        let covered = req.url().scheme() == "https"
+            && server_name(&self.handle, &req)
+                .is_some_and(|name| self.handle.registry.covers(&name));
+        let mut res = next.run(req).await;
+        if covered && res.header("Strict-Transport-Security").is_none() {
+            res.insert_header("Strict-Transport-Security", self.header_value());
+        }
+        Ok(res)
+    }
+}
+
+/// The server name a request was made under: the one sent via SNI on its connection, or else
+/// the hostname of its `Host` header.
+fn server_name<State>(handle: &AcmeHandle, req: &Request<State>) -> Option<String> {
+    req.peer_addr()
+        .and_then(|peer| peer.parse().ok())
+        .and_then(|peer| handle.tls_info(peer))
+        .and_then(|info| info.server_name)
+        .or_else(|| {
+            req.host()
+                .map(|host| strip_port(host).trim_end_matches('.').to_ascii_lowercase())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::block_on;
+    use http_types::{Method, Url};
+
+    use super::*;
+    use crate::handle::CertMaterial;
+    use crate::snapshot::CertOrigin;
+    use crate::AcmeConfig;
+
+    fn handle() -> AcmeHandle {
+        let handle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let (index, _) =
+            handle
+                .registry
+                .register(vec!["domain.example".into()], 0, CertOrigin::Configured);
+        let material =
+            CertMaterial::fake(vec![b"leaf".to_vec(), b"issuer".to_vec()], b"key".to_vec());
+        handle.registry.set_material(index, material);
+        handle
+    }
+
+    fn get(app: &tide::Server<()>, path: &str, token: Option<&str>) -> http_types::Response {
+        let url = Url::parse("https://domain.example")
+            .unwrap()
+            .join(path)
+            .unwrap();
+        let mut req = http_types::Request::new(Method::Get, url);
+        if let Some(token) = token {
+            req.insert_header("Authorization", format!("Bearer {}", token));
+        }
+        block_on(app.respond(req)).unwrap()
+    }
+
+    #[test]
+    fn serves_certificates_to_authorized_requests() {
+        let mut app = tide::new();
+        app.at("/certs/:domain")
+            .get(PemDownload::new(handle(), "token"));
+        app.at("/cert").get(PemDownload::new(handle(), "token"));
+        app.at("/key")
+            .get(PemDownload::new(handle(), "token").include_key(true));
+
+        let mut res = get(&app, "/certs/domain.example", Some("token"));
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res["Cache-Control"], "no-store");
+        let body = block_on(res.body_string()).unwrap();
+        let pems = pem::parse_many(&body).unwrap();
+        assert_eq!(pems.len(), 2);
+        assert_eq!(pems[0].contents, b"leaf");
+        assert_eq!(pems[1].contents, b"issuer");
+
+        let mut res = get(&app, "/cert", Some("token"));
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body = block_on(res.body_string()).unwrap();
+        assert!(!body.contains("PRIVATE KEY"));
+        let mut res = get(&app, "/key", Some("token"));
+        let body = block_on(res.body_string()).unwrap();
+        let pems = pem::parse_many(&body).unwrap();
+        assert_eq!(pems[2].tag, "PRIVATE KEY");
+        assert_eq!(pems[2].contents, b"key");
+
+        let res = get(&app, "/certs/other.example", Some("token"));
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+
+    #[test]
+    fn rejects_unauthorized_requests() {
+        let mut app = tide::new();
+        app.at("/certs/:domain")
+            .get(PemDownload::new(handle(), "token"));
+        for token in [None, Some("other"), Some("toke"), Some("")] {
+            let res = get(&app, "/certs/domain.example", token);
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+        }
+        let url = Url::parse("https://domain.example/certs/domain.example").unwrap();
+        let mut req = http_types::Request::new(Method::Get, url);
+        req.insert_header("Authorization", "Basic token");
+        let res: http_types::Response = block_on(app.respond(req)).unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+    }
+
+    #[test]
+    fn triggers_renewals() {
+        let handle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let (_, mut commands) =
+            handle
+                .registry
+                .register(vec!["domain.example".into()], 0, CertOrigin::Configured);
+        let mut app = tide::new();
+        app.at("/renew/:domain")
+            .post(RenewTrigger::new(handle, "token"));
+        let post = |path: &str, token: Option<&str>| {
+            let url = Url::parse("https://domain.example")
+                .unwrap()
+                .join(path)
+                .unwrap();
+            let mut req = http_types::Request::new(Method::Post, url);
+            if let Some(token) = token {
+                req.insert_header("Authorization", format!("Bearer {}", token));
+            }
+            let res: http_types::Response = block_on(app.respond(req)).unwrap();
+            res.status()
+        };
+        assert_eq!(
+            post("/renew/domain.example", None),
+            StatusCode::Unauthorized
+        );
+        assert!(commands.try_recv().is_err());
+        assert_eq!(
+            post("/renew/other.example", Some("token")),
+            StatusCode::NotFound
+        );
+        assert_eq!(
+            post("/renew/domain.example", Some("token")),
+            StatusCode::Accepted
+        );
+        assert!(matches!(
+            commands.try_recv(),
+            Ok(crate::handle::Command::Renew)
+        ));
+    }
+
+    #[test]
+    fn routes_requests_by_host() {
+        let mut api = tide::new();
+        api.at("/").get(|_| async { Ok("api") });
+        let mut app = tide::new();
+        app.with(HostRouter::new().host("API.domain.example", api));
+        app.at("/").get(|_| async { Ok("default") });
+        let body = |host: &str| {
+            let url = Url::parse("https://domain.example/").unwrap();
+            let mut req = http_types::Request::new(Method::Get, url);
+            req.insert_header("Host", host);
+            let mut res: http_types::Response = block_on(app.respond(req)).unwrap();
+            block_on(res.body_string()).unwrap()
+        };
+        assert_eq!(body("api.domain.example"), "api");
+        assert_eq!(body("Api.Domain.Example:8443"), "api");
+        assert_eq!(body("api.domain.example."), "api");
+        assert_eq!(body("domain.example"), "default");
+        assert_eq!(body("other.api.domain.example"), "default");
+    }
+
+    #[test]
+    fn answers_http_01_challenges() {
+        let handle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        handle
+            .registry
+            .set_http01_response("token".into(), "token.thumbprint".into());
+        let mut app = tide::new();
+        app.with(AcmeHttp01Middleware::new(handle.clone()));
+        app.at("/").get(|_| async { Ok("app") });
+        let mut res = get(&app, "/.well-known/acme-challenge/token", None);
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(block_on(res.body_string()).unwrap(), "token.thumbprint");
+        let res = get(&app, "/.well-known/acme-challenge/other", None);
+        assert_eq!(res.status(), StatusCode::NotFound);
+        let mut res = get(&app, "/", None);
+        assert_eq!(block_on(res.body_string()).unwrap(), "app");
+        handle.registry.remove_http01_response("token");
+        let res = get(&app, "/.well-known/acme-challenge/token", None);
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+
+    #[test]
+    fn counts_requests_in_flight() {
+        let handle = handle();
+        let mut app = tide::new();
+        let seen = handle.clone();
+        app.with(InFlightRequests::new(handle.clone()));
+        app.at("/").get(move |_| {
+            let in_flight = seen.in_flight_requests();
+            async move { Ok(in_flight.to_string()) }
+        });
+        let mut res = get(&app, "/", None);
+        assert_eq!(block_on(res.body_string()).unwrap(), "1");
+        assert!(res.header("Connection").is_none());
+        assert_eq!(handle.in_flight_requests(), 0);
+        handle.registry.shutdown.start_draining();
+        assert_eq!(get(&app, "/", None)["Connection"], "close");
+    }
+
+    #[test]
+    fn redirects_to_https() {
+        let location = |redirect: HttpsRedirect, host: &str, path: &str| {
+            let mut app = tide::new();
+            app.at("").all(redirect.clone());
+            app.at("*").all(redirect);
+            let url = Url::parse("http://domain.example")
+                .unwrap()
+                .join(path)
+                .unwrap();
+            let mut req = http_types::Request::new(Method::Get, url);
+            req.insert_header("Host", host);
+            let res: http_types::Response = block_on(app.respond(req)).unwrap();
+            assert_eq!(res.status(), StatusCode::MovedPermanently);
+            res["Location"].as_str().to_string()
+        };
+        assert_eq!(
+            location(HttpsRedirect::new(), "domain.example:80", "/a/b?c=d"),
+            "https://domain.example/a/b?c=d"
+        );
+        assert_eq!(
+            location(HttpsRedirect::new().port(8443), "domain.example", "/"),
+            "https://domain.example:8443/"
+        );
+        assert_eq!(
+            location(HttpsRedirect::new().port(443), "domain.example", "/"),
+            "https://domain.example/"
+        );
+    }
+
+    #[test]
+    fn serves_the_event_history() {
+        let handle = handle();
+        let event: crate::state::Event<std::convert::Infallible, std::convert::Infallible> =
+            Ok(crate::state::EventOk::DeployedNewCert);
+        handle.registry.publish(AcmeEvent::new(
+            &["domain.example".to_string()],
+            None,
+            &event,
+        ));
+        let mut app = tide::new();
+        app.at("/events").get(EventHistory::new(handle, "token"));
+        assert_eq!(
+            get(&app, "/events", None).status(),
+            StatusCode::Unauthorized
+        );
+        let events = |path| {
+            let mut res = get(&app, path, Some("token"));
+            assert_eq!(res.status(), StatusCode::Ok);
+            let body = block_on(res.body_string()).unwrap();
+            serde_json::from_str::<Vec<serde_json::Value>>(&body).unwrap()
+        };
+        let all = events("/events");
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0]["event_type"], "deployed_new_cert");
+        assert!(events("/events?failures_only=true").is_empty());
+        assert!(events("/events?since=99999999999").is_empty());
+    }
+
+    #[test]
+    fn reports_proxied_client_addresses() {
+        let handle = handle();
+        let client: SocketAddr = "192.0.2.1:56324".parse().unwrap();
+        handle
+            .registry
+            .client_addrs
+            .insert("10.0.0.1:40000".parse().unwrap(), client);
+        let mut app = tide::new();
+        app.with(ProxyClientAddr::new(handle));
+        app.at("/").get(|req: Request<()>| async move {
+            Ok(format!(
+                "{:?} {:?}",
+                req.ext::<ClientAddr>().map(|addr| addr.0),
+                req.header("Forwarded")
+                    .map(|forwarded| forwarded.as_str().to_string())
+            ))
+        });
+        let body = |peer: &str| {
+            let url = Url::parse("https://domain.example/").unwrap();
+            let mut req = http_types::Request::new(Method::Get, url);
+            req.set_peer_addr(Some(peer));
+            req.insert_header("X-Forwarded-For", "203.0.113.1");
+            let mut res: http_types::Response = block_on(app.respond(req)).unwrap();
+            block_on(res.body_string()).unwrap()
+        };
+        assert_eq!(
+            body("10.0.0.1:40000"),
+            "Some(192.0.2.1:56324) Some(\"for=\\\"192.0.2.1:56324\\\"\")"
+        );
+        assert_eq!(body("10.0.0.1:40001"), "None None");
+    }
+
+    #[test]
+    fn exposes_tls_connection_info() {
+        let handle = handle();
+        let info = crate::TlsConnectionInfo {
+            server_name: Some("domain.example".into()),
+            protocol_version: None,
+            cipher_suite: None,
+            alpn_protocol: Some(b"http/1.1".to_vec()),
+            client_certificates: vec![],
+            client_subject: None,
+        };
+        handle
+            .registry
+            .tls_info
+            .insert("10.0.0.1:40000".parse().unwrap(), info);
+        let mut app = tide::new();
+        app.with(TlsInfo::new(handle));
+        app.at("/").get(|req: Request<()>| async move {
+            let info = req.ext::<crate::TlsConnectionInfo>();
+            Ok(format!(
+                "{:?}",
+                info.and_then(|info| info.server_name.clone())
+            ))
+        });
+        let body = |peer: &str| {
+            let url = Url::parse("https://domain.example/").unwrap();
+            let mut req = http_types::Request::new(Method::Get, url);
+            req.set_peer_addr(Some(peer));
+            let mut res: http_types::Response = block_on(app.respond(req)).unwrap();
+            block_on(res.body_string()).unwrap()
+        };
+        assert_eq!(body("10.0.0.1:40000"), "Some(\"domain.example\")");
+        assert_eq!(body("10.0.0.1:40001"), "None");
+    }
+
+    #[test]
+    fn describes_the_served_chain() {
+        let handle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let (index, _) =
+            handle
+                .registry
+                .register(vec!["a.example".into()], 0, CertOrigin::Configured);
+        let cert = rcgen::generate_simple_self_signed(vec!["a.example".into()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        handle
+            .registry
+            .set_material(index, CertMaterial::fake(vec![der], b"key".to_vec()));
+        // As a listener offering h2 would.
+        handle
+            .registry
+            .record_offered_alpn(&[b"h2".to_vec(), b"acme-tls/1".to_vec()]);
+        let mut app = tide::new();
+        app.at("/tls").get(TlsDebug::new(handle.clone(), "token"));
+        app.at("/tls/:domain").get(TlsDebug::new(handle, "token"));
+        assert_eq!(get(&app, "/tls", None).status(), StatusCode::Unauthorized);
+        assert_eq!(
+            get(&app, "/tls/other.example", Some("token")).status(),
+            StatusCode::NotFound
+        );
+        let described = |path| {
+            let mut res = get(&app, path, Some("token"));
+            assert_eq!(res.status(), StatusCode::Ok);
+            let body = block_on(res.body_string()).unwrap();
+            serde_json::from_str::<Vec<Value>>(&body).unwrap()
+        };
+        let certs = described("/tls/a.example");
+        assert_eq!(certs[0]["domains"], json!(["a.example"]));
+        assert_eq!(certs[0]["chain"][0]["subject"], "CN=rcgen self signed cert");
+        assert_eq!(certs[0]["chain"][0]["sha256"].as_str().unwrap().len(), 64);
+        assert_eq!(certs[0]["ocsp_staple"], Value::Null);
+        assert_eq!(certs[0]["alpn_protocols"], json!(["acme-tls/1", "h2"]));
+        assert_eq!(described("/tls"), certs);
+    }
+
+    #[test]
+    fn lists_the_served_certificates() {
+        // Certificates that can't be parsed are left out.
+        assert!(handle().served_certificates().is_empty());
+        let handle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let (index, _) =
+            handle
+                .registry
+                .register(vec!["a.example".into()], 0, CertOrigin::Configured);
+        handle
+            .registry
+            .register(vec!["pending.example".into()], 0, CertOrigin::Configured);
+        let cert = rcgen::generate_simple_self_signed(vec!["a.example".into()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        handle.registry.set_material(
+            index,
+            CertMaterial::fake(vec![der.clone()], b"key".to_vec()),
+        );
+        let served = handle.served_certificates();
+        assert_eq!(served.len(), 1);
+        assert_eq!(served[0].domains, ["a.example"]);
+        assert_eq!(served[0].subject, "CN=rcgen self signed cert");
+        assert!(served[0].not_before < served[0].not_after);
+        assert_eq!(served[0].chain_der, std::slice::from_ref(&der));
+        assert!(handle.served_certificate("pending.example").is_none());
+
+        let mut app = tide::new();
+        app.at("/certs")
+            .get(CertInventory::new(handle.clone(), "token"));
+        app.at("/certs/:domain")
+            .get(CertInventory::new(handle, "token"));
+        assert_eq!(get(&app, "/certs", None).status(), StatusCode::Unauthorized);
+        assert_eq!(
+            get(&app, "/certs/other.example", Some("token")).status(),
+            StatusCode::NotFound
+        );
+        let listed = |path| {
+            let mut res = get(&app, path, Some("token"));
+            assert_eq!(res.status(), StatusCode::Ok);
+            assert_eq!(res["Cache-Control"], "no-store");
+            let body = block_on(res.body_string()).unwrap();
+            serde_json::from_str::<Vec<Value>>(&body).unwrap()
+        };
+        let certs = listed("/certs/a.example");
+        assert_eq!(certs[0]["domains"], json!(["a.example"]));
+        assert_eq!(certs[0]["serial"], served[0].serial);
+        assert_eq!(certs[0]["sha256"], served[0].sha256);
+        assert_eq!(certs[0]["chain_der"], json!([base64::encode(&der)]));
+        let chain_pem = certs[0]["chain_pem"].as_str().unwrap();
+        assert_eq!(pem::parse(chain_pem).unwrap().contents, der);
+        assert!(!certs[0].to_string().contains("PRIVATE KEY"));
+        assert_eq!(listed("/certs"), certs);
+    }
+
+    #[test]
+    fn reports_health() {
+        let handle = handle();
+        let (second, _) =
+            handle
+                .registry
+                .register(vec!["other.example".into()], 0, CertOrigin::Configured);
+        let mut app = tide::new();
+        app.at("/all").get(HealthCheck::new(handle.clone()));
+        app.at("/any")
+            .get(HealthCheck::new(handle.clone()).require_all(false));
+        let health = |path: &str| {
+            let mut res = get(&app, path, None);
+            let body: Value = block_on(res.body_json()).unwrap();
+            (res.status(), body)
+        };
+        let (status, body) = health("/all");
+        assert_eq!(status, StatusCode::ServiceUnavailable);
+        assert_eq!(body["ready"], false);
+        assert_eq!(body["certificates"][0]["readiness"], "pending");
+        assert_eq!(body["certificates"][0]["not_after"], Value::Null);
+
+        let not_after = UNIX_EPOCH + Duration::from_secs(4_000_000_000);
+        handle
+            .registry
+            .update(0, |status| status.not_after = Some(not_after));
+        handle
+            .registry
+            .update(second, |status| status.failed_orders = 2);
+        let (status, body) = health("/all");
+        assert_eq!(status, StatusCode::ServiceUnavailable);
+        assert_eq!(body["certificates"][0]["readiness"], "obtained");
+        assert_eq!(body["certificates"][0]["not_after"], 4_000_000_000u64);
+        assert_eq!(body["certificates"][1]["readiness"], "failed");
+        assert_eq!(body["certificates"][1]["failed_orders"], 2);
+        let (status, body) = health("/any");
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(body["ready"], true);
+
+        handle.registry.shutdown.start_draining();
+        let (status, body) = health("/any");
+        assert_eq!(status, StatusCode::ServiceUnavailable);
+        assert_eq!(body["draining"], true);
+    }
+
+    #[test]
+    fn flags_responses_under_expiring_certificates() {
+        let handle = handle();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let mut app = tide::new();
+        app.with(ExpiryWarning::new(handle.clone()).header("Expires-In"));
+        app.at("/").get(|_| async { Ok("ok") });
+        let set_expiry = |remaining: Duration| {
+            let not_after = std::time::SystemTime::now() + remaining;
+            handle
+                .registry
+                .update(0, |status| status.not_after = Some(not_after));
+        };
+        set_expiry(day * 30);
+        assert!(get(&app, "/", None).header("Expires-In").is_none());
+        set_expiry(day * 2);
+        let res = get(&app, "/", None);
+        let seconds: u64 = res["Expires-In"].as_str().parse().unwrap();
+        assert!(seconds > day.as_secs() && seconds <= 2 * day.as_secs());
+        assert_eq!(handle.expiring_cert_responses(), 1);
+    }
+
+    #[test]
+    fn adds_headers_from_the_tls_state() {
+        let handle = handle();
+        let mut app = tide::new();
+        app.with(
+            TlsHeaders::new(handle.clone())
+                .max_age(Duration::from_secs(600))
+                .not_after_header("X-Certificate-Not-After"),
+        );
+        app.at("/").get(|_| async { Ok("ok") });
+        app.at("/own").get(|_| async {
+            let mut res = tide::Response::new(200);
+            res.insert_header("Alt-Svc", "h2=\":8443\"");
+            Ok(res)
+        });
+        let headers = |url: &str| {
+            let req = http_types::Request::new(Method::Get, Url::parse(url).unwrap());
+            let res: http_types::Response = block_on(app.respond(req)).unwrap();
+            let header = |name| res.header(name).map(|value| value.as_str().to_string());
+            (header("Alt-Svc"), header("X-Certificate-Not-After"))
+        };
+        // Nothing until the certificate is obtained.
+        assert_eq!(headers("https://domain.example/"), (None, None));
+        let not_after = UNIX_EPOCH + Duration::from_secs(4_102_444_800);
+        handle
+            .registry
+            .update(0, |status| status.not_after = Some(not_after));
+        let expiry = Some("Fri, 01 Jan 2100 00:00:00 GMT".to_string());
+        assert_eq!(headers("https://domain.example/"), (None, expiry.clone()));
+        // The HTTP/3 endpoint is advertised once it is recorded, on HTTPS for managed names only.
+        handle.set_http3_port(Some(8443));
+        let alt_svc = Some("h3=\":8443\"; ma=600".to_string());
+        assert_eq!(
+            headers("https://domain.example/"),
+            (alt_svc.clone(), expiry.clone())
+        );
+        assert_eq!(
+            headers("https://Domain.example./"),
+            (alt_svc, expiry.clone())
+        );
+        assert_eq!(headers("http://domain.example/"), (None, None));
+        assert_eq!(headers("https://internal.example/"), (None, None));
+        assert_eq!(
+            headers("https://domain.example/own").0.as_deref(),
+            Some("h2=\":8443\"")
+        );
+        // And cleared while draining.
+        handle.registry.shutdown.start_draining();
+        assert_eq!(
+            headers("https://domain.example/"),
+            (Some("clear".into()), expiry)
+        );
+    }
+
+    #[test]
+    fn marks_managed_hostnames_as_https_only() {
+        let mut app = tide::new();
+        app.with(
+            Hsts::new(handle())
+                .max_age(Duration::from_secs(600))
+                .include_subdomains(true),
+        );
+        app.at("/").get(|_| async { Ok("ok") });
+        app.at("/own").get(|_| async {
+            let mut res = tide::Response::new(200);
+            res.insert_header("Strict-Transport-Security", "max-age=0");
+            Ok(res)
+        });
+        let hsts = |url: &str| {
+            let req = http_types::Request::new(Method::Get, Url::parse(url).unwrap());
+            let res: http_types::Response = block_on(app.respond(req)).unwrap();
+            res.header("Strict-Transport-Security")
+                .map(|value| value.as_str().to_string())
+        };
+        assert_eq!(
+            hsts("https://domain.example/").as_deref(),
+            Some("max-age=600; includeSubDomains")
+        );
+        assert_eq!(
+            hsts("https://DOMAIN.example.:443/").as_deref(),
+            Some("max-age=600; includeSubDomains")
+        );
+        assert_eq!(hsts("http://domain.example/"), None);
+        assert_eq!(hsts("https://internal.example/"), None);
+        assert_eq!(
+            hsts("https://domain.example/own").as_deref(),
+            Some("max-age=0")
+        );
+        assert_eq!(
+            Hsts::new(handle()).preload(true).header_value(),
+            "max-age=31536000; preload"
+        );
+    }
+
+    #[cfg(feature = "simulate-failure")]
+    #[test]
+    fn simulates_renewal_failures() {
+        let handle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let (_, mut commands) =
+            handle
+                .registry
+                .register(vec!["domain.example".into()], 0, CertOrigin::Configured);
+        let mut app = tide::new();
+        app.at("/simulate/:domain")
+            .post(FailureSimulation::new(handle, "token"));
+        let post = |path: &str, token: &str, body: &str| {
+            let url = Url::parse("https://domain.example")
+                .unwrap()
+                .join(path)
+                .unwrap();
+            let mut req = http_types::Request::new(Method::Post, url);
+            req.insert_header("Authorization", format!("Bearer {}", token));
+            req.set_body(body);
+            let res: http_types::Response = block_on(app.respond(req)).unwrap();
+            res.status()
+        };
+        let status = post("/simulate/domain.example", "wrong", "");
+        assert_eq!(status, StatusCode::Unauthorized);
+        let status = post("/simulate/other.example", "token", "");
+        assert_eq!(status, StatusCode::NotFound);
+        let status = post("/simulate/domain.example", "token", " CA down \n");
+        assert_eq!(status, StatusCode::Accepted);
+        let status = post("/simulate/domain.example", "token", "");
+        assert_eq!(status, StatusCode::Accepted);
+        let messages: Vec<String> = std::iter::from_fn(|| match commands.try_recv() {
+            Ok(crate::handle::Command::SimulateFailure(message)) => Some(message),
+            _ => None,
+        })
+        .collect();
+        assert_eq!(
+            messages,
+            ["CA down", "renewal failure injected for a rehearsal"]
+        );
+    }
+
+    #[test]
+    fn matches_challenge_paths() {
+        let path = "/.well-known/acme-challenge/token";
+        assert_eq!(challenge_token(path, None), Some("token"));
+        assert_eq!(challenge_token(path, Some("/app")), Some("token"));
+        let path = "/app/.well-known/acme-challenge/token";
+        assert_eq!(challenge_token(path, Some("/app")), Some("token"));
+        assert_eq!(challenge_token(path, None), None);
+    }
+
+    #[test]
+    fn rejects_other_paths() {
+        let prefix = Some("/app");
+        assert_eq!(challenge_token("/", prefix), None);
+        assert_eq!(
+            challenge_token("/.well-known/acme-challenge/", prefix),
+            None
+        );
+        assert_eq!(
+            challenge_token("/.well-known/acme-challenge/a/b", prefix),
+            None
+        );
+        let path = "/application/.well-known/acme-challenge/token";
+        assert_eq!(challenge_token(path, prefix), None);
+        let path = "/other/.well-known/acme-challenge/token";
+        assert_eq!(challenge_token(path, prefix), None);
+    }
+
+    #[test]
+    fn honors_forwarded_paths_when_trusted() {
+        let handle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        handle
+            .registry
+            .set_http01_response("token".into(), "token.thumbprint".into());
+        let status =
+            |middleware: AcmeHttp01Middleware, path: &str, header: Option<(&str, &str)>| {
+                let mut app = tide::new();
+                app.with(middleware);
+                let url = Url::parse("http://domain.example")
+                    .unwrap()
+                    .join(path)
+                    .unwrap();
+                let mut req = http_types::Request::new(Method::Get, url);
+                if let Some((name, value)) = header {
+                    req.insert_header(name, value);
+                }
+                let res: http_types::Response = block_on(app.respond(req)).unwrap();
+                res.status()
+            };
+        let prefixed = || AcmeHttp01Middleware::new(handle.clone()).path_prefix("app/");
+        let trusting = || prefixed().trust_forwarded_headers(true);
+        let path = "/app/.well-known/acme-challenge/token";
+        assert_eq!(status(prefixed(), path, None), StatusCode::Ok);
+        // A frontend that rewrote the path to a different one.
+        let uri = Some((
+            "X-Forwarded-Uri",
+            "/app/.well-known/acme-challenge/token?x=1",
+        ));
+        assert_eq!(status(prefixed(), "/rewritten", uri), StatusCode::NotFound);
+        assert_eq!(status(trusting(), "/rewritten", uri), StatusCode::Ok);
+        let path = "/app/.well-known/acme-challenge/missing";
+        assert_eq!(status(trusting(), path, None), StatusCode::NotFound);
+    }
+}
@@ -0,0 +1,412 @@
+//! Certificate Transparency checks for issued certificates.
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use ring::digest::{digest, SHA256};
+use ring::signature::{self, UnparsedPublicKey};
+use rustls_acme::{AccountCache, CertCache};
+use thiserror::Error;
+use tracing::{debug, warn};
+use x509_parser::extensions::{ParsedExtension, SignedCertificateTimestamp};
+use x509_parser::parse_x509_certificate;
+
+use crate::der::{der_element, der_encode};
+use crate::format;
+
+/// DER encoding of the OID for the embedded SCT list extension, 1.3.6.1.4.1.11129.2.4.2.
+const SCT_LIST_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0xd6, 0x79, 0x02, 0x04, 0x02];
+
+/// A Certificate Transparency log that signed certificate timestamps can be verified against.
+#[derive(Clone, Debug)]
+pub struct CtLog {
+    description: String,
+    id: [u8; 32],
+    key: Vec<u8>,
+}
+
+impl CtLog {
+    /// Create a log from a description and the log's public key, as DER-encoded
+    /// SubjectPublicKeyInfo (the `key` field of a CT log list, base64-decoded).
+    pub fn new(description: impl Into<String>, spki_der: &[u8]) -> Result<Self, SctError> {
+        let (spki, _) = der_element(spki_der).ok_or(SctError::MalformedLogKey)?;
+        let (_algorithm, rest) = der_element(spki.contents).ok_or(SctError::MalformedLogKey)?;
+        let key = match der_element(rest) {
+            Some((bits, _)) if bits.tag == 0x03 && !bits.contents.is_empty() => {
+                bits.contents[1..].to_vec()
+            }
+            _ => return Err(SctError::MalformedLogKey),
+        };
+        let mut id = [0; 32];
+        id.copy_from_slice(digest(&SHA256, spki_der).as_ref());
+        Ok(Self {
+            description: description.into(),
+            id,
+            key,
+        })
+    }
+
+    /// The description of this log.
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    /// The log ID, the SHA-256 hash of the log's public key.
+    pub fn id(&self) -> &[u8; 32] {
+        &self.id
+    }
+
+    fn verify(&self, sct: &SignedCertificateTimestamp, message: &[u8]) -> bool {
+        let algorithm: &dyn signature::VerificationAlgorithm =
+            match (sct.signature.hash_alg_id, sct.signature.sign_alg_id) {
+                (4, 3) => &signature::ECDSA_P256_SHA256_ASN1,
+                (4, 1) => &signature::RSA_PKCS1_2048_8192_SHA256,
+                _ => return false,
+            };
+        UnparsedPublicKey::new(algorithm, &self.key)
+            .verify(message, sct.signature.data)
+            .is_ok()
+    }
+}
+
+/// Errors from verifying the signed certificate timestamps embedded in a certificate.
+#[derive(Error, Debug)]
+pub enum SctError {
+    /// The certificate data could not be parsed as PEM.
+    #[error("PEM parse error: {0}")]
+    Pem(#[from] pem::PemError),
+    /// The certificate chain did not include the issuer certificate.
+    #[error("certificate chain does not include an issuer certificate")]
+    MissingIssuer,
+    /// A certificate in the chain could not be parsed.
+    #[error("malformed certificate")]
+    MalformedCertificate,
+    /// A CT log public key could not be parsed.
+    #[error("malformed CT log public key")]
+    MalformedLogKey,
+    /// The certificate does not embed any signed certificate timestamps.
+    #[error("certificate has no embedded SCTs")]
+    NoScts,
+}
+
+/// Verify the signed certificate timestamps embedded in the leaf certificate of a PEM bundle, as
+/// stored in the certificate cache, against the given CT logs.
+///
+/// Returns the number of SCTs with a valid signature from one of the given logs. SCTs from logs
+/// not in `logs`, or with timestamps in the future, don't count as valid.
+pub fn verify_scts(pem: &[u8], logs: &[CtLog]) -> Result<usize, SctError> {
+    let certs: Vec<Vec<u8>> = format::parse_pems(pem)?
+        .iter()
+        .filter(|p| p.tag == "CERTIFICATE")
+        .map(|p| p.contents.to_vec())
+        .collect();
+    let (leaf, issuer) = match certs.as_slice() {
+        [leaf, issuer, ..] => (leaf, issuer),
+        _ => return Err(SctError::MissingIssuer),
+    };
+    let (_, leaf) = parse_x509_certificate(leaf).map_err(|_| SctError::MalformedCertificate)?;
+    let (_, issuer) = parse_x509_certificate(issuer).map_err(|_| SctError::MalformedCertificate)?;
+    let scts = leaf
+        .extensions()
+        .iter()
+        .find_map(|ext| match ext.parsed_extension() {
+            ParsedExtension::SCT(scts) => Some(scts),
+            _ => None,
+        })
+        .ok_or(SctError::NoScts)?;
+    let issuer_key_hash = digest(&SHA256, issuer.public_key().raw);
+    let tbs = precert_tbs(leaf.tbs_certificate.as_ref()).ok_or(SctError::MalformedCertificate)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+
+    let mut valid = 0;
+    for sct in scts {
+        let log = match logs.iter().find(|log| &log.id == sct.id.key_id) {
+            Some(log) => log,
+            None => {
+                debug!(log_id = ?sct.id.key_id, "SCT from unknown CT log");
+                continue;
+            }
+        };
+        if sct.timestamp > now {
+            warn!(log = %log.description, "SCT timestamp is in the future");
+            continue;
+        }
+        // The digitally-signed struct for a precert_entry, per RFC 6962 section 3.2.
+        let mut message = vec![sct.version.0, 0];
+        message.extend_from_slice(&sct.timestamp.to_be_bytes());
+        message.extend_from_slice(&[0, 1]);
+        message.extend_from_slice(issuer_key_hash.as_ref());
+        message.extend_from_slice(&(tbs.len() as u32).to_be_bytes()[1..]);
+        message.extend_from_slice(&tbs);
+        message.extend_from_slice(&(sct.extensions.0.len() as u16).to_be_bytes());
+        message.extend_from_slice(sct.extensions.0);
+        if log.verify(sct, &message) {
+            valid += 1;
+        } else {
+            warn!(log = %log.description, "SCT signature is invalid");
+        }
+    }
+    Ok(valid)
+}
+
+/// Certificate cache wrapper that verifies the SCTs embedded in certificates.
+///
+/// Certificates are checked when they're stored after download and when they're loaded from the
+/// underlying cache. If a certificate has fewer than the required number of SCTs with valid
+/// signatures from the configured logs, this emits a warning; the certificate is still stored and
+/// served, so a CT problem never takes a working server offline.
+pub struct SctCheck<C> {
+    inner: C,
+    logs: Arc<[CtLog]>,
+    min_scts: usize,
+}
+
+impl<C> SctCheck<C> {
+    /// Wrap a cache, verifying SCTs against the given CT logs.
+    ///
+    /// By default, certificates need at least 2 valid SCTs.
+    pub fn new(inner: C, logs: impl IntoIterator<Item = CtLog>) -> Self {
+        Self {
+            inner,
+            logs: logs.into_iter().collect(),
+            min_scts: 2,
+        }
+    }
+
+    /// Set the number of valid SCTs a certificate needs to avoid a warning.
+    pub fn min_scts(mut self, min_scts: usize) -> Self {
+        self.min_scts = min_scts;
+        self
+    }
+
+    /// Unwrap the underlying cache.
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    fn check(&self, domains: &[String], pem: &[u8]) {
+        // Empty entries are cleared slots, such as unused previous certificate slots.
+        if pem.is_empty() {
+            return;
+        }
+        match verify_scts(pem, &self.logs) {
+            Ok(valid) if valid >= self.min_scts => {
+                debug!(?domains, valid, "certificate SCTs verified")
+            }
+            Ok(valid) => warn!(
+                ?domains,
+                valid,
+                required = self.min_scts,
+                "certificate has too few valid SCTs"
+            ),
+            Err(error) => warn!(?domains, %error, "certificate SCT verification failed"),
+        }
+    }
+}
+
+#[async_trait]
+impl<C: CertCache> CertCache for SctCheck<C> {
+    type EC = C::EC;
+
+    async fn load_cert(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+    ) -> Result<Option<Vec<u8>>, Self::EC> {
+        let pem = self.inner.load_cert(domains, directory_url).await?;
+        if let Some(pem) = &pem {
+            self.check(domains, pem);
+        }
+        Ok(pem)
+    }
+
+    async fn store_cert(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+        cert: &[u8],
+    ) -> Result<(), Self::EC> {
+        self.check(domains, cert);
+        self.inner.store_cert(domains, directory_url, cert).await
+    }
+}
+
+#[async_trait]
+impl<C: AccountCache> AccountCache for SctCheck<C> {
+    type EA = C::EA;
+
+    async fn load_account(
+        &self,
+        contact: &[String],
+        directory_url: &str,
+    ) -> Result<Option<Vec<u8>>, Self::EA> {
+        self.inner.load_account(contact, directory_url).await
+    }
+
+    async fn store_account(
+        &self,
+        contact: &[String],
+        directory_url: &str,
+        account: &[u8],
+    ) -> Result<(), Self::EA> {
+        self.inner
+            .store_account(contact, directory_url, account)
+            .await
+    }
+}
+
+/// Reconstruct the precertificate TBSCertificate that the CT logs signed, by removing the embedded
+/// SCT list extension from the final certificate's TBSCertificate.
+fn precert_tbs(tbs: &[u8]) -> Option<Vec<u8>> {
+    let (tbs, _) = der_element(tbs)?;
+    let mut fields = tbs.contents;
+    let mut out = Vec::with_capacity(tbs.element.len());
+    while !fields.is_empty() {
+        let (field, rest) = der_element(fields)?;
+        if field.tag == 0xa3 {
+            let (extensions, _) = der_element(field.contents)?;
+            let mut extensions = extensions.contents;
+            let mut kept = Vec::with_capacity(extensions.len());
+            while !extensions.is_empty() {
+                let (extension, rest) = der_element(extensions)?;
+                let (oid, _) = der_element(extension.contents)?;
+                if oid.contents != SCT_LIST_OID {
+                    kept.extend_from_slice(extension.element);
+                }
+                extensions = rest;
+            }
+            out.extend(der_encode(0xa3, &der_encode(0x30, &kept)));
+        } else {
+            out.extend_from_slice(field.element);
+        }
+        fields = rest;
+    }
+    Some(der_encode(0x30, &out))
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{Certificate, CertificateParams, CustomExtension, KeyPair as CertKeyPair};
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+
+    use super::*;
+
+    /// DER encoding of the AlgorithmIdentifier for ECDSA keys on P-256.
+    const P256_ALGORITHM: &[u8] = &[
+        0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a, 0x86,
+        0x48, 0xce, 0x3d, 0x03, 0x01, 0x07,
+    ];
+
+    /// A freshly generated P-256 public key as SubjectPublicKeyInfo, and the raw key.
+    fn log_key() -> (Vec<u8>, Vec<u8>) {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref()).unwrap();
+        let raw = key.public_key().as_ref().to_vec();
+        let bits = der_encode(0x03, &[&[0], &raw[..]].concat());
+        (der_encode(0x30, &[P256_ALGORITHM, &bits].concat()), raw)
+    }
+
+    #[test]
+    fn parses_log_keys() {
+        let (spki, raw) = log_key();
+        let log = CtLog::new("test log", &spki).unwrap();
+        assert_eq!(log.description(), "test log");
+        assert_eq!(&log.id()[..], digest(&SHA256, &spki).as_ref());
+        assert_eq!(log.key, raw);
+    }
+
+    #[test]
+    fn rejects_malformed_log_keys() {
+        let (spki, _) = log_key();
+        for len in [0, 1, 2, 10] {
+            assert!(matches!(
+                CtLog::new("test log", &spki[..len]),
+                Err(SctError::MalformedLogKey)
+            ));
+        }
+        // The algorithm followed by an empty bit string.
+        let empty = der_encode(0x30, &[P256_ALGORITHM, &[0x03, 0x00]].concat());
+        assert!(matches!(
+            CtLog::new("test log", &empty),
+            Err(SctError::MalformedLogKey)
+        ));
+    }
+
+    /// A certificate for `domain.example` signed by `key`, with an embedded SCT list if `sct`.
+    fn cert(key: &CertKeyPair, sct: bool) -> Vec<u8> {
+        let mut params = CertificateParams::new(vec!["domain.example".to_string()]);
+        params.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+        params.serial_number = Some(1);
+        params.key_pair = Some(CertKeyPair::from_pem(&key.serialize_pem()).unwrap());
+        if sct {
+            let oid = [1, 3, 6, 1, 4, 1, 11129, 2, 4, 2];
+            // One SCT from an unknown log: version, log ID, timestamp, no extensions, and an
+            // ECDSA signature over SHA-256.
+            let sct = [
+                &[0][..],
+                &[7; 32],
+                &[0; 8],
+                &[0, 0],
+                &[4, 3],
+                &[0, 2, 0x30, 0x00],
+            ]
+            .concat();
+            let sct = [&(sct.len() as u16).to_be_bytes()[..], &sct].concat();
+            let list = [&(sct.len() as u16).to_be_bytes()[..], &sct].concat();
+            let list = der_encode(0x04, &list);
+            params.custom_extensions = vec![CustomExtension::from_oid_content(&oid, list)];
+        }
+        let cert = Certificate::from_params(params).unwrap();
+        cert.serialize_der().unwrap()
+    }
+
+    #[test]
+    fn removes_sct_lists_from_tbs_certificates() {
+        let key = CertKeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        let (with, without) = (cert(&key, true), cert(&key, false));
+        let (_, with) = parse_x509_certificate(&with).unwrap();
+        let (_, without) = parse_x509_certificate(&without).unwrap();
+        let tbs = with.tbs_certificate.as_ref();
+        assert_ne!(tbs, without.tbs_certificate.as_ref());
+        assert_eq!(
+            precert_tbs(tbs).as_deref(),
+            Some(without.tbs_certificate.as_ref())
+        );
+        assert!(precert_tbs(&tbs[..tbs.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn rejects_unverifiable_chains() {
+        let key = CertKeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        let pem = |der: &[u8]| {
+            pem::encode(&pem::Pem {
+                tag: "CERTIFICATE".into(),
+                contents: der.to_vec(),
+            })
+        };
+        let leaf = pem(&cert(&key, false));
+        assert!(matches!(
+            verify_scts(leaf.as_bytes(), &[]),
+            Err(SctError::MissingIssuer)
+        ));
+        let chain = [leaf.clone(), leaf.clone()].concat();
+        assert!(matches!(
+            verify_scts(chain.as_bytes(), &[]),
+            Err(SctError::NoScts)
+        ));
+        let garbage = [pem(b"not a certificate"), leaf].concat();
+        assert!(matches!(
+            verify_scts(garbage.as_bytes(), &[]),
+            Err(SctError::MalformedCertificate)
+        ));
+        let chain = [pem(&cert(&key, true)), pem(&cert(&key, false))].concat();
+        assert_eq!(verify_scts(chain.as_bytes(), &[]).unwrap(), 0);
+    }
+}
@@ -0,0 +1,249 @@
+//! Checking the certificate the server presents, over a TLS connection to its own listener.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_std::future::timeout;
+use async_std::net::TcpStream;
+use tide_rustls::async_rustls::webpki::DNSNameRef;
+use tide_rustls::async_rustls::TlsConnector;
+use tide_rustls::rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+    WebPKIVerifier,
+};
+use webpki_roots::TLS_SERVER_ROOTS;
+
+use crate::AcmeHandle;
+
+/// How long the self-test waits to connect and complete the handshake.
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The outcome of [`AcmeHandle::self_test`]: what the listener presented, and the discrepancies
+/// found.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct SelfTestReport {
+    /// The server name the test connected with.
+    pub domain: String,
+    /// The address the test connected to.
+    pub addr: SocketAddr,
+    /// The certificate chain the listener presented, leaf first, as DER; empty if the handshake
+    /// didn't get that far.
+    pub presented_chain: Vec<Vec<u8>>,
+    /// What is wrong, if anything: the connection or handshake failing, the presented
+    /// certificate differing from the one managed for the domain, or the chain not validating
+    /// for the domain against the trusted roots.
+    pub problems: Vec<String>,
+}
+
+impl SelfTestReport {
+    /// Whether the test found nothing wrong.
+    pub fn passed(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Records the chain the server presents, accepting it so the handshake completes; the chain is
+/// validated afterwards, to report why it fails rather than only that it does.
+#[derive(Default)]
+struct RecordingVerifier {
+    chain: Mutex<Vec<Certificate>>,
+}
+
+impl ServerCertVerifier for RecordingVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        _dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        *self.chain.lock().unwrap() = presented_certs.to_vec();
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+impl AcmeHandle {
+    /// Open a TLS connection to this server's own listener for `domain`, on the loopback
+    /// interface at the port tls-alpn-01 validation connections arrive on (443, unless
+    /// [forwarded](crate::AcmeConfig::tls_alpn_port_mapping)), and check the certificate it
+    /// presents: that it is the one managed for `domain`, and that it validates for `domain`
+    /// against the Mozilla root store the ACME client trusts too. This automates connecting with
+    /// `curl -k` and inspecting the certificate by eye.
+    ///
+    /// For a wildcard certificate, pass a name it covers, such as `www.domain.example`. Use
+    /// [`self_test_at`](Self::self_test_at) for a listener on another address.
+    ///
+    /// ```no_run
+    /// # async fn example(handle: tide_acme::AcmeHandle) {
+    /// let report = handle.self_test("domain.example").await;
+    /// for problem in &report.problems {
+    ///     eprintln!("{}: {}", report.domain, problem);
+    /// }
+    /// # }
+    /// ```
+    pub async fn self_test(&self, domain: &str) -> SelfTestReport {
+        let port = self.registry.validation_port();
+        self.self_test_at(domain, SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+            .await
+    }
+
+    /// Like [`self_test`](Self::self_test), connecting to the listener at `addr`.
+    pub async fn self_test_at(&self, domain: &str, addr: SocketAddr) -> SelfTestReport {
+        let mut report = SelfTestReport {
+            domain: domain.into(),
+            addr,
+            presented_chain: vec![],
+            problems: vec![],
+        };
+        let dns_name = match DNSNameRef::try_from_ascii_str(domain) {
+            Ok(dns_name) => dns_name,
+            Err(_) => {
+                report
+                    .problems
+                    .push(format!("{:?} is not a valid server name", domain));
+                return report;
+            }
+        };
+        let verifier = Arc::new(RecordingVerifier::default());
+        let mut config = ClientConfig::default();
+        config
+            .dangerous()
+            .set_certificate_verifier(verifier.clone());
+        let handshake = async {
+            let tcp = TcpStream::connect(addr).await?;
+            TlsConnector::from(Arc::new(config))
+                .connect(dns_name, tcp)
+                .await
+        };
+        match timeout(SELF_TEST_TIMEOUT, handshake).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => report.problems.push(format!("handshake failed: {}", err)),
+            Err(_) => report
+                .problems
+                .push(format!("handshake timed out after {:?}", SELF_TEST_TIMEOUT)),
+        }
+        let chain = verifier.chain.lock().unwrap().clone();
+        if chain.is_empty() {
+            return report;
+        }
+        report.presented_chain = chain.iter().map(|cert| cert.0.clone()).collect();
+
+        match self.registry.material(domain) {
+            Some(material) if material.chain.first() == report.presented_chain.first() => {}
+            Some(_) => report.problems.push(
+                "the presented certificate differs from the one managed for the domain; another \
+                 process may be listening, or a proxy terminating TLS"
+                    .into(),
+            ),
+            None => report
+                .problems
+                .push("no certificate is managed for the domain yet".into()),
+        }
+
+        let mut roots = RootCertStore::empty();
+        roots.add_server_trust_anchors(&TLS_SERVER_ROOTS);
+        if let Err(err) = WebPKIVerifier::new().verify_server_cert(&roots, &chain, dns_name, &[]) {
+            report.problems.push(format!(
+                "the presented chain does not validate for the domain: {}",
+                err
+            ));
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::net::TcpListener;
+    use async_std::task::{block_on, spawn};
+    use tide_rustls::async_rustls::TlsAcceptor;
+    use tide_rustls::rustls::sign::{any_supported_type, CertifiedKey};
+    use tide_rustls::rustls::{NoClientAuth, PrivateKey, ServerConfig};
+
+    use super::*;
+    use crate::handle::CertMaterial;
+    use crate::snapshot::CertOrigin;
+    use crate::AcmeConfig;
+
+    /// A self-signed certificate for `domain`.
+    fn material(domain: &str) -> CertMaterial {
+        let cert = rcgen::generate_simple_self_signed(vec![domain.into()]).unwrap();
+        let chain = vec![cert.serialize_der().unwrap()];
+        let key = cert.serialize_private_key_der();
+        let signing_key = any_supported_type(&PrivateKey(key.clone())).unwrap();
+        let certified_key =
+            CertifiedKey::new(vec![Certificate(chain[0].clone())], Arc::new(signing_key));
+        CertMaterial {
+            chain,
+            key: key.into(),
+            certified_key,
+        }
+    }
+
+    /// Accept TLS connections presenting `material`, returning the address listened on.
+    async fn serve(material: &CertMaterial) -> SocketAddr {
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        let chain = material.chain.iter().cloned().map(Certificate).collect();
+        config
+            .set_single_cert(chain, PrivateKey(material.key.to_vec()))
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let _ = acceptor.accept(tcp).await;
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn checks_the_presented_certificate() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let (index, _) =
+            handle
+                .registry
+                .register(vec!["a.example".into()], 0, CertOrigin::Configured);
+        let served = material("a.example");
+        let served_der = served.chain[0].clone();
+        block_on(async {
+            let addr = serve(&served).await;
+            let report = handle.self_test_at("a.example", addr).await;
+            assert_eq!(report.presented_chain, std::slice::from_ref(&served_der));
+            assert_eq!(report.problems.len(), 2);
+            assert!(report.problems[0].contains("no certificate is managed"));
+            assert!(report.problems[1].contains("does not validate"));
+
+            handle.registry.set_material(index, material("a.example"));
+            let report = handle.self_test_at("a.example", addr).await;
+            assert!(report.problems[0].contains("differs from the one managed"));
+
+            handle.registry.set_material(index, served);
+            let report = handle.self_test_at("a.example", addr).await;
+            // Only the self-signed chain failing to validate is left.
+            assert_eq!(report.problems.len(), 1);
+            assert!(!report.passed());
+        });
+    }
+
+    #[test]
+    fn reports_failed_connections() {
+        let handle: AcmeHandle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        block_on(async {
+            let report = handle
+                .self_test_at("not a name", "127.0.0.1:1".parse().unwrap())
+                .await;
+            assert!(report.problems[0].contains("not a valid server name"));
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            drop(listener);
+            let report = handle.self_test_at("a.example", addr).await;
+            assert!(report.presented_chain.is_empty());
+            assert!(report.problems[0].starts_with("handshake failed"));
+        });
+    }
+}
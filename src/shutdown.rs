@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+
+use async_std::future::timeout;
+use async_std::task::{self, JoinHandle};
+use event_listener::Event;
+use futures::future::select;
+
+/// The background tasks of an acceptor, stopped together on shutdown.
+#[derive(Default)]
+pub(crate) struct Shutdown {
+    stopping: AtomicBool,
+    stop: Event,
+    /// The tasks still running, by ID. Each removes itself once it finishes, so that a
+    /// long-running acceptor doesn't keep the handles of every task it ever ran.
+    tasks: Arc<Mutex<HashMap<u64, JoinHandle<()>>>>,
+    next_task: AtomicU64,
+    draining: AtomicBool,
+    writes: AtomicUsize,
+    writes_finished: Event,
+}
+
+impl Shutdown {
+    /// Spawn a background task, which is dropped at its next await point once shutdown starts.
+    pub(crate) fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) {
+        let stop = self.stop.listen();
+        if self.stopping() {
+            return;
+        }
+        let id = self.next_task.fetch_add(1, Ordering::Relaxed);
+        let tasks = self.tasks.clone();
+        // Held until the task is added, so it can't try removing itself before then.
+        let mut running = self.tasks();
+        let task = task::spawn(async move {
+            select(Box::pin(future), stop).await;
+            lock(&tasks).remove(&id);
+        });
+        running.insert(id, task);
+    }
+
+    fn tasks(&self) -> MutexGuard<'_, HashMap<u64, JoinHandle<()>>> {
+        lock(&self.tasks)
+    }
+
+    pub(crate) fn stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Start shutdown, without waiting for the tasks to stop.
+    pub(crate) fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.stop.notify(usize::MAX);
+    }
+
+    /// Stop the tasks and wait until they have.
+    pub(crate) async fn shutdown(&self) {
+        self.stop();
+        let tasks = std::mem::take(&mut *self.tasks());
+        for task in tasks.into_values() {
+            task.await;
+        }
+    }
+
+    /// Whether the acceptor is draining, refusing new connections.
+    pub(crate) fn draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn start_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    /// Count a cache write as in progress until the returned guard is dropped, so draining waits
+    /// for it rather than stopping the task in the middle of it.
+    pub(crate) fn start_write(&self) -> WriteGuard<'_> {
+        self.writes.fetch_add(1, Ordering::AcqRel);
+        WriteGuard(self)
+    }
+
+    /// Wait until no cache write is in progress, or `deadline` passes. Returns whether none is.
+    pub(crate) async fn wait_for_writes(&self, deadline: Instant) -> bool {
+        loop {
+            let finished = self.writes_finished.listen();
+            if self.writes.load(Ordering::Acquire) == 0 {
+                return true;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if timeout(remaining, finished).await.is_err() {
+                return self.writes.load(Ordering::Acquire) == 0;
+            }
+        }
+    }
+}
+
+fn lock(
+    tasks: &Mutex<HashMap<u64, JoinHandle<()>>>,
+) -> MutexGuard<'_, HashMap<u64, JoinHandle<()>>> {
+    tasks.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A cache write in progress, counted until dropped.
+pub(crate) struct WriteGuard<'a>(&'a Shutdown);
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        self.0.writes.fetch_sub(1, Ordering::AcqRel);
+        self.0.writes_finished.notify(usize::MAX);
+    }
+}
+
+/// Starts shutdown when the last clone of an acceptor is dropped.
+pub(crate) struct ShutdownOnDrop(pub(crate) std::sync::Arc<crate::handle::Registry>);
+
+impl Drop for ShutdownOnDrop {
+    fn drop(&mut self) {
+        self.0.shutdown.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    use async_std::task::block_on;
+
+    use super::*;
+    use crate::AcmeConfig;
+
+    #[test]
+    fn stops_tasks_on_shutdown() {
+        let shutdown = Shutdown::default();
+        let finished = Arc::new(AtomicUsize::new(0));
+        let done = finished.clone();
+        shutdown.spawn(async move {
+            done.fetch_add(1, Ordering::SeqCst);
+        });
+        shutdown.spawn(futures::future::pending());
+        block_on(shutdown.shutdown());
+        assert!(shutdown.stopping());
+        assert!(shutdown.tasks().is_empty());
+        // Tasks spawned once stopping never run.
+        let done = finished.clone();
+        shutdown.spawn(async move {
+            done.fetch_add(1, Ordering::SeqCst);
+        });
+        assert!(shutdown.tasks().is_empty());
+        assert!(finished.load(Ordering::SeqCst) <= 1);
+    }
+
+    #[test]
+    fn forgets_finished_tasks() {
+        let shutdown = Shutdown::default();
+        for _ in 0..100 {
+            shutdown.spawn(async {});
+        }
+        shutdown.spawn(futures::future::pending());
+        block_on(async {
+            while shutdown.tasks().len() > 1 {
+                task::sleep(Duration::from_millis(1)).await;
+            }
+        });
+        assert_eq!(shutdown.tasks().keys().collect::<Vec<_>>(), [&100]);
+        block_on(shutdown.shutdown());
+        assert!(shutdown.tasks().is_empty());
+    }
+
+    #[test]
+    fn waits_for_writes() {
+        let shutdown = Shutdown::default();
+        let soon = || Instant::now() + Duration::from_millis(20);
+        assert!(block_on(shutdown.wait_for_writes(soon())));
+        let write = shutdown.start_write();
+        assert!(!block_on(shutdown.wait_for_writes(soon())));
+        let finished = block_on(async {
+            let waiting = shutdown.wait_for_writes(Instant::now() + Duration::from_secs(10));
+            let finishing = async {
+                task::sleep(Duration::from_millis(10)).await;
+                drop(write);
+            };
+            futures::join!(waiting, finishing).0
+        });
+        assert!(finished);
+    }
+
+    #[test]
+    fn stops_tasks_when_dropped() {
+        let handle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let registry = handle.registry.clone();
+        registry.shutdown.spawn(futures::future::pending());
+        drop(ShutdownOnDrop(registry.clone()));
+        assert!(registry.shutdown.stopping());
+        block_on(handle.shutdown());
+    }
+}
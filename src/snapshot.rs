@@ -0,0 +1,297 @@
+//! Saving the runtime state of an acceptor that the cache doesn't keep, to resume from it after a
+//! restart.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::problem::{problem_hint, ChallengeFailure, Problem};
+use crate::state::ERROR_CLASSES;
+use crate::{AcmeEvent, EventKind};
+
+/// The runtime state of an acceptor beyond what the cache keeps, taken with
+/// [`AcmeHandle::snapshot`](crate::AcmeHandle::snapshot) and restored with
+/// [`AcmeConfig::restore`](crate::AcmeConfig::restore), so that a restarted process resumes
+/// where the previous one left off.
+///
+/// It holds, for each certificate, the renewal schedule and the backoff after failed orders,
+/// along with the URL of the order in progress; the domains whose certificates were obtained on
+/// demand or added at runtime; and the [event history](crate::AcmeConfig::event_history). It
+/// holds no keys, and serializes with serde, such as to JSON with [`save`](Self::save).
+///
+/// ```no_run
+/// use tide_acme::{AcmeConfig, AcmeTlsAcceptor, Snapshot};
+///
+/// # async_std::task::block_on(async {
+/// let mut config = AcmeConfig::new(vec!["domain.example"]);
+/// if let Some(snapshot) = Snapshot::load("/var/lib/tide-acme/snapshot.json")? {
+///     config = config.restore(snapshot);
+/// }
+/// let acceptor = AcmeTlsAcceptor::new(config);
+/// // Before exiting:
+/// acceptor.handle().snapshot().save("/var/lib/tide-acme/snapshot.json")?;
+/// # std::io::Result::Ok(())
+/// # });
+/// ```
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub(crate) certs: Vec<CertResume>,
+    pub(crate) events: Vec<SavedEvent>,
+}
+
+impl Snapshot {
+    /// Write the snapshot to the file at `path` as JSON, replacing it atomically, via a temporary
+    /// file renamed over it.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Read a snapshot written with [`save`](Self::save) from the file at `path`, or `None` if
+    /// there is no such file.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Option<Self>> {
+        match fs::read(path) {
+            Ok(json) => Ok(Some(serde_json::from_slice(&json)?)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// The domains to obtain certificates for again, and how they were first requested.
+    pub(crate) fn runtime_domains(&self) -> Vec<(String, CertOrigin)> {
+        self.certs
+            .iter()
+            .filter(|cert| cert.origin != CertOrigin::Configured)
+            .filter_map(|cert| Some((cert.domains.first()?.clone(), cert.origin)))
+            .collect()
+    }
+
+    /// The events of the history, oldest first, skipping kinds this version doesn't know.
+    pub(crate) fn history(&self) -> Vec<AcmeEvent> {
+        self.events
+            .iter()
+            .filter_map(SavedEvent::to_event)
+            .collect()
+    }
+}
+
+/// How a certificate came to be managed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum CertOrigin {
+    /// Its domains are configured.
+    #[default]
+    Configured,
+    /// It was obtained on demand, as approved by the domain policy.
+    OnDemand,
+    /// Its domain was added with [`AcmeHandle::add_domain`](crate::AcmeHandle::add_domain).
+    Added,
+}
+
+/// The runtime state of a certificate's management, as it was after its last event.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct CertResume {
+    pub(crate) domains: Vec<String>,
+    pub(crate) origin: CertOrigin,
+    /// The end of the validity period of the certificate being served, to tell whether the
+    /// restarted process serves the same one.
+    pub(crate) not_after: Option<SystemTime>,
+    pub(crate) renew_at: Option<SystemTime>,
+    pub(crate) backoff_cnt: u32,
+    pub(crate) failure_cnt: u32,
+    pub(crate) attempts: Vec<SystemTime>,
+    pub(crate) staged: bool,
+    /// Domains left out of orders after failing validation: the domain, its consecutive
+    /// failures, and the end of its backoff.
+    pub(crate) domain_backoff: Vec<(String, u32, SystemTime)>,
+    pub(crate) order_url: Option<String>,
+    pub(crate) failed_orders: u32,
+    pub(crate) last_error: Option<String>,
+    #[serde(default)]
+    pub(crate) last_failure: Option<Error>,
+}
+
+/// An [`AcmeEvent`] of the history, with its kind and error class by name.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct SavedEvent {
+    domains: Vec<String>,
+    kind: String,
+    order_url: Option<String>,
+    error_class: Option<String>,
+    error: Option<String>,
+    #[serde(default)]
+    failure: Option<Error>,
+    problem_type: Option<String>,
+    #[serde(default)]
+    problem: Option<Problem>,
+    #[serde(default)]
+    challenge_failure: Option<ChallengeFailure>,
+    at: SystemTime,
+    acceptor: Option<String>,
+}
+
+impl SavedEvent {
+    pub(crate) fn new(event: &AcmeEvent) -> Self {
+        Self {
+            domains: event.domains.clone(),
+            kind: event.kind.name().into(),
+            order_url: event.order_url.clone(),
+            error_class: event.error_class.map(Into::into),
+            error: event.error.clone(),
+            failure: event.failure.clone(),
+            problem_type: event.problem_type.clone(),
+            problem: event.problem.clone(),
+            challenge_failure: event.challenge_failure.clone(),
+            at: event.at,
+            acceptor: event.acceptor.clone(),
+        }
+    }
+
+    fn to_event(&self) -> Option<AcmeEvent> {
+        Some(AcmeEvent {
+            domains: self.domains.clone(),
+            kind: EventKind::from_name(&self.kind)?,
+            order_url: self.order_url.clone(),
+            error_class: self
+                .error_class
+                .as_deref()
+                .and_then(|class| ERROR_CLASSES.iter().find(|known| **known == class).copied()),
+            error: self.error.clone(),
+            failure: self.failure.clone(),
+            hint: self.problem_type.as_deref().and_then(problem_hint),
+            problem_type: self.problem_type.clone(),
+            problem: self.problem.clone(),
+            challenge_failure: self.challenge_failure.clone(),
+            at: self.at,
+            acceptor: self.acceptor.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resume(domain: &str, origin: CertOrigin) -> CertResume {
+        CertResume {
+            domains: vec![domain.into()],
+            origin,
+            not_after: None,
+            renew_at: Some(SystemTime::UNIX_EPOCH),
+            backoff_cnt: 2,
+            failure_cnt: 1,
+            attempts: vec![],
+            staged: false,
+            domain_backoff: vec![],
+            order_url: Some("https://ca.example/order/1".into()),
+            failed_orders: 1,
+            last_error: None,
+            last_failure: None,
+        }
+    }
+
+    fn event(kind: EventKind) -> AcmeEvent {
+        AcmeEvent {
+            domains: vec!["a.example".into()],
+            kind,
+            order_url: None,
+            error_class: Some("acme"),
+            error: Some("failed".into()),
+            failure: Some(Error::Order("failed".into())),
+            problem_type: Some("urn:ietf:params:acme:error:rateLimited".into()),
+            hint: None,
+            problem: Problem::parse(
+                r#"{"type": "urn:ietf:params:acme:error:rateLimited", "detail": "too many"}"#,
+            ),
+            challenge_failure: None,
+            at: SystemTime::UNIX_EPOCH,
+            acceptor: None,
+        }
+    }
+
+    #[test]
+    fn saves_and_loads_snapshots() {
+        let dir = std::env::temp_dir().join(format!("tide-acme-snapshot-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("snapshot.json");
+        assert!(Snapshot::load(&path).unwrap().is_none());
+        let snapshot = Snapshot {
+            certs: vec![resume("a.example", CertOrigin::Configured)],
+            events: vec![SavedEvent::new(&event(EventKind::OrderFailed))],
+        };
+        snapshot.save(&path).unwrap();
+        let loaded = Snapshot::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.certs[0].domains, ["a.example"]);
+        assert_eq!(loaded.certs[0].backoff_cnt, 2);
+        assert_eq!(loaded.certs[0].renew_at, Some(SystemTime::UNIX_EPOCH));
+        assert_eq!(loaded.events.len(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lists_runtime_domains() {
+        let snapshot = Snapshot {
+            certs: vec![
+                resume("a.example", CertOrigin::Configured),
+                resume("b.example", CertOrigin::OnDemand),
+                resume("c.example", CertOrigin::Added),
+            ],
+            events: vec![],
+        };
+        assert_eq!(
+            snapshot.runtime_domains(),
+            [
+                ("b.example".to_string(), CertOrigin::OnDemand),
+                ("c.example".to_string(), CertOrigin::Added),
+            ]
+        );
+    }
+
+    #[test]
+    fn restores_the_history() {
+        let mut unknown = SavedEvent::new(&event(EventKind::OrderFailed));
+        unknown.kind = "from_a_later_version".into();
+        let snapshot = Snapshot {
+            certs: vec![],
+            events: vec![SavedEvent::new(&event(EventKind::OrderFailed)), unknown],
+        };
+        let history = snapshot.history();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].kind, EventKind::OrderFailed);
+        assert_eq!(history[0].error_class, Some("acme"));
+        assert_eq!(history[0].error.as_deref(), Some("failed"));
+        assert!(history[0].hint.is_some());
+        let problem = history[0].problem.as_ref().unwrap();
+        assert_eq!(problem.detail.as_deref(), Some("too many"));
+        assert!(matches!(&history[0].failure, Some(Error::Order(err)) if err == "failed"));
+        // Saved before problems and failures were.
+        let mut old =
+            serde_json::to_value(SavedEvent::new(&event(EventKind::OrderFailed))).unwrap();
+        old.as_object_mut().unwrap().remove("problem");
+        old.as_object_mut().unwrap().remove("failure");
+        let old = serde_json::from_value::<SavedEvent>(old)
+            .unwrap()
+            .to_event()
+            .unwrap();
+        assert!(old.problem.is_none() && old.failure.is_none());
+    }
+}
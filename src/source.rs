@@ -0,0 +1,119 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use zeroize::Zeroizing;
+
+/// Error from a [`CertificateSource`].
+pub type CertificateSourceError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Supplies certificates from somewhere other than an ACME directory, such as Tailscale's
+/// certificate API, an internal PKI issuance endpoint, or a secrets manager.
+///
+/// Add sources with [`AcmeConfig::certificate_source`](crate::AcmeConfig::certificate_source).
+/// Their certificates are managed like ACME certificates: checked, cached, served by SNI, and
+/// fetched again when the [renewal policy](crate::AcmeConfig::renewal_policy) says, or with
+/// backoff after a failure.
+#[async_trait]
+pub trait CertificateSource: Send + Sync + 'static {
+    /// A name identifying the source, such as a URL. It stands in for the ACME directory URL in
+    /// cache keys, keeping the source's certificates apart from others in a shared cache.
+    fn name(&self) -> &str;
+
+    /// Obtain a certificate covering `domains`, as PEM: the PKCS#8 private key, followed by the
+    /// certificate chain, leaf first.
+    async fn fetch(&self, domains: &[String]) -> Result<Vec<u8>, CertificateSourceError>;
+}
+
+/// A fixed certificate, such as one for an internal name signed by a corporate CA, to serve by
+/// SNI alongside the ACME certificates.
+///
+/// Add it with [`AcmeConfig::static_certificate`](crate::AcmeConfig::static_certificate). It is
+/// checked and served like other certificates, and read again when the
+/// [renewal policy](crate::AcmeConfig::renewal_policy) says, so replacing the files before then
+/// rotates it; an expired certificate is reported as a failure.
+#[derive(Clone, Debug)]
+pub struct StaticCertificate {
+    name: String,
+    pem: StaticPem,
+}
+
+#[derive(Clone, Debug)]
+enum StaticPem {
+    Memory(Zeroizing<Vec<u8>>),
+    Files { key: PathBuf, chain: PathBuf },
+}
+
+impl StaticCertificate {
+    /// Use a certificate given as PEM: the PKCS#8 private key, followed by the certificate chain,
+    /// leaf first.
+    pub fn new(pem: impl Into<Vec<u8>>) -> Self {
+        Self {
+            name: "static".into(),
+            pem: StaticPem::Memory(Zeroizing::new(pem.into())),
+        }
+    }
+
+    /// Use a certificate read from PEM files: the PKCS#8 private key at `key`, and the
+    /// certificate chain, leaf first, at `chain`.
+    pub fn from_files(key: impl Into<PathBuf>, chain: impl Into<PathBuf>) -> Self {
+        let chain = chain.into();
+        Self {
+            name: format!("file://{}", chain.display()),
+            pem: StaticPem::Files {
+                key: key.into(),
+                chain,
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl CertificateSource for StaticCertificate {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn fetch(&self, _domains: &[String]) -> Result<Vec<u8>, CertificateSourceError> {
+        match &self.pem {
+            StaticPem::Memory(pem) => Ok(pem.to_vec()),
+            StaticPem::Files { key, chain } => {
+                let key = Zeroizing::new(async_std::fs::read(key).await?);
+                let chain = async_std::fs::read(chain).await?;
+                // Sized up front, so that growing it leaves no copies of the key behind.
+                let mut pem = Vec::with_capacity(key.len() + 1 + chain.len());
+                pem.extend_from_slice(&key);
+                pem.push(b'\n');
+                pem.extend_from_slice(&chain);
+                Ok(pem)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::block_on;
+
+    use super::*;
+
+    #[test]
+    fn reads_static_certificates() {
+        let memory = StaticCertificate::new("key and chain");
+        assert_eq!(memory.name(), "static");
+        assert_eq!(block_on(memory.fetch(&[])).unwrap(), b"key and chain");
+
+        let dir = std::env::temp_dir().join(format!("tide-acme-static-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("key.pem"), "key").unwrap();
+        std::fs::write(dir.join("chain.pem"), "chain").unwrap();
+        let files = StaticCertificate::from_files(dir.join("key.pem"), dir.join("chain.pem"));
+        assert_eq!(
+            files.name(),
+            format!("file://{}", dir.join("chain.pem").display())
+        );
+        assert_eq!(block_on(files.fetch(&[])).unwrap(), b"key\nchain");
+        std::fs::remove_file(dir.join("key.pem")).unwrap();
+        assert!(block_on(files.fetch(&[])).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,521 @@
+//! A cache in a SQL database, for teams that already run one.
+
+use std::error::Error;
+use std::io;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_lock::Mutex;
+use async_trait::async_trait;
+use rustls_acme::{AccountCache, CertCache};
+use sqlx::Row;
+
+use crate::dir_cache::PrivateDirCache;
+
+/// Errors from a [`SqlDatabase`].
+pub type SqlError = Box<dyn Error + Send + Sync>;
+
+/// The SQL dialect a [`SqlDatabase`] speaks, for the placeholders and column types of the
+/// statements [`SqlCache`] sends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SqlDialect {
+    /// PostgreSQL: `$1` placeholders and `BYTEA` columns.
+    Postgres,
+    /// SQLite, version 3.24 or later: `?` placeholders and `BLOB` columns.
+    Sqlite,
+}
+
+/// A parameter of a statement [`SqlCache`] sends.
+#[derive(Clone, Copy, Debug)]
+pub enum SqlParam<'a> {
+    /// A `TEXT` value.
+    Text(&'a str),
+    /// A `BYTEA` or `BLOB` value.
+    Blob(&'a [u8]),
+    /// A `BIGINT` value.
+    Int(i64),
+}
+
+/// A connection pool that [`SqlCache`] sends its statements to.
+///
+/// It's implemented for the `sqlx` pools [`PgPool`](sqlx::PgPool) and
+/// [`SqlitePool`](sqlx::SqlitePool); implement it to use another database client.
+#[async_trait]
+pub trait SqlDatabase: Send + Sync + 'static {
+    /// The dialect of the database.
+    fn dialect(&self) -> SqlDialect;
+
+    /// Execute a statement, binding `params` to its placeholders in order.
+    async fn execute(&self, sql: &str, params: &[SqlParam<'_>]) -> Result<(), SqlError>;
+
+    /// Run a query returning at most one row, binding `params` to its placeholders in order, and
+    /// return the first column of the row, a `BYTEA` or `BLOB`, if there is one.
+    async fn fetch_blob(
+        &self,
+        sql: &str,
+        params: &[SqlParam<'_>],
+    ) -> Result<Option<Vec<u8>>, SqlError>;
+
+    /// Run a query returning one row with a nullable `BIGINT` column, and return it.
+    async fn fetch_int(&self, sql: &str) -> Result<Option<i64>, SqlError>;
+}
+
+/// Implement [`SqlDatabase`] for an `sqlx` pool of database `$db`, speaking `$dialect`.
+macro_rules! sqlx_database {
+    ($db:ty, $dialect:expr) => {
+        #[async_trait]
+        impl SqlDatabase for sqlx::Pool<$db> {
+            fn dialect(&self) -> SqlDialect {
+                $dialect
+            }
+
+            async fn execute(&self, sql: &str, params: &[SqlParam<'_>]) -> Result<(), SqlError> {
+                let mut query = sqlx::query(sql);
+                for param in params {
+                    query = match *param {
+                        SqlParam::Text(text) => query.bind(text),
+                        SqlParam::Blob(blob) => query.bind(blob),
+                        SqlParam::Int(int) => query.bind(int),
+                    };
+                }
+                query.execute(self).await?;
+                Ok(())
+            }
+
+            async fn fetch_blob(
+                &self,
+                sql: &str,
+                params: &[SqlParam<'_>],
+            ) -> Result<Option<Vec<u8>>, SqlError> {
+                let mut query = sqlx::query(sql);
+                for param in params {
+                    query = match *param {
+                        SqlParam::Text(text) => query.bind(text),
+                        SqlParam::Blob(blob) => query.bind(blob),
+                        SqlParam::Int(int) => query.bind(int),
+                    };
+                }
+                let row = query.fetch_optional(self).await?;
+                Ok(row.map(|row| row.try_get(0)).transpose()?)
+            }
+
+            async fn fetch_int(&self, sql: &str) -> Result<Option<i64>, SqlError> {
+                Ok(sqlx::query_scalar(sql).fetch_one(self).await?)
+            }
+        }
+    };
+}
+
+sqlx_database!(sqlx::Postgres, SqlDialect::Postgres);
+sqlx_database!(sqlx::Sqlite, SqlDialect::Sqlite);
+
+/// The statements creating and updating the schema, in order; the schema's version is the number
+/// applied. `{table}` is replaced with the table name and `{blob}` with the dialect's type of
+/// binary columns.
+const MIGRATIONS: &[&str] = &["CREATE TABLE {table} (
+    kind TEXT NOT NULL,
+    name TEXT NOT NULL,
+    value {blob} NOT NULL,
+    updated_at BIGINT NOT NULL,
+    PRIMARY KEY (kind, name)
+)"];
+
+/// Cache for certificates and account keys in a table of a SQL database, such as PostgreSQL or
+/// SQLite, so that teams already running one don't need another storage system for ACME state,
+/// and instances sharing the database share the account and certificates. Requires the `sql`
+/// feature.
+///
+/// The cache sends its statements through a [`SqlDatabase`], implemented for the application's
+/// database client. On first use, it creates the table, `tide_acme_cache` by default, and a
+/// `<table>_schema` table recording the schema version, and migrates the schema when a later
+/// version of tide-acme changes it. Entries are keyed by kind (`cert` or `account`) and a name
+/// derived like the file names of a [`PrivateDirCache`], and record when they were last updated.
+///
+/// The table holds private keys, so restrict access to it.
+///
+/// ```no_run
+/// use tide_acme::{AcmeConfig, SqlCache};
+///
+/// # async_std::task::block_on(async {
+/// let pool = sqlx::PgPool::connect("postgres://localhost/app").await?;
+/// let config = AcmeConfig::new(vec!["domain.example"]).cache(SqlCache::new(pool).table("acme_state"));
+/// # Ok::<_, sqlx::Error>(())
+/// # });
+/// ```
+#[derive(Clone)]
+pub struct SqlCache {
+    database: Arc<dyn SqlDatabase>,
+    table: String,
+    migrated: Arc<Mutex<bool>>,
+}
+
+impl std::fmt::Debug for SqlCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SqlCache")
+            .field("dialect", &self.database.dialect())
+            .field("table", &self.table)
+            .finish()
+    }
+}
+
+impl SqlCache {
+    /// Create a cache in `database`.
+    pub fn new(database: impl SqlDatabase) -> Self {
+        Self {
+            database: Arc::new(database),
+            table: "tide_acme_cache".into(),
+            migrated: Arc::default(),
+        }
+    }
+
+    /// Keep the entries in the table `table` instead of `tide_acme_cache`. The name is used in
+    /// statements as is, so it must be a valid identifier, optionally qualified with a schema.
+    pub fn table(mut self, table: impl Into<String>) -> Self {
+        self.table = table.into();
+        self
+    }
+
+    /// Create or migrate the schema, if not done yet by this process.
+    async fn migrate(&self) -> Result<(), SqlError> {
+        let mut migrated = self.migrated.lock().await;
+        if *migrated {
+            return Ok(());
+        }
+        let schema = format!("{}_schema", self.table);
+        self.database
+            .execute(
+                &format!(
+                    "CREATE TABLE IF NOT EXISTS {} (version BIGINT NOT NULL)",
+                    schema
+                ),
+                &[],
+            )
+            .await?;
+        let version = self
+            .database
+            .fetch_int(&format!("SELECT MAX(version) FROM {}", schema))
+            .await?
+            .unwrap_or(0);
+        if version > MIGRATIONS.len() as i64 {
+            return Err(format!(
+                "cache table {} has schema version {}, newer than the supported version {}",
+                self.table,
+                version,
+                MIGRATIONS.len()
+            )
+            .into());
+        }
+        let blob = match self.database.dialect() {
+            SqlDialect::Postgres => "BYTEA",
+            SqlDialect::Sqlite => "BLOB",
+        };
+        for (applied, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            let sql = migration
+                .replace("{table}", &self.table)
+                .replace("{blob}", blob);
+            self.database.execute(&sql, &[]).await?;
+            self.database
+                .execute(
+                    &format!(
+                        "INSERT INTO {} (version) VALUES ({})",
+                        schema,
+                        self.placeholder(1)
+                    ),
+                    &[SqlParam::Int(applied as i64 + 1)],
+                )
+                .await?;
+        }
+        *migrated = true;
+        Ok(())
+    }
+
+    fn placeholder(&self, n: usize) -> String {
+        match self.database.dialect() {
+            SqlDialect::Postgres => format!("${}", n),
+            SqlDialect::Sqlite => "?".into(),
+        }
+    }
+
+    async fn load(&self, kind: &str, name: String) -> io::Result<Option<Vec<u8>>> {
+        self.migrate().await.map_err(io::Error::other)?;
+        let sql = format!(
+            "SELECT value FROM {} WHERE kind = {} AND name = {}",
+            self.table,
+            self.placeholder(1),
+            self.placeholder(2)
+        );
+        self.database
+            .fetch_blob(&sql, &[SqlParam::Text(kind), SqlParam::Text(&name)])
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn store(&self, kind: &str, name: String, value: &[u8]) -> io::Result<()> {
+        self.migrate().await.map_err(io::Error::other)?;
+        let sql = format!(
+            "INSERT INTO {} (kind, name, value, updated_at) VALUES ({}, {}, {}, {}) \
+             ON CONFLICT (kind, name) DO UPDATE SET value = excluded.value, \
+             updated_at = excluded.updated_at",
+            self.table,
+            self.placeholder(1),
+            self.placeholder(2),
+            self.placeholder(3),
+            self.placeholder(4)
+        );
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let params = [
+            SqlParam::Text(kind),
+            SqlParam::Text(&name),
+            SqlParam::Blob(value),
+            SqlParam::Int(now),
+        ];
+        self.database
+            .execute(&sql, &params)
+            .await
+            .map_err(io::Error::other)
+    }
+}
+
+#[async_trait]
+impl CertCache for SqlCache {
+    type EC = io::Error;
+
+    async fn load_cert(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+    ) -> Result<Option<Vec<u8>>, Self::EC> {
+        let name = PrivateDirCache::file_name("cached_cert", domains, directory_url);
+        self.load("cert", name).await
+    }
+
+    async fn store_cert(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+        cert: &[u8],
+    ) -> Result<(), Self::EC> {
+        let name = PrivateDirCache::file_name("cached_cert", domains, directory_url);
+        self.store("cert", name, cert).await
+    }
+}
+
+#[async_trait]
+impl AccountCache for SqlCache {
+    type EA = io::Error;
+
+    async fn load_account(
+        &self,
+        contact: &[String],
+        directory_url: &str,
+    ) -> Result<Option<Vec<u8>>, Self::EA> {
+        let name = PrivateDirCache::file_name("cached_account", contact, directory_url);
+        self.load("account", name).await
+    }
+
+    async fn store_account(
+        &self,
+        contact: &[String],
+        directory_url: &str,
+        account: &[u8],
+    ) -> Result<(), Self::EA> {
+        let name = PrivateDirCache::file_name("cached_account", contact, directory_url);
+        self.store("account", name, account).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use async_std::task::block_on;
+
+    use super::*;
+
+    type Values = HashMap<(String, String), Vec<u8>>;
+
+    /// A database understanding just the statements the cache runs, recording them.
+    #[derive(Clone)]
+    struct FakeDatabase {
+        dialect: SqlDialect,
+        version: Arc<std::sync::Mutex<Option<i64>>>,
+        values: Arc<std::sync::Mutex<Values>>,
+        statements: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl FakeDatabase {
+        fn new(dialect: SqlDialect, version: Option<i64>) -> Self {
+            Self {
+                dialect,
+                version: Arc::new(std::sync::Mutex::new(version)),
+                values: Arc::default(),
+                statements: Arc::default(),
+            }
+        }
+    }
+
+    fn text(param: &SqlParam<'_>) -> String {
+        match param {
+            SqlParam::Text(text) => text.to_string(),
+            _ => panic!("expected a text parameter"),
+        }
+    }
+
+    #[async_trait]
+    impl SqlDatabase for FakeDatabase {
+        fn dialect(&self) -> SqlDialect {
+            self.dialect
+        }
+
+        async fn execute(&self, sql: &str, params: &[SqlParam<'_>]) -> Result<(), SqlError> {
+            self.statements.lock().unwrap().push(sql.into());
+            match params {
+                [SqlParam::Int(version)] => *self.version.lock().unwrap() = Some(*version),
+                [kind, name, SqlParam::Blob(value), _] => {
+                    let key = (text(kind), text(name));
+                    self.values.lock().unwrap().insert(key, value.to_vec());
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+
+        async fn fetch_blob(
+            &self,
+            sql: &str,
+            params: &[SqlParam<'_>],
+        ) -> Result<Option<Vec<u8>>, SqlError> {
+            self.statements.lock().unwrap().push(sql.into());
+            let key = (text(&params[0]), text(&params[1]));
+            Ok(self.values.lock().unwrap().get(&key).cloned())
+        }
+
+        async fn fetch_int(&self, sql: &str) -> Result<Option<i64>, SqlError> {
+            self.statements.lock().unwrap().push(sql.into());
+            Ok(*self.version.lock().unwrap())
+        }
+    }
+
+    #[test]
+    fn migrates_once_and_stores_values() {
+        let database = FakeDatabase::new(SqlDialect::Postgres, None);
+        let cache = SqlCache::new(database.clone()).table("certs");
+        let domains = ["a.example".to_string()];
+        let directory = "https://acme.test/directory";
+        block_on(async {
+            assert_eq!(cache.load_cert(&domains, directory).await.unwrap(), None);
+            cache
+                .store_cert(&domains, directory, b"cert")
+                .await
+                .unwrap();
+            cache
+                .store_account(&domains, directory, b"account")
+                .await
+                .unwrap();
+            let cert = cache.load_cert(&domains, directory).await.unwrap();
+            assert_eq!(cert.as_deref(), Some(&b"cert"[..]));
+            let account = cache.load_account(&domains, directory).await.unwrap();
+            assert_eq!(account.as_deref(), Some(&b"account"[..]));
+        });
+        assert_eq!(*database.version.lock().unwrap(), Some(1));
+        let statements = database.statements.lock().unwrap();
+        assert!(statements[2].starts_with("CREATE TABLE certs ("));
+        assert!(statements[2].contains("value BYTEA NOT NULL"));
+        assert_eq!(
+            statements[3],
+            "INSERT INTO certs_schema (version) VALUES ($1)"
+        );
+        assert_eq!(
+            statements[4],
+            "SELECT value FROM certs WHERE kind = $1 AND name = $2"
+        );
+        let creates = statements.iter().filter(|sql| sql.starts_with("CREATE"));
+        assert_eq!(creates.count(), 2);
+    }
+
+    #[test]
+    fn uses_the_dialect_of_the_database() {
+        let database = FakeDatabase::new(SqlDialect::Sqlite, Some(1));
+        let cache = SqlCache::new(database.clone());
+        block_on(cache.store_cert(&[], "https://acme.test/directory", b"cert")).unwrap();
+        let statements = database.statements.lock().unwrap();
+        // Already at the latest version, so no migrations run.
+        assert_eq!(statements.len(), 3);
+        assert!(statements[2].starts_with(
+            "INSERT INTO tide_acme_cache (kind, name, value, updated_at) VALUES (?, ?, ?, ?)"
+        ));
+    }
+
+    #[test]
+    fn refuses_newer_schemas() {
+        let cache = SqlCache::new(FakeDatabase::new(SqlDialect::Sqlite, Some(2)));
+        let err = block_on(cache.load_cert(&[], "https://acme.test/directory")).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("newer than the supported version 1"));
+    }
+
+    async fn cache() -> SqlCache {
+        // Each connection to an in-memory database has a database of its own, so keep to one.
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        SqlCache::new(pool)
+    }
+
+    #[test]
+    fn stores_and_replaces_entries() {
+        block_on(async {
+            let cache = cache().await;
+            let domains = ["domain.example".to_string()];
+            let directory = "https://acme.example/directory";
+            assert_eq!(cache.load_cert(&domains, directory).await.unwrap(), None);
+            cache
+                .store_cert(&domains, directory, b"first")
+                .await
+                .unwrap();
+            cache
+                .store_cert(&domains, directory, b"second")
+                .await
+                .unwrap();
+            let cert = cache.load_cert(&domains, directory).await.unwrap();
+            assert_eq!(cert.as_deref(), Some(&b"second"[..]));
+            assert_eq!(cache.load_account(&domains, directory).await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn migrates_once() {
+        block_on(async {
+            let cache = cache().await;
+            cache.migrate().await.unwrap();
+            *cache.migrated.lock().await = false;
+            cache.migrate().await.unwrap();
+            let version = cache
+                .database
+                .fetch_int("SELECT MAX(version) FROM tide_acme_cache_schema")
+                .await
+                .unwrap();
+            assert_eq!(version, Some(MIGRATIONS.len() as i64));
+        });
+    }
+
+    #[test]
+    fn rejects_newer_schema() {
+        block_on(async {
+            let cache = cache().await;
+            cache.migrate().await.unwrap();
+            let sql = "INSERT INTO tide_acme_cache_schema (version) VALUES (?)";
+            let params = [SqlParam::Int(MIGRATIONS.len() as i64 + 1)];
+            cache.database.execute(sql, &params).await.unwrap();
+            *cache.migrated.lock().await = false;
+            assert!(cache.migrate().await.is_err());
+        });
+    }
+}
@@ -0,0 +1,4955 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Debug;
+use std::future::Future;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use async_lock::{Mutex, Semaphore};
+use async_std::task;
+use futures::channel::mpsc::{unbounded, Receiver, UnboundedReceiver};
+use futures::future::{select, try_join_all, Either};
+use futures::{FutureExt, StreamExt};
+use rcgen::{CertificateParams, RcgenError, SignatureAlgorithm};
+use ring::signature::{
+    EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING,
+    ECDSA_P384_SHA384_ASN1_SIGNING,
+};
+use rustls_acme::Cache;
+use thiserror::Error;
+use tide_rustls::rustls::sign::{any_supported_type, CertifiedKey};
+use tide_rustls::rustls::{Certificate, KeyLogFile, PrivateKey};
+use tracing::{error, info, warn, Level, Span};
+use x509_parser::extensions::GeneralName;
+use x509_parser::parse_x509_certificate;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::acme::{
+    poll_delay, Account, AcmeError, Auth, Challenge, Directory, Identifier, Order,
+    RevocationReason, LETS_ENCRYPT_PRODUCTION_DIRECTORY, LETS_ENCRYPT_STAGING_DIRECTORY,
+};
+use crate::challenge::{self_signed_cert, Http01Responder, TlsAlpn01Responder};
+use crate::config::{ChallengeClose, ClientHelloInspector, ConfigureRustls, HandshakeFilter};
+use crate::delegation::{shared_cache, SharedChallenges, ORDERER_POLL_INTERVAL};
+use crate::export;
+use crate::format::{self, EntryKind, FormatError};
+use crate::handle::{AcmeHandle, CertMaterial, CertRotation, Command, Registry};
+#[cfg(unix)]
+use crate::handover::{self, HandoverCert};
+use crate::https::HttpsRequestError;
+use crate::ip;
+use crate::jose::{AccountKey, ExternalAccountKey};
+use crate::key_store::{self, RemoteKeys};
+use crate::ocsp::{self, OcspError, OCSP_RETRY};
+use crate::order_lock::LockGuard;
+use crate::preflight::{self, PreflightError};
+use crate::problem::{ChallengeFailure, Problem};
+use crate::promote::is_staging_directory;
+use crate::rate_limit::RateLimits;
+use crate::resolver::{AcmeResolver, OnDemandQueue};
+use crate::snapshot::{CertOrigin, CertResume};
+use crate::summary::GroupSummary;
+use crate::tickets::{Resumption, TicketKeySync};
+use crate::{
+    AcmeConfig, AcmeEvent, CertificateSource, CertificateSourceError, ChallengeDelegation,
+    ChallengeError, ChallengeResponder, Clock, ConfigSummary, Csr, EventKind, KeyProviderError,
+    KeyStore, KeyStoreError, ListenerOptions, RemoteKey, RenewalPolicy, StaleOcspPolicy,
+};
+
+/// Certificate management shared by the per-certificate state machines.
+pub(crate) struct AcmeState<EC: Debug, EA: Debug> {
+    config: AcmeConfig<EC, EA>,
+    resolver: Arc<AcmeResolver>,
+    responders: Vec<Arc<dyn ChallengeResponder>>,
+    /// The directories, with those replacing the main directory on configuration reloads
+    /// appended.
+    directories: std::sync::RwLock<Vec<Arc<DirectoryState<EC, EA>>>>,
+    /// The index of the main directory in `directories`.
+    main_directory: AtomicUsize,
+    /// The contact URLs of the main account, as last reloaded.
+    contact: std::sync::RwLock<Vec<String>>,
+    /// The staging directory for checks before production orders, if configured.
+    staging: Option<DirectoryState<EC, EA>>,
+    orders: Semaphore,
+    registry: Arc<Registry>,
+    /// Certificates handed over by the process that started this one, not yet deployed.
+    #[cfg(unix)]
+    handed_over: std::sync::Mutex<Vec<HandoverCert>>,
+    /// Server names to obtain certificates for on demand, from the resolver, until taken by the
+    /// task handling them.
+    on_demand_requests: std::sync::Mutex<Option<Receiver<String>>>,
+    /// Domains added with [`AcmeHandle::add_domain`], until taken by the task handling them.
+    added_domains: std::sync::Mutex<Option<UnboundedReceiver<String>>>,
+    /// Configurations passed to [`AcmeHandle::reload_config`], until taken by the task applying
+    /// them.
+    config_reloads: std::sync::Mutex<Option<UnboundedReceiver<ConfigReload>>>,
+    /// The challenges shared with other instances, if delegating them.
+    shared_challenges: Option<SharedChallenges>,
+    /// The cache to share session ticket keys through, with the ticketer using them, until
+    /// taken by the task syncing them.
+    ticket_key_sync: std::sync::Mutex<Option<TicketKeySync>>,
+    /// The state of certificates restored from a snapshot, until taken by their state machines.
+    restored: std::sync::Mutex<Vec<CertResume>>,
+    /// Domains obtained on demand or added at runtime before the snapshot was taken, until taken
+    /// by the task requesting them again.
+    restored_domains: std::sync::Mutex<Vec<(String, CertOrigin)>>,
+    /// The domains of revoked certificates, until replaced, so their keys are not reused.
+    revoked: std::sync::Mutex<HashSet<Vec<String>>>,
+    /// The keys of the key store in use, so certificates kept without their keys can be parsed.
+    remote_keys: RemoteKeys,
+}
+
+/// An ACME directory certificates are obtained from, with its account, or a certificate source.
+/// The first is the main directory, followed by those of the configured directory groups.
+struct DirectoryState<EC: Debug, EA: Debug> {
+    /// The directory URL, or the source's name.
+    url: String,
+    source: Option<Arc<dyn CertificateSource>>,
+    domains: Vec<String>,
+    /// The directory's own cache, or `None` to use the main one.
+    cache: Option<Box<dyn Cache<EC = EC, EA = EA>>>,
+    /// Whether the domains share one certificate, rather than being split like the main ones.
+    single_cert: bool,
+    /// The contact URLs of the directory's account, or `None` to use the main ones.
+    contact: Option<Vec<String>>,
+    /// The key binding new accounts to an account with the CA, if it requires one.
+    external_account_key: Option<ExternalAccountKey>,
+    /// The account, shared with the directory replacing this one if the directory URL is
+    /// unchanged.
+    account: Arc<Mutex<AccountState>>,
+    /// Whether this is the main directory, or one that replaced it on a configuration reload.
+    main: bool,
+}
+
+/// The settings of a configuration passed to [`AcmeHandle::reload_config`] that are applied,
+/// or `None` to keep the current ones.
+#[derive(Debug, Default)]
+pub(crate) struct ConfigReload {
+    pub(crate) domains: Option<Vec<String>>,
+    pub(crate) contact: Option<Vec<String>>,
+    pub(crate) directory_url: Option<String>,
+}
+
+#[derive(Default)]
+struct AccountState {
+    loaded: bool,
+    key: Option<Zeroizing<Vec<u8>>>,
+}
+
+/// State machine driving management of one certificate, one event at a time.
+pub(crate) struct CertState<EC: Debug, EA: Debug> {
+    state: Arc<AcmeState<EC, EA>>,
+    index: usize,
+    directory: usize,
+    domains: Vec<String>,
+    load_cert: bool,
+    store_cert: Option<Zeroizing<Vec<u8>>>,
+    store_previous: bool,
+    /// Whether an order registered a new account with the CA, to report before going on.
+    account_registered: bool,
+    quarantine_cert: Option<Zeroizing<Vec<u8>>>,
+    current: Option<Zeroizing<Vec<u8>>>,
+    /// The end of the validity period of the certificate being served, if any.
+    not_after: Option<SystemTime>,
+    previous: Vec<Zeroizing<Vec<u8>>>,
+    commands: UnboundedReceiver<Command>,
+    order_url: Option<String>,
+    /// When the next order is scheduled, by the wall clock.
+    renew_at: Option<SystemTime>,
+    /// When to fetch an OCSP response for the certificate being served, if stapling them.
+    ocsp_refresh_at: Option<SystemTime>,
+    /// When the stapled OCSP response expires, or its stale window ends, by the configured clock.
+    ocsp_expires_at: Option<SystemTime>,
+    /// Whether the stapled OCSP response expired and is still stapled within its stale window.
+    ocsp_stale: bool,
+    backoff_cnt: u32,
+    /// When recent orders were placed, for the retry policy's cap on attempts.
+    attempts: VecDeque<SystemTime>,
+    /// Whether the staging check before the first production order passed.
+    staged: bool,
+    /// The order lock, held from ordering a certificate until it is stored.
+    order_guard: Option<LockGuard>,
+    /// Consecutive failures of the most recent order, counted for the whole certificate or for
+    /// the domain the order failed on.
+    failure_cnt: u32,
+    domain_backoff: HashMap<String, DomainBackoff>,
+    /// Whether the certificate was removed from management, so its task should end.
+    removed: bool,
+    /// The certificate just deployed, until passed to the rotation hook.
+    rotation: Option<CertRotation>,
+    origin: CertOrigin,
+    /// The state restored from a snapshot, applied once the cached certificate is loaded.
+    restored: Option<CertResume>,
+}
+
+/// Backoff of a domain left out of orders after failing validation.
+struct DomainBackoff {
+    failures: u32,
+    until: SystemTime,
+}
+
+pub(crate) type Event<EC, EA> = Result<EventOk, EventError<EC, EA>>;
+
+#[derive(Debug)]
+pub(crate) enum EventOk {
+    DeployedCachedCert,
+    DeployedNewCert,
+    DeployedPreviousCert,
+    NoPreviousCert,
+    RenewalRequested,
+    StagingCheckPassed,
+    CertCacheStore,
+    AccountCacheStore,
+    CertCacheQuarantine,
+    AccountCacheQuarantine,
+    CacheUnchanged,
+    CacheReencrypted,
+    DomainRemoved,
+    CertRemoved,
+    AccountRegistered,
+    CertCacheStoreSecondary,
+    AccountCacheStoreSecondary,
+    OrderDeferred,
+    AwaitingOrderer,
+    CertRevoked,
+    NoCertToRevoke,
+    OcspStapled,
+    OcspStaleServed,
+    OcspStapleDropped,
+    OcspCertWithheld,
+}
+
+/// Which cache took a store.
+enum Stored {
+    Main,
+    Secondary,
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum EventError<EC: Debug, EA: Debug> {
+    #[error("cert cache load: {0:?}")]
+    CertCacheLoad(EC),
+    #[error("account cache load: {0:?}")]
+    AccountCacheLoad(EA),
+    #[error("cert cache store: {0:?}")]
+    CertCacheStore(EC),
+    #[error("account cache store: {0:?}")]
+    AccountCacheStore(EA),
+    #[error("cached cert parse: {0}")]
+    CachedCertParse(CertParseError),
+    #[error("order: {0}")]
+    Order(OrderError),
+    #[error("staging check order: {0}")]
+    StagingCheck(OrderError),
+    #[error("new cert parse: {0}")]
+    NewCertParse(CertParseError),
+    #[error("previous cert parse: {0}")]
+    PreviousCertParse(CertParseError),
+    #[error("cached cert format: {0}")]
+    CertCacheFormat(FormatError),
+    #[error("cached account format: {0}")]
+    AccountCacheFormat(FormatError),
+    #[error("revocation: {0}")]
+    Revocation(OrderError),
+    #[error("ocsp: {0}")]
+    Ocsp(OcspError),
+}
+
+impl<EC: Debug, EA: Debug> EventError<EC, EA> {
+    /// A stable name for the cause of the error, for structured logging.
+    pub(crate) fn class(&self) -> &'static str {
+        match self {
+            EventError::CertCacheLoad(_)
+            | EventError::AccountCacheLoad(_)
+            | EventError::CertCacheStore(_)
+            | EventError::AccountCacheStore(_) => "cache",
+            EventError::CertCacheFormat(_) | EventError::AccountCacheFormat(_) => "cache_format",
+            EventError::StagingCheck(_) => "staging_check",
+            EventError::Revocation(_) => "revocation",
+            EventError::Ocsp(_) => "ocsp",
+            EventError::Order(err) => match err {
+                OrderError::Acme(_) => "acme",
+                OrderError::Account(_) => "account",
+                OrderError::Rcgen(_) => "rcgen",
+                OrderError::BadOrder(_) => "bad_order",
+                OrderError::BadAuth(_) => "bad_auth",
+                OrderError::TooManyAttemptsAuth(_) => "too_many_attempts_auth",
+                OrderError::TooManyAttemptsOrder => "too_many_attempts_order",
+                OrderError::Challenge(_) => "challenge",
+                OrderError::Source(_) => "source",
+                OrderError::Lock(_) => "order_lock",
+                OrderError::KeyProvider(_) => "key_provider",
+                OrderError::KeyStore(_) => "key_store",
+                OrderError::Preflight(_) => "preflight",
+                #[cfg(feature = "simulate-failure")]
+                OrderError::Simulated(_) => "simulated",
+            },
+            EventError::CachedCertParse(err)
+            | EventError::NewCertParse(err)
+            | EventError::PreviousCertParse(err) => match err {
+                CertParseError::X509(_) => "x509",
+                CertParseError::Pem(_) => "pem",
+                CertParseError::TooFewPem(_) => "too_few_pem",
+                CertParseError::InvalidPrivateKey => "invalid_private_key",
+                CertParseError::KeyMismatch => "key_mismatch",
+                CertParseError::RemoteKeyMissing => "remote_key_missing",
+                CertParseError::MissingSan(_) => "missing_san",
+                CertParseError::ChainOrder(_) => "chain_order",
+                CertParseError::Expired => "expired",
+                CertParseError::StagingIssuer => "staging_issuer",
+            },
+        }
+    }
+
+    /// The ACME problem the CA reported for a failed order, if any.
+    pub(crate) fn problem(&self) -> Option<Problem> {
+        match self {
+            EventError::Order(err)
+            | EventError::StagingCheck(err)
+            | EventError::Revocation(err) => err.problem(),
+            _ => None,
+        }
+    }
+
+    /// The challenge the CA failed to validate, for a failed order.
+    pub(crate) fn challenge_failure(&self) -> Option<ChallengeFailure> {
+        match self {
+            EventError::Order(err) | EventError::StagingCheck(err) => err.challenge_failure(),
+            _ => None,
+        }
+    }
+
+    /// The cause of the error, for consumers to match on.
+    pub(crate) fn failure(&self) -> crate::Error {
+        match self {
+            EventError::CertCacheLoad(err) | EventError::CertCacheStore(err) => {
+                crate::Error::Cache(format!("{:?}", err))
+            }
+            EventError::AccountCacheLoad(err) | EventError::AccountCacheStore(err) => {
+                crate::Error::Cache(format!("{:?}", err))
+            }
+            EventError::CertCacheFormat(err) | EventError::AccountCacheFormat(err) => {
+                crate::Error::Cache(err.to_string())
+            }
+            EventError::CachedCertParse(err)
+            | EventError::NewCertParse(err)
+            | EventError::PreviousCertParse(err) => crate::Error::Certificate(err.to_string()),
+            EventError::Order(err) | EventError::StagingCheck(err) => err.failure(),
+            EventError::Revocation(err) => crate::Error::Revocation(err.to_string()),
+            EventError::Ocsp(err) => crate::Error::Ocsp(err.to_string()),
+        }
+    }
+}
+
+/// Every name [`EventError::class`] returns, and the class of expiry alarms, to restore the
+/// classes of saved events.
+pub(crate) const ERROR_CLASSES: &[&str] = &[
+    "cache",
+    "cache_format",
+    "staging_check",
+    "revocation",
+    "ocsp",
+    "acme",
+    "account",
+    "rcgen",
+    "bad_order",
+    "bad_auth",
+    "too_many_attempts_auth",
+    "too_many_attempts_order",
+    "challenge",
+    "source",
+    "order_lock",
+    "key_provider",
+    "key_store",
+    "preflight",
+    "simulated",
+    "x509",
+    "pem",
+    "too_few_pem",
+    "invalid_private_key",
+    "key_mismatch",
+    "remote_key_missing",
+    "missing_san",
+    "chain_order",
+    "expired",
+    "staging_issuer",
+    "expiry_alarm",
+];
+
+/// Clock skew relative to the CA beyond which to warn, in seconds.
+const MAX_CLOCK_SKEW_SECS: i64 = 300;
+
+/// The key of the certificate signing request of an order in progress.
+enum CsrKey {
+    /// A private key held here.
+    Local(Box<rcgen::KeyPair>),
+    /// A key in the key store.
+    Remote(Arc<dyn RemoteKey>),
+}
+
+impl CsrKey {
+    /// Load the key kept with an order in progress: a private key, or with a key store, the
+    /// public key of one of its keys.
+    async fn load(key: &format::Pem, store: Option<&dyn KeyStore>) -> Option<Self> {
+        match store {
+            Some(store) if key.tag == key_store::PUBLIC_KEY_TAG => {
+                match store.load_key(&key.contents).await {
+                    Ok(key) => key.map(CsrKey::Remote),
+                    Err(err) => {
+                        warn!(%err, "failed to load the key of the order in progress");
+                        None
+                    }
+                }
+            }
+            Some(_) => None,
+            None => rcgen::KeyPair::from_der(&key.contents)
+                .ok()
+                .map(|key_pair| CsrKey::Local(Box::new(key_pair))),
+        }
+    }
+}
+
+/// Where a certificate to deploy comes from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CertSource {
+    Cache,
+    Order,
+    Previous,
+}
+
+/// The validity period of a certificate.
+#[derive(Clone, Copy, Debug)]
+struct Validity {
+    not_before: SystemTime,
+    not_after: SystemTime,
+}
+
+impl Validity {
+    /// When to renew, as `policy` says. Working from the certificate's own validity period,
+    /// rather than an assumed lifetime or the time it was received, handles short-lived
+    /// certificates and certificates issued by a CA whose clock differs from ours.
+    fn renewal_time(&self, policy: &RenewalPolicy) -> SystemTime {
+        policy.renewal_time(self.not_before, self.not_after)
+    }
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum OrderError {
+    #[error("acme error: {0}")]
+    Acme(#[from] AcmeError),
+    #[error("account error: {0}")]
+    Account(AcmeError),
+    #[error("certificate generation error: {0}")]
+    Rcgen(#[from] RcgenError),
+    #[error("bad order object: {0:?}")]
+    BadOrder(Order),
+    #[error("bad auth object: {0:?}")]
+    BadAuth(Auth),
+    #[error("authorization for {0} failed too many times")]
+    TooManyAttemptsAuth(String),
+    #[error("order still processing after too many attempts")]
+    TooManyAttemptsOrder,
+    #[error("challenge responder error: {0}")]
+    Challenge(ChallengeError),
+    #[error("certificate source error: {0}")]
+    Source(CertificateSourceError),
+    #[error("order lock error: {0}")]
+    Lock(std::io::Error),
+    #[error("key provider error: {0}")]
+    KeyProvider(KeyProviderError),
+    #[error("key store error: {0}")]
+    KeyStore(KeyStoreError),
+    #[error("pre-flight check: {0}")]
+    Preflight(PreflightError),
+    #[cfg(feature = "simulate-failure")]
+    #[error("simulated failure: {0}")]
+    Simulated(String),
+}
+
+impl OrderError {
+    /// The ACME problem the CA reported, either in response to a request or for a failed
+    /// challenge.
+    fn problem(&self) -> Option<Problem> {
+        match self {
+            OrderError::Acme(err) | OrderError::Account(err) => err.problem(),
+            OrderError::BadAuth(Auth::Invalid { challenges, .. }) => challenges
+                .iter()
+                .find_map(|challenge| challenge.error.clone()),
+            _ => None,
+        }
+    }
+
+    /// The challenge the CA failed to validate, with the problem and validation records it
+    /// reported.
+    fn challenge_failure(&self) -> Option<ChallengeFailure> {
+        let (identifier, challenges) = match self {
+            OrderError::BadAuth(Auth::Invalid {
+                identifier,
+                challenges,
+            }) => (identifier, challenges),
+            _ => return None,
+        };
+        let challenge = challenges
+            .iter()
+            .find(|challenge| challenge.error.is_some())?;
+        Some(ChallengeFailure {
+            domain: identifier.as_ref().map(|identifier| match identifier {
+                Identifier::Dns(domain) | Identifier::Ip(domain) => domain.clone(),
+            }),
+            challenge_type: challenge.typ.clone(),
+            problem: challenge.error.clone()?,
+            validation_records: challenge.validation_record.clone(),
+        })
+    }
+
+    /// The cause of the error, for consumers to match on.
+    fn failure(&self) -> crate::Error {
+        match self {
+            OrderError::Acme(err) | OrderError::Account(err) if err.is_io() => {
+                crate::Error::Io(err.to_string())
+            }
+            OrderError::Acme(err) => match err.problem() {
+                Some(problem) => crate::Error::Rejected(problem),
+                None => crate::Error::Order(err.to_string()),
+            },
+            OrderError::Account(err) => crate::Error::Account(err.to_string()),
+            OrderError::BadAuth(_) | OrderError::TooManyAttemptsAuth(_) => {
+                crate::Error::Validation(self.to_string())
+            }
+            OrderError::Challenge(err) => crate::Error::Challenge(err.to_string()),
+            OrderError::Source(err) => crate::Error::Source(err.to_string()),
+            OrderError::Lock(err) => crate::Error::Lock(err.to_string()),
+            OrderError::KeyProvider(err) => crate::Error::KeyProvider(err.to_string()),
+            OrderError::KeyStore(err) => crate::Error::KeyStore(err.to_string()),
+            OrderError::Preflight(err) => crate::Error::Preflight(err.to_string()),
+            OrderError::Rcgen(_) | OrderError::BadOrder(_) | OrderError::TooManyAttemptsOrder => {
+                crate::Error::Order(self.to_string())
+            }
+            #[cfg(feature = "simulate-failure")]
+            OrderError::Simulated(_) => crate::Error::Order(self.to_string()),
+        }
+    }
+
+    /// When the CA asked to be retried, if it did.
+    fn retry_after(&self) -> Option<SystemTime> {
+        match self {
+            OrderError::Acme(AcmeError::HttpRequest(HttpsRequestError::Non2xxStatus {
+                retry_after,
+                ..
+            }))
+            | OrderError::Account(AcmeError::HttpRequest(HttpsRequestError::Non2xxStatus {
+                retry_after,
+                ..
+            })) => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum CertParseError {
+    #[error("X509 parsing error: {0}")]
+    X509(#[from] x509_parser::nom::Err<x509_parser::error::X509Error>),
+    #[error("PEM parsing error: {0}")]
+    Pem(#[from] pem::PemError),
+    #[error("expected 2 or more pem, got: {0}")]
+    TooFewPem(usize),
+    #[error("unsupported private key type")]
+    InvalidPrivateKey,
+    #[error("private key does not match the certificate")]
+    KeyMismatch,
+    #[error("no key in the key store matches the certificate")]
+    RemoteKeyMissing,
+    #[error("certificate does not cover {0}")]
+    MissingSan(String),
+    #[error("certificate {0} in the chain is not issued by the next one")]
+    ChainOrder(usize),
+    #[error("certificate has already expired")]
+    Expired,
+    #[error("certificate was issued by a staging CA, but the directory is not a staging one")]
+    StagingIssuer,
+}
+
+/// Whether the certificate `leaf` was issued by a staging CA, such as Let's Encrypt's, whose
+/// intermediates are named `(STAGING) ...`.
+fn staging_issued(leaf: &[u8]) -> bool {
+    match parse_x509_certificate(leaf) {
+        Ok((_, cert)) => cert.issuer().iter_common_name().any(|name| {
+            name.as_str()
+                .is_ok_and(|name| name.starts_with("(STAGING)"))
+        }),
+        Err(_) => false,
+    }
+}
+
+/// The cache key under which to keep a corrupt cache entry for inspection: the original key with a
+/// marker prepended, so it doesn't collide with the original entry, which gets replaced.
+fn quarantine_key(names: &[String]) -> Vec<String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut key = vec![format!("quarantined-{}", now.as_secs())];
+    key.extend_from_slice(names);
+    key
+}
+
+/// The cache key for the `n`th previously served certificate, counting from 1.
+fn previous_key(n: usize, domains: &[String]) -> Vec<String> {
+    let mut key = vec![format!("previous-{}", n)];
+    key.extend_from_slice(domains);
+    key
+}
+
+/// The cache key for the URL of the order in progress for `names`, the subject alternative names
+/// of its request, kept so an order interrupted by a restart can be resumed.
+fn order_key(names: &[String]) -> Vec<String> {
+    let mut key = vec!["order".into()];
+    key.extend_from_slice(names);
+    key
+}
+
+/// Check whether the PKCS#8 private key `key` belongs to the public key `public_key` of a
+/// certificate. Keys of types we can't check are assumed to match.
+fn key_matches(key: &[u8], public_key: &[u8]) -> bool {
+    for alg in [
+        &ECDSA_P256_SHA256_ASN1_SIGNING,
+        &ECDSA_P384_SHA384_ASN1_SIGNING,
+    ] {
+        if let Ok(pair) = EcdsaKeyPair::from_pkcs8(alg, key) {
+            return pair.public_key().as_ref() == public_key;
+        }
+    }
+    if let Ok(pair) = Ed25519KeyPair::from_pkcs8_maybe_unchecked(key) {
+        return pair.public_key().as_ref() == public_key;
+    }
+    if let Ok(pair) = RsaKeyPair::from_pkcs8(key) {
+        return pair.public_key().as_ref() == public_key;
+    }
+    true
+}
+
+impl<EC: 'static + Debug, EA: 'static + Debug> AcmeState<EC, EA> {
+    pub(crate) fn new(mut config: AcmeConfig<EC, EA>) -> Self {
+        let orders = Semaphore::new(config.max_concurrent_orders.max(1));
+        let external_account_key = config.external_account_key.clone();
+        let main = DirectoryState {
+            url: config.directory_url.clone(),
+            source: None,
+            domains: config.domains.clone(),
+            cache: None,
+            single_cert: false,
+            contact: None,
+            external_account_key: external_account_key.clone(),
+            account: Arc::default(),
+            main: true,
+        };
+        let directories: Vec<_> = std::iter::once(main)
+            .chain(
+                config
+                    .directory_groups
+                    .drain(..)
+                    .map(|group| DirectoryState {
+                        url: group.directory_url,
+                        source: group.source,
+                        domains: group.domains,
+                        cache: group.cache,
+                        single_cert: group.single_cert,
+                        contact: group.contact,
+                        external_account_key: match group.main_directory {
+                            true => external_account_key.clone(),
+                            false => None,
+                        },
+                        account: Arc::default(),
+                        main: false,
+                    }),
+            )
+            .collect();
+        let default_domain = config.default_domain.clone().or_else(|| {
+            directories
+                .iter()
+                .find_map(|directory| directory.domains.first().cloned())
+        });
+        let (on_demand, on_demand_requests) = match &config.on_demand {
+            Some(policy) => {
+                let (queue, receiver) = OnDemandQueue::new(policy.clone());
+                (Some(queue), Some(receiver))
+            }
+            None => (None, None),
+        };
+        let resolver = AcmeResolver::new(
+            default_domain,
+            config.sni_priority,
+            config.missing_sni.clone(),
+            config.sni_override.clone(),
+            on_demand,
+            config.clock.clone(),
+        );
+        if config.self_signed_fallback {
+            let domains: Vec<String> = directories
+                .iter()
+                .flat_map(|directory| directory.domains.iter().cloned())
+                .collect();
+            match self_signed_cert(domains) {
+                Ok(cert) => resolver.set_fallback(cert),
+                Err(err) => warn!(
+                    ?err,
+                    "failed to generate the self-signed fallback certificate"
+                ),
+            }
+        }
+        let staging = config.staging_check.then(|| DirectoryState {
+            url: LETS_ENCRYPT_STAGING_DIRECTORY.into(),
+            source: None,
+            domains: vec![],
+            cache: None,
+            single_cert: false,
+            contact: None,
+            external_account_key: None,
+            account: Arc::default(),
+            main: false,
+        });
+        let registry = Arc::<Registry>::default();
+        let (added, added_domains) = unbounded();
+        registry.set_added_domains(added);
+        let (reloads, config_reloads) = unbounded();
+        registry.set_config_reloads(reloads);
+        registry.set_history_capacity(config.event_history);
+        registry.set_clock(config.clock.clone());
+        if config.keylog {
+            match std::env::var_os("SSLKEYLOGFILE") {
+                Some(path) => warn!(
+                    path = %Path::new(&path).display(),
+                    "writing TLS session secrets to SSLKEYLOGFILE; anyone reading it can decrypt \
+                     the traffic"
+                ),
+                None => warn!("TLS key logging is on, but SSLKEYLOGFILE is not set"),
+            }
+            registry.set_key_log(Arc::new(KeyLogFile::new()));
+        }
+        let mut ticket_key_sync = None;
+        if let Some(config) = &config.session_resumption {
+            let resumption = Resumption::new(config);
+            if config.tickets && config.shared_ticket_keys {
+                ticket_key_sync = Some(resumption.ticketer());
+            }
+            registry.set_resumption(resumption);
+        }
+        let ticket_key_sync = match ticket_key_sync {
+            Some(_) if config.ephemeral => {
+                warn!("no cache to share session ticket keys through; keeping them to ourselves");
+                None
+            }
+            Some(ticketer) => {
+                let cache = shared_cache(config.cache.clone(), config.directory_url.clone());
+                Some(TicketKeySync::new(cache, ticketer))
+            }
+            None => None,
+        };
+        let snapshot = config.snapshot.take().unwrap_or_default();
+        registry.restore_history(snapshot.history());
+        if let Some((orders, per)) = config.order_rate {
+            registry.pacer.set_rate(orders, per);
+        }
+        for directory in directories.iter().filter(|d| d.source.is_none()) {
+            let limits = config.rate_limits.clone().or_else(|| {
+                (directory.url == LETS_ENCRYPT_PRODUCTION_DIRECTORY).then(RateLimits::lets_encrypt)
+            });
+            registry.rate_budgets.set_limits(&directory.url, limits);
+        }
+        if let Some(port) = config.tls_alpn_port {
+            registry.set_validation_port(port);
+        }
+        let mut responders = config.challenge_responders.clone();
+        if config.http01 {
+            responders.push(Arc::new(Http01Responder {
+                registry: registry.clone(),
+            }));
+        }
+        let shared_challenges = config.challenge_delegation.map(|_| {
+            SharedChallenges::new(
+                config.cache.clone(),
+                config.directory_url.clone(),
+                resolver.clone(),
+            )
+        });
+        responders.push(Arc::new(TlsAlpn01Responder {
+            resolver: resolver.clone(),
+            shared: shared_challenges.clone(),
+        }));
+        let summary = ConfigSummary {
+            name: config.name.clone(),
+            directory_url: config.directory_url.clone(),
+            staging: config.directory_url == LETS_ENCRYPT_STAGING_DIRECTORY,
+            groups: directories
+                .iter()
+                .map(|directory| GroupSummary {
+                    directory: match &directory.source {
+                        Some(source) => source.name().into(),
+                        None => directory.url.clone(),
+                    },
+                    source: directory.source.is_some(),
+                    domains: directory.domains.clone(),
+                })
+                .collect(),
+            cache: (!config.ephemeral).then(|| config.cache_name.into()),
+            challenge_types: responders
+                .iter()
+                .map(|responder| responder.challenge_type().into())
+                .collect(),
+            renewal: config.renewal_policy.describe(),
+            cert_per_domain: config.cert_per_domain,
+            previous_certs: config.previous_certs,
+            staging_check: config.staging_check,
+            on_demand: config.on_demand.is_some(),
+            export_dir: config.export_dir.clone(),
+        };
+        info!(
+            acceptor = summary.name.as_deref(),
+            directory_url = %summary.directory_url,
+            staging = summary.staging,
+            cache = summary.cache.as_deref().unwrap_or("none"),
+            challenge_types = ?summary.challenge_types,
+            "tide-acme configuration:\n{}",
+            summary
+        );
+        registry.set_config_summary(summary);
+        Self {
+            resolver,
+            responders,
+            contact: std::sync::RwLock::new(config.contact.clone()),
+            config,
+            directories: std::sync::RwLock::new(directories.into_iter().map(Arc::new).collect()),
+            main_directory: AtomicUsize::new(0),
+            staging,
+            orders,
+            registry,
+            #[cfg(unix)]
+            handed_over: std::sync::Mutex::new(handover::take_certs()),
+            on_demand_requests: std::sync::Mutex::new(on_demand_requests),
+            added_domains: std::sync::Mutex::new(Some(added_domains)),
+            config_reloads: std::sync::Mutex::new(Some(config_reloads)),
+            shared_challenges,
+            ticket_key_sync: std::sync::Mutex::new(ticket_key_sync),
+            restored_domains: std::sync::Mutex::new(snapshot.runtime_domains()),
+            restored: std::sync::Mutex::new(snapshot.certs),
+            revoked: std::sync::Mutex::new(HashSet::new()),
+            remote_keys: RemoteKeys::default(),
+        }
+    }
+
+    pub(crate) fn handle(&self) -> AcmeHandle {
+        AcmeHandle {
+            registry: self.registry.clone(),
+            resolver: self.resolver.clone(),
+        }
+    }
+
+    pub(crate) fn challenge_close(&self) -> ChallengeClose {
+        self.config.challenge_close
+    }
+
+    #[cfg(feature = "watch")]
+    pub(crate) fn watch_cache_dir(&self) -> Option<&std::path::Path> {
+        self.config.watch_cache_dir.as_deref()
+    }
+
+    pub(crate) fn listener_options(&self) -> ListenerOptions {
+        self.config.listener_options.clone()
+    }
+
+    pub(crate) fn handshake_filter(&self) -> Option<HandshakeFilter> {
+        self.config.handshake_filter.clone()
+    }
+
+    pub(crate) fn configure_rustls(&self) -> Option<ConfigureRustls> {
+        self.config.configure_rustls.clone()
+    }
+
+    pub(crate) fn inspect_client_hello(&self) -> Option<ClientHelloInspector> {
+        self.config.inspect_client_hello.clone()
+    }
+
+    /// The clock renewals are scheduled and expiry is checked by.
+    pub(crate) fn clock(&self) -> &dyn Clock {
+        &*self.config.clock
+    }
+
+    pub(crate) fn on_demand_wait(&self) -> Duration {
+        self.config.on_demand_wait
+    }
+
+    pub(crate) fn shared_challenges(&self) -> Option<SharedChallenges> {
+        self.shared_challenges.clone()
+    }
+
+    /// Check that the CA's tls-alpn-01 validation of `domains` would reach this server, if
+    /// configured to and tls-alpn-01 would be used.
+    async fn preflight_check(&self, domains: &[String]) -> Result<(), OrderError> {
+        let tls_alpn_01 =
+            self.responders.first().map(|r| r.challenge_type()) == Some("tls-alpn-01");
+        if !self.config.preflight_check || !tls_alpn_01 || self.shared_challenges.is_some() {
+            return Ok(());
+        }
+        for domain in domains {
+            if domain.starts_with("*.") || ip::parse(domain).is_some() {
+                continue;
+            }
+            preflight::check(&self.resolver, domain)
+                .await
+                .map_err(OrderError::Preflight)?;
+            info!(%domain, "pre-flight check passed");
+        }
+        Ok(())
+    }
+
+    /// Take the stream of server names to obtain certificates for on demand, if configured.
+    pub(crate) fn take_on_demand_requests(&self) -> Option<Receiver<String>> {
+        self.on_demand_requests.lock().unwrap().take()
+    }
+
+    /// Ask the domain policy whether to obtain a certificate for `domain` on demand. Denied names
+    /// may be requested again.
+    pub(crate) async fn approve_on_demand(&self, domain: &str) -> bool {
+        let approved = match &self.config.on_demand {
+            Some(policy) => policy.approve(domain).await,
+            None => false,
+        };
+        match approved {
+            true => self.resolver.approve_on_demand(domain),
+            false => self.resolver.forget_on_demand(domain),
+        }
+        approved
+    }
+
+    /// Record that the certificate on demand for `domain` is obtained by the certificate already
+    /// managed for it, without asking the domain policy again.
+    pub(crate) fn share_on_demand(&self, domain: &str) {
+        self.resolver.approve_on_demand(domain);
+    }
+
+    /// Take the stream of domains added at runtime.
+    pub(crate) fn take_added_domains(&self) -> Option<UnboundedReceiver<String>> {
+        self.added_domains.lock().unwrap().take()
+    }
+
+    pub(crate) fn take_config_reloads(&self) -> Option<UnboundedReceiver<ConfigReload>> {
+        self.config_reloads.lock().unwrap().take()
+    }
+
+    pub(crate) fn take_ticket_key_sync(&self) -> Option<TicketKeySync> {
+        self.ticket_key_sync.lock().unwrap().take()
+    }
+
+    /// The thresholds of remaining validity below which the expiry watchdog raises alarms.
+    pub(crate) fn expiry_alarms(&self) -> Vec<Duration> {
+        self.config.expiry_alarms.clone()
+    }
+
+    /// Pass `event`, about the certificate for `domains`, to the configured event callback, and
+    /// report it to the configured error reporters if it is a failure and not `transient`.
+    pub(crate) fn report(&self, domains: &[String], event: &AcmeEvent, transient: bool) {
+        if let Some(callback) = &self.config.on_event {
+            callback(event);
+        }
+        if !event.is_failure() || transient {
+            return;
+        }
+        let mut routed = false;
+        for (reported, reporter) in &self.config.domain_reporters {
+            let matches = reported.iter().any(|domain| {
+                domains
+                    .iter()
+                    .any(|covered| covered.eq_ignore_ascii_case(domain))
+            });
+            if matches {
+                reporter.report(event);
+                routed = true;
+            }
+        }
+        if let (false, Some(reporter)) = (routed, &self.config.error_reporter) {
+            reporter.report(event);
+        }
+    }
+
+    /// Take the domains obtained on demand or added at runtime before the snapshot restored was
+    /// taken.
+    pub(crate) fn take_restored_domains(&self) -> Vec<(String, CertOrigin)> {
+        std::mem::take(&mut *self.restored_domains.lock().unwrap())
+    }
+
+    /// Request a certificate on demand for `domain` again, as if a client had asked for it.
+    pub(crate) fn request_on_demand(&self, domain: &str) {
+        self.resolver.request_on_demand(domain);
+    }
+
+    /// Start managing a certificate for a domain added at runtime or approved on demand, from the
+    /// main directory.
+    pub(crate) fn domain_cert(
+        self: &Arc<Self>,
+        domain: String,
+        origin: CertOrigin,
+    ) -> CertState<EC, EA> {
+        let directory = self.main_directory.load(Ordering::Relaxed);
+        self.cert(directory, vec![domain], origin)
+    }
+
+    /// Apply a reloaded configuration: switch to its contact URLs, and replace the main
+    /// directory if its URL or domains changed. Certificates whose domains and directory are
+    /// unchanged are kept; the others are retired, going on being served until replaced or
+    /// their domains removed. Returns the certificates to start managing.
+    pub(crate) async fn reload_config(
+        self: &Arc<Self>,
+        reload: ConfigReload,
+    ) -> Vec<CertState<EC, EA>> {
+        let current = self.main_directory.load(Ordering::Relaxed);
+        let old = self.directory(current);
+        let mut directory_url = reload.directory_url.unwrap_or_else(|| old.url.clone());
+        if self.config.ephemeral && directory_url != LETS_ENCRYPT_STAGING_DIRECTORY {
+            warn!(
+                %directory_url,
+                "ignoring the reloaded ACME directory without a cache, using Let's Encrypt staging"
+            );
+            directory_url = LETS_ENCRYPT_STAGING_DIRECTORY.into();
+        }
+        let domains = reload.domains.unwrap_or_else(|| old.domains.clone());
+        let contact_changed = match reload.contact {
+            Some(new) => {
+                let mut contact = self.contact.write().unwrap();
+                let changed = *contact != new;
+                *contact = new;
+                changed
+            }
+            None => false,
+        };
+        if contact_changed {
+            info!("contact URLs changed; switching to the account cached for them");
+            let directories = self.directories.read().unwrap().clone();
+            for directory in directories.iter().filter(|d| d.contact.is_none()) {
+                *directory.account.lock().await = AccountState::default();
+            }
+        }
+        let directory_changed = directory_url != old.url;
+        if !directory_changed && domains == old.domains {
+            info!("configuration reloaded; domains and directory unchanged");
+            return vec![];
+        }
+        let directory = DirectoryState {
+            url: directory_url,
+            source: None,
+            domains,
+            cache: None,
+            single_cert: false,
+            contact: None,
+            external_account_key: old.external_account_key.clone(),
+            account: match directory_changed {
+                true => Arc::default(),
+                false => old.account.clone(),
+            },
+            main: true,
+        };
+        if directory_changed {
+            let limits = self.config.rate_limits.clone().or_else(|| {
+                (directory.url == LETS_ENCRYPT_PRODUCTION_DIRECTORY).then(RateLimits::lets_encrypt)
+            });
+            self.registry
+                .rate_budgets
+                .set_limits(&directory.url, limits);
+        }
+        let groups = self.cert_groups(&directory);
+        self.registry.reload_config_summary(
+            &directory.url,
+            directory.url == LETS_ENCRYPT_STAGING_DIRECTORY,
+            &directory.domains,
+        );
+        let index = {
+            let mut directories = self.directories.write().unwrap();
+            directories.push(Arc::new(directory));
+            directories.len() - 1
+        };
+        self.main_directory.store(index, Ordering::Relaxed);
+        let directories = self.directories.read().unwrap().clone();
+        let directory = &directories[index];
+
+        let mut kept = vec![];
+        let mut retired = vec![];
+        let mut certs = vec![];
+        for (cert, domains, origin) in self.registry.managed(|i| directories[i].main) {
+            let keep = !directory_changed
+                && match origin {
+                    CertOrigin::Configured => groups.contains(&domains),
+                    _ => !domains.iter().any(|d| directory.domains.contains(d)),
+                };
+            if keep {
+                kept.push(domains);
+                continue;
+            }
+            self.registry.command_index(cert, Command::Retire);
+            if origin != CertOrigin::Configured {
+                for domain in domains.iter() {
+                    if !directory.domains.contains(domain) {
+                        certs.push(self.cert(index, vec![domain.clone()], origin));
+                    }
+                }
+            }
+            retired.extend(domains);
+        }
+        for domains in groups {
+            if !kept.contains(&domains) {
+                certs.push(self.cert(index, domains, CertOrigin::Configured));
+            }
+        }
+        let removed: Vec<String> = retired
+            .into_iter()
+            .filter(|domain| !directory.domains.contains(domain))
+            .filter(|domain| !certs.iter().any(|cert| cert.domains.contains(domain)))
+            .collect();
+        self.resolver.remove_names(&removed);
+        info!(
+            directory_url = %directory.url,
+            new_certs = certs.len(),
+            ?removed,
+            "configuration reloaded"
+        );
+        certs
+    }
+
+    /// Look up the key of a certificate kept without it in the key store, so it can be parsed.
+    async fn load_remote_key(&self, pem: &[u8]) {
+        let store = match &self.config.key_store {
+            Some(store) => store,
+            None => return,
+        };
+        let public_key = match key_store::keyless_public_key(pem) {
+            Some(public_key) => public_key,
+            None => return,
+        };
+        if self.remote_keys.get(&public_key).is_some() {
+            return;
+        }
+        match store.load_key(&public_key).await {
+            Ok(Some(key)) => self.remote_keys.insert(key),
+            Ok(None) => warn!("the key store has no key for the cached certificate"),
+            Err(err) => warn!(%err, "failed to load the key of the cached certificate"),
+        }
+    }
+
+    /// Take the certificate for `domains` handed over by the process that started this one.
+    #[cfg(unix)]
+    fn take_handed_over(&self, domains: &[String]) -> Option<Zeroizing<Vec<u8>>> {
+        let mut certs = self.handed_over.lock().unwrap();
+        let index = certs.iter().position(|cert| cert.domains == domains)?;
+        let mut cert = certs.swap_remove(index);
+        Some(Zeroizing::new(std::mem::take(&mut cert.pem).into_bytes()))
+    }
+
+    #[cfg(not(unix))]
+    fn take_handed_over(&self, _domains: &[String]) -> Option<Zeroizing<Vec<u8>>> {
+        None
+    }
+
+    /// The cache of a directory.
+    fn cache<'a>(
+        &'a self,
+        directory: &'a DirectoryState<EC, EA>,
+    ) -> &'a dyn Cache<EC = EC, EA = EA> {
+        match &directory.cache {
+            Some(cache) => cache.as_ref(),
+            None => self.config.cache.as_ref(),
+        }
+    }
+
+    /// Store a certificate cache entry, retrying and then falling back to the secondary cache as
+    /// configured. Fails with the last error if no cache took it.
+    async fn store_cert(
+        &self,
+        directory: &DirectoryState<EC, EA>,
+        key: &[String],
+        entry: &[u8],
+    ) -> Result<Stored, EC> {
+        let _write = self.registry.shutdown.start_write();
+        let cache = self.cache(directory);
+        let result = self
+            .retry_store(|| cache.store_cert(key, &directory.url, entry))
+            .await;
+        let secondary = match (result, &self.config.secondary_cache) {
+            (Ok(()), _) => return Ok(Stored::Main),
+            (Err(err), None) => return Err(err),
+            (Err(err), Some(secondary)) => {
+                warn!(
+                    ?err,
+                    "failed to store certificate; storing it in the secondary cache"
+                );
+                secondary
+            }
+        };
+        secondary
+            .store_cert(key, &directory.url, entry)
+            .await
+            .map(|()| Stored::Secondary)
+    }
+
+    /// Store an account cache entry, like [`store_cert`](Self::store_cert).
+    async fn store_account(
+        &self,
+        directory: &DirectoryState<EC, EA>,
+        contact: &[String],
+        entry: &[u8],
+    ) -> Result<Stored, EA> {
+        let _write = self.registry.shutdown.start_write();
+        let cache = self.cache(directory);
+        let result = self
+            .retry_store(|| cache.store_account(contact, &directory.url, entry))
+            .await;
+        let secondary = match (result, &self.config.secondary_cache) {
+            (Ok(()), _) => return Ok(Stored::Main),
+            (Err(err), None) => return Err(err),
+            (Err(err), Some(secondary)) => {
+                warn!(
+                    ?err,
+                    "failed to store account key; storing it in the secondary cache"
+                );
+                secondary
+            }
+        };
+        secondary
+            .store_account(contact, &directory.url, entry)
+            .await
+            .map(|()| Stored::Secondary)
+    }
+
+    /// Run `store` until it succeeds or the configured retries are used up, with exponential
+    /// backoff starting at one second.
+    async fn retry_store<E: Debug, F: Future<Output = Result<(), E>>>(
+        &self,
+        mut store: impl FnMut() -> F,
+    ) -> Result<(), E> {
+        let mut attempt = 0;
+        loop {
+            let delay = Duration::from_secs(1 << attempt.min(6));
+            match store().await {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt == self.config.cache_store_retries => return Err(err),
+                Err(err) => warn!(?err, ?delay, "failed to store cache entry; retrying"),
+            }
+            task::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Load a certificate cache entry, from the secondary cache if the main cache has none or
+    /// fails.
+    async fn load_cert(
+        &self,
+        directory: &DirectoryState<EC, EA>,
+        key: &[String],
+    ) -> Result<Option<Vec<u8>>, EC> {
+        let result = self.cache(directory).load_cert(key, &directory.url).await;
+        let secondary = match (result, &self.config.secondary_cache) {
+            (Ok(Some(entry)), _) => return Ok(Some(entry)),
+            (result, None) => return result,
+            (Ok(None), Some(secondary)) => secondary,
+            (Err(err), Some(secondary)) => {
+                warn!(
+                    ?err,
+                    "failed to load certificate; loading it from the secondary cache"
+                );
+                secondary
+            }
+        };
+        secondary.load_cert(key, &directory.url).await
+    }
+
+    /// Load an account cache entry, like [`load_cert`](Self::load_cert).
+    async fn load_account(
+        &self,
+        directory: &DirectoryState<EC, EA>,
+        contact: &[String],
+    ) -> Result<Option<Vec<u8>>, EA> {
+        let result = self
+            .cache(directory)
+            .load_account(contact, &directory.url)
+            .await;
+        let secondary = match (result, &self.config.secondary_cache) {
+            (Ok(Some(entry)), _) => return Ok(Some(entry)),
+            (result, None) => return result,
+            (Ok(None), Some(secondary)) => secondary,
+            (Err(err), Some(secondary)) => {
+                warn!(
+                    ?err,
+                    "failed to load account key; loading it from the secondary cache"
+                );
+                secondary
+            }
+        };
+        secondary.load_account(contact, &directory.url).await
+    }
+
+    /// The contact URLs of a directory's account.
+    fn contact(&self, directory: &DirectoryState<EC, EA>) -> Vec<String> {
+        match &directory.contact {
+            Some(contact) => contact.clone(),
+            None => self.contact.read().unwrap().clone(),
+        }
+    }
+
+    /// The directory at `index`.
+    fn directory(&self, index: usize) -> Arc<DirectoryState<EC, EA>> {
+        self.directories.read().unwrap()[index].clone()
+    }
+
+    /// Split each directory's domains into the certificates to manage: one for all domains, one
+    /// per domain if the configuration asks for it, or as many as the SAN limit requires.
+    /// Directories without domains are skipped, except the main directory when it is the only one
+    /// and certificates aren't obtained on demand.
+    pub(crate) fn certs(self: &Arc<Self>) -> Vec<CertState<EC, EA>> {
+        let config = &self.config;
+        let directories = self.directories.read().unwrap().clone();
+        let mut certs = vec![];
+        for (directory, state) in directories.iter().enumerate() {
+            if state.domains.is_empty() && (directories.len() > 1 || config.on_demand.is_some()) {
+                continue;
+            }
+            certs.extend(
+                self.cert_groups(state)
+                    .into_iter()
+                    .map(|domains| self.cert(directory, domains, CertOrigin::Configured)),
+            );
+        }
+        certs
+    }
+
+    /// Split a directory's domains into the domains of each certificate.
+    fn cert_groups(&self, directory: &DirectoryState<EC, EA>) -> Vec<Vec<String>> {
+        let config = &self.config;
+        let max_sans = config.max_sans_per_cert.max(1);
+        if directory.single_cert {
+            vec![directory.domains.clone()]
+        } else if config.cert_per_domain {
+            directory.domains.iter().map(|d| vec![d.clone()]).collect()
+        } else if directory.domains.len() > max_sans {
+            // Sort so that the grouping, and thus the cache keys, don't depend on the order the
+            // domains were configured in.
+            let mut domains = directory.domains.clone();
+            domains.sort();
+            domains.dedup();
+            domains.chunks(max_sans).map(<[String]>::to_vec).collect()
+        } else {
+            vec![directory.domains.clone()]
+        }
+    }
+
+    fn cert(
+        self: &Arc<Self>,
+        directory: usize,
+        domains: Vec<String>,
+        origin: CertOrigin,
+    ) -> CertState<EC, EA> {
+        let (index, commands) = self.registry.register(domains.clone(), directory, origin);
+        let restored = {
+            let mut restored = self.restored.lock().unwrap();
+            let position = restored.iter().position(|cert| cert.domains == domains);
+            position.map(|position| restored.swap_remove(position))
+        };
+        CertState {
+            state: self.clone(),
+            index,
+            directory,
+            domains,
+            load_cert: true,
+            store_cert: None,
+            store_previous: false,
+            account_registered: false,
+            quarantine_cert: None,
+            current: None,
+            not_after: None,
+            previous: vec![],
+            commands,
+            order_url: None,
+            renew_at: None,
+            ocsp_refresh_at: None,
+            ocsp_expires_at: None,
+            ocsp_stale: false,
+            backoff_cnt: 0,
+            attempts: VecDeque::new(),
+            staged: false,
+            order_guard: None,
+            failure_cnt: 0,
+            domain_backoff: HashMap::new(),
+            removed: false,
+            rotation: None,
+            origin,
+            restored,
+        }
+    }
+
+    /// Get the account key for a directory, loading it from the cache or generating it on first
+    /// use.
+    ///
+    /// Returns `Err` with an event to report if loading or generating the key produced one; call
+    /// again afterwards to get the key.
+    async fn account_key(
+        &self,
+        directory: &DirectoryState<EC, EA>,
+    ) -> Result<Zeroizing<Vec<u8>>, Event<EC, EA>> {
+        let config = &self.config;
+        let contact = &self.contact(directory);
+        let mut account = directory.account.lock().await;
+        if !account.loaded {
+            account.loaded = true;
+            let loaded = match self.load_account(directory, contact).await {
+                Ok(loaded) => loaded,
+                Err(err) => return Err(Err(EventError::AccountCacheLoad(err))),
+            };
+            if let Some(entry) = loaded {
+                let (entry, migrated) = format::read(entry, EntryKind::Account)
+                    .map_err(|err| Err(EventError::AccountCacheFormat(err)))?;
+                let (directory_url, key) = format::account_key(&entry)
+                    .map_err(|err| Err(EventError::AccountCacheFormat(err)))?;
+                let key = Zeroizing::new(key.to_vec());
+                if directory_url.is_some_and(|url| url != directory.url) {
+                    // The cache doesn't keep the accounts of different directories apart, so
+                    // storing a key for this directory would replace the other directory's.
+                    warn!(
+                        cached_directory_url = directory_url,
+                        directory_url = %directory.url,
+                        "cached account key belongs to another directory; using a new account \
+                         without storing it"
+                    );
+                    let key = AccountKey::generate_pkcs8(config.account_key_algorithm)
+                        .map_err(|err| Err(EventError::Order(AcmeError::from(err).into())))?;
+                    account.key = Some(key.clone());
+                    return Ok(key);
+                }
+                match AccountKey::from_pkcs8(config.account_key_algorithm, &key) {
+                    Ok(_) if migrated || directory_url.is_none() => {
+                        account.key = Some(key.clone());
+                        let stamped = format::stamp(&format::account_entry(&directory.url, &key));
+                        return Err(
+                            match self.store_account(directory, contact, &stamped).await {
+                                Ok(Stored::Main) => Ok(EventOk::AccountCacheStore),
+                                Ok(Stored::Secondary) => Ok(EventOk::AccountCacheStoreSecondary),
+                                Err(err) => Err(EventError::AccountCacheStore(err)),
+                            },
+                        );
+                    }
+                    Ok(_) => account.key = Some(key),
+                    Err(err) => {
+                        let contact = quarantine_key(contact);
+                        warn!(
+                            %err,
+                            algorithm = ?config.account_key_algorithm,
+                            ?contact,
+                            "cached account key is corrupt or does not match the configured \
+                             algorithm; moving it aside and generating a new one"
+                        );
+                        return Err(
+                            match self
+                                .store_account(directory, &contact, &format::stamp(&entry))
+                                .await
+                            {
+                                Ok(_) => Ok(EventOk::AccountCacheQuarantine),
+                                Err(err) => Err(EventError::AccountCacheStore(err)),
+                            },
+                        );
+                    }
+                }
+            }
+        }
+        if let Some(key) = &account.key {
+            return Ok(key.clone());
+        }
+        let key = AccountKey::generate_pkcs8(config.account_key_algorithm)
+            .map_err(|err| Err(EventError::Order(AcmeError::from(err).into())))?;
+        account.key = Some(key.clone());
+        let entry = format::account_entry(&directory.url, &key);
+        Err(
+            match self
+                .store_account(directory, contact, &format::stamp(&entry))
+                .await
+            {
+                Ok(Stored::Main) => Ok(EventOk::AccountCacheStore),
+                Ok(Stored::Secondary) => Ok(EventOk::AccountCacheStoreSecondary),
+                Err(err) => Err(EventError::AccountCacheStore(err)),
+            },
+        )
+    }
+}
+
+impl<EC: 'static + Debug, EA: 'static + Debug> CertState<EC, EA> {
+    /// The domains covered by this certificate.
+    pub(crate) fn domains(&self) -> &[String] {
+        &self.domains
+    }
+
+    /// Pass `event` to the configured event callback, and report it to the configured error
+    /// reporter if it is a failure that won't resolve itself: order failures are retried, so
+    /// they're only reported from the third in a row.
+    pub(crate) fn report(&self, event: &AcmeEvent) {
+        let transient = event.kind == EventKind::OrderFailed && self.failure_cnt < 3;
+        self.state.report(&self.domains, event, transient);
+    }
+
+    /// Whether `event` is a cache store failure the configuration makes fatal.
+    pub(crate) fn is_fatal(&self, event: &Event<EC, EA>) -> bool {
+        self.state.config.cache_store_fatal
+            && matches!(
+                event,
+                Err(EventError::CertCacheStore(_) | EventError::AccountCacheStore(_))
+            )
+    }
+
+    /// Record the outcome of `event` in the certificate's status.
+    pub(crate) fn record(&self, event: &AcmeEvent) {
+        self.state.registry.update(self.index, |status| {
+            status.degraded = None;
+            match event.kind {
+                EventKind::DeployedCachedCert
+                | EventKind::DeployedNewCert
+                | EventKind::DeployedPreviousCert => {
+                    status.last_error = None;
+                    status.last_failure = None;
+                    status.last_error_hint = None;
+                    status.challenge_failure = None;
+                    status.failed_orders = 0;
+                }
+                EventKind::OrderFailed | EventKind::NewCertInvalid => {
+                    status.last_error = event.error.clone();
+                    status.last_failure = event.failure.clone();
+                    status.last_error_hint = event.hint;
+                    if event.challenge_failure.is_some() {
+                        status.challenge_failure = event.challenge_failure.clone();
+                    }
+                    status.failed_orders += 1;
+                }
+                _ if event.is_failure() => {
+                    status.last_error = event.error.clone();
+                    status.last_failure = event.failure.clone();
+                    status.last_error_hint = event.hint;
+                }
+                _ => {}
+            }
+        });
+    }
+
+    /// Recover from a panic in the task managing this certificate, marking it degraded until its
+    /// next event. The order in progress, if any, is abandoned and placed again.
+    pub(crate) fn panicked(&mut self, message: String) {
+        self.order_url = None;
+        self.order_guard = None;
+        self.rotation = None;
+        self.state
+            .registry
+            .update(self.index, |status| status.degraded = Some(message));
+    }
+
+    /// Whether the certificate was removed from management, after its last domain was removed
+    /// with [`AcmeHandle::remove_domain`].
+    pub(crate) fn is_removed(&self) -> bool {
+        self.removed
+    }
+
+    /// Pass the certificate just deployed, if any, to the rotation hook, and `event` to the hooks
+    /// for its kind, and wait for them.
+    pub(crate) async fn run_hooks(&mut self, event: &AcmeEvent) {
+        let config = &self.state.config;
+        if let Some(rotation) = self.rotation.take() {
+            if let Some(dir) = &config.export_dir {
+                let chain = rotation.certificate_pem();
+                let key = Zeroizing::new(rotation.private_key_pem());
+                match export::export(dir, &self.domains, chain, key).await {
+                    Ok(path) => info!(path = %path.display(), "exported certificate"),
+                    Err(err) => error!(%err, "failed to export certificate"),
+                }
+            }
+            if let (EventKind::DeployedNewCert, Some(hook)) = (event.kind, &config.on_cert_obtained)
+            {
+                hook(rotation.clone()).await;
+            }
+            if let Some(hook) = &config.on_rotation {
+                hook(rotation.clone()).await;
+            }
+            for (domains, hook) in &config.domain_rotation_hooks {
+                let matches = domains.iter().any(|domain| {
+                    self.domains
+                        .iter()
+                        .any(|covered| covered.eq_ignore_ascii_case(domain))
+                });
+                if matches {
+                    hook(rotation.clone()).await;
+                }
+            }
+        }
+        if let (EventKind::OrderFailed, Some(hook)) = (event.kind, &config.on_order_failed) {
+            let status = self.state.registry.cert_status(self.index);
+            hook(event.clone(), status).await;
+        }
+    }
+
+    /// The URL of the most recent order, if any.
+    pub(crate) fn order_url(&self) -> Option<&str> {
+        self.order_url.as_deref()
+    }
+
+    /// The current time, by the configured clock.
+    fn now(&self) -> SystemTime {
+        self.state.config.clock.now()
+    }
+
+    /// The number of the next order attempt: 1, or more after failed orders.
+    pub(crate) fn attempt(&self) -> u32 {
+        self.failure_cnt + 1
+    }
+
+    /// The level of the tracing span of each step of this certificate's management.
+    pub(crate) fn tracing_level(&self) -> Level {
+        self.state.config.tracing_level
+    }
+
+    /// Keep the runtime state of this certificate's management in the registry, for snapshots.
+    pub(crate) fn save_resume(&self) {
+        let mut domain_backoff: Vec<_> = self
+            .domain_backoff
+            .iter()
+            .map(|(domain, backoff)| (domain.clone(), backoff.failures, backoff.until))
+            .collect();
+        domain_backoff.sort();
+        let resume = CertResume {
+            domains: self.domains.clone(),
+            origin: self.origin,
+            not_after: self.not_after,
+            renew_at: self.renew_at,
+            backoff_cnt: self.backoff_cnt,
+            failure_cnt: self.failure_cnt,
+            attempts: self.attempts.iter().copied().collect(),
+            staged: self.staged,
+            domain_backoff,
+            order_url: self.order_url.clone(),
+            failed_orders: 0,
+            last_error: None,
+            last_failure: None,
+        };
+        self.state.registry.set_resume(self.index, resume);
+    }
+
+    /// Apply the state restored from a snapshot, if the certificate being served is the one it
+    /// was taken with.
+    async fn resume(&mut self, resume: CertResume) {
+        if resume.not_after != self.not_after {
+            info!("the certificate differs from the snapshot's; not restoring its state");
+            return;
+        }
+        info!(renew_at = ?resume.renew_at, "restoring state from the snapshot");
+        self.backoff_cnt = resume.backoff_cnt;
+        self.failure_cnt = resume.failure_cnt;
+        self.attempts = resume.attempts.into();
+        self.staged = resume.staged;
+        self.domain_backoff = resume
+            .domain_backoff
+            .into_iter()
+            .filter(|(domain, _, _)| self.domains.contains(domain))
+            .map(|(domain, failures, until)| (domain, DomainBackoff { failures, until }))
+            .collect();
+        self.update_backed_off_domains();
+        if let Some(at) = resume.renew_at {
+            self.schedule(at);
+        }
+        let (failed_orders, last_error, last_failure) =
+            (resume.failed_orders, resume.last_error, resume.last_failure);
+        self.state.registry.update(self.index, |status| {
+            status.failed_orders = failed_orders;
+            status.last_error = last_error;
+            status.last_failure = last_failure;
+        });
+        // Keep the order in progress where resuming orders looks for it, unless it is already
+        // there, along with the key of its certificate signing request.
+        if let Some(url) = &resume.order_url {
+            let directory = &self.state.directory(self.directory);
+            if directory.source.is_none() {
+                let kept = Self::load_order(&self.state, directory, &self.domains).await;
+                if kept.map(|(kept, _)| kept).as_deref() != Some(url.as_str()) {
+                    Self::store_order(&self.state, directory, &self.domains, url, "").await;
+                }
+            }
+        }
+        self.order_url = resume.order_url;
+    }
+
+    /// Drive management of this certificate until the next event.
+    pub(crate) async fn next(&mut self) -> Event<EC, EA> {
+        let state = self.state.clone();
+        let config = &state.config;
+        let directory = &state.directory(self.directory);
+        if let Some(pem) = self.store_cert.take() {
+            let pem = format::stamp(&pem);
+            let event = match state.store_cert(directory, &self.domains, &pem).await {
+                Ok(Stored::Main) => Ok(EventOk::CertCacheStore),
+                Ok(Stored::Secondary) => Ok(EventOk::CertCacheStoreSecondary),
+                Err(err) => Err(EventError::CertCacheStore(err)),
+            };
+            self.order_guard = None;
+            return event;
+        }
+
+        if self.account_registered {
+            self.account_registered = false;
+            return Ok(EventOk::AccountRegistered);
+        }
+
+        if self.store_previous {
+            self.store_previous = false;
+            // Slots beyond the ones in use are overwritten with nothing, so that certificates
+            // discarded by a rollback don't come back on the next start.
+            let mut event = EventOk::CertCacheStore;
+            for n in 1..=config.previous_certs {
+                let pem = self
+                    .previous
+                    .get(n - 1)
+                    .map_or(&[][..], |pem| pem.as_slice());
+                let key = previous_key(n, &self.domains);
+                let pem = format::stamp(pem);
+                match state.store_cert(directory, &key, &pem).await {
+                    Ok(Stored::Main) => {}
+                    Ok(Stored::Secondary) => event = EventOk::CertCacheStoreSecondary,
+                    Err(err) => return Err(EventError::CertCacheStore(err)),
+                }
+            }
+            return Ok(event);
+        }
+
+        if let Some(pem) = self.quarantine_cert.take() {
+            let key = quarantine_key(&self.domains);
+            warn!(
+                ?key,
+                "moving corrupt cached certificate aside and ordering a new one"
+            );
+            let pem = format::stamp(&pem);
+            return match state.store_cert(directory, &key, &pem).await {
+                Ok(_) => Ok(EventOk::CertCacheQuarantine),
+                Err(err) => Err(EventError::CertCacheStore(err)),
+            };
+        }
+
+        if self.load_cert {
+            self.load_cert = false;
+            self.load_previous().await;
+            if let Some(pem) = self.state.take_handed_over(&self.domains) {
+                state.load_remote_key(&pem).await;
+                return self.process_cert(pem, CertSource::Cache);
+            }
+            let pem = match state.load_cert(directory, &self.domains).await {
+                Ok(pem) => pem,
+                Err(err) => return Err(EventError::CertCacheLoad(err)),
+            };
+            if let Some(pem) = pem {
+                let (pem, migrated) = match format::read(pem, EntryKind::Cert) {
+                    Ok(read) => read,
+                    Err(err) => return Err(EventError::CertCacheFormat(err)),
+                };
+                state.load_remote_key(&pem).await;
+                let event = self.process_cert(pem.clone(), CertSource::Cache);
+                // Store a migrated certificate again in the current format.
+                if migrated && event.is_ok() {
+                    self.store_cert = Some(pem);
+                }
+                return event;
+            }
+        }
+
+        if let Some(resume) = self.restored.take() {
+            self.resume(resume).await;
+        }
+
+        if let Some(Some(command)) = self.commands.next().now_or_never() {
+            return self.command(command).await;
+        }
+
+        if self.ocsp_refresh_at.is_some_and(|at| at <= self.now()) {
+            return self.staple_ocsp().await;
+        }
+        if self.ocsp_expires_at.is_some_and(|at| at <= self.now()) {
+            return self.expire_ocsp();
+        }
+
+        if let Some(renew_at) = self.renew_at.take() {
+            let wake = [self.ocsp_refresh_at, self.ocsp_expires_at]
+                .iter()
+                .flatten()
+                .fold(renew_at, |wake, &at| wake.min(at));
+            let sleep = config.clock.sleep_until(wake);
+            if let Either::Right((Some(command), _)) = select(sleep, self.commands.next()).await {
+                self.renew_at = Some(renew_at);
+                return self.command(command).await;
+            }
+            if wake < renew_at {
+                self.renew_at = Some(renew_at);
+                if self.ocsp_refresh_at.is_some_and(|at| at <= wake) {
+                    return self.staple_ocsp().await;
+                }
+                return self.expire_ocsp();
+            }
+            self.state
+                .registry
+                .update(self.index, |status| status.renew_at = None);
+        }
+
+        if config.challenge_delegation == Some(ChallengeDelegation::Responder)
+            && directory.source.is_none()
+        {
+            if let Some(event) = self.load_renewed(&state, directory).await {
+                return event;
+            }
+            self.schedule(self.now() + ORDERER_POLL_INTERVAL);
+            return Ok(EventOk::AwaitingOrderer);
+        }
+
+        let domains = self.order_domains();
+        if let (None, Some(staging)) = (&directory.source, &state.staging) {
+            if !self.staged
+                && self.current.is_none()
+                && directory.url == LETS_ENCRYPT_PRODUCTION_DIRECTORY
+            {
+                return self.staging_check(staging, &domains).await;
+            }
+        }
+
+        if directory.source.is_none() {
+            let budgets = &state.registry.rate_budgets;
+            if let Some(until) = budgets.next_order(&directory.url, &domains) {
+                warn!(
+                    until = ?until,
+                    "deferring the order to stay within the CA's rate limits"
+                );
+                self.schedule(until);
+                return Ok(EventOk::OrderDeferred);
+            }
+        }
+
+        // Held until the new certificate is stored, so other processes waiting for the lock find
+        // it in the cache.
+        let guard = match &config.order_lock {
+            Some(lock) => match lock.acquire().await {
+                Ok(guard) => Some(guard),
+                Err(err) => {
+                    self.back_off();
+                    return Err(EventError::Order(OrderError::Lock(err)));
+                }
+            },
+            None => None,
+        };
+        if guard.is_some() {
+            if let Some(event) = self.load_renewed(&state, directory).await {
+                return event;
+            }
+        }
+
+        let mut failed_domain = None;
+        let started = Instant::now();
+        let result = match &directory.source {
+            Some(source) => {
+                let _permit = self.state.orders.acquire().await;
+                source
+                    .fetch(&domains)
+                    .await
+                    .map(Zeroizing::new)
+                    .map_err(OrderError::Source)
+            }
+            None => {
+                let account_key = match state.account_key(directory).await {
+                    Ok(account_key) => account_key,
+                    Err(event) => return event,
+                };
+                let _permit = self.state.orders.acquire().await;
+                let now = self.now();
+                config.retry_policy.record_attempt(&mut self.attempts, now);
+                self.order_url = None;
+                Self::order(
+                    &state,
+                    directory,
+                    &domains,
+                    &account_key,
+                    &mut self.order_url,
+                    &mut failed_domain,
+                    &mut self.account_registered,
+                )
+                .await
+            }
+        };
+        Span::current().record("elapsed_ms", started.elapsed().as_millis() as u64);
+        match result {
+            Ok(pem) => {
+                if let Err(err) = Self::check_new_cert(&pem, &domains, self.now()) {
+                    self.back_off();
+                    return Err(EventError::NewCertParse(err));
+                }
+                self.wait_for_not_before(&pem).await;
+                let event = self.process_cert(pem, CertSource::Order);
+                if self.store_cert.is_some() {
+                    self.order_guard = guard;
+                }
+                match event {
+                    Ok(_) => {
+                        self.backoff_cnt = 0;
+                        self.failure_cnt = 0;
+                        state.revoked.lock().unwrap().remove(&self.domains);
+                        for domain in &domains {
+                            self.domain_backoff.remove(domain);
+                        }
+                        self.update_backed_off_domains();
+                        self.schedule_domain_retry();
+                    }
+                    Err(_) => self.back_off(),
+                }
+                event
+            }
+            Err(err) => {
+                match failed_domain {
+                    Some(domain) if domains.len() > 1 => self.back_off_domain(domain),
+                    _ => self.back_off(),
+                }
+                self.honor_retry_after(&directory.url, &err);
+                Err(EventError::Order(err))
+            }
+        }
+    }
+
+    /// The private key of the cached certificate of `domains`, to renew it with if keys are
+    /// reused, it is of the configured algorithm, and the certificate wasn't revoked.
+    async fn current_key(
+        state: &AcmeState<EC, EA>,
+        directory: &DirectoryState<EC, EA>,
+        domains: &[String],
+    ) -> Option<rcgen::KeyPair> {
+        if !state.config.reuse_cert_key || state.revoked.lock().unwrap().contains(domains) {
+            return None;
+        }
+        let pem = state.load_cert(directory, domains).await.ok()??;
+        let (pem, _) = format::read(pem, EntryKind::Cert).ok()?;
+        let key = format::parse_pems(&pem).ok()?.into_iter().next()?;
+        let key_pair = rcgen::KeyPair::from_der(&key.contents).ok()?;
+        let algorithm = state.config.cert_key_algorithm.signature_algorithm();
+        if !key_pair.is_compatible(algorithm) {
+            info!("current key is not of the configured algorithm; generating a new one");
+            return None;
+        }
+        info!("reusing the key of the current certificate");
+        Some(key_pair)
+    }
+
+    /// Deploy the cached certificate instead of ordering one if another process sharing the cache
+    /// renewed it since this one was loaded, going by it differing from the current one and not
+    /// being due for renewal.
+    async fn load_renewed(
+        &mut self,
+        state: &AcmeState<EC, EA>,
+        directory: &DirectoryState<EC, EA>,
+    ) -> Option<Event<EC, EA>> {
+        let pem = state.load_cert(directory, &self.domains).await.ok()??;
+        let (pem, _) = format::read(pem, EntryKind::Cert).ok()?;
+        if self.current.as_ref() == Some(&pem) {
+            return None;
+        }
+        state.load_remote_key(&pem).await;
+        let (_, validity, _) = Self::parse_cert(&pem, &state.remote_keys).ok()?;
+        if validity.renewal_time(&state.config.renewal_policy) <= self.now() {
+            return None;
+        }
+        info!("certificate was renewed by another process; deploying it from the cache");
+        Some(self.process_cert(pem, CertSource::Cache))
+    }
+
+    /// Obtain a certificate for `domains` from the staging directory, before the first production
+    /// order. The certificate is discarded; only whether the order succeeds matters.
+    async fn staging_check(
+        &mut self,
+        staging: &DirectoryState<EC, EA>,
+        domains: &[String],
+    ) -> Event<EC, EA> {
+        let state = self.state.clone();
+        let account_key = match state.account_key(staging).await {
+            Ok(account_key) => account_key,
+            Err(event) => return event,
+        };
+        let mut failed_domain = None;
+        let result = {
+            let _permit = state.orders.acquire().await;
+            let now = self.now();
+            state
+                .config
+                .retry_policy
+                .record_attempt(&mut self.attempts, now);
+            self.order_url = None;
+            Self::order(
+                &state,
+                staging,
+                domains,
+                &account_key,
+                &mut self.order_url,
+                &mut failed_domain,
+                &mut self.account_registered,
+            )
+            .await
+        };
+        match result {
+            Ok(_) => {
+                info!("staging check passed; ordering from production");
+                self.staged = true;
+                Ok(EventOk::StagingCheckPassed)
+            }
+            Err(err) => {
+                match failed_domain {
+                    Some(domain) if domains.len() > 1 => self.back_off_domain(domain),
+                    _ => self.back_off(),
+                }
+                self.honor_retry_after(&staging.url, &err);
+                Err(EventError::StagingCheck(err))
+            }
+        }
+    }
+
+    /// Schedule a retry after a failed order, backing off exponentially as the retry policy
+    /// says.
+    fn back_off(&mut self) {
+        let policy = &self.state.config.retry_policy;
+        let at = self.now() + policy.delay(self.backoff_cnt);
+        let at = policy.next_attempt(&self.attempts, at);
+        self.schedule(at);
+        self.backoff_cnt = (self.backoff_cnt + 1).min(31);
+        self.failure_cnt = self.backoff_cnt;
+    }
+
+    /// Delay the next order until the CA asked to be retried, if it did and that's after the
+    /// backoff, and hold off other orders to the directory until then too.
+    fn honor_retry_after(&mut self, directory_url: &str, err: &OrderError) {
+        let retry_after = match err.retry_after() {
+            Some(retry_after) => retry_after,
+            None => return,
+        };
+        let budgets = &self.state.registry.rate_budgets;
+        budgets.record_retry_after(directory_url, retry_after);
+        if self.renew_at.is_some_and(|at| at < retry_after) {
+            self.schedule(retry_after);
+        }
+    }
+
+    /// Back off exponentially for a domain that failed validation. If the certificate being
+    /// served outlasts the backoff, the next order is simply delayed until then; otherwise, the
+    /// domain is left out of orders until then, and the other domains are ordered right away.
+    fn back_off_domain(&mut self, domain: String) {
+        let now = self.now();
+        let backoff = self.domain_backoff.entry(domain).or_insert(DomainBackoff {
+            failures: 0,
+            until: now,
+        });
+        let policy = &self.state.config.retry_policy;
+        backoff.until = now + policy.delay(backoff.failures);
+        backoff.failures = (backoff.failures + 1).min(31);
+        self.failure_cnt = backoff.failures;
+        let until = backoff.until;
+        self.update_backed_off_domains();
+        let at = match self.not_after {
+            Some(not_after) if not_after > until => until,
+            _ => now,
+        };
+        let at = self
+            .state
+            .config
+            .retry_policy
+            .next_attempt(&self.attempts, at);
+        self.schedule(at);
+    }
+
+    /// The domains to order a certificate for: all except those backing off. Backoff never leaves
+    /// out every domain, but if it did, all domains are ordered.
+    fn order_domains(&self) -> Vec<String> {
+        let now = self.now();
+        let domains: Vec<String> = self
+            .domains
+            .iter()
+            .filter(|domain| {
+                self.domain_backoff
+                    .get(*domain)
+                    .is_none_or(|backoff| backoff.until <= now)
+            })
+            .cloned()
+            .collect();
+        match domains.is_empty() {
+            true => self.domains.clone(),
+            false => domains,
+        }
+    }
+
+    /// Bring the next order forward to when the first domain left out of the certificate is due
+    /// to be retried, if that is before the scheduled renewal.
+    fn schedule_domain_retry(&mut self) {
+        let retry = match self
+            .domain_backoff
+            .values()
+            .map(|backoff| backoff.until)
+            .min()
+        {
+            Some(retry) => retry,
+            None => return,
+        };
+        if self.renew_at.is_none_or(|renewal| retry < renewal) {
+            self.schedule(retry);
+        }
+    }
+
+    fn update_backed_off_domains(&self) {
+        let mut domains: Vec<String> = self.domain_backoff.keys().cloned().collect();
+        domains.sort();
+        self.state
+            .registry
+            .update(self.index, |status| status.backed_off_domains = domains);
+    }
+
+    /// Check that a newly issued certificate is fit to serve: it covers every requested domain,
+    /// each certificate in the chain is issued by the next, and it hasn't expired.
+    fn check_new_cert(
+        pem: &[u8],
+        domains: &[String],
+        now: SystemTime,
+    ) -> Result<(), CertParseError> {
+        let pems = format::parse_pems(pem)?;
+        let ders: Vec<&[u8]> = pems
+            .iter()
+            .filter(|p| p.tag == "CERTIFICATE")
+            .map(|p| p.contents.as_slice())
+            .collect();
+        let certs = ders
+            .iter()
+            .map(|der| Ok(parse_x509_certificate(der)?.1))
+            .collect::<Result<Vec<_>, CertParseError>>()?;
+        let leaf = certs.first().ok_or(CertParseError::TooFewPem(pems.len()))?;
+
+        let sans: Vec<String> = match leaf.subject_alternative_name() {
+            Ok(Some(ext)) => ext
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name) => Some(name.to_ascii_lowercase()),
+                    GeneralName::IPAddress(octets) => {
+                        ip::from_octets(octets).map(|ip| ip.to_string())
+                    }
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
+        };
+        if let Some(missing) = domains.iter().find(|domain| {
+            let name = match ip::parse(domain) {
+                Some(ip) => ip.to_string(),
+                None => domain.to_ascii_lowercase(),
+            };
+            !sans.contains(&name)
+        }) {
+            return Err(CertParseError::MissingSan(missing.clone()));
+        }
+
+        for (i, pair) in certs.windows(2).enumerate() {
+            if pair[0].issuer().as_raw() != pair[1].subject().as_raw() {
+                return Err(CertParseError::ChainOrder(i));
+            }
+        }
+
+        let not_after = leaf.validity().not_after.timestamp().max(0) as u64;
+        if UNIX_EPOCH + Duration::from_secs(not_after) <= now {
+            return Err(CertParseError::Expired);
+        }
+        Ok(())
+    }
+
+    async fn command(&mut self, command: Command) -> Event<EC, EA> {
+        match command {
+            Command::Rollback => {
+                if self.previous.is_empty() {
+                    return Ok(EventOk::NoPreviousCert);
+                }
+                let pem = self.previous.remove(0);
+                self.update_previous_count();
+                self.process_cert(pem, CertSource::Previous)
+            }
+            Command::Renew => {
+                self.renew_at = None;
+                self.state
+                    .registry
+                    .update(self.index, |status| status.renew_at = None);
+                Ok(EventOk::RenewalRequested)
+            }
+            Command::Reload => {
+                let state = self.state.clone();
+                let directory = &state.directory(self.directory);
+                match self.load_renewed(&state, directory).await {
+                    Some(event) => event,
+                    None => Ok(EventOk::CacheUnchanged),
+                }
+            }
+            Command::Reencrypt => {
+                // Loading an entry through an `EncryptedCache` re-encrypts it under the current
+                // key, so loading every entry of the certificate is enough.
+                let state = self.state.clone();
+                let directory = &state.directory(self.directory);
+                let mut keys = vec![self.domains.clone(), order_key(&self.domains)];
+                keys.extend(
+                    (1..=state.config.previous_certs).map(|n| previous_key(n, &self.domains)),
+                );
+                for key in &keys {
+                    state
+                        .load_cert(directory, key)
+                        .await
+                        .map_err(EventError::CertCacheLoad)?;
+                }
+                if directory.source.is_none() {
+                    let contact = state.contact(directory);
+                    state
+                        .load_account(directory, &contact)
+                        .await
+                        .map_err(EventError::AccountCacheLoad)?;
+                }
+                Ok(EventOk::CacheReencrypted)
+            }
+            Command::RemoveDomain(domain) => {
+                self.state
+                    .resolver
+                    .remove_names(std::slice::from_ref(&domain));
+                if self.domains == [domain.as_str()] {
+                    self.removed = true;
+                    self.state.registry.remove(self.index);
+                    return Ok(EventOk::CertRemoved);
+                }
+                self.domains.retain(|d| *d != domain);
+                self.domain_backoff.remove(&domain);
+                self.update_backed_off_domains();
+                let domains = self.domains.clone();
+                self.renew_at = None;
+                self.state.registry.update(self.index, |status| {
+                    status.domains = domains;
+                    status.renew_at = None;
+                });
+                Ok(EventOk::DomainRemoved)
+            }
+            Command::Revoke(reason) => self.revoke(reason).await,
+            Command::Retire => {
+                self.removed = true;
+                self.state.registry.remove(self.index);
+                Ok(EventOk::CertRemoved)
+            }
+            #[cfg(feature = "simulate-failure")]
+            Command::SimulateFailure(message) => {
+                warn!(%message, "simulating a renewal failure");
+                Err(EventError::Order(OrderError::Simulated(message)))
+            }
+        }
+    }
+
+    /// Revoke the certificate being served for `reason`, and order its replacement now, with a
+    /// new key.
+    async fn revoke(&mut self, reason: RevocationReason) -> Event<EC, EA> {
+        let state = self.state.clone();
+        let directory = &state.directory(self.directory);
+        let leaf = self
+            .current
+            .as_ref()
+            .and_then(|pem| format::parse_pems(pem).ok())
+            .and_then(|pems| pems.into_iter().nth(1));
+        let leaf = match (leaf, &directory.source) {
+            (Some(leaf), None) => leaf,
+            _ => return Ok(EventOk::NoCertToRevoke),
+        };
+        // The first call may only report storing a migrated key.
+        let account_key = match state.account_key(directory).await {
+            Ok(account_key) => Some(account_key),
+            Err(Ok(_)) => None,
+            Err(event) => return event,
+        };
+        let account_key = match account_key {
+            Some(account_key) => account_key,
+            None => match state.account_key(directory).await {
+                Ok(account_key) => account_key,
+                Err(event) => return event,
+            },
+        };
+        let result = async {
+            let account = Self::account(
+                &state,
+                directory,
+                &account_key,
+                &mut self.account_registered,
+            )
+            .await
+            .map_err(OrderError::Account)?;
+            account
+                .revoke_cert(&leaf.contents, reason)
+                .await
+                .map_err(OrderError::Acme)
+        }
+        .await;
+        if let Err(err) = result {
+            return Err(EventError::Revocation(err));
+        }
+        warn!(?reason, "revoked the certificate; ordering a replacement");
+        state.revoked.lock().unwrap().insert(self.domains.clone());
+        self.renew_at = None;
+        self.state
+            .registry
+            .update(self.index, |status| status.renew_at = None);
+        Ok(EventOk::CertRevoked)
+    }
+
+    /// Load the previously served certificates from the cache, skipping any that are missing or
+    /// can't be used.
+    async fn load_previous(&mut self) {
+        let state = self.state.clone();
+        let directory = &state.directory(self.directory);
+        for n in 1..=state.config.previous_certs {
+            let key = previous_key(n, &self.domains);
+            let pem = match state.load_cert(directory, &key).await {
+                Ok(Some(pem)) => pem,
+                Ok(None) => continue,
+                Err(err) => {
+                    warn!(?err, n, "failed to load previous certificate");
+                    continue;
+                }
+            };
+            let read = format::read(pem, EntryKind::Cert);
+            if let Ok((pem, _)) = &read {
+                state.load_remote_key(pem).await;
+            }
+            match read {
+                Ok((pem, migrated)) if Self::parse_cert(&pem, &state.remote_keys).is_ok() => {
+                    self.previous.push(pem);
+                    self.store_previous |= migrated;
+                }
+                Ok(_) => {}
+                Err(err) => warn!(%err, n, "failed to read previous certificate"),
+            }
+        }
+        self.update_previous_count();
+    }
+
+    fn update_previous_count(&self) {
+        let count = self.previous.len();
+        self.state
+            .registry
+            .update(self.index, |status| status.previous_certs = count);
+    }
+
+    /// Schedule the next order for the specified time.
+    fn schedule(&mut self, at: SystemTime) {
+        self.renew_at = Some(at);
+        self.state
+            .registry
+            .update(self.index, |status| status.renew_at = Some(at));
+    }
+
+    /// Parse a certificate as kept in the cache: its private key followed by its chain, or the
+    /// chain alone if the key is held by the key store, in which case it is found in
+    /// `remote_keys`.
+    fn parse_cert(
+        pem: &[u8],
+        remote_keys: &RemoteKeys,
+    ) -> Result<(CertifiedKey, Validity, CertMaterial), CertParseError> {
+        let mut pems = format::parse_pems(pem)?;
+        let keyless = pems.first().is_some_and(|p| p.tag == "CERTIFICATE");
+        if pems.len() < if keyless { 1 } else { 2 } {
+            return Err(CertParseError::TooFewPem(pems.len()));
+        }
+        let key = if keyless {
+            Zeroizing::default()
+        } else {
+            pems.remove(0).contents
+        };
+        let chain: Vec<Vec<u8>> = pems.iter().map(|p| p.contents.to_vec()).collect();
+        let cert_chain: Vec<Certificate> = chain.iter().cloned().map(Certificate).collect();
+        let (_, cert) = parse_x509_certificate(&cert_chain[0].0)?;
+        let public_key = cert.public_key().subject_public_key.data;
+        let pk = if keyless {
+            let remote = remote_keys
+                .get(public_key)
+                .ok_or(CertParseError::RemoteKeyMissing)?;
+            key_store::signing_key(remote)
+        } else {
+            let mut private_key = PrivateKey(key.to_vec());
+            let pk = any_supported_type(&private_key);
+            private_key.0.zeroize();
+            let pk = pk.map_err(|()| CertParseError::InvalidPrivateKey)?;
+            if !key_matches(&key, public_key) {
+                return Err(CertParseError::KeyMismatch);
+            }
+            pk
+        };
+        let time = |t: x509_parser::time::ASN1Time| {
+            UNIX_EPOCH + Duration::from_secs(t.timestamp().max(0) as u64)
+        };
+        let validity = Validity {
+            not_before: time(cert.validity().not_before),
+            not_after: time(cert.validity().not_after),
+        };
+        let certified_key = CertifiedKey::new(cert_chain, Arc::new(pk));
+        let material = CertMaterial {
+            chain,
+            key,
+            certified_key: certified_key.clone(),
+        };
+        Ok((certified_key, validity, material))
+    }
+
+    fn process_cert(&mut self, pem: Zeroizing<Vec<u8>>, source: CertSource) -> Event<EC, EA> {
+        let parsed = Self::parse_cert(&pem, &self.state.remote_keys);
+        let (cert, validity, material) = match (parsed, source) {
+            (Ok(r), _) => r,
+            (Err(err), CertSource::Cache) => {
+                self.quarantine_cert = Some(pem);
+                return Err(EventError::CachedCertParse(err));
+            }
+            (Err(err), CertSource::Order) => return Err(EventError::NewCertParse(err)),
+            (Err(err), CertSource::Previous) => {
+                // Drop the unusable certificate from the cache too.
+                self.store_previous = true;
+                return Err(EventError::PreviousCertParse(err));
+            }
+        };
+        // Guard against caches not keeping directories apart serving staging certificates.
+        if source != CertSource::Order
+            && staging_issued(&material.chain[0])
+            && !is_staging_directory(&self.state.directory(self.directory).url)
+        {
+            let err = CertParseError::StagingIssuer;
+            return match source {
+                CertSource::Previous => {
+                    self.store_previous = true;
+                    Err(EventError::PreviousCertParse(err))
+                }
+                _ => {
+                    self.quarantine_cert = Some(pem);
+                    Err(EventError::CachedCertParse(err))
+                }
+            };
+        }
+        self.state
+            .resolver
+            .set_cert(self.index, &self.domains, cert);
+        self.state.registry.set_material(self.index, material);
+        self.not_after = Some(validity.not_after);
+        self.state.registry.update(self.index, |status| {
+            status.not_before = Some(validity.not_before);
+            status.not_after = Some(validity.not_after);
+        });
+        self.schedule(validity.renewal_time(&self.state.config.renewal_policy));
+        if self.state.config.ocsp_stapling {
+            self.ocsp_refresh_at = Some(self.now());
+        }
+        self.ocsp_expires_at = None;
+        self.ocsp_stale = false;
+        self.rotation = self.state.registry.notify(self.index);
+        let replaced = self.current.replace(pem.clone());
+        match source {
+            CertSource::Cache => Ok(EventOk::DeployedCachedCert),
+            CertSource::Order => {
+                if let Some(replaced) = replaced {
+                    self.previous.insert(0, replaced);
+                    self.previous.truncate(self.state.config.previous_certs);
+                    self.update_previous_count();
+                }
+                self.store_cert = Some(pem);
+                self.store_previous = true;
+                Ok(EventOk::DeployedNewCert)
+            }
+            CertSource::Previous => {
+                self.store_cert = Some(pem);
+                self.store_previous = true;
+                Ok(EventOk::DeployedPreviousCert)
+            }
+        }
+    }
+
+    /// Fetch an OCSP response for the certificate being served and staple it, keeping the last
+    /// good response until it expires if that fails.
+    async fn staple_ocsp(&mut self) -> Event<EC, EA> {
+        self.ocsp_refresh_at = None;
+        let material = match self.state.registry.material_at(self.index) {
+            Some(material) => material,
+            // Stapling is only scheduled once a certificate is deployed.
+            None => return Ok(EventOk::OcspStapled),
+        };
+        let response = match ocsp::fetch(&material.chain).await {
+            Ok(response) => response,
+            Err(OcspError::Revoked) => {
+                warn!("the OCSP responder reports the certificate revoked; ordering a replacement");
+                self.schedule(self.now());
+                return Err(EventError::Ocsp(OcspError::Revoked));
+            }
+            Err(err) => {
+                self.ocsp_refresh_at = Some(self.now() + OCSP_RETRY);
+                return Err(EventError::Ocsp(err));
+            }
+        };
+        info!(refresh_at = ?response.refresh_at, "stapling a fresh OCSP response");
+        let mut certified_key = material.certified_key.clone();
+        certified_key.ocsp = Some(response.der);
+        self.state
+            .resolver
+            .set_cert(self.index, &self.domains, certified_key.clone());
+        self.state.registry.set_material(
+            self.index,
+            CertMaterial {
+                chain: material.chain.clone(),
+                key: material.key.clone(),
+                certified_key,
+            },
+        );
+        // OCSP responses are valid by the system clock; wait as long by the configured one.
+        let by_clock =
+            |at: SystemTime| self.now() + at.duration_since(SystemTime::now()).unwrap_or_default();
+        let refresh_at = by_clock(response.refresh_at);
+        let expires_at = response.expires_at.map(by_clock);
+        self.ocsp_refresh_at = Some(refresh_at);
+        self.ocsp_expires_at = expires_at;
+        self.ocsp_stale = false;
+        Ok(EventOk::OcspStapled)
+    }
+
+    /// Apply the [stale OCSP policy](AcmeConfig::stale_ocsp_policy) once the stapled response
+    /// expired without a fresh one, or its stale window ended.
+    fn expire_ocsp(&mut self) -> Event<EC, EA> {
+        let expired_at = self.ocsp_expires_at.take();
+        let material = match self.state.registry.material_at(self.index) {
+            Some(material) => material,
+            None => return Ok(EventOk::OcspStapleDropped),
+        };
+        match self.state.config.stale_ocsp_policy {
+            StaleOcspPolicy::ServeStale(window) if !self.ocsp_stale => {
+                warn!(
+                    ?window,
+                    "the OCSP response expired; stapling it for a while longer"
+                );
+                self.ocsp_stale = true;
+                self.ocsp_expires_at = expired_at.map(|at| at + window);
+                return Ok(EventOk::OcspStaleServed);
+            }
+            StaleOcspPolicy::FailMustStaple
+                if material
+                    .chain
+                    .first()
+                    .is_some_and(|leaf| ocsp::is_must_staple(leaf)) =>
+            {
+                warn!("the OCSP response for the must-staple certificate expired; no longer serving it");
+                self.state.resolver.remove_names(&self.domains);
+                return Ok(EventOk::OcspCertWithheld);
+            }
+            _ => {}
+        }
+        warn!("the OCSP response expired; serving the certificate without a staple");
+        let mut certified_key = material.certified_key.clone();
+        certified_key.ocsp = None;
+        self.state
+            .resolver
+            .set_cert(self.index, &self.domains, certified_key.clone());
+        self.state.registry.set_material(
+            self.index,
+            CertMaterial {
+                chain: material.chain.clone(),
+                key: material.key.clone(),
+                certified_key,
+            },
+        );
+        Ok(EventOk::OcspStapleDropped)
+    }
+
+    /// Warn if a newly issued certificate isn't valid yet by our clock, which points to clock
+    /// skew, and wait until it is if configured to.
+    async fn wait_for_not_before(&self, pem: &[u8]) {
+        let not_before = match Self::parse_cert(pem, &self.state.remote_keys) {
+            Ok((_, validity, _)) => validity.not_before,
+            Err(_) => return,
+        };
+        let early = match not_before.duration_since(self.now()) {
+            Ok(early) => early,
+            Err(_) => return,
+        };
+        let wait = self.state.config.wait_for_not_before;
+        warn!(
+            early_secs = early.as_secs(),
+            wait, "new certificate is not valid yet; the local clock is probably behind"
+        );
+        if wait {
+            self.state.config.clock.sleep(early).await;
+        }
+    }
+
+    /// Generate the certificate to request, as customized in `csr`, with a new key in the key
+    /// store if there is one, else the key the key provider supplies, if any, else the current
+    /// key if it's to be reused, else a new key, and hand its key to the key provider to escrow.
+    /// Returns the algorithm the certificate's requests are signed with, and the key in the key
+    /// store, if the key is there, alongside it.
+    ///
+    /// An order resumed after finalizing needs the key its request was signed with, so
+    /// `resumed_key`, if given, is used as is, and not handed to the key provider again.
+    async fn certificate(
+        state: &AcmeState<EC, EA>,
+        directory: &DirectoryState<EC, EA>,
+        csr: &Csr,
+        resumed_key: Option<CsrKey>,
+    ) -> Result<
+        (
+            rcgen::Certificate,
+            &'static SignatureAlgorithm,
+            Option<Arc<dyn RemoteKey>>,
+        ),
+        OrderError,
+    > {
+        let config = &state.config;
+        let domains = csr.domains();
+        let mut params = CertificateParams::new(vec![]);
+        params.subject_alt_names = ip::subject_alt_names(&csr.names());
+        params.distinguished_name = csr.distinguished_name();
+        params.alg = config.cert_key_algorithm.signature_algorithm();
+        let has_key = resumed_key.is_some();
+        let mut remote_key = None;
+        let key_pair = match resumed_key {
+            Some(CsrKey::Local(key_pair)) => *key_pair,
+            Some(CsrKey::Remote(key)) => {
+                remote_key = Some(key.clone());
+                key_store::rcgen_key_pair(key)?
+            }
+            None => match &config.key_store {
+                Some(store) => {
+                    let key = store
+                        .create_key(domains)
+                        .await
+                        .map_err(OrderError::KeyStore)?;
+                    remote_key = Some(key.clone());
+                    key_store::rcgen_key_pair(key)?
+                }
+                None => match Self::current_key(state, directory, domains).await {
+                    Some(key_pair) => key_pair,
+                    None => config.cert_key_algorithm.generate()?,
+                },
+            },
+        };
+        if has_key || remote_key.is_some() {
+            if let Some(alg) = key_pair.compatible_algs().next() {
+                params.alg = alg;
+            }
+        }
+        params.key_pair = Some(key_pair);
+        // Keys are only handed to the key provider if held here.
+        let key_provider = config
+            .key_provider
+            .as_ref()
+            .filter(|_| !has_key && remote_key.is_none());
+        if let Some(provider) = key_provider {
+            if let Some(key) = provider
+                .supply_key(domains)
+                .await
+                .map_err(OrderError::KeyProvider)?
+            {
+                let key = Zeroizing::new(key);
+                let key_pair = rcgen::KeyPair::from_der(&key)?;
+                if let Some(alg) = key_pair.compatible_algs().next() {
+                    params.alg = alg;
+                }
+                params.key_pair = Some(key_pair);
+            }
+        }
+        let alg = params.alg;
+        let cert = rcgen::Certificate::from_params(params)?;
+        if let Some(provider) = key_provider {
+            provider
+                .key_generated(domains, &Zeroizing::new(cert.serialize_private_key_der()))
+                .await
+                .map_err(OrderError::KeyProvider)?;
+        }
+        Ok((cert, alg, remote_key))
+    }
+
+    /// Discover the directory and find or register the account of `key_pair` with it.
+    async fn account(
+        state: &AcmeState<EC, EA>,
+        directory_state: &DirectoryState<EC, EA>,
+        key_pair: &[u8],
+        account_registered: &mut bool,
+    ) -> Result<Account, AcmeError> {
+        let directory_url = directory_state.url.as_str();
+        let directory = Directory::discover(directory_url, &state.config.directory_tls).await?;
+        if let Some(skew) = directory.clock_skew {
+            if skew.abs() > MAX_CLOCK_SKEW_SECS {
+                warn!(
+                    skew_secs = skew,
+                    "the local clock differs from the CA's by more than {} seconds; certificates \
+                     may appear not yet valid or expired",
+                    MAX_CLOCK_SKEW_SECS
+                );
+            }
+            state.registry.set_clock_skew(skew);
+        }
+        let key = AccountKey::from_pkcs8(state.config.account_key_algorithm, key_pair)?;
+        let (account, registered) = Account::create(
+            directory,
+            &state.contact(directory_state),
+            directory_state.external_account_key.as_ref(),
+            key,
+        )
+        .await?;
+        if registered {
+            info!(directory_url, "registered a new account");
+            *account_registered = true;
+        }
+        Ok(account)
+    }
+
+    async fn order(
+        state: &AcmeState<EC, EA>,
+        directory_state: &DirectoryState<EC, EA>,
+        domains: &[String],
+        key_pair: &[u8],
+        order_url: &mut Option<String>,
+        failed_domain: &mut Option<String>,
+        account_registered: &mut bool,
+    ) -> Result<Zeroizing<Vec<u8>>, OrderError> {
+        let config = &state.config;
+        let responders = &state.responders;
+        let directory_url = directory_state.url.as_str();
+        let mut csr = Csr::new(domains);
+        if let Some(customize) = &config.customize_csr {
+            customize(&mut csr);
+            let dropped = csr.limit_names(config.max_sans_per_cert.max(1));
+            if !dropped.is_empty() {
+                warn!(
+                    ?dropped,
+                    max_sans = config.max_sans_per_cert,
+                    "names added to the certificate signing request exceed the SAN limit; \
+                     leaving them out"
+                );
+            }
+        }
+        // Orders in progress are kept by the names they were created for, so one resumed covers
+        // the names added to the request as well.
+        let names = csr.names();
+        state.preflight_check(&names).await?;
+        let account = Self::account(state, directory_state, key_pair, account_registered)
+            .await
+            .map_err(OrderError::Account)?;
+
+        let store = config.key_store.as_deref();
+        let (resumed, resumed_key) =
+            match Self::resume_order(state, directory_state, &account, &names, store).await {
+                Some((url, order, key)) => (Some((url, order)), key),
+                None => (None, None),
+            };
+        let has_key = resumed_key.is_some();
+        let (cert, alg, remote_key) =
+            Self::certificate(state, directory_state, &csr, resumed_key).await?;
+
+        // Certificates with a key in the key store are kept without it, and their orders with its
+        // public key instead.
+        let (key, kept_key) = match &remote_key {
+            Some(remote_key) => {
+                state.remote_keys.insert(remote_key.clone());
+                let public_key = Zeroizing::new(key_store::public_key_pem(remote_key.as_ref()));
+                (Zeroizing::default(), public_key)
+            }
+            None => {
+                let key = Zeroizing::new(cert.serialize_private_key_pem());
+                (key.clone(), key)
+            }
+        };
+        let (url, mut order) = match resumed {
+            Some(resumed) => resumed,
+            None => {
+                state.registry.pacer.wait().await;
+                state.registry.rate_budgets.record_order(directory_url);
+                let (url, order) = account.new_order(&names, config.profile.as_deref()).await?;
+                info!(order_url = %url, "created order");
+                (url, order)
+            }
+        };
+        if !has_key {
+            Self::store_order(state, directory_state, &names, &url, &kept_key).await;
+        }
+        Span::current().record("order_url", url.as_str());
+        *order_url = Some(url.clone());
+        let mut processing_attempts = 0u32;
+        // When the CA asked for the order to be polled again, while it is processing it.
+        let mut retry_after = None;
+        loop {
+            order = match order {
+                Order::Pending {
+                    authorizations,
+                    finalize,
+                } => {
+                    let auth_futures = authorizations
+                        .iter()
+                        .map(|url| Self::authorize(responders, &account, url));
+                    if let Err((domain, err)) = try_join_all(auth_futures).await {
+                        if let (Some(domain), OrderError::BadAuth(_)) = (&domain, &err) {
+                            let budgets = &state.registry.rate_budgets;
+                            budgets.record_failed_validation(directory_url, domain);
+                        }
+                        *failed_domain = domain;
+                        return Err(err);
+                    }
+                    info!("completed all authorizations");
+                    Order::Ready { finalize }
+                }
+                Order::Ready { finalize } => {
+                    info!("sending csr");
+                    let mut csr = cert.serialize_request_der()?;
+                    if config.must_staple && remote_key.is_some() {
+                        let err = "must-staple isn't supported with keys in a key store";
+                        return Err(OrderError::KeyStore(err.into()));
+                    }
+                    if config.must_staple {
+                        let key = Zeroizing::new(cert.serialize_private_key_der());
+                        csr = ocsp::must_staple_csr(&csr, &key, alg)?;
+                    }
+                    let (order, after) = account.finalize(finalize, csr).await?;
+                    retry_after = after;
+                    order
+                }
+                Order::Processing if processing_attempts < 5 => {
+                    let now = SystemTime::now();
+                    task::sleep(poll_delay(processing_attempts, retry_after, now)).await;
+                    processing_attempts += 1;
+                    let (order, after) = account.poll_order(&url).await?;
+                    retry_after = after;
+                    order
+                }
+                Order::Processing => return Err(OrderError::TooManyAttemptsOrder),
+                Order::Valid { certificate } => {
+                    info!("download certificate");
+                    let pem = [
+                        key.as_str(),
+                        "\n",
+                        &account
+                            .certificate(certificate, config.preferred_chain.as_deref())
+                            .await?,
+                    ]
+                    .concat();
+                    Self::store_order(state, directory_state, &names, "", "").await;
+                    let budgets = &state.registry.rate_budgets;
+                    budgets.record_issued(directory_url, &names);
+                    return Ok(Zeroizing::new(pem.into_bytes()));
+                }
+                Order::Invalid => return Err(OrderError::BadOrder(order)),
+            }
+        }
+    }
+
+    /// Look up the order in progress for `names` kept in the cache, and return it if it can be
+    /// resumed, along with the key of its certificate signing request if kept: if it is still
+    /// pending or ready, or, with the key, further along. Expired and failed orders can't be.
+    ///
+    /// Nothing else about its challenges needs keeping: their key authorizations follow from the
+    /// account key, so pending ones are simply answered again.
+    async fn resume_order(
+        state: &AcmeState<EC, EA>,
+        directory: &DirectoryState<EC, EA>,
+        account: &Account,
+        names: &[String],
+        key_store: Option<&dyn KeyStore>,
+    ) -> Option<(String, Order, Option<CsrKey>)> {
+        let (url, key) = Self::load_order(state, directory, names).await?;
+        let key = match key {
+            Some(key) => CsrKey::load(&key, key_store).await,
+            None => None,
+        };
+        match account.order(&url).await {
+            Ok(order @ (Order::Pending { .. } | Order::Ready { .. })) => {
+                info!(order_url = %url, "resuming order");
+                Some((url, order, key))
+            }
+            Ok(order @ (Order::Processing | Order::Valid { .. })) if key.is_some() => {
+                info!(order_url = %url, "resuming finalized order");
+                Some((url, order, key))
+            }
+            Ok(_) => None,
+            Err(err) => {
+                info!(order_url = %url, %err, "order in progress can't be resumed");
+                None
+            }
+        }
+    }
+
+    /// Load the order in progress for `names` kept in the cache: its URL, and the key of its
+    /// certificate signing request if kept.
+    async fn load_order(
+        state: &AcmeState<EC, EA>,
+        directory: &DirectoryState<EC, EA>,
+        names: &[String],
+    ) -> Option<(String, Option<format::Pem>)> {
+        let entry = match state.load_cert(directory, &order_key(names)).await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return None,
+            Err(err) => {
+                warn!(?err, "failed to load the order in progress");
+                return None;
+            }
+        };
+        let order = match format::read(entry, EntryKind::Order) {
+            Ok((entry, _)) if entry.is_empty() => return None,
+            Ok((entry, _)) => format::order(&entry),
+            Err(err) => Err(err),
+        };
+        let order = match order {
+            Ok(order) if !order.url.is_empty() => order,
+            Ok(_) => return None,
+            Err(err) => {
+                warn!(%err, "failed to read the order in progress");
+                return None;
+            }
+        };
+        let key = order
+            .key
+            .as_deref()
+            .and_then(|key| format::parse_pem(key).ok());
+        Some((order.url.clone(), key))
+    }
+
+    /// Keep the order in progress for `names` in the cache, stored as certificates are: its URL
+    /// along with `key`, the PEM-encoded key of its certificate signing request, so that an
+    /// order interrupted after finalizing can be completed after a restart. Clears it if `url`
+    /// is empty.
+    async fn store_order(
+        state: &AcmeState<EC, EA>,
+        directory: &DirectoryState<EC, EA>,
+        names: &[String],
+        url: &str,
+        key: &str,
+    ) {
+        let entry = if url.is_empty() {
+            format::stamp(b"")
+        } else {
+            let order = format::OrderEntry {
+                url: url.to_owned(),
+                key: Some(key.to_owned()).filter(|key| !key.is_empty()),
+            };
+            format::stamp(&format::order_entry(&order))
+        };
+        if let Err(err) = state.store_cert(directory, &order_key(names), &entry).await {
+            warn!(?err, "failed to store the order in progress");
+        }
+    }
+
+    /// Complete an authorization. On failure, returns the domain it was for along with the error,
+    /// if the domain was known by then.
+    async fn authorize(
+        responders: &[Arc<dyn ChallengeResponder>],
+        account: &Account,
+        url: &str,
+    ) -> Result<(), (Option<String>, OrderError)> {
+        let (domain, challenges) = match account.auth(url).await {
+            // Name wildcard domains as configured, so backoff and responders see them as such.
+            Ok(Auth::Pending {
+                identifier: Identifier::Dns(domain),
+                wildcard: true,
+                challenges,
+            }) => (format!("*.{}", domain), challenges),
+            Ok(Auth::Pending {
+                identifier: Identifier::Dns(domain) | Identifier::Ip(domain),
+                challenges,
+                ..
+            }) => (domain, challenges),
+            Ok(Auth::Valid) => return Ok(()),
+            Ok(auth) => return Err((None, OrderError::BadAuth(auth))),
+            Err(err) => return Err((None, err.into())),
+        };
+        Self::respond(responders, account, url, &domain, &challenges)
+            .await
+            .map_err(|err| (Some(domain), err))
+    }
+
+    /// Answer a challenge for a pending authorization, and have the CA validate it.
+    async fn respond(
+        responders: &[Arc<dyn ChallengeResponder>],
+        account: &Account,
+        url: &str,
+        domain: &str,
+        challenges: &[Challenge],
+    ) -> Result<(), OrderError> {
+        let (responder, challenge) = responders
+            .iter()
+            .find_map(|responder| {
+                let challenge = challenges
+                    .iter()
+                    .find(|c| c.typ == responder.challenge_type())?;
+                Some((responder, challenge))
+            })
+            .ok_or(AcmeError::NoSupportedChallenge)?;
+        Span::current().record("challenge_type", challenge.typ.as_str());
+        info!(%domain, challenge_type = %challenge.typ, "trigger challenge");
+        let key_authorization = account.key_authorization(&challenge.token)?;
+        responder
+            .present(domain, &challenge.token, &key_authorization)
+            .await
+            .map_err(OrderError::Challenge)?;
+        let result = Self::validate(account, url, domain, &challenge.url).await;
+        if let Err(err) = responder.cleanup(domain, &challenge.token).await {
+            warn!(%domain, %err, "failed to clean up challenge response");
+        }
+        result
+    }
+
+    /// Ask the CA to validate a challenge, and wait for the authorization to become valid.
+    async fn validate(
+        account: &Account,
+        url: &str,
+        domain: &str,
+        challenge_url: &str,
+    ) -> Result<(), OrderError> {
+        account.challenge(challenge_url).await?;
+        for i in 0u64..5 {
+            task::sleep(Duration::from_secs(1 << i)).await;
+            match account.auth(url).await? {
+                Auth::Pending { .. } => {
+                    info!(%domain, "authorization still pending");
+                    account.challenge(challenge_url).await?
+                }
+                Auth::Valid => return Ok(()),
+                auth => return Err(OrderError::BadAuth(auth)),
+            }
+        }
+        Err(OrderError::TooManyAttemptsAuth(domain.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::sync::atomic::Ordering;
+
+    use async_std::task::block_on;
+    use async_trait::async_trait;
+    use rcgen::PKCS_ECDSA_P256_SHA256;
+    use rustls_acme::{AccountCache, CertCache};
+
+    use super::*;
+    use crate::{
+        AccountKeyAlgorithm, CertKeyAlgorithm, DomainPolicy, KeyProvider, Snapshot,
+        StaticCertificate,
+    };
+
+    /// A cache in memory, shared by its clones.
+    #[derive(Clone, Default)]
+    struct MemoryCache {
+        certs: Arc<std::sync::Mutex<HashMap<Vec<String>, Vec<u8>>>>,
+        accounts: Arc<std::sync::Mutex<HashMap<Vec<String>, Vec<u8>>>>,
+    }
+
+    impl MemoryCache {
+        fn cert(&self, key: &[&str]) -> Option<Vec<u8>> {
+            let key: Vec<String> = key.iter().map(|k| k.to_string()).collect();
+            self.certs.lock().unwrap().get(&key).cloned()
+        }
+
+        /// The entries stored under a quarantine key, with the marker removed from the key.
+        fn quarantined(entries: &HashMap<Vec<String>, Vec<u8>>) -> Vec<(Vec<String>, Vec<u8>)> {
+            entries
+                .iter()
+                .filter(|(key, _)| key[0].starts_with("quarantined-"))
+                .map(|(key, entry)| (key[1..].to_vec(), entry.clone()))
+                .collect()
+        }
+    }
+
+    #[async_trait]
+    impl CertCache for MemoryCache {
+        type EC = Infallible;
+
+        async fn load_cert(
+            &self,
+            domains: &[String],
+            _directory_url: &str,
+        ) -> Result<Option<Vec<u8>>, Infallible> {
+            Ok(self.certs.lock().unwrap().get(domains).cloned())
+        }
+
+        async fn store_cert(
+            &self,
+            domains: &[String],
+            _directory_url: &str,
+            cert: &[u8],
+        ) -> Result<(), Infallible> {
+            let mut certs = self.certs.lock().unwrap();
+            certs.insert(domains.to_vec(), cert.to_vec());
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl AccountCache for MemoryCache {
+        type EA = Infallible;
+
+        async fn load_account(
+            &self,
+            contact: &[String],
+            _directory_url: &str,
+        ) -> Result<Option<Vec<u8>>, Infallible> {
+            Ok(self.accounts.lock().unwrap().get(contact).cloned())
+        }
+
+        async fn store_account(
+            &self,
+            contact: &[String],
+            _directory_url: &str,
+            account: &[u8],
+        ) -> Result<(), Infallible> {
+            let mut accounts = self.accounts.lock().unwrap();
+            accounts.insert(contact.to_vec(), account.to_vec());
+            Ok(())
+        }
+    }
+
+    fn groups(config: AcmeConfig<Infallible, Infallible>) -> Vec<Vec<String>> {
+        let state = Arc::new(config.state());
+        state.certs().iter().map(|c| c.domains().to_vec()).collect()
+    }
+
+    #[test]
+    fn groups_domains_into_certificates() {
+        let config = AcmeConfig::new(["b.example", "a.example", "c.example"]);
+        assert_eq!(
+            groups(config),
+            [vec!["b.example", "a.example", "c.example"]]
+        );
+        let config = AcmeConfig::new(["b.example", "a.example"]).cert_per_domain(true);
+        assert_eq!(groups(config), [vec!["b.example"], vec!["a.example"]]);
+    }
+
+    #[test]
+    fn splits_domains_over_the_san_limit() {
+        let config = AcmeConfig::new(["c.example", "a.example", "b.example", "a.example"])
+            .max_sans_per_cert(2);
+        assert_eq!(
+            groups(config),
+            [vec!["a.example", "b.example"], vec!["c.example"]]
+        );
+        // A limit of zero is treated as one.
+        let config = AcmeConfig::new(["b.example", "a.example"]).max_sans_per_cert(0);
+        assert_eq!(groups(config), [vec!["a.example"], vec!["b.example"]]);
+    }
+
+    #[test]
+    fn renews_two_thirds_through_the_validity_period() {
+        let not_before = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let day = Duration::from_secs(24 * 60 * 60);
+        let validity = Validity {
+            not_before,
+            not_after: not_before + 90 * day,
+        };
+        assert_eq!(
+            validity.renewal_time(&RenewalPolicy::default()),
+            not_before + 60 * day
+        );
+        let validity = Validity {
+            not_before,
+            not_after: not_before + 6 * day,
+        };
+        assert_eq!(
+            validity.renewal_time(&RenewalPolicy::default()),
+            not_before + 4 * day
+        );
+        let validity = Validity {
+            not_before,
+            not_after: not_before - day,
+        };
+        assert_eq!(validity.renewal_time(&RenewalPolicy::default()), not_before);
+    }
+
+    fn pem(domain: &str) -> Vec<u8> {
+        let mut params = CertificateParams::new(vec![domain.into()]);
+        params.alg = &PKCS_ECDSA_P256_SHA256;
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        [
+            cert.serialize_private_key_pem(),
+            cert.serialize_pem().unwrap(),
+        ]
+        .concat()
+        .into_bytes()
+    }
+
+    #[test]
+    fn applies_the_stale_ocsp_policy() {
+        let deploy = |policy, pem: Vec<u8>| {
+            let config = AcmeConfig::new(["a.example"])
+                .ocsp_stapling(true)
+                .stale_ocsp_policy(policy);
+            let state = Arc::new(config.state());
+            let mut cert = state.certs().remove(0);
+            cert.process_cert(Zeroizing::new(pem), CertSource::Cache)
+                .unwrap();
+            let material = state.registry.material_at(0).unwrap();
+            let mut certified_key = material.certified_key.clone();
+            certified_key.ocsp = Some(b"response".to_vec());
+            state.registry.set_material(
+                0,
+                CertMaterial {
+                    chain: material.chain.clone(),
+                    key: material.key.clone(),
+                    certified_key,
+                },
+            );
+            cert.ocsp_expires_at = Some(cert.now());
+            (state, cert)
+        };
+        let stapled = |state: &AcmeState<Infallible, Infallible>| {
+            let material = state.registry.material_at(0).unwrap();
+            material.certified_key.ocsp.is_some()
+        };
+
+        let (state, mut cert) = deploy(StaleOcspPolicy::ServeWithoutStaple, pem("a.example"));
+        assert!(matches!(cert.expire_ocsp(), Ok(EventOk::OcspStapleDropped)));
+        assert!(!stapled(&state));
+
+        let hour = Duration::from_secs(60 * 60);
+        let (state, mut cert) = deploy(StaleOcspPolicy::ServeStale(hour), pem("a.example"));
+        let expired_at = cert.ocsp_expires_at.unwrap();
+        assert!(matches!(cert.expire_ocsp(), Ok(EventOk::OcspStaleServed)));
+        assert!(stapled(&state));
+        assert_eq!(cert.ocsp_expires_at, Some(expired_at + hour));
+        // Once the stale window ends too, the staple is dropped.
+        assert!(matches!(cert.expire_ocsp(), Ok(EventOk::OcspStapleDropped)));
+        assert!(!stapled(&state));
+
+        // Only must-staple certificates are withheld.
+        let (state, mut cert) = deploy(StaleOcspPolicy::FailMustStaple, pem("a.example"));
+        assert!(matches!(cert.expire_ocsp(), Ok(EventOk::OcspStapleDropped)));
+        assert!(state.resolver.sni_table().contains_key("a.example"));
+        let mut params = CertificateParams::new(vec!["a.example".into()]);
+        params.alg = &PKCS_ECDSA_P256_SHA256;
+        params.custom_extensions = vec![rcgen::CustomExtension::from_oid_content(
+            &[1, 3, 6, 1, 5, 5, 7, 1, 24],
+            vec![0x30, 0x03, 0x02, 0x01, 0x05],
+        )];
+        let must_staple = rcgen::Certificate::from_params(params).unwrap();
+        let must_staple = [
+            must_staple.serialize_private_key_pem(),
+            must_staple.serialize_pem().unwrap(),
+        ]
+        .concat()
+        .into_bytes();
+        let (state, mut cert) = deploy(StaleOcspPolicy::FailMustStaple, must_staple);
+        assert!(matches!(cert.expire_ocsp(), Ok(EventOk::OcspCertWithheld)));
+        assert!(!state.resolver.sni_table().contains_key("a.example"));
+    }
+
+    #[test]
+    fn refuses_staging_certificates_for_production() {
+        let mut params = CertificateParams::new(vec!["domain.example".into()]);
+        params.alg = &PKCS_ECDSA_P256_SHA256;
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "(STAGING) Ersatz Edamame E1");
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let leaf = cert.serialize_der().unwrap();
+        assert!(staging_issued(&leaf));
+        assert!(!staging_issued(
+            &rcgen::generate_simple_self_signed(vec!["domain.example".into()])
+                .unwrap()
+                .serialize_der()
+                .unwrap()
+        ));
+        let staged = [
+            cert.serialize_private_key_pem(),
+            cert.serialize_pem().unwrap(),
+        ]
+        .concat()
+        .into_bytes();
+
+        let state = Arc::new(AcmeConfig::new(["domain.example"]).state());
+        let mut cert = state.certs().remove(0);
+        let event = cert.process_cert(Zeroizing::new(staged.clone()), CertSource::Cache);
+        assert!(matches!(event, Ok(EventOk::DeployedCachedCert)));
+
+        let config = AcmeConfig::new(["domain.example"])
+            .cache(MemoryCache::default())
+            .directory_lets_encrypt(true);
+        let state = Arc::new(config.state());
+        let mut cert = state.certs().remove(0);
+        let event = cert.process_cert(Zeroizing::new(staged.clone()), CertSource::Cache);
+        assert!(matches!(
+            event,
+            Err(EventError::CachedCertParse(CertParseError::StagingIssuer))
+        ));
+        assert!(cert.quarantine_cert.is_some());
+        let event = cert.process_cert(Zeroizing::new(staged), CertSource::Order);
+        assert!(matches!(event, Ok(EventOk::DeployedNewCert)));
+    }
+
+    #[test]
+    fn reports_deployed_certificates() {
+        let state = Arc::new(AcmeConfig::new(["domain.example"]).state());
+        let mut cert = state.certs().remove(0);
+        let handle = state.handle();
+        assert!(handle.status()[0].not_after.is_none());
+        let event = cert.process_cert(Zeroizing::new(pem("domain.example")), CertSource::Cache);
+        assert!(matches!(event, Ok(EventOk::DeployedCachedCert)));
+        let status = &handle.status()[0];
+        assert_eq!(status.domains, ["domain.example"]);
+        let (not_before, not_after) = (status.not_before.unwrap(), status.not_after.unwrap());
+        assert!(not_before < not_after);
+        let renew_at = status.renew_at.unwrap();
+        assert!(not_before < renew_at && renew_at < not_after);
+        assert!(cert.store_cert.is_none());
+
+        let event = cert.process_cert(Zeroizing::new(pem("domain.example")), CertSource::Order);
+        assert!(matches!(event, Ok(EventOk::DeployedNewCert)));
+        assert!(cert.store_cert.is_some());
+    }
+
+    #[test]
+    fn rejects_incomplete_certificates() {
+        let state = Arc::new(AcmeConfig::new(["domain.example"]).state());
+        let mut cert = state.certs().remove(0);
+        let pem = pem("domain.example");
+        let key = String::from_utf8(pem).unwrap();
+        let key = key.split("-----BEGIN CERTIFICATE").next().unwrap();
+        assert!(matches!(
+            cert.process_cert(Zeroizing::new(key.as_bytes().to_vec()), CertSource::Cache),
+            Err(EventError::CachedCertParse(CertParseError::TooFewPem(1)))
+        ));
+        assert!(matches!(
+            cert.process_cert(Zeroizing::new(b"not a pem".to_vec()), CertSource::Order),
+            Err(EventError::NewCertParse(_))
+        ));
+        assert!(state.handle().status()[0].not_after.is_none());
+    }
+
+    #[test]
+    fn detects_key_mismatches() {
+        let state = Arc::new(AcmeConfig::new(["domain.example"]).state());
+        let mut cert = state.certs().remove(0);
+        let first = String::from_utf8(pem("domain.example")).unwrap();
+        let other = String::from_utf8(pem("domain.example")).unwrap();
+        let key = first.split("-----BEGIN CERTIFICATE").next().unwrap();
+        let chain = other
+            .split_at(other.find("-----BEGIN CERTIFICATE").unwrap())
+            .1;
+        let mismatched = [key, chain].concat().into_bytes();
+        assert!(matches!(
+            cert.process_cert(Zeroizing::new(mismatched), CertSource::Cache),
+            Err(EventError::CachedCertParse(CertParseError::KeyMismatch))
+        ));
+    }
+
+    #[test]
+    fn quarantines_corrupt_cached_certificates() {
+        let cache = MemoryCache::default();
+        let domains = vec!["domain.example".to_string()];
+        cache
+            .certs
+            .lock()
+            .unwrap()
+            .insert(domains.clone(), b"corrupt".to_vec());
+        let state = Arc::new(AcmeConfig::new(&domains).cache(cache.clone()).state());
+        let mut cert = state.certs().remove(0);
+        assert!(matches!(
+            block_on(cert.next()),
+            Err(EventError::CachedCertParse(_))
+        ));
+        assert!(matches!(
+            block_on(cert.next()),
+            Ok(EventOk::CertCacheQuarantine)
+        ));
+        let certs = cache.certs.lock().unwrap();
+        assert_eq!(
+            MemoryCache::quarantined(&certs),
+            [(domains, format::stamp(b"corrupt").to_vec())]
+        );
+    }
+
+    #[test]
+    fn quarantines_account_keys_for_other_algorithms() {
+        let cache = MemoryCache::default();
+        let contact = vec!["mailto:admin@domain.example".to_string()];
+        let ed25519 = AccountKey::generate_pkcs8(AccountKeyAlgorithm::Ed25519).unwrap();
+        cache
+            .accounts
+            .lock()
+            .unwrap()
+            .insert(contact.clone(), ed25519.to_vec());
+        let config = AcmeConfig::new(["domain.example"])
+            .contact(&contact)
+            .cache(cache.clone());
+        let state = config.state();
+        assert!(matches!(
+            block_on(state.account_key(&state.directory(0))),
+            Err(Ok(EventOk::AccountCacheQuarantine))
+        ));
+        assert!(matches!(
+            block_on(state.account_key(&state.directory(0))),
+            Err(Ok(EventOk::AccountCacheStore))
+        ));
+        let key = block_on(state.account_key(&state.directory(0))).unwrap();
+        AccountKey::from_pkcs8(AccountKeyAlgorithm::EcdsaP256, &key).unwrap();
+        let accounts = cache.accounts.lock().unwrap();
+        let entry = format::account_entry(LETS_ENCRYPT_STAGING_DIRECTORY, &key);
+        assert_eq!(accounts[&contact], format::stamp(&entry).to_vec());
+        let quarantined = format::account_entry("", &ed25519);
+        assert_eq!(
+            MemoryCache::quarantined(&accounts),
+            [(contact.clone(), format::stamp(&quarantined).to_vec())]
+        );
+    }
+
+    #[test]
+    fn rolls_back_to_previous_certificates() {
+        let cache = MemoryCache::default();
+        let config = AcmeConfig::new(["domain.example"]).cache(cache.clone());
+        let state = Arc::new(config.state());
+        let mut cert = state.certs().remove(0);
+        let handle = state.handle();
+        let (first, second) = (pem("domain.example"), pem("domain.example"));
+        cert.process_cert(Zeroizing::new(first.clone()), CertSource::Cache)
+            .unwrap();
+        assert!(!handle.rollback("domain.example"));
+        cert.process_cert(Zeroizing::new(second.clone()), CertSource::Order)
+            .unwrap();
+        assert_eq!(handle.status()[0].previous_certs, 1);
+        while cert.store_previous || cert.store_cert.is_some() {
+            block_on(cert.next()).unwrap();
+        }
+        assert_eq!(
+            cache.cert(&["domain.example"]),
+            Some(format::stamp(&second).to_vec())
+        );
+        let previous = ["previous-1", "domain.example"];
+        assert_eq!(cache.cert(&previous), Some(format::stamp(&first).to_vec()));
+
+        assert!(!handle.rollback("other.example"));
+        assert!(handle.rollback("domain.example"));
+        let command = block_on(cert.commands.next()).unwrap();
+        assert!(matches!(
+            block_on(cert.command(command)),
+            Ok(EventOk::DeployedPreviousCert)
+        ));
+        assert_eq!(cert.current.as_deref(), Some(&first));
+        assert_eq!(handle.status()[0].previous_certs, 0);
+        while cert.store_previous || cert.store_cert.is_some() {
+            block_on(cert.next()).unwrap();
+        }
+        assert_eq!(
+            cache.cert(&["domain.example"]),
+            Some(format::stamp(&first).to_vec())
+        );
+        assert_eq!(cache.cert(&previous), Some(vec![]));
+        assert!(matches!(
+            block_on(cert.command(Command::Rollback)),
+            Ok(EventOk::NoPreviousCert)
+        ));
+    }
+
+    #[test]
+    fn renews_on_request() {
+        let state = Arc::new(AcmeConfig::new(["domain.example"]).state());
+        let mut cert = state.certs().remove(0);
+        let handle = state.handle();
+        cert.process_cert(Zeroizing::new(pem("domain.example")), CertSource::Cache)
+            .unwrap();
+        assert!(handle.status()[0].renew_at.is_some());
+        assert!(!handle.renew("other.example"));
+        assert!(handle.renew("domain.example"));
+        let command = block_on(cert.commands.next()).unwrap();
+        assert!(matches!(
+            block_on(cert.command(command)),
+            Ok(EventOk::RenewalRequested)
+        ));
+        assert!(cert.renew_at.is_none());
+        assert!(handle.status()[0].renew_at.is_none());
+    }
+
+    #[test]
+    fn revokes_on_request() {
+        let config = AcmeConfig::new(["domain.example"])
+            .directory("http://127.0.0.1:1/directory")
+            .static_certificate(
+                ["static.example"],
+                StaticCertificate::new(pem("static.example")),
+            );
+        let state = Arc::new(config.state());
+        let mut certs = state.certs();
+        let mut cert = certs.remove(0);
+        let mut static_cert = certs.remove(0);
+        let handle = state.handle();
+        let reason = RevocationReason::KeyCompromise;
+        assert!(!handle.revoke("domain.example", reason));
+        cert.process_cert(Zeroizing::new(pem("domain.example")), CertSource::Cache)
+            .unwrap();
+        assert!(handle.revoke("domain.example", reason));
+        let command = block_on(cert.commands.next()).unwrap();
+        // The CA is unreachable: the certificate is still served and renewed as scheduled.
+        assert!(matches!(
+            block_on(cert.command(command)),
+            Err(EventError::Revocation(_))
+        ));
+        assert!(cert.renew_at.is_some());
+        assert!(!state.revoked.lock().unwrap().contains(&cert.domains));
+
+        static_cert
+            .process_cert(Zeroizing::new(pem("static.example")), CertSource::Cache)
+            .unwrap();
+        assert!(matches!(
+            block_on(static_cert.command(Command::Revoke(reason))),
+            Ok(EventOk::NoCertToRevoke)
+        ));
+    }
+
+    #[test]
+    fn renews_revoked_certificates_with_new_keys() {
+        let domains = vec!["a.example".to_string()];
+        let cache = MemoryCache::default();
+        let current = pem("a.example");
+        cache
+            .certs
+            .lock()
+            .unwrap()
+            .insert(domains.clone(), format::stamp(&current).to_vec());
+        let state = AcmeConfig::new(["a.example"])
+            .cache(cache)
+            .reuse_cert_key(true)
+            .state();
+        let directory = &state.directory(0);
+        assert!(block_on(CertState::current_key(&state, directory, &domains)).is_some());
+        state.revoked.lock().unwrap().insert(domains.clone());
+        assert!(block_on(CertState::current_key(&state, directory, &domains)).is_none());
+    }
+
+    #[test]
+    fn reports_persistent_failures() {
+        let reported = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let reports = reported.clone();
+        let config = AcmeConfig::new(["domain.example"])
+            .error_reporter(move |event: &AcmeEvent| reports.lock().unwrap().push(event.kind));
+        let state = Arc::new(config.state());
+        let mut cert = state.certs().remove(0);
+        let domains = cert.domains().to_vec();
+        let report = |cert: &CertState<_, _>, event: Event<Infallible, Infallible>| {
+            cert.report(&AcmeEvent::new(&domains, None, &event))
+        };
+        report(&cert, Ok(EventOk::DeployedNewCert));
+        let failed = || Err(EventError::Order(OrderError::TooManyAttemptsOrder));
+        for failure_cnt in 0..4 {
+            cert.failure_cnt = failure_cnt;
+            report(&cert, failed());
+        }
+        report(
+            &cert,
+            Err(EventError::NewCertParse(CertParseError::KeyMismatch)),
+        );
+        assert_eq!(
+            *reported.lock().unwrap(),
+            [EventKind::OrderFailed, EventKind::NewCertInvalid]
+        );
+    }
+
+    #[test]
+    fn sanity_checks_new_certificates() {
+        let named = |name: &str, is_ca: bool| {
+            let mut params = CertificateParams::new(vec![name.into()]);
+            params.alg = &PKCS_ECDSA_P256_SHA256;
+            params
+                .distinguished_name
+                .push(rcgen::DnType::CommonName, name);
+            if is_ca {
+                params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+            }
+            rcgen::Certificate::from_params(params).unwrap()
+        };
+        let (ca, other_ca) = (named("ca.example", true), named("other.example", true));
+        let leaf = named("Domain.example", false);
+        let chain = |issuer: &rcgen::Certificate| {
+            [
+                leaf.serialize_pem_with_signer(&ca).unwrap(),
+                issuer.serialize_pem().unwrap(),
+            ]
+            .concat()
+            .into_bytes()
+        };
+        let domains = vec!["domain.example".to_string()];
+        let now = SystemTime::now();
+        CertState::<Infallible, Infallible>::check_new_cert(&chain(&ca), &domains, now).unwrap();
+        let both = vec!["domain.example".to_string(), "www.domain.example".into()];
+        assert!(matches!(
+            CertState::<Infallible, Infallible>::check_new_cert(&chain(&ca), &both, now),
+            Err(CertParseError::MissingSan(name)) if name == "www.domain.example"
+        ));
+        assert!(matches!(
+            CertState::<Infallible, Infallible>::check_new_cert(&chain(&other_ca), &domains, now),
+            Err(CertParseError::ChainOrder(0))
+        ));
+
+        let mut params = CertificateParams::new(domains.clone());
+        params.alg = &PKCS_ECDSA_P256_SHA256;
+        params.not_before = rcgen::date_time_ymd(2000, 1, 1);
+        params.not_after = rcgen::date_time_ymd(2001, 1, 1);
+        let expired = rcgen::Certificate::from_params(params).unwrap();
+        let expired = expired.serialize_pem().unwrap().into_bytes();
+        assert!(matches!(
+            CertState::<Infallible, Infallible>::check_new_cert(&expired, &domains, now),
+            Err(CertParseError::Expired)
+        ));
+        // Going by the time given, not the system clock.
+        let in_2000 = UNIX_EPOCH + Duration::from_secs(955_000_000);
+        CertState::<Infallible, Infallible>::check_new_cert(&expired, &domains, in_2000).unwrap();
+    }
+
+    #[test]
+    fn routes_domain_groups_to_their_directories() {
+        let (cache, group_cache) = (MemoryCache::default(), MemoryCache::default());
+        let domains = vec!["internal.example".to_string()];
+        group_cache
+            .certs
+            .lock()
+            .unwrap()
+            .insert(domains.clone(), pem("internal.example"));
+        let config = AcmeConfig::new(["a.example", "b.example"])
+            .cache(cache)
+            .directory_group_cache("https://ca.internal/directory", &domains, group_cache)
+            .directory_group("https://other.example/directory", ["other.example"]);
+        let state = Arc::new(config.state());
+        let certs = state.certs();
+        let groups: Vec<_> = certs
+            .iter()
+            .map(|cert| (cert.directory, cert.domains().to_vec()))
+            .collect();
+        assert_eq!(
+            groups,
+            [
+                (0, vec!["a.example".to_string(), "b.example".into()]),
+                (1, domains),
+                (2, vec!["other.example".into()]),
+            ]
+        );
+        assert_eq!(state.directory(1).url, "https://ca.internal/directory");
+        // Groups without a cache of their own share the main cache.
+        assert!(state.directory(2).cache.is_none());
+        let mut internal = certs.into_iter().nth(1).unwrap();
+        assert!(matches!(
+            block_on(internal.next()),
+            Ok(EventOk::DeployedCachedCert)
+        ));
+    }
+
+    struct StaticSource(Option<Vec<u8>>);
+
+    #[async_trait]
+    impl CertificateSource for StaticSource {
+        fn name(&self) -> &str {
+            "static"
+        }
+
+        async fn fetch(&self, _domains: &[String]) -> Result<Vec<u8>, CertificateSourceError> {
+            self.0.clone().ok_or_else(|| "unavailable".into())
+        }
+    }
+
+    #[test]
+    fn obtains_certificates_from_sources() {
+        let pem = pem("source.example");
+        let config = AcmeConfig::new(Vec::<String>::new())
+            .certificate_source(["source.example"], StaticSource(Some(pem.clone())))
+            .certificate_source(["down.example"], StaticSource(None));
+        let state = Arc::new(config.state());
+        let mut certs = state.certs().into_iter();
+        let (mut source, mut down) = (certs.next().unwrap(), certs.next().unwrap());
+        let event = loop {
+            match block_on(source.next()) {
+                Ok(EventOk::DeployedNewCert) => break Ok(()),
+                Err(err) => break Err(err),
+                Ok(_) => {}
+            }
+        };
+        event.unwrap();
+        assert_eq!(source.current.as_deref(), Some(&pem));
+        let event = loop {
+            if let Err(err) = block_on(down.next()) {
+                break err;
+            }
+        };
+        assert!(matches!(
+            event,
+            EventError::Order(OrderError::Source(err)) if err.to_string() == "unavailable"
+        ));
+    }
+
+    #[test]
+    fn migrates_cached_certificates() {
+        let cache = MemoryCache::default();
+        let domains = vec!["domain.example".to_string()];
+        let old = pem("domain.example");
+        cache
+            .certs
+            .lock()
+            .unwrap()
+            .insert(domains.clone(), old.clone());
+        let state = Arc::new(AcmeConfig::new(&domains).cache(cache.clone()).state());
+        let mut cert = state.certs().remove(0);
+        assert!(matches!(
+            block_on(cert.next()),
+            Ok(EventOk::DeployedCachedCert)
+        ));
+        assert!(matches!(block_on(cert.next()), Ok(EventOk::CertCacheStore)));
+        assert_eq!(
+            cache.cert(&["domain.example"]),
+            Some(format::stamp(&old).to_vec())
+        );
+    }
+
+    #[test]
+    fn restores_state_from_snapshots() {
+        let cache = MemoryCache::default();
+        let domains = vec!["domain.example".to_string()];
+        let restored = |snapshot: &Snapshot| {
+            let config = AcmeConfig::new(&domains).cache(cache.clone());
+            let state = Arc::new(config.restore(snapshot.clone()).state());
+            let mut cert = state.certs().remove(0);
+            assert!(matches!(
+                block_on(cert.next()),
+                Ok(EventOk::DeployedCachedCert)
+            ));
+            let resume = cert.restored.take().unwrap();
+            block_on(cert.resume(resume));
+            cert
+        };
+        let mut certs = cache.certs.lock().unwrap();
+        certs.insert(
+            domains.clone(),
+            format::stamp(&pem("domain.example")).to_vec(),
+        );
+        drop(certs);
+        let state = Arc::new(AcmeConfig::new(&domains).cache(cache.clone()).state());
+        let mut cert = state.certs().remove(0);
+        assert!(matches!(
+            block_on(cert.next()),
+            Ok(EventOk::DeployedCachedCert)
+        ));
+        cert.backoff_cnt = 3;
+        cert.failure_cnt = 2;
+        cert.save_resume();
+        let snapshot = state.handle().snapshot();
+
+        let cert = restored(&snapshot);
+        assert_eq!((cert.backoff_cnt, cert.failure_cnt), (3, 2));
+
+        // A snapshot taken while another certificate was served.
+        let mut snapshot = snapshot;
+        snapshot.certs[0].not_after = Some(SystemTime::now());
+        let cert = restored(&snapshot);
+        assert_eq!((cert.backoff_cnt, cert.failure_cnt), (0, 0));
+    }
+
+    #[test]
+    fn backs_off_failing_domains() {
+        let state = Arc::new(AcmeConfig::new(["a.example", "b.example"]).state());
+        let mut cert = state.certs().remove(0);
+        let handle = state.handle();
+        assert_eq!(cert.order_domains(), ["a.example", "b.example"]);
+        cert.back_off_domain("b.example".into());
+        assert_eq!(cert.order_domains(), ["a.example"]);
+        assert_eq!(handle.status()[0].backed_off_domains, ["b.example"]);
+        let first = cert.domain_backoff["b.example"].until;
+        cert.back_off_domain("b.example".into());
+        assert!(cert.domain_backoff["b.example"].until > first);
+        assert_eq!(cert.failure_cnt, 2);
+        // With every domain backed off, all are ordered again rather than none.
+        cert.back_off_domain("a.example".into());
+        assert_eq!(cert.order_domains(), ["a.example", "b.example"]);
+        assert_eq!(
+            handle.status()[0].backed_off_domains,
+            ["a.example", "b.example"]
+        );
+    }
+
+    #[test]
+    fn records_failures_in_the_status() {
+        let state = Arc::new(AcmeConfig::new(["domain.example"]).state());
+        let cert = state.certs().remove(0);
+        let handle = state.handle();
+        let record = |event: Event<Infallible, Infallible>| {
+            cert.record(&AcmeEvent::new(cert.domains(), None, &event))
+        };
+        let challenges = serde_json::from_str(
+            r#"[{"type": "tls-alpn-01", "url": "https://ca.example/chall/1", "token": "token",
+                "error": {"type": "urn:ietf:params:acme:error:connection"}}]"#,
+        )
+        .unwrap();
+        record(Err(EventError::Order(OrderError::BadAuth(Auth::Invalid {
+            identifier: None,
+            challenges,
+        }))));
+        record(Err(EventError::NewCertParse(CertParseError::Expired)));
+        let status = &handle.status()[0];
+        assert_eq!(status.failed_orders, 2);
+        assert!(status.last_error.as_ref().unwrap().contains("expired"));
+        assert!(matches!(
+            status.last_failure,
+            Some(crate::Error::Certificate(_))
+        ));
+        let failure = status.challenge_failure.as_ref().unwrap();
+        assert_eq!(failure.challenge_type, "tls-alpn-01");
+        assert_eq!(failure.domain, None);
+        record(Ok(EventOk::CertCacheStore));
+        assert_eq!(handle.status()[0].failed_orders, 2);
+        record(Ok(EventOk::DeployedNewCert));
+        let status = &handle.status()[0];
+        assert_eq!(status.failed_orders, 0);
+        assert!(status.last_error.is_none() && status.challenge_failure.is_none());
+        assert!(status.last_failure.is_none());
+    }
+
+    #[test]
+    fn classifies_failures() {
+        let failure = |err: EventError<Infallible, Infallible>| err.failure();
+        let unreachable = || {
+            AcmeError::HttpRequest(HttpsRequestError::Io(std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                "refused",
+            )))
+        };
+        let rejected = || {
+            AcmeError::HttpRequest(HttpsRequestError::Non2xxStatus {
+                status_code: 403,
+                body: r#"{"type": "urn:ietf:params:acme:error:unauthorized", "detail": "no"}"#
+                    .into(),
+                retry_after: None,
+            })
+        };
+        assert!(matches!(
+            failure(EventError::Order(OrderError::Acme(unreachable()))),
+            crate::Error::Io(_)
+        ));
+        assert!(matches!(
+            failure(EventError::Order(OrderError::Account(unreachable()))),
+            crate::Error::Io(_)
+        ));
+        match failure(EventError::Order(OrderError::Acme(rejected()))) {
+            crate::Error::Rejected(problem) => {
+                assert_eq!(problem.typ, "urn:ietf:params:acme:error:unauthorized");
+                assert_eq!(problem.detail.as_deref(), Some("no"));
+            }
+            other => panic!("unexpected failure: {:?}", other),
+        }
+        assert!(matches!(
+            failure(EventError::Order(OrderError::Account(rejected()))),
+            crate::Error::Account(_)
+        ));
+        assert!(matches!(
+            failure(EventError::StagingCheck(OrderError::TooManyAttemptsOrder)),
+            crate::Error::Order(_)
+        ));
+        assert!(matches!(
+            failure(EventError::NewCertParse(CertParseError::Expired)),
+            crate::Error::Certificate(_)
+        ));
+        assert!(matches!(
+            failure(EventError::CertCacheFormat(FormatError::MalformedStamp)),
+            crate::Error::Cache(_)
+        ));
+    }
+
+    #[test]
+    fn marks_panicked_tasks_as_degraded() {
+        let state = Arc::new(AcmeConfig::new(["domain.example"]).state());
+        let mut cert = state.certs().remove(0);
+        let handle = state.handle();
+        cert.order_url = Some("https://acme.test/order/1".into());
+        cert.panicked("boom".into());
+        assert!(cert.order_url.is_none());
+        assert_eq!(handle.status()[0].degraded.as_deref(), Some("boom"));
+        let event: Event<Infallible, Infallible> = Ok(EventOk::CertCacheStore);
+        cert.record(&AcmeEvent::new(cert.domains(), None, &event));
+        assert!(handle.status()[0].degraded.is_none());
+    }
+
+    #[test]
+    fn prepares_staging_checks() {
+        let config = AcmeConfig::new(["domain.example"]);
+        assert!(config.state().staging.is_none());
+        let state = AcmeConfig::new(["domain.example"])
+            .staging_check(true)
+            .state();
+        let staging = state.staging.as_ref().unwrap();
+        assert_eq!(staging.url, LETS_ENCRYPT_STAGING_DIRECTORY);
+        let event: Event<Infallible, Infallible> =
+            Err(EventError::StagingCheck(OrderError::TooManyAttemptsOrder));
+        let event = AcmeEvent::new(&["domain.example".into()], None, &event);
+        assert_eq!(event.kind, EventKind::OrderFailed);
+        assert_eq!(event.error_class, Some("staging_check"));
+    }
+
+    #[test]
+    fn deploys_certificates_renewed_by_other_processes() {
+        let cache = MemoryCache::default();
+        let config = AcmeConfig::new(["domain.example"]).cache(cache.clone());
+        let state = Arc::new(config.state());
+        let mut cert = state.certs().remove(0);
+        let directory = &state.directory(0);
+        assert!(block_on(cert.load_renewed(&state, directory)).is_none());
+        let current = pem("domain.example");
+        cert.process_cert(Zeroizing::new(current.clone()), CertSource::Cache)
+            .unwrap();
+        let mut certs = cache.certs.lock().unwrap();
+        certs.insert(
+            vec!["domain.example".into()],
+            format::stamp(&current).to_vec(),
+        );
+        drop(certs);
+        assert!(block_on(cert.load_renewed(&state, directory)).is_none());
+        let renewed = pem("domain.example");
+        let mut certs = cache.certs.lock().unwrap();
+        certs.insert(
+            vec!["domain.example".into()],
+            format::stamp(&renewed).to_vec(),
+        );
+        drop(certs);
+        assert!(matches!(
+            block_on(cert.load_renewed(&state, directory)),
+            Some(Ok(EventOk::DeployedCachedCert))
+        ));
+        assert_eq!(cert.current.as_deref(), Some(&renewed));
+    }
+
+    #[test]
+    fn leaves_orders_to_the_orderer_when_responding() {
+        let config = AcmeConfig::new(["domain.example"])
+            .challenge_delegation(ChallengeDelegation::Responder);
+        let state = Arc::new(config.state());
+        assert!(state.shared_challenges().is_some());
+        let mut cert = state.certs().remove(0);
+        assert!(matches!(
+            block_on(cert.next()),
+            Ok(EventOk::AwaitingOrderer)
+        ));
+        assert!(cert.renew_at.unwrap() > SystemTime::now());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn deploys_handed_over_certificates() {
+        let state = Arc::new(AcmeConfig::new(["domain.example"]).state());
+        let mut cert = state.certs().remove(0);
+        let pem = pem("domain.example");
+        state.handed_over.lock().unwrap().push(HandoverCert {
+            domains: vec!["other.example".into()],
+            pem: String::from_utf8(pem.clone()).unwrap(),
+        });
+        state.handed_over.lock().unwrap().push(HandoverCert {
+            domains: vec!["domain.example".into()],
+            pem: String::from_utf8(pem.clone()).unwrap(),
+        });
+        assert!(matches!(
+            block_on(cert.next()),
+            Ok(EventOk::DeployedCachedCert)
+        ));
+        assert_eq!(cert.current.as_deref(), Some(&pem));
+        assert_eq!(state.handed_over.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reloads_certificates_from_the_cache() {
+        let cache = MemoryCache::default();
+        let config = AcmeConfig::new(["domain.example"]).cache(cache.clone());
+        let state = Arc::new(config.state());
+        let mut cert = state.certs().remove(0);
+        let handle = state.handle();
+        handle.reload();
+        let command = block_on(cert.commands.next()).unwrap();
+        assert!(matches!(
+            block_on(cert.command(command)),
+            Ok(EventOk::CacheUnchanged)
+        ));
+        let renewed = pem("domain.example");
+        let mut certs = cache.certs.lock().unwrap();
+        certs.insert(
+            vec!["domain.example".into()],
+            format::stamp(&renewed).to_vec(),
+        );
+        drop(certs);
+        handle.reload();
+        let command = block_on(cert.commands.next()).unwrap();
+        assert!(matches!(
+            block_on(cert.command(command)),
+            Ok(EventOk::DeployedCachedCert)
+        ));
+        assert_eq!(cert.current.as_deref(), Some(&renewed));
+    }
+
+    #[test]
+    fn keeps_account_keys_to_their_directory() {
+        let cache = MemoryCache::default();
+        let contact = vec!["mailto:admin@domain.example".to_string()];
+        let key = AccountKey::generate_pkcs8(AccountKeyAlgorithm::EcdsaP256).unwrap();
+        let entry =
+            format::stamp(&format::account_entry("https://other.example/dir", &key)).to_vec();
+        cache
+            .accounts
+            .lock()
+            .unwrap()
+            .insert(contact.clone(), entry.clone());
+        let config = AcmeConfig::new(["domain.example"])
+            .contact(&contact)
+            .cache(cache.clone());
+        let state = config.state();
+        let new_key = block_on(state.account_key(&state.directory(0))).unwrap();
+        assert_ne!(new_key, key);
+        // The other directory's entry is left alone.
+        assert_eq!(cache.accounts.lock().unwrap()[&contact], entry);
+        assert_eq!(
+            block_on(state.account_key(&state.directory(0))).unwrap(),
+            new_key
+        );
+
+        // Entries without a directory are claimed for the one they're loaded for.
+        let entry = format::stamp(&format::account_entry("", &key)).to_vec();
+        let cache = MemoryCache::default();
+        let accounts = cache.accounts.clone();
+        accounts.lock().unwrap().insert(contact.clone(), entry);
+        let config = AcmeConfig::new(["domain.example"])
+            .contact(&contact)
+            .cache(cache);
+        let state = config.state();
+        assert!(matches!(
+            block_on(state.account_key(&state.directory(0))),
+            Err(Ok(EventOk::AccountCacheStore))
+        ));
+        assert_eq!(
+            block_on(state.account_key(&state.directory(0))).unwrap(),
+            key
+        );
+        let entry = format::account_entry(LETS_ENCRYPT_STAGING_DIRECTORY, &key);
+        assert_eq!(
+            accounts.lock().unwrap()[&contact],
+            format::stamp(&entry).to_vec()
+        );
+    }
+
+    struct Customers;
+
+    #[async_trait]
+    impl DomainPolicy for Customers {
+        async fn approve(&self, domain: &str) -> bool {
+            domain.ends_with(".customer.example")
+        }
+    }
+
+    #[test]
+    fn obtains_certificates_on_demand() {
+        let config = AcmeConfig::new(Vec::<String>::new())
+            .cache(MemoryCache::default())
+            .on_demand(Customers);
+        let state = Arc::new(config.state());
+        // Without configured domains, there's nothing to manage until a name is approved.
+        assert!(state.certs().is_empty());
+        assert!(state.take_on_demand_requests().is_some());
+        assert!(state.take_on_demand_requests().is_none());
+        assert!(block_on(state.approve_on_demand("shop.customer.example")));
+        assert!(!block_on(state.approve_on_demand("evil.example")));
+        let cert = state.domain_cert("shop.customer.example".into(), CertOrigin::OnDemand);
+        assert_eq!(cert.directory, 0);
+        assert_eq!(cert.domains(), ["shop.customer.example"]);
+    }
+
+    #[test]
+    fn shares_managed_orders_on_demand() {
+        let config = AcmeConfig::new(["managed.example"])
+            .cache(MemoryCache::default())
+            .on_demand(Customers);
+        let state = Arc::new(config.state());
+        state.request_on_demand("managed.example");
+        assert!(!state.resolver.pending_issuances()[0].approved);
+        // The policy would deny the name, but its certificate is already managed.
+        state.share_on_demand("managed.example");
+        assert!(state.resolver.pending_issuances()[0].approved);
+        assert!(state.certs()[0]
+            .domains()
+            .contains(&"managed.example".to_string()));
+    }
+
+    #[test]
+    fn reloads_the_configuration() {
+        let config = AcmeConfig::new(["a.example", "b.example"])
+            .cache(MemoryCache::default())
+            .cert_per_domain(true);
+        let state = Arc::new(config.state());
+        let mut certs = state.certs();
+        let handle = state.handle();
+        let mut reloads = state.take_config_reloads().unwrap();
+        let mut reload = |config: AcmeConfig<Infallible, Infallible>| {
+            assert!(handle.reload_config(config));
+            let reload = block_on(reloads.next()).unwrap();
+            block_on(state.reload_config(reload))
+        };
+        let retired = |cert: &mut CertState<Infallible, Infallible>| {
+            matches!(cert.commands.try_recv(), Ok(Command::Retire))
+        };
+
+        // Unchanged, except for the contact URLs.
+        let unchanged = AcmeConfig::new(["a.example", "b.example"]).contact_push("mailto:a@b");
+        assert!(reload(unchanged).is_empty());
+        assert_eq!(state.contact(&state.directory(0)), ["mailto:a@b"]);
+        assert!(!retired(&mut certs[0]) && !retired(&mut certs[1]));
+
+        // A domain replaced.
+        let added = reload(AcmeConfig::new(["a.example", "c.example"]));
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0].domains(), ["c.example"]);
+        assert!(!retired(&mut certs[0]));
+        assert!(retired(&mut certs[1]));
+        assert!(matches!(
+            block_on(certs[1].command(Command::Retire)),
+            Ok(EventOk::CertRemoved)
+        ));
+        assert_eq!(handle.status().len(), 2);
+        assert_eq!(
+            handle.config_summary().groups[0].domains,
+            ["a.example", "c.example"]
+        );
+
+        // Another directory: everything is ordered from it.
+        let moved = reload(
+            AcmeConfig::new(["a.example", "c.example"]).directory("https://ca.internal/dir"),
+        );
+        let domains: Vec<_> = moved.iter().map(|cert| cert.domains().to_vec()).collect();
+        assert_eq!(domains, [["a.example"], ["c.example"]]);
+        assert!(moved
+            .iter()
+            .all(|cert| cert.directory == moved[0].directory));
+        assert_ne!(moved[0].directory, 0);
+        assert!(retired(&mut certs[0]));
+        let summary = handle.config_summary();
+        assert_eq!(summary.directory_url, "https://ca.internal/dir");
+        assert!(!summary.staging);
+        // Certificates added later come from the new directory too.
+        let added = state.domain_cert("d.example".into(), CertOrigin::Added);
+        assert_eq!(added.directory, moved[0].directory);
+    }
+
+    /// A certificate for `domain.example` valid throughout the year 2000, and a clock at the
+    /// start of March 2000.
+    #[cfg(feature = "test-util")]
+    fn year_2000() -> (Vec<u8>, crate::test::ManualClock) {
+        let mut params = CertificateParams::new(vec!["domain.example".into()]);
+        params.alg = &PKCS_ECDSA_P256_SHA256;
+        params.not_before = rcgen::date_time_ymd(2000, 1, 1);
+        params.not_after = rcgen::date_time_ymd(2001, 1, 1);
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let pem = [
+            cert.serialize_private_key_pem(),
+            cert.serialize_pem().unwrap(),
+        ]
+        .concat()
+        .into_bytes();
+        let march = UNIX_EPOCH + Duration::from_secs(951_868_800);
+        (pem, crate::test::ManualClock::at(march))
+    }
+
+    #[cfg(feature = "test-util")]
+    #[test]
+    fn schedules_renewals_by_the_configured_clock() {
+        let (pem, clock) = year_2000();
+        let cache = MemoryCache::default();
+        let config = AcmeConfig::new(["domain.example"])
+            .cache(cache.clone())
+            .clock(clock.clone());
+        let state = Arc::new(config.state());
+        let mut cert = state.certs().remove(0);
+        let event = cert.process_cert(Zeroizing::new(pem.clone()), CertSource::Cache);
+        assert!(matches!(event, Ok(EventOk::DeployedCachedCert)));
+        // Two thirds through the 366 days of validity.
+        let renew_at = UNIX_EPOCH + Duration::from_secs(946_684_800 + 244 * 24 * 60 * 60);
+        assert_eq!(cert.renew_at, Some(renew_at));
+
+        // Until then, the certificate in the cache counts as renewed by another process.
+        let directory = &state.directory(0);
+        cache
+            .certs
+            .lock()
+            .unwrap()
+            .insert(vec!["domain.example".into()], format::stamp(&pem).to_vec());
+        cert.current = None;
+        assert!(block_on(cert.load_renewed(&state, directory)).is_some());
+        cert.current = None;
+        clock.set(renew_at);
+        assert!(block_on(cert.load_renewed(&state, directory)).is_none());
+
+        // The renewal waits for the configured clock to reach the due time.
+        block_on(async {
+            let mut due = Box::pin(
+                state
+                    .clock()
+                    .sleep_until(renew_at + Duration::from_secs(60)),
+            );
+            assert!(futures::poll!(&mut due).is_pending());
+            clock.advance(Duration::from_secs(60));
+            due.await;
+        });
+    }
+
+    #[cfg(feature = "test-util")]
+    #[test]
+    fn backs_off_by_the_configured_clock() {
+        let (_, clock) = year_2000();
+        let config = AcmeConfig::new(["a.example", "b.example"])
+            .retry_policy(crate::RetryPolicy::new().initial_delay(Duration::from_secs(60)))
+            .clock(clock.clone());
+        let state = Arc::new(config.state());
+        let mut cert = state.certs().remove(0);
+        cert.back_off();
+        assert_eq!(cert.renew_at, Some(clock.now() + Duration::from_secs(60)));
+        cert.back_off();
+        assert_eq!(cert.renew_at, Some(clock.now() + Duration::from_secs(120)));
+
+        cert.back_off_domain("b.example".into());
+        assert_eq!(cert.order_domains(), ["a.example"]);
+        let until = cert.domain_backoff["b.example"].until;
+        assert!(until > clock.now());
+        clock.set(until);
+        assert_eq!(cert.order_domains(), ["a.example", "b.example"]);
+    }
+
+    #[cfg(feature = "test-util")]
+    #[test]
+    fn checks_expiry_by_the_configured_clock() {
+        let (pem, clock) = year_2000();
+        let config = AcmeConfig::new(["domain.example"]).clock(clock.clone());
+        let state = Arc::new(config.state());
+        let handle = state.handle();
+        let mut cert = state.certs().remove(0);
+        cert.process_cert(Zeroizing::new(pem), CertSource::Cache)
+            .unwrap();
+        let obtained = || handle.status()[0].readiness_at(handle.registry.now());
+        assert_eq!(obtained(), crate::CertReadiness::Obtained);
+        assert_eq!(handle.order_pacing().pending_certs, 0);
+        let end = UNIX_EPOCH + Duration::from_secs(978_307_200);
+        assert_eq!(
+            handle.time_to_expiry("domain.example"),
+            end.duration_since(clock.now()).ok()
+        );
+        assert_eq!(block_on(handle.ready()).domains, ["domain.example"]);
+
+        clock.set(end);
+        assert_eq!(
+            handle.time_to_expiry("domain.example"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(obtained(), crate::CertReadiness::Pending);
+        assert_eq!(handle.order_pacing().pending_certs, 1);
+    }
+
+    #[test]
+    fn removes_domains_at_runtime() {
+        let state = Arc::new(AcmeConfig::new(["a.example", "b.example"]).state());
+        let mut cert = state.certs().remove(0);
+        let handle = state.handle();
+        cert.process_cert(Zeroizing::new(pem("a.example")), CertSource::Cache)
+            .unwrap();
+        assert!(!handle.remove_domain("other.example"));
+        assert!(handle.remove_domain("b.example"));
+        let command = block_on(cert.commands.next()).unwrap();
+        assert!(matches!(
+            block_on(cert.command(command)),
+            Ok(EventOk::DomainRemoved)
+        ));
+        assert_eq!(handle.status()[0].domains, ["a.example"]);
+        assert!(cert.renew_at.is_none());
+        // Removing the last domain removes the certificate.
+        assert!(handle.remove_domain("a.example"));
+        let command = block_on(cert.commands.next()).unwrap();
+        assert!(matches!(
+            block_on(cert.command(command)),
+            Ok(EventOk::CertRemoved)
+        ));
+        assert!(cert.is_removed());
+        assert!(handle.status().is_empty());
+    }
+
+    #[cfg(feature = "simulate-failure")]
+    #[test]
+    fn simulates_renewal_failures() {
+        let state = Arc::new(AcmeConfig::new(["a.example"]).state());
+        let mut cert = state.certs().remove(0);
+        let handle = state.handle();
+        assert!(handle.simulate_failure("a.example", "rehearsal"));
+        assert!(!handle.simulate_failure("b.example", "rehearsal"));
+        let command = block_on(cert.commands.next()).unwrap();
+        let event = block_on(cert.command(command));
+        assert!(matches!(
+            &event,
+            Err(EventError::Order(OrderError::Simulated(message))) if message == "rehearsal"
+        ));
+        let event = AcmeEvent::new(cert.domains(), None, &event);
+        assert_eq!(event.error_class, Some("simulated"));
+    }
+
+    #[test]
+    fn runs_the_rotation_hook_on_deployments() {
+        let rotations = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = rotations.clone();
+        let config = AcmeConfig::new(["a.example"]).on_rotation(move |rotation| {
+            seen.lock().unwrap().push(rotation.status().domains.clone());
+            async {}
+        });
+        let state = Arc::new(config.state());
+        let mut cert = state.certs().remove(0);
+        let deployed: Event<Infallible, Infallible> = Ok(EventOk::DeployedCachedCert);
+        let deployed = AcmeEvent::new(cert.domains(), None, &deployed);
+        block_on(cert.run_hooks(&deployed));
+        assert!(rotations.lock().unwrap().is_empty());
+        cert.process_cert(Zeroizing::new(pem("a.example")), CertSource::Cache)
+            .unwrap();
+        block_on(cert.run_hooks(&deployed));
+        block_on(cert.run_hooks(&deployed));
+        assert_eq!(*rotations.lock().unwrap(), [vec!["a.example".to_string()]]);
+    }
+
+    #[test]
+    fn runs_rotation_hooks_for_their_domains() {
+        let rotations = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (a, b) = (rotations.clone(), rotations.clone());
+        let config = AcmeConfig::new(["a.example", "b.example"])
+            .cert_per_domain(true)
+            .on_domain_rotation(["A.example"], move |rotation| {
+                a.lock()
+                    .unwrap()
+                    .push(("a", rotation.status().domains.clone()));
+                async {}
+            })
+            .on_domain_rotation(["c.example"], move |rotation| {
+                b.lock()
+                    .unwrap()
+                    .push(("c", rotation.status().domains.clone()));
+                async {}
+            });
+        let state = Arc::new(config.state());
+        let deployed: Event<Infallible, Infallible> = Ok(EventOk::DeployedCachedCert);
+        for mut cert in state.certs() {
+            let domain = cert.domains()[0].clone();
+            cert.process_cert(Zeroizing::new(pem(&domain)), CertSource::Cache)
+                .unwrap();
+            block_on(cert.run_hooks(&AcmeEvent::new(cert.domains(), None, &deployed)));
+        }
+        assert_eq!(
+            *rotations.lock().unwrap(),
+            [("a", vec!["a.example".to_string()])]
+        );
+    }
+
+    #[cfg(all(unix, feature = "reload-command"))]
+    #[test]
+    fn runs_reload_commands() {
+        let out = std::env::temp_dir().join(format!("tide-acme-reload-{}", std::process::id()));
+        let mut command = std::process::Command::new("sh");
+        command
+            .arg("-c")
+            .arg("echo \"$TIDE_ACME_DOMAIN $GREETING\" > \"$OUT\"")
+            .env("GREETING", "reloaded")
+            .env("OUT", &out);
+        let config = AcmeConfig::new(["a.example"]).reload_command(["a.example"], command);
+        let state = Arc::new(config.state());
+        let mut cert = state.certs().remove(0);
+        cert.process_cert(Zeroizing::new(pem("a.example")), CertSource::Cache)
+            .unwrap();
+        let deployed: Event<Infallible, Infallible> = Ok(EventOk::DeployedCachedCert);
+        block_on(cert.run_hooks(&AcmeEvent::new(cert.domains(), None, &deployed)));
+        let written = std::fs::read_to_string(&out).unwrap();
+        assert_eq!(written, "a.example reloaded\n");
+        std::fs::remove_file(&out).unwrap();
+    }
+
+    #[test]
+    fn exports_deployed_certificates() {
+        let dir = std::env::temp_dir().join(format!("tide-acme-exports-{}", std::process::id()));
+        let state = Arc::new(AcmeConfig::new(["a.example"]).export_dir(&dir).state());
+        let mut cert = state.certs().remove(0);
+        cert.process_cert(Zeroizing::new(pem("a.example")), CertSource::Cache)
+            .unwrap();
+        let deployed: Event<Infallible, Infallible> = Ok(EventOk::DeployedCachedCert);
+        block_on(cert.run_hooks(&AcmeEvent::new(cert.domains(), None, &deployed)));
+        let chain = std::fs::read_to_string(dir.join("a.example/fullchain.pem")).unwrap();
+        assert!(chain.contains("BEGIN CERTIFICATE"));
+        let key = std::fs::read_to_string(dir.join("a.example/privkey.pem")).unwrap();
+        assert!(key.contains("PRIVATE KEY"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn runs_hooks_for_obtained_certificates_and_failed_orders() {
+        let calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (obtained, failed) = (calls.clone(), calls.clone());
+        let config = AcmeConfig::new(["a.example"])
+            .on_cert_obtained(move |rotation| {
+                let domains = rotation.status().domains.clone();
+                obtained.lock().unwrap().push(("obtained", domains));
+                async {}
+            })
+            .on_order_failed(move |event, status| {
+                assert_eq!(event.kind, EventKind::OrderFailed);
+                failed.lock().unwrap().push(("failed", status.domains));
+                async {}
+            });
+        let state = Arc::new(config.state());
+        let mut cert = state.certs().remove(0);
+        let event = |event: Event<Infallible, Infallible>| {
+            AcmeEvent::new(&["a.example".to_string()], None, &event)
+        };
+        // Certificates loaded from the cache weren't obtained just now.
+        cert.process_cert(Zeroizing::new(pem("a.example")), CertSource::Cache)
+            .unwrap();
+        block_on(cert.run_hooks(&event(Ok(EventOk::DeployedCachedCert))));
+        cert.process_cert(Zeroizing::new(pem("a.example")), CertSource::Cache)
+            .unwrap();
+        block_on(cert.run_hooks(&event(Ok(EventOk::DeployedNewCert))));
+        block_on(cert.run_hooks(&event(Err(EventError::Order(
+            OrderError::TooManyAttemptsOrder,
+        )))));
+        let domains = vec!["a.example".to_string()];
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [("obtained", domains.clone()), ("failed", domains)]
+        );
+    }
+
+    #[test]
+    fn passes_events_to_the_callback() {
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let events = seen.clone();
+        let config = AcmeConfig::new(["domain.example"])
+            .on_event(move |event: &AcmeEvent| events.lock().unwrap().push(event.kind));
+        let state = Arc::new(config.state());
+        let mut cert = state.certs().remove(0);
+        cert.account_registered = true;
+        let event = block_on(cert.next());
+        assert!(matches!(event, Ok(EventOk::AccountRegistered)));
+        cert.report(&AcmeEvent::new(cert.domains(), None, &event));
+        let failed: Event<Infallible, Infallible> =
+            Err(EventError::Order(OrderError::TooManyAttemptsOrder));
+        cert.report(&AcmeEvent::new(cert.domains(), None, &failed));
+        // Unlike the error reporter, the callback sees every event.
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [EventKind::AccountRegistered, EventKind::OrderFailed]
+        );
+    }
+
+    #[test]
+    fn keeps_static_certificates_whole() {
+        let config = AcmeConfig::new(["a.example", "b.example"])
+            .cert_per_domain(true)
+            .static_certificate(
+                ["static.example", "www.static.example"],
+                StaticCertificate::new(pem("static.example")),
+            );
+        let state = Arc::new(config.state());
+        let domains: Vec<_> = state
+            .certs()
+            .iter()
+            .map(|cert| cert.domains().to_vec())
+            .collect();
+        assert_eq!(
+            domains,
+            [
+                vec!["a.example".to_string()],
+                vec!["b.example".into()],
+                vec!["static.example".into(), "www.static.example".into()],
+            ]
+        );
+    }
+
+    #[test]
+    fn reencrypts_the_cache() {
+        use crate::EncryptedCache;
+
+        let inner = MemoryCache::default();
+        let domains = vec!["a.example".to_string()];
+        let directory_url = AcmeConfig::new(["a.example"])
+            .state()
+            .directory(0)
+            .url
+            .clone();
+        let old = EncryptedCache::new(inner.clone(), [1; 32]);
+        block_on(old.store_cert(&domains, &directory_url, b"cert")).unwrap();
+        let sealed = inner.cert(&["a.example"]).unwrap();
+        let rotated = EncryptedCache::new(inner.clone(), [2; 32]).previous_key([1; 32]);
+        let state = Arc::new(AcmeConfig::new(["a.example"]).cache(rotated).state());
+        let mut cert = state.certs().remove(0);
+        assert!(matches!(
+            block_on(cert.command(Command::Reencrypt)),
+            Ok(EventOk::CacheReencrypted)
+        ));
+        assert_ne!(inner.cert(&["a.example"]).unwrap(), sealed);
+        let current = EncryptedCache::new(inner, [2; 32]);
+        let loaded = block_on(current.load_cert(&domains, &directory_url)).unwrap();
+        assert_eq!(loaded.as_deref(), Some(&b"cert"[..]));
+    }
+
+    #[test]
+    fn stores_orders_in_progress() {
+        let cache = MemoryCache::default();
+        let state = Arc::new(AcmeConfig::new(["a.example"]).cache(cache.clone()).state());
+        let directory = &state.directory(0);
+        let domains = vec!["a.example".to_string()];
+        let load = || block_on(CertState::load_order(&state, directory, &domains));
+        let store = |url: &str, key: &str| {
+            block_on(CertState::store_order(
+                &state, directory, &domains, url, key,
+            ))
+        };
+        let url = "https://ca.example/order/1";
+        let key_pair = rcgen::KeyPair::generate(&PKCS_ECDSA_P256_SHA256).unwrap();
+        store(url, &key_pair.serialize_pem());
+        let (loaded, loaded_key) = load().unwrap();
+        assert_eq!(loaded, url);
+        let loaded_key = match block_on(CsrKey::load(&loaded_key.unwrap(), None)) {
+            Some(CsrKey::Local(key_pair)) => *key_pair,
+            _ => panic!("the key of the order wasn't loaded"),
+        };
+        assert_eq!(loaded_key.public_key_raw(), key_pair.public_key_raw());
+        // Orders kept without a key, as they were before keys were kept, have none.
+        store(url, "");
+        assert!(matches!(load(), Some((loaded, None)) if loaded == url));
+        cache.certs.lock().unwrap().insert(
+            vec!["order".into(), "a.example".into()],
+            url.as_bytes().to_vec(),
+        );
+        assert!(matches!(load(), Some((loaded, None)) if loaded == url));
+        // Orders are stored as JSON.
+        store(url, "");
+        let entry = cache.cert(&["order", "a.example"]).unwrap();
+        let (entry, _) = format::read(entry, EntryKind::Order).unwrap();
+        assert_eq!(format::order(&entry).unwrap().url, url);
+        // A finished order is stored as empty, and isn't resumed.
+        store("", "");
+        assert_eq!(cache.cert(&["order", "a.example"]).unwrap(), b"");
+        assert!(load().is_none());
+    }
+
+    #[test]
+    fn exposes_deployed_certificates_as_der() {
+        let state = Arc::new(AcmeConfig::new(["a.example"]).state());
+        let mut cert = state.certs().remove(0);
+        let handle = state.handle();
+        assert!(handle.certificate_der("a.example").is_none());
+        let pem = pem("a.example");
+        cert.process_cert(Zeroizing::new(pem.clone()), CertSource::Cache)
+            .unwrap();
+        let pems = ::pem::parse_many(&pem).unwrap();
+        let chain = handle.certificate_der("a.example").unwrap();
+        assert_eq!(chain[0], pems[1].contents);
+        assert_eq!(
+            handle.private_key_der("a.example").unwrap(),
+            pems[0].contents
+        );
+        let certified_key = handle.certified_key("a.example").unwrap();
+        assert_eq!(certified_key.cert[0].0, chain[0]);
+    }
+
+    #[test]
+    fn gives_directory_groups_their_own_accounts() {
+        let config = AcmeConfig::new(["a.example"])
+            .contact_push("mailto:ops@a.example")
+            .directory_group_contact(
+                "https://ca.internal/directory",
+                ["b.example"],
+                ["mailto:ops@b.example"],
+            )
+            .directory_group_contact(
+                "https://ca.internal/directory",
+                ["c.example"],
+                ["mailto:ops@c.example"],
+            )
+            .directory_group("https://ca.internal/directory", ["d.example"]);
+        let state = Arc::new(config.state());
+        let certs = state.certs();
+        let directories: Vec<_> = certs.iter().map(|cert| cert.directory).collect();
+        assert_eq!(directories, [0, 1, 2, 3]);
+        let contacts: Vec<_> = directories
+            .iter()
+            .map(|&directory| state.contact(&state.directory(directory)))
+            .collect();
+        assert_eq!(
+            contacts,
+            [
+                ["mailto:ops@a.example"],
+                ["mailto:ops@b.example"],
+                ["mailto:ops@c.example"],
+                ["mailto:ops@a.example"],
+            ]
+        );
+        assert_eq!(state.directory(1).url, "https://ca.internal/directory");
+        assert_eq!(state.directory(2).url, state.directory(1).url);
+    }
+
+    #[test]
+    fn routes_failures_and_contacts_per_domain() {
+        let reported = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (main, team) = (reported.clone(), reported.clone());
+        let config = AcmeConfig::new(["a.example"])
+            .contact_push("mailto:ops@a.example")
+            .domain_contact(["b.example"], ["mailto:team@b.example"])
+            .error_reporter(move |event: &AcmeEvent| {
+                main.lock().unwrap().push(("main", event.domains.clone()))
+            })
+            .domain_error_reporter(["B.example"], move |event: &AcmeEvent| {
+                team.lock().unwrap().push(("team", event.domains.clone()))
+            });
+        let state = Arc::new(config.state());
+        let certs = state.certs();
+        let directories: Vec<_> = certs.iter().map(|cert| cert.directory).collect();
+        assert_eq!(directories, [0, 1]);
+        // Domains with their own contact use the main directory, with an account of their own.
+        assert_eq!(state.directory(1).url, state.directory(0).url);
+        assert_eq!(state.contact(&state.directory(0)), ["mailto:ops@a.example"]);
+        assert_eq!(
+            state.contact(&state.directory(1)),
+            ["mailto:team@b.example"]
+        );
+        let failed: Event<Infallible, Infallible> =
+            Err(EventError::NewCertParse(CertParseError::KeyMismatch));
+        for cert in &certs {
+            cert.report(&AcmeEvent::new(cert.domains(), None, &failed));
+        }
+        assert_eq!(
+            *reported.lock().unwrap(),
+            [
+                ("main", vec!["a.example".to_string()]),
+                ("team", vec!["b.example".to_string()]),
+            ]
+        );
+    }
+
+    /// A key provider supplying a fixed key, or none, and recording the keys it escrows.
+    struct FixedKey(Option<Vec<u8>>, Arc<std::sync::Mutex<Vec<Vec<u8>>>>);
+
+    #[async_trait]
+    impl KeyProvider for FixedKey {
+        async fn supply_key(
+            &self,
+            _domains: &[String],
+        ) -> Result<Option<Vec<u8>>, KeyProviderError> {
+            Ok(self.0.clone())
+        }
+
+        async fn key_generated(
+            &self,
+            _domains: &[String],
+            key: &[u8],
+        ) -> Result<(), KeyProviderError> {
+            self.1.lock().unwrap().push(key.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn uses_keys_from_the_key_provider() {
+        let domains = vec!["a.example".to_string()];
+        let csr = Csr::new(&domains);
+        let escrowed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let supplied = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P384_SHA384).unwrap();
+        let state = AcmeConfig::new(["a.example"])
+            .key_provider(FixedKey(Some(supplied.serialize_der()), escrowed.clone()))
+            .state();
+        let directory = &state.directory(0);
+        let (cert, _, _) = block_on(CertState::certificate(&state, directory, &csr, None)).unwrap();
+        assert_eq!(cert.serialize_private_key_der(), supplied.serialize_der());
+        assert_eq!(*escrowed.lock().unwrap(), [supplied.serialize_der()]);
+
+        let state = AcmeConfig::new(["a.example"])
+            .key_provider(FixedKey(None, escrowed.clone()))
+            .state();
+        let directory = &state.directory(0);
+        let (cert, _, _) = block_on(CertState::certificate(&state, directory, &csr, None)).unwrap();
+        assert_eq!(
+            escrowed.lock().unwrap()[1],
+            cert.serialize_private_key_der()
+        );
+
+        let state = AcmeConfig::new(["a.example"])
+            .key_provider(FixedKey(Some(b"junk".to_vec()), escrowed.clone()))
+            .state();
+        let directory = &state.directory(0);
+        assert!(block_on(CertState::certificate(&state, directory, &csr, None)).is_err());
+        // The key of a resumed order is used as is, without asking the key provider.
+        let resumed = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P384_SHA384).unwrap();
+        let der = resumed.serialize_der();
+        let (cert, alg, remote_key) = block_on(CertState::certificate(
+            &state,
+            directory,
+            &csr,
+            Some(CsrKey::Local(Box::new(resumed))),
+        ))
+        .unwrap();
+        assert!(remote_key.is_none());
+        assert_eq!(cert.serialize_private_key_der(), der);
+        assert_eq!(alg, &rcgen::PKCS_ECDSA_P384_SHA384);
+        assert_eq!(escrowed.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn requests_customized_certificates() {
+        let state = AcmeConfig::new(["a.example"]).state();
+        let directory = &state.directory(0);
+        let mut csr = Csr::new(&["a.example".into()]);
+        csr.add_name("www.a.example")
+            .subject(crate::SubjectField::Organization, "Example Inc.");
+        let (cert, _, _) = block_on(CertState::certificate(&state, directory, &csr, None)).unwrap();
+        let der = cert.serialize_der().unwrap();
+        let (_, parsed) = parse_x509_certificate(&der).unwrap();
+        let names: Vec<_> = parsed
+            .subject_alternative_name()
+            .unwrap()
+            .unwrap()
+            .value
+            .general_names
+            .iter()
+            .map(|name| format!("{}", name))
+            .collect();
+        assert_eq!(names, ["DNSName(a.example)", "DNSName(www.a.example)"]);
+        let organization = parsed.subject().iter_organization().next().unwrap();
+        assert_eq!(organization.as_str().unwrap(), "Example Inc.");
+    }
+
+    #[test]
+    fn keeps_keys_in_the_key_store() {
+        let state = Arc::new(
+            AcmeConfig::new(["a.example"])
+                .key_store(crate::key_store::MemoryKeyStore::default())
+                .state(),
+        );
+        let directory = &state.directory(0);
+        let csr = Csr::new(&["a.example".into()]);
+        let (cert, alg, remote_key) =
+            block_on(CertState::certificate(&state, directory, &csr, None)).unwrap();
+        let remote_key = remote_key.unwrap();
+        assert_eq!(alg, &rcgen::PKCS_ECDSA_P256_SHA256);
+        let keyless = cert.serialize_pem().unwrap().into_bytes();
+        assert_eq!(
+            key_store::keyless_public_key(&keyless).as_deref(),
+            Some(remote_key.public_key())
+        );
+
+        // Certificates kept without their key are parsed once the key store has it.
+        let fresh = Arc::new(
+            AcmeConfig::new(["a.example"])
+                .key_store(crate::key_store::MemoryKeyStore::default())
+                .state(),
+        );
+        assert!(matches!(
+            CertState::<Infallible, Infallible>::parse_cert(&keyless, &fresh.remote_keys),
+            Err(CertParseError::RemoteKeyMissing)
+        ));
+        block_on(state.load_remote_key(&keyless));
+        let mut cert = state.certs().remove(0);
+        let event = cert.process_cert(Zeroizing::new(keyless), CertSource::Cache);
+        assert!(matches!(event, Ok(EventOk::DeployedCachedCert)));
+        assert_eq!(state.handle().private_key_pem("a.example"), None);
+    }
+
+    #[test]
+    fn generates_keys_of_the_configured_algorithm() {
+        let domains = vec!["a.example".to_string()];
+        let csr = Csr::new(&domains);
+        let state = AcmeConfig::new(["a.example"])
+            .cert_key_algorithm(CertKeyAlgorithm::EcdsaP384)
+            .state();
+        let directory = &state.directory(0);
+        let (cert, _, _) = block_on(CertState::certificate(&state, directory, &csr, None)).unwrap();
+        let key = rcgen::KeyPair::from_der(&cert.serialize_private_key_der()).unwrap();
+        assert!(key.is_compatible(&rcgen::PKCS_ECDSA_P384_SHA384));
+    }
+
+    #[test]
+    fn reuses_the_current_key_if_configured() {
+        let domains = vec!["a.example".to_string()];
+        let csr = Csr::new(&domains);
+        let current = pem("a.example");
+        let cache = MemoryCache::default();
+        cache
+            .certs
+            .lock()
+            .unwrap()
+            .insert(domains.clone(), format::stamp(&current).to_vec());
+        let current_key = pem::parse_many(&current).unwrap().remove(0).contents;
+
+        let state = AcmeConfig::new(["a.example"])
+            .cache(cache.clone())
+            .reuse_cert_key(true)
+            .state();
+        let directory = &state.directory(0);
+        let (cert, _, _) = block_on(CertState::certificate(&state, directory, &csr, None)).unwrap();
+        assert_eq!(cert.serialize_private_key_der(), current_key);
+
+        let state = AcmeConfig::new(["a.example"]).cache(cache.clone()).state();
+        let directory = &state.directory(0);
+        let (cert, _, _) = block_on(CertState::certificate(&state, directory, &csr, None)).unwrap();
+        assert_ne!(cert.serialize_private_key_der(), current_key);
+
+        let state = AcmeConfig::new(["a.example"])
+            .cache(cache)
+            .reuse_cert_key(true)
+            .cert_key_algorithm(CertKeyAlgorithm::EcdsaP384)
+            .state();
+        let directory = &state.directory(0);
+        let (cert, _, _) = block_on(CertState::certificate(&state, directory, &csr, None)).unwrap();
+        assert_ne!(cert.serialize_private_key_der(), current_key);
+    }
+
+    /// A cache in memory failing its next stores, as many as `failures`.
+    #[derive(Clone, Default)]
+    struct FlakyCache {
+        failures: Arc<std::sync::atomic::AtomicUsize>,
+        entries: Arc<std::sync::Mutex<HashMap<Vec<String>, Vec<u8>>>>,
+    }
+
+    impl FlakyCache {
+        fn failing(failures: usize) -> Self {
+            let cache = Self::default();
+            cache.failures.store(failures, Ordering::SeqCst);
+            cache
+        }
+
+        fn store(&self, key: &[String], entry: &[u8]) -> Result<(), &'static str> {
+            let failures = &self.failures;
+            if failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                return Err("unavailable");
+            }
+            self.entries
+                .lock()
+                .unwrap()
+                .insert(key.to_vec(), entry.to_vec());
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl CertCache for FlakyCache {
+        type EC = &'static str;
+
+        async fn load_cert(
+            &self,
+            domains: &[String],
+            _directory_url: &str,
+        ) -> Result<Option<Vec<u8>>, &'static str> {
+            Ok(self.entries.lock().unwrap().get(domains).cloned())
+        }
+
+        async fn store_cert(
+            &self,
+            domains: &[String],
+            _directory_url: &str,
+            cert: &[u8],
+        ) -> Result<(), &'static str> {
+            self.store(domains, cert)
+        }
+    }
+
+    #[async_trait]
+    impl AccountCache for FlakyCache {
+        type EA = &'static str;
+
+        async fn load_account(
+            &self,
+            contact: &[String],
+            _directory_url: &str,
+        ) -> Result<Option<Vec<u8>>, &'static str> {
+            Ok(self.entries.lock().unwrap().get(contact).cloned())
+        }
+
+        async fn store_account(
+            &self,
+            contact: &[String],
+            _directory_url: &str,
+            account: &[u8],
+        ) -> Result<(), &'static str> {
+            self.store(contact, account)
+        }
+    }
+
+    #[test]
+    fn retries_failed_cache_stores() {
+        let key = vec!["a.example".to_string()];
+        let state = AcmeConfig::new(["a.example"])
+            .cache(FlakyCache::failing(1))
+            .cache_store_retries(1)
+            .state();
+        let directory = &state.directory(0);
+        assert!(matches!(
+            block_on(state.store_cert(directory, &key, b"cert")),
+            Ok(Stored::Main)
+        ));
+        let state = AcmeConfig::new(["a.example"])
+            .cache(FlakyCache::failing(1))
+            .state();
+        let directory = &state.directory(0);
+        assert!(matches!(
+            block_on(state.store_account(directory, &key, b"key")),
+            Err("unavailable")
+        ));
+    }
+
+    #[test]
+    fn falls_back_to_the_secondary_cache() {
+        let key = vec!["a.example".to_string()];
+        let secondary = FlakyCache::default();
+        let state = AcmeConfig::new(["a.example"])
+            .cache(FlakyCache::failing(2))
+            .secondary_cache(secondary.clone())
+            .state();
+        let directory = &state.directory(0);
+        assert!(matches!(
+            block_on(state.store_cert(directory, &key, b"cert")),
+            Ok(Stored::Secondary)
+        ));
+        let contact = vec!["mailto:ops@a.example".to_string()];
+        assert!(matches!(
+            block_on(state.store_account(directory, &contact, b"key")),
+            Ok(Stored::Secondary)
+        ));
+        assert_eq!(secondary.entries.lock().unwrap().len(), 2);
+        // Entries missing from the main cache are loaded from the secondary one.
+        assert_eq!(
+            block_on(state.load_cert(directory, &key)).unwrap(),
+            Some(b"cert".to_vec())
+        );
+        assert_eq!(
+            block_on(state.load_account(directory, &contact)).unwrap(),
+            Some(b"key".to_vec())
+        );
+    }
+
+    #[test]
+    fn treats_cache_store_failures_as_fatal_when_configured() {
+        let state = Arc::new(
+            AcmeConfig::new(["a.example"])
+                .cache(FlakyCache::default())
+                .cache_store_fatal(true)
+                .state(),
+        );
+        let cert = state.certs().remove(0);
+        assert!(cert.is_fatal(&Err(EventError::CertCacheStore("unavailable"))));
+        assert!(cert.is_fatal(&Err(EventError::AccountCacheStore("unavailable"))));
+        assert!(!cert.is_fatal(&Err(EventError::CertCacheLoad("unavailable"))));
+        assert!(!cert.is_fatal(&Ok(EventOk::CertCacheStore)));
+    }
+
+    #[test]
+    fn skips_preflight_checks_where_they_dont_apply() {
+        let skipped = vec!["*.domain.example".to_string(), "192.0.2.1".to_string()];
+        let state = AcmeConfig::new(Vec::<String>::new())
+            .preflight_check(true)
+            .state();
+        block_on(state.preflight_check(&skipped)).unwrap();
+        let unresolvable = vec!["domain.invalid".to_string()];
+        assert!(matches!(
+            block_on(state.preflight_check(&unresolvable)),
+            Err(OrderError::Preflight(_))
+        ));
+        let state = AcmeConfig::new(Vec::<String>::new()).state();
+        block_on(state.preflight_check(&unresolvable)).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+use std::fmt;
+
+/// The effective configuration, after defaults and overrides are applied, as returned by
+/// [`AcmeHandle::config_summary`](crate::AcmeHandle::config_summary) and logged on startup.
+///
+/// Printing it shows at a glance whether certificates come from production or staging, and
+/// which cache keeps them.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct ConfigSummary {
+    /// The acceptor's name, if it was given one with
+    /// [`AcmeConfig::name`](crate::AcmeConfig::name).
+    pub name: Option<String>,
+    /// The main ACME directory URL.
+    pub directory_url: String,
+    /// Whether the main directory is Let's Encrypt staging, whose certificates browsers don't
+    /// trust.
+    pub staging: bool,
+    /// The managed domains, grouped by the directory or certificate source they come from. The
+    /// main directory comes first.
+    pub groups: Vec<GroupSummary>,
+    /// The cache backend's type name, or `None` if certificates and the account key are only
+    /// kept in memory.
+    pub cache: Option<String>,
+    /// The challenge types answered, in order of preference.
+    pub challenge_types: Vec<String>,
+    /// When certificates are renewed.
+    pub renewal: String,
+    /// Whether each domain gets its own certificate.
+    pub cert_per_domain: bool,
+    /// How many previously served certificates are kept for rollback.
+    pub previous_certs: usize,
+    /// Whether each order is tried against Let's Encrypt staging first.
+    pub staging_check: bool,
+    /// Whether certificates are also ordered on demand, for domains seen in TLS handshakes.
+    pub on_demand: bool,
+    /// The directory certificates are exported to, if any; see
+    /// [`AcmeConfig::export_dir`](crate::AcmeConfig::export_dir).
+    pub export_dir: Option<std::path::PathBuf>,
+}
+
+/// Domains whose certificates come from the same ACME directory or certificate source.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct GroupSummary {
+    /// The ACME directory URL, or the certificate source's name.
+    pub directory: String,
+    /// Whether the certificates come from a certificate source rather than an ACME directory.
+    pub source: bool,
+    /// The domains.
+    pub domains: Vec<String>,
+}
+
+impl fmt::Display for ConfigSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let environment = if self.staging { " (staging)" } else { "" };
+        if let Some(name) = &self.name {
+            writeln!(f, "acceptor: {}", name)?;
+        }
+        writeln!(f, "directory: {}{}", self.directory_url, environment)?;
+        for group in &self.groups {
+            let kind = if group.source { "source" } else { "directory" };
+            writeln!(
+                f,
+                "domains: {} ({} {})",
+                group.domains.join(", "),
+                kind,
+                group.directory
+            )?;
+        }
+        match &self.cache {
+            Some(cache) => writeln!(f, "cache: {}", cache)?,
+            None => writeln!(f, "cache: none, in memory only")?,
+        }
+        writeln!(f, "challenges: {}", self.challenge_types.join(", "))?;
+        writeln!(
+            f,
+            "renewal: {}; {} per certificate; {} previous kept",
+            self.renewal,
+            if self.cert_per_domain {
+                "one domain"
+            } else {
+                "all domains"
+            },
+            self.previous_certs
+        )?;
+        if let Some(dir) = &self.export_dir {
+            writeln!(f, "export: {}", dir.display())?;
+        }
+        write!(
+            f,
+            "staging check: {}; on demand: {}",
+            self.staging_check, self.on_demand
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AcmeConfig, AcmeHandle};
+
+    #[test]
+    fn summarizes_the_configuration() {
+        let handle: AcmeHandle = AcmeConfig::new(["a.example", "b.example"])
+            .cert_per_domain(true)
+            .cache_none()
+            .state()
+            .handle();
+        let summary = handle.config_summary();
+        assert!(summary.staging);
+        assert_eq!(summary.cache, None);
+        assert_eq!(summary.groups.len(), 1);
+        assert_eq!(summary.groups[0].domains, ["a.example", "b.example"]);
+        assert!(!summary.groups[0].source);
+        assert_eq!(
+            summary.to_string(),
+            format!(
+                "directory: {url} (staging)\n\
+                 domains: a.example, b.example (directory {url})\n\
+                 cache: none, in memory only\n\
+                 challenges: {challenges}\n\
+                 renewal: two thirds of the way through the validity period; one domain per \
+                 certificate; {previous} previous kept\n\
+                 staging check: {staging_check}; on demand: false",
+                url = summary.directory_url,
+                challenges = summary.challenge_types.join(", "),
+                previous = summary.previous_certs,
+                staging_check = summary.staging_check,
+            )
+        );
+    }
+
+    #[test]
+    fn names_the_acceptor() {
+        let handle: AcmeHandle = AcmeConfig::new(["a.example"])
+            .name("admin")
+            .cache_none()
+            .state()
+            .handle();
+        assert_eq!(handle.name().as_deref(), Some("admin"));
+        assert!(handle
+            .config_summary()
+            .to_string()
+            .starts_with("acceptor: admin\n"));
+    }
+}
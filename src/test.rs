@@ -0,0 +1,194 @@
+//! Helpers for end-to-end tests against a local [Pebble](https://github.com/letsencrypt/pebble)
+//! ACME server, so tests can obtain certificates without touching Let's Encrypt staging.
+//!
+//! Start Pebble with its validation ports pointing at the test's listener, such as
+//! `tlsPort: 5001` in its configuration, and `PEBBLE_VA_NOSLEEP=1` so it validates without
+//! delay. The domains must resolve to the machine running the test, such as with
+//! `pebble -dnsserver` and pebble-challtestsrv, or by using `localhost` names.
+//!
+//! ```no_run
+//! use tide_acme::test::{pebble_config, PEBBLE_TLS_ALPN_PORT};
+//! use tide_acme::AcmeTlsAcceptor;
+//!
+//! # async_std::task::block_on(async {
+//! let acceptor = AcmeTlsAcceptor::new(pebble_config(vec!["test.localhost"]));
+//! let handle = acceptor.handle();
+//! let mut app = tide::new();
+//! app.at("/").get(|_| async { Ok("Hello TLS") });
+//! async_std::task::spawn(app.listen(
+//!     tide_rustls::TlsListener::build()
+//!         .addrs(("127.0.0.1", PEBBLE_TLS_ALPN_PORT))
+//!         .tls_acceptor(std::sync::Arc::new(acceptor)),
+//! ));
+//! let status = handle.ready().await;
+//! assert!(status.not_after.is_some());
+//! # });
+//! ```
+
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use event_listener::Event;
+
+use tide_rustls::async_rustls::webpki::DNSNameRef;
+use tide_rustls::rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+};
+
+use crate::{AcmeConfig, Clock, Profile};
+
+/// The directory URL of a Pebble server running locally with its default settings.
+pub const PEBBLE_DIRECTORY_URL: &str = "https://localhost:14000/dir";
+
+/// The port Pebble's example configuration sends tls-alpn-01 validation requests to.
+pub const PEBBLE_TLS_ALPN_PORT: u16 = 5001;
+
+/// A configuration obtaining certificates for `domains` from the Pebble server at
+/// [`PEBBLE_DIRECTORY_URL`], without verifying its TLS certificate, with the
+/// [development](Profile::Development) defaults. Set another directory with
+/// [`AcmeConfig::directory`] to use a Pebble server elsewhere.
+pub fn pebble_config(
+    domains: impl IntoIterator<Item = impl AsRef<str>>,
+) -> AcmeConfig<Infallible, Infallible> {
+    AcmeConfig::new(domains)
+        .preset(Profile::Development)
+        .directory(PEBBLE_DIRECTORY_URL)
+        .directory_tls_config(insecure_client_config())
+}
+
+/// A TLS client configuration accepting any server certificate, for
+/// [`AcmeConfig::directory_tls_config`] with test CAs, such as Pebble, whose HTTPS certificate
+/// changes with each release. Never use it with a CA on an untrusted network.
+pub fn insecure_client_config() -> ClientConfig {
+    let mut config = ClientConfig::new();
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(AcceptAnyCertificate));
+    config
+}
+
+/// Accepts any server certificate.
+struct AcceptAnyCertificate;
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        _dns_name: DNSNameRef<'_>,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// A [`Clock`] that only moves when told to, for [`AcmeConfig::clock`], so tests can fast-forward
+/// to renewals, through backoffs and to expiry warnings.
+///
+/// It starts at the system time when created. Clones share the same time.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    inner: Arc<ManualClockInner>,
+}
+
+#[derive(Debug)]
+struct ManualClockInner {
+    now: Mutex<SystemTime>,
+    /// Notified whenever the time moves, waking sleepers to check whether they are due.
+    moved: Event,
+}
+
+impl ManualClock {
+    /// A clock at the current system time.
+    pub fn new() -> Self {
+        Self::at(SystemTime::now())
+    }
+
+    /// A clock at `now`.
+    pub fn at(now: SystemTime) -> Self {
+        Self {
+            inner: Arc::new(ManualClockInner {
+                now: Mutex::new(now),
+                moved: Event::new(),
+            }),
+        }
+    }
+
+    /// Move the time forward by `duration`, waking whatever was waiting until then.
+    pub fn advance(&self, duration: Duration) {
+        *self.inner.now.lock().unwrap() += duration;
+        self.inner.moved.notify(usize::MAX);
+    }
+
+    /// Set the time to `now`, which may be in the past, waking whatever was waiting until then.
+    pub fn set(&self, now: SystemTime) {
+        *self.inner.now.lock().unwrap() = now;
+        self.inner.moved.notify(usize::MAX);
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.inner.now.lock().unwrap()
+    }
+
+    async fn sleep_until(&self, at: SystemTime) {
+        loop {
+            // Listen before checking, so a move in between isn't missed.
+            let moved = self.inner.moved.listen();
+            if self.now() >= at {
+                return;
+            }
+            moved.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use async_std::task::block_on;
+
+    use super::*;
+
+    #[test]
+    fn wakes_sleepers_when_moved() {
+        let clock = ManualClock::at(UNIX_EPOCH);
+        block_on(async {
+            let mut sleeping = Box::pin(clock.sleep(Duration::from_secs(60)));
+            assert!(futures::poll!(&mut sleeping).is_pending());
+            clock.advance(Duration::from_secs(30));
+            assert!(futures::poll!(&mut sleeping).is_pending());
+            clock.set(UNIX_EPOCH + Duration::from_secs(60));
+            sleeping.await;
+            // Times already past don't wait.
+            clock.sleep_until(UNIX_EPOCH).await;
+        });
+        assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_secs(60));
+    }
+
+    #[test]
+    fn configures_pebble() {
+        let config = pebble_config(vec!["test.localhost"]);
+        assert_eq!(config.directory_url, PEBBLE_DIRECTORY_URL);
+        assert!(!config.staging_check && !config.wait_for_not_before);
+        let cert = rcgen::generate_simple_self_signed(vec!["other.example".into()]).unwrap();
+        let verified = AcceptAnyCertificate.verify_server_cert(
+            &RootCertStore::empty(),
+            &[Certificate(cert.serialize_der().unwrap())],
+            DNSNameRef::try_from_ascii_str("test.localhost").unwrap(),
+            &[],
+        );
+        assert!(verified.is_ok());
+    }
+}
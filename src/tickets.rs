@@ -0,0 +1,531 @@
+//! TLS session resumption: the session cache, and session tickets encrypted with rotating keys,
+//! optionally shared with other instances through the cache.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use tide_rustls::rustls::{
+    NoServerSessionStorage, ProducesTickets, ServerConfig, ServerSessionMemoryCache,
+    StoresServerSessions,
+};
+use tracing::{debug, warn};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::delegation::SharedCache;
+use crate::format::{self, EntryKind};
+
+/// The longest lifetime of session tickets TLS 1.3 allows.
+const MAX_TICKET_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How often instances sharing ticket keys check the cache for keys another one generated, at
+/// most.
+const SYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The length of ticket keys, for ChaCha20-Poly1305.
+const KEY_LEN: usize = 32;
+
+/// The length of the key period number starting each ticket.
+const PERIOD_LEN: usize = 8;
+
+/// The cache key for the ticket keys shared with other instances.
+fn ticket_keys_key() -> Vec<String> {
+    vec!["tls-session-ticket-keys".into()]
+}
+
+/// Configuration of TLS session resumption, for
+/// [`AcmeConfig::session_resumption`](crate::AcmeConfig::session_resumption), letting returning
+/// clients skip the full handshake.
+///
+/// Resumption works with session tickets, which the server encrypts and clients keep, and with
+/// a cache of sessions on the server, for clients that don't support tickets. The keys
+/// encrypting tickets are rotated each ticket lifetime, and forgotten after two, so a leaked key
+/// only exposes recent sessions.
+///
+/// Behind a load balancer, returning clients may reach another replica, which can only decrypt
+/// their tickets if it has the same keys: [`shared_ticket_keys`](Self::shared_ticket_keys) keeps
+/// them in the cache, where each replica picks them up.
+///
+/// ```
+/// use std::time::Duration;
+/// use tide_acme::{AcmeConfig, SessionResumption};
+///
+/// let config = AcmeConfig::new(vec!["domain.example"]).session_resumption(
+///     SessionResumption::new()
+///         .ticket_lifetime(Duration::from_secs(6 * 60 * 60))
+///         .shared_ticket_keys(true),
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct SessionResumption {
+    pub(crate) tickets: bool,
+    pub(crate) ticket_lifetime: Duration,
+    pub(crate) shared_ticket_keys: bool,
+    pub(crate) session_cache: usize,
+}
+
+impl SessionResumption {
+    /// Resume sessions with tickets valid for 12 hours, with keys kept by this instance only,
+    /// and with a cache of 256 sessions.
+    pub fn new() -> Self {
+        Self {
+            tickets: true,
+            ticket_lifetime: Duration::from_secs(12 * 60 * 60),
+            shared_ticket_keys: false,
+            session_cache: 256,
+        }
+    }
+
+    /// Never resume sessions: every connection makes a full handshake.
+    pub fn disabled() -> Self {
+        Self {
+            tickets: false,
+            session_cache: 0,
+            ..Self::new()
+        }
+    }
+
+    /// Issue session tickets if `tickets` is true. Defaults to true.
+    pub fn tickets(mut self, tickets: bool) -> Self {
+        self.tickets = tickets;
+        self
+    }
+
+    /// Tell clients that tickets are valid for `lifetime`, and rotate the keys encrypting them
+    /// as often. Defaults to 12 hours, and is capped at the 7 days TLS 1.3 allows.
+    pub fn ticket_lifetime(mut self, lifetime: Duration) -> Self {
+        self.ticket_lifetime = lifetime.clamp(Duration::from_secs(1), MAX_TICKET_LIFETIME);
+        self
+    }
+
+    /// Keep the keys encrypting tickets in the cache if `shared` is true, so that the instances
+    /// sharing the cache decrypt each other's tickets. Defaults to false.
+    ///
+    /// Each instance checks the cache every minute, or more often for short ticket lifetimes.
+    /// The keys for the next period are stored a period ahead, so the instances agree on them
+    /// by the time they're used, and rotate together as long as their clocks are in sync.
+    /// Anyone reading the cache can decrypt tickets, and with them past sessions, so protect it
+    /// as well as the private keys of the certificates it holds.
+    pub fn shared_ticket_keys(mut self, shared: bool) -> Self {
+        self.shared_ticket_keys = shared;
+        self
+    }
+
+    /// Keep up to `size` sessions in memory, for clients resuming without tickets. Defaults to
+    /// 256; zero disables the cache.
+    pub fn session_cache(mut self, size: usize) -> Self {
+        self.session_cache = size;
+        self
+    }
+}
+
+impl Default for SessionResumption {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The session cache and ticketer, shared by every configuration the acceptor builds.
+#[derive(Clone)]
+pub(crate) struct Resumption {
+    storage: Arc<dyn StoresServerSessions + Send + Sync>,
+    ticketer: Arc<Ticketer>,
+}
+
+impl Resumption {
+    pub(crate) fn new(config: &SessionResumption) -> Self {
+        let storage: Arc<dyn StoresServerSessions + Send + Sync> = match config.session_cache {
+            0 => Arc::new(NoServerSessionStorage {}),
+            size => ServerSessionMemoryCache::new(size),
+        };
+        Self {
+            storage,
+            ticketer: Arc::new(Ticketer::new(config.tickets, config.ticket_lifetime)),
+        }
+    }
+
+    pub(crate) fn ticketer(&self) -> Arc<Ticketer> {
+        self.ticketer.clone()
+    }
+
+    /// Resume sessions with `config`.
+    pub(crate) fn apply(&self, config: &mut ServerConfig) {
+        config.session_storage = self.storage.clone();
+        config.ticketer = self.ticketer.clone();
+    }
+}
+
+/// Encrypts session tickets with ChaCha20-Poly1305, under a key per period of the ticket
+/// lifetime. Each ticket starts with the number of its key's period, and is decrypted if that
+/// key is still kept: the keys of the previous, current and next periods.
+pub(crate) struct Ticketer {
+    enabled: bool,
+    lifetime: Duration,
+    keys: RwLock<BTreeMap<u64, TicketKey>>,
+    rng: SystemRandom,
+}
+
+#[derive(Clone)]
+struct TicketKey {
+    secret: Zeroizing<[u8; KEY_LEN]>,
+    key: Arc<LessSafeKey>,
+}
+
+impl TicketKey {
+    fn new(secret: Zeroizing<[u8; KEY_LEN]>) -> Self {
+        let key =
+            UnboundKey::new(&CHACHA20_POLY1305, &secret[..]).expect("key has the right length");
+        Self {
+            secret,
+            key: Arc::new(LessSafeKey::new(key)),
+        }
+    }
+
+    fn generate(rng: &SystemRandom) -> Option<Self> {
+        let mut secret = Zeroizing::new([0; KEY_LEN]);
+        rng.fill(&mut secret[..]).ok()?;
+        Some(Self::new(secret))
+    }
+}
+
+impl Ticketer {
+    fn new(enabled: bool, lifetime: Duration) -> Self {
+        Self {
+            enabled,
+            lifetime,
+            keys: RwLock::default(),
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// The number of the current period.
+    fn period(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        now.as_secs() / self.lifetime.as_secs()
+    }
+
+    /// Make sure there are keys for the current and next periods, generating them if missing,
+    /// and forget the keys of earlier periods than the previous one. Returns whether keys were
+    /// generated.
+    fn rotate(&self, keys: &mut BTreeMap<u64, TicketKey>) -> bool {
+        let period = self.period();
+        keys.retain(|&kept, _| kept + 1 >= period && kept <= period + 1);
+        let mut generated = false;
+        for period in [period, period + 1] {
+            if let std::collections::btree_map::Entry::Vacant(entry) = keys.entry(period) {
+                if let Some(key) = TicketKey::generate(&self.rng) {
+                    entry.insert(key);
+                    generated = true;
+                }
+            }
+        }
+        generated
+    }
+
+    /// The key to encrypt tickets with now, and its period.
+    fn current_key(&self) -> Option<(u64, Arc<LessSafeKey>)> {
+        let period = self.period();
+        if let Some(key) = self.keys.read().unwrap().get(&period) {
+            return Some((period, key.key.clone()));
+        }
+        let mut keys = self.keys.write().unwrap();
+        self.rotate(&mut keys);
+        keys.get(&period).map(|key| (period, key.key.clone()))
+    }
+
+    /// Adopt the keys stored in the cache, `stored`, in place of ours for the same periods, and
+    /// rotate. Returns the keys to store back if they changed.
+    fn merge(&self, mut stored: BTreeMap<u64, [u8; KEY_LEN]>) -> Option<Zeroizing<Vec<u8>>> {
+        let mut keys = self.keys.write().unwrap();
+        for (period, secret) in &stored {
+            let adopt = keys.get(period).is_none_or(|key| *key.secret != *secret);
+            if adopt {
+                keys.insert(*period, TicketKey::new(Zeroizing::new(*secret)));
+            }
+        }
+        self.rotate(&mut keys);
+        let changed = keys.len() != stored.len()
+            || keys
+                .iter()
+                .any(|(period, key)| stored.get(period) != Some(&*key.secret));
+        stored.values_mut().for_each(Zeroize::zeroize);
+        changed.then(|| {
+            let mut entry = Vec::with_capacity(keys.len() * (PERIOD_LEN + KEY_LEN));
+            for (period, key) in keys.iter() {
+                entry.extend_from_slice(&period.to_be_bytes());
+                entry.extend_from_slice(&key.secret[..]);
+            }
+            Zeroizing::new(entry)
+        })
+    }
+}
+
+impl ProducesTickets for Ticketer {
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn get_lifetime(&self) -> u32 {
+        self.lifetime.as_secs() as u32
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let (period, key) = self.current_key()?;
+        let mut nonce = [0; NONCE_LEN];
+        self.rng.fill(&mut nonce).ok()?;
+        let period = period.to_be_bytes();
+        let mut sealed = plain.to_vec();
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(period),
+            &mut sealed,
+        )
+        .ok()?;
+        let mut ticket = Vec::with_capacity(PERIOD_LEN + NONCE_LEN + sealed.len());
+        ticket.extend_from_slice(&period);
+        ticket.extend_from_slice(&nonce);
+        ticket.extend_from_slice(&sealed);
+        Some(ticket)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        if cipher.len() < PERIOD_LEN + NONCE_LEN {
+            return None;
+        }
+        let (period, rest) = cipher.split_at(PERIOD_LEN);
+        let (nonce, sealed) = rest.split_at(NONCE_LEN);
+        let key = self
+            .keys
+            .read()
+            .unwrap()
+            .get(&u64::from_be_bytes(period.try_into().ok()?))?
+            .key
+            .clone();
+        let mut opened = sealed.to_vec();
+        let plain = key
+            .open_in_place(
+                Nonce::try_assume_unique_for_key(nonce).ok()?,
+                Aad::from(period),
+                &mut opened,
+            )
+            .ok()?;
+        Some(plain.to_vec())
+    }
+}
+
+/// Parse the ticket keys stored in the cache: the number of each period followed by its key.
+fn parse_keys(entry: &[u8]) -> Option<BTreeMap<u64, [u8; KEY_LEN]>> {
+    if !entry.len().is_multiple_of(PERIOD_LEN + KEY_LEN) {
+        return None;
+    }
+    entry
+        .chunks(PERIOD_LEN + KEY_LEN)
+        .map(|chunk| {
+            let (period, secret) = chunk.split_at(PERIOD_LEN);
+            Some((
+                u64::from_be_bytes(period.try_into().ok()?),
+                secret.try_into().ok()?,
+            ))
+        })
+        .collect()
+}
+
+/// The ticketer of an instance sharing its ticket keys with the others through the cache.
+pub(crate) struct TicketKeySync {
+    cache: Arc<dyn SharedCache>,
+    ticketer: Arc<Ticketer>,
+}
+
+impl TicketKeySync {
+    pub(crate) fn new(cache: Arc<dyn SharedCache>, ticketer: Arc<Ticketer>) -> Self {
+        Self { cache, ticketer }
+    }
+
+    /// Keep the ticket keys in sync with those of the other instances: adopt the keys found in
+    /// the cache, and store the keys of the coming period ahead of time.
+    pub(crate) async fn run(self) {
+        let Self { cache, ticketer } = self;
+        let interval = SYNC_INTERVAL.min(ticketer.lifetime / 4);
+        loop {
+            let stored = match cache.load(&ticket_keys_key()).await {
+                Ok(Some(entry)) => match format::read(entry, EntryKind::TicketKeys) {
+                    Ok((entry, _)) => parse_keys(&entry).unwrap_or_else(|| {
+                        warn!("malformed session ticket keys in the cache; replacing them");
+                        BTreeMap::new()
+                    }),
+                    Err(err) => {
+                        warn!(%err, "invalid session ticket keys in the cache; replacing them");
+                        BTreeMap::new()
+                    }
+                },
+                Ok(None) => BTreeMap::new(),
+                Err(err) => {
+                    warn!(%err, "failed to load session ticket keys; keeping ours");
+                    async_std::task::sleep(interval).await;
+                    continue;
+                }
+            };
+            if let Some(entry) = ticketer.merge(stored) {
+                debug!("storing session ticket keys");
+                if let Err(err) = cache
+                    .store(&ticket_keys_key(), &format::stamp(&entry))
+                    .await
+                {
+                    warn!(%err, "failed to store session ticket keys");
+                }
+            }
+            async_std::task::sleep(interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use async_std::future::timeout;
+    use async_std::task::block_on;
+    use async_trait::async_trait;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct MemoryCache(Mutex<HashMap<Vec<String>, Vec<u8>>>);
+
+    #[async_trait]
+    impl SharedCache for MemoryCache {
+        async fn store(&self, key: &[String], entry: &[u8]) -> Result<(), String> {
+            self.0.lock().unwrap().insert(key.to_vec(), entry.to_vec());
+            Ok(())
+        }
+
+        async fn load(&self, key: &[String]) -> Result<Option<Vec<u8>>, String> {
+            Ok(self.0.lock().unwrap().get(key).cloned())
+        }
+    }
+
+    fn ticketer() -> Ticketer {
+        Ticketer::new(true, Duration::from_secs(60 * 60))
+    }
+
+    /// Run a sync of `ticketer` through `cache` until it first sleeps.
+    fn sync(cache: &Arc<MemoryCache>, ticketer: &Arc<Ticketer>) {
+        let sync = TicketKeySync::new(cache.clone(), ticketer.clone());
+        let _ = block_on(timeout(Duration::from_millis(100), sync.run()));
+    }
+
+    #[test]
+    fn configures_resumption() {
+        let config = SessionResumption::new();
+        assert!(config.tickets && !config.shared_ticket_keys);
+        assert_eq!(config.session_cache, 256);
+        let config = SessionResumption::new().ticket_lifetime(Duration::from_secs(30 * 86400));
+        assert_eq!(config.ticket_lifetime, MAX_TICKET_LIFETIME);
+        let config = SessionResumption::new().ticket_lifetime(Duration::ZERO);
+        assert_eq!(config.ticket_lifetime, Duration::from_secs(1));
+
+        let resumption = Resumption::new(&SessionResumption::disabled());
+        let mut config = ServerConfig::new(tide_rustls::rustls::NoClientAuth::new());
+        resumption.apply(&mut config);
+        assert!(!config.ticketer.enabled());
+        assert!(!config
+            .session_storage
+            .put(b"id".to_vec(), b"session".to_vec()));
+        let resumption = Resumption::new(&SessionResumption::new());
+        resumption.apply(&mut config);
+        assert!(config.ticketer.enabled());
+        assert_eq!(config.ticketer.get_lifetime(), 12 * 60 * 60);
+        assert!(config
+            .session_storage
+            .put(b"id".to_vec(), b"session".to_vec()));
+    }
+
+    #[test]
+    fn encrypts_tickets() {
+        let ticketer = ticketer();
+        let ticket = ticketer.encrypt(b"session state").unwrap();
+        assert_eq!(ticket[..PERIOD_LEN], ticketer.period().to_be_bytes());
+        assert_eq!(ticketer.decrypt(&ticket).unwrap(), b"session state");
+        assert_ne!(ticketer.encrypt(b"session state").unwrap(), ticket);
+
+        let mut tampered = ticket.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(ticketer.decrypt(&tampered).is_none());
+        // The period is authenticated too.
+        let mut moved = ticket.clone();
+        moved[..PERIOD_LEN].copy_from_slice(&(ticketer.period() + 1).to_be_bytes());
+        assert!(ticketer.decrypt(&moved).is_none());
+        assert!(ticketer
+            .decrypt(&ticket[..PERIOD_LEN + NONCE_LEN - 1])
+            .is_none());
+        // Another instance's keys don't decrypt it.
+        assert!(self::ticketer().decrypt(&ticket).is_none());
+    }
+
+    #[test]
+    fn forgets_old_keys() {
+        let ticketer = ticketer();
+        let period = ticketer.period();
+        let mut keys = BTreeMap::new();
+        for old in period - 3..=period + 1 {
+            keys.insert(old, TicketKey::generate(&ticketer.rng).unwrap());
+        }
+        assert!(!ticketer.rotate(&mut keys));
+        assert_eq!(
+            keys.keys().copied().collect::<Vec<_>>(),
+            [period - 1, period, period + 1]
+        );
+        keys.remove(&(period + 1));
+        assert!(ticketer.rotate(&mut keys));
+        assert_eq!(keys.len(), 3);
+    }
+
+    #[test]
+    fn parses_stored_keys() {
+        let ticketer = ticketer();
+        let entry = ticketer.merge(BTreeMap::new()).unwrap();
+        let keys = parse_keys(&entry).unwrap();
+        let period = ticketer.period();
+        assert_eq!(
+            keys.keys().copied().collect::<Vec<_>>(),
+            [period, period + 1]
+        );
+        // Merging what we stored changes nothing.
+        assert!(ticketer.merge(keys).is_none());
+        assert!(parse_keys(&entry[1..]).is_none());
+        assert_eq!(parse_keys(&[]).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn shares_keys_through_the_cache() {
+        let cache = Arc::new(MemoryCache::default());
+        let (first, second) = (Arc::new(ticketer()), Arc::new(ticketer()));
+        let ticket = first.encrypt(b"session state").unwrap();
+        sync(&cache, &first);
+        let stored = cache.0.lock().unwrap()[&ticket_keys_key()].clone();
+        let (stored, _) = format::read(stored, EntryKind::TicketKeys).unwrap();
+        assert_eq!(parse_keys(&stored).unwrap().len(), 2);
+        // The second instance adopts the keys of the first.
+        sync(&cache, &second);
+        assert_eq!(second.decrypt(&ticket).unwrap(), b"session state");
+        let ticket = second.encrypt(b"other state").unwrap();
+        assert_eq!(first.decrypt(&ticket).unwrap(), b"other state");
+
+        // Malformed keys are replaced.
+        cache
+            .0
+            .lock()
+            .unwrap()
+            .insert(ticket_keys_key(), format::stamp(b"junk").to_vec());
+        sync(&cache, &first);
+        let stored = cache.0.lock().unwrap()[&ticket_keys_key()].clone();
+        let (stored, _) = format::read(stored, EntryKind::TicketKeys).unwrap();
+        assert!(parse_keys(&stored).is_some());
+    }
+}
@@ -0,0 +1,250 @@
+//! Declaring all the listeners of a deployment in one place.
+
+use std::fmt::Debug;
+use std::io::{self, ErrorKind};
+
+use futures::future::BoxFuture;
+use tide::listener::ConcurrentListener;
+use tracing::warn;
+
+use crate::{AcmeConfig, AcmeHandle, AcmeTlsAcceptor, TideRustlsExt};
+
+/// A TLS listener of a [`Topology`], with the policy of its acceptor.
+struct TlsSpec {
+    addrs: String,
+    configure: Box<dyn FnOnce(AcmeTlsAcceptor) -> AcmeTlsAcceptor + Send>,
+}
+
+/// The listeners of a deployment and the certificates they serve, declared in one structure and
+/// started together, instead of wiring up an [`AcmeTlsAcceptor`], its TLS listeners, an HTTP
+/// redirect listener and a validation socket by hand.
+///
+/// The [`AcmeConfig`] declares the domains, challenge types and cache; the topology declares
+/// where they are served. All listeners share one acceptor, so they serve the same certificates
+/// and its background tasks start once. [`listen`](Self::listen) checks that the topology can
+/// answer the configured challenges, starts every listener, and returns as soon as one of them
+/// fails.
+///
+/// ```no_run
+/// use tide_acme::{AcmeConfig, ListenerOptions, PrivateDirCache, Topology};
+///
+/// # async_std::task::block_on(async {
+/// let config = AcmeConfig::new(vec!["domain.example", "www.domain.example"])
+///     .http01(true)
+///     .cache(PrivateDirCache::new("/var/lib/tide-acme"));
+/// let topology = Topology::new(config)
+///     .tls("0.0.0.0:443")
+///     .tls_with("0.0.0.0:8443", |acceptor| {
+///         acceptor.listener_options(ListenerOptions::new().max_connections(100))
+///     })
+///     .http_redirect("0.0.0.0:80")
+///     .warm_up(true);
+/// let mut app = tide::new();
+/// app.at("/").get(|_| async { Ok("Hello TLS") });
+/// topology.listen(app).await?;
+/// # std::io::Result::Ok(())
+/// # });
+/// ```
+pub struct Topology {
+    acceptor: AcmeTlsAcceptor,
+    tls: Vec<TlsSpec>,
+    http_redirect: Vec<String>,
+    challenges: Vec<String>,
+    warm_up: bool,
+}
+
+impl Debug for Topology {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Topology")
+            .field(
+                "tls",
+                &self.tls.iter().map(|tls| &tls.addrs).collect::<Vec<_>>(),
+            )
+            .field("http_redirect", &self.http_redirect)
+            .field("challenges", &self.challenges)
+            .field("warm_up", &self.warm_up)
+            .finish()
+    }
+}
+
+impl Topology {
+    /// Create a topology serving the certificates of `config`, with no listeners yet.
+    ///
+    /// This creates the [`AcmeTlsAcceptor`], starting its background tasks, so that
+    /// [`handle`](Self::handle) can be used to set up the application before it listens.
+    pub fn new<EC: 'static + Debug, EA: 'static + Debug>(config: AcmeConfig<EC, EA>) -> Self {
+        Self::with_acceptor(AcmeTlsAcceptor::new(config))
+    }
+
+    /// Create a topology around an existing acceptor, such as one whose clones serve other
+    /// listeners too.
+    pub fn with_acceptor(acceptor: AcmeTlsAcceptor) -> Self {
+        Self {
+            acceptor,
+            tls: vec![],
+            http_redirect: vec![],
+            challenges: vec![],
+            warm_up: false,
+        }
+    }
+
+    /// Serve the application over TLS on `addrs`, such as `0.0.0.0:443`, answering tls-alpn-01
+    /// validation requests there too.
+    pub fn tls(self, addrs: impl Into<String>) -> Self {
+        self.tls_with(addrs, |acceptor| acceptor)
+    }
+
+    /// Serve the application over TLS on `addrs`, with the policy `configure` sets on a clone of
+    /// the acceptor, such as [strict SNI](AcmeTlsAcceptor::strict_sni) or [client
+    /// certificates](AcmeTlsAcceptor::client_auth).
+    pub fn tls_with(
+        mut self,
+        addrs: impl Into<String>,
+        configure: impl FnOnce(AcmeTlsAcceptor) -> AcmeTlsAcceptor + Send + 'static,
+    ) -> Self {
+        self.tls.push(TlsSpec {
+            addrs: addrs.into(),
+            configure: Box::new(configure),
+        });
+        self
+    }
+
+    /// Serve plain HTTP on `addrs`, such as `0.0.0.0:80`, redirecting to HTTPS and answering
+    /// http-01 validation requests; see [`AcmeTlsAcceptor::listen_http`].
+    pub fn http_redirect(mut self, addrs: impl Into<String>) -> Self {
+        self.http_redirect.push(addrs.into());
+        self
+    }
+
+    /// Answer tls-alpn-01 validation requests on a dedicated socket bound to `addrs`, for a proxy
+    /// to pass them through to; see [`AcmeTlsAcceptor::listen_challenges`].
+    pub fn challenges(mut self, addrs: impl Into<String>) -> Self {
+        self.challenges.push(addrs.into());
+        self
+    }
+
+    /// Obtain certificates before serving the application if `warm_up` is true, answering
+    /// validation requests on the first TLS address meanwhile; see
+    /// [`AcmeTlsAcceptor::warm_up`]. Defaults to false.
+    pub fn warm_up(mut self, warm_up: bool) -> Self {
+        self.warm_up = warm_up;
+        self
+    }
+
+    /// Get a handle for inspecting the certificates, such as to set up middleware of the
+    /// application.
+    pub fn handle(&self) -> AcmeHandle {
+        self.acceptor.handle()
+    }
+
+    /// Check that the listeners can answer the configured challenges: fails if there are no
+    /// listeners, and warns about challenge types no listener answers, in case a proxy doesn't
+    /// forward them elsewhere.
+    fn check(&self) -> io::Result<()> {
+        if self.tls.is_empty() && self.http_redirect.is_empty() && self.challenges.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "the topology has no listeners",
+            ));
+        }
+        let summary = self.handle().config_summary();
+        for challenge_type in &summary.challenge_types {
+            let answered = match challenge_type.as_str() {
+                "tls-alpn-01" => !self.tls.is_empty() || !self.challenges.is_empty(),
+                "http-01" => !self.http_redirect.is_empty(),
+                _ => true,
+            };
+            if !answered {
+                warn!(
+                    challenge_type = challenge_type.as_str(),
+                    "no listener of the topology answers {} validation requests; they must \
+                     reach another one",
+                    challenge_type
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Start every listener, serving `app` on the TLS listeners. This only returns if the
+    /// topology is invalid, binding a socket fails, or a listener fails, which stops the others.
+    pub async fn listen<State: Clone + Send + Sync + 'static>(
+        self,
+        app: tide::Server<State>,
+    ) -> io::Result<()> {
+        let logging = self.acceptor.logging;
+        crate::logged(logging, async { self.check() }).await?;
+        if self.warm_up {
+            if let Some(tls) = self.tls.first() {
+                self.acceptor.warm_up(tls.addrs.as_str()).await?;
+            }
+        }
+        let serve_tls = !self.tls.is_empty();
+        let mut tls_listener = ConcurrentListener::new();
+        for tls in self.tls {
+            let acceptor = (tls.configure)(self.acceptor.clone());
+            let socket = acceptor.listener_options.bind(tls.addrs.as_str())?;
+            tls_listener.add(
+                tide_rustls::TlsListener::build()
+                    .tcp(socket)
+                    .acme_acceptor(acceptor),
+            )?;
+        }
+        let mut listeners: Vec<BoxFuture<'static, io::Result<()>>> = vec![];
+        for addrs in self.challenges {
+            let acceptor = self.acceptor.clone();
+            listeners.push(Box::pin(async move {
+                acceptor.listen_challenges(addrs.as_str()).await
+            }));
+        }
+        for addrs in self.http_redirect {
+            let acceptor = self.acceptor.clone();
+            listeners.push(Box::pin(async move { acceptor.listen_http(addrs).await }));
+        }
+        if serve_tls {
+            listeners.push(Box::pin(async move { app.listen(tls_listener).await }));
+        }
+        let (result, _, _) = futures::future::select_all(listeners).await;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::block_on;
+
+    use super::*;
+
+    fn config() -> AcmeConfig<std::convert::Infallible, std::convert::Infallible> {
+        AcmeConfig::new(["a.example"]).cache_none()
+    }
+
+    #[test]
+    fn refuses_topologies_without_listeners() {
+        let err = block_on(Topology::new(config()).listen(tide::new())).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(Topology::new(config())
+            .challenges("[::]:8443")
+            .check()
+            .is_ok());
+    }
+
+    #[test]
+    fn declares_listeners() {
+        let topology = Topology::new(config())
+            .tls("[::]:443")
+            .tls_with("[::]:8443", |acceptor| acceptor.strict_sni(true))
+            .http_redirect("[::]:80")
+            .warm_up(true);
+        assert!(topology.check().is_ok());
+        assert_eq!(
+            format!("{:?}", topology),
+            "Topology { tls: [\"[::]:443\", \"[::]:8443\"], http_redirect: [\"[::]:80\"], \
+             challenges: [], warm_up: true }"
+        );
+        assert_eq!(
+            topology.handle().config_summary().groups[0].domains,
+            ["a.example"]
+        );
+    }
+}
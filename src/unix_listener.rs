@@ -0,0 +1,317 @@
+//! A Tide listener serving HTTPS on a Unix domain socket.
+
+use std::fmt::{self, Debug, Display};
+use std::fs::{self, Permissions};
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::{Path, PathBuf};
+
+use async_dup::{Arc, Mutex};
+use async_std::future::timeout;
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use tide::listener::{ListenInfo, Listener, ToListener};
+use tide::Server;
+use tracing::{debug, error};
+
+use crate::proxy::{self, PROXY_HEADER_TIMEOUT};
+use crate::{handover, logged, AcmeTlsAcceptor};
+
+/// A listener serving HTTPS through an [`AcmeTlsAcceptor`] on a Unix domain socket, for
+/// deployments where the application can't bind port 443 itself: a local proxy, such as
+/// HAProxy or nginx's `stream` module, passes the TCP connections through to the socket
+/// unterminated, including the CA's tls-alpn-01 validation connections.
+///
+/// Turn on [`AcmeTlsAcceptor::proxy_protocol`] and have the proxy send a PROXY protocol header,
+/// so the application sees the client's address as the peer address of its requests; otherwise
+/// requests have none. With [`inherit_or_bind`](Self::inherit_or_bind), the socket can be bound
+/// by systemd and passed to the application with socket activation.
+///
+/// ```no_run
+/// use tide_acme::{AcmeConfig, AcmeTlsAcceptor, UnixTlsListener};
+///
+/// # async_std::task::block_on(async {
+/// let config = AcmeConfig::new(vec!["domain.example"]);
+/// let acceptor = AcmeTlsAcceptor::new(config).proxy_protocol(true);
+/// let app = tide::new();
+/// app.listen(UnixTlsListener::inherit_or_bind(acceptor, "/run/app/https.sock").mode(0o660))
+///     .await?;
+/// # tide::Result::Ok(())
+/// # });
+/// ```
+pub struct UnixTlsListener<State> {
+    acceptor: AcmeTlsAcceptor,
+    path: Option<PathBuf>,
+    mode: Option<u32>,
+    listener: Option<UnixListener>,
+    server: Option<Server<State>>,
+}
+
+impl<State> UnixTlsListener<State> {
+    /// Create a listener serving HTTPS through `acceptor` on a Unix domain socket bound to
+    /// `path` once the application starts listening.
+    ///
+    /// The socket is bound with the backlog of the acceptor's
+    /// [`listener_options`](AcmeTlsAcceptor::listener_options). A socket left at `path` by a
+    /// process that exited is replaced; binding fails if another process is listening on it.
+    pub fn new(acceptor: AcmeTlsAcceptor, path: impl Into<PathBuf>) -> Self {
+        Self {
+            acceptor,
+            path: Some(path.into()),
+            mode: None,
+            listener: None,
+            server: None,
+        }
+    }
+
+    /// Create a listener serving HTTPS through `acceptor` on `listener`, a listening socket bound
+    /// by the caller.
+    pub fn from_listener(
+        acceptor: AcmeTlsAcceptor,
+        listener: std::os::unix::net::UnixListener,
+    ) -> Self {
+        let path = listener
+            .local_addr()
+            .ok()
+            .and_then(|addr| addr.as_pathname().map(Path::to_path_buf));
+        Self {
+            acceptor,
+            path,
+            mode: None,
+            listener: Some(listener.into()),
+            server: None,
+        }
+    }
+
+    /// Create a listener on the first Unix domain socket passed by systemd socket activation,
+    /// such as one declared with `ListenStream=/run/app/https.sock`, or one bound to `path` as
+    /// [`new`](Self::new) does if there is none.
+    ///
+    /// TCP sockets passed alongside are left to
+    /// [`ListenerOptions::inherit_or_bind`](crate::ListenerOptions::inherit_or_bind).
+    pub fn inherit_or_bind(acceptor: AcmeTlsAcceptor, path: impl Into<PathBuf>) -> Self {
+        match handover::take_unix_listener() {
+            Some(listener) => Self::from_listener(acceptor, listener),
+            None => Self::new(acceptor, path),
+        }
+    }
+
+    /// Set the permissions of the socket file once bound, such as `0o660` to let the proxy's
+    /// group connect, rather than leaving them to the umask. Doesn't apply to sockets passed by
+    /// the caller or by systemd, whose permissions are set where they're bound.
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Listener<State> for UnixTlsListener<State> {
+    async fn bind(&mut self, app: Server<State>) -> io::Result<()> {
+        self.server = Some(app);
+        if self.listener.is_some() {
+            return Ok(());
+        }
+        let path = self
+            .path
+            .as_deref()
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no path to bind to"))?;
+        remove_stale_socket(path)?;
+        let listener = self.acceptor.listener_options.bind_unix(path)?;
+        if let Some(mode) = self.mode {
+            fs::set_permissions(path, Permissions::from_mode(mode))?;
+        }
+        self.listener = Some(listener.into());
+        Ok(())
+    }
+
+    async fn accept(&mut self) -> io::Result<()> {
+        let not_bound = || io::Error::new(ErrorKind::NotConnected, "listener isn't bound");
+        let listener = self.listener.as_ref().ok_or_else(not_bound)?;
+        let server = self.server.as_ref().ok_or_else(not_bound)?;
+        let acceptor = &self.acceptor;
+        logged(acceptor.logging, async {
+            loop {
+                let accepted = acceptor
+                    .listener_options
+                    .accept_with(|| listener.accept(), &acceptor.connections)
+                    .await;
+                let (stream, _) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        error!(%err, "accepting connections failed; closing the socket");
+                        return Err(err);
+                    }
+                };
+                let (acceptor, server) = (acceptor.clone(), server.clone());
+                async_std::task::spawn(logged(acceptor.logging, serve(acceptor, server, stream)));
+            }
+        })
+        .await
+    }
+
+    fn info(&self) -> Vec<ListenInfo> {
+        let path = self
+            .path
+            .as_ref()
+            .map_or_else(|| "unnamed".into(), |path| path.display().to_string());
+        vec![ListenInfo::new(
+            format!("https+unix://{}", path),
+            "unix".into(),
+            true,
+        )]
+    }
+}
+
+impl<State: Clone + Send + Sync + 'static> ToListener<State> for UnixTlsListener<State> {
+    type Listener = Self;
+
+    fn to_listener(self) -> io::Result<Self> {
+        Ok(self)
+    }
+}
+
+/// Read the PROXY protocol header of a connection if enabled, complete its TLS handshake, and
+/// serve the application's requests on it.
+async fn serve<State: Clone + Send + Sync + 'static>(
+    acceptor: AcmeTlsAcceptor,
+    server: Server<State>,
+    mut stream: UnixStream,
+) {
+    let mut client: Option<SocketAddr> = None;
+    if acceptor.proxy_protocol {
+        match timeout(PROXY_HEADER_TIMEOUT, proxy::read_header(&mut stream)).await {
+            Ok(Ok(addr)) => client = addr,
+            Ok(Err(err)) => {
+                debug!(%err, "invalid PROXY protocol header; closing connection");
+                return;
+            }
+            Err(_) => {
+                debug!("timed out reading the PROXY protocol header; closing connection");
+                return;
+            }
+        }
+    }
+    let tls = match acceptor.accept_stream(stream).await {
+        Ok(Some(tls)) => tls,
+        Ok(None) => return,
+        Err(err) => {
+            debug!(%err, "TLS handshake failed on Unix domain socket");
+            return;
+        }
+    };
+    let stream = Arc::new(Mutex::new(tls));
+    let served = async_h1::accept(stream, |mut req| async {
+        let _ = req.url_mut().set_scheme("https");
+        req.set_peer_addr(client);
+        server.respond(req).await
+    })
+    .await;
+    if let Err(err) = served {
+        debug!(%err, "failed to serve connection on Unix domain socket");
+    }
+}
+
+/// Remove the socket file at `path` if no process is listening on it anymore, so binding to it
+/// again succeeds. Files other than sockets are left alone, for binding to fail on.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {}
+        _ => return Ok(()),
+    }
+    match StdUnixStream::connect(path) {
+        Err(err) if err.kind() == ErrorKind::ConnectionRefused => fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+impl<State> Debug for UnixTlsListener<State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UnixTlsListener")
+            .field("path", &self.path)
+            .field("mode", &self.mode)
+            .field("bound", &self.listener.is_some())
+            .finish()
+    }
+}
+
+impl<State> Display for UnixTlsListener<State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "https+unix://{}", path.display()),
+            None => write!(f, "https+unix://unnamed"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::block_on;
+
+    use super::*;
+    use crate::AcmeConfig;
+
+    fn acceptor() -> AcmeTlsAcceptor {
+        AcmeTlsAcceptor::new(AcmeConfig::new(Vec::<String>::new()))
+    }
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("tide-acme-{}-{}.sock", name, std::process::id()))
+    }
+
+    #[test]
+    fn binds_with_the_mode() {
+        let path = socket_path("unix-mode");
+        let _ = fs::remove_file(&path);
+        let mut listener = UnixTlsListener::new(acceptor(), &path).mode(0o660);
+        assert_eq!(
+            listener.to_string(),
+            format!("https+unix://{}", path.display())
+        );
+        block_on(Listener::<()>::bind(&mut listener, tide::new())).unwrap();
+        let metadata = fs::symlink_metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o660);
+        let info = Listener::<()>::info(&listener);
+        assert_eq!(info[0].connection(), listener.to_string());
+        assert!(info[0].is_encrypted());
+        drop(listener);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replaces_stale_sockets_only() {
+        let path = socket_path("unix-stale");
+        let _ = fs::remove_file(&path);
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let mut listener = UnixTlsListener::new(acceptor(), &path);
+        block_on(Listener::<()>::bind(&mut listener, tide::new())).unwrap();
+
+        let mut other = UnixTlsListener::new(acceptor(), &path);
+        assert!(block_on(Listener::<()>::bind(&mut other, tide::new())).is_err());
+        drop(listener);
+        fs::remove_file(&path).unwrap();
+
+        fs::write(&path, b"not a socket").unwrap();
+        let mut listener = UnixTlsListener::new(acceptor(), &path);
+        assert!(block_on(Listener::<()>::bind(&mut listener, tide::new())).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"not a socket");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn uses_passed_listeners() {
+        let path = socket_path("unix-passed");
+        let _ = fs::remove_file(&path);
+        let std_listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let mut listener = UnixTlsListener::from_listener(acceptor(), std_listener);
+        assert_eq!(listener.path.as_deref(), Some(path.as_path()));
+        block_on(Listener::<()>::bind(&mut listener, tide::new())).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut unbound = UnixTlsListener::<()>::new(acceptor(), &path);
+        let err = block_on(Listener::<()>::accept(&mut unbound)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+    }
+}
@@ -0,0 +1,54 @@
+//! Watching a cache directory for certificates stored by other processes.
+
+use std::path::Path;
+
+use notify::{Event, RecursiveMode, Watcher};
+use tracing::warn;
+
+use crate::AcmeHandle;
+
+/// Check the cache for renewed certificates whenever a file in `dir` is created or modified.
+///
+/// The watcher runs for the life of the process, like the background tasks.
+pub(crate) fn watch(dir: &Path, handle: AcmeHandle, logging: bool) -> notify::Result<()> {
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+            Ok(event) if event.kind.is_create() || event.kind.is_modify() => handle.reload(),
+            Ok(_) => {}
+            Err(err) if logging => warn!(%err, "watching the cache directory failed"),
+            Err(_) => {}
+        })?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    std::mem::forget(watcher);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use async_std::future::timeout;
+    use async_std::task::block_on;
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::handle::Command;
+    use crate::snapshot::CertOrigin;
+    use crate::AcmeConfig;
+
+    #[test]
+    fn reloads_on_changes() {
+        let dir = std::env::temp_dir().join(format!("tide-acme-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let handle = AcmeConfig::new(Vec::<String>::new()).state().handle();
+        let (_, mut commands) =
+            handle
+                .registry
+                .register(vec!["domain.example".into()], 0, CertOrigin::Configured);
+        watch(&dir, handle, true).unwrap();
+        std::fs::write(dir.join("cached_cert"), "cert").unwrap();
+        let command = block_on(timeout(Duration::from_secs(10), commands.next())).unwrap();
+        assert!(matches!(command, Some(Command::Reload)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}